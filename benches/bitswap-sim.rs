@@ -0,0 +1,341 @@
+//! Simulates block exchanges over bitswap between nodes running in this process, to evaluate the
+//! changes to the bitswap implementation.
+//!
+//! A synthetic DAG is stored on the seeding nodes, after which all of the other nodes fetch it
+//! concurrently. Every run reports the time each node took to fetch the DAG, the ratio of the
+//! duplicate blocks received and the number of bitswap messages exchanged.
+//!
+//! Usage: `cargo bench --bench bitswap-sim -- [OPTIONS]`, see `--help` for the options.
+
+use cid::{Cid, Codec};
+use futures::future::{join_all, try_join_all};
+use ipfs::ipld::{decode_ipld, encode_ipld};
+use ipfs::{Block, Error, Ipld, Node};
+use multihash::Sha2_256;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use std::process::exit;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Topology {
+    /// every node is connected to every other
+    Mesh,
+    /// the first node is connected to all of the others
+    Star,
+    /// the nodes are connected one after another
+    Line,
+    /// the nodes are connected one after another, the last to the first
+    Ring,
+}
+
+#[derive(Debug)]
+struct Options {
+    nodes: usize,
+    seeders: usize,
+    topology: Topology,
+    width: usize,
+    depth: usize,
+    block_size: usize,
+    runs: usize,
+    timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            nodes: 5,
+            seeders: 1,
+            topology: Topology::Mesh,
+            width: 10,
+            depth: 2,
+            block_size: 16 * 1024,
+            runs: 3,
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+fn usage() -> ! {
+    let defaults = Options::default();
+    eprintln!("Usage: bitswap-sim [OPTIONS]");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!(
+        "  --nodes N         number of nodes, including the seeders ({})",
+        defaults.nodes
+    );
+    eprintln!(
+        "  --seeders N       number of nodes having the DAG at start ({})",
+        defaults.seeders
+    );
+    eprintln!("  --topology T      mesh, star, line or ring (mesh)");
+    eprintln!(
+        "  --width N         links per non-leaf DAG node ({})",
+        defaults.width
+    );
+    eprintln!(
+        "  --depth N         levels of the DAG below the root ({})",
+        defaults.depth
+    );
+    eprintln!(
+        "  --block-size N    bytes per leaf block ({})",
+        defaults.block_size
+    );
+    eprintln!(
+        "  --runs N          number of runs with new nodes ({})",
+        defaults.runs
+    );
+    eprintln!(
+        "  --timeout SECS    time allowed for a node to fetch the DAG ({})",
+        defaults.timeout.as_secs()
+    );
+    exit(0);
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for {}", arg))
+        };
+
+        let number = |value: String| {
+            value
+                .parse::<usize>()
+                .map_err(|e| format!("invalid number {:?}: {}", value, e))
+        };
+
+        match arg.as_str() {
+            // passed by `cargo bench`
+            "--bench" => {}
+            "--help" | "-h" => usage(),
+            "--nodes" => options.nodes = number(value()?)?,
+            "--seeders" => options.seeders = number(value()?)?,
+            "--topology" => {
+                options.topology = match value()?.as_str() {
+                    "mesh" => Topology::Mesh,
+                    "star" => Topology::Star,
+                    "line" => Topology::Line,
+                    "ring" => Topology::Ring,
+                    other => return Err(format!("unknown topology {:?}", other)),
+                }
+            }
+            "--width" => options.width = number(value()?)?,
+            "--depth" => options.depth = number(value()?)?,
+            "--block-size" => options.block_size = number(value()?)?,
+            "--runs" => options.runs = number(value()?)?,
+            "--timeout" => options.timeout = Duration::from_secs(number(value()?)? as u64),
+            other => return Err(format!("unknown option {:?}", other)),
+        }
+    }
+
+    if options.seeders == 0 || options.seeders >= options.nodes {
+        return Err("--seeders must be at least one and less than --nodes".into());
+    }
+
+    if options.width == 0 {
+        return Err("--width must be at least one".into());
+    }
+
+    Ok(options)
+}
+
+/// Creates the blocks of a DAG of `depth` levels of DAG-CBOR lists of links below the root, with
+/// random raw blocks as the leaves. Returns the root Cid and all of the blocks.
+fn synthetic_dag(options: &Options, rng: &mut StdRng) -> (Cid, Vec<Block>) {
+    fn node(options: &Options, rng: &mut StdRng, depth: usize, blocks: &mut Vec<Block>) -> Cid {
+        let block = if depth == 0 {
+            let mut data = vec![0u8; options.block_size];
+            rng.fill_bytes(&mut data);
+            let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data));
+            Block::new(data.into_boxed_slice(), cid)
+        } else {
+            let links = (0..options.width)
+                .map(|_| Ipld::Link(node(options, rng, depth - 1, blocks)))
+                .collect();
+            let data = encode_ipld(&Ipld::List(links), Codec::DagCBOR).unwrap();
+            let cid = Cid::new_v1(Codec::DagCBOR, Sha2_256::digest(&data));
+            Block::new(data, cid)
+        };
+
+        let cid = block.cid.clone();
+        blocks.push(block);
+        cid
+    }
+
+    let mut blocks = Vec::new();
+    let root = node(options, rng, options.depth, &mut blocks);
+    (root, blocks)
+}
+
+/// Fetches the DAG a level at a time, all of the blocks of a level concurrently.
+async fn fetch_dag(node: &Node, root: Cid) -> Result<usize, Error> {
+    let mut level = vec![root];
+    let mut fetched = 0;
+
+    while !level.is_empty() {
+        let blocks = try_join_all(level.iter().map(|cid| node.get_block(cid))).await?;
+        fetched += blocks.len();
+
+        let mut next = Vec::new();
+        for block in blocks {
+            if let Ipld::List(links) = decode_ipld(&block.cid, &block.data)? {
+                next.extend(links.into_iter().filter_map(|link| match link {
+                    Ipld::Link(cid) => Some(cid),
+                    _ => None,
+                }));
+            }
+        }
+        level = next;
+    }
+
+    Ok(fetched)
+}
+
+async fn spawn_nodes(options: &Options) -> Vec<Node> {
+    let mut nodes = Vec::with_capacity(options.nodes);
+    for i in 0..options.nodes {
+        nodes.push(Node::new(i.to_string()).await);
+    }
+
+    let count = nodes.len();
+    let mut edges = Vec::new();
+    match options.topology {
+        Topology::Mesh => {
+            for i in 0..count {
+                edges.extend((i + 1..count).map(|j| (i, j)));
+            }
+        }
+        Topology::Star => edges.extend((1..count).map(|j| (0, j))),
+        Topology::Line | Topology::Ring => {
+            edges.extend((1..count).map(|j| (j - 1, j)));
+            if options.topology == Topology::Ring && count > 2 {
+                edges.push((count - 1, 0));
+            }
+        }
+    }
+
+    for (a, b) in edges {
+        nodes[a].connect(nodes[b].addrs[0].clone()).await.unwrap();
+    }
+
+    nodes
+}
+
+struct RunResult {
+    /// Time to fetch per fetching node, `None` if the node did not fetch the DAG in time.
+    fetch_times: Vec<Option<Duration>>,
+    blocks_received: u64,
+    duplicates_received: u64,
+    messages_sent: u64,
+}
+
+async fn run(options: &Options, root: &Cid, blocks: &[Block]) -> RunResult {
+    let nodes = spawn_nodes(options).await;
+
+    for seeder in &nodes[..options.seeders] {
+        for block in blocks {
+            seeder.put_block(block.clone()).await.unwrap();
+        }
+    }
+
+    let fetch_times = join_all(nodes[options.seeders..].iter().map(|node| async move {
+        let started = Instant::now();
+        match timeout(options.timeout, fetch_dag(node, root.clone())).await {
+            Ok(Ok(_)) => Some(started.elapsed()),
+            Ok(Err(e)) => {
+                eprintln!("node {} failed to fetch: {}", node.id, e);
+                None
+            }
+            Err(_) => None,
+        }
+    }))
+    .await;
+
+    let mut result = RunResult {
+        fetch_times,
+        blocks_received: 0,
+        duplicates_received: 0,
+        messages_sent: 0,
+    };
+
+    for node in &nodes {
+        let stats = node.bitswap_stats().await.unwrap();
+        result.blocks_received += stats.blocks_received;
+        result.duplicates_received += stats.dup_blks_received;
+        result.messages_sent += stats.messages_sent;
+    }
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+
+    result
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    // the same DAG on every run
+    let mut rng = StdRng::seed_from_u64(0);
+    let (root, blocks) = synthetic_dag(&options, &mut rng);
+    let bytes: usize = blocks.iter().map(|block| block.data.len()).sum();
+
+    println!("{:?}", options);
+    println!(
+        "DAG: {} blocks, {} bytes, root {}",
+        blocks.len(),
+        bytes,
+        root
+    );
+    println!();
+    println!(
+        "{:>4} {:>10} {:>10} {:>10} {:>8} {:>10} {:>10}",
+        "run", "min ms", "median ms", "max ms", "timeouts", "dup ratio", "messages"
+    );
+
+    for i in 0..options.runs {
+        let result = run(&options, &root, &blocks).await;
+
+        let mut times = result
+            .fetch_times
+            .iter()
+            .filter_map(|time| time.map(millis))
+            .collect::<Vec<_>>();
+        times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let timeouts = result.fetch_times.len() - times.len();
+
+        let (min, median, max) = if times.is_empty() {
+            (f64::NAN, f64::NAN, f64::NAN)
+        } else {
+            (times[0], times[times.len() / 2], times[times.len() - 1])
+        };
+
+        let total = result.blocks_received + result.duplicates_received;
+        let dup_ratio = if total == 0 {
+            0.0
+        } else {
+            result.duplicates_received as f64 / total as f64
+        };
+
+        println!(
+            "{:>4} {:>10.1} {:>10.1} {:>10.1} {:>8} {:>10.3} {:>10}",
+            i, min, median, max, timeouts, dup_ratio, result.messages_sent
+        );
+    }
+}
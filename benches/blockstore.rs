@@ -0,0 +1,94 @@
+//! Compares the blockstores on the puts, the lookups of the missing blocks and the reads. The
+//! rocksdb based blockstore is only included with the `rocksdb_block_store` feature.
+use cid::{Cid, Codec};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use ipfs::repo::{fs::FsBlockStore, kv::KvBlockStore, BlockStore};
+use ipfs::Block;
+use multihash::Sha2_256;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+const BLOCKS: usize = 10_000;
+const BLOCK_SIZE: usize = 1024;
+
+fn blocks(salt: u8) -> Vec<Block> {
+    (0..BLOCKS)
+        .map(|i| {
+            let mut data = vec![salt; BLOCK_SIZE];
+            data[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data));
+            Block::new(data.into_boxed_slice(), cid)
+        })
+        .collect()
+}
+
+async fn open<S: BlockStore>() -> (TempDir, S) {
+    let tmp = tempfile::tempdir().unwrap();
+    let store = S::new(tmp.path().to_owned());
+    store.init().await.unwrap();
+    store.open().await.unwrap();
+    (tmp, store)
+}
+
+async fn put_all<S: BlockStore>(store: &S, blocks: &[Block]) {
+    for block in blocks {
+        store.put(block.clone()).await.unwrap();
+    }
+}
+
+fn bench_store<S: BlockStore>(c: &mut Criterion, rt: &Runtime, name: &str) {
+    let present = blocks(0);
+    let missing = blocks(1)
+        .into_iter()
+        .map(|block| block.cid)
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("blockstore");
+    group.sample_size(10);
+
+    group.bench_function(BenchmarkId::new("put", name), |b| {
+        b.iter_batched(
+            || rt.block_on(open::<S>()),
+            |(_tmp, store)| rt.block_on(put_all(&store, &present)),
+            BatchSize::PerIteration,
+        )
+    });
+
+    let (_tmp, store) = rt.block_on(open::<S>());
+    rt.block_on(put_all(&store, &present));
+
+    group.bench_function(BenchmarkId::new("contains missing", name), |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for cid in &missing {
+                    assert!(!store.contains(cid).await.unwrap());
+                }
+            })
+        })
+    });
+
+    group.bench_function(BenchmarkId::new("get", name), |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for block in &present {
+                    assert!(store.get(&block.cid).await.unwrap().is_some());
+                }
+            })
+        })
+    });
+
+    group.finish();
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+
+    // the file per block layout of go-ipfs's flatfs
+    bench_store::<FsBlockStore>(c, &rt, "flatfs");
+    bench_store::<KvBlockStore>(c, &rt, "sled");
+    #[cfg(feature = "rocksdb_block_store")]
+    bench_store::<ipfs::repo::rocks::RocksBlockStore>(c, &rt, "rocksdb");
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
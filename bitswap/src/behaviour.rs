@@ -1,25 +1,39 @@
-//! Handles the `/ipfs/bitswap/1.0.0` and `/ipfs/bitswap/1.1.0` protocols. This
+//! Handles the `/ipfs/bitswap/1.1.0` and `/ipfs/bitswap/1.2.0` protocols. This
 //! allows exchanging IPFS blocks.
 //!
 //! # Usage
 //!
 //! The `Bitswap` struct implements the `NetworkBehaviour` trait. When used, it
 //! will allow providing and reciving IPFS blocks.
+//!
+//! Each wanted block is asked from a single peer at a time, while the other peers are only asked
+//! whether they have it. The peers telling they have the block are asked for it when the first
+//! peer does not have it, and the peers which have sent the blocks of a [`SessionId`] are asked
+//! first for the following blocks of the session.
+//!
+//! The blocks wanted by the peers are served a limited number at a time, in the order decided by
+//! the [`DecisionStrategy`].
 use crate::block::Block;
-use crate::ledger::{Ledger, Message, Priority};
+use crate::ledger::{Ledger, Message, Priority, WantlistBatchConfig};
 use crate::protocol::{BitswapConfig, MessageWrapper};
+use crate::strategy::{DecisionStrategy, RoundRobin};
 use cid::Cid;
 use fnv::FnvHashSet;
 use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
-use hash_hasher::HashedMap;
+use hash_hasher::{HashedMap, HashedSet};
 use libp2p_core::{connection::ConnectionId, ConnectedPoint, Multiaddr, PeerId};
 use libp2p_swarm::dial_opts::{DialOpts, PeerCondition};
-use libp2p_swarm::handler::OneShotHandler;
-use libp2p_swarm::{NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters};
+use libp2p_swarm::handler::{OneShotHandler, OneShotHandlerConfig};
+use libp2p_swarm::{
+    NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters, SubstreamProtocol,
+};
+use std::future::Future;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     mem,
+    pin::Pin,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
@@ -43,11 +57,26 @@ pub struct Stats {
     pub received_data: AtomicU64,
     pub duplicate_blocks: AtomicU64,
     pub duplicate_data: AtomicU64,
+    pub sent_messages: AtomicU64,
+    pub received_messages: AtomicU64,
+    /// Blocks the peer said it has but did not send in time.
+    pub unfulfilled_haves: AtomicU64,
+    /// How many times the peer has been penalized for the unfulfilled haves.
+    pub denial_penalties: AtomicU64,
 }
 
 impl Stats {
-    pub fn update_outgoing(&self, num_blocks: u64) {
+    pub fn update_outgoing(&self, num_blocks: u64, bytes: u64) {
         self.sent_blocks.fetch_add(num_blocks, Ordering::Relaxed);
+        self.sent_data.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn update_sent_messages(&self) {
+        self.sent_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn update_received_messages(&self) {
+        self.received_messages.fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn update_incoming_unique(&self, bytes: u64) {
@@ -60,6 +89,14 @@ impl Stats {
         self.duplicate_data.fetch_add(bytes, Ordering::Relaxed);
     }
 
+    pub fn update_unfulfilled_haves(&self, count: u64) {
+        self.unfulfilled_haves.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn update_denial_penalties(&self) {
+        self.denial_penalties.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn add_assign(&self, other: &Stats) {
         self.sent_blocks
             .fetch_add(other.sent_blocks.load(Ordering::Relaxed), Ordering::Relaxed);
@@ -81,9 +118,69 @@ impl Stats {
             other.duplicate_data.load(Ordering::Relaxed),
             Ordering::Relaxed,
         );
+        self.sent_messages.fetch_add(
+            other.sent_messages.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.received_messages.fetch_add(
+            other.received_messages.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.unfulfilled_haves.fetch_add(
+            other.unfulfilled_haves.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.denial_penalties.fetch_add(
+            other.denial_penalties.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
     }
 }
 
+/// How long a peer is not asked again for a block it said it does not have.
+const DEFAULT_DONT_HAVE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// How long a peer has to send a wanted block after saying it has it.
+const DEFAULT_HAVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many unfulfilled haves in a row get a peer penalized.
+const DEFAULT_DENIAL_THRESHOLD: u32 = 3;
+
+/// How long a penalized peer is not asked for the wanted blocks.
+const DEFAULT_DENIAL_PENALTY: Duration = Duration::from_secs(10 * 60);
+
+/// How many of the blocks wanted by the peers are served at a time.
+const DEFAULT_MAX_SERVING: usize = 32;
+
+/// Identifies a group of related wanted blocks, such as the blocks of a single DAG, see
+/// [`Bitswap::new_session`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SessionId(u64);
+
+/// The session of the blocks wanted without one.
+const DEFAULT_SESSION: SessionId = SessionId(0);
+
+/// The blocks wanted in a session, and the peers which have them.
+#[derive(Debug, Default)]
+struct Session {
+    /// The wanted blocks of the session, which are also in the `wanted_blocks`.
+    wanted: HashedSet<Cid>,
+    /// The peers which have said they have or have sent blocks of the session, and how many
+    /// blocks they have sent.
+    peers: HashMap<PeerId, u64>,
+}
+
+/// The record of the blocks a peer has said it has, but not yet sent.
+#[derive(Debug, Default)]
+struct Denials {
+    /// The blocks the peer has said it has, and the deadline for sending them.
+    claims: HashedMap<Cid, Instant>,
+    /// The unfulfilled haves since the last block received from the peer or the last penalty.
+    consecutive: u32,
+    /// Until when the peer is not asked for the wanted blocks.
+    penalized_until: Option<Instant>,
+}
+
 /// Network behaviour that handles sending and receiving IPFS blocks.
 pub struct Bitswap {
     /// Queue of events to report to the user.
@@ -100,9 +197,39 @@ pub struct Bitswap {
     pub connected_peers: HashMap<PeerId, Ledger>,
     /// Wanted blocks
     wanted_blocks: HashedMap<Cid, Priority>,
+    /// The peer each of the wanted blocks has been asked from; the other peers are only asked
+    /// whether they have the block.
+    requested: HashedMap<Cid, PeerId>,
+    /// The sessions of the wanted blocks.
+    sessions: HashMap<SessionId, Session>,
+    next_session: u64,
     /// Blocks queued to be sent
     pub queued_blocks: UnboundedSender<(PeerId, Block)>,
     ready_blocks: UnboundedReceiver<(PeerId, Block)>,
+    /// Wanted blocks found not to be available, queued to be told to the peers
+    pub queued_dont_haves: UnboundedSender<(PeerId, Cid)>,
+    ready_dont_haves: UnboundedReceiver<(PeerId, Cid)>,
+    /// Decides the order in which the blocks wanted by the peers are served.
+    strategy: Box<dyn DecisionStrategy>,
+    /// The wants emitted as [`BitswapEvent::ReceivedWant`] and not yet answered with a block or
+    /// a dont have through the queues.
+    serving: HashSet<(PeerId, Cid)>,
+    max_serving: usize,
+    /// The blocks the peers have said they do not have, and when they can be asked for them
+    /// again. Kept over disconnects like the stats.
+    dont_haves: HashMap<PeerId, HashedMap<Cid, Instant>>,
+    dont_have_ttl: Duration,
+    /// The blocks the peers have said they have but not sent, and the peers penalized for it.
+    /// Kept over disconnects like the stats.
+    denials: HashMap<PeerId, Denials>,
+    have_timeout: Duration,
+    denial_threshold: u32,
+    denial_penalty: Duration,
+    /// Whether the protocol with the compressed messages is offered to the peers.
+    compression: bool,
+    /// Batching of the wantlist updates, and the wakeup for sending the next batch.
+    wantlist_batching: Option<WantlistBatchConfig>,
+    wantlist_timer: Option<Pin<Box<tokio::time::Sleep>>>,
     /// Statistics related to peers.
     pub stats: HashMap<PeerId, Arc<Stats>>,
 }
@@ -110,14 +237,32 @@ pub struct Bitswap {
 impl Default for Bitswap {
     fn default() -> Self {
         let (tx, rx) = unbounded();
+        let (dont_have_tx, dont_have_rx) = unbounded();
 
         Bitswap {
             events: Default::default(),
             target_peers: Default::default(),
             connected_peers: Default::default(),
             wanted_blocks: Default::default(),
+            requested: Default::default(),
+            sessions: std::iter::once((DEFAULT_SESSION, Session::default())).collect(),
+            next_session: DEFAULT_SESSION.0 + 1,
             queued_blocks: tx,
             ready_blocks: rx,
+            queued_dont_haves: dont_have_tx,
+            ready_dont_haves: dont_have_rx,
+            strategy: Box::new(RoundRobin::default()),
+            serving: Default::default(),
+            max_serving: DEFAULT_MAX_SERVING,
+            dont_haves: Default::default(),
+            dont_have_ttl: DEFAULT_DONT_HAVE_TTL,
+            denials: Default::default(),
+            have_timeout: DEFAULT_HAVE_TIMEOUT,
+            denial_threshold: DEFAULT_DENIAL_THRESHOLD,
+            denial_penalty: DEFAULT_DENIAL_PENALTY,
+            compression: false,
+            wantlist_batching: Some(WantlistBatchConfig::default()),
+            wantlist_timer: None,
             stats: Default::default(),
         }
     }
@@ -146,10 +291,188 @@ impl Bitswap {
             })
     }
 
+    /// Returns the stats of the exchanges with the peer, kept over disconnects.
+    pub fn peer_stats(&self, peer_id: &PeerId) -> Option<&Stats> {
+        self.stats.get(peer_id).map(|stats| &**stats)
+    }
+
     pub fn peers(&self) -> Vec<PeerId> {
         self.connected_peers.keys().cloned().collect()
     }
 
+    /// Sets how long a peer is not asked again for a block after it has said it does not have
+    /// it.
+    pub fn set_dont_have_ttl(&mut self, ttl: Duration) {
+        self.dont_have_ttl = ttl;
+    }
+
+    /// Sets how long a peer has to send a wanted block after saying it has it.
+    pub fn set_have_timeout(&mut self, timeout: Duration) {
+        self.have_timeout = timeout;
+    }
+
+    /// Sets whether the messages carrying small dag-pb and dag-cbor blocks are compressed with
+    /// zstd for the peers supporting the extended protocol. Disabled by default, as offering the
+    /// extended protocol costs a round trip on every message to the peers not supporting it.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    /// Sets how the wantlist updates sent to each peer are batched, see [`WantlistBatchConfig`].
+    /// `None` sends each update right away. Enabled with the defaults by default.
+    pub fn set_wantlist_batching(&mut self, batching: Option<WantlistBatchConfig>) {
+        self.wantlist_batching = batching;
+    }
+
+    /// Sets how many unfulfilled haves in a row get a peer penalized, and for how long the
+    /// penalized peer is not asked for the wanted blocks.
+    pub fn set_denial_penalty(&mut self, threshold: u32, penalty: Duration) {
+        self.denial_threshold = threshold;
+        self.denial_penalty = penalty;
+    }
+
+    /// Sets the strategy deciding the order in which the blocks wanted by the peers are served,
+    /// [`RoundRobin`] by default. The wants not yet being served are handed over to the new
+    /// strategy.
+    pub fn set_decision_strategy(&mut self, strategy: Box<dyn DecisionStrategy>) {
+        self.strategy = strategy;
+        for (peer_id, ledger) in &self.connected_peers {
+            for (cid, priority) in &ledger.received_want_list {
+                if !self.serving.contains(&(*peer_id, cid.to_owned())) {
+                    self.strategy.want_received(peer_id, cid, *priority);
+                }
+            }
+        }
+    }
+
+    /// Sets how many of the blocks wanted by the peers are served at a time. A want is being
+    /// served from emitting the [`BitswapEvent::ReceivedWant`] until the block or the dont have
+    /// is sent through [`Bitswap::queued_blocks`] or [`Bitswap::queued_dont_haves`].
+    pub fn set_max_serving(&mut self, max: usize) {
+        self.max_serving = max;
+    }
+
+    /// Returns the peers currently penalized for not sending the blocks they said they have.
+    pub fn penalized_peers(&self) -> Vec<PeerId> {
+        let now = Instant::now();
+        self.denials
+            .iter()
+            .filter(|(_, denials)| is_penalized(denials, now))
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+
+    /// Returns true if the peer has recently said it does not have the block, or is penalized.
+    fn is_dont_have(&self, peer_id: &PeerId, cid: &Cid) -> bool {
+        let now = Instant::now();
+        is_dont_have(&self.dont_haves, peer_id, cid, now)
+            || self
+                .denials
+                .get(peer_id)
+                .map(|denials| is_penalized(denials, now))
+                .unwrap_or(false)
+    }
+
+    /// Counts the haves of the peers past their deadline, or all of the haves of the block once
+    /// it is no longer wanted, as unfulfilled, penalizing the peers with too many of them.
+    fn expire_haves(&mut self, cancelled: Option<&Cid>, now: Instant) {
+        let mut rerequested = Vec::new();
+
+        for (peer_id, denials) in self.denials.iter_mut() {
+            let mut unfulfilled = 0u32;
+            let requested = &self.requested;
+
+            denials.claims.retain(|cid, deadline| {
+                if *deadline <= now {
+                    unfulfilled += 1;
+                    if requested.get(cid) == Some(peer_id) {
+                        rerequested.push(cid.to_owned());
+                    }
+                    false
+                } else {
+                    // the haves of a block received from some other peer are not held against
+                    // the peer
+                    Some(cid) != cancelled
+                }
+            });
+
+            if unfulfilled == 0 {
+                continue;
+            }
+
+            let stats = self.stats.get(peer_id);
+            if let Some(peer_stats) = stats {
+                peer_stats.update_unfulfilled_haves(u64::from(unfulfilled));
+            }
+
+            denials.consecutive += unfulfilled;
+            debug!(
+                "bitswap: {} did not send {} blocks it has, {} in a row",
+                peer_id, unfulfilled, denials.consecutive
+            );
+
+            if denials.consecutive >= self.denial_threshold {
+                denials.consecutive = 0;
+                denials.penalized_until = Some(now + self.denial_penalty);
+                if let Some(peer_stats) = stats {
+                    peer_stats.update_denial_penalties();
+                }
+                debug!("bitswap: penalizing {} for unfulfilled haves", peer_id);
+            }
+        }
+
+        for cid in rerequested {
+            self.request_block(&cid);
+        }
+    }
+
+    /// Asks the block from a single peer: one which has said it has the block, or else the one
+    /// which has sent the most blocks of the sessions wanting the block, or else any peer not
+    /// known to lack it.
+    fn request_block(&mut self, cid: &Cid) {
+        self.requested.remove(cid);
+
+        let priority = match self.wanted_blocks.get(cid) {
+            Some(priority) => *priority,
+            None => return,
+        };
+
+        let eligible = |peer_id: &PeerId| {
+            self.connected_peers.contains_key(peer_id) && !self.is_dont_have(peer_id, cid)
+        };
+
+        let claimer = self
+            .denials
+            .iter()
+            .find(|(peer_id, denials)| denials.claims.contains_key(cid) && eligible(peer_id))
+            .map(|(peer_id, _)| *peer_id);
+
+        let target = claimer
+            .or_else(|| {
+                self.sessions
+                    .values()
+                    .filter(|session| session.wanted.contains(cid))
+                    .flat_map(|session| session.peers.iter())
+                    .filter(|(peer_id, _)| eligible(peer_id))
+                    .max_by_key(|(_, blocks)| **blocks)
+                    .map(|(peer_id, _)| *peer_id)
+            })
+            .or_else(|| {
+                self.connected_peers
+                    .keys()
+                    .find(|peer_id| eligible(peer_id))
+                    .copied()
+            });
+
+        if let Some(peer_id) = target {
+            trace!("bitswap: requesting {} from {}", cid, peer_id);
+            if let Some(ledger) = self.connected_peers.get_mut(&peer_id) {
+                ledger.want_block(cid, priority);
+            }
+            self.requested.insert(cid.to_owned(), peer_id);
+        }
+    }
+
     /// Connect to peer.
     ///
     /// Called from Kademlia behaviour.
@@ -171,37 +494,108 @@ impl Bitswap {
     pub fn send_block(&mut self, peer_id: PeerId, block: Block) {
         trace!("queueing block to be sent to {}: {}", peer_id, block.cid);
         if let Some(ledger) = self.connected_peers.get_mut(&peer_id) {
+            self.strategy.block_sent(&peer_id, block.data().len());
             ledger.add_block(block);
         }
     }
 
-    /// Sends the wantlist to the peer.
+    /// Queues the wantlist to the peer, asking the blocks not yet requested from any other peer,
+    /// and whether the peer has the rest of them.
     fn send_want_list(&mut self, peer_id: PeerId) {
-        if !self.wanted_blocks.is_empty() {
-            // FIXME: this can produce too long a message
-            // FIXME: we should shard these across all of our peers by some logic; also, peers may
-            // have been discovered to provide some specific wantlist item
-            let mut message = Message::default();
-            for (cid, priority) in &self.wanted_blocks {
-                message.want_block(cid, *priority);
+        // FIXME: this can produce too long a message
+        let wants = self
+            .wanted_blocks
+            .iter()
+            .filter(|(cid, _)| !self.is_dont_have(&peer_id, cid))
+            .map(|(cid, priority)| (cid.to_owned(), *priority, self.requested.contains_key(cid)))
+            .collect::<Vec<_>>();
+
+        let ledger = match self.connected_peers.get_mut(&peer_id) {
+            Some(ledger) => ledger,
+            None => return,
+        };
+
+        for (cid, priority, requested) in wants {
+            if requested {
+                ledger.want_have(&cid, priority);
+            } else {
+                ledger.want_block(&cid, priority);
+                self.requested.insert(cid, peer_id);
             }
-            self.events
-                .push_back(NetworkBehaviourAction::NotifyHandler {
-                    peer_id,
-                    event: message,
-                    handler: NotifyHandler::Any,
-                });
         }
     }
 
-    /// Queues the wanted block for all peers.
+    /// Queues the wanted block for all peers, in the default session shared by the blocks
+    /// wanted without a session.
     ///
     /// A user request
     pub fn want_block(&mut self, cid: Cid, priority: Priority) {
-        for (_peer_id, ledger) in self.connected_peers.iter_mut() {
-            ledger.want_block(&cid, priority);
+        self.want_block_in(DEFAULT_SESSION, cid, priority);
+    }
+
+    /// Starts a new session, for the peers which have sent the blocks wanted in the session to
+    /// be asked first for the other blocks of the session.
+    pub fn new_session(&mut self) -> SessionId {
+        let id = SessionId(self.next_session);
+        self.next_session += 1;
+        self.sessions.insert(id, Session::default());
+        id
+    }
+
+    /// Ends the session, cancelling its wanted blocks not wanted in any other session. The
+    /// default session cannot be ended.
+    pub fn close_session(&mut self, id: SessionId) {
+        if id == DEFAULT_SESSION {
+            return;
+        }
+
+        if let Some(session) = self.sessions.remove(&id) {
+            for cid in session.wanted {
+                if !self
+                    .sessions
+                    .values()
+                    .any(|session| session.wanted.contains(&cid))
+                {
+                    self.cancel_block(&cid);
+                }
+            }
+        }
+    }
+
+    /// Queues the wanted block for all peers, in the session which has not been ended. The block
+    /// is asked from a single peer, and the other peers are asked whether they have it.
+    pub fn want_block_in(&mut self, id: SessionId, cid: Cid, priority: Priority) {
+        let session = match self.sessions.get_mut(&id) {
+            Some(session) => session,
+            None => return,
+        };
+        session.wanted.insert(cid.clone());
+
+        self.wanted_blocks.insert(cid.clone(), priority);
+
+        if self.requested.contains_key(&cid) {
+            return;
+        }
+
+        self.request_block(&cid);
+
+        let now = Instant::now();
+        let requested = self.requested.get(&cid);
+        for (peer_id, ledger) in self.connected_peers.iter_mut() {
+            if Some(peer_id) == requested {
+                continue;
+            }
+            let penalized = self
+                .denials
+                .get(peer_id)
+                .map(|denials| is_penalized(denials, now))
+                .unwrap_or(false);
+            // not asked again until the dont have or the penalty expires and the block is wanted
+            // again
+            if !penalized && !is_dont_have(&self.dont_haves, peer_id, &cid, now) {
+                ledger.want_have(&cid, priority);
+            }
         }
-        self.wanted_blocks.insert(cid, priority);
     }
 
     /// Removes the block from our want list and updates all peers.
@@ -212,7 +606,13 @@ impl Bitswap {
         for (_peer_id, ledger) in self.connected_peers.iter_mut() {
             ledger.cancel_block(cid);
         }
-        self.wanted_blocks.remove(cid);
+        for session in self.sessions.values_mut() {
+            session.wanted.remove(cid);
+        }
+        self.requested.remove(cid);
+        if self.wanted_blocks.remove(cid).is_some() {
+            self.expire_haves(Some(cid), Instant::now());
+        }
     }
 }
 
@@ -222,7 +622,10 @@ impl NetworkBehaviour for Bitswap {
 
     fn new_handler(&mut self) -> Self::ConnectionHandler {
         debug!("bitswap: new_handler");
-        Default::default()
+        OneShotHandler::new(
+            SubstreamProtocol::new(BitswapConfig::new(self.compression), ()),
+            OneShotHandlerConfig::default(),
+        )
     }
 
     fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
@@ -255,8 +658,20 @@ impl NetworkBehaviour for Bitswap {
     ) {
         debug!("bitswap: inject_disconnected {:?}", peer_id);
         self.connected_peers.remove(peer_id);
+        self.strategy.peer_disconnected(peer_id);
+        self.serving.retain(|(serving, _)| serving != peer_id);
         // the related stats are not dropped, so that they
         // persist for peers regardless of disconnects
+
+        let orphaned = self
+            .requested
+            .iter()
+            .filter(|(_, requested)| *requested == peer_id)
+            .map(|(cid, _)| cid.to_owned())
+            .collect::<Vec<_>>();
+        for cid in orphaned {
+            self.request_block(&cid);
+        }
     }
 
     fn inject_event(&mut self, source: PeerId, _connection: ConnectionId, message: MessageWrapper) {
@@ -271,6 +686,10 @@ impl NetworkBehaviour for Bitswap {
 
         debug!("bitswap: inject_event from {}: {:?}", source, message);
 
+        if let Some(peer_stats) = self.stats.get(&source) {
+            peer_stats.update_received_messages();
+        }
+
         let current_wantlist = self.local_wantlist();
 
         let ledger = self
@@ -281,6 +700,8 @@ impl NetworkBehaviour for Bitswap {
         // Process the incoming cancel list.
         for cid in message.cancel() {
             ledger.received_want_list.remove(cid);
+            ledger.dont_have_wanted.remove(cid);
+            self.strategy.want_cancelled(&source, cid);
 
             let event = BitswapEvent::ReceivedCancel(source, cid.clone());
             self.events
@@ -294,14 +715,93 @@ impl NetworkBehaviour for Bitswap {
             .filter(|&(cid, _)| !current_wantlist.iter().map(|(c, _)| c).any(|c| c == cid))
         {
             ledger.received_want_list.insert(cid.to_owned(), *priority);
+            if message.wants_dont_have(cid) {
+                ledger.dont_have_wanted.insert(cid.to_owned());
+            }
+            if message.wants_have(cid) {
+                ledger.have_wanted.insert(cid.to_owned());
+            } else {
+                ledger.have_wanted.remove(cid);
+            }
 
-            let event = BitswapEvent::ReceivedWant(source, cid.clone(), *priority);
-            self.events
-                .push_back(NetworkBehaviourAction::GenerateEvent(event));
+            if !self.serving.contains(&(source, cid.to_owned())) {
+                self.strategy.want_received(&source, cid, *priority);
+            }
+        }
+
+        // Remember the blocks the peer does not have, not to ask for them again for a while.
+        if !message.dont_have().is_empty() {
+            let now = Instant::now();
+            let expires = now + self.dont_have_ttl;
+            let dont_haves = self.dont_haves.entry(source).or_default();
+            dont_haves.retain(|_, expires| *expires > now);
+            for cid in message.dont_have() {
+                trace!("bitswap: {} does not have {}", source, cid);
+                dont_haves.insert(cid.to_owned(), expires);
+            }
+
+            // ask the blocks requested from the peer from some other peer
+            for cid in message.dont_have() {
+                if self.requested.get(cid) == Some(&source) {
+                    self.request_block(cid);
+                }
+            }
+        }
+
+        // Remember the wanted blocks the peer has, to be sent before the deadline.
+        if !message.have().is_empty() {
+            let deadline = Instant::now() + self.have_timeout;
+            let denials = self.denials.entry(source).or_default();
+            for cid in message.have() {
+                if self.wanted_blocks.contains_key(cid) {
+                    trace!("bitswap: {} has {}", source, cid);
+                    denials.claims.entry(cid.to_owned()).or_insert(deadline);
+                }
+            }
+
+            for cid in message.have() {
+                let priority = match self.wanted_blocks.get(cid) {
+                    Some(priority) => *priority,
+                    None => continue,
+                };
+
+                for session in self.sessions.values_mut() {
+                    if session.wanted.contains(cid) {
+                        session.peers.entry(source).or_default();
+                    }
+                }
+
+                // the block is asked from the peer unless already asked from a peer which has
+                // said it has the block
+                let claimed = self.requested.get(cid).map(|peer_id| {
+                    self.denials
+                        .get(peer_id)
+                        .map(|denials| denials.claims.contains_key(cid))
+                        .unwrap_or(false)
+                });
+                if claimed != Some(true) && !self.is_dont_have(&source, cid) {
+                    if let Some(ledger) = self.connected_peers.get_mut(&source) {
+                        ledger.want_block(cid, priority);
+                        self.requested.insert(cid.to_owned(), source);
+                    }
+                }
+            }
         }
 
         // Process the incoming blocks.
         for block in mem::take(&mut message.blocks) {
+            if let Some(denials) = self.denials.get_mut(&source) {
+                if denials.claims.remove(block.cid()).is_some() {
+                    denials.consecutive = 0;
+                }
+            }
+
+            for session in self.sessions.values_mut() {
+                if session.wanted.contains(block.cid()) {
+                    *session.peers.entry(source).or_default() += 1;
+                }
+            }
+
             self.cancel_block(block.cid());
 
             let event = BitswapEvent::ReceivedBlock(source, block);
@@ -318,18 +818,49 @@ impl NetworkBehaviour for Bitswap {
     ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
         use futures::stream::StreamExt;
 
+        self.expire_haves(None, Instant::now());
+
         while let Poll::Ready(Some((peer_id, block))) = self.ready_blocks.poll_next_unpin(ctx) {
+            self.serving.remove(&(peer_id, block.cid().to_owned()));
             self.send_block(peer_id, block);
         }
 
+        while let Poll::Ready(Some((peer_id, cid))) = self.ready_dont_haves.poll_next_unpin(ctx) {
+            if let Some(ledger) = self.connected_peers.get_mut(&peer_id) {
+                ledger.dont_have(&cid);
+            }
+            self.serving.remove(&(peer_id, cid));
+        }
+
+        while self.serving.len() < self.max_serving {
+            let (peer_id, cid, priority) = match self.strategy.next_want() {
+                Some(want) => want,
+                None => break,
+            };
+            self.serving.insert((peer_id, cid.clone()));
+            let event = BitswapEvent::ReceivedWant(peer_id, cid, priority);
+            self.events
+                .push_back(NetworkBehaviourAction::GenerateEvent(event));
+        }
+
         if let Some(event) = self.events.pop_front() {
             return Poll::Ready(event);
         }
 
+        let now = Instant::now();
+        let batching = self.wantlist_batching.as_ref();
+
         for (peer_id, ledger) in &mut self.connected_peers {
-            if let Some(message) = ledger.send() {
+            if let Some(mut message) = ledger.send(batching, now) {
+                message.compression = self.compression;
                 if let Some(peer_stats) = self.stats.get_mut(peer_id) {
-                    peer_stats.update_outgoing(message.blocks.len() as u64);
+                    let bytes = message
+                        .blocks
+                        .iter()
+                        .map(|block| block.data().len() as u64)
+                        .sum();
+                    peer_stats.update_outgoing(message.blocks.len() as u64, bytes);
+                    peer_stats.update_sent_messages();
                 }
 
                 return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
@@ -339,6 +870,50 @@ impl NetworkBehaviour for Bitswap {
                 });
             }
         }
+
+        // wake up for sending the next of the batched wantlist updates
+        let next = batching.and_then(|batching| {
+            self.connected_peers
+                .values()
+                .filter_map(|ledger| ledger.wantlist_deadline(batching))
+                .min()
+        });
+        match next {
+            Some(next) => {
+                let next = tokio::time::Instant::from_std(next);
+                let timer = self
+                    .wantlist_timer
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(next)));
+                if timer.deadline() != next {
+                    timer.as_mut().reset(next);
+                }
+                if timer.as_mut().poll(ctx).is_ready() {
+                    ctx.waker().wake_by_ref();
+                }
+            }
+            None => self.wantlist_timer = None,
+        }
+
         Poll::Pending
     }
 }
+
+fn is_dont_have(
+    dont_haves: &HashMap<PeerId, HashedMap<Cid, Instant>>,
+    peer_id: &PeerId,
+    cid: &Cid,
+    now: Instant,
+) -> bool {
+    dont_haves
+        .get(peer_id)
+        .and_then(|dont_haves| dont_haves.get(cid))
+        .map(|expires| *expires > now)
+        .unwrap_or(false)
+}
+
+fn is_penalized(denials: &Denials, now: Instant) -> bool {
+    denials
+        .penalized_until
+        .map(|until| until > now)
+        .unwrap_or(false)
+}
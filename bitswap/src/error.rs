@@ -8,4 +8,6 @@ pub enum BitswapError {
     ProtobufError(#[from] prost::DecodeError),
     #[error("Error while parsing cid: {0}")]
     Cid(#[from] cid::Error),
+    #[error("Error while decompressing bitswap message: {0}")]
+    Compression(&'static str),
 }
@@ -7,9 +7,39 @@ use core::convert::TryFrom;
 use hash_hasher::{HashedMap, HashedSet};
 use prost::Message as ProstMessage;
 use std::mem;
+use std::time::{Duration, Instant};
 
 pub type Priority = i32;
 
+/// Batching of the wantlist updates sent to each peer, see
+/// [`Bitswap::set_wantlist_batching`](crate::Bitswap::set_wantlist_batching).
+///
+/// The wanted and cancelled blocks are gathered into a single message per peer until no more
+/// changes have been made for the `debounce` time, but at most for the `max_delay`, or until the
+/// message has `max_entries` of them. The messages carrying blocks or block presences are sent
+/// right away along with the gathered changes. A block wanted and cancelled within the same
+/// batch is not sent at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WantlistBatchConfig {
+    /// The number of the wanted and cancelled blocks sending the batch right away.
+    pub max_entries: usize,
+    /// How long the batch waits for more changes after the latest one.
+    pub debounce: Duration,
+    /// The longest time a change waits for the batch to be sent.
+    pub max_delay: Duration,
+}
+
+impl Default for WantlistBatchConfig {
+    fn default() -> Self {
+        // same as go-ipfs
+        WantlistBatchConfig {
+            max_entries: 256,
+            debounce: Duration::from_millis(1),
+            max_delay: Duration::from_millis(20),
+        }
+    }
+}
+
 /// The Ledger contains the history of transactions with a peer.
 #[derive(Debug, Default)]
 pub struct Ledger {
@@ -17,8 +47,15 @@ pub struct Ledger {
     sent_want_list: HashedMap<Cid, Priority>,
     /// The list of wanted blocks received from the peer.
     pub(crate) received_want_list: HashedMap<Cid, Priority>,
+    /// The wanted blocks for which the peer asked to be told if they are not available.
+    pub(crate) dont_have_wanted: HashedSet<Cid>,
+    /// The wanted blocks for which the peer asked to be told if they are available, instead of
+    /// being sent the blocks.
+    pub(crate) have_wanted: HashedSet<Cid>,
     /// Queued message.
     message: Message,
+    /// When the first and the latest of the queued wantlist changes were made.
+    wantlist_changes: Option<(Instant, Instant)>,
 }
 
 impl Ledger {
@@ -27,16 +64,63 @@ impl Ledger {
         Self::default()
     }
 
+    /// Sends the block to the peer, or tells the peer it is available if the peer only asked
+    /// whether it is.
     pub fn add_block(&mut self, block: Block) {
-        self.message.add_block(block);
+        if self.have_wanted.remove(block.cid()) {
+            self.received_want_list.remove(block.cid());
+            self.dont_have_wanted.remove(block.cid());
+            self.message.add_have(block.cid());
+        } else {
+            self.message.add_block(block);
+        }
     }
 
     pub fn want_block(&mut self, cid: &Cid, priority: Priority) {
+        self.message.remove_cancel(cid);
         self.message.want_block(cid, priority);
+        self.wantlist_changed();
+    }
+
+    /// Asks the peer to tell if it has the block, instead of sending it.
+    pub fn want_have(&mut self, cid: &Cid, priority: Priority) {
+        self.message.remove_cancel(cid);
+        self.message.want_have(cid, priority);
+        self.wantlist_changed();
     }
 
+    /// Cancels the wanted block. The peer is only told to cancel the wants it has been sent, the
+    /// queued want is dropped.
     pub fn cancel_block(&mut self, cid: &Cid) {
-        self.message.cancel_block(cid);
+        self.message.remove_want_block(cid);
+        if self.sent_want_list.contains_key(cid) {
+            self.message.cancel_block(cid);
+            self.wantlist_changed();
+        }
+    }
+
+    fn wantlist_changed(&mut self) {
+        let now = Instant::now();
+        let first = self.wantlist_changes.map(|(first, _)| first).unwrap_or(now);
+        self.wantlist_changes = Some((first, now));
+    }
+
+    /// Returns when the queued wantlist changes are to be sent with the `batching`, if there are
+    /// any.
+    pub fn wantlist_deadline(&self, batching: &WantlistBatchConfig) -> Option<Instant> {
+        if self.message.is_empty() {
+            return None;
+        }
+        let (first, latest) = self.wantlist_changes?;
+        Some((latest + batching.debounce).min(first + batching.max_delay))
+    }
+
+    /// Tells the peer that the block it wants is not available, if it asked to be told.
+    pub fn dont_have(&mut self, cid: &Cid) {
+        self.have_wanted.remove(cid);
+        if self.dont_have_wanted.remove(cid) {
+            self.message.add_dont_have(cid);
+        }
     }
 
     /// Returns the blocks wanted by the peer in unspecified order
@@ -47,10 +131,31 @@ impl Ledger {
             .collect()
     }
 
-    pub fn send(&mut self) -> Option<Message> {
+    /// Returns the queued message to be sent, unless it only has wantlist changes which are
+    /// still being batched.
+    pub fn send(
+        &mut self,
+        batching: Option<&WantlistBatchConfig>,
+        now: Instant,
+    ) -> Option<Message> {
         if self.message.is_empty() {
+            self.wantlist_changes = None;
             return None;
         }
+
+        if let Some(batching) = batching {
+            let batched = self.message.is_wantlist_only()
+                && self.message.want.len() + self.message.cancel.len() < batching.max_entries
+                && self
+                    .wantlist_deadline(batching)
+                    .map(|deadline| now < deadline)
+                    .unwrap_or(false);
+            if batched {
+                return None;
+            }
+        }
+        self.wantlist_changes = None;
+
         // FIXME: this might produce too large message
         for cid in self.message.cancel() {
             self.sent_want_list.remove(cid);
@@ -68,18 +173,37 @@ impl Ledger {
 pub struct Message {
     /// List of wanted blocks.
     want: HashedMap<Cid, Priority>,
+    /// The wanted blocks for which only a HAVE or a DONT_HAVE is asked, a subset of `want`.
+    want_have: HashedSet<Cid>,
     /// List of blocks to cancel.
     cancel: HashedSet<Cid>,
     /// Wheather it is the full list of wanted blocks.
     full: bool,
     /// List of blocks to send.
     pub(crate) blocks: Vec<Block>,
+    /// The wanted blocks for which a DONT_HAVE is asked if they are not available.
+    send_dont_have: HashedSet<Cid>,
+    /// List of the wanted blocks which are not available.
+    dont_have: HashedSet<Cid>,
+    /// List of the wanted blocks which are available, sent instead of the blocks.
+    have: HashedSet<Cid>,
+    /// Whether the compressed protocol is offered when sending the message.
+    pub(crate) compression: bool,
 }
 
 impl Message {
     /// Checks whether the queued message is empty.
     pub fn is_empty(&self) -> bool {
-        self.want.is_empty() && self.cancel.is_empty() && self.blocks.is_empty()
+        self.want.is_empty()
+            && self.cancel.is_empty()
+            && self.blocks.is_empty()
+            && self.dont_have.is_empty()
+            && self.have.is_empty()
+    }
+
+    /// Checks whether the message only has wanted or cancelled blocks.
+    pub fn is_wantlist_only(&self) -> bool {
+        self.blocks.is_empty() && self.dont_have.is_empty() && self.have.is_empty()
     }
 
     /// Returns the list of blocks.
@@ -97,6 +221,36 @@ impl Message {
         &self.cancel
     }
 
+    /// Returns the list of the wanted blocks the sender does not have.
+    pub fn dont_have(&self) -> &HashedSet<Cid> {
+        &self.dont_have
+    }
+
+    /// Returns the list of the wanted blocks the sender has.
+    pub fn have(&self) -> &HashedSet<Cid> {
+        &self.have
+    }
+
+    /// Returns true if the sender only asked to be told whether the wanted block is available.
+    pub fn wants_have(&self, cid: &Cid) -> bool {
+        self.want_have.contains(cid)
+    }
+
+    /// Returns true if the sender asked to be told when the wanted block is not available.
+    pub fn wants_dont_have(&self, cid: &Cid) -> bool {
+        self.send_dont_have.contains(cid)
+    }
+
+    /// Adds a block to the list of the wanted blocks which are not available.
+    pub fn add_dont_have(&mut self, cid: &Cid) {
+        self.dont_have.insert(cid.to_owned());
+    }
+
+    /// Adds a block to the list of the wanted blocks which are available.
+    pub fn add_have(&mut self, cid: &Cid) {
+        self.have.insert(cid.to_owned());
+    }
+
     /// Adds a `Block` to the message.
     pub fn add_block(&mut self, block: Block) {
         self.blocks.push(block);
@@ -109,6 +263,16 @@ impl Message {
 
     /// Adds a block to the want list.
     pub fn want_block(&mut self, cid: &Cid, priority: Priority) {
+        self.want_have.remove(cid);
+        self.want.insert(cid.to_owned(), priority);
+    }
+
+    /// Adds a block to the want list, asking only whether it is available unless the block
+    /// itself is already asked for.
+    pub fn want_have(&mut self, cid: &Cid, priority: Priority) {
+        if !self.want.contains_key(cid) {
+            self.want_have.insert(cid.to_owned());
+        }
         self.want.insert(cid.to_owned(), priority);
     }
 
@@ -117,9 +281,14 @@ impl Message {
         self.cancel.insert(cid.to_owned());
     }
 
+    /// Removes the block from the cancel list.
+    pub fn remove_cancel(&mut self, cid: &Cid) {
+        self.cancel.remove(cid);
+    }
+
     /// Removes the block from the want list.
-    #[allow(unused)]
     pub fn remove_want_block(&mut self, cid: &Cid) {
+        self.want_have.remove(cid);
         self.want.remove(cid);
     }
 }
@@ -129,9 +298,17 @@ impl From<&Message> for Vec<u8> {
         let mut proto = bitswap_pb::Message::default();
         let mut wantlist = bitswap_pb::message::Wantlist::default();
         for (cid, priority) in val.want() {
+            let want_type = if val.wants_have(cid) {
+                bitswap_pb::message::wantlist::WantType::Have
+            } else {
+                bitswap_pb::message::wantlist::WantType::Block
+            };
             let entry = bitswap_pb::message::wantlist::Entry {
                 block: cid.to_bytes(),
                 priority: *priority,
+                want_type: want_type as i32,
+                // the peers supporting it tell right away when they do not have the block
+                send_dont_have: true,
                 ..Default::default()
             };
             wantlist.entries.push(entry);
@@ -151,6 +328,20 @@ impl From<&Message> for Vec<u8> {
             };
             proto.payload.push(payload);
         }
+        for cid in val.dont_have() {
+            let presence = bitswap_pb::message::BlockPresence {
+                cid: cid.to_bytes(),
+                r#type: bitswap_pb::message::BlockPresenceType::DontHave as i32,
+            };
+            proto.block_presences.push(presence);
+        }
+        for cid in val.have() {
+            let presence = bitswap_pb::message::BlockPresence {
+                cid: cid.to_bytes(),
+                r#type: bitswap_pb::message::BlockPresenceType::Have as i32,
+            };
+            proto.block_presences.push(presence);
+        }
         if !wantlist.entries.is_empty() {
             proto.wantlist = Some(wantlist);
         }
@@ -190,7 +381,21 @@ impl TryFrom<&[u8]> for Message {
             if entry.cancel {
                 message.cancel_block(&cid);
             } else {
-                message.want_block(&cid, entry.priority);
+                if entry.want_type == bitswap_pb::message::wantlist::WantType::Have as i32 {
+                    message.want_have(&cid, entry.priority);
+                } else {
+                    message.want_block(&cid, entry.priority);
+                }
+                if entry.send_dont_have {
+                    message.send_dont_have.insert(cid);
+                }
+            }
+        }
+        for presence in proto.block_presences {
+            if presence.r#type == bitswap_pb::message::BlockPresenceType::DontHave as i32 {
+                message.add_dont_have(&Cid::try_from(presence.cid)?);
+            } else if presence.r#type == bitswap_pb::message::BlockPresenceType::Have as i32 {
+                message.add_have(&Cid::try_from(presence.cid)?);
             }
         }
         for payload in proto.payload {
@@ -215,7 +420,11 @@ impl std::fmt::Debug for Message {
             } else {
                 write!(fmt, ", ")?;
             }
-            write!(fmt, "want: {} {}", cid, priority)?;
+            if self.wants_have(cid) {
+                write!(fmt, "want_have: {} {}", cid, priority)?;
+            } else {
+                write!(fmt, "want: {} {}", cid, priority)?;
+            }
         }
         for cid in self.cancel() {
             if first {
@@ -233,6 +442,22 @@ impl std::fmt::Debug for Message {
             }
             write!(fmt, "block: {}", block.cid())?;
         }
+        for cid in self.dont_have() {
+            if first {
+                first = false;
+            } else {
+                write!(fmt, ", ")?;
+            }
+            write!(fmt, "dont_have: {}", cid)?;
+        }
+        for cid in self.have() {
+            if first {
+                first = false;
+            } else {
+                write!(fmt, ", ")?;
+            }
+            write!(fmt, "have: {}", cid)?;
+        }
 
         if first {
             write!(fmt, "(empty message)")?;
@@ -8,11 +8,13 @@ mod error;
 mod ledger;
 mod prefix;
 mod protocol;
+mod strategy;
 
-pub use self::behaviour::{Bitswap, BitswapEvent, Stats};
+pub use self::behaviour::{Bitswap, BitswapEvent, SessionId, Stats};
 pub use self::block::Block;
 pub use self::error::BitswapError;
-pub use self::ledger::Priority;
+pub use self::ledger::{Priority, WantlistBatchConfig};
+pub use self::strategy::{DecisionStrategy, RoundRobin};
 
 mod bitswap_pb {
     include!(concat!(env!("OUT_DIR"), "/bitswap_pb.rs"));
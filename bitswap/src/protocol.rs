@@ -5,32 +5,68 @@ use crate::error::BitswapError;
 ///
 /// - TODO
 use crate::ledger::Message;
+use cid::Codec;
 use core::future::Future;
-use core::iter;
 use core::pin::Pin;
 use futures::{
     io::{AsyncRead, AsyncWrite},
     AsyncWriteExt,
 };
 use libp2p_core::{upgrade, InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use std::borrow::Cow;
 use std::io;
+use std::vec;
 
 // Undocumented, but according to JS the bitswap messages have a max size of 512*1024 bytes
 // https://github.com/ipfs/js-ipfs-bitswap/blob/d8f80408aadab94c962f6b88f343eb9f39fa0fcc/src/decision-engine/index.js#L16
 const MAX_BUF_SIZE: usize = 524_288;
 
+// b"/ipfs/bitswap", b"/ipfs/bitswap/1.0.0"
+const PROTOCOL: &[u8] = b"/ipfs/bitswap/1.1.0";
+
+/// Adds the WANT_HAVE entries and the HAVE and DONT_HAVE presences to the 1.1.0 messages, which
+/// the 1.1.0 peers ignore, treating the WANT_HAVE entries as wanting the blocks.
+const PROTOCOL_1_2: &[u8] = b"/ipfs/bitswap/1.2.0";
+
+/// The bitswap 1.2.0 messages prefixed by a byte telling if the rest of the message is
+/// compressed with zstd (1) or not (0). The decompressed messages have the same maximum size as
+/// the uncompressed ones.
+const ZSTD_PROTOCOL: &[u8] = b"/rust-ipfs/bitswap-zstd/1.2.0";
+
+/// The compression level of zstd, the default of the zstd cli.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The largest block considered small enough to benefit from the compression.
+const MAX_COMPRESSED_BLOCK_SIZE: usize = 16 * 1024;
+
 type FutureResult<T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + Send>>;
 
 #[derive(Clone, Copy, Debug, Default)]
-pub struct BitswapConfig;
+pub struct BitswapConfig {
+    compression: bool,
+}
+
+impl BitswapConfig {
+    pub fn new(compression: bool) -> Self {
+        BitswapConfig { compression }
+    }
+}
+
+/// The supported protocols in the order of preference.
+fn protocols(compression: bool) -> vec::IntoIter<&'static [u8]> {
+    if compression {
+        vec![ZSTD_PROTOCOL, PROTOCOL_1_2, PROTOCOL].into_iter()
+    } else {
+        vec![PROTOCOL_1_2, PROTOCOL].into_iter()
+    }
+}
 
 impl UpgradeInfo for BitswapConfig {
     type Info = &'static [u8];
-    type InfoIter = iter::Once<Self::Info>;
+    type InfoIter = vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        // b"/ipfs/bitswap", b"/ipfs/bitswap/1.0.0"
-        iter::once(b"/ipfs/bitswap/1.1.0")
+        protocols(self.compression)
     }
 }
 
@@ -43,9 +79,14 @@ where
     type Future = FutureResult<Self::Output, Self::Error>;
 
     #[inline]
-    fn upgrade_inbound(self, mut socket: TSocket, _info: Self::Info) -> Self::Future {
+    fn upgrade_inbound(self, mut socket: TSocket, info: Self::Info) -> Self::Future {
         Box::pin(async move {
             let packet = upgrade::read_length_prefixed(&mut socket, MAX_BUF_SIZE).await?;
+            let packet = if info == ZSTD_PROTOCOL {
+                decompress(&packet)?
+            } else {
+                Cow::Borrowed(&packet[..])
+            };
             let message = Message::from_bytes(&packet)?;
             Ok(message)
         })
@@ -54,11 +95,10 @@ where
 
 impl UpgradeInfo for Message {
     type Info = &'static [u8];
-    type InfoIter = iter::Once<Self::Info>;
+    type InfoIter = vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        // b"/ipfs/bitswap", b"/ipfs/bitswap/1.0.0"
-        iter::once(b"/ipfs/bitswap/1.1.0")
+        protocols(self.compression)
     }
 }
 
@@ -71,15 +111,59 @@ where
     type Future = FutureResult<Self::Output, Self::Error>;
 
     #[inline]
-    fn upgrade_outbound(self, mut socket: TSocket, _info: Self::Info) -> Self::Future {
+    fn upgrade_outbound(self, mut socket: TSocket, info: Self::Info) -> Self::Future {
         Box::pin(async move {
             let bytes = self.to_bytes();
+            let bytes = if info == ZSTD_PROTOCOL {
+                compress(&self, bytes)
+            } else {
+                bytes
+            };
             upgrade::write_length_prefixed(&mut socket, bytes).await?;
             socket.close().await
         })
     }
 }
 
+/// Prefixes the encoded `message` for the [`ZSTD_PROTOCOL`], compressing it if it carries small
+/// dag-pb or dag-cbor blocks and the compression makes it smaller.
+fn compress(message: &Message, bytes: Vec<u8>) -> Vec<u8> {
+    let compressible = message.blocks.iter().any(|block| {
+        matches!(block.cid.codec(), Codec::DagProtobuf | Codec::DagCBOR)
+            && block.data.len() <= MAX_COMPRESSED_BLOCK_SIZE
+    });
+
+    if compressible {
+        match zstd::bulk::compress(&bytes, ZSTD_LEVEL) {
+            Ok(compressed) if compressed.len() < bytes.len() => {
+                let mut out = Vec::with_capacity(compressed.len() + 1);
+                out.push(1);
+                out.extend_from_slice(&compressed);
+                return out;
+            }
+            Ok(_) => {}
+            Err(e) => debug!("bitswap: failed to compress a message: {}", e),
+        }
+    }
+
+    let mut out = Vec::with_capacity(bytes.len() + 1);
+    out.push(0);
+    out.extend_from_slice(&bytes);
+    out
+}
+
+/// Returns the encoded message received over the [`ZSTD_PROTOCOL`].
+fn decompress(packet: &[u8]) -> Result<Cow<'_, [u8]>, BitswapError> {
+    match packet.split_first() {
+        Some((0, bytes)) => Ok(Cow::Borrowed(bytes)),
+        Some((1, compressed)) => zstd::bulk::decompress(compressed, MAX_BUF_SIZE)
+            .map(Cow::Owned)
+            .map_err(|_| BitswapError::Compression("invalid or too large zstd frame")),
+        Some(_) => Err(BitswapError::Compression("unknown compression")),
+        None => Err(BitswapError::Compression("empty message")),
+    }
+}
+
 /// An object to facilitate communication between the `OneShotHandler` and the `BitswapHandler`.
 #[derive(Debug)]
 pub enum MessageWrapper {
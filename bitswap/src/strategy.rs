@@ -0,0 +1,116 @@
+//! The order in which the blocks wanted by the peers are served, see [`DecisionStrategy`].
+use crate::ledger::Priority;
+use cid::Cid;
+use hash_hasher::HashedMap;
+use libp2p_core::PeerId;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Decides which of the blocks wanted by the peers is served next.
+///
+/// The [`Bitswap`](crate::Bitswap) tells the strategy about the wants received and cancelled,
+/// and asks it for the next want to serve whenever fewer than the
+/// [maximum](crate::Bitswap::set_max_serving) wants are being served. A want is served by
+/// emitting it as [`BitswapEvent::ReceivedWant`](crate::BitswapEvent::ReceivedWant), after which
+/// it is no longer tracked by the strategy. The wants of the disconnected peers and the wants
+/// being served are never asked from the strategy again, so they do not need to be tracked.
+pub trait DecisionStrategy: Send + 'static {
+    /// The peer wants the block, or has changed the priority of a want received earlier.
+    fn want_received(&mut self, peer_id: &PeerId, cid: &Cid, priority: Priority);
+
+    /// The peer no longer wants the block.
+    fn want_cancelled(&mut self, peer_id: &PeerId, cid: &Cid);
+
+    /// The peer has disconnected, cancelling all of its wants.
+    fn peer_disconnected(&mut self, peer_id: &PeerId);
+
+    /// Returns the next want to serve, removing it from the strategy, or `None` if there are no
+    /// wants left.
+    fn next_want(&mut self) -> Option<(PeerId, Cid, Priority)>;
+
+    /// A block of `bytes` has been queued to be sent to the peer, for the strategies keeping
+    /// count of what each peer has been sent.
+    fn block_sent(&mut self, _peer_id: &PeerId, _bytes: usize) {}
+}
+
+/// The position of a want in the queue of the peer: the highest priority first, and the earlier
+/// of the wants of the same priority first.
+type QueueKey = (Reverse<Priority>, u64);
+
+#[derive(Debug, Default)]
+struct PeerWants {
+    queue: BTreeMap<QueueKey, Cid>,
+    keys: HashedMap<Cid, QueueKey>,
+}
+
+impl PeerWants {
+    fn remove(&mut self, cid: &Cid) {
+        if let Some(key) = self.keys.remove(cid) {
+            self.queue.remove(&key);
+        }
+    }
+}
+
+/// The default [`DecisionStrategy`], taking turns between the peers and serving the wants of
+/// each peer in the order of their priority. A peer with a long wantlist does not hold off the
+/// other peers, and each peer decides the order of its own wants.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    wants: HashMap<PeerId, PeerWants>,
+    /// The peers with wants, in the order of their turns.
+    turns: VecDeque<PeerId>,
+    next_seq: u64,
+}
+
+impl DecisionStrategy for RoundRobin {
+    fn want_received(&mut self, peer_id: &PeerId, cid: &Cid, priority: Priority) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        if !self.wants.contains_key(peer_id) {
+            self.turns.push_back(*peer_id);
+        }
+
+        let wants = self.wants.entry(*peer_id).or_default();
+        wants.remove(cid);
+        let key = (Reverse(priority), seq);
+        wants.queue.insert(key, cid.to_owned());
+        wants.keys.insert(cid.to_owned(), key);
+    }
+
+    fn want_cancelled(&mut self, peer_id: &PeerId, cid: &Cid) {
+        if let Some(wants) = self.wants.get_mut(peer_id) {
+            wants.remove(cid);
+            if wants.queue.is_empty() {
+                self.peer_disconnected(peer_id);
+            }
+        }
+    }
+
+    fn peer_disconnected(&mut self, peer_id: &PeerId) {
+        if self.wants.remove(peer_id).is_some() {
+            self.turns.retain(|queued| queued != peer_id);
+        }
+    }
+
+    fn next_want(&mut self) -> Option<(PeerId, Cid, Priority)> {
+        let peer_id = self.turns.pop_front()?;
+        let wants = self.wants.get_mut(&peer_id)?;
+
+        let (key, cid) = wants
+            .queue
+            .iter()
+            .next()
+            .map(|(key, cid)| (*key, cid.to_owned()))?;
+        wants.remove(&cid);
+
+        if wants.queue.is_empty() {
+            self.wants.remove(&peer_id);
+        } else {
+            self.turns.push_back(peer_id);
+        }
+
+        let (Reverse(priority), _) = key;
+        Some((peer_id, cid, priority))
+    }
+}
@@ -1,3 +1,4 @@
 fn main() {
     prost_build::compile_protos(&["src/ipld/dag_pb.proto"], &["src"]).unwrap();
+    prost_build::compile_protos(&["src/ipns/ipns_pb.proto"], &["src"]).unwrap();
 }
@@ -1,7 +1,10 @@
 //! go-ipfs compatible configuration file handling and setup.
 
+use ipfs::ipld::{dag_json::DagJsonCodec, BlockError, Ipld};
+use ipfs::pin::remote::PinningService;
 use ipfs::{multiaddr, Multiaddr};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::num::NonZeroU16;
 use std::path::Path;
@@ -130,7 +133,14 @@ pub fn init(
         addresses: Addresses {
             swarm: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
             api: api_addr,
+            announce: Vec::new(),
+            append_announce: Vec::new(),
+            no_announce: Vec::new(),
         },
+        swarm: Swarm::default(),
+        pinning: Pinning::default(),
+        api: Api::default(),
+        datastore: Datastore::default(),
     };
 
     let config_path = ipfs_path.join("config");
@@ -165,6 +175,16 @@ pub struct Config {
     pub swarm: Vec<Multiaddr>,
     /// Address to run the API daemon on.
     pub api_addr: Multiaddr,
+    /// Limits for the outgoing dials.
+    pub dial: ipfs::DialConfig,
+    /// Policies for the advertised addresses.
+    pub announce: ipfs::AnnounceConfig,
+    /// The remote pinning services and their credentials.
+    pub pinning_services: Vec<PinningService>,
+    /// Limits of the API requests.
+    pub limits: crate::limits::Limits,
+    /// The blockstore of the repo.
+    pub blockstore: ipfs::BlockBackend,
 }
 
 /// Things which can go wrong when loading a `go-ipfs` compatible configuration file.
@@ -180,6 +200,16 @@ pub enum LoadingError {
     UnsupportedPrivateKeyType(i32),
     #[error("loaded PeerId {loaded:?} is not the same as in configuration file {stored:?}, this is likely a bug in rust-ipfs-http")]
     PeerIdMismatch { loaded: String, stored: String },
+    #[error("invalid Swarm.DialTimeout: {0}")]
+    InvalidDialTimeout(humantime::DurationError),
+    #[error("Swarm.DialConcurrency and Swarm.DialAddressLimit must be greater than zero")]
+    InvalidDialLimit,
+    #[error("invalid Addresses.NoAnnounce filter {0:?}: {1}")]
+    InvalidNoAnnounceFilter(String, ipfs::AddrFilterError),
+    #[error("invalid API.{0}: {1}")]
+    InvalidApiTimeout(&'static str, humantime::DurationError),
+    #[error("invalid Datastore.Blockstore: {0}")]
+    InvalidBlockstore(ipfs::repo::UnknownBlockBackend),
 }
 
 /// Loads a `go-ipfs` compatible configuration file from the given file.
@@ -204,15 +234,139 @@ pub fn load(config: File) -> Result<Config, LoadingError> {
         });
     }
 
+    let dial = config_file.swarm.load_dial_config()?;
+    let announce = config_file.addresses.load_announce_config()?;
+    let pinning_services = config_file.pinning.load_services();
+    let limits = config_file.api.load_limits()?;
+    let blockstore = config_file.datastore.load_block_backend()?;
+
     let config = Config {
         keypair: kp,
         swarm: config_file.addresses.swarm,
         api_addr: config_file.addresses.api,
+        dial,
+        announce,
+        pinning_services,
+        limits,
+        blockstore,
     };
 
     Ok(config)
 }
 
+/// Things which can go wrong when recording or restoring the configuration file in the
+/// configuration history.
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("failed to access the configuration file: {0}")]
+    ConfigurationFile(std::io::Error),
+    #[error("invalid configuration: {0}")]
+    ConfigurationFormat(serde_json::Error),
+    #[error("failed to convert the configuration: {0}")]
+    Conversion(BlockError),
+}
+
+/// Reads the configuration file for recording it in the configuration history, see
+/// [`ipfs::config::history`]. The private key and the keys of the remote pinning services are
+/// left out, as the history is stored in blocks which are served to the other peers.
+pub fn snapshot(config_path: &Path) -> Result<Ipld, SnapshotError> {
+    let mut config = read_json(config_path)?;
+
+    if let Some(identity) = config
+        .get_mut("Identity")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        identity.remove("PrivKey");
+    }
+
+    if let Some(services) = config
+        .pointer_mut("/Pinning/RemoteServices")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        for service in services.values_mut() {
+            if let Some(api) = service
+                .get_mut("API")
+                .and_then(serde_json::Value::as_object_mut)
+            {
+                api.remove("Key");
+            }
+        }
+    }
+
+    let bytes = serde_json::to_vec(&config).map_err(SnapshotError::ConfigurationFormat)?;
+    DagJsonCodec::decode(&bytes).map_err(SnapshotError::Conversion)
+}
+
+/// Replaces the configuration file with a configuration recorded with [`snapshot`]. The identity
+/// of the current configuration file is kept, as the private key is not recorded. The new
+/// configuration is loaded on the next restart.
+pub fn restore(config_path: &Path, snapshot: &Ipld) -> Result<(), SnapshotError> {
+    use std::io::{BufWriter, Write};
+
+    let current = read_json(config_path)?;
+
+    let bytes = DagJsonCodec::encode(snapshot).map_err(SnapshotError::Conversion)?;
+    let mut config: serde_json::Value =
+        serde_json::from_slice(&bytes).map_err(SnapshotError::ConfigurationFormat)?;
+
+    match (config.as_object_mut(), current.get("Identity")) {
+        (Some(config), Some(identity)) => {
+            config.insert("Identity".to_owned(), identity.clone());
+        }
+        _ => {
+            use serde::de::Error;
+            return Err(SnapshotError::ConfigurationFormat(
+                serde_json::Error::custom("missing Identity"),
+            ));
+        }
+    }
+
+    // the keys of the remote pinning services which are still configured are kept as well
+    if let Some(services) = config
+        .pointer_mut("/Pinning/RemoteServices")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        for (name, service) in services.iter_mut() {
+            let key = current
+                .pointer(&format!("/Pinning/RemoteServices/{}/API/Key", name))
+                .cloned();
+            if let (Some(api), Some(key)) = (
+                service
+                    .get_mut("API")
+                    .and_then(serde_json::Value::as_object_mut),
+                key,
+            ) {
+                api.insert("Key".to_owned(), key);
+            }
+        }
+    }
+
+    // make sure the restored configuration can be loaded before replacing the current one
+    serde_json::from_value::<CompatibleConfigFile>(config.clone())
+        .map_err(SnapshotError::ConfigurationFormat)?;
+
+    // the file is replaced at once, not to leave a partially written configuration behind
+    let mut temporary = config_path.as_os_str().to_owned();
+    temporary.push(".restore");
+
+    let mut writer = File::create(&temporary)
+        .map(BufWriter::new)
+        .map_err(SnapshotError::ConfigurationFile)?;
+
+    serde_json::to_writer_pretty(&mut writer, &config)
+        .map_err(SnapshotError::ConfigurationFormat)?;
+
+    writer
+        .flush()
+        .and_then(|_| fs::rename(&temporary, config_path))
+        .map_err(SnapshotError::ConfigurationFile)
+}
+
+fn read_json(config_path: &Path) -> Result<serde_json::Value, SnapshotError> {
+    let bytes = fs::read(config_path).map_err(SnapshotError::ConfigurationFile)?;
+    serde_json::from_slice(&bytes).map_err(SnapshotError::ConfigurationFormat)
+}
+
 /// Converts a PEM format to DER where PEM is a container for Base64 data with padding, starting on
 /// the first line with a magic 5 dashes, "BEGIN" and the end of line is a tag which is expected to
 /// be found in the end, in a separate line with magic 5 dashes, "END" and the tag. DER is the
@@ -282,14 +436,212 @@ fn pem_to_der(bytes: &[u8]) -> Vec<u8> {
 struct CompatibleConfigFile {
     identity: Identity,
     addresses: Addresses,
+    #[serde(default)]
+    swarm: Swarm,
+    #[serde(default)]
+    pinning: Pinning,
+    #[serde(default, rename = "API")]
+    api: Api,
+    #[serde(default)]
+    datastore: Datastore,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct Addresses {
     swarm: Vec<Multiaddr>,
-    #[serde(rename = "API")]
+    #[serde(rename = "API", with = "api_addr")]
     api: Multiaddr,
+    #[serde(default)]
+    announce: Vec<Multiaddr>,
+    #[serde(default)]
+    append_announce: Vec<Multiaddr>,
+    /// Exact addresses or networks as `/ip4/10.0.0.0/ipcidr/8`.
+    #[serde(default)]
+    no_announce: Vec<String>,
+}
+
+/// The API address may be a `/unix/..` address with a multi-segment path, see
+/// [`crate::unix_socket`].
+mod api_addr {
+    use crate::unix_socket::{format_addr, parse_addr};
+    use ipfs::Multiaddr;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(addr: &Multiaddr, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format_addr(addr))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Multiaddr, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_addr(&s).map_err(D::Error::custom)
+    }
+}
+
+impl Addresses {
+    fn load_announce_config(&self) -> Result<ipfs::AnnounceConfig, LoadingError> {
+        let no_announce = self
+            .no_announce
+            .iter()
+            .map(|filter| {
+                filter
+                    .parse()
+                    .map_err(|e| LoadingError::InvalidNoAnnounceFilter(filter.to_owned(), e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(ipfs::AnnounceConfig {
+            announce: self.announce.clone(),
+            append_announce: self.append_announce.clone(),
+            no_announce,
+        })
+    }
+}
+
+/// Dial limits, missing from the configuration files written before these were added.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct Swarm {
+    dial_concurrency: usize,
+    dial_address_limit: usize,
+    /// Human readable duration, for example `20s`.
+    dial_timeout: String,
+}
+
+impl Default for Swarm {
+    fn default() -> Self {
+        let defaults = ipfs::DialConfig::default();
+        Swarm {
+            dial_concurrency: defaults.concurrency,
+            dial_address_limit: defaults.addresses_per_peer,
+            dial_timeout: humantime::format_duration(defaults.timeout).to_string(),
+        }
+    }
+}
+
+impl Swarm {
+    fn load_dial_config(&self) -> Result<ipfs::DialConfig, LoadingError> {
+        if self.dial_concurrency == 0 || self.dial_address_limit == 0 {
+            return Err(LoadingError::InvalidDialLimit);
+        }
+
+        let timeout = humantime::parse_duration(&self.dial_timeout)
+            .map_err(LoadingError::InvalidDialTimeout)?;
+
+        Ok(ipfs::DialConfig {
+            concurrency: self.dial_concurrency,
+            addresses_per_peer: self.dial_address_limit,
+            timeout,
+        })
+    }
+}
+
+/// Limits of the API requests, missing from the configuration files written before these were
+/// added, see [`crate::limits`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct Api {
+    /// In bytes, unlimited when missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_request_body_size: Option<u64>,
+    /// Human readable duration, for example `30s`, which is also the default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    header_timeout: Option<String>,
+    /// Human readable duration, unlimited when missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_request_duration: Option<String>,
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        let defaults = crate::limits::Limits::default();
+        let format = |duration| humantime::format_duration(duration).to_string();
+        Api {
+            max_request_body_size: defaults.max_body_size,
+            header_timeout: defaults.header_timeout.map(format),
+            max_request_duration: defaults.max_duration.map(format),
+        }
+    }
+}
+
+impl Api {
+    fn load_limits(&self) -> Result<crate::limits::Limits, LoadingError> {
+        let parse = |field, duration: &Option<String>| {
+            duration
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose()
+                .map_err(|e| LoadingError::InvalidApiTimeout(field, e))
+        };
+
+        Ok(crate::limits::Limits {
+            max_body_size: self.max_request_body_size,
+            header_timeout: parse("HeaderTimeout", &self.header_timeout)?,
+            max_duration: parse("MaxRequestDuration", &self.max_request_duration)?,
+        })
+    }
+}
+
+/// The blockstore of the repo, missing from the configuration files written before it could be
+/// selected.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct Datastore {
+    /// One of `flatfs`, `sled`, `rocksdb` or `s3`, see [`ipfs::BlockBackend`]. The bucket of
+    /// `s3` cannot be configured here yet.
+    blockstore: String,
+}
+
+impl Default for Datastore {
+    fn default() -> Self {
+        Datastore {
+            blockstore: ipfs::BlockBackend::default().to_string(),
+        }
+    }
+}
+
+impl Datastore {
+    fn load_block_backend(&self) -> Result<ipfs::BlockBackend, LoadingError> {
+        self.blockstore
+            .parse()
+            .map_err(LoadingError::InvalidBlockstore)
+    }
+}
+
+/// The remote pinning services, in the same format as in go-ipfs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Pinning {
+    #[serde(default)]
+    remote_services: BTreeMap<String, RemoteService>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RemoteService {
+    #[serde(rename = "API")]
+    api: RemoteServiceApi,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RemoteServiceApi {
+    endpoint: String,
+    /// Missing from the configurations restored from the history.
+    #[serde(default)]
+    key: String,
+}
+
+impl Pinning {
+    fn load_services(&self) -> Vec<PinningService> {
+        self.remote_services
+            .iter()
+            .map(|(name, service)| PinningService {
+                name: name.clone(),
+                endpoint: service.api.endpoint.clone(),
+                key: service.api.key.clone(),
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -377,4 +729,82 @@ aGVsbG8gd29ybGQ=
 
         assert_eq!(peer_id, input.peer_id);
     }
+
+    #[test]
+    fn dial_limits_default_when_missing() {
+        use super::Swarm;
+
+        let swarm: Swarm = serde_json::from_str(r#"{ "DialTimeout": "5s" }"#).unwrap();
+        let dial = swarm.load_dial_config().unwrap();
+
+        assert_eq!(dial.timeout, std::time::Duration::from_secs(5));
+        assert_eq!(dial.concurrency, ipfs::DialConfig::default().concurrency);
+
+        let swarm: Swarm = serde_json::from_str(r#"{ "DialConcurrency": 0 }"#).unwrap();
+        assert!(swarm.load_dial_config().is_err());
+    }
+
+    #[test]
+    fn blockstore_defaults_when_missing() {
+        use super::Datastore;
+
+        let datastore: Datastore = serde_json::from_str("{}").unwrap();
+        assert_eq!(
+            datastore.load_block_backend().unwrap(),
+            ipfs::BlockBackend::default()
+        );
+
+        let datastore: Datastore = serde_json::from_str(r#"{ "Blockstore": "sled" }"#).unwrap();
+        assert_eq!(
+            datastore.load_block_backend().unwrap(),
+            ipfs::BlockBackend::Sled
+        );
+
+        let datastore: Datastore = serde_json::from_str(r#"{ "Blockstore": "badger" }"#).unwrap();
+        assert!(datastore.load_block_backend().is_err());
+    }
+
+    #[test]
+    fn unix_socket_api_keeps_the_whole_path() {
+        use super::Addresses;
+
+        let addresses: Addresses =
+            serde_json::from_str(r#"{ "Swarm": [], "API": "/unix/run/ipfs/api.sock" }"#).unwrap();
+        assert_eq!(
+            crate::unix_socket::socket_path(&addresses.api).unwrap(),
+            std::path::Path::new("/run/ipfs/api.sock")
+        );
+
+        let written = serde_json::to_value(&addresses).unwrap();
+        assert_eq!(written["API"], "/unix/run/ipfs/api.sock");
+    }
+
+    #[test]
+    fn snapshot_leaves_out_the_private_key() {
+        use super::{restore, snapshot};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+
+        let config = r#"{
+            "Identity": { "PeerID": "QmVNXj4TENKBjaUmQQzYMawDXu5LcEEzLyf4K6Ds3WgcF3", "PrivKey": "secret" },
+            "Addresses": { "Swarm": ["/ip4/127.0.0.1/tcp/0"], "API": "/ip4/127.0.0.1/tcp/4004" }
+        }"#;
+        std::fs::write(&path, config).unwrap();
+
+        let recorded = snapshot(&path).unwrap();
+        let identity = recorded.get("Identity").unwrap();
+        assert_eq!(identity.get("PrivKey"), None);
+        assert!(identity.get("PeerID").is_some());
+
+        let changed = config.replace("4004", "5005");
+        std::fs::write(&path, changed).unwrap();
+
+        restore(&path, &recorded).unwrap();
+
+        let restored: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(restored["Identity"]["PrivKey"], "secret");
+        assert_eq!(restored["Addresses"]["API"], "/ip4/127.0.0.1/tcp/4004");
+    }
 }
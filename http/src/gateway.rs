@@ -0,0 +1,698 @@
+//! Implementation of the HTTP gateway paths, `/ipfs/..` and `/ipns/..`.
+//!
+//! The files are served with the media type of their extension or sniffed from their contents,
+//! and support the single range `Range` requests. The directories are served through their
+//! `index.html`, or as an HTML listing of the entries. The responses are tagged with the Cid of
+//! the served block, and the `/ipfs/` responses are cached as immutable.
+//!
+//! The gateway can also be served from the subdomains of the Cids and the names, and from the
+//! DNSLink domains, with [`with_hosts`]. The websites served from them can define the redirects,
+//! the rewrites and the error pages of their missing paths with a `_redirects` file.
+//!
+//! See https://docs.ipfs.io/reference/http/gateway/ for more information.
+
+use crate::v0::root_files::{resolve_dagpb, walk, zip_walk};
+use crate::v0::support::with_ipfs;
+use futures::stream::TryStreamExt;
+use ipfs::path::PathRoot;
+use ipfs::popularity::RequestSource;
+use ipfs::unixfs::ll::file::{visit::IdleFileVisit, FileReadFailed};
+use ipfs::{Block, Ipfs, IpfsPath, IpfsTypes, PeerId};
+use serde::Deserialize;
+use std::str::FromStr;
+use warp::http::{header, response, HeaderMap, Response, StatusCode};
+use warp::hyper::Body;
+use warp::path::Tail;
+use warp::{query, Filter, Rejection, Reply};
+
+mod files;
+use files::{directory_listing, file_stream, sniff_content_type, RequestedRange, SNIFF_LEN};
+
+mod trustless;
+
+mod redirects;
+
+mod subdomain;
+use subdomain::IsolatedOrigin;
+pub use subdomain::{with_hosts, GatewayHosts};
+
+mod upstream;
+pub use upstream::UpstreamGateways;
+
+/// The media type of a signed IPNS record, see [IPIP-351].
+///
+/// [IPIP-351]: https://github.com/ipfs/specs/pull/351
+pub const IPNS_RECORD_MEDIA_TYPE: &str = "application/vnd.ipfs.ipns-record";
+
+/// The media type of a single block, see the [trustless gateway] specification.
+///
+/// [trustless gateway]: https://specs.ipfs.tech/http-gateways/trustless-gateway/
+pub const RAW_BLOCK_MEDIA_TYPE: &str = "application/vnd.ipld.raw";
+
+/// The media type of a CARv1 file, see the [trustless gateway] specification.
+///
+/// [trustless gateway]: https://specs.ipfs.tech/http-gateways/trustless-gateway/
+pub const CAR_MEDIA_TYPE: &str = "application/vnd.ipld.car";
+
+/// The `Cache-Control` of the `/ipfs/` responses, which never change.
+const IMMUTABLE: &str = "public, max-age=29030400, immutable";
+
+/// The `Cache-Control` of the `/ipns/` responses, for as long as the resolved names are cached by
+/// default.
+const MUTABLE: &str = "public, max-age=60";
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Query parameters common to the gateway paths.
+#[derive(Debug, Default, Deserialize)]
+pub struct GatewayQuery {
+    /// Alternative to the `Accept` header for selecting the response format.
+    format: Option<String>,
+}
+
+/// The response formats the gateway can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResponseFormat {
+    /// The signed IPNS record of an `/ipns/<key>` path.
+    IpnsRecord,
+    /// The directory tree as a tar archive.
+    Tar,
+    /// The directory tree as a store-only zip archive.
+    Zip,
+    /// The single block at the end of the path.
+    Raw,
+    /// The blocks along the path and the whole DAG at the end of it as a CAR file.
+    Car,
+}
+
+impl ResponseFormat {
+    /// Picks the response format from the `?format=` query parameter which takes precedence over
+    /// the `Accept` header. Returns `None` for the default, deserialized response.
+    fn from_request(query: &GatewayQuery, accept: Option<&str>) -> Option<Self> {
+        match query.format.as_deref() {
+            Some("ipns-record") => return Some(ResponseFormat::IpnsRecord),
+            Some("tar") => return Some(ResponseFormat::Tar),
+            Some("zip") => return Some(ResponseFormat::Zip),
+            Some("raw") => return Some(ResponseFormat::Raw),
+            Some("car") => return Some(ResponseFormat::Car),
+            Some(_) => return None,
+            None => {}
+        }
+
+        let accept = accept?;
+
+        accept
+            .split(',')
+            .map(|media_range| media_range.split(';').next().unwrap_or_default().trim())
+            .find_map(|media_type| match media_type {
+                IPNS_RECORD_MEDIA_TYPE => Some(ResponseFormat::IpnsRecord),
+                "application/x-tar" => Some(ResponseFormat::Tar),
+                "application/zip" => Some(ResponseFormat::Zip),
+                RAW_BLOCK_MEDIA_TYPE => Some(ResponseFormat::Raw),
+                CAR_MEDIA_TYPE => Some(ResponseFormat::Car),
+                _ => None,
+            })
+    }
+}
+
+/// Supported routes of the gateway. The blocks missing from the local blockstore are also fetched
+/// from the `upstreams`, if any.
+pub fn routes<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+    upstreams: Option<UpstreamGateways>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let head_upstreams = upstreams.clone();
+
+    let gateway_paths = namespace().and(warp::path::tail()).and(
+        with_ipfs(ipfs)
+            .and(warp::any().map(move || upstreams.clone()))
+            .and(query::<GatewayQuery>())
+            .and(warp::header::headers_cloned())
+            .and(warp::ext::optional::<IsolatedOrigin>())
+            .and_then(gateway_inner),
+    );
+
+    let gateway_heads = namespace().and(warp::path::tail()).and(
+        with_ipfs(ipfs)
+            .and(warp::any().map(move || head_upstreams.clone()))
+            .and(query::<GatewayQuery>())
+            .and(warp::header::headers_cloned())
+            .and(warp::ext::optional::<IsolatedOrigin>())
+            .and_then(gateway_head),
+    );
+
+    warp::get()
+        .and(gateway_paths)
+        .or(warp::head().and(gateway_heads))
+        .unify()
+}
+
+/// The namespace of the gateway path, either `ipfs` or `ipns`.
+fn namespace() -> impl Filter<Extract = (&'static str,), Error = Rejection> + Clone {
+    warp::path("ipfs")
+        .map(|| "ipfs")
+        .or(warp::path("ipns").map(|| "ipns"))
+        .unify()
+}
+
+/// Parses the `/<namespace>/<tail>` path, or returns the error response. The `/ipns/` names,
+/// either keys or DNSLink domains, are resolved recursively into an `/ipfs/` path, using the
+/// cached resolutions.
+async fn parse_tail<T: IpfsTypes>(
+    namespace: &str,
+    tail: &str,
+    ipfs: &Ipfs<T>,
+) -> Result<IpfsPath, Response<Body>> {
+    let path = match IpfsPath::from_str(&format!("/{}/{}", namespace, tail)) {
+        Ok(path) => path,
+        Err(e) => return Err(plaintext(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+
+    match path.root() {
+        PathRoot::Ipld(_) => Ok(path),
+        _ => match ipfs.resolve_ipns_cached(&path, true).await {
+            Ok(resolved) => Ok(resolved),
+            Err(e) => Err(plaintext(StatusCode::NOT_FOUND, e.to_string())),
+        },
+    }
+}
+
+/// Resolves the `/<namespace>/<tail>` path to the final dag-pb block, or the error response, see
+/// [`parse_tail`].
+async fn resolve_tail<T: IpfsTypes>(
+    namespace: &str,
+    tail: &Tail,
+    ipfs: &Ipfs<T>,
+    upstreams: &Option<UpstreamGateways>,
+) -> Result<(IpfsPath, Block), Response<Body>> {
+    let path = parse_tail(namespace, tail.as_str(), ipfs).await?;
+
+    let resolved = match upstreams {
+        Some(upstreams) => upstreams.resolve_dagpb(ipfs, path.clone()).await,
+        None => resolve_dagpb(ipfs, path.clone()).await,
+    };
+
+    match resolved {
+        Ok(block) => Ok((path, block)),
+        Err(e) => Err(plaintext(StatusCode::NOT_FOUND, e.to_string())),
+    }
+}
+
+async fn gateway_inner<T: IpfsTypes>(
+    namespace: &'static str,
+    tail: Tail,
+    ipfs: Ipfs<T>,
+    upstreams: Option<UpstreamGateways>,
+    query: GatewayQuery,
+    headers: HeaderMap,
+    origin: Option<IsolatedOrigin>,
+) -> Result<Response<Body>, Rejection> {
+    let request = GatewayRequest {
+        namespace,
+        tail: &tail,
+        headers: &headers,
+        head: false,
+        isolated_origin: origin.is_some(),
+    };
+
+    let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok());
+
+    let format = match ResponseFormat::from_request(&query, accept) {
+        Some(ResponseFormat::IpnsRecord) if namespace == "ipns" => {
+            return ipns_record(ipfs, tail.as_str().trim_end_matches('/')).await
+        }
+        Some(format @ ResponseFormat::Raw) | Some(format @ ResponseFormat::Car) => {
+            return Ok(request.trustless(ipfs, upstreams, format).await);
+        }
+        Some(format @ ResponseFormat::Tar) | Some(format @ ResponseFormat::Zip) => Some(format),
+        None if query.format.is_none() => None,
+        _ => {
+            return Ok(plaintext(
+                StatusCode::NOT_IMPLEMENTED,
+                "only the tar, zip, raw, car and ipns-record formats are supported",
+            ))
+        }
+    };
+
+    let (path, block) = match resolve_tail(namespace, &tail, &ipfs, &upstreams).await {
+        Ok(resolved) => resolved,
+        Err(resp) if format.is_none() && resp.status() == StatusCode::NOT_FOUND => {
+            return Ok(request.redirects(ipfs, upstreams, resp).await)
+        }
+        Err(resp) => return Ok(resp),
+    };
+
+    ipfs.popularity().record(&block.cid, RequestSource::Gateway);
+
+    let format = match format {
+        Some(format) => format,
+        None => return Ok(request.respond(ipfs, upstreams, path, block).await),
+    };
+
+    let name = block.cid.to_string();
+
+    let body = match format {
+        ResponseFormat::Tar => Body::wrap_stream(walk(ipfs, block, upstreams).into_stream()),
+        ResponseFormat::Zip => Body::wrap_stream(zip_walk(ipfs, block, upstreams).into_stream()),
+        _ => unreachable!("handled above"),
+    };
+
+    Ok(archive_response(format, &name, body))
+}
+
+/// Answers the HEAD requests from the root block of the resolved path alone, without fetching
+/// the rest of the blocks, so that the link previews and the CDNs checking the content do not
+/// trigger a retrieval of the whole file or tree. The size of a file comes from its UnixFS
+/// metadata and the type from the extension of the last segment of the path. The archives and the
+/// CAR files are only described by their type, as their size would require walking the tree, and
+/// the directory listings by their tag.
+async fn gateway_head<T: IpfsTypes>(
+    namespace: &'static str,
+    tail: Tail,
+    ipfs: Ipfs<T>,
+    upstreams: Option<UpstreamGateways>,
+    query: GatewayQuery,
+    headers: HeaderMap,
+    origin: Option<IsolatedOrigin>,
+) -> Result<Response<Body>, Rejection> {
+    let request = GatewayRequest {
+        namespace,
+        tail: &tail,
+        headers: &headers,
+        head: true,
+        isolated_origin: origin.is_some(),
+    };
+
+    let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok());
+    let format = ResponseFormat::from_request(&query, accept);
+
+    if let Some(format @ ResponseFormat::Raw) | Some(format @ ResponseFormat::Car) = format {
+        return Ok(request.trustless(ipfs, upstreams, format).await);
+    }
+
+    let (path, block) = match resolve_tail(namespace, &tail, &ipfs, &upstreams).await {
+        Ok(resolved) => resolved,
+        Err(resp) if format.is_none() && resp.status() == StatusCode::NOT_FOUND => {
+            return Ok(request.redirects(ipfs, upstreams, resp).await)
+        }
+        Err(resp) => return Ok(resp),
+    };
+
+    if let Some(format @ ResponseFormat::Tar) | Some(format @ ResponseFormat::Zip) = format {
+        return Ok(archive_response(
+            format,
+            &block.cid.to_string(),
+            Body::empty(),
+        ));
+    }
+
+    Ok(request.respond(ipfs, upstreams, path, block).await)
+}
+
+/// A request for one of the responses of a gateway path other than the archives and the IPNS
+/// records.
+struct GatewayRequest<'a> {
+    namespace: &'static str,
+    tail: &'a Tail,
+    headers: &'a HeaderMap,
+    /// Only the headers are sent, and the contents of the files are not read.
+    head: bool,
+    /// The request came through a subdomain or a DNSLink host, so the root of the path is a
+    /// website with its own origin, whose `_redirects` file applies.
+    isolated_origin: bool,
+}
+
+impl GatewayRequest<'_> {
+    /// Responds with the UnixFS file or directory in the `block`.
+    async fn respond<T: IpfsTypes>(
+        &self,
+        ipfs: Ipfs<T>,
+        upstreams: Option<UpstreamGateways>,
+        path: IpfsPath,
+        block: Block,
+    ) -> Response<Body> {
+        match file_size(&block) {
+            Ok(Some(size)) => return self.file(ipfs, upstreams, &path, block, size).await,
+            Ok(None) => {}
+            Err(e) => return plaintext(StatusCode::NOT_IMPLEMENTED, e.to_string()),
+        }
+
+        // the relative links within the directory need the trailing slash, and the location is
+        // relative to work also behind the subdomains and the DNSLink hosts
+        let request_path = self.request_path();
+        if !request_path.ends_with('/') {
+            let last = request_path.rsplit('/').next().unwrap_or_default();
+            return Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, format!("./{}/", last))
+                .body(Body::empty())
+                .expect("all headers are valid");
+        }
+
+        let index = match path.sub_path("index.html") {
+            Ok(index_path) => {
+                let resolved = match &upstreams {
+                    Some(upstreams) => upstreams.resolve_dagpb(&ipfs, index_path.clone()).await,
+                    None => resolve_dagpb(&ipfs, index_path.clone()).await,
+                };
+                resolved.ok().map(|block| (index_path, block))
+            }
+            Err(_) => None,
+        };
+
+        if let Some((index_path, index)) = index {
+            if let Ok(Some(size)) = file_size(&index) {
+                return self.file(ipfs, upstreams, &index_path, index, size).await;
+            }
+        }
+
+        self.listing(ipfs, block).await
+    }
+
+    async fn file<T: IpfsTypes>(
+        &self,
+        ipfs: Ipfs<T>,
+        upstreams: Option<UpstreamGateways>,
+        path: &IpfsPath,
+        block: Block,
+        size: u64,
+    ) -> Response<Body> {
+        let etag = format!("\"{}\"", block.cid);
+        if let Some(not_modified) = self.not_modified(&etag) {
+            return not_modified;
+        }
+
+        let content_type = match path
+            .iter()
+            .last()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, extension)| content_type_of(extension))
+        {
+            Some(content_type) if content_type != OCTET_STREAM => content_type,
+            _ if self.head => OCTET_STREAM,
+            _ => {
+                let start = file_stream(
+                    ipfs.clone(),
+                    block.clone(),
+                    0..SNIFF_LEN.min(size),
+                    upstreams.clone(),
+                )
+                .try_fold(Vec::new(), |mut start, bytes| async move {
+                    start.extend_from_slice(&bytes);
+                    Ok(start)
+                })
+                .await;
+
+                match start {
+                    Ok(start) => sniff_content_type(&start),
+                    Err(e) => return plaintext(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+                }
+            }
+        };
+
+        // a range of an older version of the file is not a range of the current one
+        let if_range = self.header(header::IF_RANGE);
+        let range = match self.header(header::RANGE) {
+            range @ Some(_) if if_range.map(|tag| tag == etag).unwrap_or(true) => {
+                RequestedRange::parse(range, size)
+            }
+            _ => RequestedRange::Full,
+        };
+
+        let builder = self
+            .common_headers(&etag)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::ACCEPT_RANGES, "bytes");
+
+        let (builder, range) = match range {
+            RequestedRange::Full => (builder, 0..size),
+            RequestedRange::Partial(range) => (
+                builder.status(StatusCode::PARTIAL_CONTENT).header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+                ),
+                range,
+            ),
+            RequestedRange::Unsatisfiable => {
+                return builder
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                    .body(Body::empty())
+                    .expect("all headers are valid")
+            }
+        };
+
+        let builder = builder.header(header::CONTENT_LENGTH, range.end - range.start);
+
+        let body = if self.head {
+            Body::empty()
+        } else {
+            Body::wrap_stream(file_stream(ipfs, block, range, upstreams))
+        };
+
+        builder.body(body).expect("all headers are valid")
+    }
+
+    async fn listing<T: IpfsTypes>(&self, ipfs: Ipfs<T>, block: Block) -> Response<Body> {
+        // the listing changes with the rendering, unlike the directory
+        let etag = format!("\"DirIndex-{}\"", block.cid);
+        if let Some(not_modified) = self.not_modified(&etag) {
+            return not_modified;
+        }
+
+        let body = if self.head {
+            Body::empty()
+        } else {
+            match directory_listing(ipfs, block, &self.request_path()).await {
+                Ok(html) => Body::from(html),
+                Err(e) => return plaintext(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            }
+        };
+
+        self.common_headers(&etag)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(body)
+            .expect("all headers are valid")
+    }
+
+    /// Returns the `304 Not Modified` response if the client already has the `etag`.
+    fn not_modified(&self, etag: &str) -> Option<Response<Body>> {
+        let matches = self
+            .header(header::IF_NONE_MATCH)?
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+
+        if !matches {
+            return None;
+        }
+
+        let resp = self
+            .common_headers(etag)
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .expect("all headers are valid");
+        Some(resp)
+    }
+
+    fn common_headers(&self, etag: &str) -> response::Builder {
+        let cache_control = if self.namespace == "ipfs" {
+            IMMUTABLE
+        } else {
+            MUTABLE
+        };
+
+        Response::builder()
+            .header(header::ETAG, etag)
+            .header(header::CACHE_CONTROL, cache_control)
+            .header("x-ipfs-path", self.request_path())
+            .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+    }
+
+    fn request_path(&self) -> String {
+        format!("/{}/{}", self.namespace, self.tail.as_str())
+    }
+
+    fn header(&self, name: header::HeaderName) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+/// Returns the size of the UnixFS file in the `block`, or `None` for a directory.
+fn file_size(block: &Block) -> Result<Option<u64>, FileReadFailed> {
+    match IdleFileVisit::default().start(&block.data) {
+        Ok((_, file_size, _, _)) => Ok(Some(file_size)),
+        Err(FileReadFailed::UnexpectedType(ut)) if ut.is_directory() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the response with the `body` of the archive of the tree rooted at `name`.
+fn archive_response(format: ResponseFormat, name: &str, body: Body) -> Response<Body> {
+    let (content_type, extension) = match format {
+        ResponseFormat::Tar => ("application/x-tar", "tar"),
+        ResponseFormat::Zip => ("application/zip", "zip"),
+        _ => unreachable!("not an archive"),
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.{}\"", name, extension),
+        )
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(header::VARY, "Accept")
+        .body(body)
+        .expect("all headers are valid")
+}
+
+/// Returns the media type of the common file extensions, or `application/octet-stream`.
+fn content_type_of(extension: &str) -> &'static str {
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "application/javascript",
+        "json" => "application/json",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "zip" => "application/zip",
+        "tar" => "application/x-tar",
+        "gz" => "application/gzip",
+        _ => OCTET_STREAM,
+    }
+}
+
+async fn ipns_record<T: IpfsTypes>(ipfs: Ipfs<T>, key: &str) -> Result<Response<Body>, Rejection> {
+    let name = match key.parse::<PeerId>() {
+        Ok(name) => name,
+        Err(_) => {
+            return Ok(plaintext(
+                StatusCode::BAD_REQUEST,
+                "ipns-record format requires a key, not a domain name",
+            ))
+        }
+    };
+
+    let record = match ipfs.get_ipns_record(&name).await {
+        Ok(record) => record,
+        Err(e) => return Ok(plaintext(StatusCode::NOT_FOUND, e.to_string())),
+    };
+
+    let resp = Response::builder()
+        .header(header::CONTENT_TYPE, IPNS_RECORD_MEDIA_TYPE)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.ipns-record\"", key),
+        )
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(header::VARY, "Accept")
+        .body(Body::from(record))
+        .expect("all headers are valid");
+
+    Ok(resp)
+}
+
+fn plaintext<S: Into<String>>(status: StatusCode, msg: S) -> Response<Body> {
+    let mut resp = Response::new(Body::from(msg.into()));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_type_of, GatewayQuery, ResponseFormat};
+
+    #[test]
+    fn format_query_selects_ipns_record() {
+        let query = GatewayQuery {
+            format: Some("ipns-record".into()),
+        };
+        assert_eq!(
+            ResponseFormat::from_request(&query, None),
+            Some(ResponseFormat::IpnsRecord)
+        );
+    }
+
+    #[test]
+    fn accept_header_selects_ipns_record() {
+        let query = GatewayQuery::default();
+        let accept = "text/html, application/vnd.ipfs.ipns-record;q=0.9";
+        assert_eq!(
+            ResponseFormat::from_request(&query, Some(accept)),
+            Some(ResponseFormat::IpnsRecord)
+        );
+    }
+
+    #[test]
+    fn format_query_overrides_accept_header() {
+        let query = GatewayQuery {
+            format: Some("dag-json".into()),
+        };
+        let accept = "application/vnd.ipfs.ipns-record";
+        assert_eq!(ResponseFormat::from_request(&query, Some(accept)), None);
+    }
+
+    #[test]
+    fn archive_formats() {
+        let query = GatewayQuery {
+            format: Some("zip".into()),
+        };
+        assert_eq!(
+            ResponseFormat::from_request(&query, None),
+            Some(ResponseFormat::Zip)
+        );
+
+        let query = GatewayQuery::default();
+        assert_eq!(
+            ResponseFormat::from_request(&query, Some("application/x-tar")),
+            Some(ResponseFormat::Tar)
+        );
+    }
+
+    #[test]
+    fn trustless_formats() {
+        let query = GatewayQuery {
+            format: Some("raw".into()),
+        };
+        assert_eq!(
+            ResponseFormat::from_request(&query, None),
+            Some(ResponseFormat::Raw)
+        );
+
+        let query = GatewayQuery::default();
+        assert_eq!(
+            ResponseFormat::from_request(&query, Some("application/vnd.ipld.car; version=1")),
+            Some(ResponseFormat::Car)
+        );
+    }
+
+    #[test]
+    fn default_format() {
+        let query = GatewayQuery::default();
+        assert_eq!(ResponseFormat::from_request(&query, Some("*/*")), None);
+        assert_eq!(ResponseFormat::from_request(&query, None), None);
+    }
+
+    #[test]
+    fn content_types_by_extension() {
+        assert_eq!(content_type_of("HTML"), "text/html; charset=utf-8");
+        assert_eq!(content_type_of("png"), "image/png");
+        assert_eq!(content_type_of("unknown"), "application/octet-stream");
+    }
+}
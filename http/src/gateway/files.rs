@@ -0,0 +1,309 @@
+//! Serving the UnixFS files and directories of the gateway paths: the contents of the files with
+//! the `Range` requests, and the HTML listings of the directories without an `index.html`.
+
+use super::UpstreamGateways;
+use crate::v0::root_files::ls::{list, LsLink, DIRECTORY};
+use async_stream::try_stream;
+use bytes::Bytes;
+use futures::stream::{Stream, TryStreamExt};
+use ipfs::unixfs::ll::file::visit::IdleFileVisit;
+use ipfs::{Block, Ipfs, IpfsTypes};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::fmt::Write;
+use std::ops::Range;
+
+/// How many bytes from the start of a file are looked at to sniff its media type, like the
+/// [MIME sniffing] standard does.
+///
+/// [MIME sniffing]: https://mimesniff.spec.whatwg.org/
+pub(super) const SNIFF_LEN: u64 = 512;
+
+/// The characters percent-encoded in the names of the listed entries when linking to them.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// The part of a file requested with the `Range` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum RequestedRange {
+    /// The whole file. The headers with many ranges, other units than bytes, or syntax errors
+    /// are answered with the whole file, which the HTTP semantics allow.
+    Full,
+    /// A single range within the file.
+    Partial(Range<u64>),
+    /// The range starts past the end of the file.
+    Unsatisfiable,
+}
+
+impl RequestedRange {
+    /// Parses the `Range` header against the `size` of the file.
+    pub(super) fn parse(header: Option<&str>, size: u64) -> Self {
+        let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+            Some(spec) if !spec.contains(',') => spec.trim(),
+            _ => return RequestedRange::Full,
+        };
+
+        let (start, end) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return RequestedRange::Full,
+        };
+
+        let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+            // the last bytes of the file
+            _ if start.is_empty() => match end.parse::<u64>() {
+                Ok(0) => return RequestedRange::Unsatisfiable,
+                Ok(len) => size.saturating_sub(len)..size,
+                Err(_) => return RequestedRange::Full,
+            },
+            (Ok(start), _) if end.is_empty() => start..size,
+            (Ok(start), Ok(last)) if start <= last => start..last.saturating_add(1).min(size),
+            _ => return RequestedRange::Full,
+        };
+
+        if range.start >= size {
+            RequestedRange::Unsatisfiable
+        } else {
+            RequestedRange::Partial(range)
+        }
+    }
+}
+
+/// Returns the bytes of the `range` of the UnixFS file in the `block`, loading the blocks also
+/// from the `upstreams`, if any.
+pub(super) fn file_stream<T: IpfsTypes>(
+    ipfs: Ipfs<T>,
+    block: Block,
+    range: Range<u64>,
+    upstreams: Option<UpstreamGateways>,
+) -> impl Stream<Item = Result<Bytes, ipfs::Error>> + Send + 'static {
+    try_stream! {
+        let mut cache = None;
+
+        let (bytes, _, _, mut visit) = IdleFileVisit::default()
+            .with_target_range(range)
+            .start(&block.data)?;
+
+        if !bytes.is_empty() {
+            yield Bytes::copy_from_slice(bytes);
+        }
+
+        while let Some(current) = visit {
+            let next = current.pending_links().0.to_owned();
+            let block = match &upstreams {
+                Some(upstreams) => upstreams.get_block(&ipfs, &next).await?,
+                None => ipfs.get_block(&next).await?,
+            };
+
+            let (bytes, next_visit) = current.continue_walk(&block.data, &mut cache)?;
+            if !bytes.is_empty() {
+                yield Bytes::copy_from_slice(bytes);
+            }
+            visit = next_visit;
+        }
+    }
+}
+
+/// Returns the media type of the start of a file, for the files without a known extension.
+pub(super) fn sniff_content_type(start: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b\x08", "application/gzip"),
+        (b"\x00asm", "application/wasm"),
+        (b"ID3", "audio/mpeg"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+
+    if let Some((_, media_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| start.starts_with(signature))
+    {
+        return media_type;
+    }
+
+    if start.len() >= 12 && &start[..4] == b"RIFF" && &start[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+
+    if start.len() >= 8 && &start[4..8] == b"ftyp" {
+        return "video/mp4";
+    }
+
+    let text = match std::str::from_utf8(start) {
+        Ok(text) => text,
+        // the sniffed bytes can end in the middle of a character
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&start[..e.valid_up_to()]).expect("valid up to the error")
+        }
+        Err(_) => return "application/octet-stream",
+    };
+
+    if text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b'))
+    {
+        return "application/octet-stream";
+    }
+
+    let lowercase = text.trim_start().to_ascii_lowercase();
+    if lowercase.starts_with("<!doctype html") || lowercase.starts_with("<html") {
+        "text/html; charset=utf-8"
+    } else if lowercase.starts_with("<svg") {
+        "image/svg+xml"
+    } else if lowercase.starts_with("<?xml") {
+        "application/xml"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+/// Renders the HTML listing of the directory in the `block` at the `path` of the request, which
+/// ends with a `/`.
+pub(super) async fn directory_listing<T: IpfsTypes>(
+    ipfs: Ipfs<T>,
+    block: Block,
+    path: &str,
+) -> Result<String, ipfs::Error> {
+    let cid = block.cid.to_string();
+    let mut links = list(ipfs, block, true, true)
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    links.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(render_listing(path, &cid, &links))
+}
+
+fn render_listing(path: &str, cid: &str, links: &[LsLink]) -> String {
+    let path = escape_html(path);
+    let mut html = String::with_capacity(1024 + links.len() * 256);
+
+    write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{path}</title>\n\
+         </head>\n<body>\n<h1>Index of {path}</h1>\n<p><code>{cid}</code></p>\n<table>\n",
+        path = path,
+        cid = cid,
+    )
+    .expect("writing to a string cannot fail");
+
+    // the root of the namespace has nothing above it
+    if path.trim_end_matches('/').matches('/').count() > 2 {
+        html.push_str("<tr><td><a href=\"..\">..</a></td><td></td><td></td></tr>\n");
+    }
+
+    for link in links {
+        let (href, name) = if link.kind == DIRECTORY {
+            (
+                format!("{}/", utf8_percent_encode(&link.name, SEGMENT)),
+                format!("{}/", escape_html(&link.name)),
+            )
+        } else {
+            (
+                utf8_percent_encode(&link.name, SEGMENT).to_string(),
+                escape_html(&link.name),
+            )
+        };
+
+        let size = if link.kind == DIRECTORY {
+            String::new()
+        } else {
+            link.size.to_string()
+        };
+
+        write!(
+            html,
+            "<tr><td><a href=\"{href}\">{name}</a></td><td>{size}</td>\
+             <td><a href=\"/ipfs/{cid}\"><code>{cid}</code></a></td></tr>\n",
+            href = escape_html(&href),
+            name = name,
+            size = size,
+            cid = link.hash,
+        )
+        .expect("writing to a string cannot fail");
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_html, sniff_content_type, RequestedRange};
+
+    #[test]
+    fn ranges() {
+        let parse = |header| RequestedRange::parse(Some(header), 1000);
+
+        assert_eq!(parse("bytes=0-499"), RequestedRange::Partial(0..500));
+        assert_eq!(parse("bytes=500-"), RequestedRange::Partial(500..1000));
+        assert_eq!(parse("bytes=-100"), RequestedRange::Partial(900..1000));
+        assert_eq!(parse("bytes=900-2000"), RequestedRange::Partial(900..1000));
+        assert_eq!(parse("bytes=-2000"), RequestedRange::Partial(0..1000));
+        assert_eq!(parse("bytes=1000-"), RequestedRange::Unsatisfiable);
+        assert_eq!(parse("bytes=-0"), RequestedRange::Unsatisfiable);
+        assert_eq!(parse("bytes=0-1,5-9"), RequestedRange::Full);
+        assert_eq!(parse("bytes=9-5"), RequestedRange::Full);
+        assert_eq!(parse("items=0-5"), RequestedRange::Full);
+        assert_eq!(RequestedRange::parse(None, 1000), RequestedRange::Full);
+    }
+
+    #[test]
+    fn sniffing() {
+        assert_eq!(sniff_content_type(b"\x89PNG\r\n\x1a\n\0\0"), "image/png");
+        assert_eq!(
+            sniff_content_type(b"  <!DOCTYPE html><html>"),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            sniff_content_type("hello, w\u{f6}rld".as_bytes()),
+            "text/plain; charset=utf-8"
+        );
+        // cut in the middle of the two byte character
+        assert_eq!(
+            sniff_content_type(&"w\u{f6}".as_bytes()[..2]),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            sniff_content_type(b"\x00\x01\x02\x03"),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(
+            escape_html("<a href=\"x\">&'"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;"
+        );
+    }
+}
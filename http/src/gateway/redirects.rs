@@ -0,0 +1,391 @@
+//! The [`_redirects` file] of the websites served from the subdomains and the DNSLink hosts,
+//! which defines the redirects, the rewrites like the fallbacks of the single page applications,
+//! and the custom error pages of the paths not found in the website.
+//!
+//! The file is read from the root of the website only when the requested path is not found, so
+//! the rules cannot shadow the existing files. The rules are matched in order, and the first
+//! matching one is applied. The forced rules, the query parameters and the country or language
+//! conditions of the other implementations are not supported.
+//!
+//! [`_redirects` file]: https://specs.ipfs.tech/http-gateways/web-redirects-file/
+
+use super::{file_size, file_stream, parse_tail, plaintext, GatewayRequest, UpstreamGateways};
+use crate::v0::root_files::resolve_dagpb;
+use futures::stream::TryStreamExt;
+use ipfs::{Ipfs, IpfsPath, IpfsTypes};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use thiserror::Error;
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+
+/// The name of the file at the root of the website.
+const REDIRECTS_FILE: &str = "_redirects";
+
+/// The largest `_redirects` file read, as in the specification.
+const MAX_SIZE: u64 = 64 * 1024;
+
+/// The characters percent-encoded in the expanded `Location` of the redirects.
+const LOCATION: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'<').add(b'>').add(b'`');
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(super) enum RedirectsError {
+    #[error("the _redirects file is larger than {} bytes", MAX_SIZE)]
+    TooLarge,
+    #[error("the _redirects file is not valid utf-8")]
+    InvalidUtf8,
+    #[error("line {0} of _redirects: expected the from and to paths and an optional status")]
+    Fields(usize),
+    #[error("line {0} of _redirects: the from path must start with /")]
+    From(usize),
+    #[error("line {0} of _redirects: the splat can only end the from path")]
+    Splat(usize),
+    #[error("line {0} of _redirects: the forced rules are not supported")]
+    Forced(usize),
+    #[error("line {0} of _redirects: unsupported status {1:?}")]
+    Status(usize, String),
+    #[error("line {0} of _redirects: the rewrites and the error pages must be paths")]
+    NotAPath(usize),
+}
+
+/// A segment of the from path of a [`Rule`].
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// A `:name` placeholder matching any single segment.
+    Placeholder(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Rule {
+    from: Vec<Segment>,
+    /// The from path ends with a `*` matching the rest of the path, even if empty.
+    splat: bool,
+    to: String,
+    status: StatusCode,
+}
+
+/// The parsed rules of a `_redirects` file.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Redirects {
+    rules: Vec<Rule>,
+}
+
+impl Redirects {
+    pub(super) fn parse(file: &str) -> Result<Self, RedirectsError> {
+        let mut rules = Vec::new();
+
+        for (index, line) in file.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (from, to, status) = match fields.as_slice() {
+                [from, to] => (*from, *to, None),
+                [from, to, status] => (*from, *to, Some(*status)),
+                _ => return Err(RedirectsError::Fields(number)),
+            };
+
+            if !from.starts_with('/') {
+                return Err(RedirectsError::From(number));
+            }
+
+            let status = match status {
+                None => StatusCode::MOVED_PERMANENTLY,
+                Some(status) if status.ends_with('!') => {
+                    return Err(RedirectsError::Forced(number))
+                }
+                Some(status) => match status.parse::<u16>() {
+                    Ok(code @ 200) | Ok(code @ 301..=303) | Ok(code @ 307..=308)
+                    | Ok(code @ 404) | Ok(code @ 410) | Ok(code @ 451) => {
+                        StatusCode::from_u16(code).expect("supported status codes are valid")
+                    }
+                    _ => return Err(RedirectsError::Status(number, status.to_owned())),
+                },
+            };
+
+            if !status.is_redirection() && !to.starts_with('/') {
+                return Err(RedirectsError::NotAPath(number));
+            }
+
+            let mut segments = from.trim_matches('/').split('/').peekable();
+            let mut parsed = Vec::new();
+            let mut splat = false;
+
+            while let Some(segment) = segments.next() {
+                if segment == "*" {
+                    if segments.peek().is_some() {
+                        return Err(RedirectsError::Splat(number));
+                    }
+                    splat = true;
+                } else if let Some(name) = segment.strip_prefix(':') {
+                    parsed.push(Segment::Placeholder(name.to_owned()));
+                } else if !segment.is_empty() {
+                    parsed.push(Segment::Literal(segment.to_owned()));
+                }
+            }
+
+            rules.push(Rule {
+                from: parsed,
+                splat,
+                to: to.to_owned(),
+                status,
+            });
+        }
+
+        Ok(Redirects { rules })
+    }
+
+    /// Returns the expanded target and the status of the first rule matching the `path` within
+    /// the website, which starts with a `/`.
+    pub(super) fn find(&self, path: &str) -> Option<(String, StatusCode)> {
+        let segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+
+        self.rules.iter().find_map(|rule| {
+            let matched = rule.matches(&segments)?;
+            Some((expand(&rule.to, &matched), rule.status))
+        })
+    }
+}
+
+impl Rule {
+    /// Returns the values of the placeholders, and of the splat as `splat`, if the rule matches
+    /// the `segments`.
+    fn matches(&self, segments: &[&str]) -> Option<Vec<(&str, String)>> {
+        if segments.len() < self.from.len() || (!self.splat && segments.len() > self.from.len()) {
+            return None;
+        }
+
+        let mut values = Vec::new();
+        for (pattern, segment) in self.from.iter().zip(segments) {
+            match pattern {
+                Segment::Literal(literal) if literal == segment => {}
+                Segment::Literal(_) => return None,
+                Segment::Placeholder(name) => values.push((name.as_str(), (*segment).to_owned())),
+            }
+        }
+
+        if self.splat {
+            values.push(("splat", segments[self.from.len()..].join("/")));
+        }
+
+        Some(values)
+    }
+}
+
+/// Replaces the `:name` placeholders of the `to` with their values, longest names first so that
+/// `:name` does not replace the start of `:names`.
+fn expand(to: &str, values: &[(&str, String)]) -> String {
+    let mut values = values.iter().collect::<Vec<_>>();
+    values.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+
+    let mut expanded = to.to_owned();
+    for (name, value) in values {
+        expanded = expanded.replace(&format!(":{}", name), value);
+    }
+    expanded
+}
+
+impl GatewayRequest<'_> {
+    /// Responds to a path which was not found through the `_redirects` file of the website, or
+    /// with the `not_found` response if the request is not for a website, or no rule matches.
+    pub(super) async fn redirects<T: IpfsTypes>(
+        &self,
+        ipfs: Ipfs<T>,
+        upstreams: Option<UpstreamGateways>,
+        not_found: Response<Body>,
+    ) -> Response<Body> {
+        if !self.isolated_origin {
+            return not_found;
+        }
+
+        // the website is the Cid or the name at the root of the path
+        let tail = self.tail.as_str();
+        let (site, path) = match tail.find('/') {
+            Some(at) => tail.split_at(at),
+            None => (tail, ""),
+        };
+        let path = percent_decode_str(path).decode_utf8_lossy();
+
+        let root = match parse_tail(self.namespace, site, &ipfs).await {
+            Ok(root) => root,
+            Err(_) => return not_found,
+        };
+
+        let resolve = |path: IpfsPath| {
+            let ipfs = ipfs.clone();
+            let upstreams = upstreams.clone();
+            async move {
+                match &upstreams {
+                    Some(upstreams) => upstreams.resolve_dagpb(&ipfs, path).await.ok(),
+                    None => resolve_dagpb(&ipfs, path).await.ok(),
+                }
+            }
+        };
+
+        let file = match root.sub_path(REDIRECTS_FILE) {
+            Ok(file) => resolve(file).await,
+            Err(_) => None,
+        };
+        let file = match file {
+            Some(file) => file,
+            None => return not_found,
+        };
+
+        let size = match file_size(&file) {
+            Ok(Some(size)) if size <= MAX_SIZE => size,
+            Ok(Some(_)) => {
+                let e = RedirectsError::TooLarge;
+                return plaintext(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+            }
+            _ => return not_found,
+        };
+
+        let contents = file_stream(ipfs.clone(), file, 0..size, upstreams.clone())
+            .try_fold(Vec::new(), |mut contents, bytes| async move {
+                contents.extend_from_slice(&bytes);
+                Ok(contents)
+            })
+            .await;
+
+        let redirects = match contents {
+            Ok(contents) => String::from_utf8(contents)
+                .map_err(|_| RedirectsError::InvalidUtf8)
+                .and_then(|contents| Redirects::parse(&contents)),
+            Err(e) => return plaintext(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        };
+
+        let (to, status) = match redirects {
+            Ok(redirects) => match redirects.find(&path) {
+                Some(found) => found,
+                None => return not_found,
+            },
+            Err(e) => return plaintext(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        };
+
+        if status.is_redirection() {
+            return Response::builder()
+                .status(status)
+                .header(
+                    header::LOCATION,
+                    utf8_percent_encode(&to, LOCATION).to_string(),
+                )
+                .body(Body::empty())
+                .expect("the location is percent-encoded");
+        }
+
+        // the rewrites and the error pages are served from the website, and the directories
+        // through their index.html
+        let target = match root.sub_path(to.trim_start_matches('/')) {
+            Ok(target) => target,
+            Err(_) => return not_found,
+        };
+        let (target, block) = match resolve(target.clone()).await {
+            Some(block) => (target, block),
+            None => return not_found,
+        };
+
+        let (target, block, size) = match file_size(&block) {
+            Ok(Some(size)) => (target, block, size),
+            Ok(None) => {
+                let index = match target.sub_path("index.html") {
+                    Ok(index) => index,
+                    Err(_) => return not_found,
+                };
+                match resolve(index.clone()).await {
+                    Some(block) => match file_size(&block) {
+                        Ok(Some(size)) => (index, block, size),
+                        _ => return not_found,
+                    },
+                    None => return not_found,
+                }
+            }
+            Err(_) => return not_found,
+        };
+
+        let mut resp = self.file(ipfs, upstreams, &target, block, size).await;
+        if resp.status() == StatusCode::OK {
+            *resp.status_mut() = status;
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Redirects, RedirectsError};
+    use warp::http::StatusCode;
+
+    const FILE: &str = "\
+# moved pages
+/old            /new
+/blog/:year/:slug   /posts/:year-:slug  302
+
+/docs/*         https://docs.example.com/:splat  308
+/gone/*         /410.html  410
+/app/*          /app/index.html  200
+/*              /404.html  404
+";
+
+    #[test]
+    fn rules_match_in_order() {
+        let redirects = Redirects::parse(FILE).unwrap();
+
+        let cases = [
+            ("/old", Some(("/new", 301))),
+            ("/old/", Some(("/new", 301))),
+            ("/blog/2023/hello", Some(("/posts/2023-hello", 302))),
+            (
+                "/docs/a/b.html",
+                Some(("https://docs.example.com/a/b.html", 308)),
+            ),
+            ("/docs", Some(("https://docs.example.com/", 308))),
+            ("/gone/x", Some(("/410.html", 410))),
+            ("/app/settings/profile", Some(("/app/index.html", 200))),
+            ("/blog/2023", Some(("/404.html", 404))),
+            ("/", Some(("/404.html", 404))),
+        ];
+
+        for (path, expected) in cases.iter() {
+            let found = redirects.find(path);
+            let found = found
+                .as_ref()
+                .map(|(to, status)| (to.as_str(), status.as_u16()));
+            assert_eq!(found, *expected, "{}", path);
+        }
+
+        let empty = Redirects::parse("# nothing here\n").unwrap();
+        assert_eq!(empty.find("/old"), None);
+    }
+
+    #[test]
+    fn placeholders_are_replaced_whole() {
+        let redirects = Redirects::parse("/:a/:ab /:ab/:a").unwrap();
+        assert_eq!(
+            redirects.find("/x/y"),
+            Some(("/y/x".into(), StatusCode::MOVED_PERMANENTLY))
+        );
+    }
+
+    #[test]
+    fn invalid_files() {
+        let cases = [
+            ("/a", RedirectsError::Fields(1)),
+            ("\n/a /b 301 extra", RedirectsError::Fields(2)),
+            ("a /b", RedirectsError::From(1)),
+            ("/a/*/b /c", RedirectsError::Splat(1)),
+            ("/a /b 301!", RedirectsError::Forced(1)),
+            ("/a /b 500", RedirectsError::Status(1, "500".into())),
+            ("/a https://example.com 200", RedirectsError::NotAPath(1)),
+        ];
+
+        for (file, expected) in cases.iter() {
+            assert_eq!(Redirects::parse(file).unwrap_err(), *expected, "{:?}", file);
+        }
+    }
+}
@@ -0,0 +1,457 @@
+//! The [subdomain gateway] and the DNSLink hosts, which give every Cid, IPNS name and DNSLink
+//! website an origin of its own in the browsers, so that they cannot access each other's cookies
+//! and storage.
+//!
+//! The requests to the subdomains of the configured [`GatewayHosts::subdomains`], like
+//! `<cid>.ipfs.example.com/a` or `<name>.ipns.example.com/a`, are rewritten into the gateway
+//! paths `/ipfs/<cid>/a` and `/ipns/<name>/a` before they are routed, so no other routes, like the
+//! API, can be reached through them. The path style requests to the configured hosts themselves
+//! are redirected to the subdomains.
+//!
+//! The subdomains use the base32 CIDv1 of the Cids, and the base36 CIDv1 of the IPNS keys, as
+//! the labels of the domain names are case insensitive and at most 63 characters long. The
+//! DNSLink names are inlined into a single label by replacing the `-` with `--` and the `.` with
+//! `-`.
+//!
+//! With [`GatewayHosts::dnslink`], the requests to any other domain name with a DNSLink are
+//! rewritten into `/ipns/<domain>/..`, so that a gateway can serve the websites pointing their
+//! DNS at it.
+//!
+//! [subdomain gateway]: https://specs.ipfs.tech/http-gateways/subdomain-gateway/
+
+use super::plaintext;
+use futures::future::{BoxFuture, FutureExt};
+use ipfs::{Cid, Ipfs, IpfsPath, IpfsTypes, PeerId};
+use std::convert::{Infallible, TryFrom};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use warp::http::uri::PathAndQuery;
+use warp::http::{header, Request, Response, StatusCode, Uri};
+use warp::hyper::Body;
+
+/// The multicodec of the public keys, used for the IPNS keys in the CIDv1 form.
+const LIBP2P_KEY: u8 = 0x72;
+
+/// The longest label of a domain name.
+const MAX_LABEL_LEN: usize = 63;
+
+/// Marks the requests rewritten from a subdomain or a DNSLink host, whose paths are rooted at a
+/// website with an origin of its own.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct IsolatedOrigin;
+
+/// The hostnames the gateway is served at, and how the requests to them are handled. The
+/// defaults handle the requests by their path alone.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GatewayHosts {
+    /// The hosts of the subdomain gateways, like `example.com` for `<cid>.ipfs.example.com`,
+    /// without a port.
+    pub subdomains: Vec<String>,
+    /// Serve the DNSLink websites of the other domain names the requests are sent to.
+    pub dnslink: bool,
+}
+
+/// How a request is handled, decided from its host and path.
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+    /// Routed as it is.
+    Unchanged,
+    /// Routed as a request to the gateway path.
+    Rewrite(String),
+    /// Redirected to the subdomain.
+    Redirect(String),
+    /// Routed as a request to the `/ipns/` path of the domain if it has a DNSLink.
+    DnsLink(String),
+    /// The subdomain is not a valid Cid or name.
+    Invalid(String),
+}
+
+impl GatewayHosts {
+    /// Returns true if the requests are handled by their path alone.
+    pub fn is_empty(&self) -> bool {
+        self.subdomains.is_empty() && !self.dnslink
+    }
+
+    fn route(&self, host: &str, uri: &Uri, forwarded_proto: Option<&str>) -> Route {
+        let (hostname, port) = split_port(host);
+        let hostname = hostname.to_ascii_lowercase();
+        let path = uri.path();
+        let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
+
+        for gateway in &self.subdomains {
+            if hostname == *gateway {
+                return match redirect_label(path) {
+                    Some((label, namespace, rest)) => Route::Redirect(format!(
+                        "{}://{}.{}.{}{}{}{}",
+                        forwarded_proto.unwrap_or("http"),
+                        label,
+                        namespace,
+                        gateway,
+                        port,
+                        rest,
+                        query
+                    )),
+                    None => Route::Unchanged,
+                };
+            }
+
+            let subdomain = match hostname
+                .strip_suffix(gateway.as_str())
+                .and_then(|rest| rest.strip_suffix('.'))
+            {
+                Some(subdomain) => subdomain,
+                None => continue,
+            };
+
+            return match subdomain.split_once('.') {
+                Some((label, "ipfs")) => match Cid::try_from(label) {
+                    Ok(cid) => Route::Rewrite(format!("/ipfs/{}{}{}", cid, path, query)),
+                    Err(_) => Route::Invalid(format!("invalid cid {:?}", label)),
+                },
+                Some((label, "ipns")) if !label.is_empty() => {
+                    Route::Rewrite(format!("/ipns/{}{}{}", ipns_name(label), path, query))
+                }
+                _ => Route::Invalid(format!("unsupported subdomain {:?}", subdomain)),
+            };
+        }
+
+        if self.dnslink && is_domain_name(&hostname) {
+            return Route::DnsLink(hostname);
+        }
+
+        Route::Unchanged
+    }
+}
+
+/// Wraps the `service`, usually created with [`warp::service`], to handle the requests to the
+/// `hosts` as described in the [module documentation](self).
+pub fn with_hosts<T, S, F>(
+    hosts: GatewayHosts,
+    ipfs: &Ipfs<T>,
+    mut service: S,
+) -> impl FnMut(Request<Body>) -> BoxFuture<'static, Result<Response<Body>, Infallible>> + Clone
+where
+    T: IpfsTypes,
+    S: FnMut(Request<Body>) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+{
+    let hosts = Arc::new(hosts);
+    let ipfs = ipfs.clone();
+
+    move |mut req: Request<Body>| {
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| req.uri().authority().map(|authority| authority.as_str()));
+
+        let forwarded_proto = req
+            .headers()
+            .get("x-forwarded-proto")
+            .and_then(|value| value.to_str().ok());
+
+        let route = match host {
+            Some(host) => hosts.route(host, req.uri(), forwarded_proto),
+            None => Route::Unchanged,
+        };
+
+        match route {
+            Route::Unchanged => service(req).boxed(),
+            Route::Rewrite(path) => {
+                if rewrite(&mut req, &path) {
+                    service(req).boxed()
+                } else {
+                    async move { Ok(plaintext(StatusCode::BAD_REQUEST, "invalid path")) }.boxed()
+                }
+            }
+            Route::Redirect(location) => async move {
+                let resp = Response::builder()
+                    .status(StatusCode::MOVED_PERMANENTLY)
+                    .header(header::LOCATION, location)
+                    .body(Body::empty())
+                    .expect("the location is made of the valid host and path");
+                Ok(resp)
+            }
+            .boxed(),
+            Route::Invalid(msg) => {
+                async move { Ok(plaintext(StatusCode::BAD_REQUEST, msg)) }.boxed()
+            }
+            Route::DnsLink(domain) => {
+                let ipfs = ipfs.clone();
+                let mut service = service.clone();
+
+                async move {
+                    if has_dnslink(&ipfs, &domain).await {
+                        let path = match req.uri().path_and_query() {
+                            Some(pq) => format!("/ipns/{}{}", domain, pq),
+                            None => format!("/ipns/{}/", domain),
+                        };
+                        rewrite(&mut req, &path);
+                    }
+                    service(req).await
+                }
+                .boxed()
+            }
+        }
+    }
+}
+
+/// Replaces the path and the query of the request, marking it with [`IsolatedOrigin`], or returns
+/// false if they are invalid.
+fn rewrite(req: &mut Request<Body>, path_and_query: &str) -> bool {
+    match PathAndQuery::from_str(path_and_query) {
+        Ok(pq) => {
+            let mut parts = req.uri().clone().into_parts();
+            parts.path_and_query = Some(pq);
+            match Uri::from_parts(parts) {
+                Ok(uri) => {
+                    *req.uri_mut() = uri;
+                    req.extensions_mut().insert(IsolatedOrigin);
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+        Err(_) => false,
+    }
+}
+
+async fn has_dnslink<T: IpfsTypes>(ipfs: &Ipfs<T>, domain: &str) -> bool {
+    match IpfsPath::from_str(&format!("/ipns/{}", domain)) {
+        Ok(path) => ipfs.resolve_ipns_cached(&path, true).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Returns the subdomain label, the namespace and the rest of the path of the path style
+/// request, or `None` if it cannot be redirected.
+fn redirect_label(path: &str) -> Option<(String, &'static str, &str)> {
+    let (namespace, rest) = if let Some(rest) = path.strip_prefix("/ipfs/") {
+        ("ipfs", rest)
+    } else if let Some(rest) = path.strip_prefix("/ipns/") {
+        ("ipns", rest)
+    } else {
+        return None;
+    };
+
+    let (name, rest) = match rest.find('/') {
+        Some(at) => rest.split_at(at),
+        None => (rest, "/"),
+    };
+
+    let label = if namespace == "ipfs" {
+        let cid = Cid::try_from(name).ok()?;
+        Cid::new_v1(cid.codec(), cid.hash().to_owned()).to_string()
+    } else if let Ok(peer_id) = PeerId::from_str(name) {
+        let mut bytes = vec![1, LIBP2P_KEY];
+        bytes.extend_from_slice(&peer_id.to_bytes());
+        format!("k{}", base36_encode(&bytes))
+    } else if is_domain_name(name) {
+        name.replace('-', "--").replace('.', "-")
+    } else {
+        return None;
+    };
+
+    // served from the path instead
+    if label.len() > MAX_LABEL_LEN {
+        return None;
+    }
+
+    Some((label, namespace, rest))
+}
+
+/// Returns the IPNS name of the label of an `ipns` subdomain.
+fn ipns_name(label: &str) -> String {
+    let key = label
+        .strip_prefix('k')
+        .and_then(base36_decode)
+        .filter(|bytes| bytes.starts_with(&[1, LIBP2P_KEY]))
+        .and_then(|bytes| PeerId::from_bytes(&bytes[2..]).ok());
+
+    match key {
+        Some(peer_id) => peer_id.to_base58(),
+        // an inlined DNSLink name
+        None => label
+            .split("--")
+            .map(|part| part.replace('-', "."))
+            .collect::<Vec<_>>()
+            .join("-"),
+    }
+}
+
+/// Splits the host into the hostname and the port including the `:`, if any.
+fn split_port(host: &str) -> (&str, &str) {
+    match host.rfind(':') {
+        Some(at)
+            if !host.starts_with('[') && host[at + 1..].bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            host.split_at(at)
+        }
+        _ => (host, ""),
+    }
+}
+
+/// Returns true for the hostnames which are not IP addresses or single labels like `localhost`.
+fn is_domain_name(hostname: &str) -> bool {
+    hostname.contains('.')
+        && !hostname.starts_with('[')
+        && hostname.parse::<std::net::IpAddr>().is_err()
+}
+
+/// The digits of the base36 encoding of the multibase specification.
+const BASE36: &[u8; 36] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// Encodes the `bytes` in base36 without the `k` prefix of the multibase.
+fn base36_encode(bytes: &[u8]) -> String {
+    let zeros = bytes.iter().take_while(|b| **b == 0).count();
+
+    // the digits of the big endian number in the bytes, least significant first
+    let mut digits: Vec<u8> = Vec::with_capacity(bytes.len() * 2);
+    for &byte in &bytes[zeros..] {
+        let mut carry = u32::from(byte);
+        for digit in digits.iter_mut() {
+            carry += u32::from(*digit) << 8;
+            *digit = (carry % 36) as u8;
+            carry /= 36;
+        }
+        while carry > 0 {
+            digits.push((carry % 36) as u8);
+            carry /= 36;
+        }
+    }
+
+    std::iter::repeat('0')
+        .take(zeros)
+        .chain(digits.iter().rev().map(|d| BASE36[*d as usize] as char))
+        .collect()
+}
+
+/// Decodes the base36 without the `k` prefix of the multibase, or returns `None` if it is not
+/// base36.
+fn base36_decode(encoded: &str) -> Option<Vec<u8>> {
+    let zeros = encoded.bytes().take_while(|c| *c == b'0').count();
+
+    // the bytes of the number, least significant first
+    let mut bytes: Vec<u8> = Vec::with_capacity(encoded.len());
+    for c in encoded.bytes().skip(zeros) {
+        let mut carry = BASE36.iter().position(|d| *d == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += u32::from(*byte) * 36;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+
+    let mut decoded = vec![0u8; zeros];
+    decoded.extend(bytes.iter().rev());
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base36_decode, base36_encode, ipns_name, GatewayHosts, Route};
+    use warp::http::Uri;
+
+    fn hosts() -> GatewayHosts {
+        GatewayHosts {
+            subdomains: vec!["example.com".into()],
+            dnslink: true,
+        }
+    }
+
+    fn route(host: &str, uri: &str) -> Route {
+        hosts().route(host, &uri.parse::<Uri>().unwrap(), None)
+    }
+
+    #[test]
+    fn path_style_is_redirected() {
+        assert_eq!(
+            route(
+                "example.com:8080",
+                "/ipfs/QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn/a/b?x=1"
+            ),
+            Route::Redirect(
+                "http://bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354.ipfs.\
+                 example.com:8080/a/b?x=1"
+                    .into()
+            )
+        );
+
+        assert_eq!(
+            route("example.com", "/ipns/en.wikipedia-on-ipfs.org"),
+            Route::Redirect("http://en-wikipedia--on--ipfs-org.ipns.example.com/".into())
+        );
+
+        // the other routes of the gateway host are left alone
+        assert_eq!(route("example.com", "/api/v0/id"), Route::Unchanged);
+    }
+
+    #[test]
+    fn subdomains_are_rewritten() {
+        assert_eq!(
+            route(
+                "bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354.ipfs.Example.com",
+                "/api/v0/id?arg=1"
+            ),
+            Route::Rewrite(
+                "/ipfs/bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354/api/v0/id?arg=1"
+                    .into()
+            )
+        );
+
+        assert_eq!(
+            route("en-wikipedia--on--ipfs-org.ipns.example.com", "/wiki/"),
+            Route::Rewrite("/ipns/en.wikipedia-on-ipfs.org/wiki/".into())
+        );
+
+        assert!(matches!(
+            route("not-a-cid.ipfs.example.com", "/"),
+            Route::Invalid(_)
+        ));
+    }
+
+    #[test]
+    fn other_domains_are_dnslink_candidates() {
+        assert_eq!(
+            route("docs.ipfs.tech", "/"),
+            Route::DnsLink("docs.ipfs.tech".into())
+        );
+        assert_eq!(route("localhost:5001", "/"), Route::Unchanged);
+        assert_eq!(route("127.0.0.1:5001", "/"), Route::Unchanged);
+        assert_eq!(route("[::1]:5001", "/"), Route::Unchanged);
+    }
+
+    #[test]
+    fn ipns_keys_roundtrip() {
+        let peer_id = ipfs::PeerId::random();
+
+        let location = match route("example.com", &format!("/ipns/{}", peer_id)) {
+            Route::Redirect(location) => location,
+            other => panic!("unexpected {:?}", other),
+        };
+        let label = location
+            .strip_prefix("http://")
+            .and_then(|rest| rest.split('.').next())
+            .unwrap();
+
+        assert!(label.len() <= 63);
+        assert_eq!(ipns_name(label), peer_id.to_base58());
+    }
+
+    #[test]
+    fn base36() {
+        // from the multibase test vectors
+        assert_eq!(base36_encode(b"yes mani !"), "2lcpzo5yikidynfl");
+        assert_eq!(base36_encode(b"\0\0yes mani !"), "002lcpzo5yikidynfl");
+        assert_eq!(
+            base36_decode("002lcpzo5yikidynfl").unwrap(),
+            b"\0\0yes mani !".to_vec()
+        );
+        assert_eq!(base36_decode("not base36!"), None);
+    }
+}
@@ -0,0 +1,188 @@
+//! The responses of the [trustless gateway] specification: the single blocks in the raw format,
+//! and the CAR files of the blocks along the path followed by the DAG at the end of it. The
+//! clients verify them against the Cids instead of trusting the gateway.
+//!
+//! The paths are resolved through the UnixFS directories, including the HAMT sharded ones, whose
+//! blocks are all needed to verify the path.
+//!
+//! [trustless gateway]: https://specs.ipfs.tech/http-gateways/trustless-gateway/
+
+use super::{
+    parse_tail, plaintext, GatewayRequest, ResponseFormat, UpstreamGateways, CAR_MEDIA_TYPE,
+    RAW_BLOCK_MEDIA_TYPE,
+};
+use crate::v0::support::StringError;
+use futures::stream::TryStreamExt;
+use ipfs::car::CarExport;
+use ipfs::popularity::RequestSource;
+use ipfs::unixfs::ll::{resolve, MaybeResolved};
+use ipfs::{Block, Cid, Ipfs, IpfsPath, IpfsTypes};
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+
+impl GatewayRequest<'_> {
+    /// Responds with the [`ResponseFormat::Raw`] or the [`ResponseFormat::Car`] of the path.
+    pub(super) async fn trustless<T: IpfsTypes>(
+        &self,
+        ipfs: Ipfs<T>,
+        upstreams: Option<UpstreamGateways>,
+        format: ResponseFormat,
+    ) -> Response<Body> {
+        let path = match parse_tail(self.namespace, self.tail.as_str(), &ipfs).await {
+            Ok(path) => path,
+            Err(resp) => return resp,
+        };
+
+        let (path_blocks, block) = match resolve_blocks(&ipfs, &upstreams, &path).await {
+            Ok(resolved) => resolved,
+            Err(e) => return plaintext(StatusCode::NOT_FOUND, e.to_string()),
+        };
+
+        if !self.head {
+            ipfs.popularity().record(&block.cid, RequestSource::Gateway);
+        }
+
+        match format {
+            ResponseFormat::Raw => self.raw(block),
+            ResponseFormat::Car => self.car(ipfs, path_blocks, block),
+            _ => unreachable!("not a trustless format"),
+        }
+    }
+
+    fn raw(&self, block: Block) -> Response<Body> {
+        let etag = format!("\"{}.raw\"", block.cid);
+        if let Some(not_modified) = self.not_modified(&etag) {
+            return not_modified;
+        }
+
+        let builder = self
+            .common_headers(&etag)
+            .header(header::CONTENT_TYPE, RAW_BLOCK_MEDIA_TYPE)
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.bin\"", block.cid),
+            )
+            .header(header::CONTENT_LENGTH, block.data.len())
+            .header(header::VARY, "Accept");
+
+        let body = if self.head {
+            Body::empty()
+        } else {
+            Body::from(block.into_vec())
+        };
+
+        builder.body(body).expect("all headers are valid")
+    }
+
+    fn car<T: IpfsTypes>(
+        &self,
+        ipfs: Ipfs<T>,
+        path_blocks: Vec<Block>,
+        block: Block,
+    ) -> Response<Body> {
+        // the blocks along the path are part of the response
+        let etag = match path_blocks.first() {
+            Some(root) => format!("\"{}.{}.car\"", root.cid, block.cid),
+            None => format!("\"{}.car\"", block.cid),
+        };
+        if let Some(not_modified) = self.not_modified(&etag) {
+            return not_modified;
+        }
+
+        let builder = self
+            .common_headers(&etag)
+            .header(
+                header::CONTENT_TYPE,
+                format!("{}; version=1", CAR_MEDIA_TYPE),
+            )
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.car\"", block.cid),
+            )
+            .header(header::VARY, "Accept");
+
+        let body = if self.head {
+            Body::empty()
+        } else {
+            let export = CarExport::default()
+                .with_path_blocks(path_blocks)
+                .export(ipfs, block.cid)
+                .into_stream();
+            Body::wrap_stream(export)
+        };
+
+        builder.body(body).expect("all headers are valid")
+    }
+}
+
+/// Resolves the `path` through the UnixFS directories, returning the blocks needed to verify the
+/// path, starting from its root, and the block at the end of the path, which can be of any codec.
+async fn resolve_blocks<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+    upstreams: &Option<UpstreamGateways>,
+    path: &IpfsPath,
+) -> Result<(Vec<Block>, Block), StringError> {
+    let root = match path.root().cid() {
+        Some(cid) => cid,
+        None => return Err(StringError::from(format!("unresolved path {}", path))),
+    };
+
+    let mut path_blocks = Vec::new();
+    let mut block = load(ipfs, upstreams, root).await?;
+    let mut cache = None;
+
+    for segment in path.iter() {
+        if block.cid.codec() != cid::Codec::DagProtobuf {
+            return Err(StringError::from(format!(
+                "cannot resolve {:?} through the {:?} block {}",
+                segment,
+                block.cid.codec(),
+                block.cid
+            )));
+        }
+
+        let mut resolved = resolve(&block.data, segment, &mut cache)?;
+        let mut buckets = Vec::new();
+
+        let cid = loop {
+            match resolved {
+                MaybeResolved::Found(cid) => break cid,
+                MaybeResolved::NeedToLoadMore(lookup) => {
+                    let bucket = load(ipfs, upstreams, lookup.pending_links().0).await?;
+                    resolved = lookup.continue_walk(&bucket.data, &mut cache)?;
+                    buckets.push(bucket);
+                }
+                MaybeResolved::NotFound => {
+                    return Err(StringError::from(format!(
+                        "no link named {:?} under {}",
+                        segment, block.cid
+                    )))
+                }
+                MaybeResolved::Symlink(target) => {
+                    return Err(StringError::from(format!(
+                        "path goes through the symlink {} to {:?}",
+                        block.cid,
+                        String::from_utf8_lossy(&target)
+                    )))
+                }
+            }
+        };
+
+        let next = load(ipfs, upstreams, &cid).await?;
+        path_blocks.push(std::mem::replace(&mut block, next));
+        path_blocks.extend(buckets);
+    }
+
+    Ok((path_blocks, block))
+}
+
+async fn load<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+    upstreams: &Option<UpstreamGateways>,
+    cid: &Cid,
+) -> Result<Block, ipfs::Error> {
+    match upstreams {
+        Some(upstreams) => upstreams.get_block(ipfs, cid).await,
+        None => ipfs.get_block(cid).await,
+    }
+}
@@ -0,0 +1,299 @@
+//! Fetching of the blocks missing from the local blockstore from upstream gateways, in parallel
+//! with the fetch over bitswap and the DHT provider lookup it starts.
+//!
+//! The blocks are requested in the raw block format of the [trustless gateway] specification and
+//! are verified against the multihash of the Cid before they are put into the local blockstore,
+//! so the upstream gateways need not be trusted. Putting the block completes the bitswap fetch of
+//! the same block, and with it every request waiting for the block, so a block is only requested
+//! from the upstream gateways once no matter how many requests are waiting for it.
+//!
+//! [trustless gateway]: https://specs.ipfs.tech/http-gateways/trustless-gateway/
+
+use super::RAW_BLOCK_MEDIA_TYPE;
+use crate::v0::root_files;
+use crate::v0::support::StringError;
+use futures::future::{select_ok, FutureExt};
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Request, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use ipfs::ipld::MAX_BLOCK_SIZE;
+use ipfs::unixfs::ll::{resolve, MaybeResolved};
+use ipfs::{Block, Cid, Ipfs, IpfsPath, IpfsTypes};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use url::Url;
+
+/// How long a single upstream gateway is given to respond with the block.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The upstream gateways the blocks missing from the local blockstore are fetched from.
+#[derive(Clone)]
+pub struct UpstreamGateways {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    client: Client<HttpsConnector<HttpConnector>>,
+    urls: Vec<Url>,
+    /// The upstream fetches in progress, at most one per block.
+    in_flight: Mutex<HashMap<Cid, JoinHandle<()>>>,
+}
+
+impl UpstreamGateways {
+    /// Creates the upstream gateways from their base urls, like `https://ipfs.io`. Returns `None`
+    /// if there are no urls.
+    pub fn new(urls: Vec<Url>) -> Option<Self> {
+        if urls.is_empty() {
+            return None;
+        }
+
+        let urls = urls
+            .into_iter()
+            .map(|mut url| {
+                // the request paths are joined relative to the base url
+                if !url.path().ends_with('/') {
+                    let path = format!("{}/", url.path());
+                    url.set_path(&path);
+                }
+                url
+            })
+            .collect();
+
+        // the upstream certificates are verified against the bundled mozilla roots
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .build();
+
+        let inner = Inner {
+            client: Client::builder().build(connector),
+            urls,
+            in_flight: Default::default(),
+        };
+
+        Some(UpstreamGateways {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Retrieves a block from the local blockstore, or fetches it from the upstream gateways and
+    /// over bitswap at the same time, returning the first verified block.
+    pub async fn get_block<T: IpfsTypes>(
+        &self,
+        ipfs: &Ipfs<T>,
+        cid: &Cid,
+    ) -> Result<Block, ipfs::Error> {
+        if let Some(block) = ipfs
+            .get_blocks_now(std::slice::from_ref(cid))
+            .await?
+            .pop()
+            .flatten()
+        {
+            return Ok(block);
+        }
+
+        self.spawn_fetch(ipfs, cid);
+
+        // completed by the upstream fetch putting the block, unless bitswap is faster
+        let res = ipfs.get_block(cid).await;
+
+        if res.is_ok() {
+            if let Some(fetch) = self.inner.in_flight.lock().unwrap().remove(cid) {
+                fetch.abort();
+            }
+        }
+
+        res
+    }
+
+    /// Resolves the `path` into the dag-pb block it points to like
+    /// [`root_files::resolve_dagpb`], loading the blocks along the path with
+    /// [`UpstreamGateways::get_block`].
+    pub(crate) async fn resolve_dagpb<T: IpfsTypes>(
+        &self,
+        ipfs: &Ipfs<T>,
+        path: IpfsPath,
+    ) -> Result<Block, StringError> {
+        let mut block = match path.root().cid() {
+            Some(cid) => self.get_block(ipfs, cid).await?,
+            None => return root_files::resolve_dagpb(ipfs, path).await,
+        };
+
+        let mut cache = None;
+
+        for segment in path.iter() {
+            let mut resolved = resolve(&block.data, segment, &mut cache)?;
+
+            let cid = loop {
+                match resolved {
+                    MaybeResolved::Found(cid) => break cid,
+                    MaybeResolved::NeedToLoadMore(lookup) => {
+                        let next = self.get_block(ipfs, lookup.pending_links().0).await?;
+                        resolved = lookup.continue_walk(&next.data, &mut cache)?;
+                    }
+                    MaybeResolved::NotFound => {
+                        return Err(StringError::from(format!(
+                            "no link named {:?} under {}",
+                            segment, block.cid
+                        )))
+                    }
+                    MaybeResolved::Symlink(target) => {
+                        return Err(StringError::from(format!(
+                            "path goes through the symlink {} to {:?}",
+                            block.cid,
+                            String::from_utf8_lossy(&target)
+                        )))
+                    }
+                }
+            };
+
+            block = self.get_block(ipfs, &cid).await?;
+        }
+
+        if block.cid.codec() != cid::Codec::DagProtobuf {
+            return Err(StringError::from(format!(
+                "expected a dag-pb block, found {:?}",
+                block.cid.codec()
+            )));
+        }
+
+        Ok(block)
+    }
+
+    /// Starts fetching the block from the upstream gateways unless it is already being fetched.
+    fn spawn_fetch<T: IpfsTypes>(&self, ipfs: &Ipfs<T>, cid: &Cid) {
+        let mut in_flight = self.inner.in_flight.lock().unwrap();
+
+        if in_flight.contains_key(cid) {
+            return;
+        }
+
+        let inner = Arc::clone(&self.inner);
+        let ipfs = ipfs.clone();
+        let key = cid.to_owned();
+        let cid = cid.to_owned();
+
+        let fetch = tokio::spawn(async move {
+            match inner.fetch(&cid).await {
+                Ok(block) => {
+                    if let Err(e) = ipfs.put_block(block).await {
+                        warn!("failed to store the block {} from upstream: {}", cid, e);
+                    }
+                }
+                Err(e) => debug!("no upstream gateway provided the block {}: {}", cid, e),
+            }
+
+            inner.in_flight.lock().unwrap().remove(&cid);
+        });
+
+        in_flight.insert(key, fetch);
+    }
+}
+
+impl Inner {
+    /// Requests the block from all of the upstream gateways, returning the first verified one.
+    async fn fetch(&self, cid: &Cid) -> Result<Block, FetchError> {
+        let requests = self.urls.iter().map(|url| {
+            tokio::time::timeout(UPSTREAM_TIMEOUT, self.fetch_from(url, cid))
+                .map(|res| res.unwrap_or_else(|_| Err(FetchError::Timeout)))
+                .boxed()
+        });
+
+        // the other requests are dropped once one succeeds
+        select_ok(requests).await.map(|(block, _)| block)
+    }
+
+    async fn fetch_from(&self, base: &Url, cid: &Cid) -> Result<Block, FetchError> {
+        let url = base.join(&format!("ipfs/{}?format=raw", cid))?;
+
+        let request = Request::get(url.as_str())
+            .header(header::ACCEPT, RAW_BLOCK_MEDIA_TYPE)
+            .body(Body::empty())
+            .expect("all headers are valid");
+
+        let response = self.client.request(request).await?;
+
+        if response.status() != StatusCode::OK {
+            return Err(FetchError::Status(response.status()));
+        }
+
+        let mut body = response.into_body();
+        let mut data = Vec::new();
+
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if data.len() + chunk.len() > MAX_BLOCK_SIZE {
+                return Err(FetchError::TooLarge);
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        let hash = cid.hash().algorithm().digest(&data);
+        if hash.as_ref() != cid.hash() {
+            return Err(FetchError::HashMismatch);
+        }
+
+        Ok(Block {
+            cid: cid.to_owned(),
+            data: data.into(),
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+enum FetchError {
+    #[error("invalid url: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("request failed: {0}")]
+    Request(#[from] hyper::Error),
+    #[error("unexpected status: {0}")]
+    Status(StatusCode),
+    #[error("the block is larger than {} bytes", MAX_BLOCK_SIZE)]
+    TooLarge,
+    #[error("the block does not match the hash of the cid")]
+    HashMismatch,
+    #[error("timed out")]
+    Timeout,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UpstreamGateways;
+    use url::Url;
+
+    #[test]
+    fn no_urls_no_upstreams() {
+        assert!(UpstreamGateways::new(Vec::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn base_urls_are_joined_as_directories() {
+        let urls = vec![
+            Url::parse("https://ipfs.io").unwrap(),
+            Url::parse("https://example.com/gateway").unwrap(),
+        ];
+
+        let upstreams = UpstreamGateways::new(urls).unwrap();
+
+        let joined = upstreams
+            .inner
+            .urls
+            .iter()
+            .map(|url| url.join("ipfs/bafy?format=raw").unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            joined,
+            &[
+                "https://ipfs.io/ipfs/bafy?format=raw",
+                "https://example.com/gateway/ipfs/bafy?format=raw",
+            ]
+        );
+    }
+}
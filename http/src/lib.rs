@@ -6,6 +6,12 @@
 #[macro_use]
 extern crate tracing;
 
+pub mod gateway;
+pub mod limits;
+pub mod log_tail;
+pub mod request_id;
+pub mod supervise;
+pub mod unix_socket;
 pub mod v0;
 
 pub mod config;
@@ -0,0 +1,249 @@
+//! Limits of the HTTP requests, hardening the API and the gateway against the oversized uploads
+//! and the slow clients.
+//!
+//! The requests with a body longer than [`Limits::max_body_size`] are answered with `413 Payload
+//! Too Large`, also when the body is only found too long while it is being read, for example by
+//! `add` or `dag/import`. The requests not answered within [`Limits::max_duration`] are answered
+//! with `408 Request Timeout`, which covers the bodies sent too slowly, and the responses still
+//! being streamed at that point are cut off. The reading of the request headers is limited by
+//! the server, see [`Limits::header_timeout`].
+
+use crate::v0::support::MessageKind;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::StreamExt;
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::Instant;
+use warp::http::{header::CONTENT_LENGTH, HeaderMap, Request, Response, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::hyper::Body;
+use warp::Reply;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The limits of the requests, see the [module documentation](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The longest accepted request body in bytes, unlimited when `None`.
+    pub max_body_size: Option<u64>,
+    /// The time the client has for sending the request headers after connecting or after the
+    /// previous request, after which the connection is closed. Needs to be applied to the
+    /// server, as the headers have been read by the time the request is handled.
+    pub header_timeout: Option<Duration>,
+    /// The time from receiving the request headers to sending the whole response, unlimited
+    /// when `None`.
+    pub max_duration: Option<Duration>,
+}
+
+impl Default for Limits {
+    /// Limits only the reading of the headers, as the large files can be added and fetched
+    /// through the API.
+    fn default() -> Self {
+        Limits {
+            max_body_size: None,
+            header_timeout: Some(Duration::from_secs(30)),
+            max_duration: None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+enum LimitError {
+    #[error("request body exceeds the limit of {0} bytes")]
+    BodyTooLarge(u64),
+    #[error("response not completed within {0:?}")]
+    ResponseTimedOut(Duration),
+}
+
+/// Wraps the `service`, like the one returned by [`crate::request_id::with_request_ids`], to
+/// apply the `limits` to every request.
+pub fn with_limits<S, F>(
+    limits: Limits,
+    mut service: S,
+) -> impl FnMut(Request<Body>) -> BoxFuture<'static, Result<Response<Body>, Infallible>> + Clone
+where
+    S: FnMut(Request<Body>) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+{
+    move |req: Request<Body>| {
+        let exceeded = Arc::new(AtomicBool::new(false));
+
+        let req = match limits.max_body_size {
+            Some(max) if content_length(req.headers()).map_or(false, |len| len > max) => {
+                return async move { Ok(too_large(max)) }.boxed();
+            }
+            Some(max) => {
+                let exceeded = Arc::clone(&exceeded);
+                req.map(move |body| limit_body(body, max, exceeded))
+            }
+            None => req,
+        };
+
+        let deadline = limits
+            .max_duration
+            .map(|duration| (Instant::now() + duration, duration));
+        let resp = service(req);
+
+        async move {
+            let resp = match deadline {
+                Some((deadline, duration)) => match tokio::time::timeout_at(deadline, resp).await {
+                    Ok(resp) => resp?,
+                    Err(_) => return Ok(timed_out(duration)),
+                },
+                None => resp.await?,
+            };
+
+            if let (true, Some(max)) = (exceeded.load(Ordering::Relaxed), limits.max_body_size) {
+                // the handler failed on the body error, which is replaced with the reason
+                return Ok(too_large(max));
+            }
+
+            Ok(match deadline {
+                Some((deadline, duration)) => resp.map(|body| cut_off(body, deadline, duration)),
+                None => resp,
+            })
+        }
+        .boxed()
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Fails the body once more than `max` bytes have been read, setting the `exceeded` flag.
+fn limit_body(body: Body, max: u64, exceeded: Arc<AtomicBool>) -> Body {
+    let mut read = 0u64;
+
+    Body::wrap_stream(body.map(move |chunk| {
+        let chunk = chunk?;
+        read += chunk.len() as u64;
+
+        if read > max {
+            exceeded.store(true, Ordering::Relaxed);
+            return Err(BoxError::from(LimitError::BodyTooLarge(max)));
+        }
+
+        Ok(chunk)
+    }))
+}
+
+/// Fails the body at the `deadline`, so that the connection is closed instead of the client
+/// being left with a response which looks complete.
+fn cut_off(body: Body, deadline: Instant, duration: Duration) -> Body {
+    let chunks = futures::stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+
+        let next: Result<Bytes, BoxError> =
+            match tokio::time::timeout_at(deadline, body.next()).await {
+                Ok(Some(Ok(chunk))) => Ok(chunk),
+                Ok(Some(Err(e))) => Err(e.into()),
+                Ok(None) => return None,
+                Err(_) => Err(LimitError::ResponseTimedOut(duration).into()),
+            };
+
+        let body = next.as_ref().ok().map(|_| body);
+        Some((next, body))
+    });
+
+    Body::wrap_stream(chunks)
+}
+
+fn too_large(max: u64) -> Response<Body> {
+    error_response(StatusCode::PAYLOAD_TOO_LARGE, LimitError::BodyTooLarge(max))
+}
+
+fn timed_out(duration: Duration) -> Response<Body> {
+    error_response(
+        StatusCode::REQUEST_TIMEOUT,
+        LimitError::ResponseTimedOut(duration),
+    )
+}
+
+fn error_response(status: StatusCode, error: LimitError) -> Response<Body> {
+    let message = MessageKind::Error
+        .with_code(0)
+        .with_message(error.to_string());
+    warp::reply::with_status(warp::reply::json(&message), status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{with_limits, Limits};
+    use futures::future::{BoxFuture, FutureExt};
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use warp::http::{Request, Response, StatusCode};
+    use warp::hyper::Body;
+
+    /// Reads the whole body like the upload routes, failing on the body errors.
+    fn read_body(req: Request<Body>) -> BoxFuture<'static, Result<Response<Body>, Infallible>> {
+        async move {
+            let resp = match warp::hyper::body::to_bytes(req.into_body()).await {
+                Ok(bytes) => Response::new(Body::from(bytes.len().to_string())),
+                Err(_) => {
+                    let mut resp = Response::new(Body::empty());
+                    *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    resp
+                }
+            };
+            Ok(resp)
+        }
+        .boxed()
+    }
+
+    fn limits(max_body_size: u64) -> Limits {
+        Limits {
+            max_body_size: Some(max_body_size),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        let mut service = with_limits(limits(4), read_body);
+
+        let resp = service(Request::new(Body::from("abcd"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // known from the header
+        let req = Request::builder()
+            .header("content-length", "5")
+            .body(Body::from("abcde"))
+            .unwrap();
+        let resp = service(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // found out while reading
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in &["abc", "def"] {
+                let _ = sender.send_data((*chunk).into()).await;
+            }
+        });
+        let resp = service(Request::new(body)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn slow_requests_time_out() {
+        let limits = Limits {
+            max_duration: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let mut service = with_limits(limits, read_body);
+
+        // the body is never finished
+        let (sender, body) = Body::channel();
+        let resp = service(Request::new(body)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+        drop(sender);
+    }
+}
@@ -0,0 +1,234 @@
+//! In-memory ring buffer of the latest log events, for following the logs of a headless node
+//! through the `/api/v0/log/tail` endpoint.
+//!
+//! The [`LogLayer`] is installed next to the usual log output, and records the events passing the
+//! `RUST_LOG` filter up to its own maximum level into the [`LogBuffer`]. The events are kept
+//! until the buffer is full, and are also sent to the followers as they happen.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// A recorded log event.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LogEvent {
+    /// Increasing number of the event within the process.
+    #[serde(skip)]
+    pub sequence: u64,
+    /// RFC 3339 timestamp.
+    pub time: String,
+    pub level: String,
+    /// The first component of the target, usually the crate.
+    pub subsystem: String,
+    pub target: String,
+    pub message: String,
+    /// The other fields of the event.
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl LogEvent {
+    /// Returns true if the event is at least as severe as the `level`.
+    pub fn is_at_least(&self, level: Level) -> bool {
+        self.level
+            .parse::<Level>()
+            .map(|own| own <= level)
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the event was logged by the `subsystem`, given as the crate or a module
+    /// path like `ipfs::p2p`.
+    pub fn is_from(&self, subsystem: &str) -> bool {
+        self.target == subsystem
+            || (self.target.starts_with(subsystem)
+                && self.target[subsystem.len()..].starts_with("::"))
+    }
+}
+
+struct Inner {
+    capacity: usize,
+    next_sequence: u64,
+    events: VecDeque<LogEvent>,
+}
+
+/// The latest log events, shared between the [`LogLayer`] and the log routes.
+#[derive(Clone)]
+pub struct LogBuffer {
+    inner: Arc<Mutex<Inner>>,
+    live: broadcast::Sender<LogEvent>,
+}
+
+impl LogBuffer {
+    /// Creates a buffer keeping at most `capacity` of the latest events.
+    pub fn new(capacity: usize) -> Self {
+        // the followers slower than this miss events instead of slowing down the logging
+        let (live, _) = broadcast::channel(256);
+        LogBuffer {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                next_sequence: 0,
+                events: VecDeque::with_capacity(capacity.min(1024)),
+            })),
+            live,
+        }
+    }
+
+    fn push(&self, mut event: LogEvent) {
+        let mut inner = self.inner.lock().unwrap();
+
+        event.sequence = inner.next_sequence;
+        inner.next_sequence += 1;
+
+        if inner.capacity > 0 {
+            if inner.events.len() == inner.capacity {
+                inner.events.pop_front();
+            }
+            inner.events.push_back(event.clone());
+        }
+
+        // sent while holding the lock so that the followers see the events in order; failing
+        // only means there are no followers
+        let _ = self.live.send(event);
+    }
+
+    /// Returns the buffered events along with the receiver of the events logged after them.
+    pub fn follow(&self) -> (Vec<LogEvent>, broadcast::Receiver<LogEvent>) {
+        let inner = self.inner.lock().unwrap();
+        let receiver = self.live.subscribe();
+        (inner.events.iter().cloned().collect(), receiver)
+    }
+
+    /// Returns the [`Layer`] recording the events at most as verbose as `max_level` into this
+    /// buffer.
+    pub fn layer(&self, max_level: Level) -> LogLayer {
+        LogLayer {
+            buffer: self.clone(),
+            max_level,
+        }
+    }
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        LogBuffer::new(1024)
+    }
+}
+
+impl fmt::Debug for LogBuffer {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        fmt.debug_struct("LogBuffer")
+            .field("capacity", &inner.capacity)
+            .field("buffered", &inner.events.len())
+            .finish()
+    }
+}
+
+/// Records the log events into a [`LogBuffer`], see [`LogBuffer::layer`].
+pub struct LogLayer {
+    buffer: LogBuffer,
+    max_level: Level,
+}
+
+impl<S: Subscriber> Layer<S> for LogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > self.max_level {
+            return;
+        }
+
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+
+        let target = metadata.target();
+        let subsystem = target.split("::").next().unwrap_or(target);
+
+        self.buffer.push(LogEvent {
+            sequence: 0,
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            level: metadata.level().to_string(),
+            subsystem: subsystem.to_owned(),
+            target: target.to_owned(),
+            message: visitor.message,
+            fields: visitor.fields,
+        });
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_owned();
+        } else {
+            self.fields.insert(field.name().to_owned(), value.into());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields
+                .insert(field.name().to_owned(), format!("{:?}", value).into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogBuffer;
+    use tracing::Level;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn keeps_the_latest_events() {
+        let buffer = LogBuffer::new(2);
+        let subscriber = tracing_subscriber::registry().with(buffer.layer(Level::DEBUG));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(target: "ipfs::p2p", peer = "foo", "first");
+            tracing::warn!(target: "bitswap", count = 3, "second");
+            tracing::trace!(target: "ipfs", "too verbose");
+            tracing::error!(target: "ipfs_http", "third");
+        });
+
+        let (events, _) = buffer.follow();
+        assert_eq!(events.len(), 2);
+
+        let second = &events[0];
+        assert_eq!(second.message, "second");
+        assert_eq!(second.subsystem, "bitswap");
+        assert_eq!(second.fields["count"], 3);
+        assert!(second.is_at_least(Level::INFO));
+        assert!(!second.is_at_least(Level::ERROR));
+
+        let third = &events[1];
+        assert_eq!(third.sequence, 2);
+        assert!(third.is_from("ipfs_http"));
+        assert!(!third.is_from("ipfs"));
+    }
+}
@@ -1,14 +1,33 @@
+use std::fmt;
 use std::num::NonZeroU16;
-use std::path::PathBuf;
-use structopt::StructOpt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use structopt::{clap::Shell, StructOpt};
 
+use futures::future::{BoxFuture, FutureExt};
+
+use ipfs::{multiaddr, Multiaddr, Protocol};
 use ipfs::{Ipfs, IpfsOptions, IpfsTypes, UninitializedIpfs};
-use ipfs::{Multiaddr, Protocol};
-use ipfs_http::{config, v0};
+use ipfs_http::v0::Lifecycle;
+use ipfs_http::{config, gateway, limits, log_tail, request_id, supervise, unix_socket, v0};
 
 #[macro_use]
 extern crate tracing;
 
+#[derive(Debug, StructOpt)]
+struct Cli {
+    /// Output format of the results, `text` or `json`. The `json` output is a single object per
+    /// result on its own line.
+    #[structopt(long, global = true, default_value = "text")]
+    output: OutputFormat,
+    /// Only print the results, like the peer id after `init` or the API address once the daemon
+    /// is running.
+    #[structopt(long, short, global = true)]
+    quiet: bool,
+    #[structopt(subcommand)]
+    command: Options,
+}
+
 #[derive(Debug, StructOpt)]
 enum Options {
     /// Should initialize the repository (create directories and such). `js-ipfsd-ctl` calls this
@@ -25,7 +44,96 @@ enum Options {
         profile: Vec<config::Profile>,
     },
     /// Start the IPFS node in the foreground (not detaching from parent process).
-    Daemon,
+    Daemon {
+        /// Only report the migrations an outdated repository would need instead of running
+        /// them, failing to start.
+        #[structopt(long)]
+        migrate_dry_run: bool,
+        /// Record the repository mutations in a journal, so that the ones interrupted by a crash
+        /// are repaired on the next start. Every mutation then waits for a sync to the disk.
+        #[structopt(long)]
+        journal: bool,
+        /// Serve the API on this address instead of the `Addresses.API` of the configuration, a
+        /// `/ip4/../tcp/..`, `/ip6/../tcp/..` or `/unix/..` multiaddr. The whole path following
+        /// `/unix` is the path of the socket, which is not supported on Windows.
+        #[structopt(long, parse(try_from_str = unix_socket::parse_addr))]
+        api: Option<Multiaddr>,
+        /// Fetch the blocks missing for the gateway requests also from these gateways, like
+        /// `https://ipfs.io`, while they are looked up from the network. The blocks are verified
+        /// before they are used, and the first one to arrive is used.
+        #[structopt(long, use_delimiter = true)]
+        gateway_upstream: Vec<url::Url>,
+        /// Serve the gateway also from the subdomains of these hosts, like
+        /// `<cid>.ipfs.example.com` for `example.com`, redirecting the path style requests to
+        /// the hosts into the subdomains so that every Cid and name has an origin of its own.
+        #[structopt(long, use_delimiter = true)]
+        gateway_subdomain_host: Vec<String>,
+        /// Serve the DNSLink websites of the other domain names the gateway is reached at.
+        #[structopt(long)]
+        gateway_dnslink: bool,
+        /// Publish and resolve the IPNS records also over pubsub, falling back to the DHT for the
+        /// names without a received record.
+        #[structopt(long)]
+        enable_namesys_pubsub: bool,
+        /// Resolve the DNSLinks and the DNS names of the multiaddresses with the `system`
+        /// configuration, or over HTTPS with the `cloudflare` or `google` servers.
+        #[structopt(long, default_value = "system")]
+        dns_resolver: ipfs::dns::DnsResolver,
+    },
+    /// Generate the shell completions for all of the subcommands to stdout.
+    Completions {
+        /// The shell to generate the completions for.
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unsupported output format: {:?}", format)),
+        }
+    }
+}
+
+/// Prints the output of the commands in the format selected with `--output` and `--quiet`.
+#[derive(Debug, Clone, Copy)]
+struct Output {
+    format: OutputFormat,
+    quiet: bool,
+}
+
+impl Output {
+    /// Prints an informational message, only included in the verbose text output.
+    fn info(&self, args: fmt::Arguments<'_>) {
+        if self.format == OutputFormat::Text && !self.quiet {
+            println!("{}", args);
+        }
+    }
+
+    /// Prints a result as the `verbose` or `quiet` text, or as the `json` object.
+    fn result(
+        &self,
+        verbose: impl fmt::Display,
+        quiet: impl fmt::Display,
+        json: serde_json::Value,
+    ) {
+        match self.format {
+            OutputFormat::Text if self.quiet => println!("{}", quiet),
+            OutputFormat::Text => println!("{}", verbose),
+            OutputFormat::Json => println!("{}", json),
+        }
+    }
 }
 
 fn main() {
@@ -36,11 +144,34 @@ fn main() {
         );
     }
 
-    tracing_subscriber::fmt::init();
+    // the latest events are also kept in memory for the log/tail endpoint
+    let logs = log_tail::LogBuffer::default();
+    {
+        use tracing_subscriber::prelude::*;
+
+        tracing_subscriber::registry()
+            .with(tracing_subscriber::EnvFilter::from_default_env())
+            .with(tracing_subscriber::fmt::layer())
+            .with(logs.layer(tracing::Level::DEBUG))
+            .init();
+    }
+
+    let Cli {
+        output,
+        quiet,
+        command: opts,
+    } = Cli::from_args();
+    let output = Output {
+        format: output,
+        quiet,
+    };
 
-    let opts = Options::from_args();
+    if let Options::Completions { shell } = opts {
+        Cli::clap().gen_completions_to(env!("CARGO_PKG_NAME"), shell, &mut std::io::stdout());
+        return;
+    }
 
-    println!("Invoked with args: {:?}", opts);
+    output.info(format_args!("Invoked with args: {:?}", opts));
 
     // go-ipfs seems to deduce like this
     let home = std::env::var_os("IPFS_PATH")
@@ -63,9 +194,46 @@ fn main() {
 
     let config_path = home.join("config");
 
+    let (migrate_dry_run, journal, api_addr, upstreams, hosts, ipns_pubsub, dns) = match &opts {
+        Options::Daemon {
+            migrate_dry_run,
+            journal,
+            api,
+            gateway_upstream,
+            gateway_subdomain_host,
+            gateway_dnslink,
+            enable_namesys_pubsub,
+            dns_resolver,
+        } => (
+            *migrate_dry_run,
+            *journal,
+            api.clone(),
+            gateway::UpstreamGateways::new(gateway_upstream.clone()),
+            gateway::GatewayHosts {
+                subdomains: gateway_subdomain_host
+                    .iter()
+                    .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+                    .collect(),
+                dnslink: *gateway_dnslink,
+            },
+            *enable_namesys_pubsub,
+            dns_resolver.clone(),
+        ),
+        _ => (
+            false,
+            false,
+            false,
+            None,
+            None,
+            Default::default(),
+            false,
+            Default::default(),
+        ),
+    };
+
     let config = match opts {
         Options::Init { bits, profile } => {
-            println!("initializing IPFS node at {:?}", home);
+            output.info(format_args!("initializing IPFS node at {:?}", home));
 
             if config_path.is_file() {
                 eprintln!("Error: ipfs configuration file already exists!");
@@ -80,7 +248,11 @@ fn main() {
                     // go-ipfs prints here (in addition to earlier "initializing ..."):
                     //
                     // generating {}-bit RSA keypair...done
-                    println!("peer identity: {}", peer_id);
+                    output.result(
+                        format!("peer identity: {}", peer_id),
+                        &peer_id,
+                        serde_json::json!({ "ID": peer_id.to_string() }),
+                    );
                     std::process::exit(0);
                 }
                 Err(config::InitializationError::DirectoryCreationFailed(e)) => {
@@ -110,7 +282,7 @@ fn main() {
                 }
             }
         }
-        Options::Daemon => {
+        Options::Daemon { .. } => {
             // FIXME: toctou, should just match for this err?
             if !config_path.is_file() {
                 eprintln!("Error: no IPFS repo found in {:?}", home);
@@ -118,61 +290,121 @@ fn main() {
                 std::process::exit(1);
             }
 
-            std::fs::File::open(config_path)
-                .map_err(config::LoadingError::ConfigurationFileOpening)
-                .and_then(config::load)
-                .unwrap()
+            load_config(&config_path).unwrap()
         }
+        Options::Completions { .. } => unreachable!("completions were generated above"),
     };
 
-    println!("IPFS_PATH: {:?}", home);
-    println!("Process id: {}", std::process::id());
+    output.info(format_args!("IPFS_PATH: {:?}", home));
+    output.info(format_args!("Process id: {}", std::process::id()));
 
     // TODO: sigterm should initiate graceful shutdown, second time should shutdown right now
     // NOTE: sigkill ... well surely it will stop the process right away
 
     let rt = tokio::runtime::Runtime::new().expect("Failed to create event loop");
 
-    rt.block_on(async move {
+    let res = rt.block_on(async move {
+        let peer_id = config.keypair.public().to_peer_id();
+
         let opts = IpfsOptions {
             ipfs_path: home.clone(),
+            repo_migrations: if migrate_dry_run {
+                ipfs::MigrationMode::DryRun
+            } else {
+                ipfs::MigrationMode::Run
+            },
+            repo_journal: journal,
+            block_storage: ipfs::BlockStorage {
+                backend: config.blockstore,
+                ..Default::default()
+            },
             keypair: config.keypair,
             bootstrap: Vec::new(),
             mdns: false,
             kad_protocol: None,
-            listening_addrs: config.swarm,
+            listening_addrs: config.swarm.clone(),
+            dial: config.dial,
+            memory_transport: false,
+            pubsub_discovery: false,
+            pubsub_batching: None,
+            announce: config.announce,
+            popularity: Default::default(),
+            ipns_cache: Default::default(),
+            ipns_pubsub,
+            memory_budgets: Default::default(),
+            bitswap_compression: false,
+            bitswap_wantlist_batching: Some(Default::default()),
+            codecs: Default::default(),
+            dns,
+            content_routing: Vec::new(),
+            pinning_services: config.pinning_services,
             span: None,
         };
 
         // TODO: handle errors more gracefully.
-        let (ipfs, task): (Ipfs<ipfs::Types>, _) = UninitializedIpfs::new(opts)
-            .start()
-            .await
-            .expect("Initialization failed");
+        let (ipfs, task): (Ipfs<ipfs::Types>, _) = match UninitializedIpfs::new(opts).start().await
+        {
+            Ok(started) => started,
+            Err(e) => {
+                eprintln!("Error: initialization failed: {}", e);
+                if let Some(ipfs::repo::LockError::HeldAfterExit(_)) =
+                    e.downcast_ref::<ipfs::repo::LockError>()
+                {
+                    eprintln!("The daemon is no longer running; stop the processes it started")
+                }
+                std::process::exit(1);
+            }
+        };
 
         tokio::spawn(task);
 
-        let api_link_file = home.join("api");
-
-        let (addr, server) = serve(&ipfs, config.api_addr);
+        record_config(&ipfs, &config_path, "loaded on start").await;
 
-        // shutdown future will handle signalling the exit
-        drop(ipfs);
+        // announces the local blocks to the DHT for as long as the daemon runs
+        let (_reprovider, reprovide_task) =
+            ipfs::reprovide::Reprovider::new(ipfs.clone(), Default::default());
+        tokio::spawn(reprovide_task);
 
-        // We can't simply reuse the address from the config as the test profile uses ephemeral
-        // ports.
-        let api_multiaddr = format!("/ip4/{}/tcp/{}", addr.ip(), addr.port());
+        let api_link_file = home.join("api");
 
-        // this file is looked for when js-ipfsd-ctl checks optimistically if the IPFS_PATH has a
-        // daemon running already. go-ipfs file does not contain newline at the end.
-        let wrote = tokio::fs::write(&api_link_file, &api_multiaddr)
-            .await
-            .is_ok();
+        let (lifecycle_tx, lifecycle_rx) = tokio::sync::mpsc::channel::<Lifecycle>(1);
+
+        let mut daemon = ApiDaemon {
+            ipfs: &ipfs,
+            peer_id,
+            home: &home,
+            config_path: &config_path,
+            output,
+            api_addr,
+            served: (config.api_addr.clone(), config.limits),
+            listening_addr: config.api_addr,
+            limits: config.limits,
+            listeners: config
+                .swarm
+                .iter()
+                .map(|addr| (addr.clone(), addr.clone()))
+                .collect(),
+            logs,
+            upstreams,
+            hosts,
+            lifecycle: lifecycle_tx,
+            api_link_file,
+            wrote: false,
+        };
 
-        println!("API listening on {}", api_multiaddr);
-        println!("daemon is running");
+        // the API is served again after each restart, while the node and the repo stay open
+        let res = supervise::supervise(&mut daemon, lifecycle_rx).await;
 
-        server.await;
+        match &res {
+            Ok(()) => info!("Shutdown trigger received; starting shutdown"),
+            Err(e) => error!("Failed to serve the API; starting shutdown: {}", e),
+        }
+        let ApiDaemon {
+            wrote,
+            api_link_file,
+            ..
+        } = daemon;
+        ipfs.exit_daemon().await;
 
         if wrote {
             // FIXME: this should probably make sure the contents match what we wrote or do some
@@ -181,38 +413,264 @@ fn main() {
                 .await
                 .map_err(|e| info!("Failed to truncate {:?}: {}", api_link_file, e));
         }
+
+        res
     });
 
     info!("Shutdown complete");
+
+    if let Err(e) = res {
+        eprintln!("Error: failed to serve the API: {}", e);
+        std::process::exit(1);
+    }
 }
 
+/// The state of the daemon kept over the restarts, see [`supervise::supervise`].
+struct ApiDaemon<'a, Types: IpfsTypes> {
+    ipfs: &'a Ipfs<Types>,
+    peer_id: ipfs::PeerId,
+    home: &'a Path,
+    config_path: &'a Path,
+    output: Output,
+    /// The address given on the command line, overriding the configured one.
+    api_addr: Option<Multiaddr>,
+    /// The address and the limits of the last server, returned to when the reloaded ones cannot
+    /// be served.
+    served: (Multiaddr, limits::Limits),
+    listening_addr: Multiaddr,
+    limits: limits::Limits,
+    /// Pairs of the configured and the bound swarm addresses.
+    listeners: Vec<(Multiaddr, Multiaddr)>,
+    logs: log_tail::LogBuffer,
+    upstreams: Option<gateway::UpstreamGateways>,
+    hosts: gateway::GatewayHosts,
+    lifecycle: tokio::sync::mpsc::Sender<Lifecycle>,
+    api_link_file: PathBuf,
+    wrote: bool,
+}
+
+impl<Types: IpfsTypes> supervise::Daemon for ApiDaemon<'_, Types> {
+    fn serve(&mut self) -> BoxFuture<'_, std::io::Result<supervise::Server>> {
+        async move {
+            // We can't simply reuse the address from the config as the test profile uses
+            // ephemeral ports.
+            let (stop, stop_rx) = tokio::sync::oneshot::channel::<()>();
+            let (api_multiaddr, server) = serve(
+                self.ipfs,
+                self.api_addr
+                    .clone()
+                    .unwrap_or_else(|| self.listening_addr.clone()),
+                self.limits,
+                self.config_path.to_owned(),
+                self.logs.clone(),
+                self.upstreams.clone(),
+                self.hosts.clone(),
+                self.lifecycle.clone(),
+                stop_rx,
+            )?;
+
+            let server = tokio::spawn(server);
+            self.served = (self.listening_addr.clone(), self.limits);
+
+            let api_multiaddr = unix_socket::format_addr(&api_multiaddr);
+
+            // this file is looked for when js-ipfsd-ctl checks optimistically if the IPFS_PATH
+            // has a daemon running already. go-ipfs file does not contain newline at the end.
+            self.wrote = tokio::fs::write(&self.api_link_file, &api_multiaddr)
+                .await
+                .is_ok();
+
+            self.output.result(
+                format!("API listening on {}\ndaemon is running", api_multiaddr),
+                &api_multiaddr,
+                serde_json::json!({
+                    "API": api_multiaddr,
+                    "IpfsPath": self.home,
+                    "ProcessID": std::process::id(),
+                }),
+            );
+
+            Ok(supervise::Server {
+                task: server.map(|_| ()).boxed(),
+                stop,
+            })
+        }
+        .boxed()
+    }
+
+    fn reload(&mut self) -> BoxFuture<'_, ()> {
+        async move {
+            info!("Restart trigger received; reloading the configuration");
+            match load_config(self.config_path) {
+                Ok(reloaded) => {
+                    record_config(self.ipfs, self.config_path, "reloaded on restart").await;
+                    reload_listeners(self.ipfs, &mut self.listeners, &reloaded.swarm).await;
+                    if reloaded.keypair.public().to_peer_id() != self.peer_id {
+                        warn!("The changed identity requires restarting the process");
+                    }
+                    self.listening_addr = reloaded.api_addr;
+                    self.limits = reloaded.limits;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to reload the configuration, keeping the previous one: {}",
+                        e
+                    );
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn rollback(&mut self) {
+        let (addr, limits) = self.served.clone();
+        self.listening_addr = addr;
+        self.limits = limits;
+    }
+}
+
+fn load_config(config_path: &Path) -> Result<config::Config, config::LoadingError> {
+    std::fs::File::open(config_path)
+        .map_err(config::LoadingError::ConfigurationFileOpening)
+        .and_then(config::load)
+}
+
+/// Records the configuration file in the configuration history when it has changed since it was
+/// last recorded. Failing to record is not fatal, as the history is only used for auditing and
+/// rolling back.
+async fn record_config<Types: IpfsTypes>(ipfs: &Ipfs<Types>, config_path: &Path, message: &str) {
+    let recorded = match config::snapshot(config_path) {
+        Ok(snapshot) => ipfs.record_config(snapshot, message).await,
+        Err(e) => {
+            warn!("Failed to read the configuration for the history: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = recorded {
+        warn!("Failed to record the configuration in the history: {}", e);
+    }
+}
+
+/// Replaces the swarm `listeners`, pairs of the configured and the bound addresses, with the
+/// `configured` addresses of the reloaded configuration. The addresses which cannot be removed,
+/// like the unspecified ones, are listened to until the process is restarted.
+async fn reload_listeners<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    listeners: &mut Vec<(Multiaddr, Multiaddr)>,
+    configured: &[Multiaddr],
+) {
+    let mut kept = Vec::with_capacity(configured.len());
+
+    for (addr, bound) in listeners.drain(..) {
+        if configured.contains(&addr) {
+            kept.push((addr, bound));
+        } else if let Err(e) = ipfs.remove_listening_address(bound.clone()).await {
+            warn!("Failed to stop listening on {}: {}", bound, e);
+        }
+    }
+
+    for addr in configured {
+        if kept.iter().any(|(kept, _)| kept == addr) {
+            continue;
+        }
+        match ipfs.add_listening_address(addr.clone()).await {
+            Ok(bound) => kept.push((addr.clone(), bound)),
+            Err(e) => warn!("Failed to listen on {}: {}", addr, e),
+        }
+    }
+
+    *listeners = kept;
+}
+
+/// Serves the API and the gateway on the `listening_addr`, which is either a TCP address or a
+/// `/unix/..` address of a Unix domain socket, see [`unix_socket`], until `stop` completes, applying
+/// the `limits` to the requests. The shutdown and restart requests to the API are sent to
+/// `lifecycle`, the configuration rollbacks are written to `config_path` and the log events are
+/// followed from `logs`. The gateway is also served from the subdomains and the DNSLink domains of
+/// the `hosts`.
+///
+/// Returns the bound address, which differs from `listening_addr` for ephemeral ports.
+#[allow(clippy::too_many_arguments)]
 fn serve<Types: IpfsTypes>(
     ipfs: &Ipfs<Types>,
     listening_addr: Multiaddr,
-) -> (std::net::SocketAddr, impl std::future::Future<Output = ()>) {
+    limits: limits::Limits,
+    config_path: PathBuf,
+    logs: log_tail::LogBuffer,
+    upstreams: Option<gateway::UpstreamGateways>,
+    hosts: gateway::GatewayHosts,
+    lifecycle: tokio::sync::mpsc::Sender<Lifecycle>,
+    stop: tokio::sync::oneshot::Receiver<()>,
+) -> std::io::Result<(Multiaddr, BoxFuture<'static, ()>)> {
+    use std::convert::Infallible;
     use std::net::SocketAddr;
+    use warp::hyper::server::Server;
+    use warp::hyper::service::{make_service_fn, service_fn};
     use warp::Filter;
 
-    let (shutdown_tx, mut shutdown_rx) = tokio::sync::mpsc::channel::<()>(1);
-
-    let routes = v0::routes(ipfs, shutdown_tx);
-    let routes = routes.with(warp::log(env!("CARGO_PKG_NAME")));
-
-    let ipfs = ipfs.clone();
+    // the gateway goes first as the api routes recover every rejection into a response
+    let routes =
+        gateway::routes(ipfs, upstreams).or(v0::routes(ipfs, Some(config_path), logs, lifecycle));
+    let routes = routes
+        .with(warp::log(env!("CARGO_PKG_NAME")))
+        .with(warp::trace(|info| {
+            let id = request_id::from_headers(info.request_headers()).unwrap_or_default();
+            info_span!("http", method = %info.method(), path = info.path(), request_id = id)
+        }));
+
+    let service = request_id::with_request_ids(warp::service(routes));
+    // the hosts are routed before the limits, which apply to the rewritten paths
+    let service = gateway::with_hosts(hosts, ipfs, service);
+    let service = limits::with_limits(limits, service);
+
+    let shutdown = async move {
+        let _ = stop.await;
+    };
 
     let components = listening_addr.iter().collect::<Vec<_>>();
 
     let socket_addr = match components.as_slice() {
         [Protocol::Ip4(ip), Protocol::Tcp(port)] => SocketAddr::new((*ip).into(), *port),
+        [Protocol::Ip6(ip), Protocol::Tcp(port)] => SocketAddr::new((*ip).into(), *port),
+        [Protocol::Unix(path)] => {
+            let (bound, server) = unix_socket::serve(
+                Path::new(path.as_ref()),
+                service,
+                limits.header_timeout,
+                shutdown,
+            )?;
+            return Ok((unix_socket::unix_addr(&bound), server));
+        }
         _ => panic!(
             "Couldn't convert MultiAddr into SocketAddr: {}",
             listening_addr
         ),
     };
 
-    warp::serve(routes).bind_with_graceful_shutdown(socket_addr, async move {
-        let _ = shutdown_rx.recv().await;
-        info!("Shutdown trigger received; starting shutdown");
-        ipfs.exit_daemon().await;
-    })
+    let mut builder = Server::try_bind(&socket_addr)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    if let Some(timeout) = limits.header_timeout {
+        builder = builder.http1_header_read_timeout(timeout);
+    }
+
+    let server = builder.serve(make_service_fn(move |_| {
+        let service = service.clone();
+        async move { Ok::<_, Infallible>(service_fn(service)) }
+    }));
+
+    let addr = server.local_addr();
+    let server = server.with_graceful_shutdown(shutdown);
+    let server = async move {
+        if let Err(e) = server.await {
+            error!("API server failed: {}", e);
+        }
+    };
+
+    let bound = match addr.ip() {
+        std::net::IpAddr::V4(ip) => multiaddr!(Ip4(ip), Tcp(addr.port())),
+        std::net::IpAddr::V6(ip) => multiaddr!(Ip6(ip), Tcp(addr.port())),
+    };
+
+    Ok((bound, server.boxed()))
 }
@@ -0,0 +1,104 @@
+//! Request IDs correlating the HTTP requests with the log events.
+//!
+//! Every request is given an ID, or the one given by the client in the `X-Request-Id` header is
+//! used. The ID is returned in the same header of the response, and the operations made for the
+//! request are instrumented with a span containing the ID, including the work done for them in
+//! the background task of the node.
+
+use futures::future::{BoxFuture, FutureExt};
+use std::collections::hash_map::RandomState;
+use std::convert::Infallible;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use warp::http::{HeaderMap, HeaderValue, Request, Response};
+use warp::hyper::{service::Service, Body};
+
+/// The header containing the request ID.
+pub const HEADER: &str = "x-request-id";
+
+/// The longest accepted client given request ID.
+const MAX_LEN: usize = 128;
+
+/// Returns the request ID from the headers, if present.
+pub fn from_headers(headers: &HeaderMap) -> Option<&str> {
+    headers.get(HEADER).and_then(|value| value.to_str().ok())
+}
+
+/// Makes sure the request has an ID, replacing an unacceptable one given by the client.
+fn ensure(headers: &mut HeaderMap) -> HeaderValue {
+    let acceptable = from_headers(headers)
+        .map(|id| !id.is_empty() && id.len() <= MAX_LEN)
+        .unwrap_or(false);
+
+    if !acceptable {
+        let id = HeaderValue::from_str(&generate()).expect("hex is a valid header value");
+        headers.insert(HEADER, id);
+    }
+
+    headers[HEADER].clone()
+}
+
+fn generate() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // hashing the counter and the time makes repeating the same ID unlikely, both within the
+    // process and over restarts
+    let mut hasher = RandomState::new().build_hasher();
+    COUNTER.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Wraps the `service`, usually created with [`warp::service`], to give every request an ID and
+/// to return it in the response.
+pub fn with_request_ids<S>(
+    service: S,
+) -> impl FnMut(Request<Body>) -> BoxFuture<'static, Result<Response<Body>, Infallible>> + Clone
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    move |mut req: Request<Body>| {
+        let id = ensure(req.headers_mut());
+        let resp = service.clone().call(req);
+
+        async move {
+            let mut resp = resp.await?;
+            resp.headers_mut().insert(HEADER, id);
+            Ok(resp)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ensure, HEADER};
+    use warp::http::{HeaderMap, HeaderValue};
+
+    #[test]
+    fn client_ids_are_kept() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER, HeaderValue::from_static("abc"));
+        assert_eq!(ensure(&mut headers), "abc");
+    }
+
+    #[test]
+    fn missing_and_overlong_ids_are_generated() {
+        let mut headers = HeaderMap::new();
+        let first = ensure(&mut headers);
+        assert_eq!(headers[HEADER], first);
+
+        let mut headers = HeaderMap::new();
+        let second = ensure(&mut headers);
+        assert_ne!(first, second);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER, HeaderValue::from_str(&"a".repeat(200)).unwrap());
+        assert_eq!(ensure(&mut headers).len(), 16);
+    }
+}
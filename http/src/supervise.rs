@@ -0,0 +1,256 @@
+//! Serving the API until the daemon is shut down, see [`supervise`].
+
+use crate::v0::Lifecycle;
+use futures::future::BoxFuture;
+use std::io;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// How long to wait before serving the API again after the server exited on its own.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// An API server started by [`Daemon::serve`].
+pub struct Server {
+    /// Completes once the server has stopped.
+    pub task: BoxFuture<'static, ()>,
+    /// Stops the server.
+    pub stop: oneshot::Sender<()>,
+}
+
+/// The daemon serving the API, driven by [`supervise`].
+pub trait Daemon {
+    /// Starts serving the API.
+    fn serve(&mut self) -> BoxFuture<'_, io::Result<Server>>;
+
+    /// Reloads the configuration used by the next [`Daemon::serve`].
+    fn reload(&mut self) -> BoxFuture<'_, ()>;
+
+    /// Returns to the configuration of the last successful [`Daemon::serve`], as the reloaded
+    /// configuration could not be served.
+    fn rollback(&mut self);
+}
+
+/// Serves the API of the `daemon` until [`Lifecycle::Shutdown`] is requested through `requests`,
+/// or all of the senders have been dropped. The API is served again after a
+/// [`Lifecycle::Restart`] request, with the reloaded configuration, and after the server exits on
+/// its own. If the reloaded configuration cannot be served, the API is served again with the
+/// previous one.
+///
+/// Fails if the API cannot be served otherwise, leaving the shutdown of the daemon to the caller.
+pub async fn supervise<D: Daemon>(
+    daemon: &mut D,
+    mut requests: mpsc::Receiver<Lifecycle>,
+) -> io::Result<()> {
+    let mut reloaded = false;
+
+    loop {
+        let Server { mut task, stop } = match daemon.serve().await {
+            Ok(server) => server,
+            Err(e) if reloaded => {
+                error!(
+                    "Failed to serve the API with the reloaded configuration, keeping the previous one: {}",
+                    e
+                );
+                daemon.rollback();
+                reloaded = false;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        reloaded = false;
+
+        let request = tokio::select! {
+            biased;
+            _ = &mut task => None,
+            request = requests.recv() => Some(request.unwrap_or(Lifecycle::Shutdown)),
+        };
+
+        let request = match request {
+            Some(request) => request,
+            None => {
+                warn!("The API server exited; serving it again");
+                tokio::time::sleep(RESTART_DELAY).await;
+                continue;
+            }
+        };
+
+        let _ = stop.send(());
+        task.await;
+
+        match request {
+            Lifecycle::Shutdown => return Ok(()),
+            Lifecycle::Restart => {
+                daemon.reload().await;
+                reloaded = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{supervise, Daemon, Server};
+    use crate::v0::Lifecycle;
+    use futures::future::{BoxFuture, FutureExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, oneshot};
+
+    #[derive(Default)]
+    struct Counters {
+        served: AtomicUsize,
+        stopped: AtomicUsize,
+        reloaded: AtomicUsize,
+        rolled_back: AtomicUsize,
+    }
+
+    /// Returns a server which runs until stopped.
+    fn server(counters: Arc<Counters>) -> Server {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let task = async move {
+            let _ = stopped.await;
+            counters.stopped.fetch_add(1, Ordering::SeqCst);
+        };
+        Server {
+            task: task.boxed(),
+            stop,
+        }
+    }
+
+    /// The first server fails right away, the following ones run until stopped.
+    struct FailingOnce(Arc<Counters>);
+
+    impl Daemon for FailingOnce {
+        fn serve(&mut self) -> BoxFuture<'_, std::io::Result<Server>> {
+            let first = self.0.served.fetch_add(1, Ordering::SeqCst) == 0;
+
+            let server = if first {
+                Server {
+                    task: async {}.boxed(),
+                    stop: oneshot::channel().0,
+                }
+            } else {
+                server(Arc::clone(&self.0))
+            };
+
+            async move { Ok(server) }.boxed()
+        }
+
+        fn reload(&mut self) -> BoxFuture<'_, ()> {
+            self.0.reloaded.fetch_add(1, Ordering::SeqCst);
+            async {}.boxed()
+        }
+
+        fn rollback(&mut self) {
+            unreachable!("the reloaded configuration is served");
+        }
+    }
+
+    /// The reloaded configuration cannot be served, for example as the address is in use.
+    struct FailingReload {
+        counters: Arc<Counters>,
+        reloaded: bool,
+    }
+
+    impl Daemon for FailingReload {
+        fn serve(&mut self) -> BoxFuture<'_, std::io::Result<Server>> {
+            let res = if self.reloaded {
+                Err(std::io::ErrorKind::AddrInUse.into())
+            } else {
+                self.counters.served.fetch_add(1, Ordering::SeqCst);
+                Ok(server(Arc::clone(&self.counters)))
+            };
+            async move { res }.boxed()
+        }
+
+        fn reload(&mut self) -> BoxFuture<'_, ()> {
+            self.counters.reloaded.fetch_add(1, Ordering::SeqCst);
+            self.reloaded = true;
+            async {}.boxed()
+        }
+
+        fn rollback(&mut self) {
+            self.counters.rolled_back.fetch_add(1, Ordering::SeqCst);
+            self.reloaded = false;
+        }
+    }
+
+    async fn served(counters: &Counters, count: usize) {
+        while counters.served.load(Ordering::SeqCst) < count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_server_is_served_again_until_shutdown() {
+        let counters = Arc::new(Counters::default());
+        let mut daemon = FailingOnce(Arc::clone(&counters));
+        let (tx, rx) = mpsc::channel(1);
+
+        let requests = async {
+            // the server which failed was served again
+            served(&counters, 2).await;
+            tx.send(Lifecycle::Restart).await.unwrap();
+            served(&counters, 3).await;
+            tx.send(Lifecycle::Shutdown).await.unwrap();
+        };
+
+        let (res, ()) = tokio::time::timeout(
+            Duration::from_secs(10),
+            futures::future::join(supervise(&mut daemon, rx), requests),
+        )
+        .await
+        .unwrap();
+        res.unwrap();
+
+        assert_eq!(counters.served.load(Ordering::SeqCst), 3);
+        assert_eq!(counters.reloaded.load(Ordering::SeqCst), 1);
+        // both of the servers which did not fail were stopped
+        assert_eq!(counters.stopped.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn reloaded_configuration_which_cannot_be_served_is_rolled_back() {
+        let counters = Arc::new(Counters::default());
+        let mut daemon = FailingReload {
+            counters: Arc::clone(&counters),
+            reloaded: false,
+        };
+        let (tx, rx) = mpsc::channel(1);
+
+        let requests = async {
+            served(&counters, 1).await;
+            tx.send(Lifecycle::Restart).await.unwrap();
+            // served again with the previous configuration
+            served(&counters, 2).await;
+            tx.send(Lifecycle::Shutdown).await.unwrap();
+        };
+
+        let (res, ()) = tokio::time::timeout(
+            Duration::from_secs(10),
+            futures::future::join(supervise(&mut daemon, rx), requests),
+        )
+        .await
+        .unwrap();
+        res.unwrap();
+
+        assert_eq!(counters.reloaded.load(Ordering::SeqCst), 1);
+        assert_eq!(counters.rolled_back.load(Ordering::SeqCst), 1);
+        assert_eq!(counters.stopped.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn failing_to_serve_otherwise_is_returned() {
+        let counters = Arc::new(Counters::default());
+        let mut daemon = FailingReload {
+            counters: Arc::clone(&counters),
+            reloaded: true,
+        };
+        let (_tx, rx) = mpsc::channel(1);
+
+        let e = supervise(&mut daemon, rx).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+        assert_eq!(counters.rolled_back.load(Ordering::SeqCst), 0);
+    }
+}
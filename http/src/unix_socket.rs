@@ -0,0 +1,221 @@
+//! Serving the API and the gateway on a Unix domain socket, configured with a `/unix/..`
+//! multiaddr.
+//!
+//! As with go-ipfs, everything following `/unix` is the path of the socket, so
+//! `/unix/run/ipfs/api.sock` is the socket at `/run/ipfs/api.sock`. The addresses are read with
+//! [`parse_addr`], as parsing a multiaddr would keep only the first segment of the path. The
+//! access to the socket is controlled by the permissions of the file and the directory it is
+//! created in.
+//!
+//! Windows named pipes are not supported; serving on a `/unix/..` address fails on the platforms
+//! other than unix.
+
+use futures::future::BoxFuture;
+use ipfs::{Multiaddr, Protocol};
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use warp::http::{Request, Response};
+use warp::hyper::Body;
+
+/// Parses a multiaddr, keeping all of the segments following `/unix` as the path of the socket.
+pub fn parse_addr(s: &str) -> Result<Multiaddr, <Multiaddr as FromStr>::Err> {
+    match s.strip_prefix("/unix") {
+        Some(path) if path.len() > 1 && path.starts_with('/') => Ok(unix_addr(Path::new(path))),
+        _ => s.parse(),
+    }
+}
+
+/// Formats the address the way [`parse_addr`] reads it back. The relative paths are read back as
+/// absolute ones.
+pub fn format_addr(addr: &Multiaddr) -> String {
+    match socket_path(addr) {
+        Some(path) if path.is_absolute() => format!("/unix{}", path.display()),
+        Some(path) => format!("/unix/{}", path.display()),
+        None => addr.to_string(),
+    }
+}
+
+/// Returns the `/unix/..` address of the socket at `path`.
+pub fn unix_addr(path: &Path) -> Multiaddr {
+    Multiaddr::empty().with(Protocol::Unix(path.to_string_lossy().into_owned().into()))
+}
+
+/// Returns the path of the socket if the address is a `/unix/..` address.
+pub fn socket_path(addr: &Multiaddr) -> Option<PathBuf> {
+    let mut iter = addr.iter();
+    match (iter.next(), iter.next()) {
+        (Some(Protocol::Unix(path)), None) => Some(PathBuf::from(path.as_ref())),
+        _ => None,
+    }
+}
+
+/// Binds a Unix domain socket at `path`, replacing a socket left over by an earlier run. A socket
+/// which still accepts connections is in use by another process, and is not replaced.
+#[cfg(unix)]
+fn bind(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{:?} is in use by another process", path),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        // binding fails for the other kinds of files
+        _ => {}
+    }
+
+    tokio::net::UnixListener::bind(path)
+}
+
+/// Serves the `service` on the Unix domain socket at `path` until `shutdown` completes, removing
+/// the socket afterwards. A relative `path` is relative to the working directory.
+///
+/// Returns the absolute path of the socket and the server future.
+#[cfg(unix)]
+pub fn serve<S, F, Shutdown>(
+    path: &Path,
+    service: S,
+    header_timeout: Option<Duration>,
+    shutdown: Shutdown,
+) -> io::Result<(PathBuf, BoxFuture<'static, ()>)>
+where
+    S: FnMut(Request<Body>) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+    Shutdown: Future<Output = ()> + Send + 'static,
+{
+    use futures::future::FutureExt;
+    use warp::hyper::server::{accept, Server};
+    use warp::hyper::service::{make_service_fn, service_fn};
+
+    let path = std::env::current_dir()?.join(path);
+    let incoming = tokio_stream::wrappers::UnixListenerStream::new(bind(&path)?);
+
+    let mut builder = Server::builder(accept::from_stream(incoming));
+    if let Some(timeout) = header_timeout {
+        builder = builder.http1_header_read_timeout(timeout);
+    }
+
+    let server = builder
+        .serve(make_service_fn(move |_| {
+            let service = service.clone();
+            async move { Ok::<_, Infallible>(service_fn(service)) }
+        }))
+        .with_graceful_shutdown(shutdown);
+
+    let bound = path.clone();
+    let server = async move {
+        if let Err(e) = server.await {
+            error!("API server failed: {}", e);
+        }
+        if let Err(e) = std::fs::remove_file(&path) {
+            info!("Failed to remove {:?}: {}", path, e);
+        }
+    };
+
+    Ok((bound, server.boxed()))
+}
+
+/// Named pipes and other local sockets are not supported outside unix platforms.
+#[cfg(not(unix))]
+pub fn serve<S, F, Shutdown>(
+    path: &Path,
+    _service: S,
+    _header_timeout: Option<Duration>,
+    _shutdown: Shutdown,
+) -> io::Result<(PathBuf, BoxFuture<'static, ()>)>
+where
+    S: FnMut(Request<Body>) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+    Shutdown: Future<Output = ()> + Send + 'static,
+{
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!(
+            "unix domain sockets are not supported on this platform: {:?}",
+            path
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_addr, parse_addr, socket_path, unix_addr};
+    use std::path::Path;
+
+    #[test]
+    fn the_whole_path_is_kept() {
+        let addr = parse_addr("/unix/run/ipfs/api.sock").unwrap();
+        assert_eq!(socket_path(&addr).unwrap(), Path::new("/run/ipfs/api.sock"));
+        assert_eq!(format_addr(&addr), "/unix/run/ipfs/api.sock");
+
+        let relative = unix_addr(Path::new("ipfs/api.sock"));
+        assert_eq!(format_addr(&relative), "/unix/ipfs/api.sock");
+
+        let tcp = parse_addr("/ip4/127.0.0.1/tcp/5001").unwrap();
+        assert_eq!(socket_path(&tcp), None);
+        assert_eq!(format_addr(&tcp), "/ip4/127.0.0.1/tcp/5001");
+
+        assert!(parse_addr("/unix").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_a_request_and_replaces_only_a_stale_socket() {
+        use futures::future::{BoxFuture, FutureExt};
+        use std::convert::Infallible;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use warp::http::{Request, Response};
+        use warp::hyper::Body;
+
+        fn hello(_: Request<Body>) -> BoxFuture<'static, Result<Response<Body>, Infallible>> {
+            async { Ok(Response::new(Body::from("hello"))) }.boxed()
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("api.sock");
+
+        // left over by a process which did not remove it
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let (bound, server) = super::serve(&path, hello, None, async move {
+            let _ = stop_rx.await;
+        })
+        .unwrap();
+        assert_eq!(bound, path);
+        let server = tokio::spawn(server);
+
+        // the socket in use is not replaced
+        let e = super::serve(&path, hello, None, futures::future::pending()).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(resp.ends_with("\r\n\r\nhello"), "{}", resp);
+
+        stop_tx.send(()).unwrap();
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -2,20 +2,27 @@
 //!
 //! See https://docs.ipfs.io/reference/http/api/ for more information.
 
+use crate::log_tail::LogBuffer;
 use ipfs::{Ipfs, IpfsTypes};
+use std::path::PathBuf;
 use warp::{query, Filter};
 
 pub mod bitswap;
 pub mod block;
 pub mod bootstrap;
+pub mod config;
 pub mod dag;
 pub mod dht;
+pub mod filestore;
 pub mod id;
 pub mod ipns;
+pub mod log;
 pub mod pin;
 pub mod pubsub;
 pub mod refs;
+pub mod repo;
 pub mod root_files;
+pub mod stats;
 pub mod swarm;
 pub mod version;
 
@@ -64,25 +71,48 @@ macro_rules! and_boxed {
     };
 }
 
-/// Supported routes of the crate.
+/// The requests to stop or restart the daemon, sent by the `shutdown` and `restart` routes to
+/// the `lifecycle` channel given to [`routes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// Stop serving the API and exit.
+    Shutdown,
+    /// Stop serving the API, reload the configuration and serve the API again, while the node
+    /// and the repo stay open.
+    Restart,
+}
+
+/// Supported routes of the crate. The configuration history is rolled back by rewriting the
+/// configuration file at `config_path`, and the log events are followed from `logs`.
 pub fn routes<T: IpfsTypes>(
     ipfs: &Ipfs<T>,
-    shutdown_tx: tokio::sync::mpsc::Sender<()>,
+    config_path: Option<PathBuf>,
+    logs: LogBuffer,
+    lifecycle: tokio::sync::mpsc::Sender<Lifecycle>,
 ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     let mount = warp::post().and(warp::path!("api" / "v0" / ..));
 
+    let restart_tx = lifecycle.clone();
+
     let api = mount.and(combine!(
         and_boxed!(
             warp::path!("shutdown"),
             warp::any()
-                .map(move || shutdown_tx.clone())
-                .and_then(handle_shutdown)
+                .map(move || (lifecycle.clone(), Lifecycle::Shutdown))
+                .and_then(handle_lifecycle)
+        ),
+        and_boxed!(
+            warp::path!("restart"),
+            warp::any()
+                .map(move || (restart_tx.clone(), Lifecycle::Restart))
+                .and_then(handle_lifecycle)
         ),
         and_boxed!(warp::path!("id"), id::identity(ipfs)),
         and_boxed!(warp::path!("add"), root_files::add(ipfs)),
         and_boxed!(warp::path!("cat"), root_files::cat(ipfs)),
         and_boxed!(warp::path!("dns"), ipns::dns(ipfs)),
         and_boxed!(warp::path!("get"), root_files::get(ipfs)),
+        and_boxed!(warp::path!("ls"), root_files::ls(ipfs)),
         and_boxed!(warp::path!("refs" / "local"), refs::local(ipfs)),
         and_boxed!(warp::path!("refs"), refs::refs(ipfs)),
         and_boxed!(warp::path!("resolve"), ipns::resolve(ipfs)),
@@ -91,7 +121,8 @@ pub fn routes<T: IpfsTypes>(
             .and_then(version::version),
         warp::path("bitswap").and(combine!(
             and_boxed!(warp::path!("wantlist"), bitswap::wantlist(ipfs)),
-            and_boxed!(warp::path!("stat"), bitswap::stat(ipfs))
+            and_boxed!(warp::path!("stat"), bitswap::stat(ipfs)),
+            and_boxed!(warp::path!("ledger"), bitswap::ledger(ipfs))
         )),
         warp::path("block").and(combine!(
             and_boxed!(warp::path!("get"), block::get(ipfs)),
@@ -110,6 +141,8 @@ pub fn routes<T: IpfsTypes>(
             and_boxed!(warp::path!("rm" / "all"), bootstrap::bootstrap_clear(ipfs)),
         )),
         warp::path("dag").and(combine!(
+            and_boxed!(warp::path!("get"), dag::get(ipfs)),
+            and_boxed!(warp::path!("import"), dag::import(ipfs)),
             and_boxed!(warp::path!("put"), dag::put(ipfs)),
             and_boxed!(warp::path!("resolve"), dag::resolve(ipfs)),
         )),
@@ -139,25 +172,47 @@ pub fn routes<T: IpfsTypes>(
             and_boxed!(warp::path!("add"), pin::add(ipfs)),
             and_boxed!(warp::path!("ls"), pin::list(ipfs)),
             and_boxed!(warp::path!("rm"), pin::rm(ipfs)),
+            and_boxed!(warp::path!("remote" / "add"), pin::remote::add(ipfs)),
+            and_boxed!(warp::path!("remote" / "ls"), pin::remote::ls(ipfs)),
+            and_boxed!(warp::path!("remote" / "rm"), pin::remote::rm(ipfs)),
+            and_boxed!(
+                warp::path!("remote" / "service" / "ls"),
+                pin::remote::service_ls(ipfs)
+            ),
+        )),
+        warp::path("config").and(combine!(
+            and_boxed!(warp::path!("history"), config::history(ipfs)),
+            and_boxed!(warp::path!("rollback"), config::rollback(ipfs, config_path)),
         )),
         warp::path!("config" / ..).and_then(not_implemented),
         warp::path!("dht" / "get").and_then(not_implemented),
+        and_boxed!(warp::path!("filestore" / "verify"), filestore::verify(ipfs)),
+        warp::path!("filestore" / ..).and_then(not_implemented),
         warp::path!("dht" / "put").and_then(not_implemented),
         warp::path!("key" / ..).and_then(not_implemented),
+        warp::path("name").and(combine!(
+            and_boxed!(warp::path!("publish"), ipns::publish(ipfs)),
+            and_boxed!(warp::path!("resolve"), ipns::name_resolve(ipfs)),
+        )),
         warp::path!("name" / ..).and_then(not_implemented),
+        and_boxed!(warp::path!("log" / "tail"), log::tail(logs)),
         warp::path!("object" / ..).and_then(not_implemented),
         warp::path!("ping" / ..).and_then(not_implemented),
+        and_boxed!(warp::path!("repo" / "fsck"), repo::fsck(ipfs)),
+        and_boxed!(warp::path!("repo" / "gc"), repo::gc(ipfs)),
+        and_boxed!(warp::path!("repo" / "rebalance"), repo::rebalance(ipfs)),
         warp::path!("repo" / ..).and_then(not_implemented),
+        and_boxed!(warp::path!("stats" / "top"), stats::top(ipfs)),
         warp::path!("stats" / ..).and_then(not_implemented),
     ));
 
     api.recover(recover_as_message_response)
 }
 
-pub(crate) async fn handle_shutdown(
-    tx: tokio::sync::mpsc::Sender<()>,
+pub(crate) async fn handle_lifecycle(
+    (tx, request): (tokio::sync::mpsc::Sender<Lifecycle>, Lifecycle),
 ) -> Result<impl warp::Reply, std::convert::Infallible> {
-    Ok(match tx.send(()).await {
+    Ok(match tx.send(request).await {
         Ok(_) => warp::http::StatusCode::OK,
         Err(_) => warp::http::StatusCode::NOT_IMPLEMENTED,
     })
@@ -180,9 +235,9 @@ mod tests {
         let (ipfs, _): (Ipfs<TestTypes>, _) =
             UninitializedIpfs::new(options).start().await.unwrap();
 
-        let (shutdown_tx, _) = tokio::sync::mpsc::channel::<()>(1);
+        let (lifecycle_tx, _) = tokio::sync::mpsc::channel(1);
 
-        routes(&ipfs, shutdown_tx)
+        routes(&ipfs, None, Default::default(), lifecycle_tx)
     }
 
     #[tokio::test]
@@ -1,5 +1,5 @@
 use crate::v0::support::{with_ipfs, InvalidPeerId, StringError};
-use ipfs::{BitswapStats, Ipfs, IpfsTypes};
+use ipfs::{BitswapLedger, BitswapStats, Ipfs, IpfsTypes};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use warp::{query, reply, Filter, Rejection, Reply};
@@ -81,7 +81,7 @@ impl From<BitswapStats> for StatResponse {
             dup_data_received: stats.dup_data_received,
             peers,
             wantlist,
-            messages_received: 0,
+            messages_received: stats.messages_received,
             provide_buf_len: 0,
         }
     }
@@ -101,3 +101,53 @@ pub fn stat<T: IpfsTypes>(
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     with_ipfs(ipfs).and_then(stat_query)
 }
+
+#[derive(Debug, Deserialize)]
+pub struct LedgerQuery {
+    arg: String,
+}
+
+/// The ledger in the format of go-ipfs, with the bytes sent and received and the number of
+/// blocks exchanged.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LedgerResponse {
+    peer: String,
+    value: f64,
+    sent: u64,
+    recv: u64,
+    exchanged: u64,
+}
+
+impl From<BitswapLedger> for LedgerResponse {
+    fn from(ledger: BitswapLedger) -> Self {
+        Self {
+            peer: ledger.peer.to_string(),
+            value: ledger.debt_ratio(),
+            sent: ledger.data_sent,
+            recv: ledger.total_data_received(),
+            exchanged: ledger.exchanged(),
+        }
+    }
+}
+
+async fn ledger_query<T: IpfsTypes>(
+    ipfs: Ipfs<T>,
+    query: LedgerQuery,
+) -> Result<impl Reply, Rejection> {
+    let peer_id = query.arg.parse().map_err(|_| InvalidPeerId)?;
+    let ledger: LedgerResponse = ipfs
+        .bitswap_ledger(peer_id)
+        .await
+        .map_err(StringError::from)?
+        .into();
+    Ok(reply::json(&ledger))
+}
+
+pub fn ledger<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs)
+        .and(query::<LedgerQuery>())
+        .and_then(ledger_query)
+}
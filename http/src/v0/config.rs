@@ -0,0 +1,113 @@
+use crate::v0::support::{with_ipfs, StringError};
+use ipfs::config::history::ConfigSnapshot;
+use ipfs::ipld::dag_json::DagJsonCodec;
+use ipfs::{Ipfs, IpfsTypes};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use warp::{query, reply, Filter, Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SnapshotEntry {
+    cid: Value,
+    previous: Option<Value>,
+    /// Seconds since the unix epoch.
+    timestamp: u64,
+    message: String,
+    config: Value,
+}
+
+impl SnapshotEntry {
+    fn from_snapshot(snapshot: ConfigSnapshot) -> Result<Self, StringError> {
+        let config = DagJsonCodec::encode(&snapshot.config).map_err(StringError::from)?;
+        let config = serde_json::from_slice(&config).map_err(StringError::from)?;
+
+        Ok(SnapshotEntry {
+            cid: json!({ "/": snapshot.cid.to_string() }),
+            previous: snapshot
+                .previous
+                .map(|previous| json!({ "/": previous.to_string() })),
+            timestamp: snapshot
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            message: snapshot.message,
+            config,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct HistoryResponse {
+    snapshots: Vec<SnapshotEntry>,
+}
+
+async fn history_query<T: IpfsTypes>(
+    ipfs: Ipfs<T>,
+    query: HistoryQuery,
+) -> Result<impl Reply, Rejection> {
+    let snapshots = ipfs
+        .config_history(query.limit)
+        .await
+        .map_err(StringError::from)?
+        .into_iter()
+        .map(SnapshotEntry::from_snapshot)
+        .collect::<Result<_, _>>()?;
+
+    Ok(reply::json(&HistoryResponse { snapshots }))
+}
+
+/// Lists the recorded configurations from the latest to the oldest, see
+/// [`ipfs::config::history`].
+pub fn history<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs)
+        .and(query::<HistoryQuery>())
+        .and_then(history_query)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RollbackQuery {
+    /// The number of changes to roll back.
+    arg: Option<usize>,
+}
+
+async fn rollback_query<T: IpfsTypes>(
+    ipfs: Ipfs<T>,
+    config_path: Option<PathBuf>,
+    query: RollbackQuery,
+) -> Result<impl Reply, Rejection> {
+    let config_path = config_path
+        .ok_or_else(|| StringError::from("the daemon was not started with a configuration file"))?;
+
+    let snapshot = ipfs
+        .rollback_config(query.arg.unwrap_or(1))
+        .await
+        .map_err(StringError::from)?;
+
+    crate::config::restore(&config_path, &snapshot.config).map_err(StringError::from)?;
+
+    Ok(reply::json(&SnapshotEntry::from_snapshot(snapshot)?))
+}
+
+/// Restores the configuration file to the configuration before the given number of changes, one
+/// by default. The restored configuration is used after the next `restart`.
+pub fn rollback<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+    config_path: Option<PathBuf>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs)
+        .and(warp::any().map(move || config_path.clone()))
+        .and(query::<RollbackQuery>())
+        .and_then(rollback_query)
+}
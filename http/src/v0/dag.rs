@@ -1,9 +1,10 @@
 use crate::v0::support::{
-    try_only_named_multipart, with_ipfs, MaybeTimeoutExt, NotImplemented, StringError,
-    StringSerialized,
+    try_only_named_multipart, with_ipfs, MaybeTimeoutExt, StringError, StringSerialized,
 };
 use cid::{Cid, Codec};
 use futures::stream::Stream;
+use ipfs::car::CarImportOptions;
+use ipfs::ipld::{dag_cbor::DagCborCodec, dag_json::DagJsonCodec, encode_ipld, BlockError, Ipld};
 use ipfs::{Ipfs, IpfsTypes};
 use mime::Mime;
 
@@ -13,18 +14,24 @@ use warp::{query, reply, Buf, Filter, Rejection, Reply};
 
 #[derive(Debug, Deserialize)]
 pub struct PutQuery {
+    #[serde(alias = "store-codec")]
     format: Option<String>,
     hash: Option<String>,
-    #[serde(rename = "input-enc", default)]
+    #[serde(rename = "input-enc", alias = "input-codec", default)]
     encoding: InputEncoding,
 }
 
+/// The encoding of the posted document. Anything other than `raw` is decoded and encoded again
+/// with the codec given in `format`.
 #[derive(PartialEq, Eq, Debug, Deserialize)]
 pub enum InputEncoding {
+    /// The document is already encoded with the codec given in `format`.
     #[serde(rename = "raw")]
     Raw,
-    #[serde(rename = "json")]
+    #[serde(rename = "json", alias = "dag-json")]
     Json,
+    #[serde(rename = "dag-cbor")]
+    DagCbor,
 }
 
 impl Default for InputEncoding {
@@ -51,10 +58,6 @@ async fn put_query<T: IpfsTypes>(
 ) -> Result<impl Reply, Rejection> {
     use multihash::{Multihash, Sha2_256, Sha2_512, Sha3_512};
 
-    if query.encoding != InputEncoding::Raw {
-        return Err(NotImplemented.into());
-    }
-
     let (format, v0_fmt) = match query.format.as_deref().unwrap_or("dag-cbor") {
         "dag-cbor" => (Codec::DagCBOR, false),
         "dag-pb" => (Codec::DagProtobuf, true),
@@ -79,6 +82,15 @@ async fn put_query<T: IpfsTypes>(
         .await
         .map_err(StringError::from)?;
 
+    let data = match query.encoding {
+        InputEncoding::Raw => data,
+        InputEncoding::Json => reencode(DagJsonCodec::decode(&data), format)?,
+        InputEncoding::DagCbor => reencode(
+            DagCborCodec::decode(&data).map_err(BlockError::from),
+            format,
+        )?,
+    };
+
     let digest = hasher(&data);
 
     let cid = if v0_fmt && v0_hash {
@@ -100,6 +112,143 @@ async fn put_query<T: IpfsTypes>(
     Ok(reply::json(&reply))
 }
 
+/// Encodes the decoded input document with the `format` to be stored.
+fn reencode(decoded: Result<Ipld, BlockError>, format: Codec) -> Result<Vec<u8>, Rejection> {
+    let ipld = decoded.map_err(|e| StringError::from(format!("invalid input: {}", e)))?;
+
+    encode_ipld(&ipld, format)
+        .map(Vec::from)
+        .map_err(|e| StringError::from(e).into())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetQuery {
+    arg: String,
+    #[serde(rename = "output-codec", alias = "output-enc")]
+    output_codec: Option<String>,
+    timeout: Option<StringSerialized<humantime::Duration>>,
+}
+
+/// Returns the document or the node at the path, encoded with the `output-codec`, which is
+/// `dag-json` by default like in go-ipfs.
+pub fn get<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs).and(query::<GetQuery>()).and_then(get_query)
+}
+
+async fn get_query<T: IpfsTypes>(ipfs: Ipfs<T>, query: GetQuery) -> Result<impl Reply, Rejection> {
+    use ipfs::IpfsPath;
+    use std::convert::TryFrom;
+
+    let (codec, content_type) = match query.output_codec.as_deref().unwrap_or("dag-json") {
+        "dag-json" | "json" => (Codec::DagJSON, "application/json"),
+        "dag-cbor" | "cbor" => (Codec::DagCBOR, "application/cbor"),
+        _ => return Err(StringError::from("unknown output codec").into()),
+    };
+
+    let path = IpfsPath::try_from(query.arg.as_str()).map_err(StringError::from)?;
+
+    let ipld = ipfs
+        .dag()
+        .get(path)
+        .maybe_timeout(query.timeout.map(StringSerialized::into_inner))
+        .await
+        .map_err(StringError::from)?
+        .map_err(StringError::from)?;
+
+    let body = encode_ipld(&ipld, codec).map_err(StringError::from)?;
+
+    Ok(reply::with_header(
+        Vec::from(body),
+        "content-type",
+        content_type,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    #[serde(rename = "pin-roots")]
+    pin_roots: Option<bool>,
+    #[serde(default)]
+    stats: bool,
+}
+
+/// Imports the blocks of the CARv1 or CARv2 files posted as multipart fields, pinning their
+/// roots unless `pin-roots=false` is given. Responds with the roots of the files, and their
+/// block counts with `stats=true`, like go-ipfs.
+pub fn import<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs)
+        .and(query::<ImportQuery>())
+        .and(warp::header::<Mime>("content-type")) // TODO: rejects if missing
+        .and(warp::body::stream())
+        .and_then(import_query)
+}
+
+async fn import_query<T: IpfsTypes>(
+    ipfs: Ipfs<T>,
+    query: ImportQuery,
+    mime: Mime,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+) -> Result<impl Reply, Rejection> {
+    use bytes::Bytes;
+    use futures::stream::TryStreamExt;
+    use mpart_async::server::MultipartStream;
+    use tokio_util::io::StreamReader;
+
+    let boundary = mime
+        .get_param("boundary")
+        .map(|v| v.to_string())
+        .ok_or_else(|| StringError::from("missing 'boundary' on content-type"))?;
+
+    let mut fields = MultipartStream::new(
+        Bytes::from(boundary),
+        body.map_ok(|mut buf| buf.copy_to_bytes(buf.remaining())),
+    );
+
+    let opts = CarImportOptions {
+        pin_roots: query.pin_roots.unwrap_or(true),
+        // only the blocks are needed
+        build_index: false,
+    };
+
+    let mut roots = Vec::new();
+    let (mut blocks, mut block_bytes) = (0, 0);
+
+    while let Some(field) = fields.try_next().await.map_err(StringError::from)? {
+        let reader = StreamReader::new(
+            field.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
+        );
+
+        let imported = ipfs
+            .import_car(reader, opts.clone())
+            .await
+            .map_err(StringError::from)?;
+
+        roots.extend(imported.roots);
+        blocks += imported.blocks;
+        block_bytes += imported.block_bytes;
+    }
+
+    let mut lines = roots
+        .into_iter()
+        .map(|root| json!({ "Root": { "Cid": { "/": root.to_string() }, "PinErrorMsg": "" } }))
+        .collect::<Vec<_>>();
+
+    if query.stats {
+        lines.push(json!({ "Stats": { "BlockCount": blocks, "BlockBytesCount": block_bytes } }));
+    }
+
+    let body = lines
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect::<String>();
+
+    Ok(reply::with_header(body, "content-type", "application/json"))
+}
+
 /// Per https://docs-beta.ipfs.io/reference/http/api/#api-v0-block-resolve this endpoint takes in a
 /// path and resolves it to the last block (the cid), and to the path inside the final block
 /// (rempath).
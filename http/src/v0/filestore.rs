@@ -0,0 +1,69 @@
+use crate::v0::support::{with_ipfs, StringError};
+use ipfs::{FilestoreCheck, FilestoreStatus, Ipfs, IpfsTypes};
+use serde::Serialize;
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Debug, Serialize)]
+struct Key {
+    #[serde(rename = "/")]
+    cid: String,
+}
+
+/// A line of the streamed response, like go-ipfs writes them.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct VerifyResponse {
+    status: u8,
+    error_msg: String,
+    key: Key,
+    file_path: String,
+    offset: u64,
+    size: u64,
+}
+
+impl From<FilestoreCheck> for VerifyResponse {
+    fn from(check: FilestoreCheck) -> Self {
+        // the status codes of go-ipfs
+        let (status, error_msg) = match check.status {
+            FilestoreStatus::Ok => (0, String::new()),
+            FilestoreStatus::FileError(e) => (1, e),
+            FilestoreStatus::FileNotFound => (2, String::from("file not found")),
+            FilestoreStatus::FileChanged => (3, String::from("file changed")),
+        };
+
+        VerifyResponse {
+            status,
+            error_msg,
+            key: Key {
+                cid: check.entry.cid.to_string(),
+            },
+            file_path: check.entry.source.to_string(),
+            offset: check.entry.offset,
+            size: check.entry.len,
+        }
+    }
+}
+
+async fn verify_query<T: IpfsTypes>(ipfs: Ipfs<T>) -> Result<impl Reply, Rejection> {
+    let checks = ipfs.filestore_verify().await.map_err(StringError::from)?;
+
+    let mut body = Vec::new();
+    for check in checks {
+        serde_json::to_writer(&mut body, &VerifyResponse::from(check))
+            .map_err(StringError::from)?;
+        body.push(b'\n');
+    }
+
+    Ok(warp::reply::with_header(
+        body,
+        "content-type",
+        "application/json",
+    ))
+}
+
+/// Checks the blocks added without copying against their files, see [`Ipfs::filestore_verify`].
+pub fn verify<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs).and_then(verify_query)
+}
@@ -1,6 +1,7 @@
 use crate::v0::support::{with_ipfs, StringError, StringSerialized};
 use ipfs::{Ipfs, IpfsPath, IpfsTypes};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use warp::{query, Filter, Rejection, Reply};
 
 #[derive(Debug, Deserialize)]
@@ -15,6 +16,8 @@ pub struct ResolveQuery {
     #[allow(unused)]
     #[serde(rename = "dht-timeout")]
     dht_timeout: Option<String>,
+    // skip the cached resolution
+    nocache: Option<bool>,
 }
 
 pub fn resolve<T: IpfsTypes>(
@@ -29,13 +32,14 @@ async fn resolve_query<T: IpfsTypes>(
     ipfs: Ipfs<T>,
     query: ResolveQuery,
 ) -> Result<impl Reply, Rejection> {
-    let ResolveQuery { arg, .. } = query;
+    let ResolveQuery { arg, nocache, .. } = query;
     let name = arg.into_inner();
-    let path = ipfs
-        .resolve_ipns(&name, false)
-        .await
-        .map_err(StringError::from)?
-        .to_string();
+    let resolved = if nocache.unwrap_or(false) {
+        ipfs.resolve_ipns(&name, false).await
+    } else {
+        ipfs.resolve_ipns_cached(&name, false).await
+    };
+    let path = resolved.map_err(StringError::from)?.to_string();
 
     let response = ResolveResponse { path };
 
@@ -77,7 +81,7 @@ async fn dns_query<T: IpfsTypes>(ipfs: Ipfs<T>, query: DnsQuery) -> Result<impl
     .map_err(StringError::from)?;
 
     let path = ipfs
-        .resolve_ipns(&path, recursive.unwrap_or(false))
+        .resolve_ipns_cached(&path, recursive.unwrap_or(false))
         .await
         .map_err(StringError::from)?
         .to_string();
@@ -92,3 +96,121 @@ async fn dns_query<T: IpfsTypes>(ipfs: Ipfs<T>, query: DnsQuery) -> Result<impl
 struct DnsResponse {
     path: String,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct PublishQuery {
+    // the path to publish
+    arg: StringSerialized<IpfsPath>,
+    // how long the record is valid for, like `24h`
+    lifetime: Option<String>,
+    // how long the record can be cached for, like `1h`
+    ttl: Option<String>,
+    // only the key of the node, `self`, is supported
+    key: Option<String>,
+}
+
+pub fn publish<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs)
+        .and(query::<PublishQuery>())
+        .and_then(publish_query)
+}
+
+async fn publish_query<T: IpfsTypes>(
+    ipfs: Ipfs<T>,
+    query: PublishQuery,
+) -> Result<impl Reply, Rejection> {
+    let PublishQuery {
+        arg,
+        lifetime,
+        ttl,
+        key,
+    } = query;
+
+    if let Some(key) = key.filter(|key| key != "self") {
+        return Err(StringError::from(format!("no key named {:?}", key)).into());
+    }
+
+    let parse_duration = |duration: Option<String>, default: Duration| match duration {
+        Some(duration) => humantime::parse_duration(&duration).map_err(StringError::from),
+        None => Ok(default),
+    };
+
+    let defaults = ipfs::ipns::PublishOptions::default();
+    let options = ipfs::ipns::PublishOptions {
+        lifetime: parse_duration(lifetime, defaults.lifetime)?,
+        ttl: parse_duration(ttl, defaults.ttl)?,
+    };
+
+    let path = arg.into_inner();
+    let name = ipfs
+        .publish_ipns(&path, &options)
+        .await
+        .map_err(StringError::from)?;
+
+    let response = PublishResponse {
+        name: name.to_string(),
+        value: path.to_string(),
+    };
+
+    Ok(warp::reply::json(&response))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PublishResponse {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NameResolveQuery {
+    // the name to resolve, the name of the node by default
+    arg: Option<String>,
+    recursive: Option<bool>,
+    // skip the cached resolution
+    nocache: Option<bool>,
+}
+
+pub fn name_resolve<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs)
+        .and(query::<NameResolveQuery>())
+        .and_then(name_resolve_query)
+}
+
+async fn name_resolve_query<T: IpfsTypes>(
+    ipfs: Ipfs<T>,
+    query: NameResolveQuery,
+) -> Result<impl Reply, Rejection> {
+    let NameResolveQuery {
+        arg,
+        recursive,
+        nocache,
+    } = query;
+
+    // the names can be given without the /ipns/ prefix
+    let name = match arg {
+        Some(arg) if arg.starts_with('/') => arg,
+        Some(arg) => format!("/ipns/{}", arg),
+        None => {
+            let (public_key, _) = ipfs.identity().await.map_err(StringError::from)?;
+            format!("/ipns/{}", public_key.to_peer_id())
+        }
+    };
+    let name: IpfsPath = name.parse().map_err(StringError::from)?;
+
+    let recursive = recursive.unwrap_or(true);
+    let resolved = if nocache.unwrap_or(false) {
+        ipfs.resolve_ipns(&name, recursive).await
+    } else {
+        ipfs.resolve_ipns_cached(&name, recursive).await
+    };
+    let path = resolved.map_err(StringError::from)?.to_string();
+
+    let response = ResolveResponse { path };
+
+    Ok(warp::reply::json(&response))
+}
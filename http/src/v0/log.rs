@@ -0,0 +1,93 @@
+use crate::log_tail::{LogBuffer, LogEvent};
+use crate::v0::support::{StreamResponse, StringError};
+use bytes::Bytes;
+use futures::stream::Stream;
+use serde::Deserialize;
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::Level;
+use warp::{query, Filter, Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+pub struct TailQuery {
+    /// The most verbose level included, `info` by default.
+    level: Option<String>,
+    /// Comma separated crates or module paths the events are included from, all by default.
+    subsystem: Option<String>,
+    /// Keep streaming the new events after the buffered ones, true by default.
+    follow: Option<bool>,
+}
+
+/// Streams the buffered log events followed by the new ones as newline delimited json, see
+/// [`crate::log_tail`].
+pub fn tail(logs: LogBuffer) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::any()
+        .map(move || logs.clone())
+        .and(query::<TailQuery>())
+        .and_then(tail_query)
+}
+
+async fn tail_query(logs: LogBuffer, query: TailQuery) -> Result<impl Reply, Rejection> {
+    let level = match query.level.as_deref() {
+        Some(level) => level
+            .parse::<Level>()
+            .map_err(|_| StringError::from(format!("invalid level {:?}", level)))?,
+        None => Level::INFO,
+    };
+
+    let subsystems = query
+        .subsystem
+        .map(|subsystems| {
+            subsystems
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let filter = move |event: &LogEvent| {
+        event.is_at_least(level)
+            && (subsystems.is_empty() || subsystems.iter().any(|s| event.is_from(s)))
+    };
+
+    Ok(StreamResponse(tail_stream(
+        logs,
+        filter,
+        query.follow.unwrap_or(true),
+    )))
+}
+
+fn tail_stream(
+    logs: LogBuffer,
+    filter: impl Fn(&LogEvent) -> bool + Send + 'static,
+    follow: bool,
+) -> impl Stream<Item = Result<Bytes, Infallible>> + Send + 'static {
+    async_stream::stream! {
+        let (buffered, mut live) = logs.follow();
+
+        for event in buffered.iter().filter(|event| filter(event)) {
+            yield Ok(to_ndjson(event));
+        }
+
+        if !follow {
+            return;
+        }
+
+        loop {
+            match live.recv().await {
+                Ok(event) if filter(&event) => yield Ok(to_ndjson(&event)),
+                Ok(_) => {}
+                // the slow followers miss some of the events
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+fn to_ndjson(event: &LogEvent) -> Bytes {
+    let mut line = serde_json::to_vec(event).expect("log events serialize");
+    line.push(b'\n');
+    line.into()
+}
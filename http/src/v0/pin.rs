@@ -9,6 +9,7 @@ use std::str::FromStr;
 use warp::{Filter, Rejection, Reply};
 
 mod add;
+pub mod remote;
 
 /// `pin/add` per https://docs.ipfs.io/reference/http/api/#api-v0-pin-add or the
 /// interface-ipfs-http test suite.
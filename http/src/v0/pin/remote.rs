@@ -0,0 +1,176 @@
+//! `pin/remote` routes delegating the pins to the remote pinning services configured under
+//! `Pinning.RemoteServices`, see [`ipfs::pin::remote`].
+
+use crate::v0::support::{with_ipfs, StringError, StringSerialized};
+use ipfs::pin::remote::{RemotePinQuery, RemotePinRecord, RemotePinStatus};
+use ipfs::{Cid, Ipfs, IpfsTypes};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use warp::{query, Filter, Rejection, Reply};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PinResponse {
+    cid: String,
+    name: String,
+    status: String,
+}
+
+impl From<RemotePinRecord> for PinResponse {
+    fn from(record: RemotePinRecord) -> Self {
+        PinResponse {
+            cid: record.pin.cid.to_string(),
+            name: record.pin.name.unwrap_or_default(),
+            status: record.status.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddQuery {
+    arg: StringSerialized<Cid>,
+    service: String,
+    name: Option<String>,
+}
+
+async fn add_query<T: IpfsTypes>(ipfs: Ipfs<T>, query: AddQuery) -> Result<impl Reply, Rejection> {
+    let record = ipfs
+        .pin_remote_add(&query.service, query.arg.into_inner(), query.name)
+        .await
+        .map_err(StringError::from)?;
+
+    Ok(warp::reply::json(&PinResponse::from(record)))
+}
+
+/// `pin/remote/add` asks the service to pin the content, fetching it from this node. The
+/// response is sent right away, without waiting for the content to be pinned.
+pub fn add<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs).and(query::<AddQuery>()).and_then(add_query)
+}
+
+/// The filters of `pin/remote/ls` and `pin/remote/rm`.
+#[derive(Debug, Deserialize)]
+pub struct MatchQuery {
+    service: String,
+    name: Option<String>,
+    /// Comma separated Cids.
+    cid: Option<String>,
+    /// Comma separated statuses, `pinned` by default.
+    status: Option<String>,
+    /// Only used by `pin/remote/rm`.
+    force: Option<bool>,
+}
+
+impl MatchQuery {
+    fn to_query(&self) -> Result<RemotePinQuery, StringError> {
+        let cids = split(self.cid.as_deref())
+            .map(|cid| Cid::try_from(cid).map_err(StringError::from))
+            .collect::<Result<_, _>>()?;
+        let statuses = split(self.status.as_deref())
+            .map(|status| status.parse::<RemotePinStatus>().map_err(StringError::from))
+            .collect::<Result<_, _>>()?;
+
+        Ok(RemotePinQuery {
+            cids,
+            name: self.name.clone(),
+            statuses,
+            limit: None,
+        })
+    }
+}
+
+fn split(list: Option<&str>) -> impl Iterator<Item = &str> {
+    list.unwrap_or_default()
+        .split(',')
+        .filter(|item| !item.is_empty())
+}
+
+/// The pins as newline delimited json, like the streamed responses of go-ipfs.
+fn ndjson(records: Vec<RemotePinRecord>) -> Result<impl Reply, StringError> {
+    let mut body = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut body, &PinResponse::from(record)).map_err(StringError::from)?;
+        body.push(b'\n');
+    }
+    Ok(warp::reply::with_header(
+        body,
+        "content-type",
+        "application/json",
+    ))
+}
+
+async fn ls_query<T: IpfsTypes>(ipfs: Ipfs<T>, query: MatchQuery) -> Result<impl Reply, Rejection> {
+    let records = ipfs
+        .pin_remote_ls(&query.service, &query.to_query()?)
+        .await
+        .map_err(StringError::from)?;
+
+    Ok(ndjson(records)?)
+}
+
+/// `pin/remote/ls` lists the matching pins on the service.
+pub fn ls<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs)
+        .and(query::<MatchQuery>())
+        .and_then(ls_query)
+}
+
+async fn rm_query<T: IpfsTypes>(ipfs: Ipfs<T>, query: MatchQuery) -> Result<impl Reply, Rejection> {
+    ipfs.pin_remote_rm(
+        &query.service,
+        &query.to_query()?,
+        query.force.unwrap_or(false),
+    )
+    .await
+    .map_err(StringError::from)?;
+
+    Ok(warp::reply())
+}
+
+/// `pin/remote/rm` removes the matching pins from the service, requiring `force` for removing
+/// more than one.
+pub fn rm<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs)
+        .and(query::<MatchQuery>())
+        .and_then(rm_query)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    service: String,
+    #[serde(rename = "ApiEndpoint")]
+    api_endpoint: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceListResponse {
+    remote_services: Vec<ServiceEntry>,
+}
+
+async fn service_ls_query<T: IpfsTypes>(ipfs: Ipfs<T>) -> Result<impl Reply, Rejection> {
+    let remote_services = ipfs
+        .pinning_services()
+        .into_iter()
+        .map(|service| ServiceEntry {
+            service: service.name,
+            api_endpoint: service.endpoint,
+        })
+        .collect();
+
+    Ok(warp::reply::json(&ServiceListResponse { remote_services }))
+}
+
+/// `pin/remote/service/ls` lists the configured services, without their keys.
+pub fn service_ls<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs).and_then(service_ls_query)
+}
@@ -0,0 +1,137 @@
+use crate::v0::support::{with_ipfs, StreamResponse, StringError};
+use futures::stream::StreamExt;
+use ipfs::fsck::Repair;
+use ipfs::{Cid, Ipfs, IpfsTypes};
+use serde::Serialize;
+use std::convert::Infallible;
+use warp::{reply, Filter, Rejection, Reply};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct FsckResponse {
+    message: String,
+    repairs: Vec<String>,
+}
+
+fn describe(repair: &Repair) -> String {
+    match repair {
+        Repair::RemovedCorruptedBlock(cid) => format!("removed corrupted block {}", cid),
+        Repair::CompletedBlockRemoval(cid) => format!("completed removal of block {}", cid),
+        Repair::RolledBackRecursivePin(cid) => format!("rolled back partial recursive pin {}", cid),
+        Repair::CompletedRecursiveUnpin(cid) => format!("completed recursive unpin {}", cid),
+        Repair::Failed(mutation, e) => format!(
+            "failed to repair {} of {}: {}",
+            mutation.kind(),
+            mutation.cid(),
+            e
+        ),
+    }
+}
+
+async fn fsck_query<T: IpfsTypes>(ipfs: Ipfs<T>) -> Result<impl Reply, Infallible> {
+    let report = ipfs.repo_fsck().await;
+
+    let message = if report.is_clean() {
+        String::from("Repo is consistent")
+    } else {
+        format!(
+            "Checked {} interrupted operations, performed {} repairs",
+            report.checked,
+            report.repairs.len()
+        )
+    };
+
+    let response = FsckResponse {
+        message,
+        repairs: report.repairs.iter().map(describe).collect(),
+    };
+
+    Ok(reply::json(&response))
+}
+
+/// Repairs the repo after operations interrupted by a crash, see [`Ipfs::repo_fsck`].
+pub fn fsck<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs).and_then(fsck_query)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct RebalanceResponse {
+    moved: usize,
+    moved_bytes: u64,
+    removed_duplicates: usize,
+}
+
+async fn rebalance_query<T: IpfsTypes>(ipfs: Ipfs<T>) -> Result<impl Reply, Rejection> {
+    let rebalance = ipfs.rebalance_blocks().await.map_err(StringError::from)?;
+
+    let response = RebalanceResponse {
+        moved: rebalance.moved,
+        moved_bytes: rebalance.moved_bytes,
+        removed_duplicates: rebalance.removed_duplicates,
+    };
+
+    Ok(reply::json(&response))
+}
+
+/// Moves the blocks between the blockstore volumes, see [`Ipfs::rebalance_blocks`].
+pub fn rebalance<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs).and_then(rebalance_query)
+}
+
+#[derive(Debug, Serialize)]
+struct GcKey {
+    #[serde(rename = "/")]
+    cid: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GcResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<GcKey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<Result<Cid, ipfs::Error>> for GcResponse {
+    fn from(removed: Result<Cid, ipfs::Error>) -> Self {
+        match removed {
+            Ok(cid) => GcResponse {
+                key: Some(GcKey {
+                    cid: cid.to_string(),
+                }),
+                error: None,
+            },
+            Err(e) => GcResponse {
+                key: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+async fn gc_query<T: IpfsTypes>(ipfs: Ipfs<T>) -> Result<impl Reply, Infallible> {
+    // a line for each removed block as it is removed, like go-ipfs, ending with the error if the
+    // collection fails
+    let st = ipfs.gc_stream().map(|removed| {
+        let mut line = serde_json::to_vec(&GcResponse::from(removed))
+            .expect("serializing the response cannot fail");
+        line.push(b'\n');
+        Ok::<_, Infallible>(line)
+    });
+
+    Ok(StreamResponse(st))
+}
+
+/// Removes the blocks which are neither pinned nor reachable from the roots of the node, see
+/// [`Ipfs::gc`].
+pub fn gc<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs).and_then(gc_query)
+}
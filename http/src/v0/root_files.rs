@@ -1,13 +1,18 @@
+use crate::gateway::UpstreamGateways;
 use crate::v0::support::{
     with_ipfs, MaybeTimeoutExt, StreamResponse, StringError, StringSerialized,
 };
 use async_stream::try_stream;
 use bytes::Bytes;
-use futures::stream::TryStream;
+use cid::Cid;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt, TryStream};
+use ipfs::unixfs::ll::file::adder::ChunkerOptions;
 use ipfs::unixfs::ll::walk::{self, ContinuedWalk, Walker};
 use ipfs::unixfs::{ll::file::FileReadFailed, TraversalFailed};
 use ipfs::{dag::ResolveError, Block, Ipfs, IpfsPath, IpfsTypes};
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::path::Path;
 use warp::{query, Filter, Rejection, Reply};
@@ -15,8 +20,13 @@ use warp::{query, Filter, Rejection, Reply};
 mod tar_helper;
 use tar_helper::TarHelper;
 
+mod zip_helper;
+use zip_helper::ZipHelper;
+
 mod add;
 
+pub(crate) mod ls;
+
 #[derive(Debug, Deserialize)]
 pub struct AddArgs {
     // unknown meaning; ignoring it doesn't fail any tests
@@ -29,6 +39,10 @@ pub struct AddArgs {
     /// When true, a new directory is created to hold more than 1 root level directories.
     #[serde(default, rename = "wrap-with-directory")]
     wrap_with_directory: bool,
+    /// The chunker of the files, like `size-<size>`, `rabin-<min>-<avg>-<max>` or `buzhash`.
+    chunker: Option<StringSerialized<ChunkerOptions>>,
+    /// Pin the added files and directories recursively, defaults to true.
+    pin: Option<bool>,
 }
 
 pub fn add<T: IpfsTypes>(
@@ -112,10 +126,36 @@ async fn get_inner<T: IpfsTypes>(ipfs: Ipfs<T>, args: GetArgs) -> Result<impl Re
         .map_err(StringError::from)?
         .map_err(StringError::from)?;
 
-    Ok(StreamResponse(walk(ipfs, block).into_stream()))
+    Ok(StreamResponse(walk(ipfs, block, None).into_stream()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LsArgs {
+    arg: StringSerialized<IpfsPath>,
+    /// Load the linked blocks to find out the types of the entries. Defaults to true.
+    #[serde(rename = "resolve-type")]
+    resolve_type: Option<bool>,
+    /// Load the linked blocks to find out the sizes of the files. Defaults to true.
+    size: Option<bool>,
+    /// Write out each entry as soon as it is listed, as an object of its own on a separate line.
+    #[serde(default)]
+    stream: bool,
+    timeout: Option<StringSerialized<humantime::Duration>>,
 }
 
-async fn resolve_dagpb<T: IpfsTypes>(ipfs: &Ipfs<T>, path: IpfsPath) -> Result<Block, StringError> {
+/// https://docs.ipfs.io/reference/http/api/#api-v0-ls
+pub fn ls<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs)
+        .and(query::<LsArgs>())
+        .and_then(ls::ls_inner)
+}
+
+pub(crate) async fn resolve_dagpb<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+    path: IpfsPath,
+) -> Result<Block, StringError> {
     let (resolved, _) = ipfs
         .dag()
         .resolve(path, true)
@@ -125,12 +165,14 @@ async fn resolve_dagpb<T: IpfsTypes>(ipfs: &Ipfs<T>, path: IpfsPath) -> Result<B
     resolved.into_unixfs_block().map_err(StringError::from)
 }
 
-fn walk<Types: IpfsTypes>(
+/// Exports the tree as a tar archive, fetching the missing blocks also from the `upstreams`.
+pub(crate) fn walk<Types: IpfsTypes>(
     ipfs: Ipfs<Types>,
     Block {
         cid: root,
         data: first_block_data,
     }: Block,
+    upstreams: Option<UpstreamGateways>,
 ) -> impl TryStream<Ok = Bytes, Error = GetError> + 'static {
     let mut cache = None;
     let mut tar_helper = TarHelper::with_capacity(16 * 1024);
@@ -141,16 +183,13 @@ fn walk<Types: IpfsTypes>(
     let mut walker = Walker::new(root, name);
 
     let mut buffer = Some(first_block_data);
+    let mut read_ahead = ReadAhead::new(upstreams);
 
     try_stream! {
         while walker.should_continue() {
             let data = match buffer.take() {
                 Some(first) => first,
-                None => {
-                    let (next, _) = walker.pending_links();
-                    let Block { data, .. } = ipfs.get_block(next).await?;
-                    data
-                }
+                None => read_ahead.load(&ipfs, &walker).await?,
             };
 
             match walker.next(&data, &mut cache)? {
@@ -207,13 +246,190 @@ fn walk<Types: IpfsTypes>(
     }
 }
 
+/// Exports the tree as a zip archive, the counterpart of [`walk`] for clients without tar support.
+pub(crate) fn zip_walk<Types: IpfsTypes>(
+    ipfs: Ipfs<Types>,
+    Block {
+        cid: root,
+        data: first_block_data,
+    }: Block,
+    upstreams: Option<UpstreamGateways>,
+) -> impl TryStream<Ok = Bytes, Error = GetError> + 'static {
+    let mut cache = None;
+    let mut zip_helper = ZipHelper::with_capacity(16 * 1024);
+
+    // same as with tar, the final Cid is used as the root name
+    let name = root.to_string();
+    let mut walker = Walker::new(root, name);
+
+    let mut buffer = Some(first_block_data);
+    let mut read_ahead = ReadAhead::new(upstreams);
+
+    try_stream! {
+        while walker.should_continue() {
+            let data = match buffer.take() {
+                Some(first) => first,
+                None => read_ahead.load(&ipfs, &walker).await?,
+            };
+
+            match walker.next(&data, &mut cache)? {
+                ContinuedWalk::Bucket(..) => {}
+                ContinuedWalk::File(segment, _, path, metadata, size) => {
+                    if segment.is_first() {
+                        yield zip_helper.apply_file(path, metadata, size)?;
+                    }
+
+                    let mut n = 0usize;
+                    let slice = segment.as_ref();
+                    let total = slice.len();
+
+                    while n < total {
+                        let next = zip_helper.buffer_file_contents(&slice[n..]);
+                        n += next.len();
+                        yield next;
+                    }
+
+                    if segment.is_last() {
+                        yield zip_helper.finish_file()?;
+                    }
+                },
+                ContinuedWalk::Directory(_, path, metadata) | ContinuedWalk::RootDirectory(_, path, metadata) => {
+                    yield zip_helper.apply_directory(path, metadata)?;
+                },
+                ContinuedWalk::Symlink(bytes, _, path, metadata) => {
+                    yield zip_helper.apply_symlink(path, bytes, metadata)?;
+                },
+            };
+        }
+
+        yield zip_helper.finish();
+    }
+}
+
+/// Loads the blocks for a [`Walker`], reading the next few pending links from the local
+/// blockstore at once instead of doing one lookup per block.
+///
+/// The pending links missing from the local blockstore are fetched from the network concurrently,
+/// up to [`ReadAhead::PREFETCH`] at a time, so that the blocks of the sibling files are already
+/// being fetched while the current file is exported. The fetches are dropped along with the
+/// walk.
+struct ReadAhead {
+    blocks: HashMap<Cid, Box<[u8]>>,
+    fetching: FuturesUnordered<BoxFuture<'static, (Cid, Result<Block, ipfs::Error>)>>,
+    in_flight: HashSet<Cid>,
+    upstreams: Option<UpstreamGateways>,
+}
+
+impl ReadAhead {
+    /// How many of the pending links are read at once.
+    const WINDOW: usize = 16;
+
+    /// How many of the pending links are fetched from the network at once.
+    const PREFETCH: usize = 32;
+
+    fn new(upstreams: Option<UpstreamGateways>) -> Self {
+        ReadAhead {
+            blocks: Default::default(),
+            fetching: Default::default(),
+            in_flight: Default::default(),
+            upstreams,
+        }
+    }
+
+    async fn load<Types: IpfsTypes>(
+        &mut self,
+        ipfs: &Ipfs<Types>,
+        walker: &Walker,
+    ) -> Result<Box<[u8]>, ipfs::Error> {
+        let (next, rest) = walker.pending_links();
+
+        if let Some(data) = self.blocks.remove(next) {
+            return Ok(data);
+        }
+
+        // whatever was read earlier but not yet used is still pending, possibly behind a link
+        // which was not in the local blockstore
+        let wanted = std::iter::once(next)
+            .chain(rest)
+            .filter(|cid| !self.blocks.contains_key(cid) && !self.in_flight.contains(cid))
+            .take(Self::WINDOW.max(Self::PREFETCH))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let local = &wanted[..wanted.len().min(Self::WINDOW)];
+        for block in ipfs.get_blocks_now(local).await?.into_iter().flatten() {
+            self.blocks.insert(block.cid, block.data);
+        }
+
+        // not available locally; fetch the next and the following ones from the network
+        for cid in &wanted {
+            if cid != next && self.in_flight.len() >= Self::PREFETCH {
+                break;
+            }
+            if !self.blocks.contains_key(cid) {
+                self.fetch(ipfs, cid.to_owned());
+            }
+        }
+
+        loop {
+            if let Some(data) = self.blocks.remove(next) {
+                return Ok(data);
+            }
+
+            if !self.in_flight.contains(next) {
+                self.fetch(ipfs, next.to_owned());
+            }
+
+            let (cid, res) = self
+                .fetching
+                .next()
+                .await
+                .expect("the next block is being fetched");
+            self.in_flight.remove(&cid);
+
+            match res {
+                Ok(block) => {
+                    self.blocks.insert(block.cid, block.data);
+                }
+                Err(e) if &cid == next => return Err(e),
+                // fetched again once it is the next
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn fetch<Types: IpfsTypes>(&mut self, ipfs: &Ipfs<Types>, cid: Cid) {
+        if !self.in_flight.insert(cid.clone()) {
+            return;
+        }
+
+        let ipfs = ipfs.clone();
+        let upstreams = self.upstreams.clone();
+        self.fetching.push(
+            async move {
+                let res = match upstreams {
+                    Some(upstreams) => upstreams.get_block(&ipfs, &cid).await,
+                    None => ipfs.get_block(&cid).await,
+                };
+                (cid, res)
+            }
+            .boxed(),
+        );
+    }
+}
+
 #[derive(Debug)]
-enum GetError {
+pub(crate) enum GetError {
     NonUtf8Symlink,
     InvalidFileName(Vec<u8>),
     InvalidLinkName(Vec<u8>),
     Walk(walk::Error),
     Loading(ipfs::Error),
+    /// The contents of a file were not of the size recorded in its metadata.
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
 }
 
 impl From<ipfs::Error> for GetError {
@@ -237,6 +453,11 @@ impl fmt::Display for GetError {
             Loading(e) => write!(fmt, "loading failed: {}", e),
             InvalidFileName(x) => write!(fmt, "filename cannot be put inside tar: {:?}", x),
             InvalidLinkName(x) => write!(fmt, "symlink name cannot be put inside tar: {:?}", x),
+            SizeMismatch { expected, actual } => write!(
+                fmt,
+                "file size differs from the announced: expected {} bytes, got {}",
+                expected, actual
+            ),
         }
     }
 }
@@ -397,6 +618,91 @@ mod tests {
         assert_eq!(found, expected);
     }
 
+    #[tokio::test]
+    async fn ls_resolves_types_and_sizes() {
+        let ipfs = Node::new("test_node").await;
+
+        let blocks: &[&[u8]] = &[
+            // the root, QmRJHYTNvC3hmd9gJQARxLR1QMEincccBV53bBw524yyq6 from get_multiblock_file
+            &hex!("12280a221220fef9fe1804942b35e19e145a03f9c9d5ca9c997dda0a9416f3f515a52f1b3ce11200180a12280a221220dfb94b75acb208fd4873d84872af58bd65c731770a7d4c0deeb4088e87390bfe1200180a12280a221220054497ae4e89812c83276a48e3e679013a788b7c0eb02712df15095c02d6cd2c1200180a12280a221220cc332ceb37dea7d3d7c00d1393117638d3ed963575836c6d44a24951e444cf5d120018090a0c080218072002200220022001"),
+        ];
+
+        drop(put_all_blocks(&ipfs, blocks).await.unwrap());
+
+        // files have no entries, like in go-ipfs
+        let filter = super::ls(&ipfs);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/ls?arg=QmRJHYTNvC3hmd9gJQARxLR1QMEincccBV53bBw524yyq6")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(response.status(), 200);
+
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "Objects": [{
+                    "Hash": "QmRJHYTNvC3hmd9gJQARxLR1QMEincccBV53bBw524yyq6",
+                    "Links": [],
+                }]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_blocks_are_prefetched_within_the_window() {
+        use super::ReadAhead;
+        use ipfs::unixfs::ll::file::adder::{Chunker, FileAdder};
+        use ipfs::unixfs::ll::walk::Walker;
+        use std::collections::HashSet;
+        use std::time::Duration;
+
+        let ipfs = Node::new("test_node").await;
+
+        // a file of single byte leaves, none of which is available
+        let content = (0..40u8).collect::<Vec<_>>();
+        let mut adder = FileAdder::builder().with_chunker(Chunker::Size(1)).build();
+        let mut blocks = Vec::new();
+        let mut input = &content[..];
+        while !input.is_empty() {
+            let (ready, used) = adder.push(input);
+            blocks.extend(ready);
+            input = &input[used..];
+        }
+        blocks.extend(adder.finish());
+
+        let (root, root_data) = blocks.pop().unwrap();
+        let leaves = blocks.into_iter().map(|(cid, _)| cid).collect::<Vec<_>>();
+        assert_eq!(leaves.len(), content.len());
+
+        let mut walker = Walker::new(root.clone(), root.to_string());
+        walker.next(&root_data, &mut None).unwrap();
+
+        let mut read_ahead = ReadAhead::new(None);
+
+        // the reader keeps waiting for the first leaf
+        let load = read_ahead.load(&ipfs, &walker);
+        assert!(tokio::time::timeout(Duration::from_millis(500), load)
+            .await
+            .is_err());
+
+        let wanted = ipfs
+            .bitswap_wantlist(None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .collect::<HashSet<_>>();
+
+        let (prefetched, rest) = leaves.split_at(ReadAhead::PREFETCH);
+        assert_eq!(wanted.len(), ReadAhead::PREFETCH);
+        assert!(prefetched.iter().all(|cid| wanted.contains(cid)));
+        assert!(rest.iter().all(|cid| !wanted.contains(cid)));
+    }
+
     fn get_archive_entries(bytes: impl AsRef<[u8]>) -> Vec<Entry> {
         let mut cursor = std::io::Cursor::new(bytes.as_ref());
 
@@ -9,7 +9,7 @@ use ipfs::unixfs::ll::{
     },
     file::adder::FileAdder,
 };
-use ipfs::{Block, Ipfs, IpfsTypes};
+use ipfs::{Block, Ipfs, IpfsTypes, PinKind};
 use mime::Mime;
 use mpart_async::server::{MultipartError, MultipartStream};
 use serde::Serialize;
@@ -67,10 +67,12 @@ enum AddError {
     Parsing(MultipartError),
     Header(MultipartError),
     InvalidFilename(std::str::Utf8Error),
+    InvalidSymlinkTarget(std::str::Utf8Error),
     UnsupportedField(String),
     UnsupportedContentType(String),
     ResponseSerialization(serde_json::Error),
     Persisting(ipfs::Error),
+    Pinning(ipfs::Error),
     TreeGathering(TreeBuildingFailed),
     TreeBuilding(TreeConstructionFailed),
 }
@@ -88,10 +90,12 @@ impl fmt::Display for AddError {
             Parsing(me) => write!(fmt, "invalid request body: {}", me),
             Header(me) => write!(fmt, "invalid multipart header(s): {}", me),
             InvalidFilename(e) => write!(fmt, "invalid multipart filename: {:?}", e),
+            InvalidSymlinkTarget(e) => write!(fmt, "invalid symlink target: {:?}", e),
             UnsupportedField(name) => write!(fmt, "unsupported field name: {:?}", name),
-            UnsupportedContentType(t) => write!(fmt, "unsupported content-type: {:?} (supported: application/{{octet-stream,x-directory}})", t),
+            UnsupportedContentType(t) => write!(fmt, "unsupported content-type: {:?} (supported: application/{{octet-stream,x-directory,symlink}})", t),
             ResponseSerialization(e) => write!(fmt, "progress serialization failed: {}", e),
             Persisting(e) => write!(fmt, "put_block failed: {}", e),
+            Pinning(e) => write!(fmt, "pinning failed: {}", e),
             TreeGathering(g) => write!(fmt, "invalid directory tree: {}", g),
             TreeBuilding(b) => write!(fmt, "constructed invalid directory tree: {}", b),
         }
@@ -116,6 +120,17 @@ where
             tree_opts.wrap_with_directory();
         }
 
+        let chunker = opts.chunker.map(|chunker| chunker.into_inner()).unwrap_or_default();
+        let pin = opts.pin.unwrap_or(true);
+        // the added blocks are not collected before the roots are pinned
+        let _session = if pin {
+            Some(ipfs.gc_session().await)
+        } else {
+            None
+        };
+        // the top level files and directories, or the wrapping directory, are pinned once added
+        let mut roots = Vec::new();
+
         let mut tree = BufferingTreeBuilder::new(tree_opts);
         let mut buffer = BytesMut::new();
 
@@ -153,7 +168,7 @@ where
                         Ok(())
                     }?;
 
-                    let mut adder = FileAdder::default();
+                    let mut adder = FileAdder::builder().with_chunker(chunker).build();
                     // how many bytes we have stored as blocks
                     let mut total_written = 0u64;
                     // how many bytes of input we have read
@@ -218,6 +233,10 @@ where
                     tree.put_link(&filename, root.clone(), total_written)
                         .map_err(AddError::TreeGathering)?;
 
+                    if !opts.wrap_with_directory && !filename.contains('/') {
+                        roots.push(root.clone());
+                    }
+
                     let filename: Cow<'_, str> = if filename.is_empty() {
                         // cid needs to be repeated if no filename was given; in which case there
                         // should not be anything to build as tree either. note that intentionally
@@ -238,6 +257,49 @@ where
 
                     Ok(buffer.split().freeze())
                 },
+                "application/symlink" => {
+                    // symlinks are named like the files, with the target as the content
+                    let _ = if field_name != "file" && !field_name.starts_with("file-") {
+                        Err(AddError::UnsupportedField(field_name.to_string()))
+                    } else {
+                        Ok(())
+                    }?;
+
+                    let mut target = Vec::new();
+                    while let Some(next) = field.try_next().await.map_err(AddError::Parsing)? {
+                        target.extend_from_slice(&next);
+                    }
+
+                    let target = std::str::from_utf8(&target).map_err(AddError::InvalidSymlinkTarget)?;
+
+                    let (cid, block) = tree.put_symlink(&filename, target)
+                        .map_err(AddError::TreeGathering)?;
+                    let size = block.len() as u64;
+
+                    ipfs.put_block(Block { cid: cid.clone(), data: block.into() })
+                        .await
+                        .map_err(AddError::Persisting)?;
+
+                    if !opts.wrap_with_directory && !filename.contains('/') {
+                        roots.push(cid.clone());
+                    }
+
+                    let filename: Cow<'_, str> = if filename.is_empty() {
+                        Cow::Owned(cid.to_string())
+                    } else {
+                        Cow::Owned(filename)
+                    };
+
+                    serde_json::to_writer((&mut buffer).writer(), &Response::Added {
+                        name: filename,
+                        hash: Quoted(&cid),
+                        size: Quoted(size),
+                    }).map_err(AddError::ResponseSerialization)?;
+
+                    buffer.put(&b"\r\n"[..]);
+
+                    Ok(buffer.split().freeze())
+                },
                 "application/x-directory" => {
                     // dirs are of the form "dir-{1,2,3,..}"
                     let _ = if field_name != "dir" && !field_name.starts_with("dir-") {
@@ -268,11 +330,25 @@ where
         let mut iter = tree.build();
 
         while let Some(res) = iter.next_borrowed() {
-            let TreeNode { path, cid, total_size, block } = res.map_err(AddError::TreeBuilding)?;
+            let TreeNode { path, cid, total_size, block, bucket, .. } = res.map_err(AddError::TreeBuilding)?;
 
             // shame we need to allocate once again here..
             ipfs.put_block(Block { cid: cid.to_owned(), data: block.into() }).await.map_err(AddError::Persisting)?;
 
+            if bucket {
+                // the inner buckets of a sharded directory are not reported, like with go-ipfs
+                continue;
+            }
+
+            let top_level = if opts.wrap_with_directory {
+                path.is_empty()
+            } else {
+                !path.contains('/')
+            };
+            if top_level {
+                roots.push(cid.to_owned());
+            }
+
             serde_json::to_writer((&mut buffer).writer(), &Response::Added {
                 name: Cow::Borrowed(path),
                 hash: Quoted(cid),
@@ -283,9 +359,28 @@ where
 
             yield buffer.split().freeze();
         }
+
+        if pin {
+            for root in &roots {
+                // the same content can be added many times, but the pinstores refuse to pin a
+                // root recursively for the second time
+                if pinned_recursively(&ipfs, root).await.map_err(AddError::Pinning)? {
+                    continue;
+                }
+                ipfs.insert_pin(root, true).await.map_err(AddError::Pinning)?;
+            }
+        }
     }
 }
 
+async fn pinned_recursively<T: IpfsTypes>(ipfs: &Ipfs<T>, cid: &Cid) -> Result<bool, ipfs::Error> {
+    if !ipfs.is_pinned(cid).await? {
+        return Ok(false);
+    }
+    let pins = ipfs.query_pins(vec![cid.to_owned()], None).await?;
+    Ok(matches!(pins.first(), Some((_, PinKind::Recursive(_)))))
+}
+
 async fn push_all(
     ipfs: &Ipfs<impl IpfsTypes>,
     adder: &mut FileAdder,
@@ -407,6 +502,104 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn add_with_chunker_and_pin() {
+        let ipfs = tokio_ipfs().await;
+
+        let added = |query: &'static str| {
+            let ipfs = ipfs.clone();
+            async move {
+                let response = warp::test::request()
+                    .path(query)
+                    .header("content-type", "multipart/form-data; boundary=-----xyz")
+                    .body(
+                        &b"-------xyz\r\n\
+                            Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+                            Content-Type: application/octet-stream\r\n\
+                            \r\n\
+                            Plz add me!\n\
+                            \r\n-------xyz--\r\n"[..],
+                    )
+                    .reply(&add(&ipfs))
+                    .await;
+
+                let added: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+                added["Hash"].as_str().unwrap().parse::<cid::Cid>().unwrap()
+            }
+        };
+
+        let single = added("/add").await;
+        assert!(ipfs.is_pinned(&single).await.unwrap());
+
+        // adding the same content again does not fail on the existing recursive pin
+        assert_eq!(added("/add").await, single);
+        assert!(ipfs.is_pinned(&single).await.unwrap());
+
+        // three leaves of four bytes under a root
+        let chunked = added("/add?chunker=size-4&pin=false").await;
+        assert_ne!(chunked, single);
+        assert!(!ipfs.is_pinned(&chunked).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn gc_waits_for_the_add_to_pin() {
+        use super::{add_stream, AddArgs};
+        use bytes::Bytes;
+        use futures::stream::{StreamExt, TryStreamExt};
+        use mpart_async::server::MultipartStream;
+        use std::time::Duration;
+
+        let ipfs = tokio_ipfs().await;
+
+        let (tx, rx) = futures::channel::mpsc::unbounded::<Result<Bytes, std::io::Error>>();
+        let opts = AddArgs {
+            stream_channels: false,
+            progress: false,
+            wrap_with_directory: false,
+            chunker: None,
+            pin: None,
+        };
+        let mut added = Box::pin(add_stream(
+            ipfs.clone(),
+            MultipartStream::new(Bytes::from("-----xyz"), rx),
+            opts,
+        ));
+
+        tx.unbounded_send(Ok(Bytes::from_static(
+            b"-------xyz\r\n\
+                Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+                Content-Type: application/octet-stream\r\n\
+                \r\n\
+                Plz add me!\n",
+        )))
+        .unwrap();
+
+        // the add has started but waits for the rest of the file
+        tokio::time::timeout(Duration::from_millis(50), added.next())
+            .await
+            .unwrap_err();
+
+        tokio::time::timeout(Duration::from_millis(200), ipfs.gc())
+            .await
+            .unwrap_err();
+
+        tx.unbounded_send(Ok(Bytes::from_static(b"\r\n-------xyz--\r\n")))
+            .unwrap();
+        drop(tx);
+
+        let response = added.try_concat().await.unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        let root = response["Hash"]
+            .as_str()
+            .unwrap()
+            .parse::<cid::Cid>()
+            .unwrap();
+
+        ipfs.gc().await.unwrap();
+        assert!(ipfs.is_pinned(&root).await.unwrap());
+        assert!(ipfs.get_blocks_now(&[root]).await.unwrap()[0].is_some());
+    }
+
     async fn tokio_ipfs() -> ipfs::Ipfs<ipfs::TestTypes> {
         let options = ipfs::IpfsOptions::inmemory_with_generated_keys();
         let (ipfs, fut) = ipfs::UninitializedIpfs::new(options).start().await.unwrap();
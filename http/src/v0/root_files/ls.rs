@@ -0,0 +1,206 @@
+use super::{resolve_dagpb, LsArgs};
+use crate::v0::support::{
+    HandledErr, MaybeTimeoutExt, StreamResponse, StringError, StringSerialized,
+};
+use async_stream::try_stream;
+use bytes::Bytes;
+use cid::{Cid, Codec};
+use futures::future::{FutureExt, TryFutureExt};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use ipfs::unixfs::ll::walk::{ContinuedWalk, Walker};
+use ipfs::unixfs::ll::{ListedEntry, ResolveError};
+use ipfs::{Block, Ipfs, IpfsTypes};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use warp::{Rejection, Reply};
+
+/// The unixfs types of the listed entries, as used by go-ipfs.
+const UNKNOWN: i32 = 0;
+pub(crate) const DIRECTORY: i32 = 1;
+const FILE: i32 = 2;
+const SYMLINK: i32 = 4;
+
+/// How many of the linked blocks are loaded at once when the types or sizes are resolved.
+const RESOLVE_CONCURRENCY: usize = 16;
+
+pub(super) async fn ls_inner<T: IpfsTypes>(
+    ipfs: Ipfs<T>,
+    args: LsArgs,
+) -> Result<impl Reply, Rejection> {
+    let path = args.arg.into_inner();
+    let hash = path.to_string();
+
+    let block = resolve_dagpb(&ipfs, path)
+        .maybe_timeout(args.timeout.map(StringSerialized::into_inner))
+        .await
+        .map_err(StringError::from)?
+        .map_err(StringError::from)?;
+
+    let resolve_type = args.resolve_type.unwrap_or(true);
+    let size = args.size.unwrap_or(true);
+
+    let links = list(ipfs, block, resolve_type, size);
+
+    let st = if args.stream {
+        // every entry is written out as soon as it is known, in an object of its own
+        links.map_ok(move |link| render(&hash, vec![link])).boxed()
+    } else {
+        links
+            .try_collect::<Vec<_>>()
+            .map_ok(move |links| render(&hash, links))
+            .into_stream()
+            .boxed()
+    };
+
+    // the errors are rendered as the last line, as they cannot yet be sent as trailers
+    let st = st.map(|res| match res {
+        Ok(bytes) => Ok::<_, HandledErr>(bytes),
+        Err(e) => {
+            let msg =
+                crate::v0::support::MessageResponseBuilder::default().with_message(e.to_string());
+            let mut bytes =
+                serde_json::to_vec(&msg).expect("serializing here should not have failed");
+            bytes.push(b'\n');
+            Ok(bytes.into())
+        }
+    });
+
+    Ok(StreamResponse(st))
+}
+
+fn render(hash: &str, links: Vec<LsLink>) -> Bytes {
+    let output = LsOutput {
+        objects: vec![LsObject { hash, links }],
+    };
+
+    let mut bytes = serde_json::to_vec(&output).expect("serializing here should not have failed");
+    bytes.push(b'\n');
+    bytes.into()
+}
+
+/// Lists the entries of the directory `block`, including the ones in the HAMT buckets of a
+/// sharded directory. The linked blocks are only loaded when their types or sizes are resolved.
+pub(crate) fn list<T: IpfsTypes>(
+    ipfs: Ipfs<T>,
+    block: Block,
+    resolve_type: bool,
+    size: bool,
+) -> impl Stream<Item = Result<LsLink, LsError>> + Send + 'static {
+    try_stream! {
+        let mut buckets = VecDeque::new();
+        let mut data = block.data;
+
+        loop {
+            let listing = match ipfs::unixfs::ll::list(&data) {
+                Ok(listing) => listing,
+                // like go-ipfs, files are listed as having no entries
+                Err(ResolveError::UnexpectedType(ut)) if ut.is_file() => break,
+                Err(e) => Err(LsError::Listing(e))?,
+            };
+
+            buckets.extend(listing.buckets);
+
+            let mut links = stream::iter(listing.entries)
+                .map(|entry| describe(&ipfs, entry, resolve_type, size))
+                .buffered(RESOLVE_CONCURRENCY);
+
+            while let Some(link) = links.next().await {
+                yield link?;
+            }
+
+            match buckets.pop_front() {
+                Some(cid) => data = ipfs.get_block(&cid).await.map_err(LsError::Loading)?.data,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Describes the listed entry, loading the linked block only if needed.
+async fn describe<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+    entry: ListedEntry,
+    resolve_type: bool,
+    size: bool,
+) -> Result<LsLink, LsError> {
+    let ListedEntry {
+        name,
+        cid,
+        total_size,
+    } = entry;
+
+    let (kind, file_size) = if cid.codec() == Codec::Raw {
+        // raw leaves are always files, and the link records the size of the block
+        (FILE, if size { total_size } else { 0 })
+    } else if (resolve_type || size) && cid.codec() == Codec::DagProtobuf {
+        let block = ipfs.get_block(&cid).await.map_err(LsError::Loading)?;
+        let (kind, file_size) = unixfs_type(&cid, &block.data);
+        (kind, if size { file_size } else { 0 })
+    } else {
+        (UNKNOWN, 0)
+    };
+
+    Ok(LsLink {
+        name,
+        hash: cid.to_string(),
+        size: file_size,
+        kind,
+        target: String::new(),
+    })
+}
+
+/// Returns the unixfs type and the file size of the block, which need not be a UnixFS node.
+fn unixfs_type(cid: &Cid, data: &[u8]) -> (i32, u64) {
+    let mut walker = Walker::new(cid.to_owned(), String::new());
+
+    match walker.next(data, &mut None) {
+        Ok(ContinuedWalk::File(.., file_size)) => (FILE, file_size),
+        Ok(ContinuedWalk::RootDirectory(..))
+        | Ok(ContinuedWalk::Directory(..))
+        | Ok(ContinuedWalk::Bucket(..)) => (DIRECTORY, 0),
+        Ok(ContinuedWalk::Symlink(..)) => (SYMLINK, 0),
+        Err(_) => (UNKNOWN, 0),
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LsOutput<'a> {
+    objects: Vec<LsObject<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LsObject<'a> {
+    hash: &'a str,
+    links: Vec<LsLink>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct LsLink {
+    pub(crate) name: String,
+    pub(crate) hash: String,
+    pub(crate) size: u64,
+    #[serde(rename = "Type")]
+    pub(crate) kind: i32,
+    target: String,
+}
+
+#[derive(Debug)]
+pub(crate) enum LsError {
+    Listing(ResolveError),
+    Loading(ipfs::Error),
+}
+
+impl fmt::Display for LsError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LsError::Listing(e) => write!(fmt, "listing failed: {}", e),
+            LsError::Loading(e) => write!(fmt, "loading failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LsError {}
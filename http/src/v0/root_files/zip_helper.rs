@@ -0,0 +1,510 @@
+///! Zip helper is the zip counterpart of the `TarHelper`, writing a store-only zip archive into
+///! `bytes::Bytes` as the directory tree is walked.
+///!
+///! As the file contents are streamed, the crc32 of each file is only known after the contents and
+///! is written in a data descriptor following the contents. The entries are collected in memory for
+///! the central directory which is written last. Zip64 extensions are used for the files larger
+///! than 4 GiB and when the offsets or the number of entries grow too large for the original format.
+use super::GetError;
+use bytes::{buf::BufMut, Bytes, BytesMut};
+use ipfs::unixfs::ll::Metadata;
+use std::path::Path;
+
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR: u32 = 0x0706_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+
+/// Version 2.0 is enough for the stored entries with data descriptors, 4.5 is needed for zip64.
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Upper byte of the "version made by"; unix, so that the external attributes hold the mode.
+const MADE_BY_UNIX: u16 = 3 << 8;
+
+/// The sizes are in the data descriptor.
+const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+/// The file names are utf-8.
+const FLAG_UTF8: u16 = 0x0800;
+
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+const SATURATED: u64 = u32::MAX as u64;
+
+const S_IFREG: u32 = 0o100_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFLNK: u32 = 0o120_000;
+/// MS-DOS directory attribute in the lowest byte of the external attributes.
+const DOS_DIRECTORY: u32 = 0x10;
+
+/// Zip helper is internal to the `get` implementation. Each of the `apply_*` methods returns the
+/// bytes to write for the entry.
+pub(super) struct ZipHelper {
+    bufsize: usize,
+    bytes: BytesMut,
+    /// Total number of bytes returned so far, which is the offset of the next local header.
+    offset: u64,
+    entries: Vec<CentralEntry>,
+    /// The file whose contents are being written.
+    current: Option<CurrentFile>,
+}
+
+/// The details of an entry needed for the central directory.
+struct CentralEntry {
+    name: String,
+    flags: u16,
+    dos_datetime: (u16, u16),
+    crc32: u32,
+    size: u64,
+    external_attributes: u32,
+    local_header_offset: u64,
+}
+
+struct CurrentFile {
+    hasher: crc32fast::Hasher,
+    written: u64,
+    zip64: bool,
+}
+
+impl ZipHelper {
+    pub(super) fn with_capacity(n: usize) -> Self {
+        ZipHelper {
+            bufsize: n,
+            bytes: BytesMut::with_capacity(n),
+            offset: 0,
+            entries: Vec::new(),
+            current: None,
+        }
+    }
+
+    /// Writes the local header of a file which is followed by `total_size` bytes of contents
+    /// through [`ZipHelper::buffer_file_contents`] and finally the [`ZipHelper::finish_file`].
+    pub(super) fn apply_file(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+        total_size: u64,
+    ) -> Result<Bytes, GetError> {
+        assert!(self.current.is_none(), "previous file was not finished");
+
+        let name = utf8_path(path)?.to_owned();
+        let zip64 = total_size >= SATURATED;
+        let entry = CentralEntry {
+            name,
+            flags: FLAG_UTF8 | FLAG_DATA_DESCRIPTOR,
+            dos_datetime: dos_datetime(metadata),
+            crc32: 0,
+            size: total_size,
+            external_attributes: (S_IFREG | mode_or(metadata, 0o644)) << 16,
+            local_header_offset: self.offset,
+        };
+
+        // the crc32 and the sizes are in the data descriptor, except for the zip64 extra field
+        // which has to be present for the data descriptor to use the 64-bit sizes.
+        let (sizes, extra) = if zip64 {
+            (SATURATED as u32, Some((0, 0)))
+        } else {
+            (0, None)
+        };
+
+        self.put_local_header(&entry, 0, sizes, extra);
+        self.current = Some(CurrentFile {
+            hasher: crc32fast::Hasher::new(),
+            written: 0,
+            zip64,
+        });
+        self.entries.push(entry);
+
+        Ok(self.split())
+    }
+
+    pub(super) fn buffer_file_contents(&mut self, contents: &[u8]) -> Bytes {
+        assert!(!contents.is_empty());
+        let taken = self.bufsize.min(contents.len());
+        let contents = &contents[..taken];
+
+        let current = self
+            .current
+            .as_mut()
+            .expect("file contents without apply_file");
+        current.hasher.update(contents);
+        current.written += contents.len() as u64;
+
+        self.bytes.put_slice(contents);
+        self.split()
+    }
+
+    /// Writes the data descriptor of the file once all of the contents have been written. Fails
+    /// if the contents were not of the size given to [`ZipHelper::apply_file`], as the archive
+    /// cannot be completed then.
+    pub(super) fn finish_file(&mut self) -> Result<Bytes, GetError> {
+        let current = self.current.take().expect("finish_file without apply_file");
+        let crc32 = current.hasher.finalize();
+
+        let entry = self
+            .entries
+            .last_mut()
+            .expect("apply_file pushed the entry");
+        if entry.size != current.written {
+            return Err(GetError::SizeMismatch {
+                expected: entry.size,
+                actual: current.written,
+            });
+        }
+        entry.crc32 = crc32;
+
+        self.bytes.put_u32_le(DATA_DESCRIPTOR);
+        self.bytes.put_u32_le(crc32);
+        if current.zip64 {
+            self.bytes.put_u64_le(current.written);
+            self.bytes.put_u64_le(current.written);
+        } else {
+            self.bytes.put_u32_le(current.written as u32);
+            self.bytes.put_u32_le(current.written as u32);
+        }
+
+        Ok(self.split())
+    }
+
+    pub(super) fn apply_directory(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+    ) -> Result<Bytes, GetError> {
+        let mut name = utf8_path(path)?.to_owned();
+        name.push('/');
+
+        let entry = CentralEntry {
+            name,
+            flags: FLAG_UTF8,
+            dos_datetime: dos_datetime(metadata),
+            crc32: 0,
+            size: 0,
+            external_attributes: ((S_IFDIR | mode_or(metadata, 0o755)) << 16) | DOS_DIRECTORY,
+            local_header_offset: self.offset,
+        };
+
+        self.put_local_header(&entry, 0, 0, None);
+        self.entries.push(entry);
+
+        Ok(self.split())
+    }
+
+    /// Symlinks are stored as files with the target as the contents and the symlink file type in
+    /// the external attributes, like Info-ZIP does.
+    pub(super) fn apply_symlink(
+        &mut self,
+        path: &Path,
+        target: &[u8],
+        metadata: &Metadata,
+    ) -> Result<Bytes, GetError> {
+        let name = utf8_path(path)?.to_owned();
+        let crc32 = crc32fast::hash(target);
+
+        let entry = CentralEntry {
+            name,
+            flags: FLAG_UTF8,
+            dos_datetime: dos_datetime(metadata),
+            crc32,
+            size: target.len() as u64,
+            external_attributes: (S_IFLNK | 0o777) << 16,
+            local_header_offset: self.offset,
+        };
+
+        self.put_local_header(&entry, crc32, target.len() as u32, None);
+        self.bytes.put_slice(target);
+        self.entries.push(entry);
+
+        Ok(self.split())
+    }
+
+    /// Writes the central directory and the end of central directory records.
+    pub(super) fn finish(mut self) -> Bytes {
+        assert!(self.current.is_none(), "last file was not finished");
+
+        let central_directory_offset = self.offset;
+
+        for entry in &self.entries {
+            let zip64_size = entry.size >= SATURATED;
+            let zip64_offset = entry.local_header_offset >= SATURATED;
+
+            let mut extra = Vec::new();
+            if zip64_size {
+                // uncompressed and compressed size, in this order
+                extra.push(entry.size);
+                extra.push(entry.size);
+            }
+            if zip64_offset {
+                extra.push(entry.local_header_offset);
+            }
+
+            let version = if extra.is_empty() {
+                VERSION_DEFAULT
+            } else {
+                VERSION_ZIP64
+            };
+
+            self.bytes.put_u32_le(CENTRAL_DIRECTORY_HEADER);
+            self.bytes.put_u16_le(MADE_BY_UNIX | version);
+            self.bytes.put_u16_le(version);
+            self.bytes.put_u16_le(entry.flags);
+            // stored
+            self.bytes.put_u16_le(0);
+            self.bytes.put_u16_le(entry.dos_datetime.1);
+            self.bytes.put_u16_le(entry.dos_datetime.0);
+            self.bytes.put_u32_le(entry.crc32);
+            self.bytes.put_u32_le(entry.size.min(SATURATED) as u32);
+            self.bytes.put_u32_le(entry.size.min(SATURATED) as u32);
+            self.bytes.put_u16_le(entry.name.len() as u16);
+            let extra_len = if extra.is_empty() {
+                0
+            } else {
+                4 + 8 * extra.len() as u16
+            };
+            self.bytes.put_u16_le(extra_len);
+            // comment length, disk number start, internal attributes
+            self.bytes.put_u16_le(0);
+            self.bytes.put_u16_le(0);
+            self.bytes.put_u16_le(0);
+            self.bytes.put_u32_le(entry.external_attributes);
+            self.bytes
+                .put_u32_le(entry.local_header_offset.min(SATURATED) as u32);
+            self.bytes.put_slice(entry.name.as_bytes());
+
+            if !extra.is_empty() {
+                self.bytes.put_u16_le(ZIP64_EXTRA_FIELD);
+                self.bytes.put_u16_le(8 * extra.len() as u16);
+                for value in extra {
+                    self.bytes.put_u64_le(value);
+                }
+            }
+        }
+
+        let central_directory_size =
+            self.offset + self.bytes.len() as u64 - central_directory_offset;
+        let count = self.entries.len() as u64;
+
+        let needs_zip64 = count >= 0xffff
+            || central_directory_offset >= SATURATED
+            || central_directory_size >= SATURATED;
+
+        if needs_zip64 {
+            let zip64_end_offset = self.offset + self.bytes.len() as u64;
+
+            self.bytes.put_u32_le(ZIP64_END_OF_CENTRAL_DIRECTORY);
+            // size of the remaining record
+            self.bytes.put_u64_le(44);
+            self.bytes.put_u16_le(MADE_BY_UNIX | VERSION_ZIP64);
+            self.bytes.put_u16_le(VERSION_ZIP64);
+            // number of this disk, disk with the central directory
+            self.bytes.put_u32_le(0);
+            self.bytes.put_u32_le(0);
+            self.bytes.put_u64_le(count);
+            self.bytes.put_u64_le(count);
+            self.bytes.put_u64_le(central_directory_size);
+            self.bytes.put_u64_le(central_directory_offset);
+
+            self.bytes
+                .put_u32_le(ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR);
+            self.bytes.put_u32_le(0);
+            self.bytes.put_u64_le(zip64_end_offset);
+            // total number of disks
+            self.bytes.put_u32_le(1);
+        }
+
+        self.bytes.put_u32_le(END_OF_CENTRAL_DIRECTORY);
+        self.bytes.put_u16_le(0);
+        self.bytes.put_u16_le(0);
+        self.bytes.put_u16_le(count.min(0xffff) as u16);
+        self.bytes.put_u16_le(count.min(0xffff) as u16);
+        self.bytes
+            .put_u32_le(central_directory_size.min(SATURATED) as u32);
+        self.bytes
+            .put_u32_le(central_directory_offset.min(SATURATED) as u32);
+        // comment length
+        self.bytes.put_u16_le(0);
+
+        self.split()
+    }
+
+    fn put_local_header(
+        &mut self,
+        entry: &CentralEntry,
+        crc32: u32,
+        sizes: u32,
+        zip64_sizes: Option<(u64, u64)>,
+    ) {
+        let version = if zip64_sizes.is_some() {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        };
+
+        self.bytes.put_u32_le(LOCAL_FILE_HEADER);
+        self.bytes.put_u16_le(version);
+        self.bytes.put_u16_le(entry.flags);
+        // stored
+        self.bytes.put_u16_le(0);
+        self.bytes.put_u16_le(entry.dos_datetime.1);
+        self.bytes.put_u16_le(entry.dos_datetime.0);
+        self.bytes.put_u32_le(crc32);
+        self.bytes.put_u32_le(sizes);
+        self.bytes.put_u32_le(sizes);
+        self.bytes.put_u16_le(entry.name.len() as u16);
+        self.bytes
+            .put_u16_le(if zip64_sizes.is_some() { 20 } else { 0 });
+        self.bytes.put_slice(entry.name.as_bytes());
+
+        if let Some((uncompressed, compressed)) = zip64_sizes {
+            self.bytes.put_u16_le(ZIP64_EXTRA_FIELD);
+            self.bytes.put_u16_le(16);
+            self.bytes.put_u64_le(uncompressed);
+            self.bytes.put_u64_le(compressed);
+        }
+    }
+
+    fn split(&mut self) -> Bytes {
+        self.offset += self.bytes.len() as u64;
+        self.bytes.split().freeze()
+    }
+}
+
+fn utf8_path(path: &Path) -> Result<&str, GetError> {
+    // the paths come from the walker which has already parsed them as utf-8
+    path.to_str()
+        .ok_or_else(|| GetError::InvalidFileName(path.to_string_lossy().into_owned().into_bytes()))
+}
+
+fn mode_or(metadata: &Metadata, default_mode: u32) -> u32 {
+    metadata
+        .mode()
+        .map(|mode| mode & 0o7777)
+        .unwrap_or(default_mode)
+}
+
+/// MS-DOS `(date, time)` of the modification time, defaulting to the earliest representable
+/// 1980-01-01 00:00:00 like the `TarHelper` defaults to the unix epoch.
+fn dos_datetime(metadata: &Metadata) -> (u16, u16) {
+    let seconds = metadata.mtime().map(|(seconds, _)| seconds).unwrap_or(0);
+    unix_to_dos(seconds)
+}
+
+fn unix_to_dos(seconds: i64) -> (u16, u16) {
+    let days = seconds.div_euclid(86400);
+    let secs_of_day = seconds.rem_euclid(86400);
+
+    let (year, month, day) = civil_from_days(days);
+
+    if year < 1980 {
+        return ((1 << 5) | 1, 0);
+    } else if year > 2107 {
+        return ((127 << 9) | (12 << 5) | 31, (23 << 11) | (59 << 5) | 29);
+    }
+
+    let date = (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = (((secs_of_day / 3600) as u16) << 11)
+        | (((secs_of_day / 60 % 60) as u16) << 5)
+        | ((secs_of_day % 60 / 2) as u16);
+
+    (date, time)
+}
+
+/// The proleptic gregorian `(year, month, day)` for the days since the unix epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unix_to_dos, GetError, ZipHelper, END_OF_CENTRAL_DIRECTORY};
+    use ipfs::unixfs::ll::Metadata;
+    use std::convert::TryInto;
+    use std::path::Path;
+
+    #[test]
+    fn dos_datetime_conversion() {
+        assert_eq!(unix_to_dos(0), (33, 0), "clamped to 1980-01-01");
+        // 2020-02-29 12:34:56
+        let date = (40 << 9) | (2 << 5) | 29;
+        let time = (12 << 11) | (34 << 5) | 28;
+        assert_eq!(unix_to_dos(1_582_979_696), (date, time));
+    }
+
+    #[test]
+    fn archive_layout() {
+        let metadata = Metadata::default();
+        let mut helper = ZipHelper::with_capacity(4);
+        let mut out = Vec::new();
+
+        out.extend_from_slice(&helper.apply_directory(Path::new("dir"), &metadata).unwrap());
+        out.extend_from_slice(
+            &helper
+                .apply_file(Path::new("dir/a.txt"), &metadata, 6)
+                .unwrap(),
+        );
+
+        let mut contents = &b"foobar"[..];
+        while !contents.is_empty() {
+            let written = helper.buffer_file_contents(contents);
+            contents = &contents[written.len()..];
+            out.extend_from_slice(&written);
+        }
+
+        out.extend_from_slice(&helper.finish_file().unwrap());
+        out.extend_from_slice(
+            &helper
+                .apply_symlink(Path::new("dir/link"), b"a.txt", &metadata)
+                .unwrap(),
+        );
+        out.extend_from_slice(&helper.finish());
+
+        let eocd = &out[out.len() - 22..];
+        assert_eq!(
+            u32::from_le_bytes(eocd[..4].try_into().unwrap()),
+            END_OF_CENTRAL_DIRECTORY
+        );
+        let entries = u16::from_le_bytes(eocd[10..12].try_into().unwrap());
+        let cd_size = u32::from_le_bytes(eocd[12..16].try_into().unwrap()) as usize;
+        let cd_offset = u32::from_le_bytes(eocd[16..20].try_into().unwrap()) as usize;
+
+        assert_eq!(entries, 3);
+        assert_eq!(cd_offset + cd_size, out.len() - 22);
+
+        // the crc32 of the file is in the central directory
+        let first_file = &out[cd_offset..];
+        let second = &first_file[46 + "dir/".len()..];
+        assert_eq!(
+            u32::from_le_bytes(second[16..20].try_into().unwrap()),
+            crc32fast::hash(b"foobar")
+        );
+    }
+
+    #[test]
+    fn size_mismatch_is_an_error() {
+        let metadata = Metadata::default();
+        let mut helper = ZipHelper::with_capacity(16);
+
+        helper
+            .apply_file(Path::new("a.txt"), &metadata, 10)
+            .unwrap();
+        helper.buffer_file_contents(b"foobar");
+
+        match helper.finish_file() {
+            Err(GetError::SizeMismatch {
+                expected: 10,
+                actual: 6,
+            }) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+}
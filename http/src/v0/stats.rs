@@ -0,0 +1,57 @@
+use crate::v0::support::with_ipfs;
+use ipfs::{Ipfs, IpfsTypes};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::time::UNIX_EPOCH;
+use warp::{query, reply, Filter, Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+pub struct TopQuery {
+    count: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct TopEntry {
+    cid: Value,
+    score: f64,
+    gateway_requests: u64,
+    bitswap_requests: u64,
+    /// Seconds since the unix epoch.
+    last_requested: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct TopResponse {
+    content: Vec<TopEntry>,
+}
+
+async fn top_query<T: IpfsTypes>(ipfs: Ipfs<T>, query: TopQuery) -> Result<impl Reply, Infallible> {
+    let content = ipfs
+        .popularity()
+        .top(query.count.unwrap_or(10))
+        .into_iter()
+        .map(|popular| TopEntry {
+            cid: json!({ "/": popular.cid.to_string() }),
+            score: popular.score,
+            gateway_requests: popular.gateway_requests,
+            bitswap_requests: popular.bitswap_requests,
+            last_requested: popular
+                .last_requested
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        })
+        .collect();
+
+    Ok(reply::json(&TopResponse { content }))
+}
+
+/// Lists the most requested content, see [`ipfs::popularity`].
+pub fn top<T: IpfsTypes>(
+    ipfs: &Ipfs<T>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    with_ipfs(ipfs).and(query::<TopQuery>()).and_then(top_query)
+}
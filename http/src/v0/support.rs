@@ -70,13 +70,19 @@ impl MessageResponseBuilder {
     }
 }
 
-/// Clones the handle to the filters
+/// Clones the handle to the filters. The operations through the handle are instrumented with a
+/// span containing the request ID, when the request has one, see [`crate::request_id`].
 pub fn with_ipfs<T: IpfsTypes>(
     ipfs: &Ipfs<T>,
 ) -> impl warp::Filter<Extract = (Ipfs<T>,), Error = std::convert::Infallible> + Clone {
     use warp::Filter;
     let ipfs = ipfs.clone();
-    warp::any().map(move || ipfs.clone())
+    warp::header::headers_cloned().map(move |headers| {
+        match crate::request_id::from_headers(&headers) {
+            Some(id) => ipfs.with_span(debug_span!(parent: ipfs.span(), "request", id)),
+            None => ipfs.clone(),
+        }
+    })
 }
 
 /// Special rejection from `pubsub/pub`
@@ -0,0 +1,246 @@
+//! Budgets bounding the blocks in flight between the swarm, bitswap and the repo.
+//!
+//! The blocks received from the peers are kept in memory until they have been written to the
+//! repo, and each block wanted by a peer is read from the repo in a task of its own. Without a
+//! bound a fast peer could have the node receive or read blocks much faster than the disks keep
+//! up with. While either of the budgets is exhausted the swarm is not polled, so no more data is
+//! read from the connections until the writes and reads in progress have completed.
+
+use futures::task::AtomicWaker;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Configuration of the [`MemoryBudgets`].
+#[derive(Debug, Clone)]
+pub struct MemoryBudgetConfig {
+    /// The most bytes of the blocks received from the peers waiting to be written to the repo.
+    /// `None` for no limit.
+    pub incoming_bytes: Option<u64>,
+    /// The most blocks wanted by the peers being read from the repo at once. `None` for no limit.
+    pub outgoing_reads: Option<u64>,
+}
+
+impl Default for MemoryBudgetConfig {
+    fn default() -> Self {
+        MemoryBudgetConfig {
+            incoming_bytes: Some(64 * 1024 * 1024),
+            outgoing_reads: Some(256),
+        }
+    }
+}
+
+/// The usage of a budget, returned by [`MemoryBudgets::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BudgetStats {
+    /// The configured limit, in bytes for the incoming blocks and in reads for the outgoing ones.
+    pub limit: Option<u64>,
+    /// The currently used amount of the budget.
+    pub used: u64,
+    /// The number of blocks currently in flight, the depth of the queue.
+    pub queued: usize,
+    /// The most of the budget used at once.
+    pub peak: u64,
+    /// How many times the swarm has been paused because of the budget being exhausted.
+    pub stalls: u64,
+}
+
+/// The usage of the [`MemoryBudgets`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryBudgetStats {
+    /// The blocks received from the peers, until they have been written to the repo.
+    pub incoming: BudgetStats,
+    /// The blocks wanted by the peers, until they have been read from the repo.
+    pub outgoing: BudgetStats,
+}
+
+/// The budgets of the blocks in flight, see the [module documentation](self).
+#[derive(Debug)]
+pub struct MemoryBudgets {
+    incoming: Arc<Budget>,
+    outgoing: Arc<Budget>,
+}
+
+impl MemoryBudgets {
+    pub fn new(config: MemoryBudgetConfig) -> Self {
+        MemoryBudgets {
+            incoming: Arc::new(Budget::new(config.incoming_bytes)),
+            outgoing: Arc::new(Budget::new(config.outgoing_reads)),
+        }
+    }
+
+    /// Returns the current usage of the budgets.
+    pub fn stats(&self) -> MemoryBudgetStats {
+        MemoryBudgetStats {
+            incoming: self.incoming.stats(),
+            outgoing: self.outgoing.stats(),
+        }
+    }
+
+    /// Charges a received block of `bytes` to the incoming budget until the returned permit is
+    /// dropped. The block has already been received, so this never waits; the budget may be
+    /// overdrawn, which pauses the swarm.
+    pub(crate) fn charge_incoming(&self, bytes: u64) -> BudgetPermit {
+        BudgetPermit::new(&self.incoming, bytes)
+    }
+
+    /// Charges a read of a block wanted by a peer to the outgoing budget until the returned
+    /// permit is dropped.
+    pub(crate) fn charge_outgoing(&self) -> BudgetPermit {
+        BudgetPermit::new(&self.outgoing, 1)
+    }
+
+    /// Returns `Poll::Ready` when neither of the budgets is exhausted, otherwise wakes up the
+    /// task once the blocks in flight have been released.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let incoming = self.incoming.poll_available(cx);
+        let outgoing = self.outgoing.poll_available(cx);
+
+        if incoming.is_ready() && outgoing.is_ready() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Budget {
+    limit: Option<u64>,
+    used: AtomicU64,
+    queued: AtomicUsize,
+    peak: AtomicU64,
+    stalls: AtomicU64,
+    /// The swarm task waiting for the budget to become available.
+    waker: AtomicWaker,
+}
+
+impl Budget {
+    fn new(limit: Option<u64>) -> Self {
+        Budget {
+            limit,
+            used: AtomicU64::new(0),
+            queued: AtomicUsize::new(0),
+            peak: AtomicU64::new(0),
+            stalls: AtomicU64::new(0),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.limit
+            .map(|limit| self.used.load(Ordering::Acquire) >= limit)
+            .unwrap_or(false)
+    }
+
+    fn poll_available(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_exhausted() {
+            return Poll::Ready(());
+        }
+
+        self.waker.register(cx.waker());
+
+        // the permits could have been released before registering
+        if !self.is_exhausted() {
+            return Poll::Ready(());
+        }
+
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+        Poll::Pending
+    }
+
+    fn stats(&self) -> BudgetStats {
+        BudgetStats {
+            limit: self.limit,
+            used: self.used.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A block in flight charged to one of the [`MemoryBudgets`], released on drop.
+#[derive(Debug)]
+pub(crate) struct BudgetPermit {
+    budget: Arc<Budget>,
+    amount: u64,
+}
+
+impl BudgetPermit {
+    fn new(budget: &Arc<Budget>, amount: u64) -> Self {
+        let used = budget.used.fetch_add(amount, Ordering::AcqRel) + amount;
+        budget.queued.fetch_add(1, Ordering::Relaxed);
+        budget.peak.fetch_max(used, Ordering::Relaxed);
+
+        BudgetPermit {
+            budget: Arc::clone(budget),
+            amount,
+        }
+    }
+}
+
+impl Drop for BudgetPermit {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.amount, Ordering::AcqRel);
+        self.budget.queued.fetch_sub(1, Ordering::Relaxed);
+        self.budget.waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryBudgetConfig, MemoryBudgets};
+    use futures::task::{noop_waker_ref, Context};
+    use std::task::Poll;
+
+    #[test]
+    fn exhausted_budget_pauses_until_released() {
+        let budgets = MemoryBudgets::new(MemoryBudgetConfig {
+            incoming_bytes: Some(100),
+            outgoing_reads: Some(1),
+        });
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let first = budgets.charge_incoming(60);
+        assert_eq!(budgets.poll_ready(&mut cx), Poll::Ready(()));
+
+        // the received blocks are always accepted, even over the limit
+        let second = budgets.charge_incoming(60);
+        assert_eq!(budgets.poll_ready(&mut cx), Poll::Pending);
+
+        let stats = budgets.stats().incoming;
+        assert_eq!((stats.used, stats.queued, stats.peak), (120, 2, 120));
+        assert_eq!(stats.stalls, 1);
+
+        drop(first);
+        assert_eq!(budgets.poll_ready(&mut cx), Poll::Ready(()));
+
+        let read = budgets.charge_outgoing();
+        assert_eq!(budgets.poll_ready(&mut cx), Poll::Pending);
+        drop(read);
+        drop(second);
+
+        assert_eq!(budgets.poll_ready(&mut cx), Poll::Ready(()));
+
+        let stats = budgets.stats();
+        assert_eq!((stats.incoming.used, stats.incoming.queued), (0, 0));
+        assert_eq!((stats.outgoing.peak, stats.outgoing.stalls), (1, 1));
+    }
+
+    #[test]
+    fn unlimited_budget_is_never_exhausted() {
+        let budgets = MemoryBudgets::new(MemoryBudgetConfig {
+            incoming_bytes: None,
+            outgoing_reads: None,
+        });
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let _permits = (0..10)
+            .map(|_| budgets.charge_incoming(u64::from(u32::MAX)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(budgets.poll_ready(&mut cx), Poll::Ready(()));
+        assert_eq!(budgets.stats().incoming.queued, 10);
+    }
+}
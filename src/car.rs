@@ -0,0 +1,1031 @@
+//! Export of DAGs as [CARv1] (content addressable archive) streams, the format of
+//! `ipfs dag export` and the Filecoin tooling, and import of CARv1 and [CARv2] files.
+//!
+//! A CARv1 stream starts with a header listing the roots, encoded as dag-cbor, followed by the
+//! blocks of the DAG. Both the header and the blocks are prefixed by their length as an unsigned
+//! varint, and the blocks are written as the bytes of their Cid followed by their data. A CARv2
+//! file wraps a CARv1 payload with a fixed size header and an index of the blocks in the payload.
+//!
+//! [CARv1]: https://ipld.io/specs/transport/car/carv1/
+//! [CARv2]: https://ipld.io/specs/transport/car/carv2/
+
+use crate::ipld::dag_cbor::DagCborCodec;
+use crate::ipld::{decode_ipld, encode_ipld, validate, BlockError, Ipld, MAX_BLOCK_SIZE};
+use crate::refs::ipld_links;
+use crate::selectors::{self, SelectorTraversal, TraversalError};
+use crate::{Block, Ipfs, IpfsTypes};
+use async_stream::stream;
+use cid::{Cid, Codec};
+use futures::stream::{Stream, StreamExt};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+
+/// The length of the CARv2 header following the pragma.
+const V2_HEADER_LEN: usize = 40;
+
+/// The multicodec of the `IndexSorted` CARv2 index.
+const INDEX_SORTED: u64 = 0x0400;
+
+/// The largest accepted CARv1 header.
+const MAX_HEADER_SIZE: usize = 1024 * 1024;
+
+/// The largest accepted block section, the largest block with room for the Cid.
+const MAX_SECTION_SIZE: usize = MAX_BLOCK_SIZE + 1024;
+
+/// The blocks of the DAG included in the export, corresponding to the recursive `ExploreAll`
+/// selectors of `ipfs dag export`, or any other IPLD selector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    /// All of the blocks reachable from the root.
+    All,
+    /// The blocks at most the given number of links away from the root. Zero selects only the
+    /// root block.
+    Depth(u64),
+    /// The blocks loaded by the [`SelectorTraversal`] with the selector.
+    Ipld(selectors::Selector),
+}
+
+impl Default for Selector {
+    fn default() -> Self {
+        Selector::All
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CarError {
+    #[error("failed to encode the header")]
+    Header(#[source] BlockError),
+    #[error("failed to decode {0}")]
+    Block(Cid, #[source] BlockError),
+    #[error("loading failed")]
+    Loading(#[from] crate::Error),
+    #[error("block not found locally: {0}")]
+    BlockNotFound(Cid),
+    #[error("failed to read the car")]
+    Io(#[from] std::io::Error),
+    #[error("invalid car: {0}")]
+    InvalidCar(&'static str),
+    #[error("unsupported car version {0}")]
+    UnsupportedVersion(i128),
+    #[error("block {0} failed verification")]
+    Verification(Cid, #[source] BlockError),
+    #[error("failed to pin the root {0}")]
+    Pinning(Cid, #[source] crate::Error),
+}
+
+impl From<TraversalError> for CarError {
+    fn from(e: TraversalError) -> Self {
+        match e {
+            TraversalError::Block(cid, e) => CarError::Block(cid, e),
+            TraversalError::Loading(e) => CarError::Loading(e),
+            TraversalError::BlockNotFound(cid) => CarError::BlockNotFound(cid),
+        }
+    }
+}
+
+/// Options of a CARv1 export, see [`CarExport::export`].
+#[derive(Debug, Clone, Default)]
+pub struct CarExport {
+    selector: Selector,
+    existing_blocks: bool,
+    path_blocks: Vec<Block>,
+}
+
+impl CarExport {
+    /// Overrides the default of exporting all of the blocks reachable from the root.
+    pub fn with_selector(mut self, selector: Selector) -> CarExport {
+        self.selector = selector;
+        self
+    }
+
+    /// Overrides the default of fetching the missing blocks from the network. The export ends
+    /// with [`CarError::BlockNotFound`] on the first selected block which is not found locally.
+    pub fn with_existing_blocks(mut self) -> CarExport {
+        self.existing_blocks = true;
+        self
+    }
+
+    /// Writes the `blocks` along a path to the exported root ahead of the exported DAG, and
+    /// lists the first of them as the root of the CAR instead of the exported root. This makes
+    /// the export of a path verifiable from the start of the path, like the trustless gateways
+    /// respond.
+    pub fn with_path_blocks(mut self, blocks: Vec<Block>) -> CarExport {
+        self.path_blocks = blocks;
+        self
+    }
+
+    /// Returns the CARv1 stream of the DAG rooted at `root` in chunks: the header first, followed
+    /// by one chunk per block. The blocks are visited depth-first in the order of their links,
+    /// like go-ipfs does, and each block is included once even if it is linked many times.
+    ///
+    /// Depending on how this function is called, the lifetime will be tied to the lifetime of
+    /// given `&Ipfs` or `'static` when given ownership of `Ipfs`.
+    pub fn export<'a, Types, MaybeOwned>(
+        self,
+        ipfs: MaybeOwned,
+        root: Cid,
+    ) -> impl Stream<Item = Result<Vec<u8>, CarError>> + Send + 'a
+    where
+        Types: IpfsTypes,
+        MaybeOwned: Borrow<Ipfs<Types>> + Send + 'a,
+    {
+        let CarExport {
+            selector,
+            existing_blocks,
+            path_blocks,
+        } = self;
+
+        let max_depth = match selector {
+            Selector::All => None,
+            Selector::Depth(depth) => Some(depth),
+            Selector::Ipld(selector) => {
+                return export_selected(selector, existing_blocks, ipfs, root, path_blocks)
+                    .left_stream()
+            }
+        };
+
+        let exported = stream! {
+            match header(car_root(&root, &path_blocks)) {
+                Ok(header) => yield Ok(header),
+                Err(e) => {
+                    yield Err(CarError::Header(e));
+                    return;
+                }
+            }
+
+            for block in &path_blocks {
+                yield Ok(section(block));
+            }
+
+            let mut work = vec![(0, root)];
+            let mut visited = path_blocks
+                .into_iter()
+                .map(|block| block.cid)
+                .collect::<HashSet<_>>();
+
+            while let Some((depth, cid)) = work.pop() {
+                if !visited.insert(cid.clone()) {
+                    continue;
+                }
+
+                // if this is not bound to a local variable it'll introduce a Sync requirement on
+                // `MaybeOwned` which we don't necessarily need.
+                let borrowed = ipfs.borrow();
+
+                let block = if existing_blocks {
+                    match borrowed.repo.get_block_now(&cid).await {
+                        Ok(Some(block)) => block,
+                        Ok(None) => {
+                            yield Err(CarError::BlockNotFound(cid));
+                            return;
+                        }
+                        Err(e) => {
+                            yield Err(e.into());
+                            return;
+                        }
+                    }
+                } else {
+                    match borrowed.get_block(&cid).await {
+                        Ok(block) => block,
+                        Err(e) => {
+                            yield Err(e.into());
+                            return;
+                        }
+                    }
+                };
+
+                if max_depth.map(|max| depth < max).unwrap_or(true) {
+                    let ipld = match decode_ipld(&cid, &block.data) {
+                        Ok(ipld) => ipld,
+                        Err(e) => {
+                            yield Err(CarError::Block(cid, e));
+                            return;
+                        }
+                    };
+
+                    let links = ipld_links(&cid, ipld).collect::<Vec<_>>();
+
+                    // reversed to pop the first link first
+                    for (_, next) in links.into_iter().rev() {
+                        if !visited.contains(&next) {
+                            work.push((depth + 1, next));
+                        }
+                    }
+                }
+
+                trace!(cid = %cid, depth, "exported");
+
+                yield Ok(section(&block));
+            }
+        };
+
+        exported.right_stream()
+    }
+}
+
+/// Returns the CARv1 stream of the blocks loaded by the [`SelectorTraversal`], each included once
+/// in the order they were first loaded.
+fn export_selected<'a, Types, MaybeOwned>(
+    selector: selectors::Selector,
+    existing_blocks: bool,
+    ipfs: MaybeOwned,
+    root: Cid,
+    path_blocks: Vec<Block>,
+) -> impl Stream<Item = Result<Vec<u8>, CarError>> + Send + 'a
+where
+    Types: IpfsTypes,
+    MaybeOwned: Borrow<Ipfs<Types>> + Send + 'a,
+{
+    let mut traversal = SelectorTraversal::new(selector);
+    if existing_blocks {
+        traversal = traversal.with_existing_blocks();
+    }
+
+    stream! {
+        match header(car_root(&root, &path_blocks)) {
+            Ok(header) => yield Ok(header),
+            Err(e) => {
+                yield Err(CarError::Header(e));
+                return;
+            }
+        }
+
+        for block in &path_blocks {
+            yield Ok(section(block));
+        }
+
+        let mut selected = Box::pin(traversal.traverse(ipfs, root));
+        let mut exported = path_blocks
+            .into_iter()
+            .map(|block| block.cid)
+            .collect::<HashSet<_>>();
+
+        while let Some(next) = selected.next().await {
+            match next {
+                Ok(next) => {
+                    if exported.insert(next.block.cid.clone()) {
+                        yield Ok(section(&next.block));
+                    }
+                }
+                Err(e) => {
+                    yield Err(e.into());
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the root listed in the header of the export of the `root`, see
+/// [`CarExport::with_path_blocks`].
+fn car_root<'a>(root: &'a Cid, path_blocks: &'a [Block]) -> &'a Cid {
+    path_blocks.first().map(|block| &block.cid).unwrap_or(root)
+}
+
+/// Encodes the header of a CARv1 stream with the single `root`.
+fn header(root: &Cid) -> Result<Vec<u8>, BlockError> {
+    let mut map = BTreeMap::new();
+    map.insert(
+        "roots".to_owned(),
+        Ipld::List(vec![Ipld::Link(root.clone())]),
+    );
+    map.insert("version".to_owned(), Ipld::Integer(1));
+
+    let header = encode_ipld(&Ipld::Map(map), Codec::DagCBOR)?;
+
+    let mut out = Vec::with_capacity(header.len() + 2);
+    write_varint(&mut out, header.len() as u64);
+    out.extend_from_slice(&header);
+    Ok(out)
+}
+
+/// Encodes the block as a length prefixed section of a CARv1 stream.
+fn section(block: &Block) -> Vec<u8> {
+    let cid = block.cid.to_bytes();
+
+    let mut out = Vec::with_capacity(cid.len() + block.data.len() + 4);
+    write_varint(&mut out, (cid.len() + block.data.len()) as u64);
+    out.extend_from_slice(&cid);
+    out.extend_from_slice(&block.data);
+    out
+}
+
+/// Encodes the CARv1 file of the single `block`, which is also its root. Used for the small
+/// standalone documents which are not stored in the blockstore.
+pub(crate) fn single_block(block: &Block) -> Result<Vec<u8>, CarError> {
+    let mut out = header(&block.cid).map_err(CarError::Header)?;
+    out.extend_from_slice(&section(block));
+    Ok(out)
+}
+
+/// Decodes and verifies the CARv1 file written by [`single_block`].
+pub(crate) fn read_single_block(mut car: &[u8]) -> Result<Block, CarError> {
+    let header_len = read_varint(&mut car)
+        .and_then(|len| usize::try_from(len).ok())
+        .filter(|len| *len <= car.len())
+        .ok_or(CarError::InvalidCar("truncated header"))?;
+    let (header, mut rest) = car.split_at(header_len);
+
+    let header =
+        DagCborCodec::decode(header).map_err(|_| CarError::InvalidCar("invalid header"))?;
+    match header_version(&header)? {
+        1 => {}
+        version => return Err(CarError::UnsupportedVersion(version)),
+    }
+    let roots = header_roots(header)?;
+
+    let section_len = read_varint(&mut rest)
+        .and_then(|len| usize::try_from(len).ok())
+        .ok_or(CarError::InvalidCar("truncated section"))?;
+    if section_len != rest.len() {
+        return Err(CarError::InvalidCar("expected a single block"));
+    }
+
+    let cid_len = cid_length(rest).ok_or(CarError::InvalidCar("invalid cid"))?;
+    let cid = Cid::try_from(&rest[..cid_len]).map_err(|_| CarError::InvalidCar("invalid cid"))?;
+    let data = &rest[cid_len..];
+
+    validate(&cid, data).map_err(|e| CarError::Verification(cid.clone(), e))?;
+
+    if roots != [cid.clone()] {
+        return Err(CarError::InvalidCar("the block is not the root"));
+    }
+
+    Ok(Block::new(data.into(), cid))
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Options of [`Ipfs::import_car`].
+#[derive(Debug, Clone)]
+pub struct CarImportOptions {
+    /// Pin the roots listed in the header recursively once all of the blocks have been imported.
+    pub pin_roots: bool,
+    /// Build the [`CarIndex`] of the imported blocks.
+    pub build_index: bool,
+}
+
+impl Default for CarImportOptions {
+    fn default() -> Self {
+        CarImportOptions {
+            pin_roots: true,
+            build_index: true,
+        }
+    }
+}
+
+/// The outcome of [`Ipfs::import_car`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarImport {
+    /// The roots listed in the header.
+    pub roots: Vec<Cid>,
+    /// The number of the imported blocks.
+    pub blocks: u64,
+    /// The bytes of the data of the imported blocks.
+    pub block_bytes: u64,
+    /// The index of the imported blocks, if [`CarImportOptions::build_index`] was set.
+    pub index: Option<CarIndex>,
+}
+
+/// The positions of the blocks in the CARv1 payload, the `IndexSorted` index of CARv2 files.
+///
+/// The positions are the offsets of the sections of the blocks from the start of the CARv1
+/// payload, which for a CARv2 file is the data offset of its header. The blocks are indexed by
+/// the digests of their multihashes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CarIndex {
+    offsets: BTreeMap<Vec<u8>, u64>,
+}
+
+impl CarIndex {
+    /// Returns the offset of the section of the block from the start of the CARv1 payload.
+    pub fn get(&self, cid: &Cid) -> Option<u64> {
+        self.offsets.get(cid.hash().digest()).copied()
+    }
+
+    /// Returns the number of the indexed blocks.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Encodes the index as the `IndexSorted` index of a CARv2 file, including the multicodec
+    /// prefix: the buckets of the digests of the same length in the order of the length, with the
+    /// digests followed by the offsets sorted within the buckets.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buckets = BTreeMap::<usize, Vec<(&[u8], u64)>>::new();
+        for (digest, offset) in &self.offsets {
+            buckets
+                .entry(digest.len())
+                .or_default()
+                .push((digest, *offset));
+        }
+
+        let mut out = Vec::new();
+        write_varint(&mut out, INDEX_SORTED);
+        out.extend_from_slice(&(buckets.len() as i32).to_le_bytes());
+
+        for (len, entries) in buckets {
+            let width = len + 8;
+            out.extend_from_slice(&(width as u32).to_le_bytes());
+            out.extend_from_slice(&((width * entries.len()) as u64).to_le_bytes());
+            for (digest, offset) in entries {
+                out.extend_from_slice(digest);
+                out.extend_from_slice(&offset.to_le_bytes());
+            }
+        }
+
+        out
+    }
+}
+
+/// Imports the blocks of the CARv1 or CARv2 file read from the `reader`, see
+/// [`Ipfs::import_car`].
+pub(crate) async fn import<Types, R>(
+    ipfs: &Ipfs<Types>,
+    reader: R,
+    opts: CarImportOptions,
+) -> Result<CarImport, CarError>
+where
+    Types: IpfsTypes,
+    R: AsyncRead + Unpin,
+{
+    let mut reader = CarReader::new(BufReader::new(reader));
+
+    let header = reader.header().await?;
+
+    let import = match header_version(&header)? {
+        1 => import_v1(ipfs, reader, header, &opts).await?,
+        2 => {
+            // the index possibly following the data is not needed, as it is built again
+            let (_, mut inner) = v2_data(reader).await?;
+            let header = inner.header().await?;
+
+            if header_version(&header)? != 1 {
+                return Err(CarError::InvalidCar("the data of a CARv2 is not a CARv1"));
+            }
+
+            import_v1(ipfs, inner, header, &opts).await?
+        }
+        version => return Err(CarError::UnsupportedVersion(version)),
+    };
+
+    if opts.pin_roots {
+        for root in &import.roots {
+            ipfs.insert_pin(root, true)
+                .await
+                .map_err(|e| CarError::Pinning(root.clone(), e))?;
+        }
+    }
+
+    Ok(import)
+}
+
+async fn import_v1<Types, R>(
+    ipfs: &Ipfs<Types>,
+    mut reader: CarReader<R>,
+    header: Ipld,
+    opts: &CarImportOptions,
+) -> Result<CarImport, CarError>
+where
+    Types: IpfsTypes,
+    R: AsyncRead + Unpin,
+{
+    let roots = header_roots(header)?;
+
+    let mut index = if opts.build_index {
+        Some(CarIndex::default())
+    } else {
+        None
+    };
+    let mut blocks = 0;
+    let mut block_bytes = 0;
+
+    loop {
+        let offset = reader.position;
+
+        let len = match reader.varint().await? {
+            // zero length sections are written as padding by some writers
+            Some(0) | None => break,
+            Some(len) => len,
+        };
+
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= MAX_SECTION_SIZE)
+            .ok_or(CarError::InvalidCar("too large section"))?;
+
+        let section = reader.exact(len).await?;
+
+        let cid_len = cid_length(&section).ok_or(CarError::InvalidCar("invalid cid"))?;
+        let cid =
+            Cid::try_from(&section[..cid_len]).map_err(|_| CarError::InvalidCar("invalid cid"))?;
+        let data = &section[cid_len..];
+
+        validate(&cid, data).map_err(|e| CarError::Verification(cid.clone(), e))?;
+
+        if let Some(index) = index.as_mut() {
+            index.offsets.insert(cid.hash().digest().to_vec(), offset);
+        }
+
+        blocks += 1;
+        block_bytes += data.len() as u64;
+
+        trace!(cid = %cid, offset, "imported");
+
+        ipfs.put_block(Block::new(data.into(), cid)).await?;
+    }
+
+    Ok(CarImport {
+        roots,
+        blocks,
+        block_bytes,
+        index,
+    })
+}
+
+/// Skips the rest of the CARv2 header, returning the offset of the CARv1 data in the file and a
+/// reader limited to the data.
+async fn v2_data<R>(
+    mut reader: CarReader<R>,
+) -> Result<(u64, CarReader<tokio::io::Take<R>>), CarError>
+where
+    R: AsyncRead + Unpin,
+{
+    let v2 = reader.exact(V2_HEADER_LEN).await?;
+    let data_offset = u64::from_le_bytes(<[u8; 8]>::try_from(&v2[16..24]).unwrap());
+    let data_size = u64::from_le_bytes(<[u8; 8]>::try_from(&v2[24..32]).unwrap());
+
+    let skipped = data_offset
+        .checked_sub(reader.position)
+        .ok_or(CarError::InvalidCar("data offset inside the header"))?;
+    reader.skip(skipped).await?;
+
+    Ok((data_offset, CarReader::new(reader.reader.take(data_size))))
+}
+
+/// The location of the data of a block in a CAR file, see [`locate_blocks`].
+#[derive(Debug, Clone)]
+pub(crate) struct BlockLocation {
+    /// The Cid the block is listed with, not verified against the data.
+    pub cid: Cid,
+    /// The offset of the data from the start of the file.
+    pub offset: u64,
+    pub len: usize,
+}
+
+/// Reads the roots and the locations of the blocks of the CARv1 or CARv2 file read from the
+/// `reader`, without verifying the blocks or keeping them in memory.
+pub(crate) async fn locate_blocks<R>(reader: R) -> Result<(Vec<Cid>, Vec<BlockLocation>), CarError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = CarReader::new(BufReader::new(reader));
+
+    let header = reader.header().await?;
+
+    match header_version(&header)? {
+        1 => locate_v1(reader, header, 0).await,
+        2 => {
+            let (data_offset, mut inner) = v2_data(reader).await?;
+            let header = inner.header().await?;
+
+            if header_version(&header)? != 1 {
+                return Err(CarError::InvalidCar("the data of a CARv2 is not a CARv1"));
+            }
+
+            locate_v1(inner, header, data_offset).await
+        }
+        version => Err(CarError::UnsupportedVersion(version)),
+    }
+}
+
+async fn locate_v1<R>(
+    mut reader: CarReader<R>,
+    header: Ipld,
+    base: u64,
+) -> Result<(Vec<Cid>, Vec<BlockLocation>), CarError>
+where
+    R: AsyncRead + Unpin,
+{
+    let roots = header_roots(header)?;
+    let mut locations = Vec::new();
+
+    loop {
+        let len = match reader.varint().await? {
+            Some(0) | None => break,
+            Some(len) => len,
+        };
+
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= MAX_SECTION_SIZE)
+            .ok_or(CarError::InvalidCar("too large section"))?;
+
+        let start = reader.position;
+        let section = reader.exact(len).await?;
+
+        let cid_len = cid_length(&section).ok_or(CarError::InvalidCar("invalid cid"))?;
+        let cid =
+            Cid::try_from(&section[..cid_len]).map_err(|_| CarError::InvalidCar("invalid cid"))?;
+
+        locations.push(BlockLocation {
+            cid,
+            offset: base + start + cid_len as u64,
+            len: len - cid_len,
+        });
+    }
+
+    Ok((roots, locations))
+}
+
+/// Returns the roots listed in the CARv1 `header`.
+fn header_roots(header: Ipld) -> Result<Vec<Cid>, CarError> {
+    match header {
+        Ipld::Map(mut map) => match map.remove("roots") {
+            Some(Ipld::List(roots)) => roots
+                .into_iter()
+                .map(|root| match root {
+                    Ipld::Link(cid) => Ok(cid),
+                    _ => Err(CarError::InvalidCar("root is not a link")),
+                })
+                .collect(),
+            _ => Err(CarError::InvalidCar("missing roots")),
+        },
+        _ => Err(CarError::InvalidCar("header is not a map")),
+    }
+}
+
+fn header_version(header: &Ipld) -> Result<i128, CarError> {
+    match header {
+        Ipld::Map(map) => match map.get("version") {
+            Some(Ipld::Integer(version)) => Ok(*version),
+            _ => Err(CarError::InvalidCar("missing version")),
+        },
+        _ => Err(CarError::InvalidCar("header is not a map")),
+    }
+}
+
+/// Returns the length of the Cid at the start of the section.
+fn cid_length(section: &[u8]) -> Option<usize> {
+    // the sha2-256 multihash of a cidv0
+    if section.starts_with(&[0x12, 0x20]) {
+        return Some(34);
+    }
+
+    let mut rest = section;
+    // the version, the codec and the multihash code
+    for _ in 0..3 {
+        read_varint(&mut rest)?;
+    }
+    let digest_len = usize::try_from(read_varint(&mut rest)?).ok()?;
+
+    let len = section.len() - rest.len() + digest_len;
+    if len > section.len() {
+        return None;
+    }
+    Some(len)
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Reads the values of a CAR file, tracking the position in the file.
+struct CarReader<R> {
+    reader: R,
+    position: u64,
+}
+
+impl<R: AsyncRead + Unpin> CarReader<R> {
+    fn new(reader: R) -> Self {
+        CarReader {
+            reader,
+            position: 0,
+        }
+    }
+
+    /// Reads a varint, or returns `None` at the end of the file.
+    async fn varint(&mut self) -> Result<Option<u64>, CarError> {
+        let mut value = 0u64;
+
+        for i in 0..10 {
+            let mut byte = [0u8];
+            if self.reader.read(&mut byte).await? == 0 {
+                if i == 0 {
+                    return Ok(None);
+                }
+                return Err(CarError::InvalidCar("truncated varint"));
+            }
+            self.position += 1;
+
+            value |= u64::from(byte[0] & 0x7f) << (7 * i);
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(value));
+            }
+        }
+
+        Err(CarError::InvalidCar("too long varint"))
+    }
+
+    async fn exact(&mut self, len: usize) -> Result<Vec<u8>, CarError> {
+        let mut buf = vec![0u8; len];
+        self.reader.read_exact(&mut buf).await?;
+        self.position += len as u64;
+        Ok(buf)
+    }
+
+    async fn skip(&mut self, len: u64) -> Result<(), CarError> {
+        let skipped =
+            tokio::io::copy(&mut (&mut self.reader).take(len), &mut tokio::io::sink()).await?;
+        if skipped != len {
+            return Err(CarError::InvalidCar("truncated"));
+        }
+        self.position += len;
+        Ok(())
+    }
+
+    /// Reads the length prefixed dag-cbor header.
+    async fn header(&mut self) -> Result<Ipld, CarError> {
+        let len = self.varint().await?.ok_or(CarError::InvalidCar("empty"))?;
+
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= MAX_HEADER_SIZE)
+            .ok_or(CarError::InvalidCar("too large header"))?;
+
+        let header = self.exact(len).await?;
+        DagCborCodec::decode(&header).map_err(|_| CarError::InvalidCar("invalid header"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import, read_varint, CarError, CarExport, CarImportOptions, Selector};
+    use crate::ipld::dag_cbor::DagCborCodec;
+    use crate::ipld::{validate, Ipld};
+    use crate::{make_ipld, selectors, Node};
+    use cid::{Cid, Codec};
+    use futures::stream::{StreamExt, TryStreamExt};
+    use std::convert::TryFrom;
+
+    /// Parses the CARv1 stream into the header and the Cids of the blocks.
+    fn parse(car: &[u8]) -> (Ipld, Vec<Cid>) {
+        let mut car = car;
+
+        let len = read_varint(&mut car).unwrap() as usize;
+        let header = DagCborCodec::decode(&car[..len]).unwrap();
+        car = &car[len..];
+
+        let mut cids = Vec::new();
+        while !car.is_empty() {
+            let len = read_varint(&mut car).unwrap() as usize;
+            let (section, rest) = car.split_at(len);
+            car = rest;
+
+            // the cids of the fixtures are all cidv1 dag-cbor with sha2-256
+            let cid = Cid::try_from(&section[..36]).unwrap();
+            validate(&cid, &section[36..]).unwrap();
+            cids.push(cid);
+        }
+
+        (header, cids)
+    }
+
+    async fn export(ipfs: &crate::Ipfs<crate::TestTypes>, root: &Cid, opts: CarExport) -> Vec<u8> {
+        opts.export(ipfs, root.clone()).try_concat().await.unwrap()
+    }
+
+    /// Returns a node with a DAG of four blocks and its root.
+    async fn node_with_dag() -> (Node, Cid, Vec<Cid>) {
+        let ipfs = Node::new("test_node").await;
+        let dag = ipfs.dag();
+
+        let leaf = dag.put(make_ipld!("leaf"), Codec::DagCBOR).await.unwrap();
+        let mid = dag
+            .put(make_ipld!({ "leaf": leaf.clone() }), Codec::DagCBOR)
+            .await
+            .unwrap();
+        let other = dag
+            .put(make_ipld!([leaf.clone()]), Codec::DagCBOR)
+            .await
+            .unwrap();
+        let root = dag
+            .put(make_ipld!([mid.clone(), other.clone()]), Codec::DagCBOR)
+            .await
+            .unwrap();
+
+        let cids = vec![root.clone(), mid, leaf, other];
+        (ipfs, root, cids)
+    }
+
+    #[tokio::test]
+    async fn exports_depth_first_once_per_block() {
+        let (ipfs, root, cids) = node_with_dag().await;
+        let (mid, leaf, other) = (cids[1].clone(), cids[2].clone(), cids[3].clone());
+
+        let car = export(&ipfs, &root, CarExport::default()).await;
+        let (header, cids) = parse(&car);
+
+        assert_eq!(
+            header,
+            make_ipld!({ "roots": [root.clone()], "version": 1 })
+        );
+        let full = vec![root.clone(), mid.clone(), leaf, other.clone()];
+        assert_eq!(cids, full);
+
+        let car = export(
+            &ipfs,
+            &root,
+            CarExport::default().with_selector(Selector::Depth(1)),
+        )
+        .await;
+        assert_eq!(parse(&car).1, vec![root.clone(), mid, other]);
+
+        // the recursive selector matching everything selects the same blocks as the default
+        let car = export(
+            &ipfs,
+            &root,
+            CarExport::default().with_selector(Selector::Ipld(selectors::Selector::all())),
+        )
+        .await;
+        assert_eq!(parse(&car).1, full);
+
+        let car = export(
+            &ipfs,
+            &root,
+            CarExport::default().with_selector(Selector::Depth(0)),
+        )
+        .await;
+        assert_eq!(parse(&car).1, vec![root]);
+    }
+
+    #[tokio::test]
+    async fn path_blocks_precede_the_export() {
+        let (ipfs, root, cids) = node_with_dag().await;
+        let (mid, leaf) = (cids[1].clone(), cids[2].clone());
+
+        let path_blocks = vec![
+            ipfs.get_block(&root).await.unwrap(),
+            ipfs.get_block(&mid).await.unwrap(),
+        ];
+
+        let car = export(
+            &ipfs,
+            &leaf,
+            CarExport::default().with_path_blocks(path_blocks),
+        )
+        .await;
+        let (header, cids) = parse(&car);
+
+        assert_eq!(
+            header,
+            make_ipld!({ "roots": [root.clone()], "version": 1 })
+        );
+        assert_eq!(cids, vec![root, mid, leaf]);
+    }
+
+    #[tokio::test]
+    async fn missing_block_ends_local_export() {
+        let ipfs = Node::new("test_node").await;
+
+        let missing =
+            Cid::try_from("bafyreibvjvcv745gig4mvqs4hctx4zfkono4rjejm2ta6gtyzkqxfjeily").unwrap();
+        let root = ipfs
+            .dag()
+            .put(make_ipld!([missing.clone()]), Codec::DagCBOR)
+            .await
+            .unwrap();
+
+        let chunks = CarExport::default()
+            .with_existing_blocks()
+            .export(&*ipfs, root)
+            .collect::<Vec<_>>()
+            .await;
+
+        // the header and the root block before the failure
+        assert_eq!(chunks.len(), 3);
+        match chunks.last() {
+            Some(Err(CarError::BlockNotFound(cid))) => assert_eq!(cid, &missing),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn imports_exported_car() {
+        let (exporter, root, cids) = node_with_dag().await;
+        let car = export(&exporter, &root, CarExport::default()).await;
+
+        let ipfs = Node::new("test_node").await;
+        let imported = ipfs
+            .import_car(&car[..], CarImportOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(imported.roots, vec![root.clone()]);
+        assert_eq!(imported.blocks, 4);
+
+        let mut rest = &car[..];
+        let header_len = read_varint(&mut rest).unwrap() as usize;
+        let first_section = car.len() - rest.len() + header_len;
+
+        let index = imported.index.unwrap();
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.get(&root), Some(first_section as u64));
+        assert!(cids.iter().all(|cid| index.get(cid).is_some()));
+
+        let found = ipfs.get_blocks_now(&cids).await.unwrap();
+        assert!(found.iter().all(Option::is_some));
+        assert!(ipfs.is_pinned(&root).await.unwrap());
+
+        // the buckets of the digests of the same length, here all 32 bytes
+        let bytes = index.to_bytes();
+        assert_eq!(&bytes[..2], &[0x80, 0x08]);
+        assert_eq!(&bytes[2..6], &1i32.to_le_bytes());
+        assert_eq!(&bytes[6..10], &40u32.to_le_bytes());
+        assert_eq!(&bytes[10..18], &160u64.to_le_bytes());
+        assert_eq!(bytes.len(), 18 + 160);
+    }
+
+    #[tokio::test]
+    async fn imports_carv2() {
+        let (exporter, root, cids) = node_with_dag().await;
+        let car = export(&exporter, &root, CarExport::default()).await;
+
+        let ipfs = Node::new("test_node").await;
+
+        let pragma = [
+            0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+        ];
+        // some padding between the header and the data
+        let data_offset = pragma.len() as u64 + 40 + 5;
+
+        let mut v2 = pragma.to_vec();
+        v2.extend_from_slice(&[0u8; 16]);
+        v2.extend_from_slice(&data_offset.to_le_bytes());
+        v2.extend_from_slice(&(car.len() as u64).to_le_bytes());
+        v2.extend_from_slice(&(data_offset + car.len() as u64).to_le_bytes());
+        v2.extend_from_slice(&[0u8; 5]);
+        v2.extend_from_slice(&car);
+        // an index, which is skipped
+        v2.extend_from_slice(&[0x80, 0x08, 0, 0, 0, 0]);
+
+        let opts = CarImportOptions {
+            pin_roots: false,
+            build_index: true,
+        };
+        let imported = ipfs.import_car(&v2[..], opts.clone()).await.unwrap();
+        let from_v1 = Node::new("test_node")
+            .await
+            .import_car(&car[..], opts)
+            .await
+            .unwrap();
+
+        assert_eq!(imported, from_v1);
+        assert_eq!(imported.blocks, 4);
+
+        let found = ipfs.get_blocks_now(&cids).await.unwrap();
+        assert!(found.iter().all(Option::is_some));
+        assert!(!ipfs.is_pinned(&root).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn corrupted_block_fails_verification() {
+        let (exporter, root, cids) = node_with_dag().await;
+        let mut car = export(&exporter, &root, CarExport::default()).await;
+
+        // the last byte of the last block
+        *car.last_mut().unwrap() ^= 0xff;
+
+        let ipfs = Node::new("test_node").await;
+        let e = import(&*ipfs, &car[..], CarImportOptions::default())
+            .await
+            .unwrap_err();
+
+        match e {
+            CarError::Verification(cid, _) => assert_eq!(&cid, cids.last().unwrap()),
+            x => panic!("{:?}", x),
+        }
+
+        assert!(!ipfs.is_pinned(&root).await.unwrap());
+    }
+}
@@ -1,14 +1,355 @@
-//! Static configuration (the bootstrap node(s)).
+//! Static configuration (the bootstrap node(s)), and the profiles and validation of the
+//! [`IpfsOptions`], see [`IpfsOptionsBuilder`]. The changes of the configuration can be recorded
+//! in the repo, see [`history`].
 
-/// The supported bootstrap nodes (/dnsaddr is not yet supported). This will be updated to contain
-/// the latest known supported IPFS bootstrap peers.
+use crate::p2p::{AddrFilter, MultiaddrWithPeerId};
+use crate::IpfsOptions;
+use libp2p::identity::Keypair;
+use libp2p::Multiaddr;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub mod history;
+
+/// The supported bootstrap nodes, the same as the default go-ipfs ones. The `/dnsaddr` nodes are
+/// resolved into the concrete addresses when they are added, see [`crate::dns`].
 // FIXME: it would be nice to parse these into MultiaddrWithPeerId with const fn.
-pub const BOOTSTRAP_NODES: &[&str] =
-    &["/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ"];
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
+    "/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
+];
+
+/// The networks which are not reachable from the internet, never announced with
+/// [`Profile::Server`]. Same as in the `server` profile of go-ipfs.
+const PRIVATE_NETWORKS: &[&str] = &[
+    "/ip4/10.0.0.0/ipcidr/8",
+    "/ip4/100.64.0.0/ipcidr/10",
+    "/ip4/169.254.0.0/ipcidr/16",
+    "/ip4/172.16.0.0/ipcidr/12",
+    "/ip4/192.0.0.0/ipcidr/24",
+    "/ip4/192.0.2.0/ipcidr/24",
+    "/ip4/192.168.0.0/ipcidr/16",
+    "/ip4/198.18.0.0/ipcidr/15",
+    "/ip4/198.51.100.0/ipcidr/24",
+    "/ip4/203.0.113.0/ipcidr/24",
+    "/ip4/240.0.0.0/ipcidr/4",
+    "/ip6/100::/ipcidr/64",
+    "/ip6/2001:2::/ipcidr/48",
+    "/ip6/2001:db8::/ipcidr/32",
+    "/ip6/fc00::/ipcidr/7",
+    "/ip6/fe80::/ipcidr/10",
+];
+
+/// A set of changes to the [`IpfsOptions`] for a common use, like the profiles of
+/// `ipfs init --profile` in go-ipfs. Later profiles override the earlier ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// For nodes running in a datacenter: the private networks are never announced, the local
+    /// peer discovery is disabled and the global DHT is used.
+    Server,
+    /// For nodes on small devices: fewer dials at once, smaller memory budgets and no pubsub
+    /// peer discovery.
+    LowPower,
+    /// For tests: listens only on an ephemeral localhost port, uses the LAN DHT and has no
+    /// bootstrap peers.
+    Test,
+    /// Stores all of the blocks under the repo again, removing the additional block volumes.
+    DefaultDatastore,
+}
+
+impl Profile {
+    /// Applies the changes of the profile to the `options`.
+    pub fn apply(self, options: &mut IpfsOptions) {
+        match self {
+            Profile::Server => {
+                for network in PRIVATE_NETWORKS {
+                    let filter = network
+                        .parse::<AddrFilter>()
+                        .expect("the private networks are valid filters");
+
+                    if !options.announce.no_announce.contains(&filter) {
+                        options.announce.no_announce.push(filter);
+                    }
+                }
+                options.mdns = false;
+                options.kad_protocol = None;
+            }
+            Profile::LowPower => {
+                options.dial.concurrency = 8;
+                options.dial.addresses_per_peer = 4;
+                options.pubsub_discovery = false;
+                options.memory_budgets.incoming_bytes = Some(16 * 1024 * 1024);
+                options.memory_budgets.outgoing_reads = Some(32);
+                options.popularity.capacity = 1_000;
+            }
+            Profile::Test => {
+                options.listening_addrs = vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()];
+                options.bootstrap.clear();
+                options.mdns = false;
+                options.kad_protocol = Some("/ipfs/lan/kad/1.0.0".to_owned());
+            }
+            Profile::DefaultDatastore => {
+                options.block_storage = Default::default();
+            }
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Profile::Server => "server",
+            Profile::LowPower => "lowpower",
+            Profile::Test => "test",
+            Profile::DefaultDatastore => "default-datastore",
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+/// The name given to [`Profile::from_str`] is not one of the profiles.
+#[derive(Debug, thiserror::Error)]
+#[error("unknown profile {0:?}, expected one of server, lowpower, test or default-datastore")]
+pub struct UnknownProfile(String);
+
+impl FromStr for Profile {
+    type Err = UnknownProfile;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "server" => Profile::Server,
+            "lowpower" => Profile::LowPower,
+            "test" => Profile::Test,
+            "default-datastore" => Profile::DefaultDatastore,
+            _ => return Err(UnknownProfile(s.to_owned())),
+        })
+    }
+}
+
+/// The settings of the [`IpfsOptions`] rejected by [`IpfsOptions::validate`].
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidOptions {
+    #[error("dial.{0} must be greater than zero")]
+    ZeroDialLimit(&'static str),
+    #[error("the announced address {addr} is filtered out by the no_announce filter {filter}")]
+    FilteredAnnounce { addr: Multiaddr, filter: AddrFilter },
+    #[error("the bootstrap peer {0} is the node itself")]
+    BootstrapSelf(Multiaddr),
+    #[error("the block volume {0:?} is configured more than once")]
+    DuplicateVolume(PathBuf),
+    #[error("the block volume {0:?} has a capacity of zero bytes")]
+    ZeroCapacityVolume(PathBuf),
+    #[error("pubsub_batching.{0} must be greater than zero")]
+    ZeroPubsubBatch(&'static str),
+    #[error("bitswap_wantlist_batching.max_entries must be greater than zero")]
+    ZeroWantlistBatch,
+    #[error("memory_budgets.{0} of zero would never let any blocks through")]
+    ZeroMemoryBudget(&'static str),
+    #[error("popularity.half_life must be greater than zero")]
+    ZeroPopularityHalfLife,
+}
+
+impl IpfsOptions {
+    /// Returns a builder of the options for a repo at `ipfs_path`, see [`IpfsOptionsBuilder`].
+    pub fn builder(ipfs_path: impl Into<PathBuf>) -> IpfsOptionsBuilder {
+        IpfsOptionsBuilder::new(ipfs_path)
+    }
+
+    /// Checks that the settings are usable and do not conflict with each other. Called when
+    /// starting the node.
+    pub fn validate(&self) -> Result<(), InvalidOptions> {
+        let dial = &self.dial;
+        if dial.concurrency == 0 {
+            return Err(InvalidOptions::ZeroDialLimit("concurrency"));
+        }
+        if dial.addresses_per_peer == 0 {
+            return Err(InvalidOptions::ZeroDialLimit("addresses_per_peer"));
+        }
+        if dial.timeout == Duration::from_secs(0) {
+            return Err(InvalidOptions::ZeroDialLimit("timeout"));
+        }
+
+        for addr in &self.announce.announce {
+            if let Some(filter) = self.announce.no_announce.iter().find(|f| f.matches(addr)) {
+                return Err(InvalidOptions::FilteredAnnounce {
+                    addr: addr.clone(),
+                    filter: filter.clone(),
+                });
+            }
+        }
+
+        let peer_id = self.keypair.public().to_peer_id();
+        if let Some((addr, _)) = self.bootstrap.iter().find(|(_, peer)| *peer == peer_id) {
+            return Err(InvalidOptions::BootstrapSelf(addr.clone()));
+        }
+
+        let mut volumes = HashSet::new();
+        volumes.insert(self.ipfs_path.join("blockstore"));
+        for volume in &self.block_storage.volumes {
+            if !volumes.insert(volume.path.clone()) {
+                return Err(InvalidOptions::DuplicateVolume(volume.path.clone()));
+            }
+            if volume.capacity == Some(0) {
+                return Err(InvalidOptions::ZeroCapacityVolume(volume.path.clone()));
+            }
+        }
+
+        if let Some(batching) = &self.pubsub_batching {
+            if batching.max_messages == 0 {
+                return Err(InvalidOptions::ZeroPubsubBatch("max_messages"));
+            }
+            if batching.max_bytes == 0 {
+                return Err(InvalidOptions::ZeroPubsubBatch("max_bytes"));
+            }
+        }
+
+        if let Some(batching) = &self.bitswap_wantlist_batching {
+            if batching.max_entries == 0 {
+                return Err(InvalidOptions::ZeroWantlistBatch);
+            }
+        }
+
+        if self.memory_budgets.incoming_bytes == Some(0) {
+            return Err(InvalidOptions::ZeroMemoryBudget("incoming_bytes"));
+        }
+        if self.memory_budgets.outgoing_reads == Some(0) {
+            return Err(InvalidOptions::ZeroMemoryBudget("outgoing_reads"));
+        }
+
+        if self.popularity.half_life == Duration::from_secs(0) {
+            return Err(InvalidOptions::ZeroPopularityHalfLife);
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds validated [`IpfsOptions`] out of the defaults, the [`Profile`]s and the explicit
+/// settings, in the order they are given.
+///
+/// ```
+/// use ipfs::config::Profile;
+/// use ipfs::IpfsOptions;
+///
+/// let options = IpfsOptions::builder("/tmp/ipfs")
+///     .profile(Profile::Server)
+///     .profile(Profile::LowPower)
+///     .configure(|options| options.pubsub_discovery = true)
+///     .build()
+///     .unwrap();
+///
+/// assert!(options.pubsub_discovery);
+/// ```
+pub struct IpfsOptionsBuilder {
+    options: IpfsOptions,
+}
+
+impl IpfsOptionsBuilder {
+    /// Starts from the defaults with a newly generated ed25519 keypair. By default the node does
+    /// not listen on any address and has no bootstrap peers.
+    pub fn new(ipfs_path: impl Into<PathBuf>) -> Self {
+        IpfsOptionsBuilder {
+            options: IpfsOptions {
+                ipfs_path: ipfs_path.into(),
+                repo_migrations: Default::default(),
+                repo_journal: false,
+                block_storage: Default::default(),
+                keypair: Keypair::generate_ed25519(),
+                bootstrap: Default::default(),
+                mdns: false,
+                kad_protocol: None,
+                listening_addrs: Default::default(),
+                dial: Default::default(),
+                memory_transport: false,
+                pubsub_discovery: false,
+                pubsub_batching: None,
+                announce: Default::default(),
+                popularity: Default::default(),
+                ipns_cache: Default::default(),
+                ipns_pubsub: false,
+                memory_budgets: Default::default(),
+                bitswap_compression: false,
+                bitswap_wantlist_batching: Some(Default::default()),
+                codecs: Default::default(),
+                dns: Default::default(),
+                content_routing: Vec::new(),
+                pinning_services: Vec::new(),
+                span: None,
+            },
+        }
+    }
+
+    /// Applies the profile, see [`Profile::apply`].
+    pub fn profile(mut self, profile: Profile) -> Self {
+        profile.apply(&mut self.options);
+        self
+    }
+
+    /// Sets the identity of the node.
+    pub fn keypair(mut self, keypair: Keypair) -> Self {
+        self.options.keypair = keypair;
+        self
+    }
+
+    /// Sets the addresses to listen on.
+    pub fn listening_addrs(mut self, addrs: Vec<Multiaddr>) -> Self {
+        self.options.listening_addrs = addrs;
+        self
+    }
+
+    /// Adds a bootstrap peer.
+    pub fn bootstrap(mut self, peer: MultiaddrWithPeerId) -> Self {
+        let MultiaddrWithPeerId { multiaddr, peer_id } = peer;
+        self.options.bootstrap.push((multiaddr.into(), peer_id));
+        self
+    }
+
+    /// Adds the [`BOOTSTRAP_NODES`] as the bootstrap peers.
+    pub fn default_bootstrap(mut self) -> Self {
+        for node in BOOTSTRAP_NODES {
+            let peer = node
+                .parse::<MultiaddrWithPeerId>()
+                .expect("the bootstrap nodes are valid");
+            self = self.bootstrap(peer);
+        }
+        self
+    }
+
+    /// Changes any of the other settings.
+    pub fn configure(mut self, f: impl FnOnce(&mut IpfsOptions)) -> Self {
+        f(&mut self.options);
+        self
+    }
+
+    /// Returns the options once they have been validated with [`IpfsOptions::validate`].
+    pub fn build(self) -> Result<IpfsOptions, InvalidOptions> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+impl fmt::Debug for IpfsOptionsBuilder {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("IpfsOptionsBuilder")
+            .field("options", &self.options)
+            .finish()
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use super::{InvalidOptions, Profile};
     use crate::p2p::MultiaddrWithPeerId;
+    use crate::{BlockVolume, IpfsOptions};
 
     #[test]
     fn bootstrap_nodes_are_multiaddr_with_peerid() {
@@ -17,4 +358,106 @@ mod tests {
             .try_for_each(|s| s.parse::<MultiaddrWithPeerId>().map(|_| ()))
             .unwrap();
     }
+
+    #[test]
+    fn profiles_round_trip() {
+        for profile in &[
+            Profile::Server,
+            Profile::LowPower,
+            Profile::Test,
+            Profile::DefaultDatastore,
+        ] {
+            assert_eq!(profile.to_string().parse::<Profile>().unwrap(), *profile);
+        }
+
+        assert!("flatfs".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn profiles_are_applied_in_order() {
+        let options = IpfsOptions::builder("repo")
+            .default_bootstrap()
+            .profile(Profile::Test)
+            .profile(Profile::Server)
+            .build()
+            .unwrap();
+
+        assert!(options.bootstrap.is_empty());
+        assert_eq!(options.kad_protocol, None);
+        assert_eq!(
+            options.listening_addrs,
+            vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()]
+        );
+        assert!(!options
+            .announce
+            .allows(&"/ip4/192.168.1.1/tcp/4001".parse().unwrap()));
+
+        // applying a profile twice does not duplicate the filters
+        let again = IpfsOptions::builder("repo")
+            .profile(Profile::Server)
+            .profile(Profile::Server)
+            .build()
+            .unwrap();
+        assert_eq!(
+            again.announce.no_announce.len(),
+            super::PRIVATE_NETWORKS.len()
+        );
+    }
+
+    #[test]
+    fn conflicting_options_are_rejected() {
+        let err = IpfsOptions::builder("repo")
+            .profile(Profile::Server)
+            .configure(|options| {
+                options.announce.announce = vec!["/ip4/10.1.2.3/tcp/4001".parse().unwrap()]
+            })
+            .build()
+            .unwrap_err();
+        assert!(
+            matches!(err, InvalidOptions::FilteredAnnounce { .. }),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            "the announced address /ip4/10.1.2.3/tcp/4001 is filtered out by the no_announce \
+             filter /ip4/10.0.0.0/ipcidr/8"
+        );
+
+        let err = IpfsOptions::builder("repo")
+            .configure(|options| options.dial.concurrency = 0)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dial.concurrency must be greater than zero"
+        );
+
+        let err = IpfsOptions::builder("repo")
+            .configure(|options| {
+                options.block_storage.volumes.push(BlockVolume {
+                    path: "repo/blockstore".into(),
+                    capacity: None,
+                })
+            })
+            .build()
+            .unwrap_err();
+        assert!(
+            matches!(err, InvalidOptions::DuplicateVolume(_)),
+            "{:?}",
+            err
+        );
+
+        let options = IpfsOptions::inmemory_with_generated_keys();
+        let myself = MultiaddrWithPeerId {
+            multiaddr: "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+            peer_id: options.keypair.public().to_peer_id(),
+        };
+        let err = IpfsOptions::builder("repo")
+            .keypair(options.keypair)
+            .bootstrap(myself)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, InvalidOptions::BootstrapSelf(_)), "{:?}", err);
+    }
 }
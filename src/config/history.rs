@@ -0,0 +1,290 @@
+//! Content-addressed history of the configuration.
+//!
+//! Every recorded configuration is stored as a dag-cbor document linking to the previously
+//! recorded one:
+//!
+//! ```text
+//! { "config": <the configuration>, "message": "..", "previous": <link or null>, "timestamp": 1600000000 }
+//! ```
+//!
+//! The latest document is the head of the history, kept in [`Column::Config`] of the datastore
+//! and pinned recursively, so the whole chain stays in the repo. Rolling back records the older
+//! configuration again as the new head, leaving the history intact for auditing.
+//!
+//! The configuration is stored as-is, so any secrets like the private key should be removed before
+//! recording it, as the blocks are served to the other peers like any other blocks.
+//!
+//! [`Column::Config`]: crate::repo::Column::Config
+
+use crate::ipld::Ipld;
+use crate::{Error, Ipfs, IpfsTypes};
+use cid::Cid;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A recorded configuration, see [`Ipfs::config_history`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigSnapshot {
+    /// The Cid of the dag-cbor document.
+    pub cid: Cid,
+    /// The previously recorded snapshot, `None` for the first one.
+    pub previous: Option<Cid>,
+    /// When the snapshot was recorded, with a precision of seconds.
+    pub timestamp: SystemTime,
+    /// Describes the change, for example what caused it.
+    pub message: String,
+    /// The recorded configuration.
+    pub config: Ipld,
+}
+
+impl ConfigSnapshot {
+    /// Returns the document stored for the snapshot.
+    fn document(
+        config: &Ipld,
+        message: &str,
+        previous: Option<&Cid>,
+        timestamp: SystemTime,
+    ) -> Ipld {
+        let seconds = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut map = BTreeMap::new();
+        map.insert("config".to_owned(), config.clone());
+        map.insert("message".to_owned(), Ipld::String(message.to_owned()));
+        map.insert(
+            "previous".to_owned(),
+            previous.cloned().map(Ipld::Link).unwrap_or(Ipld::Null),
+        );
+        map.insert("timestamp".to_owned(), Ipld::Integer(seconds.into()));
+        Ipld::Map(map)
+    }
+
+    fn from_ipld(cid: Cid, ipld: Ipld) -> Result<Self, HistoryError> {
+        let invalid = || HistoryError::InvalidSnapshot(cid.clone());
+
+        let mut map = match ipld {
+            Ipld::Map(map) => map,
+            _ => return Err(invalid()),
+        };
+
+        let previous = match map.remove("previous") {
+            Some(Ipld::Link(previous)) => Some(previous),
+            Some(Ipld::Null) | None => None,
+            Some(_) => return Err(invalid()),
+        };
+
+        let timestamp = match map.remove("timestamp") {
+            Some(Ipld::Integer(seconds)) => u64::try_from(seconds)
+                .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+                .map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        };
+
+        let message = match map.remove("message") {
+            Some(Ipld::String(message)) => message,
+            _ => return Err(invalid()),
+        };
+
+        let config = map.remove("config").ok_or_else(invalid)?;
+
+        Ok(ConfigSnapshot {
+            cid,
+            previous,
+            timestamp,
+            message,
+            config,
+        })
+    }
+}
+
+/// The ways the configuration history can fail, in addition to the repo errors.
+#[derive(Debug, thiserror::Error)]
+pub enum HistoryError {
+    #[error("at least one change needs to be rolled back")]
+    NoChanges,
+    #[error(
+        "cannot roll back {requested} changes, the history has only {available} older snapshots"
+    )]
+    NotEnoughSnapshots { requested: usize, available: usize },
+    #[error("the configuration snapshot {0} is not a valid snapshot document")]
+    InvalidSnapshot(Cid),
+}
+
+impl<Types: IpfsTypes> Ipfs<Types> {
+    /// Records the `config` as the latest snapshot of the configuration history, unless it is
+    /// the same as the latest one. Returns the latest snapshot, which is either the newly
+    /// recorded or the unchanged one.
+    ///
+    /// The history has a single head, so the snapshots should be recorded by a single task.
+    pub async fn record_config(
+        &self,
+        config: Ipld,
+        message: impl Into<String>,
+    ) -> Result<ConfigSnapshot, Error> {
+        let previous = match self.repo.get_config_head().await? {
+            Some(head) => {
+                let head = self.config_snapshot(head).await?;
+                if head.config == config {
+                    return Ok(head);
+                }
+                Some(head.cid)
+            }
+            None => None,
+        };
+
+        // the timestamps are stored as whole seconds
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let timestamp = UNIX_EPOCH + Duration::from_secs(seconds);
+        let message = message.into();
+
+        let document = ConfigSnapshot::document(&config, &message, previous.as_ref(), timestamp);
+        let _session = self.gc_session().await;
+        let snapshot = ConfigSnapshot {
+            cid: self.put_dag(document).await?,
+            previous,
+            timestamp,
+            message,
+            config,
+        };
+
+        // the pinstore does not count the references, so the previous head is unpinned first not
+        // to unpin the chain shared with the new head
+        if let Some(previous) = &snapshot.previous {
+            self.remove_pin(previous, true).await?;
+        }
+        self.insert_pin(&snapshot.cid, true).await?;
+        self.repo.put_config_head(&snapshot.cid).await?;
+
+        debug!(cid = %snapshot.cid, message = %snapshot.message, "recorded the configuration");
+
+        Ok(snapshot)
+    }
+
+    /// Returns the snapshots of the configuration history from the latest to the oldest, at most
+    /// `limit` of them.
+    pub async fn config_history(&self, limit: Option<usize>) -> Result<Vec<ConfigSnapshot>, Error> {
+        let mut snapshots = Vec::new();
+        let mut next = self.repo.get_config_head().await?;
+
+        while let Some(cid) = next {
+            if limit.map(|limit| snapshots.len() >= limit).unwrap_or(false) {
+                break;
+            }
+            let snapshot = self.config_snapshot(cid).await?;
+            next = snapshot.previous.clone();
+            snapshots.push(snapshot);
+        }
+
+        Ok(snapshots)
+    }
+
+    /// Records the configuration of the snapshot `changes` steps before the latest one as the new
+    /// latest snapshot, and returns it. Rolling back one change restores the configuration in
+    /// use before the latest change.
+    pub async fn rollback_config(&self, changes: usize) -> Result<ConfigSnapshot, Error> {
+        if changes == 0 {
+            return Err(HistoryError::NoChanges.into());
+        }
+
+        let history = self.config_history(Some(changes + 1)).await?;
+
+        let target = match history.get(changes) {
+            Some(target) => target,
+            None => {
+                return Err(HistoryError::NotEnoughSnapshots {
+                    requested: changes,
+                    available: history.len().saturating_sub(1),
+                }
+                .into())
+            }
+        };
+
+        let message = format!("rollback to {}", target.cid);
+        self.record_config(target.config.clone(), message).await
+    }
+
+    async fn config_snapshot(&self, cid: Cid) -> Result<ConfigSnapshot, Error> {
+        let block = self.repo.get_block_now(&cid).await?;
+        let block = block.ok_or_else(|| HistoryError::InvalidSnapshot(cid.clone()))?;
+        let ipld = crate::ipld::decode_ipld(&cid, &block.data)?;
+        Ok(ConfigSnapshot::from_ipld(cid, ipld)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HistoryError;
+    use crate::Node;
+
+    #[tokio::test]
+    async fn records_and_rolls_back() {
+        let ipfs = Node::new("test_node").await;
+
+        assert!(ipfs.config_history(None).await.unwrap().is_empty());
+
+        let first = ipfs
+            .record_config(make_ipld!({ "port": 4001 }), "init")
+            .await
+            .unwrap();
+        assert_eq!(first.previous, None);
+
+        // unchanged configurations are not recorded again
+        let again = ipfs
+            .record_config(make_ipld!({ "port": 4001 }), "restart")
+            .await
+            .unwrap();
+        assert_eq!(again, first);
+
+        let second = ipfs
+            .record_config(make_ipld!({ "port": 4002 }), "restart")
+            .await
+            .unwrap();
+        assert_eq!(second.previous, Some(first.cid.clone()));
+
+        let rolled_back = ipfs.rollback_config(1).await.unwrap();
+        assert_eq!(rolled_back.config, first.config);
+        assert_eq!(rolled_back.message, format!("rollback to {}", first.cid));
+
+        let history = ipfs.config_history(None).await.unwrap();
+        assert_eq!(history, vec![rolled_back.clone(), second, first]);
+
+        assert_eq!(
+            ipfs.config_history(Some(1)).await.unwrap(),
+            vec![rolled_back]
+        );
+
+        // the whole chain stays pinned
+        for snapshot in &history {
+            assert!(ipfs.is_pinned(&snapshot.cid).await.unwrap());
+        }
+
+        let err = ipfs.rollback_config(3).await.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<HistoryError>(),
+                Some(HistoryError::NotEnoughSnapshots {
+                    requested: 3,
+                    available: 2
+                })
+            ),
+            "{:?}",
+            err
+        );
+        let err = ipfs.rollback_config(0).await.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<HistoryError>(),
+                Some(HistoryError::NoChanges)
+            ),
+            "{:?}",
+            err
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! Serde and command line parsing support for the Cids, the peer ids, the multiaddresses and the
+//! paths, so that they can be used in the configuration files and the command line arguments
+//! without conversion shims.
+//!
+//! With the `serde_support` feature the [`IpfsPath`], [`PathRoot`], [`MultiaddrWithPeerId`] and
+//! [`MultiaddrWithoutPeerId`] implement `Serialize` and `Deserialize`, and the [`cid`],
+//! [`peer_id`] and [`multiaddr`] modules can be used with `#[serde(with = "...")]` for the types
+//! of the other crates. The human-readable formats such as JSON or TOML use the usual string
+//! forms, while the binary formats use the binary forms of the Cids, the peer ids and the
+//! multiaddresses.
+//!
+//! With the `clap_support` feature the [`value_parser`] module has the `clap` value parsers of
+//! the same types.
+
+#[cfg(feature = "serde_support")]
+pub use self::serde_impls::{cid, multiaddr, peer_id};
+
+#[cfg(feature = "serde_support")]
+mod serde_impls {
+    use crate::path::{IpfsPath, PathRoot};
+    use crate::{MultiaddrWithPeerId, MultiaddrWithoutPeerId};
+    use libp2p::Multiaddr;
+    use serde::de::{self, Deserialize, Deserializer, Visitor};
+    use serde::ser::{Serialize, Serializer};
+    use std::convert::TryFrom;
+    use std::fmt;
+    use std::marker::PhantomData;
+    use std::str::FromStr;
+
+    /// Serializes the string form for the human-readable formats and the binary form for the
+    /// others.
+    fn serialize<S, T>(
+        value: &T,
+        bytes: impl FnOnce(&T) -> Vec<u8>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: fmt::Display,
+    {
+        if serializer.is_human_readable() {
+            serializer.collect_str(value)
+        } else {
+            serializer.serialize_bytes(&bytes(value))
+        }
+    }
+
+    /// Deserializes what [`serialize`] serialized.
+    fn deserialize<'de, D, T, E, F>(deserializer: D, from_bytes: F) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: fmt::Display,
+        E: fmt::Display,
+        F: FnOnce(Vec<u8>) -> Result<T, E>,
+    {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(StrVisitor(PhantomData))
+        } else {
+            let bytes = deserializer.deserialize_bytes(BytesVisitor)?;
+            from_bytes(bytes).map_err(de::Error::custom)
+        }
+    }
+
+    struct StrVisitor<T>(PhantomData<T>);
+
+    impl<'de, T> Visitor<'de> for StrVisitor<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        type Value = T;
+
+        fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt.write_str("a string")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<T, E> {
+            s.parse().map_err(E::custom)
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt.write_str("bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+
+    /// Serde support for the [`cid::Cid`], usable with `#[serde(with = "ipfs::convert::cid")]`.
+    pub mod cid {
+        use cid::Cid;
+        use serde::{Deserializer, Serializer};
+        use std::convert::TryFrom;
+
+        pub fn serialize<S: Serializer>(cid: &Cid, serializer: S) -> Result<S::Ok, S::Error> {
+            super::serialize(cid, Cid::to_bytes, serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Cid, D::Error> {
+            super::deserialize(deserializer, Cid::try_from)
+        }
+    }
+
+    /// Serde support for the [`libp2p::PeerId`], usable with
+    /// `#[serde(with = "ipfs::convert::peer_id")]`.
+    pub mod peer_id {
+        use libp2p::PeerId;
+        use serde::{Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            peer_id: &PeerId,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            super::serialize(peer_id, PeerId::to_bytes, serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PeerId, D::Error> {
+            super::deserialize(deserializer, |bytes| PeerId::from_bytes(&bytes))
+        }
+    }
+
+    /// Serde support for the [`libp2p::Multiaddr`], usable with
+    /// `#[serde(with = "ipfs::convert::multiaddr")]`.
+    pub mod multiaddr {
+        use libp2p::Multiaddr;
+        use serde::{Deserializer, Serializer};
+        use std::convert::TryFrom;
+
+        pub fn serialize<S: Serializer>(
+            addr: &Multiaddr,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            super::serialize(addr, Multiaddr::to_vec, serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Multiaddr, D::Error> {
+            super::deserialize(deserializer, Multiaddr::try_from)
+        }
+    }
+
+    /// The paths have no binary form, the strings are used with all of the formats.
+    fn path_bytes<T: fmt::Display>(path: &T) -> Vec<u8> {
+        path.to_string().into_bytes()
+    }
+
+    fn path_from_bytes<T>(bytes: Vec<u8>) -> Result<T, String>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let s = String::from_utf8(bytes).map_err(|e| e.to_string())?;
+        s.parse().map_err(|e: T::Err| e.to_string())
+    }
+
+    impl Serialize for IpfsPath {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(self, path_bytes, serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for IpfsPath {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize(deserializer, path_from_bytes)
+        }
+    }
+
+    impl Serialize for PathRoot {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(self, path_bytes, serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for PathRoot {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize(deserializer, path_from_bytes)
+        }
+    }
+
+    impl Serialize for MultiaddrWithPeerId {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(
+                self,
+                |addr| Multiaddr::from(addr.clone()).to_vec(),
+                serializer,
+            )
+        }
+    }
+
+    impl<'de> Deserialize<'de> for MultiaddrWithPeerId {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize(deserializer, |bytes| {
+                let addr = Multiaddr::try_from(bytes).map_err(|e| e.to_string())?;
+                MultiaddrWithPeerId::try_from(addr).map_err(|e| e.to_string())
+            })
+        }
+    }
+
+    impl Serialize for MultiaddrWithoutPeerId {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(self, |addr| addr.as_ref().to_vec(), serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for MultiaddrWithoutPeerId {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize(deserializer, |bytes| {
+                let addr = Multiaddr::try_from(bytes).map_err(|e| e.to_string())?;
+                MultiaddrWithoutPeerId::try_from(addr).map_err(|e| e.to_string())
+            })
+        }
+    }
+}
+
+/// The `clap` value parsers of the Cids, the peer ids, the multiaddresses and the paths, for
+/// example `#[clap(value_parser = ipfs::convert::value_parser::cid())]`.
+#[cfg(feature = "clap_support")]
+pub mod value_parser {
+    use crate::path::{IpfsPath, PathRoot};
+    use crate::{MultiaddrWithPeerId, MultiaddrWithoutPeerId};
+    use cid::Cid;
+    use clap::builder::ValueParser;
+    use libp2p::{Multiaddr, PeerId};
+    use std::convert::TryFrom;
+
+    pub fn cid() -> ValueParser {
+        ValueParser::new(|s: &str| Cid::try_from(s))
+    }
+
+    pub fn peer_id() -> ValueParser {
+        ValueParser::new(|s: &str| s.parse::<PeerId>())
+    }
+
+    pub fn multiaddr() -> ValueParser {
+        ValueParser::new(|s: &str| s.parse::<Multiaddr>())
+    }
+
+    pub fn multiaddr_with_peer_id() -> ValueParser {
+        ValueParser::new(|s: &str| s.parse::<MultiaddrWithPeerId>())
+    }
+
+    pub fn multiaddr_without_peer_id() -> ValueParser {
+        ValueParser::new(|s: &str| s.parse::<MultiaddrWithoutPeerId>())
+    }
+
+    pub fn ipfs_path() -> ValueParser {
+        ValueParser::new(|s: &str| s.parse::<IpfsPath>())
+    }
+
+    pub fn path_root() -> ValueParser {
+        ValueParser::new(|s: &str| s.parse::<PathRoot>())
+    }
+}
+
+#[cfg(all(test, feature = "serde_support"))]
+mod tests {
+    use crate::{IpfsPath, MultiaddrWithPeerId};
+    use cid::Cid;
+    use libp2p::{Multiaddr, PeerId};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Config {
+        #[serde(with = "crate::convert::cid")]
+        root: Cid,
+        #[serde(with = "crate::convert::peer_id")]
+        peer: PeerId,
+        #[serde(with = "crate::convert::multiaddr")]
+        listen: Multiaddr,
+        bootstrap: MultiaddrWithPeerId,
+        path: IpfsPath,
+    }
+
+    #[test]
+    fn human_readable_strings() {
+        let json = serde_json::json!({
+            "root": "QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n",
+            "peer": "QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
+            "listen": "/ip4/127.0.0.1/tcp/4001",
+            "bootstrap": "/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
+            "path": "/ipfs/QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n/a/b",
+        });
+
+        let config: Config = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(config.bootstrap.peer_id, config.peer);
+        assert_eq!(config.path.root().cid(), Some(&config.root));
+
+        assert_eq!(serde_json::to_value(&config).unwrap(), json);
+    }
+
+    #[test]
+    fn invalid_strings_are_rejected() {
+        let json = serde_json::json!("/ip4/127.0.0.1/tcp/4001");
+        assert!(serde_json::from_value::<MultiaddrWithPeerId>(json).is_err());
+
+        let json = serde_json::json!("not a path");
+        assert!(serde_json::from_value::<IpfsPath>(json).is_err());
+    }
+}
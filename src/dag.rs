@@ -1,11 +1,14 @@
 //! `ipfs.dag` interface implementation around [`Ipfs`].
 
+use crate::car::{CarError, CarExport, Selector};
 use crate::error::Error;
-use crate::ipld::{decode_ipld, encode_ipld, Ipld};
+use crate::ipld::{decode_ipld, CodecRegistry, Ipld};
 use crate::path::{IpfsPath, SlashedPath};
 use crate::repo::RepoTypes;
+use crate::retrieval::RetrievalStrategy;
 use crate::{Block, Ipfs};
 use cid::{Cid, Codec, Version};
+use futures::stream::Stream;
 use ipfs_unixfs::{
     dagpb::{wrap_node_data, NodeData},
     dir::{Cache, ShardedLookup},
@@ -14,6 +17,7 @@ use ipfs_unixfs::{
 use std::convert::TryFrom;
 use std::error::Error as StdError;
 use std::iter::Peekable;
+use std::sync::Arc;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -48,6 +52,11 @@ pub enum ResolveError {
     #[error("no link named {:?} under {0}", .1.iter().last().unwrap())]
     NotFound(Cid, SlashedPath),
 
+    /// Path attempted to resolve through a UnixFS symlink, which is not followed. The path ends in
+    /// the symlink, and the target is the path contained in it.
+    #[error("path goes through the symlink {0} to {2:?}")]
+    Symlink(Cid, SlashedPath, String),
+
     /// Tried to use a path neiter containing nor resolving to a Cid.
     #[error("the path neiter contains nor resolves to a Cid")]
     NoCid(IpfsPath),
@@ -88,6 +97,11 @@ enum RawResolveLocalError {
         document: Cid,
         segment_index: usize,
     },
+    Symlink {
+        document: Cid,
+        segment_index: usize,
+        target: String,
+    },
 }
 
 impl RawResolveLocalError {
@@ -112,6 +126,10 @@ impl RawResolveLocalError {
             | NotFound {
                 ref mut segment_index,
                 ..
+            }
+            | Symlink {
+                ref mut segment_index,
+                ..
             } => {
                 // NOTE: this is the **index** compared to the number of segments matched, i.e. **count**
                 // from `resolve_local`'s Ok return value.
@@ -154,6 +172,11 @@ impl RawResolveLocalError {
                 document,
                 segment_index,
             } => ResolveError::NotFound(document, path.into_truncated(segment_index + 1)),
+            Symlink {
+                document,
+                segment_index,
+                target,
+            } => ResolveError::Symlink(document, path.into_truncated(segment_index), target),
         }
     }
 }
@@ -162,20 +185,38 @@ impl RawResolveLocalError {
 #[derive(Clone, Debug)]
 pub struct IpldDag<Types: RepoTypes> {
     ipfs: Ipfs<Types>,
+    /// How the missing blocks are fetched, or the defaults of [`Ipfs::get_block`].
+    strategy: Option<Arc<dyn RetrievalStrategy>>,
 }
 
 impl<Types: RepoTypes> IpldDag<Types> {
     /// Creates a new `IpldDag` for DAG operations.
     // FIXME: duplicates Ipfs::dag(), having both is redundant.
     pub fn new(ipfs: Ipfs<Types>) -> Self {
-        IpldDag { ipfs }
+        IpldDag {
+            ipfs,
+            strategy: None,
+        }
+    }
+
+    /// Fetches the missing blocks following the `strategy`, see [`crate::retrieval`].
+    pub fn with_strategy(mut self, strategy: Arc<dyn RetrievalStrategy>) -> Self {
+        self.strategy = Some(strategy);
+        self
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Block, Error> {
+        match &self.strategy {
+            Some(strategy) => self.ipfs.get_block_with(cid, &**strategy).await,
+            None => self.ipfs.repo.get_block(cid).await,
+        }
     }
 
     /// Returns the `Cid` of a newly inserted block.
     ///
     /// The block is created from the `data`, encoded with the `codec` and inserted into the repo.
     pub async fn put(&self, data: Ipld, codec: Codec) -> Result<Cid, Error> {
-        let bytes = encode_ipld(&data, codec)?;
+        let bytes = self.ipfs.codecs.encode(&data, codec)?;
         let hash = multihash::Sha2_256::digest(&bytes);
         let version = if codec == Codec::DagProtobuf {
             Version::V0
@@ -188,13 +229,25 @@ impl<Types: RepoTypes> IpldDag<Types> {
         Ok(cid)
     }
 
+    /// Returns the CARv1 stream of the blocks selected by the `selector` from the DAG rooted at
+    /// `root`, like `ipfs dag export`. See [`crate::car`] for more options.
+    pub fn export(
+        &self,
+        root: Cid,
+        selector: Selector,
+    ) -> impl Stream<Item = Result<Vec<u8>, CarError>> + Send + 'static {
+        CarExport::default()
+            .with_selector(selector)
+            .export(self.ipfs.clone(), root)
+    }
+
     /// Resolves a `Cid`-rooted path to a document "node."
     ///
     /// Returns the resolved node as `Ipld`.
     pub async fn get(&self, path: IpfsPath) -> Result<Ipld, ResolveError> {
         let resolved_path = self
             .ipfs
-            .resolve_ipns(&path, true)
+            .resolve_ipns_cached(&path, true)
             .await
             .map_err(|_| ResolveError::IpnsResolutionFailed(path))?;
 
@@ -205,7 +258,7 @@ impl<Types: RepoTypes> IpldDag<Types> {
 
         let mut iter = resolved_path.iter().peekable();
 
-        let (node, _) = match self.resolve0(cid, &mut iter, true).await {
+        let (node, _) = match self.resolve0(cid, &mut iter, true, false).await {
             Ok(t) => t,
             Err(e) => {
                 drop(iter);
@@ -213,7 +266,68 @@ impl<Types: RepoTypes> IpldDag<Types> {
             }
         };
 
-        Ipld::try_from(node)
+        match node {
+            ResolvedNode::Block(block) => self
+                .ipfs
+                .codecs
+                .decode(block.cid(), block.data())
+                .map_err(move |e| ResolveError::UnsupportedDocument(block.cid, e.into())),
+            node => Ipld::try_from(node),
+        }
+    }
+
+    /// Like [`IpldDag::get`], but resolving stops at the first raw block or block of an unknown
+    /// codec which the path would need to continue through, instead of failing.
+    ///
+    /// Returns the value where the resolving stopped along with the unresolved remainder of the
+    /// path, which is empty if the whole path was resolved. The value of a raw block is
+    /// [`Ipld::Bytes`], and the value of a block of an unknown codec is the [`Ipld::Link`] to it.
+    pub async fn get_partial(&self, path: IpfsPath) -> Result<(Ipld, SlashedPath), ResolveError> {
+        let resolved_path = self
+            .ipfs
+            .resolve_ipns_cached(&path, true)
+            .await
+            .map_err(|_| ResolveError::IpnsResolutionFailed(path))?;
+
+        let cid = match resolved_path.root().cid() {
+            Some(cid) => cid,
+            None => return Err(ResolveError::NoCid(resolved_path)),
+        };
+
+        let (node, matched_segments) = {
+            let mut iter = resolved_path.iter().peekable();
+            match self.resolve0(cid, &mut iter, true, true).await {
+                Ok(t) => t,
+                Err(e) => {
+                    drop(iter);
+                    return Err(e.with_path(resolved_path));
+                }
+            }
+        };
+
+        match node {
+            ResolvedNode::Block(block) => {
+                // blocks are only returned at the end of the path or where the resolving stopped
+                let remainder = resolved_path.into_shifted(matched_segments);
+
+                // the unknown codecs cannot be decoded, unlike raw
+                let codec = block.cid().codec();
+                if !remainder.is_empty()
+                    && codec != Codec::Raw
+                    && !self.ipfs.codecs.is_traversable(codec)
+                {
+                    return Ok((Ipld::Link(block.cid), remainder));
+                }
+
+                let ipld = self
+                    .ipfs
+                    .codecs
+                    .decode(block.cid(), block.data())
+                    .map_err(move |e| ResolveError::UnsupportedDocument(block.cid, e.into()))?;
+                Ok((ipld, remainder))
+            }
+            node => Ok((Ipld::try_from(node)?, SlashedPath::default())),
+        }
     }
 
     /// Resolves a `Cid`-rooted path to a document "node."
@@ -234,7 +348,7 @@ impl<Types: RepoTypes> IpldDag<Types> {
     ) -> Result<(ResolvedNode, SlashedPath), ResolveError> {
         let resolved_path = self
             .ipfs
-            .resolve_ipns(&path, true)
+            .resolve_ipns_cached(&path, true)
             .await
             .map_err(|_| ResolveError::IpnsResolutionFailed(path))?;
 
@@ -245,7 +359,7 @@ impl<Types: RepoTypes> IpldDag<Types> {
 
         let (node, matched_segments) = {
             let mut iter = resolved_path.iter().peekable();
-            match self.resolve0(cid, &mut iter, follow_links).await {
+            match self.resolve0(cid, &mut iter, follow_links, false).await {
                 Ok(t) => t,
                 Err(e) => {
                     drop(iter);
@@ -262,12 +376,15 @@ impl<Types: RepoTypes> IpldDag<Types> {
         Ok((node, remaining_path))
     }
 
-    /// Return the node where the resolving ended, and the **count** of segments matched.
+    /// Return the node where the resolving ended, and the **count** of segments matched. With
+    /// `stop_at_opaque`, the resolving ends in the block which cannot be resolved through, with the
+    /// count of the segments matched before it.
     async fn resolve0<'a>(
         &self,
         cid: &Cid,
         segments: &mut Peekable<impl Iterator<Item = &'a str>>,
         follow_links: bool,
+        stop_at_opaque: bool,
     ) -> Result<(ResolvedNode, usize), RawResolveLocalError> {
         use LocallyResolved::*;
 
@@ -277,20 +394,28 @@ impl<Types: RepoTypes> IpldDag<Types> {
         let mut cache = None;
 
         loop {
-            let block = match self.ipfs.repo.get_block(&current).await {
+            let block = match self.get_block(&current).await {
                 Ok(block) => block,
                 Err(e) => return Err(RawResolveLocalError::Loading(current, e)),
             };
 
             let start = total;
 
-            let (resolution, matched) = match resolve_local(block, segments, &mut cache) {
-                Ok(t) => t,
-                Err(mut e) => {
-                    e.add_starting_point_in_path(start);
-                    return Err(e);
-                }
-            };
+            if stop_at_opaque
+                && segments.peek().is_some()
+                && !self.ipfs.codecs.is_traversable(block.cid().codec())
+            {
+                return Ok((ResolvedNode::Block(block), start));
+            }
+
+            let (resolution, matched) =
+                match resolve_local(block, segments, &mut cache, &self.ipfs.codecs) {
+                    Ok(t) => t,
+                    Err(mut e) => {
+                        e.add_starting_point_in_path(start);
+                        return Err(e);
+                    }
+                };
             total += matched;
 
             let (src, dest) = match resolution {
@@ -327,12 +452,13 @@ impl<Types: RepoTypes> IpldDag<Types> {
         loop {
             let (next, _) = lookup.pending_links();
 
-            let block = self.ipfs.repo.get_block(next).await?;
+            let block = self.get_block(next).await?;
 
             match lookup.continue_walk(block.data(), cache)? {
                 NeedToLoadMore(next) => lookup = next,
                 Found(cid) => return Ok(cid),
                 NotFound => return Err(anyhow::anyhow!("key not found: ???")),
+                Symlink(_) => unreachable!("continue_walk only returns links from the buckets"),
             }
         }
     }
@@ -432,6 +558,7 @@ fn resolve_local<'a>(
     block: Block,
     segments: &mut Peekable<impl Iterator<Item = &'a str>>,
     cache: &mut Option<Cache>,
+    codecs: &CodecRegistry,
 ) -> Result<(LocallyResolved<'a>, usize), RawResolveLocalError> {
     if segments.peek().is_none() {
         return Ok((LocallyResolved::Complete(ResolvedNode::Block(block)), 0));
@@ -457,7 +584,7 @@ fn resolve_local<'a>(
             cache,
         )?)
     } else {
-        let ipld = match decode_ipld(&cid, &data) {
+        let ipld = match codecs.decode(&cid, &data) {
             Ok(ipld) => ipld,
             Err(e) => return Err(RawResolveLocalError::UnsupportedDocument(cid, e.into())),
         };
@@ -495,9 +622,18 @@ fn resolve_local_dagpb<'a>(
                 segment_index: 0,
             })
         }
+        Ok(MaybeResolved::Symlink(target)) => {
+            // like go-ipfs, the symlinks are not followed while resolving, but the target is
+            // returned for the caller to decide
+            Err(RawResolveLocalError::Symlink {
+                document: cid,
+                segment_index: 0,
+                target: String::from_utf8_lossy(&target).into_owned(),
+            })
+        }
         Err(ipfs_unixfs::ResolveError::UnexpectedType(ut)) if ut.is_file() => {
-            // this might even be correct: files we know are not supported, however not sure if
-            // symlinks are, let alone custom unxifs types should such exist
+            // this might even be correct: files we know are not supported, let alone custom
+            // unxifs types should such exist
             Err(RawResolveLocalError::NotFound {
                 document: cid,
                 segment_index: 0,
@@ -666,6 +802,51 @@ mod tests {
         assert_eq!(res, make_ipld!(1));
     }
 
+    #[tokio::test]
+    async fn test_resolve_through_maps_and_lists() {
+        let Node { ipfs, .. } = Node::new("test_node").await;
+        let data = make_ipld!({
+            "a": {
+                "b": ["first", "second"],
+            },
+        });
+        let cid = ipfs.put_dag(data).await.unwrap();
+        let res = ipfs
+            .get_dag(IpfsPath::from(cid).sub_path("a/b/0").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res, make_ipld!("first"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_through_registered_codec() {
+        let mut opts = crate::IpfsOptions::inmemory_with_generated_keys();
+        opts.codecs
+            .register(
+                Codec::GitRaw.into(),
+                |ipld| DagCborCodec::encode(ipld).map_err(Into::into),
+                |data| DagCborCodec::decode(data).map_err(Into::into),
+            )
+            .unwrap();
+        let Node { ipfs, .. } = Node::with_options(opts).await;
+
+        let linked = ipfs.put_dag(make_ipld!("target")).await.unwrap();
+        let data = make_ipld!({
+            "a": [linked],
+        });
+        let cid = ipfs.dag().put(data.clone(), Codec::GitRaw).await.unwrap();
+        assert_eq!(cid.codec(), Codec::GitRaw);
+
+        let res = ipfs.get_dag(IpfsPath::from(cid.clone())).await.unwrap();
+        assert_eq!(res, data);
+
+        let res = ipfs
+            .get_dag(IpfsPath::from(cid).sub_path("a/0").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res, make_ipld!("target"));
+    }
+
     /// Returns an example ipld document with strings, ints, maps, lists, and a link. The link target is also
     /// returned.
     fn example_doc_and_cid() -> (Cid, Ipld, Cid) {
@@ -874,6 +1055,31 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn get_partial_stops_at_raw_block() {
+        let Node { ipfs, .. } = Node::new("test_node").await;
+        let dag = IpldDag::new(ipfs.clone());
+
+        let data = b"raw tail".to_vec().into_boxed_slice();
+        let raw = Cid::new_v1(Codec::Raw, multihash::Sha2_256::digest(&data));
+        ipfs.put_block(Block::new(data, raw.clone())).await.unwrap();
+
+        let cid = dag
+            .put(make_ipld!({ "a": { "b": raw } }), Codec::DagCBOR)
+            .await
+            .unwrap();
+
+        let path = IpfsPath::from(cid.clone()).sub_path("a/b/c/0").unwrap();
+        let (ipld, remainder) = dag.get_partial(path).await.unwrap();
+        assert_eq!(ipld, Ipld::Bytes(b"raw tail".to_vec()));
+        assert_eq!(remainder, ["c", "0"][..]);
+
+        let path = IpfsPath::from(cid).sub_path("a").unwrap();
+        let (ipld, remainder) = dag.get_partial(path).await.unwrap();
+        assert_eq!(ipld, make_ipld!({ "b": raw }));
+        assert!(remainder.is_empty());
+    }
+
     #[tokio::test]
     async fn fail_resolving_first_segment() {
         let Node { ipfs, .. } = Node::new("test_node").await;
@@ -997,6 +1203,46 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn fail_resolving_through_symlink() {
+        let Node { ipfs, .. } = Node::new("test_node").await;
+
+        let mut tree = ipfs_unixfs::dir::builder::BufferingTreeBuilder::default();
+        let (cid, data) = tree.put_symlink("something/link", "../elsewhere").unwrap();
+
+        ipfs.put_block(Block {
+            cid: cid.clone(),
+            data: data.into(),
+        })
+        .await
+        .unwrap();
+
+        let mut root = None;
+        for node in tree.build() {
+            let node = node.unwrap();
+            root = Some(node.cid.clone());
+            ipfs.put_block(Block {
+                cid: node.cid,
+                data: node.block,
+            })
+            .await
+            .unwrap();
+        }
+
+        let path = IpfsPath::from(root.unwrap())
+            .sub_path("link/anything-here")
+            .unwrap();
+
+        match ipfs.dag().resolve(path, true).await.unwrap_err() {
+            ResolveError::Symlink(document, path, target) => {
+                assert_eq!(document, cid);
+                assert_eq!(path.to_string(), "link");
+                assert_eq!(target, "../elsewhere");
+            }
+            e => unreachable!("{:?}", e),
+        }
+    }
+
     #[test]
     fn observes_strict_order_of_map_keys() {
         let map = make_ipld!({
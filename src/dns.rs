@@ -0,0 +1,257 @@
+//! The resolution of the DNS names of the DNSLinks and of the `/dns4`, `/dns6` and `/dnsaddr`
+//! multiaddresses, including the ones of the bootstrap peers, see [`DnsResolver`].
+//!
+//! The `/dnsaddr` bootstrap peers are resolved into the concrete addresses before they are added
+//! to the DHT, following the TXT records of the [dnsaddr] domains recursively.
+//!
+//! [dnsaddr]: https://github.com/multiformats/multiaddr/blob/master/protocols/DNSADDR.md
+
+use crate::p2p::MultiaddrWithPeerId;
+use libp2p::multiaddr::{Multiaddr, Protocol};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// The maximum nesting of the `/dnsaddr` records.
+const MAX_DNSADDR_DEPTH: usize = 8;
+
+/// How all of the DNS names are resolved, given in [`crate::IpfsOptions::dns`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnsResolver {
+    /// With the system configuration, such as `/etc/resolv.conf` on unix.
+    System,
+    /// With DNS over HTTPS, for not revealing the names to the network and for working around
+    /// broken system resolvers.
+    OverHttps {
+        /// The addresses of the DoH server, contacted on port 443. Given as addresses, as the
+        /// name of the server cannot be resolved without a resolver.
+        servers: Vec<IpAddr>,
+        /// The name of the server, verified against its TLS certificate.
+        tls_name: String,
+    },
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        DnsResolver::System
+    }
+}
+
+impl DnsResolver {
+    /// DNS over HTTPS with the Cloudflare servers.
+    pub fn cloudflare() -> Self {
+        DnsResolver::OverHttps {
+            servers: vec![
+                Ipv4Addr::new(1, 1, 1, 1).into(),
+                Ipv4Addr::new(1, 0, 0, 1).into(),
+            ],
+            tls_name: "cloudflare-dns.com".into(),
+        }
+    }
+
+    /// DNS over HTTPS with the Google servers.
+    pub fn google() -> Self {
+        DnsResolver::OverHttps {
+            servers: vec![
+                Ipv4Addr::new(8, 8, 8, 8).into(),
+                Ipv4Addr::new(8, 8, 4, 4).into(),
+            ],
+            tls_name: "dns.google".into(),
+        }
+    }
+
+    /// Returns the configuration of the resolver, reading the system configuration for
+    /// [`DnsResolver::System`].
+    pub(crate) fn config(&self) -> io::Result<(ResolverConfig, ResolverOpts)> {
+        match self {
+            DnsResolver::System => trust_dns_resolver::system_conf::read_system_conf()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+            DnsResolver::OverHttps { servers, tls_name } => {
+                let name_servers =
+                    NameServerConfigGroup::from_ips_https(servers, 443, tls_name.to_owned(), true);
+                let config = ResolverConfig::from_parts(None, Vec::new(), name_servers);
+                Ok((config, ResolverOpts::default()))
+            }
+        }
+    }
+
+    /// Creates the resolver for the DNSLink lookups.
+    pub(crate) fn resolver(&self) -> io::Result<TokioAsyncResolver> {
+        let (config, opts) = self.config()?;
+        TokioAsyncResolver::tokio(config, opts).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+impl FromStr for DnsResolver {
+    type Err = String;
+
+    /// Parses `system`, `cloudflare` or `google`.
+    fn from_str(resolver: &str) -> Result<Self, Self::Err> {
+        match resolver {
+            "system" => Ok(DnsResolver::System),
+            "cloudflare" => Ok(DnsResolver::cloudflare()),
+            "google" => Ok(DnsResolver::google()),
+            _ => Err(format!("unsupported dns resolver: {:?}", resolver)),
+        }
+    }
+}
+
+/// Caches the `/dnsaddr` records of the domains for as long as they are valid.
+#[derive(Debug, Default)]
+pub(crate) struct DnsaddrCache {
+    records: Mutex<HashMap<String, (Instant, Vec<Multiaddr>)>>,
+}
+
+impl DnsaddrCache {
+    /// Resolves the `/dnsaddr` address recursively into the concrete addresses of the same peer.
+    /// The other addresses are returned as they are.
+    pub(crate) async fn resolve(
+        &self,
+        dns: &DnsResolver,
+        addr: MultiaddrWithPeerId,
+    ) -> io::Result<Vec<MultiaddrWithPeerId>> {
+        let mut resolved = Vec::new();
+        let mut pending = vec![(addr, 0)];
+
+        while let Some((addr, depth)) = pending.pop() {
+            let domain = match dnsaddr_domain(&addr) {
+                Some(domain) => domain,
+                None => {
+                    resolved.push(addr);
+                    continue;
+                }
+            };
+
+            if depth == MAX_DNSADDR_DEPTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("too deeply nested dnsaddr records under {}", addr),
+                ));
+            }
+
+            for record in self.records(dns, &domain).await? {
+                // the records of the other peers under the same domain are skipped
+                match MultiaddrWithPeerId::try_from(record) {
+                    Ok(record) if record.peer_id == addr.peer_id => {
+                        pending.push((record, depth + 1))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// Returns the addresses in the `dnsaddr` TXT records of the `domain`.
+    async fn records(&self, dns: &DnsResolver, domain: &str) -> io::Result<Vec<Multiaddr>> {
+        if let Some((valid_until, records)) = self.records.lock().unwrap().get(domain) {
+            if *valid_until > Instant::now() {
+                return Ok(records.clone());
+            }
+        }
+
+        let lookup = dns
+            .resolver()?
+            .txt_lookup(format!("_dnsaddr.{}", domain))
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        let records = lookup
+            .iter()
+            .filter_map(|txt| {
+                let data = txt.txt_data().concat();
+                let record = std::str::from_utf8(&data).ok()?;
+                record.strip_prefix("dnsaddr=")?.parse().ok()
+            })
+            .collect::<Vec<Multiaddr>>();
+
+        self.records
+            .lock()
+            .unwrap()
+            .insert(domain.to_owned(), (lookup.valid_until(), records.clone()));
+
+        Ok(records)
+    }
+}
+
+/// Returns the domain of the `/dnsaddr` component of the address, if any.
+fn dnsaddr_domain(addr: &MultiaddrWithPeerId) -> Option<String> {
+    addr.multiaddr.as_ref().iter().find_map(|p| match p {
+        Protocol::Dnsaddr(domain) => Some(domain.into_owned()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DnsResolver, DnsaddrCache};
+    use crate::p2p::MultiaddrWithPeerId;
+
+    #[test]
+    fn resolvers_are_parsed_by_name() {
+        assert_eq!("system".parse(), Ok(DnsResolver::System));
+        assert_eq!("google".parse(), Ok(DnsResolver::google()));
+        assert!("8.8.8.8".parse::<DnsResolver>().is_err());
+    }
+
+    #[test]
+    fn over_https_uses_only_the_given_servers() {
+        let (config, _) = DnsResolver::cloudflare().config().unwrap();
+
+        assert!(config.domain().is_none());
+        assert_eq!(config.name_servers().len(), 2);
+        assert!(config
+            .name_servers()
+            .iter()
+            .all(|ns| ns.socket_addr.port() == 443
+                && ns.tls_dns_name.as_deref() == Some("cloudflare-dns.com")));
+    }
+
+    #[tokio::test]
+    async fn concrete_addresses_are_not_resolved() {
+        let addr =
+            "/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ"
+                .parse::<MultiaddrWithPeerId>()
+                .unwrap();
+
+        let resolved = DnsaddrCache::default()
+            .resolve(&DnsResolver::System, addr.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(resolved, vec![addr]);
+    }
+
+    #[ignore = "targets the actual bootstrap.libp2p.io records, which can change"]
+    #[tokio::test]
+    async fn resolves_bootstrap_dnsaddr() {
+        let addr =
+            "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN"
+                .parse::<MultiaddrWithPeerId>()
+                .unwrap();
+        let cache = DnsaddrCache::default();
+
+        let resolved = cache
+            .resolve(&DnsResolver::System, addr.clone())
+            .await
+            .unwrap();
+
+        assert!(!resolved.is_empty());
+        assert!(resolved
+            .iter()
+            .all(|resolved| resolved.peer_id == addr.peer_id
+                && super::dnsaddr_domain(resolved).is_none()));
+        assert!(cache
+            .records
+            .lock()
+            .unwrap()
+            .contains_key("bootstrap.libp2p.io"));
+    }
+}
@@ -0,0 +1,202 @@
+//! Repairing the repo after the mutations interrupted by a crash.
+//!
+//! The multi-step repo mutations are recorded in a [`crate::repo::Journal`] before they are
+//! started. The ones never completed are found when the repo is opened and are repaired to a
+//! consistent state by [`Ipfs::repo_fsck`], which is also run when the node is started:
+//!
+//! * written blocks are re-hashed and removed when corrupted
+//! * block removals are completed, unless the block has been pinned since
+//! * partially written recursive pins are rolled back, requiring the pin to be added again
+//! * recursive unpins are completed
+
+use crate::repo::{Mutation, PinKind, PinMode};
+use crate::scrub::verify;
+use crate::{Error, Ipfs, IpfsTypes};
+use cid::Cid;
+
+/// A repair performed by [`Ipfs::repo_fsck`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Repair {
+    /// A block was corrupted while being written and was removed.
+    RemovedCorruptedBlock(Cid),
+    /// An interrupted block removal was completed.
+    CompletedBlockRemoval(Cid),
+    /// A partially written recursive pin was removed; the pin needs to be added again.
+    RolledBackRecursivePin(Cid),
+    /// An interrupted recursive unpin was completed.
+    CompletedRecursiveUnpin(Cid),
+    /// Repairing failed. The mutation is retried on the next [`Ipfs::repo_fsck`].
+    Failed(Mutation, String),
+}
+
+/// Outcome of [`Ipfs::repo_fsck`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Number of the interrupted mutations checked.
+    pub checked: usize,
+    /// The repairs performed, or attempted.
+    pub repairs: Vec<Repair>,
+}
+
+impl FsckReport {
+    /// Returns true if nothing needed to be repaired.
+    pub fn is_clean(&self) -> bool {
+        self.repairs.is_empty()
+    }
+}
+
+impl<Types: IpfsTypes> Ipfs<Types> {
+    /// Checks and repairs the repo after the mutations interrupted by a crash, see the
+    /// [module documentation](crate::fsck). This is already done when the node is started, so
+    /// calling this is only necessary to retry the failed repairs.
+    pub async fn repo_fsck(&self) -> FsckReport {
+        let mut report = FsckReport::default();
+
+        for (id, mutation) in self.repo.take_unfinished() {
+            report.checked += 1;
+
+            let repaired = match repair(self, &mutation).await {
+                Ok(Some(repair)) => {
+                    report.repairs.push(repair);
+                    true
+                }
+                Ok(None) => true,
+                Err(e) => {
+                    warn!("failed to repair {:?}: {}", mutation, e);
+                    report
+                        .repairs
+                        .push(Repair::Failed(mutation.clone(), e.to_string()));
+                    false
+                }
+            };
+
+            self.repo.finish_unfinished(id, mutation, repaired);
+        }
+
+        report
+    }
+}
+
+async fn repair<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    mutation: &Mutation,
+) -> Result<Option<Repair>, Error> {
+    Ok(match mutation {
+        Mutation::PutBlock(cid) => match ipfs.repo.get_block_now(cid).await? {
+            Some(block) if !verify(cid, &block.data) => {
+                ipfs.repo.remove_corrupted_block(cid).await?;
+                Some(Repair::RemovedCorruptedBlock(cid.clone()))
+            }
+            _ => None,
+        },
+        Mutation::RemoveBlock(cid) => {
+            let exists = ipfs.repo.get_block_now(cid).await?.is_some();
+            if exists && !ipfs.repo.is_pinned(cid).await? {
+                ipfs.repo.remove_block(cid).await?;
+                Some(Repair::CompletedBlockRemoval(cid.clone()))
+            } else {
+                None
+            }
+        }
+        Mutation::InsertRecursivePin(cid) => match recursive_pin(ipfs, cid).await? {
+            Some(PinKind::RecursiveIntention) => {
+                ipfs.remove_pin(cid, true).await?;
+                Some(Repair::RolledBackRecursivePin(cid.clone()))
+            }
+            _ => None,
+        },
+        Mutation::RemoveRecursivePin(cid) => match recursive_pin(ipfs, cid).await? {
+            Some(_) => {
+                ipfs.remove_pin(cid, true).await?;
+                Some(Repair::CompletedRecursiveUnpin(cid.clone()))
+            }
+            None => None,
+        },
+    })
+}
+
+/// Returns the kind of the recursive pin on the Cid, if it is pinned recursively.
+async fn recursive_pin<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    cid: &Cid,
+) -> Result<Option<PinKind<Cid>>, Error> {
+    if !ipfs.repo.is_pinned(cid).await? {
+        return Ok(None);
+    }
+
+    // the query fails if the cid is pinned, but not recursively
+    match ipfs
+        .repo
+        .query_pins(vec![cid.clone()], Some(PinMode::Recursive))
+        .await
+    {
+        Ok(mut found) => Ok(found.pop().map(|(_, kind)| kind)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FsckReport, Repair};
+    use crate::repo::Mutation;
+    use crate::{Block, Node};
+    use cid::{Cid, Codec};
+    use multihash::Sha2_256;
+
+    #[tokio::test]
+    async fn clean_repo() {
+        let ipfs = Node::new("test_node").await;
+        assert_eq!(ipfs.repo_fsck().await, FsckReport::default());
+    }
+
+    #[tokio::test]
+    async fn repairs_interrupted_mutations() {
+        let ipfs = Node::new("test_node").await;
+
+        let corrupted = Cid::new_v1(Codec::Raw, Sha2_256::digest(b"expected"));
+        // the blocks are not verified when put
+        ipfs.put_block(Block::new(b"actual".to_vec().into(), corrupted.clone()))
+            .await
+            .unwrap();
+
+        let data = b"to be removed".to_vec().into_boxed_slice();
+        let removed = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data));
+        ipfs.put_block(Block::new(data, removed.clone()))
+            .await
+            .unwrap();
+
+        let data = b"pinned meanwhile".to_vec().into_boxed_slice();
+        let pinned = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data));
+        ipfs.put_block(Block::new(data, pinned.clone()))
+            .await
+            .unwrap();
+        ipfs.insert_pin(&pinned, false).await.unwrap();
+
+        for (id, mutation) in vec![
+            Mutation::PutBlock(corrupted.clone()),
+            Mutation::RemoveBlock(removed.clone()),
+            Mutation::RemoveBlock(pinned.clone()),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            ipfs.repo.finish_unfinished(id as u64, mutation, false);
+        }
+
+        let report = ipfs.repo_fsck().await;
+        assert_eq!(report.checked, 3);
+        assert_eq!(
+            report.repairs,
+            vec![
+                Repair::RemovedCorruptedBlock(corrupted.clone()),
+                Repair::CompletedBlockRemoval(removed.clone()),
+            ]
+        );
+
+        assert!(ipfs.repo.get_block_now(&corrupted).await.unwrap().is_none());
+        assert!(ipfs.repo.get_block_now(&removed).await.unwrap().is_none());
+        assert!(ipfs.repo.get_block_now(&pinned).await.unwrap().is_some());
+
+        assert_eq!(ipfs.repo_fsck().await, FsckReport::default());
+    }
+}
@@ -0,0 +1,404 @@
+//! Mark-and-sweep garbage collection of the blockstore, see [`Ipfs::gc`].
+//!
+//! The blocks kept are the pinned ones, including the indirectly pinned blocks of the recursive
+//! pins, and the blocks reachable from the roots of the node: the [`crate::mfs`] root and the
+//! head of the [`crate::config::history`]. All of the other blocks are removed. The blocks are
+//! told apart by their multihashes, as the blockstores may list them with another version or
+//! codec of the Cid than they were pinned with. The blocks added without copying, see
+//! [`crate::repo::filestore`], are collected the same way, leaving their files alone.
+//!
+//! The marking runs concurrently with the normal use of the node. While it runs, the blocks put
+//! into the repo are recorded by a write barrier, and are kept along with the blocks they link
+//! to. The collection waits for the [`GcSession`]s started earlier, like the adds pinning their
+//! results, before it starts marking. The unmarked blocks are then removed in batches, each
+//! removed after marking from the pins and the blocks recorded in the meantime with the puts and
+//! pin insertions held off, so that the puts only wait for a single batch while the reads are
+//! never blocked.
+//!
+//! Only the blocks available locally are traversed, so that the missing blocks of a partially
+//! fetched DAG are not fetched by the collection.
+
+use crate::error::Error;
+use crate::{Ipfs, IpfsTypes};
+use cid::Cid;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedRwLockReadGuard, RwLock, RwLockReadGuard};
+use tracing_futures::Instrument;
+
+/// How many of the unmarked blocks are removed while the puts are held off.
+const SWEEP_BATCH: usize = 256;
+
+/// The write barrier of the garbage collector, held by the repo.
+#[derive(Debug, Default)]
+pub(crate) struct GcBarrier {
+    /// Held for reading by the puts and the pin insertions, and for writing by the collector
+    /// while it finishes the marking and removes a batch of the unmarked blocks.
+    lock: RwLock<()>,
+    /// Held for reading by the [`GcSession`]s, and for writing by the collector before it starts
+    /// marking.
+    sessions: Arc<RwLock<()>>,
+    /// The blocks put during the collection, or `None` when no collection is running.
+    recorded: Mutex<Option<Vec<Cid>>>,
+    /// Only one collection runs at a time.
+    running: tokio::sync::Mutex<()>,
+}
+
+/// Keeps the garbage collection from starting until dropped, so that the blocks put before the
+/// collection starts can be pinned without a collection removing them in between. See
+/// [`Ipfs::gc_session`].
+#[derive(Debug)]
+pub struct GcSession {
+    _guard: OwnedRwLockReadGuard<()>,
+}
+
+impl GcBarrier {
+    /// Waits for the running collection to finish removing a batch of blocks, and keeps it from
+    /// starting to remove the next one until the returned guard is dropped.
+    pub(crate) async fn enter(&self) -> RwLockReadGuard<'_, ()> {
+        self.lock.read().await
+    }
+
+    /// Records the block put or the root of the pin inserted while a collection is running, so
+    /// that the blocks reachable from it are kept. Needs to be called while holding the guard
+    /// from [`GcBarrier::enter`].
+    pub(crate) fn record(&self, cid: &Cid) {
+        if let Some(recorded) = self.recorded.lock().unwrap().as_mut() {
+            recorded.push(cid.to_owned());
+        }
+    }
+
+    fn take_recorded(&self) -> Vec<Cid> {
+        self.recorded
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+/// Stops the recording of the puts when the collection ends, also when it fails.
+struct Recording<'a>(&'a GcBarrier);
+
+impl<'a> Recording<'a> {
+    fn start(barrier: &'a GcBarrier) -> Self {
+        *barrier.recorded.lock().unwrap() = Some(Vec::new());
+        Recording(barrier)
+    }
+}
+
+impl Drop for Recording<'_> {
+    fn drop(&mut self) {
+        *self.0.recorded.lock().unwrap() = None;
+    }
+}
+
+/// The blocks marked to be kept, by their multihashes.
+type Marked = HashSet<Vec<u8>>;
+
+fn key(cid: &Cid) -> Vec<u8> {
+    cid.hash().as_bytes().to_vec()
+}
+
+impl<Types: IpfsTypes> Ipfs<Types> {
+    /// Removes the blocks which are neither pinned nor reachable from the roots of the node, see
+    /// [`crate::gc`]. Returns the removed blocks.
+    pub async fn gc(&self) -> Result<Vec<Cid>, Error> {
+        self.gc_stream().try_collect().await
+    }
+
+    /// Like [`Ipfs::gc`], but returns the removed blocks as they are removed.
+    pub fn gc_stream(&self) -> BoxStream<'static, Result<Cid, Error>> {
+        let ipfs = self.clone();
+        let span = debug_span!(parent: &self.span, "gc");
+
+        let stream = async_stream::try_stream! {
+            let barrier = &ipfs.repo.gc;
+            let _running = barrier.running.lock().await;
+            let _recording = Recording::start(barrier);
+
+            // the sessions started before the recording have put blocks which are not recorded
+            drop(barrier.sessions.write().await);
+
+            let mut marked = Marked::default();
+
+            // the concurrent marking, which covers most of the blocks. the files root is only
+            // read here, as the files operations wait for the puts; the roots committed later
+            // consist of the recorded blocks and the blocks reachable from this one.
+            ipfs.mark_pins(&mut marked).await?;
+            let mut roots = Vec::new();
+            match ipfs.files.current_root().await {
+                Some(root) => roots.push(root),
+                None => roots.extend(ipfs.repo.get_files_root().await?),
+            }
+            roots.extend(ipfs.repo.get_config_head().await?);
+            ipfs.mark_from(&mut marked, roots).await?;
+            debug!(marked = marked.len(), "concurrent marking done");
+
+            let mut blocks = ipfs.repo.list_blocks().await?;
+            let mut listed = blocks.iter().map(key).collect::<HashSet<_>>();
+            blocks.extend(
+                ipfs.repo
+                    .list_filestore_blocks()
+                    .await?
+                    .into_iter()
+                    .filter(|cid| listed.insert(key(cid))),
+            );
+            let mut first = true;
+            let mut removed_total = 0;
+
+            for batch in blocks.chunks(SWEEP_BATCH) {
+                let mut removed = Vec::new();
+
+                {
+                    let _exclusive = barrier.lock.write().await;
+
+                    // the pins inserted and the blocks put since the previous batch
+                    if first {
+                        ipfs.mark_pins(&mut marked).await?;
+                        first = false;
+                    }
+                    let mut roots = barrier.take_recorded();
+                    roots.extend(ipfs.repo.get_config_head().await?);
+                    ipfs.mark_from(&mut marked, roots).await?;
+
+                    for cid in batch {
+                        if marked.contains(&key(cid)) {
+                            continue;
+                        }
+                        match ipfs.repo.remove_unreferenced_block(cid).await {
+                            Ok(true) => removed.push(cid.to_owned()),
+                            Ok(false) => {}
+                            Err(e) => warn!(cid = %cid, "failed to remove: {}", e),
+                        }
+                    }
+                }
+
+                removed_total += removed.len();
+                for cid in removed {
+                    yield cid;
+                }
+            }
+
+            info!(
+                marked = marked.len(),
+                removed = removed_total,
+                "garbage collection done"
+            );
+        };
+
+        stream.instrument(span).boxed()
+    }
+
+    /// Returns a [`GcSession`], which keeps the garbage collection from starting until dropped.
+    /// Meant to be held from the first put until the added blocks are pinned. The operations of
+    /// the session must not wait for a garbage collection to complete, as it waits for the
+    /// session.
+    pub async fn gc_session(&self) -> GcSession {
+        GcSession {
+            _guard: self.repo.gc.sessions.clone().read_owned().await,
+        }
+    }
+
+    async fn mark_pins(&self, marked: &mut Marked) -> Result<(), Error> {
+        let mut pins = self.repo.list_pins(None).await;
+        while let Some((cid, _)) = pins.try_next().await? {
+            marked.insert(key(&cid));
+        }
+        Ok(())
+    }
+
+    /// Marks the blocks reachable from the `roots` which are available locally. The marked
+    /// blocks are not traversed again.
+    async fn mark_from(&self, marked: &mut Marked, roots: Vec<Cid>) -> Result<(), Error> {
+        let mut pending = roots;
+
+        while let Some(cid) = pending.pop() {
+            if !marked.insert(key(&cid)) {
+                continue;
+            }
+
+            let block = match self.repo.get_block_untracked(&cid).await? {
+                Some(block) => block,
+                None => continue,
+            };
+
+            // the raw blocks and the blocks of the unknown codecs have no links to follow
+            if let Ok(ipld) = self.codecs.decode(&block.cid, &block.data) {
+                pending.extend(
+                    crate::refs::ipld_links(&block.cid, ipld)
+                        .map(|(_, link)| link)
+                        .filter(|link| !marked.contains(&key(link))),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Block, Node};
+    use cid::{Cid, Codec};
+    use multihash::Sha2_256;
+
+    async fn put_raw(ipfs: &Node, data: &[u8]) -> Cid {
+        let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(data));
+        let block = Block::new(data.to_vec().into_boxed_slice(), cid.clone());
+        ipfs.put_block(block).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn keeps_the_pinned_and_the_reachable_blocks() {
+        let ipfs = Node::new("test_node").await;
+
+        let garbage = put_raw(&ipfs, b"garbage").await;
+        let direct = put_raw(&ipfs, b"direct").await;
+        let indirect = put_raw(&ipfs, b"indirect").await;
+        let recursive = ipfs
+            .put_dag(make_ipld!({ "child": indirect.clone() }))
+            .await
+            .unwrap();
+
+        ipfs.insert_pin(&direct, false).await.unwrap();
+        ipfs.insert_pin(&recursive, true).await.unwrap();
+
+        ipfs.mfs()
+            .write(
+                "/kept",
+                b"in the files",
+                &crate::mfs::WriteOptions {
+                    create: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let in_files = ipfs.mfs().stat("/kept").await.unwrap().cid;
+
+        // the earlier roots of the files are removed as well
+        let removed = ipfs.gc().await.unwrap();
+        assert!(removed.contains(&garbage));
+
+        for kept in &[direct, indirect, recursive, in_files] {
+            assert!(!removed.contains(kept));
+            assert!(ipfs.repo.get_block_now(kept).await.unwrap().is_some());
+        }
+        assert!(ipfs.repo.get_block_now(&garbage).await.unwrap().is_none());
+
+        // nothing left to remove
+        assert!(ipfs.gc().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_pinned_cidv0_blocks_of_the_fs_store() {
+        use crate::unixfs::ll::file::adder::FileAdder;
+        use crate::{IpfsOptions, Types, UninitializedIpfs};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let options = IpfsOptions {
+            ipfs_path: tmp.path().to_owned(),
+            ..IpfsOptions::inmemory_with_generated_keys()
+        };
+        let (ipfs, task) = UninitializedIpfs::<Types>::new(options)
+            .start()
+            .await
+            .unwrap();
+        tokio::spawn(task);
+
+        // a few dag-pb leaves under a root, with the Cids of the default adds
+        let data = (0..600 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut adder = FileAdder::default();
+        let mut blocks = Vec::new();
+        let mut read = 0;
+        while read < data.len() {
+            let (added, used) = adder.push(&data[read..]);
+            blocks.extend(added);
+            read += used;
+        }
+        blocks.extend(adder.finish());
+
+        let root = blocks.last().unwrap().0.clone();
+        assert_eq!(root.version(), cid::Version::V0);
+
+        for (cid, data) in blocks.iter().cloned() {
+            ipfs.put_block(Block::new(data.into_boxed_slice(), cid))
+                .await
+                .unwrap();
+        }
+        ipfs.insert_pin(&root, true).await.unwrap();
+
+        // the files root is kept in the fs datastore
+        ipfs.mfs()
+            .write(
+                "/kept",
+                b"in the files",
+                &crate::mfs::WriteOptions {
+                    create: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let in_files = ipfs.mfs().stat("/kept").await.unwrap().cid;
+        assert!(ipfs.repo.get_files_root().await.unwrap().is_some());
+
+        let garbage = b"garbage".to_vec().into_boxed_slice();
+        let garbage_cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(&garbage));
+        ipfs.put_block(Block::new(garbage, garbage_cid.clone()))
+            .await
+            .unwrap();
+
+        // the fs store lists the dag-pb blocks as CIDv1
+        let removed = ipfs.gc().await.unwrap();
+        assert!(removed.contains(&garbage_cid));
+
+        for (cid, _) in &blocks {
+            assert!(!removed.iter().any(|removed| removed.hash() == cid.hash()));
+            assert!(ipfs.repo.get_block_now(cid).await.unwrap().is_some());
+        }
+        assert!(!removed.contains(&in_files));
+        assert!(ipfs.repo.get_block_now(&in_files).await.unwrap().is_some());
+
+        ipfs.exit_daemon().await;
+    }
+
+    #[tokio::test]
+    async fn collection_waits_for_the_sessions() {
+        let ipfs = Node::new("test_node").await;
+
+        let session = ipfs.gc_session().await;
+        let added = put_raw(&ipfs, b"added in the session").await;
+
+        let gc = tokio::spawn({
+            let ipfs = ipfs.ipfs.clone();
+            async move { ipfs.gc().await.unwrap() }
+        });
+
+        // the collection would have removed the block by now without the session
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        ipfs.insert_pin(&added, false).await.unwrap();
+        drop(session);
+
+        let removed = gc.await.unwrap();
+        assert!(!removed.contains(&added));
+        assert!(ipfs.repo.get_block_now(&added).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn blocks_put_during_the_marking_are_kept() {
+        let ipfs = Node::new("test_node").await;
+
+        let barrier = &ipfs.repo.gc;
+        let recording = super::Recording::start(barrier);
+        let cid = put_raw(&ipfs, b"put while marking").await;
+        assert_eq!(barrier.take_recorded(), vec![cid]);
+        drop(recording);
+
+        put_raw(&ipfs, b"put after").await;
+        assert!(barrier.take_recorded().is_empty());
+    }
+}
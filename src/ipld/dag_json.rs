@@ -1,4 +1,8 @@
 //! DAG-JSON codec.
+//!
+//! The links are encoded as `{"/": "<cid>"}` and the bytes as `{"/": {"bytes": "<base64>"}}`
+//! with the unpadded standard base64 alphabet, like in go-ipfs. The links with the base64 encoded
+//! bytes of the Cid written by the earlier versions are still decoded.
 
 use crate::ipld::{BlockError, Ipld};
 use cid::Cid;
@@ -26,6 +30,7 @@ impl DagJsonCodec {
 }
 
 const LINK_KEY: &str = "/";
+const BYTES_KEY: &str = "bytes";
 
 pub fn json_encode(ipld: &Ipld) -> Result<Box<[u8]>, Error> {
     let mut writer = Vec::with_capacity(128);
@@ -49,7 +54,17 @@ where
         Ipld::Integer(i128) => ser.serialize_i128(*i128),
         Ipld::Float(f64) => ser.serialize_f64(*f64),
         Ipld::String(string) => ser.serialize_str(string),
-        Ipld::Bytes(bytes) => ser.serialize_bytes(bytes),
+        Ipld::Bytes(bytes) => {
+            let mut inner = BTreeMap::new();
+            inner.insert(
+                BYTES_KEY,
+                base64::encode_config(bytes, base64::STANDARD_NO_PAD),
+            );
+            let mut map = BTreeMap::new();
+            map.insert(LINK_KEY, inner);
+
+            ser.collect_map(map)
+        }
         Ipld::List(list) => {
             let wrapped = list.iter().map(Wrapper);
             ser.collect_seq(wrapped)
@@ -59,9 +74,8 @@ where
             ser.collect_map(wrapped)
         }
         Ipld::Link(link) => {
-            let value = base64::encode(&link.to_bytes());
             let mut map = BTreeMap::new();
-            map.insert("/", value);
+            map.insert(LINK_KEY, link.to_string());
 
             ser.collect_map(map)
         }
@@ -201,14 +215,25 @@ impl<'de> de::Visitor<'de> for JSONVisitor {
             values.push((key, value));
         }
 
-        // JSON Object represents IPLD Link if it is `{ "/": "...." }` therefor
-        // we valiadet if that is the case here.
-        if let Some((key, WrapperOwned(Ipld::String(value)))) = values.first() {
-            if key == LINK_KEY && values.len() == 1 {
-                let link = base64::decode(value).map_err(SerdeError::custom)?;
-                let cid = Cid::try_from(link).map_err(SerdeError::custom)?;
-                return Ok(Ipld::Link(cid));
+        // JSON Object represents IPLD Link if it is `{ "/": "...." }` and bytes if it is
+        // `{ "/": { "bytes": "...." } }`, therefore we validate if that is the case here.
+        match values.first() {
+            Some((key, WrapperOwned(Ipld::String(value))))
+                if key == LINK_KEY && values.len() == 1 =>
+            {
+                return parse_link(value).map(Ipld::Link);
             }
+            Some((key, WrapperOwned(Ipld::Map(inner))))
+                if key == LINK_KEY && values.len() == 1 && inner.len() == 1 =>
+            {
+                if let Some(Ipld::String(value)) = inner.get(BYTES_KEY) {
+                    let bytes =
+                        base64::decode_config(value.trim_end_matches('='), base64::STANDARD_NO_PAD)
+                            .map_err(SerdeError::custom)?;
+                    return Ok(Ipld::Bytes(bytes));
+                }
+            }
+            _ => {}
         }
 
         let unwrapped = values
@@ -226,6 +251,16 @@ impl<'de> de::Visitor<'de> for JSONVisitor {
     }
 }
 
+/// Parses the Cid of a link from its string representation, or from the base64 encoded bytes
+/// written by the earlier versions.
+fn parse_link<E: SerdeError>(value: &str) -> Result<Cid, E> {
+    if let Ok(cid) = Cid::try_from(value) {
+        return Ok(cid);
+    }
+    let link = base64::decode(value).map_err(SerdeError::custom)?;
+    Cid::try_from(link).map_err(SerdeError::custom)
+}
+
 // Needed for `visit_seq` and `visit_map` in Deserializer
 /// We cannot directly implement `serde::Deserializer` for `Ipld` as it is a remote type.
 /// Instead wrap it into a newtype struct and implement `serde::Deserialize` for that one.
@@ -244,3 +279,39 @@ impl<'de> Deserialize<'de> for WrapperOwned {
         deserialized.map(Self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{json_decode, json_encode};
+    use crate::{ipld::Ipld, make_ipld};
+    use cid::{Cid, Codec};
+    use core::convert::TryFrom;
+    use multihash::Sha2_256;
+
+    #[test]
+    fn go_ipfs_links_and_bytes() {
+        let cid = Cid::new_v1(Codec::DagCBOR, Sha2_256::digest(b"foo"));
+        let ipld = make_ipld!({
+            "bytes": Ipld::Bytes(vec![0, 1, 2, 3, 4]),
+            "link": cid,
+        });
+
+        let json = json_encode(&ipld).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&json).unwrap(),
+            format!(
+                r#"{{"bytes":{{"/":{{"bytes":"AAECAwQ"}}}},"link":{{"/":"{}"}}}}"#,
+                cid
+            )
+        );
+        assert_eq!(json_decode(&json).unwrap(), ipld);
+    }
+
+    #[test]
+    fn legacy_base64_links() {
+        let cid = Cid::try_from("QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n").unwrap();
+        let json = format!(r#"{{"/":"{}"}}"#, base64::encode(&cid.to_bytes()));
+
+        assert_eq!(json_decode(json.as_bytes()).unwrap(), Ipld::Link(cid));
+    }
+}
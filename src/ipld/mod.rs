@@ -10,12 +10,14 @@ pub mod dag_json;
 pub mod dag_pb;
 #[macro_use]
 pub mod ipld_macro;
+pub mod registry;
 
 use cid::{Cid, Codec};
 use dag_cbor::DagCborCodec;
 use dag_json::DagJsonCodec;
 use dag_pb::DagPbCodec;
 use multihash::Multihash;
+pub use registry::{CodecRegistry, RegistryError};
 use std::collections::BTreeMap;
 use thiserror::Error;
 
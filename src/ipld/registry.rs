@@ -0,0 +1,178 @@
+//! Application-specific IPLD codecs, see [`CodecRegistry`].
+
+use super::{decode_ipld, encode_ipld, BlockError, Ipld};
+use cid::{Cid, Codec};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use thiserror::Error;
+
+type EncodeFn = Arc<dyn Fn(&Ipld) -> Result<Box<[u8]>, BlockError> + Send + Sync>;
+type DecodeFn = Arc<dyn Fn(&[u8]) -> Result<Ipld, BlockError> + Send + Sync>;
+
+/// The codecs built into [`encode_ipld`] and [`decode_ipld`], which cannot be replaced.
+const BUILT_IN: [Codec; 4] = [
+    Codec::DagCBOR,
+    Codec::DagProtobuf,
+    Codec::DagJSON,
+    Codec::Raw,
+];
+
+/// Failure to [`CodecRegistry::register`] a codec.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RegistryError {
+    /// Only the multicodecs known to the `cid` crate can be used in the Cids.
+    #[error("multicodec {0:#x} is not supported by the cid crate")]
+    UnknownMulticodec(u64),
+    /// The built-in codecs cannot be replaced.
+    #[error("codec {0:?} is built-in")]
+    BuiltIn(Codec),
+    /// The codec has already been registered.
+    #[error("codec {0:?} has already been registered")]
+    AlreadyRegistered(Codec),
+}
+
+/// The encode and decode functions of the application-specific codecs, used by the DAG API in
+/// addition to the built-in dag-cbor, dag-pb, dag-json and raw codecs.
+///
+/// The registry is given to the node in [`crate::IpfsOptions::codecs`], after which the
+/// [`crate::IpldDag::put`] accepts the registered codecs, and the [`crate::IpldDag::get`] decodes
+/// and resolves the paths through the blocks with the registered codecs like through any other
+/// `Ipld` document.
+#[derive(Clone, Default)]
+pub struct CodecRegistry {
+    codecs: HashMap<u64, (EncodeFn, DecodeFn)>,
+}
+
+impl fmt::Debug for CodecRegistry {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut codes = self.codecs.keys().collect::<Vec<_>>();
+        codes.sort_unstable();
+        fmt.debug_struct("CodecRegistry")
+            .field("codecs", &codes)
+            .finish()
+    }
+}
+
+impl CodecRegistry {
+    /// Registers the `encode` and `decode` functions for the multicodec `code`.
+    pub fn register<E, D>(&mut self, code: u64, encode: E, decode: D) -> Result<(), RegistryError>
+    where
+        E: Fn(&Ipld) -> Result<Box<[u8]>, BlockError> + Send + Sync + 'static,
+        D: Fn(&[u8]) -> Result<Ipld, BlockError> + Send + Sync + 'static,
+    {
+        let codec = Codec::from(code).map_err(|_| RegistryError::UnknownMulticodec(code))?;
+
+        if BUILT_IN.contains(&codec) {
+            return Err(RegistryError::BuiltIn(codec));
+        }
+
+        if self.codecs.contains_key(&code) {
+            return Err(RegistryError::AlreadyRegistered(codec));
+        }
+
+        self.codecs
+            .insert(code, (Arc::new(encode), Arc::new(decode)));
+        Ok(())
+    }
+
+    /// Returns `true` if the `codec` has been registered.
+    pub fn is_registered(&self, codec: Codec) -> bool {
+        self.codecs.contains_key(&u64::from(codec))
+    }
+
+    /// Returns `true` if paths can be resolved through the documents of the `codec`: the built-in
+    /// codecs other than raw, and the registered ones.
+    pub fn is_traversable(&self, codec: Codec) -> bool {
+        (BUILT_IN.contains(&codec) && codec != Codec::Raw) || self.is_registered(codec)
+    }
+
+    /// Encodes the `ipld` with the built-in or the registered `codec`.
+    pub fn encode(&self, ipld: &Ipld, codec: Codec) -> Result<Box<[u8]>, BlockError> {
+        match self.codecs.get(&u64::from(codec)) {
+            Some((encode, _)) => encode(ipld),
+            None => encode_ipld(ipld, codec),
+        }
+    }
+
+    /// Decodes the block `data` with the built-in or the registered codec of the `cid`.
+    pub fn decode(&self, cid: &Cid, data: &[u8]) -> Result<Ipld, BlockError> {
+        match self.codecs.get(&u64::from(cid.codec())) {
+            Some((_, decode)) => decode(data),
+            None => decode_ipld(cid, data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CodecRegistry, RegistryError};
+    use crate::ipld::{dag_cbor::DagCborCodec, BlockError, Ipld};
+    use cid::{Cid, Codec};
+    use multihash::Sha2_256;
+
+    /// Stands for an application-specific codec by encoding the value in dag-cbor after a marker.
+    fn marked() -> CodecRegistry {
+        let mut registry = CodecRegistry::default();
+        registry
+            .register(
+                Codec::GitRaw.into(),
+                |ipld| {
+                    let mut bytes = vec![0xff];
+                    bytes.extend_from_slice(&DagCborCodec::encode(ipld)?);
+                    Ok(bytes.into_boxed_slice())
+                },
+                |data| match data.split_first() {
+                    Some((0xff, rest)) => Ok(DagCborCodec::decode(rest)?),
+                    _ => Err(BlockError::CodecError("missing the marker".into())),
+                },
+            )
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn registered_codec_roundtrips() {
+        let registry = marked();
+        let ipld = Ipld::List(vec![Ipld::Integer(1), Ipld::String("a".into())]);
+
+        let bytes = registry.encode(&ipld, Codec::GitRaw).unwrap();
+        assert_eq!(bytes[0], 0xff);
+
+        let cid = Cid::new_v1(Codec::GitRaw, Sha2_256::digest(&bytes));
+        assert_eq!(registry.decode(&cid, &bytes).unwrap(), ipld);
+        assert!(registry.decode(&cid, &bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn built_in_codecs_are_used_without_registering() {
+        let registry = CodecRegistry::default();
+        let ipld = Ipld::Bytes(vec![1, 2, 3]);
+
+        assert_eq!(&*registry.encode(&ipld, Codec::Raw).unwrap(), &[1, 2, 3]);
+        assert!(matches!(
+            registry.encode(&ipld, Codec::GitRaw),
+            Err(BlockError::UnsupportedCodec(Codec::GitRaw))
+        ));
+    }
+
+    #[test]
+    fn rejected_registrations() {
+        let mut registry = marked();
+        let noop = |_: &Ipld| Ok(Box::<[u8]>::default());
+        let fail = |_: &[u8]| Ok(Ipld::Null);
+
+        assert_eq!(
+            registry.register(Codec::DagCBOR.into(), noop, fail),
+            Err(RegistryError::BuiltIn(Codec::DagCBOR))
+        );
+        assert_eq!(
+            registry.register(Codec::GitRaw.into(), noop, fail),
+            Err(RegistryError::AlreadyRegistered(Codec::GitRaw))
+        );
+        assert_eq!(
+            registry.register(0x3f_ffff, noop, fail),
+            Err(RegistryError::UnknownMulticodec(0x3f_ffff))
+        );
+    }
+}
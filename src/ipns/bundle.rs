@@ -0,0 +1,249 @@
+//! Portable bundles of an IPNS name, for moving the publishing of a name to another node.
+//!
+//! A bundle holds the private key of the name along with its latest signed record, so that the
+//! node importing it continues publishing with a higher sequence number even before the previous
+//! records can be found in the DHT. It is written as a CARv1 file of a single dag-cbor block:
+//!
+//! ```text
+//! { "key": <protobuf encoded private key>, "record": <protobuf encoded record>, "version": 1 }
+//! ```
+//!
+//! The private key is not encrypted, so the bundles need to be handled like the keys themselves.
+
+use super::record::{verify_record, ImportedKey, RecordError};
+use super::Ipns;
+use crate::car::{self, CarError};
+use crate::error::Error;
+use crate::ipld::{encode_ipld, Ipld};
+use crate::repo::RepoTypes;
+use crate::Block;
+use cid::{Cid, Codec};
+use libp2p::core::PeerId;
+use multihash::Sha2_256;
+use std::collections::BTreeMap;
+
+/// The version of the bundle document.
+const BUNDLE_VERSION: i128 = 1;
+
+/// The ways a bundle can fail to be read.
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    #[error("invalid bundle file")]
+    Car(#[from] CarError),
+    #[error("invalid bundle: {0}")]
+    InvalidBundle(&'static str),
+    #[error("unsupported bundle version {0}")]
+    UnsupportedVersion(i128),
+    #[error("invalid private key in the bundle")]
+    InvalidKey(#[source] Error),
+    #[error("invalid record in the bundle")]
+    InvalidRecord(#[from] RecordError),
+}
+
+/// The private key of an IPNS name with its latest signed record, see [`crate::ipns::bundle`].
+#[derive(Debug)]
+pub struct NameBundle {
+    key: ImportedKey,
+    record: Vec<u8>,
+    sequence: u64,
+}
+
+impl NameBundle {
+    /// Bundles the `key` with the `record` of its name. Expired records are accepted, as only the
+    /// sequence number needs to carry over.
+    pub fn new(key: ImportedKey, record: Vec<u8>) -> Result<Self, RecordError> {
+        let sequence = verify_record(&key.name(), &record)?.sequence;
+        Ok(NameBundle {
+            key,
+            record,
+            sequence,
+        })
+    }
+
+    /// The IPNS name of the bundle.
+    pub fn name(&self) -> PeerId {
+        self.key.name()
+    }
+
+    /// The sequence number of the bundled record.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// The protobuf encoded signed record.
+    pub fn record(&self) -> &[u8] {
+        &self.record
+    }
+
+    /// Returns the key for publishing the name with [`crate::Ipfs::publish_ipns_with`].
+    pub fn into_key(self) -> ImportedKey {
+        self.key
+    }
+
+    /// Encodes the bundle as a CARv1 file.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut map = BTreeMap::new();
+        map.insert(
+            "key".to_owned(),
+            Ipld::Bytes(self.key.protobuf_encoding().to_vec()),
+        );
+        map.insert("record".to_owned(), Ipld::Bytes(self.record.clone()));
+        map.insert("version".to_owned(), Ipld::Integer(BUNDLE_VERSION));
+
+        let data = encode_ipld(&Ipld::Map(map), Codec::DagCBOR)?;
+        let cid = Cid::new_v1(Codec::DagCBOR, Sha2_256::digest(&data));
+
+        Ok(car::single_block(&Block::new(data, cid))?)
+    }
+
+    /// Decodes a bundle written by [`NameBundle::to_bytes`], verifying that the record is signed
+    /// by the key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BundleError> {
+        let block = car::read_single_block(bytes)?;
+        let document = crate::ipld::decode_ipld(&block.cid, &block.data)
+            .map_err(|_| BundleError::InvalidBundle("not a dag-cbor document"))?;
+
+        let mut map = match document {
+            Ipld::Map(map) => map,
+            _ => return Err(BundleError::InvalidBundle("not a map")),
+        };
+
+        match map.remove("version") {
+            Some(Ipld::Integer(BUNDLE_VERSION)) => {}
+            Some(Ipld::Integer(version)) => return Err(BundleError::UnsupportedVersion(version)),
+            _ => return Err(BundleError::InvalidBundle("missing version")),
+        }
+
+        let key = match map.remove("key") {
+            Some(Ipld::Bytes(key)) => {
+                ImportedKey::from_protobuf_encoding(&key).map_err(BundleError::InvalidKey)?
+            }
+            _ => return Err(BundleError::InvalidBundle("missing key")),
+        };
+
+        let record = match map.remove("record") {
+            Some(Ipld::Bytes(record)) => record,
+            _ => return Err(BundleError::InvalidBundle("missing record")),
+        };
+
+        Ok(NameBundle::new(key, record)?)
+    }
+}
+
+impl<Types: RepoTypes> Ipns<Types> {
+    /// Bundles the `key` with the latest record of its name, published or imported on this node
+    /// or found in the DHT.
+    pub async fn export_name(&self, key: ImportedKey) -> Result<NameBundle, Error> {
+        let name = key.name();
+        let (_, record) = self
+            .latest_record(&name)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("no records found for {}", name))?;
+        Ok(NameBundle::new(key, record)?)
+    }
+
+    /// Stores the record of the `bundle` as the latest record of its name, unless a newer one is
+    /// already stored, so that the next record published with the key continues from it.
+    pub async fn import_name(&self, bundle: NameBundle) -> Result<ImportedKey, Error> {
+        let name = bundle.name();
+
+        let stored = match self.ipfs.repo.get_ipns_record(&name).await? {
+            Some(record) => verify_record(&name, &record).ok(),
+            None => None,
+        };
+
+        if stored
+            .map(|stored| stored.sequence < bundle.sequence)
+            .unwrap_or(true)
+        {
+            self.ipfs
+                .repo
+                .put_ipns_record(&name, &bundle.record)
+                .await?;
+        }
+
+        Ok(bundle.into_key())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BundleError, NameBundle};
+    use crate::ipns::record::{create_record, ImportedKey};
+    use crate::ipns::PublishOptions;
+    use crate::path::IpfsPath;
+    use crate::Node;
+    use libp2p::core::identity::{ed25519, Keypair};
+    use std::str::FromStr;
+
+    fn generate_key() -> ImportedKey {
+        let keypair = ed25519::Keypair::generate();
+        // the protobuf PrivateKey message with the Ed25519 type
+        let mut encoded = vec![0x08, 0x01, 0x12, 0x40];
+        encoded.extend_from_slice(&keypair.encode());
+        ImportedKey::from_protobuf_encoding(&encoded).unwrap()
+    }
+
+    #[tokio::test]
+    async fn bundles_round_trip() {
+        let key = generate_key();
+        let path =
+            IpfsPath::from_str("/ipfs/QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap();
+        let record = create_record(&key, &path, 7, &PublishOptions::default())
+            .await
+            .unwrap();
+
+        let name = key.name();
+        let bytes = NameBundle::new(key, record.clone())
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+
+        let bundle = NameBundle::from_bytes(&bytes).unwrap();
+        assert_eq!(bundle.name(), name);
+        assert_eq!(bundle.sequence(), 7);
+        assert_eq!(bundle.record(), record.as_slice());
+
+        let other = create_record(
+            &Keypair::generate_ed25519(),
+            &path,
+            1,
+            &PublishOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(NameBundle::new(generate_key(), other).is_err());
+
+        let mut corrupted = bytes;
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        assert!(matches!(
+            NameBundle::from_bytes(&corrupted),
+            Err(BundleError::Car(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn publishing_continues_from_the_imported_record() {
+        let ipfs = Node::new("test_node").await;
+        let key = generate_key();
+        let path =
+            IpfsPath::from_str("/ipfs/QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap();
+        let record = create_record(&key, &path, 41, &PublishOptions::default())
+            .await
+            .unwrap();
+
+        let key = ipfs
+            .import_ipns_name(NameBundle::new(key, record).unwrap())
+            .await
+            .unwrap();
+
+        // the record cannot be put to the DHT without peers, but it is stored before that
+        let _ = ipfs
+            .publish_ipns_with(&key, &path, &PublishOptions::default())
+            .await;
+
+        let bundle = ipfs.export_ipns_name(key).await.unwrap();
+        assert_eq!(bundle.sequence(), 42);
+    }
+}
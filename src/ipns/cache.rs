@@ -0,0 +1,166 @@
+//! Caching of the IPNS and DNSLink resolution results.
+
+use crate::path::IpfsPath;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Configuration of the cache used by [`crate::Ipfs::resolve_ipns_cached`].
+#[derive(Debug, Clone)]
+pub struct ResolveCacheConfig {
+    /// The longest time a resolved name is cached for. The TTL of the DNS records is used when
+    /// shorter, so lowering this trades more lookups for fresher results.
+    pub max_ttl: Duration,
+    /// The maximum number of cached names. The entries closest to expiring are evicted first.
+    pub capacity: usize,
+}
+
+impl Default for ResolveCacheConfig {
+    fn default() -> Self {
+        ResolveCacheConfig {
+            max_ttl: Duration::from_secs(60),
+            capacity: 1024,
+        }
+    }
+}
+
+/// Resolved names with the time they expire at, keyed by the name without the `/ipns/` prefix.
+#[derive(Debug)]
+pub(crate) struct ResolveCache {
+    config: ResolveCacheConfig,
+    entries: Mutex<HashMap<String, (IpfsPath, Instant)>>,
+}
+
+impl ResolveCache {
+    pub(crate) fn new(config: ResolveCacheConfig) -> Self {
+        ResolveCache {
+            config,
+            entries: Default::default(),
+        }
+    }
+
+    /// Returns the cached resolution of the `name`, unless it has expired.
+    pub(crate) fn get(&self, name: &str) -> Option<IpfsPath> {
+        self.get_at(name, Instant::now())
+    }
+
+    fn get_at(&self, name: &str, now: Instant) -> Option<IpfsPath> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(name) {
+            Some((path, expires)) if *expires > now => Some(path.to_owned()),
+            Some(_) => {
+                entries.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Caches the resolution of the `name` for the `ttl`, clamped to
+    /// [`ResolveCacheConfig::max_ttl`].
+    pub(crate) fn insert(&self, name: &str, path: IpfsPath, ttl: Duration) {
+        self.insert_at(name, path, ttl, Instant::now())
+    }
+
+    fn insert_at(&self, name: &str, path: IpfsPath, ttl: Duration, now: Instant) {
+        let ttl = ttl.min(self.config.max_ttl);
+        if ttl == Duration::from_secs(0) || self.config.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.config.capacity && !entries.contains_key(name) {
+            entries.retain(|_, (_, expires)| *expires > now);
+
+            if entries.len() >= self.config.capacity {
+                let soonest = entries
+                    .iter()
+                    .min_by_key(|(_, (_, expires))| *expires)
+                    .map(|(name, _)| name.to_owned());
+
+                if let Some(soonest) = soonest {
+                    entries.remove(&soonest);
+                }
+            }
+        }
+
+        entries.insert(name.to_owned(), (path, now + ttl));
+    }
+
+    /// Removes the `name` from the cache, returning true if it was cached.
+    pub(crate) fn invalidate(&self, name: &str) -> bool {
+        self.entries.lock().unwrap().remove(name).is_some()
+    }
+
+    /// Removes all of the cached names.
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ResolveCache, ResolveCacheConfig};
+    use crate::path::IpfsPath;
+    use std::time::{Duration, Instant};
+
+    fn path() -> IpfsPath {
+        "/ipfs/QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn ttl_is_clamped() {
+        let cache = ResolveCache::new(ResolveCacheConfig {
+            max_ttl: Duration::from_secs(10),
+            ..Default::default()
+        });
+
+        let now = Instant::now();
+        cache.insert_at("example.com", path(), Duration::from_secs(3600), now);
+
+        assert_eq!(
+            cache.get_at("example.com", now + Duration::from_secs(9)),
+            Some(path())
+        );
+        assert_eq!(
+            cache.get_at("example.com", now + Duration::from_secs(10)),
+            None
+        );
+    }
+
+    #[test]
+    fn invalidation() {
+        let cache = ResolveCache::new(ResolveCacheConfig::default());
+
+        cache.insert("example.com", path(), Duration::from_secs(30));
+        cache.insert("example.org", path(), Duration::from_secs(30));
+
+        assert!(cache.invalidate("example.com"));
+        assert!(!cache.invalidate("example.com"));
+        assert_eq!(cache.get("example.com"), None);
+        assert_eq!(cache.get("example.org"), Some(path()));
+
+        cache.clear();
+        assert_eq!(cache.get("example.org"), None);
+    }
+
+    #[test]
+    fn soonest_to_expire_is_evicted() {
+        let cache = ResolveCache::new(ResolveCacheConfig {
+            capacity: 2,
+            ..Default::default()
+        });
+
+        let now = Instant::now();
+        cache.insert_at("a", path(), Duration::from_secs(30), now);
+        cache.insert_at("b", path(), Duration::from_secs(10), now);
+        cache.insert_at("c", path(), Duration::from_secs(20), now);
+
+        assert!(cache.get_at("a", now).is_some());
+        assert!(cache.get_at("b", now).is_none());
+        assert!(cache.get_at("c", now).is_some());
+    }
+}
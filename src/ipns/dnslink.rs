@@ -1,18 +1,21 @@
+use crate::dns::DnsResolver;
 use crate::error::Error;
 use crate::path::IpfsPath;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tracing_futures::Instrument;
 
-pub async fn resolve(domain: &str) -> Result<IpfsPath, Error> {
+/// Resolves the DNSLink of the `domain` with the `dns`, returning the path along with the time
+/// the TXT record may be cached for.
+pub async fn resolve(domain: &str, dns: &DnsResolver) -> Result<(IpfsPath, Duration), Error> {
     use std::borrow::Cow;
-    use trust_dns_resolver::AsyncResolver;
 
     let span = tracing::trace_span!("dnslink", %domain);
 
     async move {
-        // allow using non fqdn names (using the local search path suffices)
-        let searched = Some(Cow::Borrowed(domain));
-
+        // the `_dnslink.` subdomain is looked up first, and the domain itself only for the
+        // records published before the subdomain was specified. non fqdn names are allowed, using
+        // the local search path suffices.
         let prefix = "_dnslink.";
         let prefixed = if !domain.starts_with(prefix) {
             let mut next = String::with_capacity(domain.len() + prefix.len());
@@ -23,16 +26,16 @@ pub async fn resolve(domain: &str) -> Result<IpfsPath, Error> {
             None
         };
 
-        let searched = searched.into_iter().chain(prefixed.into_iter());
+        let searched = prefixed.into_iter().chain(Some(Cow::Borrowed(domain)));
 
         // FIXME: this uses caching trust-dns resolver even though it's discarded right away
         // when trust-dns support lands in future libp2p-dns investigate if we could share one, no need
         // to have multiple related caches.
-        let resolver = AsyncResolver::tokio_from_system_conf()?;
+        let resolver = dns.resolver()?;
 
-        // previous implementation searched $domain and _dnslink.$domain concurrently. not sure did
-        // `domain` assume fqdn names or not, but local suffices were not being searched on windows at
-        // least. they are probably waste of time most of the time.
+        // previous implementation searched $domain and _dnslink.$domain concurrently. local
+        // suffices were not being searched on windows at least. they are probably waste of time
+        // most of the time.
         for domain in searched {
             let res = match resolver.txt_lookup(&*domain).await {
                 Ok(res) => res,
@@ -60,7 +63,8 @@ pub async fn resolve(domain: &str) -> Result<IpfsPath, Error> {
 
             if let Some(Ok(x)) = paths.next() {
                 tracing::trace!("dnslink found for {:?}", domain);
-                return Ok(x);
+                let ttl = res.valid_until().saturating_duration_since(Instant::now());
+                return Ok((x, ttl));
             }
 
             tracing::trace!("zero TXT records found for {:?}", domain);
@@ -79,13 +83,26 @@ mod tests {
     #[tokio::test]
     async fn resolve_ipfs_io() {
         tracing_subscriber::fmt::init();
-        let res = resolve("ipfs.io").await.unwrap().to_string();
+        let res = resolve("ipfs.io", &Default::default())
+            .await
+            .unwrap()
+            .0
+            .to_string();
+        assert_eq!(res, "/ipns/website.ipfs.io");
+    }
+
+    #[tokio::test]
+    async fn resolve_ipfs_io_over_https() {
+        let dns = crate::dns::DnsResolver::cloudflare();
+        let res = resolve("ipfs.io", &dns).await.unwrap().0.to_string();
         assert_eq!(res, "/ipns/website.ipfs.io");
     }
 
     #[tokio::test]
     async fn resolve_website_ipfs_io() {
-        let res = resolve("website.ipfs.io").await.unwrap();
+        let (res, _) = resolve("website.ipfs.io", &Default::default())
+            .await
+            .unwrap();
 
         assert!(
             matches!(res.root(), crate::path::PathRoot::Ipld(_)),
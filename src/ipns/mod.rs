@@ -3,30 +3,341 @@
 use crate::error::Error;
 use crate::path::{IpfsPath, PathRoot};
 use crate::repo::RepoTypes;
+use crate::subscription::SubscriptionErr;
 use crate::Ipfs;
+use libp2p::core::PeerId;
+use libp2p::kad::{record::Key, Quorum};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing_futures::Instrument;
+
+mod cache;
+pub(crate) use cache::ResolveCache;
+pub use cache::ResolveCacheConfig;
 
 mod dnslink;
 
+mod pubsub;
+pub use pubsub::record_topic;
+pub(crate) use pubsub::PubsubRecords;
+
+mod record;
+use record::verify_record;
+pub use record::{validate_record, ImportedKey, PublishOptions, RecordError, Signer, ValidRecord};
+
+mod bundle;
+pub use bundle::{BundleError, NameBundle};
+
+/// Generated types for the signed IPNS records as they are stored in the DHT.
+mod ipns_pb {
+    include!(concat!(env!("OUT_DIR"), "/ipns_pb.rs"));
+}
+
+/// No valid IPNS records of the name were found in the DHT, see [`Ipns::get_record`].
+#[derive(Debug, thiserror::Error)]
+#[error("no valid ipns records found for {0}")]
+pub struct RecordNotFound(pub PeerId);
+
 /// IPNS facade around [`Ipns`].
 #[derive(Clone, Debug)]
 pub struct Ipns<Types: RepoTypes> {
-    // FIXME(unused): scaffolding while ipns functionality as a whole suggests we should have dht
-    // queries etc. here (currently unimplemented).
-    _ipfs: Ipfs<Types>,
+    ipfs: Ipfs<Types>,
 }
 
 impl<Types: RepoTypes> Ipns<Types> {
-    pub fn new(_ipfs: Ipfs<Types>) -> Self {
-        Ipns { _ipfs }
+    pub fn new(ipfs: Ipfs<Types>) -> Self {
+        Ipns { ipfs }
     }
 
-    /// Resolves a ipns path to an ipld path.
+    /// Resolves a ipns path to an ipld path, refreshing the cached resolution.
     pub async fn resolve(&self, path: &IpfsPath) -> Result<IpfsPath, Error> {
         let path = path.to_owned();
         match path.root() {
             PathRoot::Ipld(_) => Ok(path),
-            PathRoot::Ipns(_) => Err(anyhow::anyhow!("unimplemented")),
-            PathRoot::Dns(domain) => Ok(dnslink::resolve(domain).await?),
+            PathRoot::Ipns(name) => {
+                let (resolved, ttl) = match self.resolve_pubsub(name).await {
+                    Some(resolved) => resolved,
+                    None => self.resolve_name(name).await?,
+                };
+                self.ipfs
+                    .ipns_cache
+                    .insert(&name.to_string(), resolved.clone(), ttl);
+                with_remainder(resolved, path.iter())
+            }
+            PathRoot::Dns(domain) => {
+                let (resolved, ttl) = dnslink::resolve(domain, &self.ipfs.dns).await?;
+                self.ipfs.ipns_cache.insert(domain, resolved.clone(), ttl);
+                with_remainder(resolved, path.iter())
+            }
+        }
+    }
+
+    /// Resolves a ipns path to an ipld path, returning the cached resolution if it has not yet
+    /// expired.
+    pub async fn resolve_cached(&self, path: &IpfsPath) -> Result<IpfsPath, Error> {
+        match path.root() {
+            PathRoot::Dns(domain) => {
+                if let Some(resolved) = self.ipfs.ipns_cache.get(domain) {
+                    return with_remainder(resolved, path.iter());
+                }
+            }
+            PathRoot::Ipns(name) => {
+                if let Some(resolved) = self.ipfs.ipns_cache.get(&name.to_string()) {
+                    return with_remainder(resolved, path.iter());
+                }
+            }
+            PathRoot::Ipld(_) => {}
+        }
+        self.resolve(path).await
+    }
+
+    /// Resolves the `name` from the latest valid record found in the DHT, returning the path
+    /// along with the time the resolution may be cached for: the TTL of the record, at most until
+    /// the record expires.
+    pub async fn resolve_name(&self, name: &PeerId) -> Result<(IpfsPath, Duration), Error> {
+        let record = self.get_record(name).await?;
+        let now = SystemTime::now();
+        let valid = validate_record(name, &record, now)?;
+        let ttl = valid
+            .ttl
+            .min(valid.eol.duration_since(now).unwrap_or_default());
+        Ok((valid.value, ttl))
+    }
+
+    /// Resolves the `name` from the latest record received over pubsub, when enabled with
+    /// [`crate::IpfsOptions::ipns_pubsub`]. Subscribes to the records of the name on the first
+    /// resolution, so the first resolution of a name is always left to the DHT.
+    async fn resolve_pubsub(&self, name: &PeerId) -> Option<(IpfsPath, Duration)> {
+        let records = self.ipfs.ipns_pubsub.as_ref()?;
+
+        if records.start_subscription(name) {
+            match self.ipfs.pubsub_subscribe(record_topic(name)).await {
+                Ok(subscription) => {
+                    let receiving = pubsub::receive_records(
+                        name.to_owned(),
+                        subscription,
+                        Arc::downgrade(records),
+                        Arc::downgrade(&self.ipfs.ipns_cache),
+                    );
+                    tokio::spawn(receiving.instrument(self.ipfs.span.clone()));
+                }
+                Err(e) => {
+                    debug!(name = %name, "failed to subscribe to the ipns records: {}", e);
+                    records.end_subscription(name);
+                }
+            }
         }
+
+        let record = records.get(name)?;
+        let ttl = record.ttl.min(
+            record
+                .eol
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        );
+        Some((record.value, ttl))
+    }
+
+    /// Looks up the signed IPNS record of the given name from the DHT, returning the protobuf
+    /// encoded bytes as-is so that the caller can verify the signature itself.
+    ///
+    /// When multiple records are found, the one with the highest sequence number is returned.
+    /// Values which do not pass [`validate_record`] are ignored, and [`RecordNotFound`] is
+    /// returned when none of the records is valid.
+    pub async fn get_record(&self, name: &PeerId) -> Result<Vec<u8>, Error> {
+        let values = match self.ipfs.dht_get(record_key(name), Quorum::One).await {
+            Ok(values) => values,
+            Err(e) if is_record_not_found(&e) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let now = SystemTime::now();
+
+        values
+            .into_iter()
+            .filter_map(|value| match validate_record(name, &value, now) {
+                Ok(record) => Some((record.sequence, value)),
+                Err(e) => {
+                    trace!(name = %name, "ignoring invalid ipns record: {}", e);
+                    None
+                }
+            })
+            .max_by_key(|(sequence, _)| *sequence)
+            .map(|(_, value)| value)
+            .ok_or_else(|| RecordNotFound(name.to_owned()).into())
+    }
+
+    /// Returns the latest record of the `name` along with its sequence number, either the one
+    /// published or imported on this node, even if it has expired, or the one found in the DHT.
+    /// Failing to look up the DHT fails, unless no records were found.
+    async fn latest_record(&self, name: &PeerId) -> Result<Option<(u64, Vec<u8>)>, Error> {
+        let stored = self.ipfs.repo.get_ipns_record(name).await?;
+        let found = match self.get_record(name).await {
+            Ok(record) => Some(record),
+            Err(e) if e.is::<RecordNotFound>() => None,
+            Err(e) => return Err(e),
+        };
+
+        Ok(stored
+            .into_iter()
+            .chain(found)
+            .filter_map(|record| {
+                verify_record(name, &record)
+                    .ok()
+                    .map(|valid| (valid.sequence, record))
+            })
+            .max_by_key(|(sequence, _)| *sequence))
+    }
+
+    /// Publishes a record pointing to `path` under the name derived from the public key of the
+    /// `signer`. The sequence number continues from the previous record published or imported on
+    /// this node or found in the DHT, if any.
+    ///
+    /// Returns the name the record was published under.
+    pub async fn publish(
+        &self,
+        signer: &dyn Signer,
+        path: &IpfsPath,
+        options: &PublishOptions,
+    ) -> Result<PeerId, Error> {
+        let name = signer.public_key().to_peer_id();
+
+        let sequence = match self.latest_record(&name).await? {
+            Some((sequence, _)) => sequence + 1,
+            None => 0,
+        };
+
+        let record = record::create_record(signer, path, sequence, options).await?;
+
+        // stored first so that the next record continues from this one even if it could not be
+        // put to the DHT
+        self.ipfs.repo.put_ipns_record(&name, &record).await?;
+
+        if let Some(records) = &self.ipfs.ipns_pubsub {
+            // the own records are resolved from the pubsub records as well
+            records.insert(&name, &record);
+            if let Err(e) = self
+                .ipfs
+                .pubsub_publish(record_topic(&name), record.clone())
+                .await
+            {
+                warn!(name = %name, "failed to publish the ipns record over pubsub: {}", e);
+            }
+        }
+
+        self.ipfs
+            .dht_put(record_key(&name), record, Quorum::One)
+            .await?;
+
+        // the published path is used right away instead of the resolution cached before
+        self.ipfs
+            .ipns_cache
+            .insert(&name.to_string(), path.to_owned(), options.ttl);
+
+        Ok(name)
+    }
+
+    /// Puts the latest record of `name` stored on this node to the DHT again as it is, without
+    /// signing a new one. Returns `false` if no record is stored.
+    pub(crate) async fn put_stored_record(&self, name: &PeerId) -> Result<bool, Error> {
+        let record = match self.ipfs.repo.get_ipns_record(name).await? {
+            Some(record) => record,
+            None => return Ok(false),
+        };
+
+        self.ipfs
+            .dht_put(record_key(name), record, Quorum::One)
+            .await?;
+
+        Ok(true)
+    }
+}
+
+/// Appends the path `remainder` following the name to the `resolved` path.
+fn with_remainder<'a>(
+    resolved: IpfsPath,
+    remainder: impl Iterator<Item = &'a str>,
+) -> Result<IpfsPath, Error> {
+    let remainder = remainder.collect::<Vec<_>>();
+    if remainder.is_empty() {
+        Ok(resolved)
+    } else {
+        resolved.sub_path(&remainder.join("/"))
+    }
+}
+
+/// Returns `true` if the [`Ipfs::dht_get`] failed as none of the peers had a record of the key.
+fn is_record_not_found(e: &Error) -> bool {
+    matches!(
+        e.downcast_ref::<SubscriptionErr<String>>(),
+        Some(SubscriptionErr::Failed(msg)) if msg == crate::p2p::RECORD_NOT_FOUND
+    )
+}
+
+/// The DHT key used for the IPNS records of `name`: `/ipns/` followed by the peer id bytes.
+pub(crate) fn record_key(name: &PeerId) -> Key {
+    let mut key = b"/ipns/".to_vec();
+    key.extend_from_slice(&name.to_bytes());
+    Key::from(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_record_not_found, record_key, with_remainder, RecordNotFound};
+    use crate::path::IpfsPath;
+    use crate::subscription::SubscriptionErr;
+    use crate::Node;
+    use libp2p::core::PeerId;
+
+    #[test]
+    fn record_key_is_prefixed_peer_id() {
+        let peer_id = PeerId::random();
+        let key = record_key(&peer_id);
+
+        let key = key.to_vec();
+        let (prefix, rest) = key.split_at(b"/ipns/".len());
+        assert_eq!(prefix, b"/ipns/");
+        assert_eq!(rest, peer_id.to_bytes().as_slice());
+    }
+
+    #[test]
+    fn remainder_is_appended() {
+        let resolved: IpfsPath = "/ipfs/QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH/a"
+            .parse()
+            .unwrap();
+        let name: IpfsPath = format!("/ipns/{}/b/c", PeerId::random()).parse().unwrap();
+
+        assert_eq!(
+            with_remainder(resolved.clone(), name.iter())
+                .unwrap()
+                .to_string(),
+            "/ipfs/QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH/a/b/c"
+        );
+        assert_eq!(
+            with_remainder(resolved.clone(), std::iter::empty()).unwrap(),
+            resolved
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_records_are_not_failures() {
+        let ipfs = Node::new("test_node").await;
+        let name = PeerId::random();
+
+        // without any peers the DHT definitely has no records
+        let e = ipfs.ipns().get_record(&name).await.unwrap_err();
+        assert!(e.is::<RecordNotFound>(), "{}", e);
+        assert_eq!(ipfs.ipns().latest_record(&name).await.unwrap(), None);
+    }
+
+    #[test]
+    fn only_missing_records_are_recognized() {
+        let missing = SubscriptionErr::Failed(crate::p2p::RECORD_NOT_FOUND.to_owned());
+        assert!(is_record_not_found(&anyhow::anyhow!(missing)));
+
+        let quorum = SubscriptionErr::Failed("quorum failed".to_owned());
+        assert!(!is_record_not_found(&anyhow::anyhow!(quorum)));
+        assert!(!is_record_not_found(&anyhow::anyhow!(
+            SubscriptionErr::<String>::Cancelled
+        )));
     }
 }
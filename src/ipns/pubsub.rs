@@ -0,0 +1,162 @@
+//! IPNS over PubSub: the records are also published to a pubsub topic of the name, and the
+//! resolvers subscribed to the topic receive the updates as soon as they are published.
+//!
+//! The topic of a name is `/record/` followed by the unpadded base64url encoding of the DHT key of
+//! the name, like in go-ipfs. A name is subscribed to when it is first resolved, after which the
+//! latest valid record received is used for the resolutions. The names without a received record
+//! are resolved through the DHT.
+
+use super::cache::ResolveCache;
+use super::record::{validate_record, ValidRecord};
+use crate::SubscriptionStream;
+use futures::stream::StreamExt;
+use libp2p::core::PeerId;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, Weak};
+use std::time::SystemTime;
+
+/// Returns the pubsub topic the records of `name` are published to.
+pub fn record_topic(name: &PeerId) -> String {
+    let key = super::record_key(name);
+    format!(
+        "/record/{}",
+        base64::encode_config(key.to_vec(), base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// The records received over pubsub, and the names subscribed to.
+#[derive(Debug, Default)]
+pub(crate) struct PubsubRecords {
+    subscribed: Mutex<HashSet<PeerId>>,
+    records: Mutex<HashMap<PeerId, ValidRecord>>,
+}
+
+impl PubsubRecords {
+    /// Returns the latest record received for the `name`, unless it has expired.
+    pub(crate) fn get(&self, name: &PeerId) -> Option<ValidRecord> {
+        let mut records = self.records.lock().unwrap();
+        match records.get(name) {
+            Some(record) if record.eol > SystemTime::now() => Some(record.clone()),
+            Some(_) => {
+                records.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Marks the `name` as subscribed to, returning false if it already was.
+    pub(crate) fn start_subscription(&self, name: &PeerId) -> bool {
+        self.subscribed.lock().unwrap().insert(name.to_owned())
+    }
+
+    /// Forgets the subscription of the `name` after it has ended or failed, so that it can be
+    /// subscribed to again.
+    pub(crate) fn end_subscription(&self, name: &PeerId) {
+        self.subscribed.lock().unwrap().remove(name);
+    }
+
+    /// Stores the `bytes` received for the `name` if they are a valid record newer than the one
+    /// already stored. Returns the stored record.
+    pub(crate) fn insert(&self, name: &PeerId, bytes: &[u8]) -> Option<ValidRecord> {
+        let record = match validate_record(name, bytes, SystemTime::now()) {
+            Ok(record) => record,
+            Err(e) => {
+                trace!(name = %name, "ignoring invalid ipns record from pubsub: {}", e);
+                return None;
+            }
+        };
+
+        let mut records = self.records.lock().unwrap();
+        match records.get(name) {
+            Some(existing) if existing.sequence >= record.sequence => None,
+            _ => {
+                records.insert(name.to_owned(), record.clone());
+                Some(record)
+            }
+        }
+    }
+}
+
+/// Stores the records of `name` received from the `subscription` until it ends or the node is
+/// dropped, updating the cached resolution of the name with each newer record.
+pub(crate) async fn receive_records(
+    name: PeerId,
+    mut subscription: SubscriptionStream,
+    records: Weak<PubsubRecords>,
+    cache: Weak<ResolveCache>,
+) {
+    while let Some(message) = subscription.next().await {
+        let (records, cache) = match (records.upgrade(), cache.upgrade()) {
+            (Some(records), Some(cache)) => (records, cache),
+            _ => return,
+        };
+
+        if let Some(record) = records.insert(&name, &message.data) {
+            debug!(name = %name, sequence = record.sequence, "received an ipns record over pubsub");
+            let ttl = record.ttl.min(
+                record
+                    .eol
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default(),
+            );
+            cache.insert(&name.to_string(), record.value, ttl);
+        }
+    }
+
+    if let Some(records) = records.upgrade() {
+        records.end_subscription(&name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_topic, PubsubRecords};
+    use crate::ipns::record::create_record;
+    use crate::ipns::PublishOptions;
+    use crate::path::IpfsPath;
+    use libp2p::core::identity::Keypair;
+    use libp2p::core::PeerId;
+
+    #[test]
+    fn topic_is_base64url_of_the_key() {
+        let name: PeerId = "QmVNXj4TENKBjaUmQQzYMawDXu5LcEEzLyf4K6Ds3WgcF3"
+            .parse()
+            .unwrap();
+        let topic = record_topic(&name);
+
+        let encoded = topic.strip_prefix("/record/").unwrap();
+        assert!(!encoded.contains('='));
+        let decoded = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).unwrap();
+        assert_eq!(&decoded[..6], b"/ipns/");
+        assert_eq!(&decoded[6..], name.to_bytes().as_slice());
+    }
+
+    #[tokio::test]
+    async fn only_newer_valid_records_are_kept() {
+        let keypair = Keypair::generate_ed25519();
+        let name = keypair.public().to_peer_id();
+        let first: IpfsPath = "/ipfs/QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH"
+            .parse()
+            .unwrap();
+        let second: IpfsPath = "/ipfs/QmXTbqB2Npq1ftW3VbgoujGHhEWapnEAc98vYPWRXfcvqq"
+            .parse()
+            .unwrap();
+        let options = PublishOptions::default();
+
+        let records = PubsubRecords::default();
+        assert!(records.insert(&name, b"garbage").is_none());
+
+        let newer = create_record(&keypair, &second, 2, &options).await.unwrap();
+        let older = create_record(&keypair, &first, 1, &options).await.unwrap();
+
+        assert!(records.insert(&name, &newer).is_some());
+        assert!(records.insert(&name, &older).is_none());
+        assert_eq!(records.get(&name).unwrap().value, second);
+
+        // the records of other names are not accepted
+        let other = Keypair::generate_ed25519().public().to_peer_id();
+        assert!(records.insert(&other, &newer).is_none());
+        assert!(records.get(&other).is_none());
+    }
+}
@@ -0,0 +1,343 @@
+//! Creation of signed IPNS records, with the signing delegated through [`Signer`], and their
+//! validation.
+
+use super::ipns_pb::{ipns_entry::ValidityType, IpnsEntry};
+use crate::error::Error;
+use crate::path::IpfsPath;
+use async_trait::async_trait;
+use libp2p::core::{identity::Keypair, PeerId, PublicKey};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// Signs IPNS records on behalf of a name.
+///
+/// The node's own [`Keypair`] implements this, but the signing can be delegated for example to a
+/// HSM or a KMS which never expose the private key bytes to the process.
+#[async_trait]
+pub trait Signer: Send + Sync {
+    /// The public key of the signing key; the IPNS name is derived from this.
+    fn public_key(&self) -> PublicKey;
+
+    /// Signs the given bytes.
+    async fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+#[async_trait]
+impl Signer for Keypair {
+    fn public_key(&self) -> PublicKey {
+        self.public()
+    }
+
+    async fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(Keypair::sign(self, data)?)
+    }
+}
+
+/// A key imported from an external source in the libp2p protobuf private key format, as exported
+/// by `ipfs key export` for example.
+pub struct ImportedKey {
+    keypair: Keypair,
+    /// The encoding the key was imported from, kept for exporting it again.
+    encoded: Vec<u8>,
+}
+
+impl ImportedKey {
+    /// Decodes the protobuf encoded private key.
+    pub fn from_protobuf_encoding(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(ImportedKey {
+            keypair: Keypair::from_protobuf_encoding(bytes)?,
+            encoded: bytes.to_vec(),
+        })
+    }
+
+    /// The protobuf encoded private key, as it was imported.
+    pub fn protobuf_encoding(&self) -> &[u8] {
+        &self.encoded
+    }
+
+    /// The IPNS name the records signed by this key are published under.
+    pub fn name(&self) -> PeerId {
+        self.keypair.public().to_peer_id()
+    }
+}
+
+impl std::fmt::Debug for ImportedKey {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_tuple("ImportedKey")
+            .field(&crate::DebuggableKeypair(&self.keypair))
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Signer for ImportedKey {
+    fn public_key(&self) -> PublicKey {
+        self.keypair.public()
+    }
+
+    async fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(self.keypair.sign(data)?)
+    }
+}
+
+/// Options for creating the IPNS records.
+#[derive(Debug, Clone)]
+pub struct PublishOptions {
+    /// How long the record is valid for, from the time of creation.
+    pub lifetime: Duration,
+    /// Hint for how long the resolvers can cache the record.
+    pub ttl: Duration,
+}
+
+impl Default for PublishOptions {
+    fn default() -> Self {
+        // these are the go-ipfs defaults
+        PublishOptions {
+            lifetime: Duration::from_secs(24 * 60 * 60),
+            ttl: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Creates a protobuf encoded record pointing to `path`, signed with the `signer`.
+pub(crate) async fn create_record(
+    signer: &dyn Signer,
+    path: &IpfsPath,
+    sequence: u64,
+    options: &PublishOptions,
+) -> Result<Vec<u8>, Error> {
+    use prost::Message;
+
+    let value = path.to_string().into_bytes();
+    let eol = SystemTime::now() + options.lifetime;
+    let validity = humantime::format_rfc3339_nanos(eol)
+        .to_string()
+        .into_bytes();
+
+    let signature = signer.sign(&signature_payload(&value, &validity)).await?;
+
+    let public_key = signer.public_key();
+    let pub_key = match public_key {
+        // ed25519 public keys are inlined in the peer id
+        PublicKey::Ed25519(_) => Vec::new(),
+        other => other.to_protobuf_encoding(),
+    };
+
+    let entry = IpnsEntry {
+        value,
+        signature,
+        validity_type: ValidityType::Eol as i32,
+        validity,
+        sequence,
+        ttl: options.ttl.as_nanos() as u64,
+        pub_key,
+    };
+
+    let mut buf = Vec::with_capacity(entry.encoded_len());
+    entry.encode(&mut buf)?;
+    Ok(buf)
+}
+
+/// The ways an IPNS record can fail [`validate_record`].
+#[derive(Debug, thiserror::Error)]
+pub enum RecordError {
+    #[error("failed to decode the record: {0}")]
+    Decode(#[from] prost::DecodeError),
+    #[error("the public key of the record could not be found or decoded")]
+    MissingPublicKey,
+    #[error("the public key of the record does not match the name")]
+    PublicKeyMismatch,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("unsupported validity type {0}")]
+    UnsupportedValidityType(i32),
+    #[error("invalid validity {0:?}")]
+    InvalidValidity(String),
+    #[error("the record expired at {0}")]
+    Expired(humantime::Rfc3339Timestamp),
+    #[error("the value is not a valid path: {0:?}")]
+    InvalidValue(String),
+}
+
+/// An IPNS record which has passed [`validate_record`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidRecord {
+    /// The path the name points to.
+    pub value: IpfsPath,
+    /// The sequence number, the record with the highest one is the latest.
+    pub sequence: u64,
+    /// Hint for how long the resolvers can cache the record.
+    pub ttl: Duration,
+    /// The time the record expires at.
+    pub eol: SystemTime,
+}
+
+/// Checks that the protobuf encoded record of `name` is signed by the key of the name, has not
+/// expired at `now` and points to a valid path.
+pub fn validate_record(
+    name: &PeerId,
+    bytes: &[u8],
+    now: SystemTime,
+) -> Result<ValidRecord, RecordError> {
+    let record = verify_record(name, bytes)?;
+    if record.eol <= now {
+        return Err(RecordError::Expired(humantime::format_rfc3339(record.eol)));
+    }
+    Ok(record)
+}
+
+/// Like [`validate_record`] but accepts the expired records, for reading the sequence numbers of
+/// the previously published records.
+pub(crate) fn verify_record(name: &PeerId, bytes: &[u8]) -> Result<ValidRecord, RecordError> {
+    use prost::Message;
+
+    let entry = IpnsEntry::decode(bytes)?;
+
+    let public_key = if entry.pub_key.is_empty() {
+        inlined_public_key(name).ok_or(RecordError::MissingPublicKey)?
+    } else {
+        let public_key = PublicKey::from_protobuf_encoding(&entry.pub_key)
+            .map_err(|_| RecordError::MissingPublicKey)?;
+        if public_key.to_peer_id() != *name {
+            return Err(RecordError::PublicKeyMismatch);
+        }
+        public_key
+    };
+
+    let payload = signature_payload(&entry.value, &entry.validity);
+    if !public_key.verify(&payload, &entry.signature) {
+        return Err(RecordError::InvalidSignature);
+    }
+
+    if entry.validity_type != ValidityType::Eol as i32 {
+        return Err(RecordError::UnsupportedValidityType(entry.validity_type));
+    }
+
+    let validity = String::from_utf8_lossy(&entry.validity);
+    let eol = humantime::parse_rfc3339_weak(&validity)
+        .map_err(|_| RecordError::InvalidValidity(validity.clone().into_owned()))?;
+
+    let value = String::from_utf8_lossy(&entry.value);
+    let value =
+        IpfsPath::from_str(&value).map_err(|_| RecordError::InvalidValue(value.into_owned()))?;
+
+    Ok(ValidRecord {
+        value,
+        sequence: entry.sequence,
+        ttl: Duration::from_nanos(entry.ttl),
+        eol,
+    })
+}
+
+/// Returns the public key inlined in the `name` with the identity multihash, as is done for the
+/// ed25519 keys.
+fn inlined_public_key(name: &PeerId) -> Option<PublicKey> {
+    use libp2p::multihash::Multihash;
+
+    // the code of the identity multihash
+    const IDENTITY: u64 = 0x00;
+
+    let multihash = Multihash::from_bytes(&name.to_bytes()).ok()?;
+    if multihash.code() != IDENTITY {
+        return None;
+    }
+    PublicKey::from_protobuf_encoding(multihash.digest()).ok()
+}
+
+/// The bytes covered by the signature: value, validity and the validity type as a string.
+fn signature_payload(value: &[u8], validity: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(value.len() + validity.len() + 3);
+    payload.extend_from_slice(value);
+    payload.extend_from_slice(validity);
+    payload.extend_from_slice(b"EOL");
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        create_record, signature_payload, validate_record, ImportedKey, PublishOptions,
+        RecordError, Signer,
+    };
+    use crate::ipns::ipns_pb::IpnsEntry;
+    use crate::path::IpfsPath;
+    use libp2p::core::identity::Keypair;
+    use prost::Message;
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime};
+
+    #[tokio::test]
+    async fn record_signature_verifies() {
+        let keypair = Keypair::generate_ed25519();
+        let path =
+            IpfsPath::from_str("/ipfs/QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap();
+
+        let bytes = create_record(&keypair, &path, 3, &PublishOptions::default())
+            .await
+            .unwrap();
+        let entry = IpnsEntry::decode(bytes.as_slice()).unwrap();
+
+        assert_eq!(entry.value, path.to_string().into_bytes());
+        assert_eq!(entry.sequence, 3);
+        assert!(entry.pub_key.is_empty());
+
+        let payload = signature_payload(&entry.value, &entry.validity);
+        assert!(keypair.public().verify(&payload, &entry.signature));
+    }
+
+    #[tokio::test]
+    async fn imported_key_signs_as_the_original() {
+        let keypair = Keypair::generate_ed25519();
+        let imported =
+            ImportedKey::from_protobuf_encoding(&keypair.to_protobuf_encoding().unwrap()).unwrap();
+
+        assert_eq!(imported.name(), keypair.public().to_peer_id());
+
+        let signature = imported.sign(b"foobar").await.unwrap();
+        assert!(keypair.public().verify(b"foobar", &signature));
+    }
+
+    #[tokio::test]
+    async fn records_are_validated() {
+        let keypair = Keypair::generate_ed25519();
+        let name = keypair.public().to_peer_id();
+        let path =
+            IpfsPath::from_str("/ipfs/QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap();
+
+        let options = PublishOptions {
+            lifetime: Duration::from_secs(60),
+            ttl: Duration::from_secs(10),
+        };
+        let bytes = create_record(&keypair, &path, 7, &options).await.unwrap();
+
+        let now = SystemTime::now();
+        let valid = validate_record(&name, &bytes, now).unwrap();
+        assert_eq!(valid.value, path);
+        assert_eq!(valid.sequence, 7);
+        assert_eq!(valid.ttl, Duration::from_secs(10));
+
+        let expired = validate_record(&name, &bytes, now + Duration::from_secs(120));
+        assert!(
+            matches!(expired, Err(RecordError::Expired(_))),
+            "{:?}",
+            expired
+        );
+
+        let other = Keypair::generate_ed25519().public().to_peer_id();
+        let mismatch = validate_record(&other, &bytes, now);
+        assert!(
+            matches!(mismatch, Err(RecordError::InvalidSignature)),
+            "{:?}",
+            mismatch
+        );
+
+        let mut entry = IpnsEntry::decode(bytes.as_slice()).unwrap();
+        entry.value = b"/ipfs/QmXTbqB2Npq1ftW3VbgoujGHhEWapnEAc98vYPWRXfcvqq".to_vec();
+        let mut tampered = Vec::new();
+        entry.encode(&mut tampered).unwrap();
+        assert!(matches!(
+            validate_record(&name, &tampered, now),
+            Err(RecordError::InvalidSignature)
+        ));
+    }
+}
@@ -22,18 +22,36 @@
 // the docs better.
 //#![allow(private_intra_doc_links)]
 
+pub mod budget;
+pub mod car;
 pub mod config;
+pub mod convert;
 pub mod dag;
+pub mod dns;
 pub mod error;
+pub mod fsck;
+pub mod gc;
 #[macro_use]
 pub mod ipld;
 pub mod ipns;
+pub mod mfs;
 pub mod p2p;
 pub mod path;
+pub mod pin;
+pub mod popularity;
+pub mod prefetch;
 pub mod refs;
 pub mod repo;
+pub mod reprovide;
+pub mod republish;
+pub mod retrieval;
+pub mod routing;
+pub mod scrub;
+pub mod selectors;
 mod subscription;
+mod task;
 pub mod unixfs;
+pub mod verify;
 
 #[macro_use]
 extern crate tracing;
@@ -43,19 +61,20 @@ use cid::Codec;
 use either::Either;
 use futures::{
     channel::{
-        mpsc::{channel, Receiver, Sender},
+        mpsc::{channel, Receiver, SendError, Sender, TrySendError},
         oneshot::{channel as oneshot_channel, Sender as OneshotSender},
     },
     sink::SinkExt,
     stream::{Fuse, Stream},
 };
-use libp2p::swarm::NetworkBehaviour;
+use libp2p::swarm::{AddressScore, NetworkBehaviour};
 use tracing::Span;
 use tracing_futures::Instrument;
 
 use std::{
     borrow::Borrow,
     collections::{HashMap, HashSet},
+    convert::TryFrom,
     env, fmt,
     future::Future,
     ops::{Deref, DerefMut, Range},
@@ -66,28 +85,39 @@ use std::{
 };
 
 use self::{
+    budget::{MemoryBudgetConfig, MemoryBudgets},
     dag::IpldDag,
-    ipns::Ipns,
+    ipns::{Ipns, PubsubRecords, ResolveCache, ResolveCacheConfig},
     p2p::{
         addr::{could_be_bound_from_ephemeral, starts_unspecified},
-        create_swarm, SwarmOptions, TSwarm,
+        create_swarm,
+        sync::{BloomFilter, SyncRequest, SyncResponse},
+        SwarmOptions, TSwarm,
     },
+    popularity::{PopularityConfig, PopularityTracker},
     repo::{create_repo, Repo, RepoEvent, RepoOptions},
     subscription::SubscriptionFuture,
 };
 
+#[cfg(feature = "block_access_stats")]
+pub use self::repo::BlockAccess;
 pub use self::{
     error::Error,
     ipld::Ipld,
     p2p::{
-        pubsub::{PubsubMessage, SubscriptionStream},
-        Connection, KadResult, MultiaddrWithPeerId, MultiaddrWithoutPeerId,
+        pubsub::{PubsubBatchConfig, PubsubMessage, SubscriptionStream, PUBSUB_BATCH_PROTOCOL},
+        AddrFilter, AddrFilterError, AnnounceConfig, Connection, DialConfig, KadResult,
+        MultiaddrWithPeerId, MultiaddrWithoutPeerId, NetworkSurvey, BLOCK_SYNC_PROTOCOL,
+    },
+    path::{IpfsPath, SlashedPath},
+    repo::{
+        BlockAccessor, BlockBackend, BlockCache, BlockCacheStats, BlockHook, BlockPlacement,
+        BlockStorage, BlockVolume, FileSource, FilestoreCheck, FilestoreEntry, FilestoreStatus,
+        MigrationMode, ObjectStorage, PinKind, PinMode, Rebalance, RepoTypes,
     },
-    path::IpfsPath,
-    repo::{PinKind, PinMode, RepoTypes},
 };
 pub use cid::Cid;
-pub use ipfs_bitswap::Block;
+pub use ipfs_bitswap::{Block, WantlistBatchConfig};
 pub use libp2p::{
     core::{
         connection::ListenerId, multiaddr::multiaddr, multiaddr::Protocol, Multiaddr, PeerId,
@@ -105,12 +135,13 @@ impl<T: RepoTypes> IpfsTypes for T {}
 #[derive(Debug)]
 pub struct Types;
 impl RepoTypes for Types {
-    type TBlockStore = repo::fs::FsBlockStore;
+    type TBlockStore = repo::backend::DynBlockStore;
     #[cfg(feature = "sled_data_store")]
     type TDataStore = repo::kv::KvDataStore;
     #[cfg(not(feature = "sled_data_store"))]
     type TDataStore = repo::fs::FsDataStore;
     type TLock = repo::fs::FsLock;
+    type TJournal = repo::fs::FsJournal;
 }
 
 /// In-memory testing configuration used in tests.
@@ -120,9 +151,14 @@ impl RepoTypes for TestTypes {
     type TBlockStore = repo::mem::MemBlockStore;
     type TDataStore = repo::mem::MemDataStore;
     type TLock = repo::mem::MemLock;
+    type TJournal = repo::mem::MemJournal;
+    const VERSIONED: bool = false;
 }
 
 /// Ipfs node options used to configure the node to be created with [`UninitializedIpfs`].
+///
+/// The options can also be built out of the [`config::Profile`]s and validated with
+/// [`IpfsOptions::builder`].
 #[derive(Clone)]
 pub struct IpfsOptions {
     /// The path of the ipfs repo (blockstore and datastore).
@@ -136,6 +172,19 @@ pub struct IpfsOptions {
     /// existing repository.
     pub ipfs_path: PathBuf,
 
+    /// Whether an outdated repo is migrated to the current format when started, see
+    /// [`repo::migrations`]. Migrated by default.
+    pub repo_migrations: MigrationMode,
+
+    /// Record the repo mutations in a write-ahead journal, so that the mutations interrupted by a
+    /// crash are repaired by [`Ipfs::repo_fsck`] on the next start. Every mutation then waits for
+    /// its record to be synced to the disk, so this is off by default.
+    pub repo_journal: bool,
+
+    /// Spreading the blocks over multiple disks, see [`BlockStorage`]. By default all of the
+    /// blocks are stored under the `ipfs_path`.
+    pub block_storage: BlockStorage,
+
     /// The keypair used with libp2p, the identity of the node.
     pub keypair: Keypair,
 
@@ -156,6 +205,66 @@ pub struct IpfsOptions {
     /// Bound listening addresses; by default the node will not listen on any address.
     pub listening_addrs: Vec<Multiaddr>,
 
+    /// Limits for the outgoing dials. Dials over the concurrency limit are queued instead of
+    /// failing, so connecting to many peers at once does not exhaust the file descriptors.
+    pub dial: DialConfig,
+
+    /// Uses the in-process memory transport instead of TCP, so that the node can only listen on
+    /// and dial the `/memory/<port>` addresses of the nodes in the same process. Used by
+    /// [`Ipfs::test_node`].
+    pub memory_transport: bool,
+
+    /// Advertises the subscribed pubsub topics as provider records in the DHT and looks up the
+    /// other subscribers of a topic when subscribing to it, so that peers subscribed to the same
+    /// topics find each other without being connected beforehand. Uses the same keys as go-ipfs.
+    pub pubsub_discovery: bool,
+
+    /// Batching of the published pubsub messages for the peers supporting it, see
+    /// [`PubsubBatchConfig`]. Reduces the overhead of publishing many small messages. `None`
+    /// disables the batching.
+    pub pubsub_batching: Option<PubsubBatchConfig>,
+
+    /// Selects the advertised addresses out of the listened and observed ones, see
+    /// [`AnnounceConfig`].
+    pub announce: AnnounceConfig,
+
+    /// Tracking of the content requested through the gateway and over bitswap, see
+    /// [`popularity`].
+    pub popularity: PopularityConfig,
+
+    /// Caching of the resolved IPNS names, see [`Ipfs::resolve_ipns_cached`].
+    pub ipns_cache: ResolveCacheConfig,
+
+    /// Publishes the IPNS records also over pubsub, and resolves the names from the records
+    /// received over pubsub once subscribed to them, falling back to the DHT. See
+    /// [`ipns::record_topic`].
+    pub ipns_pubsub: bool,
+
+    /// Bounds on the blocks in flight between the swarm, bitswap and the repo, see [`budget`].
+    pub memory_budgets: MemoryBudgetConfig,
+
+    /// Compress the bitswap messages with zstd for the peers supporting the extended protocol,
+    /// see [`ipfs_bitswap::Bitswap::set_compression`].
+    pub bitswap_compression: bool,
+
+    /// Batching of the wantlist updates sent to each peer, see
+    /// [`ipfs_bitswap::WantlistBatchConfig`]. Avoids a storm of small messages when traversing
+    /// wide DAGs. `None` sends each update right away.
+    pub bitswap_wantlist_batching: Option<WantlistBatchConfig>,
+
+    /// The application-specific IPLD codecs used by the DAG API, see [`ipld::CodecRegistry`].
+    pub codecs: ipld::CodecRegistry,
+
+    /// How the DNSLinks and the DNS names in the multiaddresses are resolved, see
+    /// [`dns::DnsResolver`].
+    pub dns: dns::DnsResolver,
+
+    /// The sources of the content providers asked along with the DHT, see [`routing`].
+    pub content_routing: Vec<Arc<dyn routing::ContentRouting>>,
+
+    /// The remote pinning services and their credentials, see [`pin::remote`].
+    pub pinning_services: Vec<pin::remote::PinningService>,
+
     /// The span for tracing purposes, `None` value is converted to `tracing::trace_span!("ipfs")`.
     ///
     /// All futures returned by `Ipfs`, background task actions and swarm actions are instrumented
@@ -170,11 +279,29 @@ impl fmt::Debug for IpfsOptions {
         // is a struct with all public fields, don't enforce users to use this wrapper.
         fmt.debug_struct("IpfsOptions")
             .field("ipfs_path", &self.ipfs_path)
+            .field("repo_migrations", &self.repo_migrations)
+            .field("repo_journal", &self.repo_journal)
+            .field("block_storage", &self.block_storage)
             .field("bootstrap", &self.bootstrap)
             .field("keypair", &DebuggableKeypair(&self.keypair))
             .field("mdns", &self.mdns)
             .field("kad_protocol", &self.kad_protocol)
             .field("listening_addrs", &self.listening_addrs)
+            .field("dial", &self.dial)
+            .field("memory_transport", &self.memory_transport)
+            .field("pubsub_discovery", &self.pubsub_discovery)
+            .field("pubsub_batching", &self.pubsub_batching)
+            .field("announce", &self.announce)
+            .field("popularity", &self.popularity)
+            .field("ipns_cache", &self.ipns_cache)
+            .field("ipns_pubsub", &self.ipns_pubsub)
+            .field("memory_budgets", &self.memory_budgets)
+            .field("bitswap_compression", &self.bitswap_compression)
+            .field("bitswap_wantlist_batching", &self.bitswap_wantlist_batching)
+            .field("codecs", &self.codecs)
+            .field("dns", &self.dns)
+            .field("content_routing", &self.content_routing)
+            .field("pinning_services", &self.pinning_services)
             .field("span", &self.span)
             .finish()
     }
@@ -184,15 +311,37 @@ impl IpfsOptions {
     /// Creates an in-memory store backed configuration useful for any testing purposes.
     ///
     /// Also used from examples.
+    ///
+    /// The `ipfs_path` is the shared temporary directory, so it needs to be replaced with a
+    /// directory of its own for every node running in the same process, when used with
+    /// [`RepoTypes`] storing to the disk.
     pub fn inmemory_with_generated_keys() -> Self {
         Self {
             ipfs_path: env::temp_dir(),
+            repo_migrations: Default::default(),
+            repo_journal: false,
+            block_storage: Default::default(),
             keypair: Keypair::generate_ed25519(),
             mdns: Default::default(),
             bootstrap: Default::default(),
             // default to lan kad for go-ipfs use in tests
             kad_protocol: Some("/ipfs/lan/kad/1.0.0".to_owned()),
             listening_addrs: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            dial: Default::default(),
+            memory_transport: false,
+            pubsub_discovery: false,
+            pubsub_batching: None,
+            announce: Default::default(),
+            popularity: Default::default(),
+            ipns_cache: Default::default(),
+            ipns_pubsub: false,
+            memory_budgets: Default::default(),
+            bitswap_compression: false,
+            bitswap_wantlist_batching: Some(Default::default()),
+            codecs: Default::default(),
+            dns: Default::default(),
+            content_routing: Vec::new(),
+            pinning_services: Vec::new(),
             span: None,
         }
     }
@@ -232,7 +381,15 @@ pub struct Ipfs<Types: IpfsTypes> {
     span: Span,
     repo: Arc<Repo<Types>>,
     keys: DebuggableKeypair<Keypair>,
-    to_task: Sender<IpfsEvent>,
+    ipns_cache: Arc<ResolveCache>,
+    ipns_pubsub: Option<Arc<PubsubRecords>>,
+    codecs: ipld::CodecRegistry,
+    dns: dns::DnsResolver,
+    dnsaddr_cache: Arc<dns::DnsaddrCache>,
+    files: Arc<mfs::FilesRoot>,
+    content_routing: Vec<Arc<dyn routing::ContentRouting>>,
+    pinning_services: Arc<pin::remote::PinningServices>,
+    to_task: TaskSender,
 }
 
 impl<Types: IpfsTypes> Clone for Ipfs<Types> {
@@ -241,6 +398,14 @@ impl<Types: IpfsTypes> Clone for Ipfs<Types> {
             span: self.span.clone(),
             repo: Arc::clone(&self.repo),
             keys: self.keys.clone(),
+            ipns_cache: Arc::clone(&self.ipns_cache),
+            ipns_pubsub: self.ipns_pubsub.clone(),
+            codecs: self.codecs.clone(),
+            dns: self.dns.clone(),
+            dnsaddr_cache: Arc::clone(&self.dnsaddr_cache),
+            files: Arc::clone(&self.files),
+            content_routing: self.content_routing.clone(),
+            pinning_services: Arc::clone(&self.pinning_services),
             to_task: self.to_task.clone(),
         }
     }
@@ -248,6 +413,22 @@ impl<Types: IpfsTypes> Clone for Ipfs<Types> {
 
 type Channel<T> = OneshotSender<Result<T, Error>>;
 
+/// Sends the [`IpfsEvent`]s to the background task together with the current span, so that the
+/// work started for them in the background task is instrumented with the span of the operation,
+/// see [`Ipfs::with_span`].
+#[derive(Debug, Clone)]
+struct TaskSender(Sender<(Span, IpfsEvent)>);
+
+impl TaskSender {
+    async fn send(&mut self, event: IpfsEvent) -> Result<(), SendError> {
+        self.0.send((Span::current(), event)).await
+    }
+
+    fn try_send(&mut self, event: IpfsEvent) -> Result<(), TrySendError<(Span, IpfsEvent)>> {
+        self.0.try_send((Span::current(), event))
+    }
+}
+
 /// Events used internally to communicate with the swarm, which is executed in the the background
 /// task.
 #[derive(Debug)]
@@ -277,18 +458,31 @@ enum IpfsEvent {
         OneshotSender<Vec<(Cid, ipfs_bitswap::Priority)>>,
     ),
     BitswapStats(OneshotSender<BitswapStats>),
+    BitswapLedger(PeerId, OneshotSender<BitswapLedger>),
     AddListeningAddress(Multiaddr, Channel<Multiaddr>),
     RemoveListeningAddress(Multiaddr, Channel<()>),
     Bootstrap(Channel<SubscriptionFuture<KadResult, String>>),
     AddPeer(PeerId, Multiaddr),
+    /// Remembers the addresses of the providers found outside of the DHT, for dialing them
+    AddProviderAddresses(PeerId, Vec<Multiaddr>),
     GetClosestPeers(PeerId, OneshotSender<SubscriptionFuture<KadResult, String>>),
+    /// The peers in the routing table closest to the key, without querying the network
+    LocalClosestPeers(Key, usize, OneshotSender<Vec<PeerId>>),
+    NetworkSurvey(OneshotSender<NetworkSurvey>),
     GetBitswapPeers(OneshotSender<Vec<PeerId>>),
+    SyncRequest(
+        PeerId,
+        SyncRequest,
+        OneshotSender<Result<SyncResponse, String>>,
+    ),
     FindPeer(
         PeerId,
         bool,
         OneshotSender<Either<Vec<Multiaddr>, SubscriptionFuture<KadResult, String>>>,
     ),
     GetProviders(Cid, OneshotSender<SubscriptionFuture<KadResult, String>>),
+    /// Dials the peers with the addresses known to the DHT, unless already connected
+    DialPeers(Vec<PeerId>),
     Provide(Cid, Channel<SubscriptionFuture<KadResult, String>>),
     DhtGet(
         Key,
@@ -305,7 +499,7 @@ enum IpfsEvent {
     AddBootstrapper(MultiaddrWithPeerId, Channel<Multiaddr>),
     RemoveBootstrapper(MultiaddrWithPeerId, Channel<Multiaddr>),
     ClearBootstrappers(OneshotSender<Vec<Multiaddr>>),
-    RestoreBootstrappers(Channel<Vec<Multiaddr>>),
+    RestoreBootstrappers(Vec<MultiaddrWithPeerId>, Channel<Vec<Multiaddr>>),
     Exit,
 }
 
@@ -319,7 +513,8 @@ pub struct UninitializedIpfs<Types: IpfsTypes> {
 
 impl<Types: IpfsTypes> UninitializedIpfs<Types> {
     /// Configures a new UninitializedIpfs with from the given options and optionally a span.
-    /// If the span is not given, it is defaulted to `tracing::trace_span!("ipfs")`.
+    /// If the span is not given, it is defaulted to `tracing::trace_span!("ipfs", peer_id)` to
+    /// tell apart the nodes running in the same process.
     ///
     /// The span is attached to all operations called on the later created `Ipfs` along with all
     /// operations done in the background task as well as tasks spawned by the underlying
@@ -353,17 +548,22 @@ impl<Types: IpfsTypes> UninitializedIpfs<Types> {
             mut options,
         } = self;
 
+        options.validate()?;
+
+        let peer_id = keys.public().to_peer_id();
         let root_span = options
             .span
             .take()
             // not sure what would be the best practice with tracing and spans
-            .unwrap_or_else(|| tracing::trace_span!(parent: &Span::current(), "ipfs"));
+            .unwrap_or_else(
+                || tracing::trace_span!(parent: &Span::current(), "ipfs", peer_id = %peer_id),
+            );
 
         // the "current" span which is not entered but the awaited futures are instrumented with it
         let init_span = tracing::trace_span!(parent: &root_span, "init");
 
         // stored in the Ipfs, instrumenting every method call
-        let facade_span = tracing::trace_span!("facade");
+        let facade_span = tracing::trace_span!(parent: &root_span, "facade");
 
         // stored in the executor given to libp2p, used to spawn at least the connections,
         // instrumenting each of those.
@@ -374,15 +574,58 @@ impl<Types: IpfsTypes> UninitializedIpfs<Types> {
 
         repo.init().instrument(init_span.clone()).await?;
 
-        let (to_task, receiver) = channel::<IpfsEvent>(1);
+        let (to_task, receiver) = channel::<(Span, IpfsEvent)>(1);
 
         let ipfs = Ipfs {
             span: facade_span,
             repo: repo.clone(),
             keys: DebuggableKeypair(keys),
-            to_task,
+            ipns_cache: Arc::new(ResolveCache::new(options.ipns_cache.clone())),
+            ipns_pubsub: if options.ipns_pubsub {
+                Some(Default::default())
+            } else {
+                None
+            },
+            codecs: options.codecs.clone(),
+            dns: options.dns.clone(),
+            dnsaddr_cache: Default::default(),
+            files: Default::default(),
+            content_routing: options.content_routing.clone(),
+            pinning_services: Arc::new(pin::remote::PinningServices::new(
+                &options.pinning_services,
+            )),
+            to_task: TaskSender(to_task),
         };
 
+        // repair whatever was left inconsistent by a crash before the repo is used any further
+        let report = ipfs.repo_fsck().instrument(init_span.clone()).await;
+        if !report.is_clean() {
+            warn!("repaired the repo after an unclean shutdown: {:?}", report);
+        }
+
+        // the /dnsaddr bootstrappers are added to the DHT with the concrete addresses
+        let mut bootstrap = Vec::with_capacity(options.bootstrap.len());
+        for (addr, peer_id) in options.bootstrap.drain(..) {
+            let addr = match MultiaddrWithoutPeerId::try_from(addr.clone()) {
+                Ok(without) if addr.iter().any(|p| matches!(p, Protocol::Dnsaddr(_))) => {
+                    without.with(peer_id)
+                }
+                _ => {
+                    bootstrap.push((addr, peer_id));
+                    continue;
+                }
+            };
+            match ipfs.resolve_bootstrapper(addr.clone()).await {
+                Ok(resolved) => bootstrap.extend(
+                    resolved
+                        .into_iter()
+                        .map(|addr| (addr.multiaddr.into(), addr.peer_id)),
+                ),
+                Err(e) => warn!("failed to resolve the bootstrapper {}: {}", addr, e),
+            }
+        }
+        options.bootstrap = bootstrap;
+
         // FIXME: mutating options above is an unfortunate side-effect of this call, which could be
         // reordered for less error prone code.
         let swarm_options = SwarmOptions::from(&options);
@@ -391,7 +634,9 @@ impl<Types: IpfsTypes> UninitializedIpfs<Types> {
             .await?;
 
         let IpfsOptions {
-            listening_addrs, ..
+            listening_addrs,
+            announce,
+            ..
         } = options;
 
         let mut fut = IpfsFuture {
@@ -399,8 +644,20 @@ impl<Types: IpfsTypes> UninitializedIpfs<Types> {
             from_facade: receiver.fuse(),
             swarm,
             listening_addresses: HashMap::with_capacity(listening_addrs.len()),
+            announce,
         };
 
+        // the explicitly announced addresses are advertised over identify as well
+        for addr in fut
+            .announce
+            .announce
+            .iter()
+            .chain(fut.announce.append_announce.iter())
+        {
+            fut.swarm
+                .add_external_address(addr.to_owned(), AddressScore::Infinite);
+        }
+
         for addr in listening_addrs.into_iter() {
             fut.start_add_listener_address(addr, None);
         }
@@ -410,12 +667,28 @@ impl<Types: IpfsTypes> UninitializedIpfs<Types> {
 }
 
 impl<Types: IpfsTypes> Ipfs<Types> {
+    /// Returns the span the operations of this handle are instrumented with.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Returns a handle to the same node, with the operations instrumented with the `span`
+    /// instead. This allows correlating the operations and their log events with, for example,
+    /// the request they were made for, in which case the `span` should be a child of
+    /// [`Ipfs::span`].
+    pub fn with_span(&self, span: Span) -> Self {
+        Ipfs {
+            span,
+            ..self.clone()
+        }
+    }
+
     /// Return an [`IpldDag`] for DAG operations
     pub fn dag(&self) -> IpldDag<Types> {
         IpldDag::new(self.clone())
     }
 
-    fn ipns(&self) -> Ipns<Types> {
+    pub(crate) fn ipns(&self) -> Ipns<Types> {
         Ipns::new(self.clone())
     }
 
@@ -433,12 +706,100 @@ impl<Types: IpfsTypes> Ipfs<Types> {
             .map(|(cid, _put_status)| cid)
     }
 
+    /// Registers a hook run on every block read and write of the repo, including the blocks sent
+    /// to and received from other peers, see [`BlockHook`].
+    pub fn add_block_hook(&self, hook: Arc<dyn BlockHook>) {
+        self.repo.add_block_hook(hook)
+    }
+
+    /// Returns the request counts of the content served by this node.
+    pub fn popularity(&self) -> &PopularityTracker {
+        self.repo.popularity()
+    }
+
+    /// Returns the budgets of the blocks in flight between the swarm and the repo, and their
+    /// usage.
+    pub fn memory_budgets(&self) -> &MemoryBudgets {
+        self.repo.budgets()
+    }
+
     /// Retrieves a block from the local blockstore, or starts fetching from the network or join an
     /// already started fetch.
     pub async fn get_block(&self, cid: &Cid) -> Result<Block, Error> {
         self.repo.get_block(cid).instrument(self.span.clone()).await
     }
 
+    /// Retrieves a block from the local blockstore, or fetches it from the network following the
+    /// `strategy`, see [`retrieval`].
+    pub async fn get_block_with(
+        &self,
+        cid: &Cid,
+        strategy: &dyn retrieval::RetrievalStrategy,
+    ) -> Result<Block, Error> {
+        retrieval::fetch_block(self, cid, strategy)
+            .instrument(self.span.clone())
+            .await
+    }
+
+    /// Retrieves the blocks available in the local blockstore, in the same order as the `cids`.
+    /// The missing blocks are returned as `None` and are not fetched from the network.
+    ///
+    /// Compared to calling [`Ipfs::get_block`] once per block, this allows the blockstore to
+    /// read the blocks concurrently.
+    pub async fn get_blocks_now(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, Error> {
+        self.repo
+            .get_blocks_now(cids)
+            .instrument(self.span.clone())
+            .await
+    }
+
+    /// Returns the access statistics of the block, tracked with the `block_access_stats`
+    /// feature.
+    #[cfg(feature = "block_access_stats")]
+    pub async fn block_access(&self, cid: &Cid) -> Result<BlockAccess, Error> {
+        self.repo
+            .block_access(cid)
+            .instrument(self.span.clone())
+            .await
+    }
+
+    /// Returns the access statistics of the blocks in the local blockstore which have not been
+    /// read or written since `time`, for example to find the candidates for garbage collection.
+    #[cfg(feature = "block_access_stats")]
+    pub async fn blocks_not_accessed_since(
+        &self,
+        time: std::time::SystemTime,
+    ) -> Result<Vec<BlockAccess>, Error> {
+        self.repo
+            .blocks_not_accessed_since(time)
+            .instrument(self.span.clone())
+            .await
+    }
+
+    /// Checks that the blocks of the files added with [`unixfs::AddOptions::nocopy`] can still be
+    /// read from the files, reporting the changed and removed files. See [`repo::filestore`].
+    pub async fn filestore_verify(&self) -> Result<Vec<FilestoreCheck>, Error> {
+        self.repo
+            .filestore_verify()
+            .instrument(self.span.clone())
+            .await
+    }
+
+    /// Returns the hits and the misses of the in-memory cache of the blocks, see [`BlockCache`].
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.repo.block_cache_stats()
+    }
+
+    /// Moves the blocks between the volumes of the blockstore to where the placement would now put
+    /// them, for example after adding a volume, and removes the extra copies left over from the
+    /// interrupted moves. See [`BlockStorage`].
+    pub async fn rebalance_blocks(&self) -> Result<Rebalance, Error> {
+        self.repo
+            .rebalance_blocks()
+            .instrument(self.span.clone())
+            .await
+    }
+
     /// Remove block from the ipfs repo. A pinned block cannot be removed.
     pub async fn remove_block(&self, cid: Cid) -> Result<Cid, Error> {
         self.repo
@@ -494,6 +855,28 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         .await
     }
 
+    /// Like [`Ipfs::insert_pin`], but the missing blocks are fetched following the `strategy`,
+    /// with all of the blocks of the recursive pins fetched before pinning them.
+    pub async fn insert_pin_with(
+        &self,
+        cid: &Cid,
+        recursive: bool,
+        strategy: &dyn retrieval::RetrievalStrategy,
+    ) -> Result<(), Error> {
+        let span = debug_span!(parent: &self.span, "insert_pin_with", cid = %cid, recursive);
+
+        async move {
+            if recursive {
+                retrieval::fetch_dag(self, cid, strategy).await?;
+            } else {
+                retrieval::fetch_block(self, cid, strategy).await?;
+            }
+            self.insert_pin(cid, recursive).await
+        }
+        .instrument(span)
+        .await
+    }
+
     /// Unpins a given Cid recursively or only directly.
     ///
     /// Recursively unpinning a previously only directly pinned Cid will remove the direct pin.
@@ -606,6 +989,33 @@ impl<Types: IpfsTypes> Ipfs<Types> {
             .map_err(Error::new)
     }
 
+    /// Like [`Ipfs::get_dag`], but the missing blocks are fetched following the `strategy`.
+    pub async fn get_dag_with(
+        &self,
+        path: IpfsPath,
+        strategy: Arc<dyn retrieval::RetrievalStrategy>,
+    ) -> Result<Ipld, Error> {
+        self.dag()
+            .with_strategy(strategy)
+            .get(path)
+            .instrument(self.span.clone())
+            .await
+            .map_err(Error::new)
+    }
+
+    /// Gets an ipld node from the ipfs like [`Ipfs::get_dag`], returning the value where the path
+    /// crosses into a raw block or a block of an unknown codec along with the unresolved rest of
+    /// the path.
+    ///
+    /// See [`IpldDag::get_partial`] for more information.
+    pub async fn get_dag_partial(&self, path: IpfsPath) -> Result<(Ipld, SlashedPath), Error> {
+        self.dag()
+            .get_partial(path)
+            .instrument(self.span.clone())
+            .await
+            .map_err(Error::new)
+    }
+
     /// Creates a stream which will yield the bytes of an UnixFS file from the root Cid, with the
     /// optional file byte range. If the range is specified and is outside of the file, the stream
     /// will end without producing any bytes.
@@ -626,11 +1036,43 @@ impl<Types: IpfsTypes> Ipfs<Types> {
             .await
     }
 
-    /// Resolves a ipns path to an ipld path; currently only supports dnslink resolution.
+    /// Resolves a ipns path to an ipld path, either with the DNSLink of a domain name or the
+    /// latest valid IPNS record of a key found in the DHT.
+    ///
+    /// The names are always resolved again, but the results are stored for
+    /// [`Ipfs::resolve_ipns_cached`].
     pub async fn resolve_ipns(&self, path: &IpfsPath, recursive: bool) -> Result<IpfsPath, Error> {
+        self.resolve_ipns_with(path, recursive, false).await
+    }
+
+    /// Resolves a ipns path to an ipld path like [`Ipfs::resolve_ipns`], but uses the previous
+    /// resolutions of the names until they expire. The resolutions are cached for the TTL of the
+    /// DNS or IPNS records, at most for [`ResolveCacheConfig::max_ttl`].
+    pub async fn resolve_ipns_cached(
+        &self,
+        path: &IpfsPath,
+        recursive: bool,
+    ) -> Result<IpfsPath, Error> {
+        self.resolve_ipns_with(path, recursive, true).await
+    }
+
+    async fn resolve_ipns_with(
+        &self,
+        path: &IpfsPath,
+        recursive: bool,
+        cached: bool,
+    ) -> Result<IpfsPath, Error> {
         async move {
-            let ipns = self.ipns();
-            let mut resolved = ipns.resolve(path).await;
+            let ipns = &self.ipns();
+            let resolve = move |path: IpfsPath| async move {
+                if cached {
+                    ipns.resolve_cached(&path).await
+                } else {
+                    ipns.resolve(&path).await
+                }
+            };
+
+            let mut resolved = resolve(path.to_owned()).await;
 
             if recursive {
                 let mut seen = HashSet::with_capacity(1);
@@ -638,7 +1080,7 @@ impl<Types: IpfsTypes> Ipfs<Types> {
                     if !seen.insert(res.clone()) {
                         break;
                     }
-                    resolved = ipns.resolve(res).await;
+                    resolved = resolve(res.clone()).await;
                 }
             }
             resolved
@@ -647,6 +1089,80 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         .await
     }
 
+    /// Forgets the cached resolution of the IPNS `name`, given with or without the `/ipns/`
+    /// prefix, so that it is resolved again on the next [`Ipfs::resolve_ipns_cached`]. Returns
+    /// true if the name was cached.
+    pub fn ipns_cache_invalidate(&self, name: &str) -> bool {
+        let name = name.strip_prefix("/ipns/").unwrap_or(name);
+        self.ipns_cache.invalidate(name.trim_end_matches('/'))
+    }
+
+    /// Forgets all of the cached resolutions of the IPNS names.
+    pub fn ipns_cache_clear(&self) {
+        self.ipns_cache.clear()
+    }
+
+    /// Returns the signed IPNS record for the given name as protobuf encoded bytes, allowing the
+    /// record to be verified by the caller. The record is looked up from the DHT, and only the
+    /// records passing [`ipns::validate_record`] are considered.
+    pub async fn get_ipns_record(&self, name: &PeerId) -> Result<Vec<u8>, Error> {
+        self.ipns()
+            .get_record(name)
+            .instrument(self.span.clone())
+            .await
+    }
+
+    /// Publishes an IPNS record pointing to `path` under the name of this node, signed with the
+    /// node's keypair and valid for the [`ipns::PublishOptions::lifetime`].
+    pub async fn publish_ipns(
+        &self,
+        path: &IpfsPath,
+        options: &ipns::PublishOptions,
+    ) -> Result<PeerId, Error> {
+        self.publish_ipns_with(self.keys.get_ref(), path, options)
+            .await
+    }
+
+    /// Publishes an IPNS record pointing to `path` under the name derived from the `signer`, which
+    /// can be an [`ipns::ImportedKey`] or an implementation delegating to external key storage.
+    pub async fn publish_ipns_with(
+        &self,
+        signer: &dyn ipns::Signer,
+        path: &IpfsPath,
+        options: &ipns::PublishOptions,
+    ) -> Result<PeerId, Error> {
+        self.ipns()
+            .publish(signer, path, options)
+            .instrument(self.span.clone())
+            .await
+    }
+
+    /// Bundles the `key` with the latest record of its name for moving the name to another node,
+    /// see [`ipns::NameBundle`]. The record is the latest one published or imported on this node,
+    /// or the one found in the DHT.
+    pub async fn export_ipns_name(
+        &self,
+        key: ipns::ImportedKey,
+    ) -> Result<ipns::NameBundle, Error> {
+        self.ipns()
+            .export_name(key)
+            .instrument(self.span.clone())
+            .await
+    }
+
+    /// Imports a name exported with [`Ipfs::export_ipns_name`] on another node. Returns the key
+    /// of the name, with which the name can be published with [`Ipfs::publish_ipns_with`]
+    /// continuing from the sequence number of the bundled record.
+    pub async fn import_ipns_name(
+        &self,
+        bundle: ipns::NameBundle,
+    ) -> Result<ipns::ImportedKey, Error> {
+        self.ipns()
+            .import_name(bundle)
+            .instrument(self.span.clone())
+            .await
+    }
+
     /// Connects to the peer at the given Multiaddress.
     ///
     /// Accepts only multiaddresses with the PeerId to authenticate the connection.
@@ -709,6 +1225,21 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         .await
     }
 
+    /// Returns the breakdown of the connected peers by their agent versions, protocols and
+    /// transports, see [`NetworkSurvey`].
+    pub async fn network_survey(&self) -> Result<NetworkSurvey, Error> {
+        async move {
+            let (tx, rx) = oneshot_channel();
+            self.to_task
+                .clone()
+                .send(IpfsEvent::NetworkSurvey(tx))
+                .await?;
+            Ok(rx.await?)
+        }
+        .instrument(self.span.clone())
+        .await
+    }
+
     /// Disconnects a given peer.
     ///
     /// At the moment the peer is disconnected by temporarily banning the peer and unbanning it
@@ -728,8 +1259,9 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         .await
     }
 
-    /// Returns the local node public key and the listened and externally visible addresses.
-    /// The addresses are suffixed with the P2p protocol containing the node's PeerId.
+    /// Returns the local node public key and the listened and externally visible addresses, as
+    /// selected by [`IpfsOptions::announce`]. The addresses are suffixed with the P2p protocol
+    /// containing the node's PeerId.
     ///
     /// Public key can be converted to [`PeerId`].
     pub async fn identity(&self) -> Result<(PublicKey, Vec<Multiaddr>), Error> {
@@ -867,6 +1399,103 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         self.repo.list_blocks().instrument(self.span.clone()).await
     }
 
+    /// Returns a stream of the local blocks and their sizes in bytes, reading the blockstore
+    /// lazily.
+    pub async fn refs_local_stream(
+        &self,
+    ) -> Result<futures::stream::BoxStream<'static, Result<(Cid, u64), Error>>, Error> {
+        self.repo.iter_blocks().instrument(self.span.clone()).await
+    }
+
+    /// Imports the blocks of the CARv1 or CARv2 file read from the `reader`, like
+    /// `ipfs dag import`. The blocks are verified against their Cids before they are written to
+    /// the repo, and the roots are pinned recursively once all of the blocks have been imported,
+    /// unless disabled in the `opts`. See [`car`] for the export.
+    pub async fn import_car<R>(
+        &self,
+        reader: R,
+        opts: car::CarImportOptions,
+    ) -> Result<car::CarImport, Error>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        // the imported blocks are not collected before the roots are pinned
+        let _session = self.gc_session().await;
+
+        car::import(self, reader, opts)
+            .instrument(self.span.clone())
+            .await
+            .map_err(Error::from)
+    }
+
+    /// Syncs the blocks under the `root` from the `peer`, transferring only the blocks missing
+    /// locally. The peer lists its blocks under the root which are not found in a bloom filter of
+    /// the local blocks, see [`BLOCK_SYNC_PROTOCOL`], and only the listed blocks are fetched over
+    /// bitswap. The few blocks hidden by the false positives of the filter are fetched by a final
+    /// walk of the root, after which all of the blocks under the root are available locally.
+    ///
+    /// Meant for the mirrors repeatedly syncing the same growing dataset. The peer needs to be
+    /// connected or its addresses known. Returns the number of the blocks listed by the peer.
+    pub async fn sync_from(&self, peer: PeerId, root: Cid) -> Result<usize, Error> {
+        use futures::stream::{self, StreamExt, TryStreamExt};
+
+        /// How many of the listed blocks are fetched at once.
+        const SYNC_CONCURRENCY: usize = 32;
+
+        async move {
+            let filter = BloomFilter::new(&self.repo.list_blocks().await?);
+            let mut listed = 0;
+
+            loop {
+                let request = SyncRequest {
+                    root: root.clone(),
+                    skip: listed as u64,
+                    filter: filter.clone(),
+                };
+
+                let (tx, rx) = oneshot_channel();
+
+                self.to_task
+                    .clone()
+                    .send(IpfsEvent::SyncRequest(peer, request, tx))
+                    .await?;
+
+                let (cids, complete) = match rx.await?.map_err(|e| format_err!("{}", e))? {
+                    SyncResponse::Missing { cids, complete } => (cids, complete),
+                    SyncResponse::RootNotFound => {
+                        return Err(anyhow!("{} does not have the root {}", peer, root))
+                    }
+                };
+
+                listed += cids.len();
+
+                stream::iter(cids)
+                    .map(|cid| async move { self.get_block(&cid).await })
+                    .buffer_unordered(SYNC_CONCURRENCY)
+                    .try_for_each(|_| futures::future::ready(Ok(())))
+                    .await?;
+
+                if complete {
+                    break;
+                }
+            }
+
+            // fetch the blocks hidden by the false positives of the filter
+            let block = self.get_block(&root).await?;
+            let ipld = ipld::decode_ipld(&root, &block.data)?;
+
+            refs::IpldRefs::default()
+                .with_only_unique()
+                .refs_of_resolved(self, std::iter::once((root, ipld)))
+                .try_for_each(|_| futures::future::ready(Ok(())))
+                .await?;
+
+            Ok(listed)
+        }
+        .instrument(self.span.clone())
+        .await
+    }
+
     /// Returns the accumulated bitswap stats
     pub async fn bitswap_stats(&self) -> Result<BitswapStats, Error> {
         async move {
@@ -883,6 +1512,22 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         .await
     }
 
+    /// Returns the bitswap exchanges with the peer, all zeroes if there have been none.
+    pub async fn bitswap_ledger(&self, peer: PeerId) -> Result<BitswapLedger, Error> {
+        async move {
+            let (tx, rx) = oneshot_channel();
+
+            self.to_task
+                .clone()
+                .send(IpfsEvent::BitswapLedger(peer, tx))
+                .await?;
+
+            Ok(rx.await?)
+        }
+        .instrument(self.span.clone())
+        .await
+    }
+
     /// Add a given multiaddr as a listening address. Will fail if the address is unsupported, or
     /// if it is already being listened on. Currently will invoke `Swarm::listen_on` internally,
     /// keep the ListenerId for later `remove_listening_address` use in a HashMap.
@@ -967,10 +1612,86 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         .await
     }
 
-    /// Performs a DHT lookup for providers of a value to the given key.
+    /// Performs a DHT lookup for providers of a value to the given key, asking the
+    /// [`IpfsOptions::content_routing`] at the same time.
     ///
-    /// Returns a list of peers found providing the Cid.
+    /// Returns a list of peers found providing the Cid. Fails only if the DHT lookup fails and
+    /// the content routers found no providers.
     pub async fn get_providers(&self, cid: Cid) -> Result<Vec<PeerId>, Error> {
+        if self.content_routing.is_empty() {
+            return self.get_dht_providers(cid).await;
+        }
+
+        let (dht, routed) =
+            futures::future::join(self.get_dht_providers(cid.clone()), self.route(&cid)).await;
+
+        let mut providers = match dht {
+            Ok(providers) => providers,
+            Err(e) if routed.is_empty() => return Err(e),
+            Err(e) => {
+                debug!(
+                    "failed to look up the providers of {} in the DHT: {}",
+                    cid, e
+                );
+                Vec::new()
+            }
+        };
+
+        for peer_id in routed {
+            if !providers.contains(&peer_id) {
+                providers.push(peer_id);
+            }
+        }
+
+        Ok(providers)
+    }
+
+    /// Asks the [`IpfsOptions::content_routing`] for the providers, remembering their addresses.
+    async fn route(&self, cid: &Cid) -> Vec<PeerId> {
+        let lookups = self
+            .content_routing
+            .iter()
+            .map(|router| router.find_providers(cid));
+
+        let mut peers = Vec::new();
+        for (router, result) in self
+            .content_routing
+            .iter()
+            .zip(futures::future::join_all(lookups).await)
+        {
+            let providers = match result {
+                Ok(providers) => providers,
+                Err(e) => {
+                    debug!(
+                        "{:?} failed to find the providers of {}: {}",
+                        router, cid, e
+                    );
+                    continue;
+                }
+            };
+
+            for provider in providers {
+                if !provider.addrs.is_empty() {
+                    // sending only fails if the background task has exited
+                    self.to_task
+                        .clone()
+                        .send(IpfsEvent::AddProviderAddresses(
+                            provider.peer_id,
+                            provider.addrs,
+                        ))
+                        .await
+                        .ok();
+                }
+                if !peers.contains(&provider.peer_id) {
+                    peers.push(provider.peer_id);
+                }
+            }
+        }
+
+        peers
+    }
+
+    async fn get_dht_providers(&self, cid: Cid) -> Result<Vec<PeerId>, Error> {
         let kad_result = async move {
             let (tx, rx) = oneshot_channel();
 
@@ -992,10 +1713,21 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         }
     }
 
+    /// Dials the peers with the addresses known to the DHT, unless already connected to them.
+    pub(crate) async fn dial_peers(&self, peers: Vec<PeerId>) {
+        // sending only fails if the background task has exited
+        self.to_task
+            .clone()
+            .send(IpfsEvent::DialPeers(peers))
+            .await
+            .ok();
+    }
+
     /// Establishes the node as a provider of a block with the given Cid: it publishes a provider
     /// record with the given key (Cid) and the node's PeerId to the peers closest to the key. The
     /// publication of provider records is periodically repeated as per the interval specified in
-    /// `libp2p`'s  `KademliaConfig`.
+    /// `libp2p`'s  `KademliaConfig`, while the process runs. To announce the local blocks again
+    /// after restarts, see [`reprovide::Reprovider`].
     pub async fn provide(&self, cid: Cid) -> Result<(), Error> {
         // don't provide things we don't actually have
         if self.repo.get_block_now(&cid).await?.is_none() {
@@ -1051,6 +1783,27 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         }
     }
 
+    /// Returns at most `count` of the peers in the routing table closest to the `key`, without
+    /// querying the network.
+    pub(crate) async fn local_closest_peers(
+        &self,
+        key: Key,
+        count: usize,
+    ) -> Result<Vec<PeerId>, Error> {
+        async move {
+            let (tx, rx) = oneshot_channel();
+
+            self.to_task
+                .clone()
+                .send(IpfsEvent::LocalClosestPeers(key, count, tx))
+                .await?;
+
+            Ok(rx.await?)
+        }
+        .instrument(self.span.clone())
+        .await
+    }
+
     /// Attempts to look a key up in the DHT and returns the values found in the records
     /// containing that key.
     pub async fn dht_get<T: Into<Key>>(
@@ -1144,37 +1897,59 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         .await
     }
 
-    /// Extend the list of used bootstrapper nodes with an additional address.
+    /// Resolves a `/dnsaddr` bootstrapper into the concrete addresses, see [`dns`].
+    async fn resolve_bootstrapper(
+        &self,
+        addr: MultiaddrWithPeerId,
+    ) -> Result<Vec<MultiaddrWithPeerId>, Error> {
+        Ok(self.dnsaddr_cache.resolve(&self.dns, addr).await?)
+    }
+
+    /// Extend the list of used bootstrapper nodes with an additional address. A `/dnsaddr`
+    /// address is replaced with the concrete addresses it resolves to.
     /// Return value cannot be used to determine if the `addr` was a new bootstrapper, subject to
     /// change.
     pub async fn add_bootstrapper(&self, addr: MultiaddrWithPeerId) -> Result<Multiaddr, Error> {
         async move {
-            let (tx, rx) = oneshot_channel();
+            let ret = addr.clone().into();
 
-            self.to_task
-                .clone()
-                .send(IpfsEvent::AddBootstrapper(addr, tx))
-                .await?;
+            for addr in self.resolve_bootstrapper(addr).await? {
+                let (tx, rx) = oneshot_channel();
 
-            rx.await?
+                self.to_task
+                    .clone()
+                    .send(IpfsEvent::AddBootstrapper(addr, tx))
+                    .await?;
+
+                rx.await??;
+            }
+
+            Ok(ret)
         }
         .instrument(self.span.clone())
         .await
     }
 
-    /// Remove an address from the currently used list of bootstrapper nodes.
+    /// Remove an address from the currently used list of bootstrapper nodes. A `/dnsaddr`
+    /// address removes the concrete addresses it resolves to.
     /// Return value cannot be used to determine if the `addr` was an actual bootstrapper, subject to
     /// change.
     pub async fn remove_bootstrapper(&self, addr: MultiaddrWithPeerId) -> Result<Multiaddr, Error> {
         async move {
-            let (tx, rx) = oneshot_channel();
+            let ret = addr.clone().into();
 
-            self.to_task
-                .clone()
-                .send(IpfsEvent::RemoveBootstrapper(addr, tx))
-                .await?;
+            for addr in self.resolve_bootstrapper(addr).await? {
+                let (tx, rx) = oneshot_channel();
 
-            rx.await?
+                self.to_task
+                    .clone()
+                    .send(IpfsEvent::RemoveBootstrapper(addr, tx))
+                    .await?;
+
+                rx.await??;
+            }
+
+            Ok(ret)
         }
         .instrument(self.span.clone())
         .await
@@ -1197,14 +1972,29 @@ impl<Types: IpfsTypes> Ipfs<Types> {
     }
 
     /// Restore the originally configured bootstrapper node list by adding them to the list of the
-    /// currently used bootstrapper node address list; returns the restored addresses.
+    /// currently used bootstrapper node address list; returns the restored addresses. The
+    /// `/dnsaddr` nodes are restored with the concrete addresses they resolve to, skipping the
+    /// ones which fail to resolve.
     pub async fn restore_bootstrappers(&self) -> Result<Vec<Multiaddr>, Error> {
         async move {
+            let mut addrs = Vec::new();
+
+            for node in config::BOOTSTRAP_NODES {
+                let addr = node
+                    .parse::<MultiaddrWithPeerId>()
+                    .expect("see test bootstrap_nodes_are_multiaddr_with_peerid");
+
+                match self.resolve_bootstrapper(addr.clone()).await {
+                    Ok(resolved) => addrs.extend(resolved),
+                    Err(e) => warn!("failed to resolve the bootstrapper {}: {}", addr, e),
+                }
+            }
+
             let (tx, rx) = oneshot_channel();
 
             self.to_task
                 .clone()
-                .send(IpfsEvent::RestoreBootstrappers(tx))
+                .send(IpfsEvent::RestoreBootstrappers(addrs, tx))
                 .await?;
 
             rx.await?
@@ -1219,6 +2009,18 @@ impl<Types: IpfsTypes> Ipfs<Types> {
         // the background task or stream. After that this could be handled by dropping.
         self.repo.shutdown();
 
+        if let Err(e) = self.repo.flush_journal().await {
+            warn!(
+                "failed to write the completed mutations to the journal: {}",
+                e
+            );
+        }
+
+        #[cfg(feature = "block_access_stats")]
+        if let Err(e) = self.repo.flush_access_stats().await {
+            warn!("failed to store the block access statistics: {}", e);
+        }
+
         // ignoring the error because it'd mean that the background task had already been dropped
         let _ = self.to_task.try_send(IpfsEvent::Exit);
     }
@@ -1229,8 +2031,9 @@ impl<Types: IpfsTypes> Ipfs<Types> {
 struct IpfsFuture<Types: IpfsTypes> {
     swarm: TSwarm<Types>,
     repo_events: Fuse<Receiver<RepoEvent>>,
-    from_facade: Fuse<Receiver<IpfsEvent>>,
+    from_facade: Fuse<Receiver<(Span, IpfsEvent)>>,
     listening_addresses: HashMap<Multiaddr, (ListenerId, Option<Channel<Multiaddr>>)>,
+    announce: AnnounceConfig,
 }
 
 impl<TRepoTypes: RepoTypes> IpfsFuture<TRepoTypes> {
@@ -1366,6 +2169,20 @@ impl<TRepoTypes: RepoTypes> Future for IpfsFuture<TRepoTypes> {
 
         loop {
             loop {
+                // stop reading from the peers until the blocks in flight have been written or read
+                if self
+                    .swarm
+                    .behaviour()
+                    .budgets()
+                    .poll_ready(ctx)
+                    .is_pending()
+                {
+                    if done {
+                        return Poll::Pending;
+                    }
+                    break;
+                }
+
                 let inner = {
                     use futures::StreamExt;
                     let next = self.swarm.select_next_some();
@@ -1388,17 +2205,27 @@ impl<TRepoTypes: RepoTypes> Future for IpfsFuture<TRepoTypes> {
                 }
             }
 
+            // the pubsub subscriptions can also end by dropping the stream, which is only noticed
+            // when polling the swarm
+            self.swarm
+                .behaviour_mut()
+                .stop_advertising_unsubscribed_topics();
+
             // temporary pinning of the receivers should be safe as we are pinning through the
             // already pinned self. with the receivers we can also safely ignore exhaustion
             // as those are fused.
             loop {
-                let inner = match Pin::new(&mut self.from_facade).poll_next(ctx) {
+                let (span, inner) = match Pin::new(&mut self.from_facade).poll_next(ctx) {
                     Poll::Ready(Some(evt)) => evt,
                     // doing teardown also after the `Ipfs` has been dropped
-                    Poll::Ready(None) => IpfsEvent::Exit,
+                    Poll::Ready(None) => (Span::current(), IpfsEvent::Exit),
                     Poll::Pending => break,
                 };
 
+                // the swarm work started for the event is logged within the span of the operation,
+                // for example with the ID of the HTTP request
+                let _entered = span.enter();
+
                 match inner {
                     IpfsEvent::Connect(target, ret) => {
                         ret.send(self.swarm.behaviour_mut().connect(target)).ok();
@@ -1423,15 +2250,15 @@ impl<TRepoTypes: RepoTypes> Future for IpfsFuture<TRepoTypes> {
                     }
                     IpfsEvent::GetAddresses(ret) => {
                         // perhaps this could be moved under `IpfsEvent` or free functions?
-                        let mut addresses = Vec::new();
-                        addresses.extend(self.swarm.listeners().map(|a| a.to_owned()));
-                        addresses
-                            .extend(self.swarm.external_addresses().map(|ar| ar.addr.to_owned()));
+                        let addresses = self.announce.announced(
+                            self.swarm.listeners(),
+                            self.swarm.external_addresses().map(|ar| &ar.addr),
+                        );
                         // ignore error, perhaps caller went away already
                         let _ = ret.send(addresses);
                     }
                     IpfsEvent::PubsubSubscribe(topic, ret) => {
-                        let _ = ret.send(self.swarm.behaviour_mut().pubsub().subscribe(topic));
+                        let _ = ret.send(self.swarm.behaviour_mut().pubsub_subscribe(topic));
                     }
                     IpfsEvent::PubsubUnsubscribe(topic, ret) => {
                         let _ = ret.send(self.swarm.behaviour_mut().pubsub().unsubscribe(topic));
@@ -1469,6 +2296,13 @@ impl<TRepoTypes: RepoTypes> Future for IpfsFuture<TRepoTypes> {
                         let wantlist = self.swarm.behaviour_mut().bitswap().local_wantlist();
                         let _ = ret.send((stats, peers, wantlist).into());
                     }
+                    IpfsEvent::BitswapLedger(peer, ret) => {
+                        let ledger = match self.swarm.behaviour_mut().bitswap().peer_stats(&peer) {
+                            Some(stats) => BitswapLedger::new(peer, stats),
+                            None => BitswapLedger::new(peer, &Default::default()),
+                        };
+                        let _ = ret.send(ledger);
+                    }
                     IpfsEvent::AddListeningAddress(addr, ret) => {
                         self.start_add_listener_address(addr, Some(ret));
                     }
@@ -1496,10 +2330,25 @@ impl<TRepoTypes: RepoTypes> Future for IpfsFuture<TRepoTypes> {
                     IpfsEvent::AddPeer(peer_id, addr) => {
                         self.swarm.behaviour_mut().add_peer(peer_id, addr);
                     }
+                    IpfsEvent::AddProviderAddresses(peer_id, addrs) => {
+                        self.swarm
+                            .behaviour_mut()
+                            .add_provider_addresses(peer_id, addrs);
+                    }
                     IpfsEvent::GetClosestPeers(peer_id, ret) => {
                         let future = self.swarm.behaviour_mut().get_closest_peers(peer_id);
                         let _ = ret.send(future);
                     }
+                    IpfsEvent::LocalClosestPeers(key, count, ret) => {
+                        let peers = self.swarm.behaviour_mut().local_closest_peers(&key, count);
+                        let _ = ret.send(peers);
+                    }
+                    IpfsEvent::NetworkSurvey(ret) => {
+                        let _ = ret.send(self.swarm.behaviour().network_survey());
+                    }
+                    IpfsEvent::SyncRequest(peer, request, ret) => {
+                        self.swarm.behaviour_mut().sync_request(peer, request, ret);
+                    }
                     IpfsEvent::GetBitswapPeers(ret) => {
                         let peers = self
                             .swarm
@@ -1532,6 +2381,11 @@ impl<TRepoTypes: RepoTypes> Future for IpfsFuture<TRepoTypes> {
                         let future = self.swarm.behaviour_mut().get_providers(cid);
                         let _ = ret.send(future);
                     }
+                    IpfsEvent::DialPeers(peers) => {
+                        for peer_id in peers {
+                            self.swarm.behaviour_mut().bitswap().connect(peer_id);
+                        }
+                    }
                     IpfsEvent::Provide(cid, ret) => {
                         let _ = ret.send(self.swarm.behaviour_mut().start_providing(cid));
                     }
@@ -1559,8 +2413,8 @@ impl<TRepoTypes: RepoTypes> Future for IpfsFuture<TRepoTypes> {
                         let list = self.swarm.behaviour_mut().clear_bootstrappers();
                         let _ = ret.send(list);
                     }
-                    IpfsEvent::RestoreBootstrappers(ret) => {
-                        let list = self.swarm.behaviour_mut().restore_bootstrappers();
+                    IpfsEvent::RestoreBootstrappers(addrs, ret) => {
+                        let list = self.swarm.behaviour_mut().restore_bootstrappers(addrs);
                         let _ = ret.send(list);
                     }
                     IpfsEvent::Exit => {
@@ -1575,6 +2429,9 @@ impl<TRepoTypes: RepoTypes> Future for IpfsFuture<TRepoTypes> {
             while let Poll::Ready(Some(evt)) = Pin::new(&mut self.repo_events).poll_next(ctx) {
                 match evt {
                     RepoEvent::WantBlock(cid) => self.swarm.behaviour_mut().want_block(cid),
+                    RepoEvent::WantBlockFromPeers(cid) => {
+                        self.swarm.behaviour_mut().want_block_from_peers(cid)
+                    }
                     RepoEvent::UnwantBlock(cid) => {
                         self.swarm.behaviour_mut().bitswap().cancel_block(&cid)
                     }
@@ -1616,6 +2473,14 @@ pub struct BitswapStats {
     pub dup_blks_received: u64,
     /// The number of bytes in duplicate blocks received
     pub dup_data_received: u64,
+    /// The number of bitswap messages sent to other peers
+    pub messages_sent: u64,
+    /// The number of bitswap messages received from other peers
+    pub messages_received: u64,
+    /// The number of blocks other peers said they have but did not send in time
+    pub unfulfilled_haves: u64,
+    /// How many times other peers have been penalized for not sending the blocks they have
+    pub denial_penalties: u64,
     /// The current peers
     pub peers: Vec<PeerId>,
     /// The wantlist of the local node
@@ -1643,12 +2508,102 @@ impl
             data_received: stats.received_data.load(Ordering::Relaxed),
             dup_blks_received: stats.duplicate_blocks.load(Ordering::Relaxed),
             dup_data_received: stats.duplicate_data.load(Ordering::Relaxed),
+            messages_sent: stats.sent_messages.load(Ordering::Relaxed),
+            messages_received: stats.received_messages.load(Ordering::Relaxed),
+            unfulfilled_haves: stats.unfulfilled_haves.load(Ordering::Relaxed),
+            denial_penalties: stats.denial_penalties.load(Ordering::Relaxed),
             peers,
             wantlist,
         }
     }
 }
 
+/// The bitswap exchanges with a single peer
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BitswapLedger {
+    /// The other peer
+    pub peer: PeerId,
+    /// The number of IPFS blocks sent to the peer
+    pub blocks_sent: u64,
+    /// The number of bytes sent in IPFS blocks to the peer
+    pub data_sent: u64,
+    /// The number of IPFS blocks received from the peer
+    pub blocks_received: u64,
+    /// The number of bytes received in IPFS blocks from the peer
+    pub data_received: u64,
+    /// Duplicate blocks received from the peer
+    pub dup_blks_received: u64,
+    /// The number of bytes in duplicate blocks received from the peer
+    pub dup_data_received: u64,
+    /// The number of bitswap messages sent to the peer
+    pub messages_sent: u64,
+    /// The number of bitswap messages received from the peer
+    pub messages_received: u64,
+}
+
+impl BitswapLedger {
+    fn new(peer: PeerId, stats: &ipfs_bitswap::Stats) -> Self {
+        BitswapLedger {
+            peer,
+            blocks_sent: stats.sent_blocks.load(Ordering::Relaxed),
+            data_sent: stats.sent_data.load(Ordering::Relaxed),
+            blocks_received: stats.received_blocks.load(Ordering::Relaxed),
+            data_received: stats.received_data.load(Ordering::Relaxed),
+            dup_blks_received: stats.duplicate_blocks.load(Ordering::Relaxed),
+            dup_data_received: stats.duplicate_data.load(Ordering::Relaxed),
+            messages_sent: stats.sent_messages.load(Ordering::Relaxed),
+            messages_received: stats.received_messages.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The bytes received from the peer, including the duplicate blocks.
+    pub fn total_data_received(&self) -> u64 {
+        self.data_received + self.dup_data_received
+    }
+
+    /// The ratio of the bytes sent to the bytes received, like the "value" of the go-ipfs
+    /// ledger.
+    pub fn debt_ratio(&self) -> f64 {
+        self.data_sent as f64 / (self.total_data_received() as f64 + 1.0)
+    }
+
+    /// The number of blocks sent to and received from the peer, including the duplicates.
+    pub fn exchanged(&self) -> u64 {
+        self.blocks_sent + self.blocks_received + self.dup_blks_received
+    }
+}
+
+impl Ipfs<TestTypes> {
+    /// Starts a node for the tests, keeping everything in memory. The identity is derived from
+    /// the `seed`, so the same seed always gives the same [`PeerId`]. The node listens on a
+    /// `/memory/<port>` address, and can only connect to the other nodes of the same process,
+    /// started with this method or with [`IpfsOptions::memory_transport`].
+    ///
+    /// The background task is spawned on the current tokio runtime, and runs until
+    /// [`Ipfs::exit_daemon`] is called.
+    pub async fn test_node(seed: u64) -> Result<Self, Error> {
+        use libp2p::identity::ed25519;
+
+        let mut secret = [0u8; 32];
+        secret[..8].copy_from_slice(&seed.to_be_bytes());
+        let secret = ed25519::SecretKey::from_bytes(&mut secret)?;
+
+        let options = IpfsOptions {
+            keypair: Keypair::Ed25519(secret.into()),
+            listening_addrs: vec!["/memory/0".parse().unwrap()],
+            memory_transport: true,
+            kad_protocol: None,
+            span: Some(trace_span!("ipfs", seed)),
+            ..IpfsOptions::inmemory_with_generated_keys()
+        };
+
+        let (ipfs, fut) = UninitializedIpfs::new(options).start().await?;
+        tokio::task::spawn(fut);
+
+        Ok(ipfs)
+    }
+}
+
 #[doc(hidden)]
 pub use node::Node;
 
@@ -1808,6 +2763,92 @@ mod tests {
         assert_eq!(data, new_data);
     }
 
+    #[tokio::test]
+    async fn events_carry_the_span_of_the_operation() {
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (tx, mut rx) = channel(1);
+        let span = debug_span!("request", id = "abc");
+
+        TaskSender(tx)
+            .send(IpfsEvent::Exit)
+            .instrument(span.clone())
+            .await
+            .unwrap();
+
+        let (sent, _) = futures::StreamExt::next(&mut rx).await.unwrap();
+        assert!(span.id().is_some());
+        assert_eq!(sent.id(), span.id());
+    }
+
+    #[tokio::test]
+    async fn test_nodes_have_deterministic_identities() {
+        let first = Ipfs::test_node(1).await.unwrap();
+        let again = Ipfs::test_node(1).await.unwrap();
+        let second = Ipfs::test_node(2).await.unwrap();
+
+        let (first_key, first_addrs) = first.identity().await.unwrap();
+        assert_eq!(first_key, again.identity().await.unwrap().0);
+        assert_ne!(first_key, second.identity().await.unwrap().0);
+
+        // connected over the memory transport
+        let addr = MultiaddrWithPeerId::try_from(first_addrs[0].clone()).unwrap();
+        assert!(matches!(
+            addr.multiaddr.as_ref().iter().next(),
+            Some(Protocol::Memory(_))
+        ));
+        second.connect(addr).await.unwrap();
+        assert_eq!(second.peers().await.unwrap().len(), 1);
+
+        for ipfs in vec![first, again, second] {
+            ipfs.exit_daemon().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn independent_nodes_in_one_process() {
+        let first_dir = tempfile::tempdir().unwrap();
+        let second_dir = tempfile::tempdir().unwrap();
+
+        let start = |path: &std::path::Path| {
+            let options = IpfsOptions {
+                ipfs_path: path.to_owned(),
+                ..IpfsOptions::inmemory_with_generated_keys()
+            };
+            UninitializedIpfs::<Types>::new(options).start()
+        };
+
+        let (first, task) = start(first_dir.path()).await.unwrap();
+        tokio::spawn(task);
+        let (second, task) = start(second_dir.path()).await.unwrap();
+        tokio::spawn(task);
+
+        assert!(
+            start(first_dir.path()).await.is_err(),
+            "the repo is locked by the first node"
+        );
+
+        let data = b"only in the first".to_vec().into_boxed_slice();
+        let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data));
+        first
+            .put_block(Block::new(data, cid.clone()))
+            .await
+            .unwrap();
+
+        assert!(first.get_blocks_now(&[cid.clone()]).await.unwrap()[0].is_some());
+        assert!(second.get_blocks_now(&[cid]).await.unwrap()[0].is_none());
+
+        let (first_key, _) = first.identity().await.unwrap();
+        let (second_key, _) = second.identity().await.unwrap();
+        assert_ne!(first_key, second_key);
+
+        first.exit_daemon().await;
+        second.exit_daemon().await;
+    }
+
     #[tokio::test]
     async fn test_pin_and_unpin() {
         let ipfs = Node::new("test_node").await;
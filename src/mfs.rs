@@ -0,0 +1,932 @@
+//! Mutable File System: a posix-like namespace of files and directories on top of UnixFS, like
+//! the `ipfs files` commands of go-ipfs, see [`Ipfs::mfs`].
+//!
+//! The namespace is a UnixFS directory whose Cid is kept in [`Column::Files`] of the datastore.
+//! The blocks themselves are immutable, so each change stores the changed file or directory and
+//! then each of the directories on the path up to a new root. The changes are serialized, and the
+//! latest root is kept in memory; it is written to the datastore after every change, unless the
+//! changes are made through [`Mfs::with_flush`] with `false`, in which case only
+//! [`Mfs::flush`] writes it.
+//!
+//! The paths are absolute `/` separated paths like `/photos/cat.jpg`. [`Mfs::cp`] also accepts
+//! the `/ipfs/` and `/ipns/` paths as the source, for bringing content into the namespace. The
+//! mode and modification time of a changed directory are not kept.
+//!
+//! [`Column::Files`]: crate::repo::Column::Files
+
+use crate::dag::ResolveError;
+use crate::path::IpfsPath;
+use crate::unixfs::{cat, AddError, FileImport, StartingPoint, TraversalFailed};
+use crate::{Block, Error, Ipfs, IpfsTypes};
+use cid::{Cid, Codec};
+use futures::future::Either;
+use futures::stream::{self, Stream, StreamExt};
+use ipfs_unixfs::dir::builder::{
+    BufferingTreeBuilder, TreeBuildingFailed, TreeConstructionFailed, TreeNode, TreeOptions,
+};
+use ipfs_unixfs::{ListedEntry, Node};
+use std::str::FromStr;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// The ways the operations on the [`Mfs`] can fail.
+#[derive(Debug, thiserror::Error)]
+pub enum MfsError {
+    /// The path was not absolute, or contained `.` or `..` segments.
+    #[error("invalid path {0:?}")]
+    InvalidPath(String),
+    /// There was no file or directory at the path.
+    #[error("{0}: no such file or directory")]
+    NotFound(String),
+    /// There already was a file or directory at the path.
+    #[error("{0}: already exists")]
+    AlreadyExists(String),
+    /// A directory was expected at the path.
+    #[error("{0}: not a directory")]
+    NotADirectory(String),
+    /// A file was expected at the path.
+    #[error("{0}: is a directory")]
+    IsADirectory(String),
+    /// The root directory was to be removed or moved.
+    #[error("the root directory cannot be removed or moved")]
+    Root,
+    /// A directory was to be moved under itself.
+    #[error("cannot move {0} into itself")]
+    IntoItself(String),
+    /// The linked block was not a supported UnixFS node.
+    #[error("unsupported unixfs node {0}")]
+    UnsupportedNode(Cid),
+    /// Resolving the `/ipfs/` or `/ipns/` path to be copied failed.
+    #[error("failed to resolve {0}")]
+    Resolving(String, #[source] ResolveError),
+    /// Storing the written file failed.
+    #[error("failed to import the file")]
+    Import(#[source] AddError),
+    /// Reading the previous contents of the written file failed.
+    #[error("failed to read the file")]
+    Read(#[source] TraversalFailed),
+    /// The directory to be stored was invalid.
+    #[error("invalid directory tree")]
+    TreeGathering(#[source] TreeBuildingFailed),
+    /// Building the directory to be stored failed.
+    #[error("constructed invalid directory tree")]
+    TreeBuilding(#[source] TreeConstructionFailed),
+    /// Loading or storing a block or the root failed.
+    #[error("repo operation failed")]
+    Repo(#[source] Error),
+}
+
+/// The kind of an entry in the [`Mfs`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// The information on an entry returned by [`Mfs::stat`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MfsStat {
+    pub cid: Cid,
+    pub kind: EntryKind,
+    /// The size of the file contents or of the symlink target, zero for the directories.
+    pub size: u64,
+    /// The size of the block and all of the linked blocks.
+    pub cumulative_size: u64,
+    /// The number of links in the block.
+    pub blocks: usize,
+}
+
+/// Options for [`Mfs::write`].
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// The offset in the file the data is written at. The file is padded with zeroes up to the
+    /// offset if it is shorter.
+    pub offset: u64,
+    /// Create the file if it does not exist.
+    pub create: bool,
+    /// Create the missing parent directories.
+    pub parents: bool,
+    /// Discard the previous contents of the file before writing.
+    pub truncate: bool,
+}
+
+/// The root of the [`Mfs`] shared by the clones of an [`Ipfs`].
+#[derive(Debug, Default)]
+pub(crate) struct FilesRoot {
+    state: Mutex<RootState>,
+}
+
+#[derive(Debug, Default)]
+struct RootState {
+    /// The latest root, loaded from the datastore on the first use.
+    cid: Option<Cid>,
+    /// True if the latest root has not been written to the datastore.
+    dirty: bool,
+}
+
+impl FilesRoot {
+    /// The latest root, or `None` if it has not been loaded from the datastore yet.
+    pub(crate) async fn current_root(&self) -> Option<Cid> {
+        self.state.lock().await.cid.clone()
+    }
+}
+
+/// The mutable file system of the node, see [`crate::mfs`].
+#[derive(Clone, Debug)]
+pub struct Mfs<Types: IpfsTypes> {
+    ipfs: Ipfs<Types>,
+    flush: bool,
+}
+
+impl<Types: IpfsTypes> Ipfs<Types> {
+    /// Returns the mutable file system of the node, writing the root to the datastore after each
+    /// change.
+    pub fn mfs(&self) -> Mfs<Types> {
+        Mfs {
+            ipfs: self.clone(),
+            flush: true,
+        }
+    }
+}
+
+impl<Types: IpfsTypes> Mfs<Types> {
+    /// Sets whether the root is written to the datastore after each change made through the
+    /// returned value, like the `--flush` option of `ipfs files`. The unflushed changes are lost
+    /// if the node is stopped before [`Mfs::flush`] is called.
+    pub fn with_flush(mut self, flush: bool) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Creates the directory at `path`. With `parents` the missing parent directories are
+    /// created as well, and an existing directory is not an error.
+    pub async fn mkdir(&self, path: &str, parents: bool) -> Result<(), MfsError> {
+        let segments = segments(path)?;
+        let mut state = self.lock().await?;
+        let root = state.cid.clone().expect("loaded by lock");
+
+        if let Some(existing) = self.lookup(&root, &segments).await? {
+            if parents && self.stat_cid(&existing.cid).await?.kind == EntryKind::Directory {
+                return Ok(());
+            }
+            return Err(MfsError::AlreadyExists(join(&segments)));
+        }
+
+        let directory = self.put_directory(Vec::new()).await?;
+        let root = self
+            .replace(&root, &segments, Some(directory), parents)
+            .await?;
+        self.commit(&mut state, root).await
+    }
+
+    /// Writes the `data` to the file at `path`, see [`WriteOptions`]. The file is stored again as
+    /// a whole.
+    pub async fn write(
+        &self,
+        path: &str,
+        data: &[u8],
+        opts: &WriteOptions,
+    ) -> Result<(), MfsError> {
+        let segments = segments(path)?;
+        let mut state = self.lock().await?;
+        let root = state.cid.clone().expect("loaded by lock");
+
+        let previous = match self.lookup(&root, &segments).await? {
+            Some(existing) => {
+                let block = self.get_block(&existing.cid).await?;
+                if file_kind(&block)?.0 != EntryKind::File {
+                    return Err(MfsError::IsADirectory(join(&segments)));
+                }
+                if opts.truncate {
+                    None
+                } else {
+                    Some(block)
+                }
+            }
+            None if opts.create => None,
+            None => return Err(MfsError::NotFound(join(&segments))),
+        };
+
+        let (cid, total_size) = self.import(previous, data, opts.offset).await?;
+
+        let file = ListedEntry {
+            name: String::new(),
+            cid,
+            total_size,
+        };
+        let root = self
+            .replace(&root, &segments, Some(file), opts.parents)
+            .await?;
+        self.commit(&mut state, root).await
+    }
+
+    /// Reads the file at `path` from the `offset`, at most `count` bytes if given.
+    pub async fn read(
+        &self,
+        path: &str,
+        offset: u64,
+        count: Option<u64>,
+    ) -> Result<impl Stream<Item = Result<Vec<u8>, TraversalFailed>> + Send + 'static, MfsError>
+    {
+        let segments = segments(path)?;
+        let entry = {
+            let state = self.lock().await?;
+            let root = state.cid.as_ref().expect("loaded by lock");
+            self.lookup(root, &segments)
+                .await?
+                .ok_or_else(|| MfsError::NotFound(join(&segments)))?
+        };
+
+        let block = self.get_block(&entry.cid).await?;
+        if file_kind(&block)?.0 != EntryKind::File {
+            return Err(MfsError::IsADirectory(join(&segments)));
+        }
+
+        let end = count.map(|count| offset.saturating_add(count));
+
+        if block.cid.codec() == Codec::Raw {
+            let len = block.data.len();
+            let start = (offset.min(len as u64)) as usize;
+            let end = end.map(|end| end.min(len as u64) as usize).unwrap_or(len);
+            let bytes = block.data[start..end.max(start)].to_vec();
+            return Ok(Either::Left(stream::once(async move {
+                Ok::<_, TraversalFailed>(bytes)
+            })));
+        }
+
+        let range = match (offset, end) {
+            (0, None) => None,
+            (offset, end) => Some(offset..end.unwrap_or(u64::MAX)),
+        };
+
+        let stream = cat(self.ipfs.clone(), StartingPoint::Right(block), range)
+            .await
+            .map_err(MfsError::Read)?;
+        Ok(Either::Right(stream))
+    }
+
+    /// Copies the file or directory at `from` to `to`, which must not exist. The source can also
+    /// be an `/ipfs/` or `/ipns/` path.
+    pub async fn cp(&self, from: &str, to: &str) -> Result<(), MfsError> {
+        let to_segments = segments(to)?;
+        let mut state = self.lock().await?;
+        let root = state.cid.clone().expect("loaded by lock");
+
+        let source = if from.starts_with("/ipfs/") || from.starts_with("/ipns/") {
+            let path =
+                IpfsPath::from_str(from).map_err(|_| MfsError::InvalidPath(from.to_owned()))?;
+            let (resolved, _) = self
+                .ipfs
+                .dag()
+                .resolve(path, true)
+                .await
+                .map_err(|e| MfsError::Resolving(from.to_owned(), e))?;
+            let cid = resolved.source().to_owned();
+            let total_size = self.stat_cid(&cid).await?.cumulative_size;
+            ListedEntry {
+                name: String::new(),
+                cid,
+                total_size,
+            }
+        } else {
+            let from_segments = segments(from)?;
+            self.lookup(&root, &from_segments)
+                .await?
+                .ok_or_else(|| MfsError::NotFound(join(&from_segments)))?
+        };
+
+        if to_segments.is_empty() || self.lookup(&root, &to_segments).await?.is_some() {
+            return Err(MfsError::AlreadyExists(join(&to_segments)));
+        }
+
+        let root = self
+            .replace(&root, &to_segments, Some(source), false)
+            .await?;
+        self.commit(&mut state, root).await
+    }
+
+    /// Moves the file or directory at `from` to `to`. If `to` is an existing directory the entry
+    /// is moved into it.
+    pub async fn mv(&self, from: &str, to: &str) -> Result<(), MfsError> {
+        let from_segments = segments(from)?;
+        let mut to_segments = segments(to)?;
+        let mut state = self.lock().await?;
+        let root = state.cid.clone().expect("loaded by lock");
+
+        let name = match from_segments.last() {
+            Some(name) => *name,
+            None => return Err(MfsError::Root),
+        };
+
+        let entry = self
+            .lookup(&root, &from_segments)
+            .await?
+            .ok_or_else(|| MfsError::NotFound(join(&from_segments)))?;
+
+        if let Some(existing) = self.lookup(&root, &to_segments).await? {
+            if self.stat_cid(&existing.cid).await?.kind != EntryKind::Directory {
+                return Err(MfsError::AlreadyExists(join(&to_segments)));
+            }
+            to_segments.push(name);
+            if self.lookup(&root, &to_segments).await?.is_some() {
+                return Err(MfsError::AlreadyExists(join(&to_segments)));
+            }
+        }
+
+        if to_segments.starts_with(&from_segments) {
+            return Err(MfsError::IntoItself(join(&from_segments)));
+        }
+
+        let root = self.replace(&root, &from_segments, None, false).await?;
+        let root = self
+            .replace(&root, &to_segments, Some(entry), false)
+            .await?;
+        self.commit(&mut state, root).await
+    }
+
+    /// Removes the file or directory at `path`. The directories are only removed with
+    /// `recursive`.
+    pub async fn rm(&self, path: &str, recursive: bool) -> Result<(), MfsError> {
+        let segments = segments(path)?;
+        if segments.is_empty() {
+            return Err(MfsError::Root);
+        }
+
+        let mut state = self.lock().await?;
+        let root = state.cid.clone().expect("loaded by lock");
+
+        let entry = self
+            .lookup(&root, &segments)
+            .await?
+            .ok_or_else(|| MfsError::NotFound(join(&segments)))?;
+
+        if !recursive && self.stat_cid(&entry.cid).await?.kind == EntryKind::Directory {
+            return Err(MfsError::IsADirectory(join(&segments)));
+        }
+
+        let root = self.replace(&root, &segments, None, false).await?;
+        self.commit(&mut state, root).await
+    }
+
+    /// Returns the information on the file or directory at `path`.
+    pub async fn stat(&self, path: &str) -> Result<MfsStat, MfsError> {
+        let segments = segments(path)?;
+        let state = self.lock().await?;
+        let root = state.cid.as_ref().expect("loaded by lock");
+
+        let entry = self
+            .lookup(root, &segments)
+            .await?
+            .ok_or_else(|| MfsError::NotFound(join(&segments)))?;
+        self.stat_cid(&entry.cid).await
+    }
+
+    /// Lists the entries of the directory at `path` ordered by name, or the file itself.
+    pub async fn ls(&self, path: &str) -> Result<Vec<ListedEntry>, MfsError> {
+        let segments = segments(path)?;
+        let state = self.lock().await?;
+        let root = state.cid.as_ref().expect("loaded by lock");
+
+        let entry = self
+            .lookup(root, &segments)
+            .await?
+            .ok_or_else(|| MfsError::NotFound(join(&segments)))?;
+
+        if self.stat_cid(&entry.cid).await?.kind != EntryKind::Directory {
+            return Ok(vec![entry]);
+        }
+
+        let mut entries = self.list_directory(&entry.cid, &segments).await?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// Writes the latest root to the datastore if it has changed, returning the Cid of the file
+    /// or directory at `path`.
+    pub async fn flush(&self, path: &str) -> Result<Cid, MfsError> {
+        let segments = segments(path)?;
+        let mut state = self.lock().await?;
+        let root = state.cid.clone().expect("loaded by lock");
+
+        if state.dirty {
+            self.ipfs
+                .repo
+                .put_files_root(&root)
+                .await
+                .map_err(MfsError::Repo)?;
+            state.dirty = false;
+        }
+
+        self.lookup(&root, &segments)
+            .await?
+            .map(|entry| entry.cid)
+            .ok_or_else(|| MfsError::NotFound(join(&segments)))
+    }
+
+    /// Locks the root for the duration of an operation, loading it first if needed. An empty
+    /// root directory is created on the first use.
+    async fn lock(&self) -> Result<MutexGuard<'_, RootState>, MfsError> {
+        let mut state = self.ipfs.files.state.lock().await;
+
+        if state.cid.is_none() {
+            let stored = self
+                .ipfs
+                .repo
+                .get_files_root()
+                .await
+                .map_err(MfsError::Repo)?;
+
+            let root = match stored {
+                Some(root) => root,
+                None => {
+                    let root = self.put_directory(Vec::new()).await?.cid;
+                    self.ipfs
+                        .repo
+                        .put_files_root(&root)
+                        .await
+                        .map_err(MfsError::Repo)?;
+                    root
+                }
+            };
+
+            state.cid = Some(root);
+        }
+
+        Ok(state)
+    }
+
+    /// Replaces the root with the changed one.
+    async fn commit(&self, state: &mut RootState, root: Cid) -> Result<(), MfsError> {
+        if self.flush {
+            self.ipfs
+                .repo
+                .put_files_root(&root)
+                .await
+                .map_err(MfsError::Repo)?;
+        }
+        state.dirty = !self.flush;
+        state.cid = Some(root);
+        Ok(())
+    }
+
+    /// Returns the link to the entry at the `segments` under the `root`, or `None` if it or any
+    /// of its parent directories does not exist.
+    async fn lookup(&self, root: &Cid, segments: &[&str]) -> Result<Option<ListedEntry>, MfsError> {
+        let mut entry = ListedEntry {
+            name: String::new(),
+            cid: root.to_owned(),
+            total_size: 0,
+        };
+
+        for (depth, segment) in segments.iter().enumerate() {
+            let found = self
+                .list_directory(&entry.cid, &segments[..depth])
+                .await?
+                .into_iter()
+                .find(|entry| entry.name == *segment);
+
+            entry = match found {
+                Some(found) => found,
+                None => return Ok(None),
+            };
+        }
+
+        if segments.is_empty() {
+            entry.total_size = self.stat_cid(root).await?.cumulative_size;
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// Replaces the entry at the `segments` under the `root` with the `link`, or removes it with
+    /// `None`, and stores the changed directories. The missing parent directories are created
+    /// with `parents`. Returns the new root.
+    async fn replace(
+        &self,
+        root: &Cid,
+        segments: &[&str],
+        link: Option<ListedEntry>,
+        parents: bool,
+    ) -> Result<Cid, MfsError> {
+        if segments.is_empty() {
+            return Err(MfsError::Root);
+        }
+
+        // the entries of the root and each of the parents of the replaced entry
+        let mut directories = Vec::with_capacity(segments.len());
+        let mut next = Some(root.to_owned());
+
+        for depth in 0..segments.len() {
+            let entries = match next.take() {
+                Some(cid) => self.list_directory(&cid, &segments[..depth]).await?,
+                None if parents => Vec::new(),
+                None => return Err(MfsError::NotFound(join(&segments[..depth]))),
+            };
+
+            if depth + 1 < segments.len() {
+                next = entries
+                    .iter()
+                    .find(|entry| entry.name == segments[depth])
+                    .map(|entry| entry.cid.to_owned());
+            }
+
+            directories.push(entries);
+        }
+
+        let mut link = link;
+        for (depth, mut entries) in directories.into_iter().enumerate().rev() {
+            let name = segments[depth];
+            entries.retain(|entry| entry.name != name);
+            if let Some(link) = link.take() {
+                entries.push(ListedEntry {
+                    name: name.to_owned(),
+                    ..link
+                });
+            }
+            link = Some(self.put_directory(entries).await?);
+        }
+
+        Ok(link.expect("the root is always stored").cid)
+    }
+
+    /// Returns the entries of the directory, including the entries in the buckets of a sharded
+    /// directory.
+    async fn list_directory(
+        &self,
+        cid: &Cid,
+        segments: &[&str],
+    ) -> Result<Vec<ListedEntry>, MfsError> {
+        let mut entries = Vec::new();
+        let mut pending = vec![cid.to_owned()];
+
+        while let Some(cid) = pending.pop() {
+            if cid.codec() != Codec::DagProtobuf {
+                return Err(MfsError::NotADirectory(join(segments)));
+            }
+
+            let block = self.get_block(&cid).await?;
+            let listing = ipfs_unixfs::dir::list(&block.data)
+                .map_err(|_| MfsError::NotADirectory(join(segments)))?;
+
+            entries.extend(listing.entries);
+            pending.extend(listing.buckets);
+        }
+
+        Ok(entries)
+    }
+
+    /// Stores a directory of the `entries`, returning the link to it.
+    async fn put_directory(&self, entries: Vec<ListedEntry>) -> Result<ListedEntry, MfsError> {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let mut tree = BufferingTreeBuilder::new(opts);
+
+        for entry in entries {
+            tree.put_link(&entry.name, entry.cid, entry.total_size)
+                .map_err(MfsError::TreeGathering)?;
+        }
+
+        let mut iter = tree.build();
+        let mut root = None;
+
+        while let Some(res) = iter.next_borrowed() {
+            let TreeNode {
+                cid,
+                total_size,
+                block,
+                bucket,
+                ..
+            } = res.map_err(MfsError::TreeBuilding)?;
+
+            self.ipfs
+                .put_block(Block {
+                    cid: cid.to_owned(),
+                    data: block.into(),
+                })
+                .await
+                .map_err(MfsError::Repo)?;
+
+            if !bucket {
+                root = Some(ListedEntry {
+                    name: String::new(),
+                    cid: cid.to_owned(),
+                    total_size,
+                });
+            }
+        }
+
+        Ok(root.expect("the wrapping directory is always built"))
+    }
+
+    /// Stores the contents of the `previous` file with the `data` written at the `offset`,
+    /// returning the root Cid and the cumulative size of the blocks.
+    async fn import(
+        &self,
+        previous: Option<Block>,
+        data: &[u8],
+        offset: u64,
+    ) -> Result<(Cid, u64), MfsError> {
+        let ipfs = &self.ipfs;
+        let mut import = FileImport::default();
+        let end = offset + data.len() as u64;
+        let mut written = false;
+        let mut position = 0;
+
+        if let Some(previous) = previous {
+            let contents = self.contents(previous).await?;
+            futures::pin_mut!(contents);
+
+            while let Some(chunk) = contents.next().await {
+                let chunk = chunk.map_err(MfsError::Read)?;
+                let chunk_end = position + chunk.len() as u64;
+
+                // the parts before the offset and after the end of the written data are kept
+                if position < offset {
+                    let keep = (offset.min(chunk_end) - position) as usize;
+                    import
+                        .push(ipfs, &chunk[..keep])
+                        .await
+                        .map_err(MfsError::Import)?;
+                }
+
+                if chunk_end > end {
+                    if !written {
+                        import.push(ipfs, data).await.map_err(MfsError::Import)?;
+                        written = true;
+                    }
+                    let skip = (end.max(position) - position) as usize;
+                    import
+                        .push(ipfs, &chunk[skip..])
+                        .await
+                        .map_err(MfsError::Import)?;
+                }
+
+                position = chunk_end;
+            }
+        }
+
+        if !written {
+            if position < offset {
+                let zeroes = vec![0u8; (offset - position).min(64 * 1024) as usize];
+                while position < offset {
+                    let len = (offset - position).min(zeroes.len() as u64) as usize;
+                    import
+                        .push(ipfs, &zeroes[..len])
+                        .await
+                        .map_err(MfsError::Import)?;
+                    position += len as u64;
+                }
+            }
+            import.push(ipfs, data).await.map_err(MfsError::Import)?;
+        }
+
+        import.finish(ipfs).await.map_err(MfsError::Import)
+    }
+
+    /// Returns the contents of the file `block`.
+    async fn contents(
+        &self,
+        block: Block,
+    ) -> Result<impl Stream<Item = Result<Vec<u8>, TraversalFailed>> + Send + '_, MfsError> {
+        if block.cid.codec() == Codec::Raw {
+            let bytes = block.data.to_vec();
+            return Ok(Either::Left(stream::once(async move {
+                Ok::<_, TraversalFailed>(bytes)
+            })));
+        }
+
+        let stream = cat(&self.ipfs, StartingPoint::Right(block), None)
+            .await
+            .map_err(MfsError::Read)?;
+        Ok(Either::Right(stream))
+    }
+
+    async fn stat_cid(&self, cid: &Cid) -> Result<MfsStat, MfsError> {
+        let block = self.get_block(cid).await?;
+        let (kind, size) = file_kind(&block)?;
+
+        let (blocks, linked) = if block.cid.codec() == Codec::Raw {
+            (0, 0)
+        } else {
+            let node =
+                Node::parse(&block.data).map_err(|_| MfsError::UnsupportedNode(cid.to_owned()))?;
+            let links = node.links();
+            let linked = links
+                .iter()
+                .map(|link| link.total_size.unwrap_or_default())
+                .sum::<u64>();
+            (links.len(), linked)
+        };
+
+        Ok(MfsStat {
+            cid: cid.to_owned(),
+            kind,
+            size,
+            cumulative_size: block.data.len() as u64 + linked,
+            blocks,
+        })
+    }
+
+    async fn get_block(&self, cid: &Cid) -> Result<Block, MfsError> {
+        self.ipfs.get_block(cid).await.map_err(MfsError::Repo)
+    }
+}
+
+/// Returns the kind of the UnixFS `block` and the size of its contents.
+fn file_kind(block: &Block) -> Result<(EntryKind, u64), MfsError> {
+    if block.cid.codec() == Codec::Raw {
+        return Ok((EntryKind::File, block.data.len() as u64));
+    }
+
+    match Node::parse(&block.data) {
+        Ok(Node::File(file)) => Ok((EntryKind::File, file.file_size())),
+        Ok(Node::Directory(_)) | Ok(Node::ShardedDirectory(_)) => Ok((EntryKind::Directory, 0)),
+        Ok(Node::Symlink(symlink)) => Ok((EntryKind::Symlink, symlink.target().len() as u64)),
+        Err(_) => Err(MfsError::UnsupportedNode(block.cid.to_owned())),
+    }
+}
+
+/// Splits the absolute `path` into its segments.
+fn segments(path: &str) -> Result<Vec<&str>, MfsError> {
+    if !path.starts_with('/') {
+        return Err(MfsError::InvalidPath(path.to_owned()));
+    }
+
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    if segments
+        .iter()
+        .any(|segment| *segment == "." || *segment == "..")
+    {
+        return Err(MfsError::InvalidPath(path.to_owned()));
+    }
+
+    Ok(segments)
+}
+
+fn join(segments: &[&str]) -> String {
+    format!("/{}", segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EntryKind, MfsError, WriteOptions};
+    use crate::{Ipfs, Node, TestTypes};
+    use futures::stream::TryStreamExt;
+
+    fn create() -> WriteOptions {
+        WriteOptions {
+            create: true,
+            ..Default::default()
+        }
+    }
+
+    async fn read(ipfs: &Ipfs<TestTypes>, path: &str) -> Vec<u8> {
+        let chunks = ipfs
+            .mfs()
+            .read(path, 0, None)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        chunks.concat()
+    }
+
+    #[tokio::test]
+    async fn write_read_and_list() {
+        let ipfs = Node::new("test_node").await;
+        let mfs = ipfs.mfs();
+
+        mfs.mkdir("/docs/notes", true).await.unwrap();
+        assert!(matches!(
+            mfs.mkdir("/docs", false).await,
+            Err(MfsError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            mfs.write("/missing", b"data", &WriteOptions::default())
+                .await,
+            Err(MfsError::NotFound(_))
+        ));
+
+        mfs.write("/docs/notes/todo.txt", b"hello world", &create())
+            .await
+            .unwrap();
+        assert_eq!(read(&ipfs, "/docs/notes/todo.txt").await, b"hello world");
+
+        let overwrite = WriteOptions {
+            offset: 6,
+            ..Default::default()
+        };
+        mfs.write("/docs/notes/todo.txt", b"WORLD", &overwrite)
+            .await
+            .unwrap();
+        assert_eq!(read(&ipfs, "/docs/notes/todo.txt").await, b"hello WORLD");
+
+        let past_the_end = WriteOptions {
+            offset: 13,
+            ..Default::default()
+        };
+        mfs.write("/docs/notes/todo.txt", b"!", &past_the_end)
+            .await
+            .unwrap();
+        assert_eq!(
+            read(&ipfs, "/docs/notes/todo.txt").await,
+            b"hello WORLD\0\0!"
+        );
+
+        let partial = mfs
+            .read("/docs/notes/todo.txt", 6, Some(5))
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+            .concat();
+        assert_eq!(partial, b"WORLD");
+
+        let stat = mfs.stat("/docs/notes/todo.txt").await.unwrap();
+        assert_eq!(stat.kind, EntryKind::File);
+        assert_eq!(stat.size, 14);
+
+        mfs.write("/docs/readme", b"read me", &create())
+            .await
+            .unwrap();
+        let names = mfs
+            .ls("/docs")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["notes", "readme"]);
+
+        assert!(matches!(
+            mfs.read("/docs", 0, None).await.map(|_| ()),
+            Err(MfsError::IsADirectory(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn copy_move_and_remove() {
+        let ipfs = Node::new("test_node").await;
+        let mfs = ipfs.mfs();
+
+        mfs.write("/a.txt", b"a", &create()).await.unwrap();
+        mfs.mkdir("/dir", false).await.unwrap();
+
+        mfs.cp("/a.txt", "/dir/b.txt").await.unwrap();
+        mfs.mv("/a.txt", "/dir").await.unwrap();
+        assert_eq!(read(&ipfs, "/dir/a.txt").await, b"a");
+        assert_eq!(read(&ipfs, "/dir/b.txt").await, b"a");
+        assert!(matches!(
+            mfs.stat("/a.txt").await,
+            Err(MfsError::NotFound(_))
+        ));
+
+        assert!(matches!(
+            mfs.mv("/dir", "/dir/inner").await,
+            Err(MfsError::IntoItself(_))
+        ));
+
+        let cid = mfs.flush("/dir").await.unwrap();
+        mfs.cp(&format!("/ipfs/{}", cid), "/copy").await.unwrap();
+        assert_eq!(read(&ipfs, "/copy/b.txt").await, b"a");
+
+        assert!(matches!(
+            mfs.rm("/copy", false).await,
+            Err(MfsError::IsADirectory(_))
+        ));
+        mfs.rm("/copy", true).await.unwrap();
+        mfs.rm("/dir/b.txt", false).await.unwrap();
+        assert!(matches!(mfs.rm("/", true).await, Err(MfsError::Root)));
+
+        let names = mfs
+            .ls("/")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["dir"]);
+    }
+
+    #[tokio::test]
+    async fn root_is_written_on_flush() {
+        let ipfs = Node::new("test_node").await;
+
+        ipfs.mfs().mkdir("/flushed", false).await.unwrap();
+        let flushed = ipfs.repo.get_files_root().await.unwrap().unwrap();
+        assert_eq!(ipfs.mfs().flush("/").await.unwrap(), flushed);
+
+        let unflushed = ipfs.mfs().with_flush(false);
+        unflushed.mkdir("/unflushed", false).await.unwrap();
+        assert_eq!(ipfs.repo.get_files_root().await.unwrap(), Some(flushed));
+
+        let root = unflushed.flush("/").await.unwrap();
+        assert_eq!(ipfs.repo.get_files_root().await.unwrap(), Some(root));
+    }
+}
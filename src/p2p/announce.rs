@@ -0,0 +1,266 @@
+//! Policies for the addresses advertised to other peers.
+use libp2p::{
+    multiaddr::{self, Protocol},
+    Multiaddr,
+};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// Selects which of the listened and observed addresses are advertised, similar to the
+/// `Addresses.Announce`, `Addresses.AppendAnnounce` and `Addresses.NoAnnounce` of go-ipfs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnnounceConfig {
+    /// When not empty, only these addresses are advertised instead of the listened and observed
+    /// addresses, useful when the node is reachable only through a forwarded public address.
+    pub announce: Vec<Multiaddr>,
+    /// Addresses advertised in addition to the listened and observed, or the `announce`
+    /// addresses.
+    pub append_announce: Vec<Multiaddr>,
+    /// Addresses matching any of these are never advertised.
+    pub no_announce: Vec<AddrFilter>,
+}
+
+impl AnnounceConfig {
+    /// Returns true if the address is not filtered out by any of the `no_announce` filters.
+    pub fn allows(&self, addr: &Multiaddr) -> bool {
+        !self.no_announce.iter().any(|filter| filter.matches(addr))
+    }
+
+    /// Returns the addresses to advertise out of the `listeners` and the `observed` external
+    /// addresses.
+    ///
+    /// On dual-stack hosts the remote peers report the address they saw for the connection, which
+    /// for example for an IPv6 connection is an IPv6 address even if the node listens only on
+    /// IPv4. Such observed addresses, along with the loopback and unspecified ones, are dropped
+    /// as no one could connect to them.
+    pub(crate) fn announced<'a>(
+        &self,
+        listeners: impl Iterator<Item = &'a Multiaddr>,
+        observed: impl Iterator<Item = &'a Multiaddr>,
+    ) -> Vec<Multiaddr> {
+        let mut addresses = Vec::new();
+
+        if self.announce.is_empty() {
+            let listeners = listeners.cloned().collect::<Vec<_>>();
+
+            let listened_families = listeners
+                .iter()
+                .filter_map(ip_of)
+                .map(|ip| ip.is_ipv4())
+                .collect::<Vec<_>>();
+
+            addresses.extend(listeners.iter().cloned());
+            addresses.extend(
+                observed
+                    .filter(|addr| match ip_of(addr) {
+                        Some(ip) => {
+                            !ip.is_loopback()
+                                && !ip.is_unspecified()
+                                && listened_families.contains(&ip.is_ipv4())
+                        }
+                        // dns and other non-ip addresses are kept as is
+                        None => true,
+                    })
+                    .cloned(),
+            );
+        } else {
+            addresses.extend(self.announce.iter().cloned());
+        }
+
+        addresses.extend(self.append_announce.iter().cloned());
+
+        let mut announced = Vec::with_capacity(addresses.len());
+        for addr in addresses {
+            if self.allows(&addr) && !announced.contains(&addr) {
+                announced.push(addr);
+            }
+        }
+        announced
+    }
+}
+
+/// A `no_announce` filter, either an exact address or an IP network in the go-ipfs format of
+/// `/ip4/10.0.0.0/ipcidr/8` or `/ip6/fe80::/ipcidr/10`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddrFilter {
+    /// Matches only the same address.
+    Exact(Multiaddr),
+    /// Matches all of the addresses within the network, regardless of the transport.
+    Cidr(IpAddr, u8),
+}
+
+/// An error that can be thrown when parsing an [`AddrFilter`].
+#[derive(Debug)]
+pub enum AddrFilterError {
+    /// The address part of the filter is invalid.
+    InvalidMultiaddr(multiaddr::Error),
+    /// The `ipcidr` prefix length is not a number or is too long for the address.
+    InvalidPrefixLength(String),
+}
+
+impl fmt::Display for AddrFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for AddrFilterError {}
+
+impl AddrFilter {
+    /// Returns true if the address matches this filter.
+    pub fn matches(&self, addr: &Multiaddr) -> bool {
+        match self {
+            AddrFilter::Exact(exact) => exact == addr,
+            AddrFilter::Cidr(network, prefix) => match ip_of(addr) {
+                Some(ip) => in_network(&ip, network, *prefix),
+                None => false,
+            },
+        }
+    }
+}
+
+impl FromStr for AddrFilter {
+    type Err = AddrFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // the ipcidr protocol is not supported by the multiaddr crate
+        if let Some((network, prefix)) = s.rsplit_once("/ipcidr/") {
+            let network = network
+                .parse::<Multiaddr>()
+                .map_err(AddrFilterError::InvalidMultiaddr)?;
+
+            let mut iter = network.iter();
+            let ip = match (iter.next(), iter.next()) {
+                (Some(Protocol::Ip4(ip)), None) => IpAddr::V4(ip),
+                (Some(Protocol::Ip6(ip)), None) => IpAddr::V6(ip),
+                _ => return Err(AddrFilterError::InvalidPrefixLength(s.to_owned())),
+            };
+
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix = prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| AddrFilterError::InvalidPrefixLength(prefix.to_owned()))?;
+
+            Ok(AddrFilter::Cidr(ip, prefix))
+        } else {
+            s.parse::<Multiaddr>()
+                .map(AddrFilter::Exact)
+                .map_err(AddrFilterError::InvalidMultiaddr)
+        }
+    }
+}
+
+impl fmt::Display for AddrFilter {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddrFilter::Exact(addr) => fmt::Display::fmt(addr, fmt),
+            AddrFilter::Cidr(IpAddr::V4(ip), prefix) => {
+                write!(fmt, "/ip4/{}/ipcidr/{}", ip, prefix)
+            }
+            AddrFilter::Cidr(IpAddr::V6(ip), prefix) => {
+                write!(fmt, "/ip6/{}/ipcidr/{}", ip, prefix)
+            }
+        }
+    }
+}
+
+/// Returns the IP address the multiaddr starts with.
+fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    match addr.iter().next()? {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    }
+}
+
+fn in_network(ip: &IpAddr, network: &IpAddr, prefix: u8) -> bool {
+    fn prefix_eq(a: &[u8], b: &[u8], prefix: u8) -> bool {
+        let bytes = usize::from(prefix / 8);
+        let bits = prefix % 8;
+
+        if a[..bytes] != b[..bytes] {
+            return false;
+        }
+
+        bits == 0 || {
+            let mask = 0xffu8 << (8 - bits);
+            a[bytes] & mask == b[bytes] & mask
+        }
+    }
+
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => prefix_eq(&ip.octets(), &network.octets(), prefix),
+        (IpAddr::V6(ip), IpAddr::V6(network)) => prefix_eq(&ip.octets(), &network.octets(), prefix),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<Multiaddr> {
+        addrs.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn cidr_filters() {
+        let filter: AddrFilter = "/ip4/10.0.0.0/ipcidr/8".parse().unwrap();
+        assert_eq!(filter.to_string(), "/ip4/10.0.0.0/ipcidr/8");
+        assert!(filter.matches(&"/ip4/10.1.2.3/tcp/4001".parse().unwrap()));
+        assert!(!filter.matches(&"/ip4/11.0.0.1/tcp/4001".parse().unwrap()));
+        assert!(!filter.matches(&"/ip6/::1/tcp/4001".parse().unwrap()));
+
+        let filter: AddrFilter = "/ip6/fe80::/ipcidr/10".parse().unwrap();
+        assert!(filter.matches(&"/ip6/fe80::1/tcp/4001".parse().unwrap()));
+        assert!(filter.matches(&"/ip6/febf::1/tcp/4001".parse().unwrap()));
+        assert!(!filter.matches(&"/ip6/fec0::1/tcp/4001".parse().unwrap()));
+
+        assert!("/ip4/10.0.0.0/ipcidr/33".parse::<AddrFilter>().is_err());
+        assert!("/ip4/10.0.0.0/tcp/1/ipcidr/8"
+            .parse::<AddrFilter>()
+            .is_err());
+    }
+
+    #[test]
+    fn observed_addresses_of_other_family_are_dropped() {
+        let config = AnnounceConfig {
+            no_announce: vec!["/ip4/192.168.0.0/ipcidr/16".parse().unwrap()],
+            append_announce: addrs(&["/dns4/example.com/tcp/4001"]),
+            ..Default::default()
+        };
+
+        let listeners = addrs(&["/ip4/192.168.1.2/tcp/4001", "/ip4/1.2.3.4/tcp/4001"]);
+        let observed = addrs(&[
+            "/ip4/5.6.7.8/tcp/4001",
+            "/ip6/2001:db8::1/tcp/4001",
+            "/ip4/127.0.0.1/tcp/4001",
+            "/ip4/1.2.3.4/tcp/4001",
+        ]);
+
+        assert_eq!(
+            config.announced(listeners.iter(), observed.iter()),
+            addrs(&[
+                "/ip4/1.2.3.4/tcp/4001",
+                "/ip4/5.6.7.8/tcp/4001",
+                "/dns4/example.com/tcp/4001",
+            ])
+        );
+    }
+
+    #[test]
+    fn announce_replaces_listeners() {
+        let config = AnnounceConfig {
+            announce: addrs(&["/ip4/1.2.3.4/tcp/4001"]),
+            ..Default::default()
+        };
+
+        let listeners = addrs(&["/ip4/172.17.0.2/tcp/4001"]);
+
+        assert_eq!(
+            config.announced(listeners.iter(), std::iter::empty()),
+            addrs(&["/ip4/1.2.3.4/tcp/4001"])
+        );
+    }
+}
@@ -1,29 +1,47 @@
-use super::pubsub::Pubsub;
+use super::pubsub::{discovery_key, Pubsub, SubscriptionStream};
+use super::survey::NetworkSurvey;
 use super::swarm::{Connection, Disconnector, SwarmApi};
-use crate::config::BOOTSTRAP_NODES;
+use super::sync::{self, BlockSyncCodec, BlockSyncProtocol, SyncRequest, SyncResponse};
+use crate::budget::MemoryBudgets;
 use crate::p2p::{MultiaddrWithPeerId, SwarmOptions};
-use crate::repo::{BlockPut, Repo};
+use crate::popularity::RequestSource;
+use crate::repo::{BlockAccessor, BlockPut, Repo};
 use crate::subscription::{SubscriptionFuture, SubscriptionRegistry};
 use crate::IpfsTypes;
 use anyhow::anyhow;
 use cid::Cid;
+use futures::channel::{mpsc, oneshot};
+use futures::StreamExt;
 use ipfs_bitswap::{Bitswap, BitswapEvent};
 use libp2p::core::{Multiaddr, PeerId};
-use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent};
+use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent, IdentifyInfo};
+use libp2p::kad::kbucket;
 use libp2p::kad::record::{store::MemoryStore, Key, Record};
-use libp2p::kad::{Kademlia, KademliaConfig, KademliaEvent, Quorum};
+use libp2p::kad::{Kademlia, KademliaConfig, KademliaEvent, QueryId, Quorum};
+use libp2p::multiaddr::Protocol;
 // use libp2p::mdns::{MdnsEvent, TokioMdns};
 use libp2p::ping::{Ping, PingEvent};
 // use libp2p::swarm::toggle::Toggle;
 use libp2p::floodsub::FloodsubEvent;
-use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourEventProcess};
+use libp2p::request_response::{
+    ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+    RequestResponseMessage, ResponseChannel,
+};
+use libp2p::swarm::{
+    NetworkBehaviour, NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters,
+};
 use multibase::Base;
-use std::{convert::TryInto, sync::Arc};
+use std::collections::{HashMap, HashSet};
+use std::task::{Context, Poll};
+use std::{convert::TryInto, iter, sync::Arc, time::Duration};
 use tokio::task;
 
+/// The error of [`Behaviour::dht_get`] when no record of the key was found.
+pub(crate) const RECORD_NOT_FOUND: &str = "couldn't find a record for the given key";
+
 /// Behaviour type.
 #[derive(libp2p::NetworkBehaviour)]
-#[behaviour(event_process = true)]
+#[behaviour(event_process = true, poll_method = "poll_sync_responses")]
 pub struct Behaviour<Types: IpfsTypes> {
     // mdns: Toggle<TokioMdns>,
     kademlia: Kademlia<MemoryStore>,
@@ -31,11 +49,31 @@ pub struct Behaviour<Types: IpfsTypes> {
     ping: Ping,
     identify: Identify,
     pubsub: Pubsub,
+    sync: RequestResponse<BlockSyncCodec>,
     pub swarm: SwarmApi,
     #[behaviour(ignore)]
     repo: Arc<Repo<Types>>,
     #[behaviour(ignore)]
     kad_subscriptions: SubscriptionRegistry<KadResult, String>,
+    #[behaviour(ignore)]
+    peer_id: PeerId,
+    #[behaviour(ignore)]
+    pubsub_discovery: bool,
+    /// The provider queries looking up the other subscribers of the subscribed topics.
+    #[behaviour(ignore)]
+    topic_discoveries: HashSet<QueryId>,
+    /// The latest identify information of the connected peers, see [`Behaviour::network_survey`].
+    #[behaviour(ignore)]
+    identified: HashMap<PeerId, IdentifyInfo>,
+    /// The sync requests sent to the other peers, waiting for the responses.
+    #[behaviour(ignore)]
+    sync_requests: HashMap<RequestId, oneshot::Sender<Result<SyncResponse, String>>>,
+    /// The responses to the sync requests of the other peers, once listed in the background.
+    #[behaviour(ignore)]
+    sync_responses: (
+        mpsc::UnboundedSender<(ResponseChannel<SyncResponse>, SyncResponse)>,
+        mpsc::UnboundedReceiver<(ResponseChannel<SyncResponse>, SyncResponse)>,
+    ),
 }
 
 /// Represents the result of a Kademlia query.
@@ -148,7 +186,16 @@ impl<Types: IpfsTypes> NetworkBehaviourEventProcess<KademliaEvent> for Behaviour
                         providers,
                         closest_peers: _,
                     })) => {
-                        if self.kademlia.query(&id).is_none() {
+                        if self.topic_discoveries.contains(&id) {
+                            for peer in providers.into_iter().filter(|p| *p != self.peer_id) {
+                                debug!("pubsub: discovered topic subscriber {}", peer);
+                                self.pubsub.add_node_to_partial_view(peer);
+                            }
+
+                            if self.kademlia.query(&id).is_none() {
+                                self.topic_discoveries.remove(&id);
+                            }
+                        } else if self.kademlia.query(&id).is_none() {
                             let providers = providers.into_iter().collect::<Vec<_>>();
 
                             self.kad_subscriptions
@@ -159,7 +206,11 @@ impl<Types: IpfsTypes> NetworkBehaviourEventProcess<KademliaEvent> for Behaviour
                         let key = multibase::encode(Base::Base32Lower, key);
                         warn!("kad: timed out while trying to get providers for {}", key);
 
-                        if self.kademlia.query(&id).is_none() {
+                        if self.topic_discoveries.contains(&id) {
+                            if self.kademlia.query(&id).is_none() {
+                                self.topic_discoveries.remove(&id);
+                            }
+                        } else if self.kademlia.query(&id).is_none() {
                             self.kad_subscriptions.finish_subscription(
                                 id.into(),
                                 Err("timed out while trying to get providers for the given key"
@@ -205,10 +256,8 @@ impl<Types: IpfsTypes> NetworkBehaviourEventProcess<KademliaEvent> for Behaviour
                         warn!("kad: couldn't find record {}", key);
 
                         if self.kademlia.query(&id).is_none() {
-                            self.kad_subscriptions.finish_subscription(
-                                id.into(),
-                                Err("couldn't find a record for the given key".into()),
-                            );
+                            self.kad_subscriptions
+                                .finish_subscription(id.into(), Err(RECORD_NOT_FOUND.into()));
                         }
                     }
                     GetRecord(Err(GetRecordError::QuorumFailed {
@@ -327,9 +376,13 @@ impl<Types: IpfsTypes> NetworkBehaviourEventProcess<BitswapEvent> for Behaviour<
             BitswapEvent::ReceivedBlock(peer_id, block) => {
                 let repo = self.repo.clone();
                 let peer_stats = Arc::clone(self.bitswap.stats.get(&peer_id).unwrap());
+                let bytes = block.data().len() as u64;
+                // held until the block has been written
+                let permit = repo.budgets().charge_incoming(bytes);
                 task::spawn(async move {
-                    let bytes = block.data().len() as u64;
-                    let res = repo.put_block(block.clone()).await;
+                    let res = repo
+                        .put_block_as(block.clone(), &BlockAccessor::Peer(peer_id))
+                        .await;
                     match res {
                         Ok((_, uniqueness)) => match uniqueness {
                             BlockPut::NewBlock => peer_stats.update_incoming_unique(bytes),
@@ -344,6 +397,7 @@ impl<Types: IpfsTypes> NetworkBehaviourEventProcess<BitswapEvent> for Behaviour<
                             );
                         }
                     };
+                    drop(permit);
                 });
             }
             BitswapEvent::ReceivedWant(peer_id, cid, priority) => {
@@ -353,14 +407,23 @@ impl<Types: IpfsTypes> NetworkBehaviourEventProcess<BitswapEvent> for Behaviour<
                 );
 
                 let queued_blocks = self.bitswap().queued_blocks.clone();
+                let queued_dont_haves = self.bitswap().queued_dont_haves.clone();
                 let repo = self.repo.clone();
+                let permit = repo.budgets().charge_outgoing();
 
                 task::spawn(async move {
-                    match repo.get_block_now(&cid).await {
+                    let _permit = permit;
+                    match repo
+                        .get_block_now_as(&cid, &BlockAccessor::Peer(peer_id))
+                        .await
+                    {
                         Ok(Some(block)) => {
+                            repo.popularity().record(&cid, RequestSource::Bitswap);
                             let _ = queued_blocks.unbounded_send((peer_id, block));
                         }
-                        Ok(None) => {}
+                        Ok(None) => {
+                            let _ = queued_dont_haves.unbounded_send((peer_id, cid));
+                        }
                         Err(err) => {
                             warn!(
                                 "Peer {} wanted block {} but we failed: {}",
@@ -368,6 +431,8 @@ impl<Types: IpfsTypes> NetworkBehaviourEventProcess<BitswapEvent> for Behaviour<
                                 cid,
                                 err,
                             );
+                            // answered as unavailable, so that bitswap moves on to the next want
+                            let _ = queued_dont_haves.unbounded_send((peer_id, cid));
                         }
                     }
                 });
@@ -424,6 +489,18 @@ impl<Types: IpfsTypes> NetworkBehaviourEventProcess<PingEvent> for Behaviour<Typ
 impl<Types: IpfsTypes> NetworkBehaviourEventProcess<IdentifyEvent> for Behaviour<Types> {
     fn inject_event(&mut self, event: IdentifyEvent) {
         trace!("identify: {:?}", event);
+
+        if let IdentifyEvent::Received { peer_id, info } = event {
+            self.identified.insert(peer_id, info);
+
+            // forget the peers which have disconnected since they were identified
+            let connected = self
+                .swarm
+                .connected_addresses()
+                .map(|(peer, _)| *peer)
+                .collect::<HashSet<_>>();
+            self.identified.retain(|peer, _| connected.contains(peer));
+        }
     }
 }
 
@@ -433,6 +510,63 @@ impl<Types: IpfsTypes> NetworkBehaviourEventProcess<FloodsubEvent> for Behaviour
     }
 }
 
+impl<Types: IpfsTypes> NetworkBehaviourEventProcess<RequestResponseEvent<SyncRequest, SyncResponse>>
+    for Behaviour<Types>
+{
+    fn inject_event(&mut self, event: RequestResponseEvent<SyncRequest, SyncResponse>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => {
+                debug!(
+                    "sync: {} requested the blocks missing under {}",
+                    peer, request.root
+                );
+
+                let repo = self.repo.clone();
+                let responses = self.sync_responses.0.clone();
+
+                task::spawn(async move {
+                    let response = sync::list_missing(&repo, request).await;
+                    // sending only fails if the swarm has been dropped
+                    let _ = responses.unbounded_send((channel, response));
+                });
+            }
+            RequestResponseEvent::Message {
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                if let Some(ret) = self.sync_requests.remove(&request_id) {
+                    let _ = ret.send(Ok(response));
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                debug!("sync: request to {} failed: {}", peer, error);
+
+                if let Some(ret) = self.sync_requests.remove(&request_id) {
+                    let _ = ret.send(Err(error.to_string()));
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("sync: failed to respond to {}: {}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
 impl<Types: IpfsTypes> Behaviour<Types> {
     /// Create a Kademlia behaviour with the IPFS bootstrap nodes.
     pub async fn new(options: SwarmOptions, repo: Arc<Repo<Types>>) -> Self {
@@ -461,14 +595,24 @@ impl<Types: IpfsTypes> Behaviour<Types> {
             kademlia.add_address(peer_id, addr.to_owned());
         }
 
-        let bitswap = Bitswap::default();
+        let mut bitswap = Bitswap::default();
+        bitswap.set_compression(options.bitswap_compression);
+        bitswap.set_wantlist_batching(options.bitswap_wantlist_batching.clone());
         let ping = Ping::default();
         let identify = Identify::new(
             IdentifyConfig::new("/ipfs/0.1.0".into(), options.keypair.public())
                 .with_agent_version("rust-ipfs".into()),
         );
-        let pubsub = Pubsub::new(options.peer_id);
-        let mut swarm = SwarmApi::default();
+        let pubsub = Pubsub::new(options.peer_id, options.pubsub_batching.clone());
+        let mut sync_config = RequestResponseConfig::default();
+        // the peer walks all of its blocks under the root before responding
+        sync_config.set_request_timeout(Duration::from_secs(300));
+        let sync = RequestResponse::new(
+            BlockSyncCodec,
+            iter::once((BlockSyncProtocol, ProtocolSupport::Full)),
+            sync_config,
+        );
+        let mut swarm = SwarmApi::with_dial_config(options.dial.clone());
 
         for (addr, _peer_id) in &options.bootstrap {
             if let Ok(addr) = addr.to_owned().try_into() {
@@ -481,14 +625,68 @@ impl<Types: IpfsTypes> Behaviour<Types> {
             // mdns,
             kademlia,
             kad_subscriptions: Default::default(),
+            peer_id: options.peer_id,
+            pubsub_discovery: options.pubsub_discovery,
+            topic_discoveries: Default::default(),
+            identified: Default::default(),
             bitswap,
             ping,
             identify,
             pubsub,
+            sync,
+            sync_requests: Default::default(),
+            sync_responses: mpsc::unbounded(),
             swarm,
         }
     }
 
+    /// Sends the sync request to the peer, sending the response or the failure to `ret`.
+    pub fn sync_request(
+        &mut self,
+        peer: PeerId,
+        request: SyncRequest,
+        ret: oneshot::Sender<Result<SyncResponse, String>>,
+    ) {
+        let id = self.sync.send_request(&peer, request);
+        self.sync_requests.insert(id, ret);
+    }
+
+    /// Sends the responses to the sync requests once they have been listed in the background.
+    fn poll_sync_responses<TEvent>(
+        &mut self,
+        cx: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEvent, <Self as NetworkBehaviour>::ConnectionHandler>> {
+        let mut sent_any = false;
+
+        while let Poll::Ready(Some((channel, response))) = self.sync_responses.1.poll_next_unpin(cx)
+        {
+            if self.sync.send_response(channel, response).is_err() {
+                debug!("sync: the requester is no longer waiting for the response");
+            }
+            sent_any = true;
+        }
+
+        if sent_any {
+            // the responses are only sent once the RequestResponse is polled again
+            cx.waker().wake_by_ref();
+        }
+
+        Poll::Pending
+    }
+
+    /// Remembers the addresses of a provider found outside of the DHT, so that it can be dialed
+    /// by the peer id.
+    pub fn add_provider_addresses(&mut self, peer: PeerId, addrs: Vec<Multiaddr>) {
+        for mut addr in addrs {
+            // Kademlia::add_address requires the address to not contain the PeerId
+            if matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+                addr.pop();
+            }
+            self.kademlia.add_address(&peer, addr);
+        }
+    }
+
     pub fn add_peer(&mut self, peer: PeerId, addr: Multiaddr) {
         self.kademlia.add_address(&peer, addr);
         self.swarm.add_peer(peer);
@@ -520,6 +718,15 @@ impl<Types: IpfsTypes> Behaviour<Types> {
         self.swarm.connections()
     }
 
+    /// Counts the connected peers by their identify information and their transports.
+    pub fn network_survey(&self) -> NetworkSurvey {
+        let mut survey = NetworkSurvey::default();
+        for (peer, connections) in self.swarm.connected_addresses() {
+            survey.add_peer(connections, self.identified.get(peer));
+        }
+        survey
+    }
+
     pub fn connect(&mut self, addr: MultiaddrWithPeerId) -> Option<SubscriptionFuture<(), String>> {
         self.swarm.connect(addr)
     }
@@ -536,6 +743,11 @@ impl<Types: IpfsTypes> Behaviour<Types> {
         self.bitswap.want_block(cid, 1);
     }
 
+    /// Asks the block only from the connected peers, see [`crate::retrieval`].
+    pub fn want_block_from_peers(&mut self, cid: Cid) {
+        self.bitswap.want_block(cid, 1);
+    }
+
     pub fn stop_providing_block(&mut self, cid: &Cid) {
         info!("Finished providing block {}", cid.to_string());
         //let hash = Multihash::from_bytes(cid.to_bytes()).unwrap();
@@ -546,10 +758,42 @@ impl<Types: IpfsTypes> Behaviour<Types> {
         &mut self.pubsub
     }
 
+    /// Subscribes to the pubsub `topic`, advertising the subscription and looking up the other
+    /// subscribers in the DHT when [`SwarmOptions::pubsub_discovery`] is enabled. Returns `None`
+    /// if the topic was already subscribed to.
+    pub fn pubsub_subscribe(&mut self, topic: String) -> Option<SubscriptionStream> {
+        let key = discovery_key(&topic);
+        let stream = self.pubsub.subscribe(topic)?;
+
+        if self.pubsub_discovery {
+            if let Err(e) = self.kademlia.start_providing(key.clone()) {
+                warn!("kad: can't advertise the pubsub topic: {:?}", e);
+            }
+            let id = self.kademlia.get_providers(key);
+            self.topic_discoveries.insert(id);
+        }
+
+        Some(stream)
+    }
+
+    /// Stops advertising the topics which have been unsubscribed from since the last call.
+    pub fn stop_advertising_unsubscribed_topics(&mut self) {
+        for topic in self.pubsub.take_unsubscribed() {
+            if self.pubsub_discovery {
+                self.kademlia.stop_providing(&discovery_key(&topic));
+            }
+        }
+    }
+
     pub fn bitswap(&mut self) -> &mut Bitswap {
         &mut self.bitswap
     }
 
+    /// The budgets of the blocks in flight, see [`crate::budget`].
+    pub fn budgets(&self) -> &MemoryBudgets {
+        self.repo.budgets()
+    }
+
     pub fn bootstrap(&mut self) -> Result<SubscriptionFuture<KadResult, String>, anyhow::Error> {
         match self.kademlia.bootstrap() {
             Ok(id) => Ok(self.kad_subscriptions.create_subscription(id.into(), None)),
@@ -572,6 +816,25 @@ impl<Types: IpfsTypes> Behaviour<Types> {
             .create_subscription(self.kademlia.get_closest_peers(id).into(), None)
     }
 
+    /// Returns at most `count` of the peers in the routing table closest to the `key`, without
+    /// querying the network.
+    pub fn local_closest_peers(&mut self, key: &Key, count: usize) -> Vec<PeerId> {
+        let target = kbucket::Key::new(key.clone());
+        let mut peers = self
+            .kademlia
+            .kbuckets()
+            .flat_map(|bucket| {
+                bucket
+                    .iter()
+                    .map(|entry| *entry.node.key.preimage())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| target.distance(&kbucket::Key::from(*peer)));
+        peers.truncate(count);
+        peers
+    }
+
     pub fn get_providers(&mut self, cid: Cid) -> SubscriptionFuture<KadResult, String> {
         let key = Key::from(cid.hash().as_bytes().to_owned());
         self.kad_subscriptions
@@ -592,6 +855,8 @@ impl<Types: IpfsTypes> Behaviour<Types> {
         }
     }
 
+    /// Looks up the records of the `key`, failing with [`RECORD_NOT_FOUND`] when none of the
+    /// queried peers has one.
     pub fn dht_get(&mut self, key: Key, quorum: Quorum) -> SubscriptionFuture<KadResult, String> {
         self.kad_subscriptions
             .create_subscription(self.kademlia.get_record(key, quorum).into(), None)
@@ -681,13 +946,14 @@ impl<Types: IpfsTypes> Behaviour<Types> {
         ret
     }
 
-    pub fn restore_bootstrappers(&mut self) -> Result<Vec<Multiaddr>, anyhow::Error> {
+    /// Adds the [`crate::config::BOOTSTRAP_NODES`], resolved into the concrete `addrs`.
+    pub fn restore_bootstrappers(
+        &mut self,
+        addrs: Vec<MultiaddrWithPeerId>,
+    ) -> Result<Vec<Multiaddr>, anyhow::Error> {
         let mut ret = Vec::new();
 
-        for addr in BOOTSTRAP_NODES {
-            let addr = addr
-                .parse::<MultiaddrWithPeerId>()
-                .expect("see test bootstrap_nodes_are_multiaddr_with_peerid");
+        for addr in addrs {
             if self.swarm.bootstrappers.insert(addr.clone()) {
                 let MultiaddrWithPeerId {
                     multiaddr: ma,
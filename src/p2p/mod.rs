@@ -1,6 +1,7 @@
 //! P2P handling for IPFS nodes.
+use crate::dns::DnsResolver;
 use crate::repo::Repo;
-use crate::{IpfsOptions, IpfsTypes};
+use crate::{IpfsOptions, IpfsTypes, WantlistBatchConfig};
 use libp2p::identity::Keypair;
 use libp2p::Swarm;
 use libp2p::{Multiaddr, PeerId};
@@ -9,13 +10,25 @@ use std::sync::Arc;
 use tracing::Span;
 
 pub(crate) mod addr;
+mod announce;
 mod behaviour;
 pub(crate) mod pubsub;
+mod survey;
 mod swarm;
+pub(crate) mod sync;
 mod transport;
 
+use self::pubsub::PubsubBatchConfig;
+
 pub use addr::{MultiaddrWithPeerId, MultiaddrWithoutPeerId};
-pub use {behaviour::KadResult, swarm::Connection};
+pub use announce::{AddrFilter, AddrFilterError, AnnounceConfig};
+pub(crate) use behaviour::RECORD_NOT_FOUND;
+pub use survey::NetworkSurvey;
+pub use sync::BLOCK_SYNC_PROTOCOL;
+pub use {
+    behaviour::KadResult,
+    swarm::{Connection, DialConfig},
+};
 
 /// Type alias for [`libp2p::Swarm`] running the [`behaviour::Behaviour`] with the given [`IpfsTypes`].
 pub type TSwarm<T> = Swarm<behaviour::Behaviour<T>>;
@@ -32,6 +45,20 @@ pub struct SwarmOptions {
     pub mdns: bool,
     /// Custom Kademlia protocol name, see [`IpfsOptions::kad_protocol`].
     pub kad_protocol: Option<String>,
+    /// Limits for the dials, see [`IpfsOptions::dial`].
+    pub dial: DialConfig,
+    /// Uses the in-process transport, see [`IpfsOptions::memory_transport`].
+    pub memory_transport: bool,
+    /// Advertising and discovery of the pubsub topics, see [`IpfsOptions::pubsub_discovery`].
+    pub pubsub_discovery: bool,
+    /// Batching of the published pubsub messages, see [`IpfsOptions::pubsub_batching`].
+    pub pubsub_batching: Option<PubsubBatchConfig>,
+    /// Compression of the bitswap messages, see [`IpfsOptions::bitswap_compression`].
+    pub bitswap_compression: bool,
+    /// Batching of the wantlist updates, see [`IpfsOptions::bitswap_wantlist_batching`].
+    pub bitswap_wantlist_batching: Option<WantlistBatchConfig>,
+    /// Resolution of the DNS names in the multiaddresses, see [`IpfsOptions::dns`].
+    pub dns: DnsResolver,
 }
 
 impl From<&IpfsOptions> for SwarmOptions {
@@ -41,6 +68,13 @@ impl From<&IpfsOptions> for SwarmOptions {
         let bootstrap = options.bootstrap.clone();
         let mdns = options.mdns;
         let kad_protocol = options.kad_protocol.clone();
+        let dial = options.dial.clone();
+        let memory_transport = options.memory_transport;
+        let pubsub_discovery = options.pubsub_discovery;
+        let pubsub_batching = options.pubsub_batching.clone();
+        let bitswap_compression = options.bitswap_compression;
+        let bitswap_wantlist_batching = options.bitswap_wantlist_batching.clone();
+        let dns = options.dns.clone();
 
         SwarmOptions {
             keypair,
@@ -48,6 +82,13 @@ impl From<&IpfsOptions> for SwarmOptions {
             bootstrap,
             mdns,
             kad_protocol,
+            dial,
+            memory_transport,
+            pubsub_discovery,
+            pubsub_batching,
+            bitswap_compression,
+            bitswap_wantlist_batching,
+            dns,
         }
     }
 }
@@ -61,7 +102,11 @@ pub async fn create_swarm<TIpfsTypes: IpfsTypes>(
     let peer_id = options.peer_id;
 
     // Set up an encrypted TCP transport over the Mplex protocol.
-    let transport = transport::build_transport(options.keypair.clone())?;
+    let transport = if options.memory_transport {
+        transport::build_memory_transport(options.keypair.clone(), options.dial.timeout)?
+    } else {
+        transport::build_transport(options.keypair.clone(), options.dial.timeout, &options.dns)?
+    };
 
     // Create a Kademlia behaviour
     let behaviour = behaviour::build_behaviour(options, repo).await;
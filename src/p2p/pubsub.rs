@@ -1,4 +1,5 @@
 use futures::channel::mpsc as channel;
+use futures::future::Future;
 use futures::stream::{FusedStream, Stream};
 
 use std::collections::HashMap;
@@ -6,15 +7,23 @@ use std::fmt;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 use libp2p::core::{
     connection::{ConnectedPoint, ConnectionId, ListenerId},
     Multiaddr, PeerId,
 };
 use libp2p::floodsub::{Floodsub, FloodsubConfig, FloodsubEvent, FloodsubMessage, Topic};
+use libp2p::kad::record::Key;
 use libp2p::swarm::{
     ConnectionHandler, DialError, NetworkBehaviour, NetworkBehaviourAction, PollParameters,
 };
+use multihash::Sha2_256;
+
+mod batch;
+
+use batch::{batch_topic, batched_topic, PendingBatch};
+pub use batch::{PubsubBatchConfig, PUBSUB_BATCH_PROTOCOL};
 
 /// Currently a thin wrapper around Floodsub, perhaps supporting both Gossipsub and Floodsub later.
 /// Allows single subscription to a topic with only unbounded senders. Tracks the peers subscribed
@@ -32,6 +41,21 @@ pub struct Pubsub {
         channel::UnboundedSender<String>,
         channel::UnboundedReceiver<String>,
     ),
+    // the topics unsubscribed from, until taken with `take_unsubscribed`.
+    unsubscribed: Vec<String>,
+    // batching of the published messages, when enabled.
+    batching: Option<PubsubBatchConfig>,
+    pending_batches: HashMap<Topic, PendingBatch>,
+    // fires at the earliest deadline of the pending batches.
+    batch_timer: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+/// Returns the DHT provider key the subscribers of the topic are advertised under when
+/// [`crate::IpfsOptions::pubsub_discovery`] is enabled. Like with go-ipfs, the key is the hash of
+/// the `floodsub:<topic>` namespace.
+pub(crate) fn discovery_key(topic: &str) -> Key {
+    let namespace = format!("floodsub:{}", topic);
+    Key::from(Sha2_256::digest(namespace.as_bytes()).as_bytes().to_owned())
 }
 
 /// Adaptation hopefully supporting both Floodsub and Gossipsub Messages in the future
@@ -133,8 +157,9 @@ impl FusedStream for SubscriptionStream {
 
 impl Pubsub {
     /// Delegates the `peer_id` over to [`Floodsub::new`] and internally only does accounting on
-    /// top of the floodsub.
-    pub fn new(peer_id: PeerId) -> Self {
+    /// top of the floodsub. The published messages are batched for the peers supporting it when
+    /// `batching` is given.
+    pub fn new(peer_id: PeerId, batching: Option<PubsubBatchConfig>) -> Self {
         let (tx, rx) = channel::unbounded();
         let mut config = FloodsubConfig::new(peer_id);
         config.subscribe_local_messages = true;
@@ -143,6 +168,10 @@ impl Pubsub {
             peers: HashMap::new(),
             floodsub: Floodsub::from_config(config),
             unsubscriptions: (tx, rx),
+            unsubscribed: Vec::new(),
+            batching,
+            pending_batches: HashMap::new(),
+            batch_timer: None,
         }
     }
 
@@ -168,6 +197,11 @@ impl Pubsub {
 
                 let name = ve.key().id().to_string();
                 ve.insert(tx);
+
+                if self.batching.is_some() {
+                    self.floodsub.subscribe(Topic::new(batch_topic(&name)));
+                }
+
                 Some(SubscriptionStream {
                     on_drop: Some(self.unsubscriptions.0.clone()),
                     topic: Some(name),
@@ -185,6 +219,8 @@ impl Pubsub {
     pub fn unsubscribe(&mut self, topic: impl Into<String>) -> bool {
         let topic = Topic::new(topic);
         if self.streams.remove(&topic).is_some() {
+            self.unsubscribed.push(topic.id().to_owned());
+            self.unsubscribe_batches(&topic);
             assert!(
                 self.floodsub.unsubscribe(topic),
                 "sender removed but unsubscription failed"
@@ -195,9 +231,141 @@ impl Pubsub {
         }
     }
 
-    /// See [`Floodsub::publish_any`]
+    /// See [`Floodsub::publish_any`]. The message is added to a batch instead when batching is
+    /// enabled and all of the known subscribers of the topic support it.
     pub fn publish(&mut self, topic: impl Into<String>, data: impl Into<Vec<u8>>) {
-        self.floodsub.publish_any(Topic::new(topic), data);
+        let topic = Topic::new(topic);
+        let data = data.into();
+
+        let config = match self.batching.as_ref() {
+            Some(config) if self.batching_supported(&topic) => config,
+            _ => {
+                // keep the messages in order in case the batching was in use until now
+                self.publish_batch(&topic);
+                self.floodsub.publish_any(topic, data);
+                return;
+            }
+        };
+
+        let batch = self
+            .pending_batches
+            .entry(topic.clone())
+            .or_insert_with(|| PendingBatch::new(config));
+
+        if batch.push(&data, config) {
+            self.publish_batch(&topic);
+        }
+    }
+
+    /// Returns true if there are subscribers to the topic and all of them also subscribe to the
+    /// batches of the topic.
+    fn batching_supported(&self, topic: &Topic) -> bool {
+        let batches = Topic::new(batch_topic(topic.id()));
+        let mut subscribers = self
+            .peers
+            .values()
+            .filter(|topics| topics.contains(topic))
+            .peekable();
+
+        subscribers.peek().is_some() && subscribers.all(|topics| topics.contains(&batches))
+    }
+
+    /// Publishes the pending batch of the topic, if any.
+    fn publish_batch(&mut self, topic: &Topic) {
+        if let (Some(config), Some(batch)) =
+            (self.batching.as_ref(), self.pending_batches.remove(topic))
+        {
+            let batches = Topic::new(batch_topic(topic.id()));
+            self.floodsub.publish_any(batches, batch.encode(config));
+        }
+    }
+
+    /// Publishes the batches past their deadline and sets the timer to wake up the task at the
+    /// earliest of the remaining deadlines.
+    fn poll_batches(&mut self, ctx: &mut Context) {
+        loop {
+            let now = Instant::now();
+            let due = self
+                .pending_batches
+                .iter()
+                .filter(|(_, batch)| batch.deadline <= now)
+                .map(|(topic, _)| topic.clone())
+                .collect::<Vec<_>>();
+
+            for topic in due {
+                self.publish_batch(&topic);
+            }
+
+            let deadline = match self.pending_batches.values().map(|b| b.deadline).min() {
+                Some(deadline) => tokio::time::Instant::from(deadline),
+                None => {
+                    self.batch_timer = None;
+                    return;
+                }
+            };
+
+            let timer = self
+                .batch_timer
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+
+            if timer.deadline() != deadline {
+                timer.as_mut().reset(deadline);
+            }
+
+            if timer.as_mut().poll(ctx).is_pending() {
+                return;
+            }
+        }
+    }
+
+    /// Delivers the messages of a received batch to the subscription streams of the `topics`.
+    fn deliver_batch(&mut self, msg: FloodsubMessage, topics: Vec<Topic>) {
+        use std::collections::hash_map::Entry;
+
+        let messages = match batch::decode(&msg.data) {
+            Ok(messages) => messages,
+            Err(e) => {
+                debug!("invalid pubsub batch from {}: {}", msg.source, e);
+                return;
+            }
+        };
+
+        for topic in topics {
+            for data in &messages {
+                let sent = Arc::new(PubsubMessage {
+                    source: msg.source,
+                    data: data.clone(),
+                    sequence_number: msg.sequence_number.clone(),
+                    topics: vec![topic.id().to_owned()],
+                });
+
+                match self.streams.entry(topic.clone()) {
+                    Entry::Occupied(oe) => {
+                        if oe.get().unbounded_send(sent).is_err() {
+                            let (topic, _) = oe.remove_entry();
+                            debug!("unsubscribing via SendError from {:?}", topic.id());
+                            self.unsubscribed.push(topic.id().to_owned());
+                            self.unsubscribe_batches(&topic);
+                            assert!(
+                                self.floodsub.unsubscribe(topic),
+                                "Failed to unsubscribe following SendError"
+                            );
+                            break;
+                        }
+                    }
+                    // unsubscribed after floodsub had received the batch
+                    Entry::Vacant(_) => break,
+                }
+            }
+        }
+    }
+
+    fn unsubscribe_batches(&mut self, topic: &Topic) {
+        self.publish_batch(topic);
+        if self.batching.is_some() {
+            self.floodsub
+                .unsubscribe(Topic::new(batch_topic(topic.id())));
+        }
     }
 
     /// Returns the known peers subscribed to any topic
@@ -223,6 +391,12 @@ impl Pubsub {
             .collect()
     }
 
+    /// Returns the topics unsubscribed from since the previous call, either with
+    /// [`Pubsub::unsubscribe`] or by dropping the [`SubscriptionStream`].
+    pub fn take_unsubscribed(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.unsubscribed)
+    }
+
     /// See [`Floodsub::add_node_from_partial_view`]
     pub fn add_node_to_partial_view(&mut self, peer_id: PeerId) {
         self.floodsub.add_node_to_partial_view(peer_id);
@@ -334,6 +508,8 @@ impl NetworkBehaviour for Pubsub {
                     let topic = Topic::new(dropped);
                     if self.streams.remove(&topic).is_some() {
                         debug!("unsubscribing via drop from {:?}", topic.id());
+                        self.unsubscribed.push(topic.id().to_owned());
+                        self.unsubscribe_batches(&topic);
                         assert!(
                             self.floodsub.unsubscribe(topic),
                             "Failed to unsubscribe a dropped subscription"
@@ -349,9 +525,23 @@ impl NetworkBehaviour for Pubsub {
             }
         }
 
+        self.poll_batches(ctx);
+
         loop {
             match futures::ready!(self.floodsub.poll(ctx, poll)) {
                 NetworkBehaviourAction::GenerateEvent(FloodsubEvent::Message(msg)) => {
+                    let batched = msg
+                        .topics
+                        .iter()
+                        .filter_map(|topic| batched_topic(topic.id()))
+                        .map(Topic::new)
+                        .collect::<Vec<_>>();
+
+                    if !batched.is_empty() {
+                        self.deliver_batch(msg, batched);
+                        continue;
+                    }
+
                     let topics = msg.topics.clone();
                     let msg = Arc::new(PubsubMessage::from(msg));
                     let mut buffer = None;
@@ -364,6 +554,8 @@ impl NetworkBehaviour for Pubsub {
                                 // receiver has dropped
                                 let (topic, _) = oe.remove_entry();
                                 debug!("unsubscribing via SendError from {:?}", topic.id());
+                                self.unsubscribed.push(topic.id().to_owned());
+                                self.unsubscribe_batches(&topic);
                                 assert!(
                                     self.floodsub.unsubscribe(topic),
                                     "Failed to unsubscribe following SendError"
@@ -0,0 +1,221 @@
+//! Batching of small pubsub messages, optionally compressed with zstd.
+//!
+//! Batching is an extension on top of floodsub: the nodes having it enabled subscribe to the
+//! topic prefixed with [`PUBSUB_BATCH_PROTOCOL`] in addition to the topic itself. Once all of the
+//! known subscribers of a topic have subscribed to the prefixed topic as well, the published
+//! messages are collected into batches published on the prefixed topic. Until then, and always
+//! for the nodes not supporting the extension, the messages are published as is.
+//!
+//! A batch starts with a byte telling if the rest is compressed, followed by the messages each
+//! prefixed with its length as a big endian `u32`.
+
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+/// The extension protocol id, used as the prefix of the topics the batches are published on.
+pub const PUBSUB_BATCH_PROTOCOL: &str = "/rust-ipfs/pubsub-batch/1.0.0/";
+
+/// The largest accepted batch once decompressed, so that a small compressed batch cannot
+/// expand to exhaust the memory.
+const MAX_BATCH_SIZE: usize = 1024 * 1024;
+
+const UNCOMPRESSED: u8 = 0;
+const ZSTD: u8 = 1;
+
+/// Configuration of the batching of the published pubsub messages.
+#[derive(Debug, Clone)]
+pub struct PubsubBatchConfig {
+    /// The maximum number of messages in a batch.
+    pub max_messages: usize,
+    /// The batch is published once its messages add up to this many bytes.
+    pub max_bytes: usize,
+    /// The longest time a message waits for more messages to be batched with.
+    pub max_delay: Duration,
+    /// Batches of at least this many bytes are compressed with zstd, `None` disables the
+    /// compression.
+    pub compress_over: Option<usize>,
+}
+
+impl Default for PubsubBatchConfig {
+    fn default() -> Self {
+        PubsubBatchConfig {
+            max_messages: 128,
+            max_bytes: 16 * 1024,
+            max_delay: Duration::from_millis(20),
+            compress_over: Some(512),
+        }
+    }
+}
+
+/// Returns the topic the batches of the `topic` are published on.
+pub(crate) fn batch_topic(topic: &str) -> String {
+    format!("{}{}", PUBSUB_BATCH_PROTOCOL, topic)
+}
+
+/// Returns the topic the messages were batched for, if the `topic` is a batch topic.
+pub(crate) fn batched_topic(topic: &str) -> Option<&str> {
+    topic.strip_prefix(PUBSUB_BATCH_PROTOCOL)
+}
+
+/// Messages waiting to be published as a batch.
+#[derive(Debug)]
+pub(crate) struct PendingBatch {
+    body: Vec<u8>,
+    messages: usize,
+    /// The time the batch must be published by.
+    pub(crate) deadline: Instant,
+}
+
+impl PendingBatch {
+    pub(crate) fn new(config: &PubsubBatchConfig) -> Self {
+        PendingBatch {
+            body: Vec::new(),
+            messages: 0,
+            deadline: Instant::now() + config.max_delay,
+        }
+    }
+
+    /// Adds the message to the batch, returning true if the batch should be published now.
+    pub(crate) fn push(&mut self, data: &[u8], config: &PubsubBatchConfig) -> bool {
+        self.body
+            .extend_from_slice(&(data.len() as u32).to_be_bytes());
+        self.body.extend_from_slice(data);
+        self.messages += 1;
+
+        self.messages >= config.max_messages || self.body.len() >= config.max_bytes
+    }
+
+    /// Returns the batch as published, compressed if it is large enough and compresses well.
+    pub(crate) fn encode(self, config: &PubsubBatchConfig) -> Vec<u8> {
+        let compressed = config
+            .compress_over
+            .filter(|min| self.body.len() >= *min)
+            .and_then(|_| zstd::bulk::compress(&self.body, 0).ok())
+            .filter(|compressed| compressed.len() < self.body.len());
+
+        let (flag, body) = match compressed {
+            Some(compressed) => (ZSTD, compressed),
+            None => (UNCOMPRESSED, self.body),
+        };
+
+        let mut encoded = Vec::with_capacity(body.len() + 1);
+        encoded.push(flag);
+        encoded.extend_from_slice(&body);
+        encoded
+    }
+}
+
+/// An error that can be thrown when decoding a received batch.
+#[derive(Debug, thiserror::Error)]
+pub enum BatchError {
+    #[error("empty batch")]
+    Empty,
+    #[error("unknown batch encoding {0}")]
+    UnknownEncoding(u8),
+    #[error("decompressing the batch failed: {0}")]
+    Decompression(std::io::Error),
+    #[error("message truncated")]
+    Truncated,
+}
+
+/// Returns the messages of the received batch.
+pub(crate) fn decode(batch: &[u8]) -> Result<Vec<Vec<u8>>, BatchError> {
+    let (flag, body) = batch.split_first().ok_or(BatchError::Empty)?;
+
+    let decompressed;
+    let mut body = match *flag {
+        UNCOMPRESSED => body,
+        ZSTD => {
+            decompressed =
+                zstd::bulk::decompress(body, MAX_BATCH_SIZE).map_err(BatchError::Decompression)?;
+            &decompressed[..]
+        }
+        other => return Err(BatchError::UnknownEncoding(other)),
+    };
+
+    let mut messages = Vec::new();
+    while !body.is_empty() {
+        if body.len() < 4 {
+            return Err(BatchError::Truncated);
+        }
+        let (len, rest) = body.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+
+        if rest.len() < len {
+            return Err(BatchError::Truncated);
+        }
+        let (message, rest) = rest.split_at(len);
+        messages.push(message.to_vec());
+        body = rest;
+    }
+
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(config: &PubsubBatchConfig, messages: &[Vec<u8>]) -> Vec<u8> {
+        let mut batch = PendingBatch::new(config);
+        for message in messages {
+            batch.push(message, config);
+        }
+        let encoded = batch.encode(config);
+        assert_eq!(decode(&encoded).unwrap(), messages);
+        encoded
+    }
+
+    #[test]
+    fn batches_roundtrip() {
+        let messages = (0..100u32)
+            .map(|i| format!("{{\"temperature\":{}}}", i).into_bytes())
+            .collect::<Vec<_>>();
+
+        let compressed = roundtrip(&PubsubBatchConfig::default(), &messages);
+        assert_eq!(compressed[0], ZSTD);
+
+        let uncompressed = roundtrip(
+            &PubsubBatchConfig {
+                compress_over: None,
+                ..Default::default()
+            },
+            &messages,
+        );
+        assert_eq!(uncompressed[0], UNCOMPRESSED);
+        assert!(compressed.len() < uncompressed.len());
+
+        roundtrip(&PubsubBatchConfig::default(), &[vec![], b"a".to_vec()]);
+    }
+
+    #[test]
+    fn batch_is_full() {
+        let config = PubsubBatchConfig {
+            max_messages: 3,
+            max_bytes: 100,
+            ..Default::default()
+        };
+
+        let mut batch = PendingBatch::new(&config);
+        assert!(!batch.push(b"a", &config));
+        assert!(!batch.push(b"b", &config));
+        assert!(batch.push(b"c", &config));
+
+        let mut batch = PendingBatch::new(&config);
+        assert!(batch.push(&[0u8; 100], &config));
+    }
+
+    #[test]
+    fn invalid_batches() {
+        assert!(matches!(decode(&[]), Err(BatchError::Empty)));
+        assert!(matches!(decode(&[7]), Err(BatchError::UnknownEncoding(7))));
+        assert!(matches!(
+            decode(&[UNCOMPRESSED, 0, 0, 0, 2, 1]),
+            Err(BatchError::Truncated)
+        ));
+        assert!(matches!(
+            decode(&[ZSTD, 1, 2, 3]),
+            Err(BatchError::Decompression(_))
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+//! Breakdown of the connected peers by what they reported over the identify protocol, see
+//! [`crate::Ipfs::network_survey`].
+use super::MultiaddrWithoutPeerId;
+use libp2p::identify::IdentifyInfo;
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use std::collections::BTreeMap;
+
+/// The connected peers counted by their identify information and the transports of their
+/// connections and listened addresses, useful for example for finding out whether any of the
+/// peers still depend on a legacy transport before disabling it.
+///
+/// The transports are named after the protocols of the addresses following the IP address or
+/// the DNS name, like `tcp`, `tcp/ws` or `udp/quic`, or `p2p-circuit` for the relayed
+/// addresses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkSurvey {
+    /// The number of connected peers.
+    pub peers: usize,
+    /// The connected peers which have not completed the identify exchange yet, and are not
+    /// included in the identify based counts.
+    pub unidentified: usize,
+    /// The number of peers by the agent version, like `go-ipfs/0.12.0/`.
+    pub agent_versions: BTreeMap<String, usize>,
+    /// The number of peers by the protocol version, like `ipfs/0.1.0`.
+    pub protocol_versions: BTreeMap<String, usize>,
+    /// The number of peers by the supported protocol, like `/ipfs/bitswap/1.2.0`.
+    pub protocols: BTreeMap<String, usize>,
+    /// The number of connections by the transport used.
+    pub connection_transports: BTreeMap<String, usize>,
+    /// The number of peers listening on the transport.
+    pub listen_transports: BTreeMap<String, usize>,
+}
+
+impl NetworkSurvey {
+    /// Counts a connected peer with its `connections`, and the identify `info` if it has been
+    /// received.
+    pub(crate) fn add_peer(
+        &mut self,
+        connections: &[MultiaddrWithoutPeerId],
+        info: Option<&IdentifyInfo>,
+    ) {
+        self.peers += 1;
+
+        for addr in connections {
+            *self
+                .connection_transports
+                .entry(transport_of(addr.as_ref()))
+                .or_default() += 1;
+        }
+
+        let info = match info {
+            Some(info) => info,
+            None => {
+                self.unidentified += 1;
+                return;
+            }
+        };
+
+        *self
+            .agent_versions
+            .entry(info.agent_version.clone())
+            .or_default() += 1;
+        *self
+            .protocol_versions
+            .entry(info.protocol_version.clone())
+            .or_default() += 1;
+
+        let mut protocols = info.protocols.iter().collect::<Vec<_>>();
+        protocols.sort();
+        protocols.dedup();
+        for protocol in protocols {
+            *self.protocols.entry(protocol.clone()).or_default() += 1;
+        }
+
+        let mut transports = info
+            .listen_addrs
+            .iter()
+            .map(transport_of)
+            .collect::<Vec<_>>();
+        transports.sort();
+        transports.dedup();
+        for transport in transports {
+            *self.listen_transports.entry(transport).or_default() += 1;
+        }
+    }
+}
+
+/// Returns the protocols following the IP address or the DNS name, or `p2p-circuit` for the
+/// relayed addresses.
+fn transport_of(addr: &Multiaddr) -> String {
+    let mut names = Vec::new();
+
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::Ip4(_)
+            | Protocol::Ip6(_)
+            | Protocol::Dns(_)
+            | Protocol::Dns4(_)
+            | Protocol::Dns6(_)
+            | Protocol::Dnsaddr(_)
+            | Protocol::P2p(_) => {}
+            Protocol::P2pCircuit => return String::from("p2p-circuit"),
+            other => {
+                // the name without the value, like the "tcp" of "/tcp/4001"
+                let tag = other.to_string();
+                names.extend(tag.split('/').nth(1).map(str::to_owned));
+            }
+        }
+    }
+
+    names.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{transport_of, NetworkSurvey};
+    use libp2p::identify::IdentifyInfo;
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn transports() {
+        for (addr, transport) in &[
+            ("/ip4/127.0.0.1/tcp/4001", "tcp"),
+            ("/dns4/example.com/tcp/443/wss", "tcp/wss"),
+            ("/ip6/::1/udp/4001/quic", "udp/quic"),
+            (
+                "/ip4/127.0.0.1/tcp/4001/p2p/QmcgpsyWgH8Y8ajJz1Cu72KnS5uo2Aa2LpzU7kinSupNKC/p2p-circuit",
+                "p2p-circuit",
+            ),
+            ("/memory/1234", "memory"),
+        ] {
+            assert_eq!(transport_of(&addr.parse().unwrap()), *transport, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn peers_are_counted_once() {
+        let info = IdentifyInfo {
+            public_key: Keypair::generate_ed25519().public(),
+            protocol_version: "ipfs/0.1.0".into(),
+            agent_version: "go-ipfs/0.12.0/".into(),
+            listen_addrs: vec![
+                "/ip4/10.0.0.1/tcp/4001".parse().unwrap(),
+                "/ip4/192.168.0.1/tcp/4001".parse().unwrap(),
+                "/ip4/10.0.0.1/udp/4001/quic".parse().unwrap(),
+            ],
+            protocols: vec!["/ipfs/bitswap/1.2.0".into(), "/ipfs/kad/1.0.0".into()],
+            observed_addr: "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+        };
+        let connections = vec!["/ip4/10.0.0.1/tcp/4001".parse().unwrap()];
+
+        let mut survey = NetworkSurvey::default();
+        survey.add_peer(&connections, Some(&info));
+        survey.add_peer(&connections, None);
+
+        assert_eq!(survey.peers, 2);
+        assert_eq!(survey.unidentified, 1);
+        assert_eq!(survey.agent_versions["go-ipfs/0.12.0/"], 1);
+        assert_eq!(survey.protocols["/ipfs/kad/1.0.0"], 1);
+        assert_eq!(survey.connection_transports["tcp"], 2);
+        assert_eq!(survey.listen_transports["tcp"], 1);
+        assert_eq!(survey.listen_transports["udp/quic"], 1);
+    }
+}
@@ -21,6 +21,29 @@ pub struct Connection {
     pub rtt: Option<Duration>,
 }
 
+/// Limits for the dials started through [`SwarmApi::connect`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DialConfig {
+    /// The maximum number of peers dialed at the same time. Excess dials are queued, and started
+    /// in the order of the requests as the earlier dials complete.
+    pub concurrency: usize,
+    /// The maximum number of addresses attempted per dial to a peer. The rest of the addresses
+    /// are attempted in following dials, should the earlier ones fail.
+    pub addresses_per_peer: usize,
+    /// Timeout for dialing a single address, including the connection upgrades.
+    pub timeout: Duration,
+}
+
+impl Default for DialConfig {
+    fn default() -> Self {
+        DialConfig {
+            concurrency: 100,
+            addresses_per_peer: 8,
+            timeout: Duration::from_secs(20),
+        }
+    }
+}
+
 /// Disconnected will use banning to disconnect a node. Disconnecting a single peer connection is
 /// not supported at the moment.
 pub struct Disconnector {
@@ -62,9 +85,24 @@ pub struct SwarmApi {
     pending_connections: HashMap<PeerId, Vec<MultiaddrWithPeerId>>,
 
     pub(crate) bootstrappers: HashSet<MultiaddrWithPeerId>,
+
+    dial_config: DialConfig,
+
+    /// The peers being dialed, at most [`DialConfig::concurrency`].
+    dialing: HashSet<PeerId>,
+
+    /// The peers waiting for a dial to be started.
+    queued_dials: VecDeque<PeerId>,
 }
 
 impl SwarmApi {
+    pub fn with_dial_config(dial_config: DialConfig) -> Self {
+        SwarmApi {
+            dial_config,
+            ..Default::default()
+        }
+    }
+
     pub fn add_peer(&mut self, peer_id: PeerId) {
         self.peers.insert(peer_id);
     }
@@ -94,6 +132,15 @@ impl SwarmApi {
             })
     }
 
+    /// Returns the connected peers with the addresses of all of their connections.
+    pub fn connected_addresses(
+        &self,
+    ) -> impl Iterator<Item = (&PeerId, &[MultiaddrWithoutPeerId])> + '_ {
+        self.connected_peers
+            .iter()
+            .map(|(peer, conns)| (peer, conns.as_slice()))
+    }
+
     pub fn set_rtt(&mut self, peer_id: &PeerId, rtt: Duration) {
         // NOTE: this is for any connection
         self.roundtrip_times.insert(*peer_id, rtt);
@@ -116,25 +163,61 @@ impl SwarmApi {
             .connect_registry
             .create_subscription(addr.clone().into(), None);
 
+        let peer_id = addr.peer_id;
+
+        self.pending_addresses
+            .entry(peer_id)
+            .or_insert_with(|| Vec::with_capacity(1))
+            .push(addr);
+
+        // an ongoing or a queued dial will pick up the address
+        if !self.dialing.contains(&peer_id) && !self.queued_dials.contains(&peer_id) {
+            // store this for returning the time since connecting started before ping is available
+            self.connected_times.insert(peer_id, Instant::now());
+
+            self.dial_or_queue(peer_id);
+        }
+
+        Some(subscription)
+    }
+
+    fn dial_or_queue(&mut self, peer_id: PeerId) {
+        if self.dialing.len() >= self.dial_config.concurrency.max(1) {
+            trace!("queueing dial to {}", peer_id);
+            self.queued_dials.push_back(peer_id);
+            return;
+        }
+
+        self.dialing.insert(peer_id);
+
         let handler = self.new_handler();
         self.events.push_back(NetworkBehaviourAction::Dial {
             // rationale: this is sort of explicit command, perhaps the old address is no longer
             // valid. Always would be even better but it's bugged at the moment.
-            opts: DialOpts::peer_id(addr.peer_id)
+            opts: DialOpts::peer_id(peer_id)
                 .condition(PeerCondition::NotDialing)
                 .build(),
             handler,
         });
+    }
 
-        // store this for returning the time since connecting started before ping is available
-        self.connected_times.insert(addr.peer_id, Instant::now());
+    /// Frees the slot of the completed dial, if any, and starts the queued dials. When `retry` is
+    /// true, the addresses left over by the address limit or added during the dial are dialed.
+    fn dial_finished(&mut self, peer_id: &PeerId, retry: bool) {
+        if !self.dialing.remove(peer_id) {
+            return;
+        }
 
-        self.pending_addresses
-            .entry(addr.peer_id)
-            .or_insert_with(|| Vec::with_capacity(1))
-            .push(addr);
+        if retry && self.pending_addresses.contains_key(peer_id) {
+            self.queued_dials.push_back(*peer_id);
+        }
 
-        Some(subscription)
+        while self.dialing.len() < self.dial_config.concurrency.max(1) {
+            match self.queued_dials.pop_front() {
+                Some(next) => self.dial_or_queue(next),
+                None => break,
+            }
+        }
     }
 
     pub fn disconnect(&mut self, addr: MultiaddrWithPeerId) -> Option<Disconnector> {
@@ -167,7 +250,13 @@ impl NetworkBehaviour for SwarmApi {
         // when libp2p starts dialing, it'll collect these from all of known addresses for the peer
         // from the behaviour and dial them all through, ending with calls to inject_connected or
         // inject_addr_reach_failure.
-        let addresses = self.pending_addresses.remove(peer_id).unwrap_or_default();
+        let limit = self.dial_config.addresses_per_peer.max(1);
+        let addresses = match self.pending_addresses.entry(*peer_id) {
+            Entry::Occupied(oe) if oe.get().len() <= limit => oe.remove(),
+            // the rest are left for the next dial, see `dial_finished`
+            Entry::Occupied(mut oe) => oe.get_mut().drain(..limit).collect(),
+            Entry::Vacant(_) => Vec::new(),
+        };
 
         // store the "given out" addresses as we have created the subscriptions for them
         self.pending_connections
@@ -261,6 +350,10 @@ impl NetworkBehaviour for SwarmApi {
                 Err("finished connecting to another address".into()),
             );
         }
+
+        // any queued dial is no longer needed either
+        self.queued_dials.retain(|queued| queued != peer_id);
+        self.dial_finished(peer_id, false);
     }
 
     fn inject_connection_closed(
@@ -350,6 +443,8 @@ impl NetworkBehaviour for SwarmApi {
                 Entry::Occupied(mut oe) => {
                     let addresses = oe.get_mut();
 
+                    let mut retry = true;
+
                     match error {
                         DialError::Transport(multiaddrs) => {
                             for (addr, error) in multiaddrs {
@@ -380,6 +475,8 @@ impl NetworkBehaviour for SwarmApi {
                                 ?error,
                                 "unexpected DialError; some futures might never complete"
                             );
+                            // retrying could fail right away over and over again
+                            retry = false;
                         }
                     }
 
@@ -387,10 +484,9 @@ impl NetworkBehaviour for SwarmApi {
                         oe.remove();
                     }
 
-                    // FIXME from libp2p-0.43 upgrade: unclear if there could be a need for new
-                    // dial attempt if new entries to self.pending_addresses arrived.
+                    self.dial_finished(&peer_id, retry);
                 }
-                Entry::Vacant(_) => {}
+                Entry::Vacant(_) => self.dial_finished(&peer_id, false),
             }
         }
     }
@@ -595,12 +691,69 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn excess_dials_are_queued() {
+        let (peer1_id, mut swarm1) = build_swarm();
+        let (peer3_id, mut swarm3) = build_swarm();
+        let (_, mut swarm2) = build_swarm_with(DialConfig {
+            concurrency: 1,
+            ..Default::default()
+        });
+
+        Swarm::listen_on(&mut swarm1, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        Swarm::listen_on(&mut swarm3, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+
+        let mut addrs = Vec::with_capacity(2);
+
+        for (peer_id, swarm) in vec![(peer1_id, &mut swarm1), (peer3_id, &mut swarm3)] {
+            loop {
+                if let Some(SwarmEvent::NewListenAddr { address, .. }) = swarm.next().await {
+                    addrs.push(
+                        MultiaddrWithoutPeerId::try_from(address)
+                            .unwrap()
+                            .with(peer_id),
+                    );
+                    break;
+                }
+            }
+        }
+
+        let mut connections = futures::stream::FuturesOrdered::new();
+        for addr in addrs {
+            connections.push(swarm2.behaviour_mut().connect(addr).unwrap());
+        }
+
+        assert_eq!(swarm2.behaviour().dialing.len(), 1);
+        assert_eq!(swarm2.behaviour().queued_dials.len(), 1);
+
+        let ready = connections.map_err(|e| e.into_inner()).collect::<Vec<_>>();
+
+        tokio::pin!(ready);
+
+        loop {
+            tokio::select! {
+                _ = swarm1.next() => {}
+                _ = swarm2.next() => {}
+                _ = swarm3.next() => {}
+                res = &mut ready => {
+                    assert_eq!(res, vec![Ok(()), Ok(())]);
+                    assert!(swarm2.behaviour().queued_dials.is_empty());
+                    break;
+                }
+            }
+        }
+    }
+
     fn build_swarm() -> (PeerId, libp2p::swarm::Swarm<SwarmApi>) {
+        build_swarm_with(DialConfig::default())
+    }
+
+    fn build_swarm_with(dial_config: DialConfig) -> (PeerId, libp2p::swarm::Swarm<SwarmApi>) {
         let key = Keypair::generate_ed25519();
         let peer_id = key.public().to_peer_id();
-        let transport = build_transport(key).unwrap();
+        let transport = build_transport(key, dial_config.timeout, &Default::default()).unwrap();
 
-        let swarm = SwarmBuilder::new(transport, SwarmApi::default(), peer_id)
+        let swarm = SwarmBuilder::new(transport, SwarmApi::with_dial_config(dial_config), peer_id)
             .executor(Box::new(ThreadLocalTokio))
             .build();
         (peer_id, swarm)
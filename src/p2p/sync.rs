@@ -0,0 +1,425 @@
+//! Selective sync of the blocks under a root from a single peer.
+//!
+//! The syncing node sends the peer a bloom filter of all of its blocks along with the root. The
+//! peer walks the blocks it has under the root and replies with the Cids not found in the
+//! filter, at most [`MAX_CIDS`] at a time; the syncing node asks for the following pages by
+//! skipping the already listed Cids, as the walk and the filter stay the same between the
+//! pages. Only the listed blocks are then transferred over bitswap. The blocks hidden by the
+//! false positives of the filter are fetched by a final walk of the root.
+//!
+//! The request is the root Cid prefixed with its length as a big endian `u16`, the number of the
+//! skipped Cids as a big endian `u64`, the number of the filter hashes as a byte and the filter
+//! bits. The response starts with a byte telling if the walk was completed or if the root was
+//! not found, followed by the Cids each prefixed with its length as a big endian `u16`.
+
+use crate::ipld::decode_ipld;
+use crate::refs::ipld_links;
+use crate::repo::{Repo, RepoTypes};
+use async_trait::async_trait;
+use cid::Cid;
+use futures::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::RequestResponseCodec;
+use std::collections::{HashSet, VecDeque};
+use std::convert::{TryFrom, TryInto};
+use std::io;
+
+/// The protocol id of the sync requests.
+pub const BLOCK_SYNC_PROTOCOL: &str = "/rust-ipfs/block-sync/1.0.0";
+
+/// The most Cids listed in a single response.
+const MAX_CIDS: usize = 64 * 1024;
+
+/// The largest accepted request, which is mostly the bloom filter.
+const MAX_REQUEST_SIZE: usize = 16 * 1024 * 1024;
+
+/// The largest accepted response.
+const MAX_RESPONSE_SIZE: usize = MAX_CIDS * (2 + 128) + 1;
+
+/// Bits per element in the bloom filter; with the 7 hashes the false positive rate is about 1%.
+const BITS_PER_BLOCK: usize = 10;
+const HASHES: u8 = 7;
+
+const INCOMPLETE: u8 = 0;
+const COMPLETE: u8 = 1;
+const ROOT_NOT_FOUND: u8 = 2;
+
+/// Asks the peer to list the blocks under the root missing from the filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncRequest {
+    pub(crate) root: Cid,
+    /// The number of missing blocks already listed in the previous responses.
+    pub(crate) skip: u64,
+    pub(crate) filter: BloomFilter,
+}
+
+/// The blocks under the root missing from the filter of the request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncResponse {
+    /// The next page of the missing blocks, and whether the walk of the root was completed.
+    Missing { cids: Vec<Cid>, complete: bool },
+    /// The peer does not have the root.
+    RootNotFound,
+}
+
+/// A bloom filter of the multihashes of the blocks, so that the Cid versions match.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct BloomFilter {
+    hashes: u8,
+    bits: Vec<u8>,
+}
+
+impl std::fmt::Debug for BloomFilter {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            fmt,
+            "BloomFilter {{ hashes: {}, bits: {} }}",
+            self.hashes,
+            self.bits.len() * 8
+        )
+    }
+}
+
+impl BloomFilter {
+    /// Creates a filter of the given blocks.
+    pub(crate) fn new(cids: &[Cid]) -> Self {
+        let bits = (cids.len() * BITS_PER_BLOCK).max(64);
+        // the filter gets worse beyond the request size, but the final walk covers for it
+        let bytes = ((bits + 7) / 8).min(MAX_REQUEST_SIZE - 1024);
+
+        let mut filter = BloomFilter {
+            hashes: HASHES,
+            bits: vec![0; bytes],
+        };
+
+        for cid in cids {
+            filter.insert(cid);
+        }
+
+        filter
+    }
+
+    /// Creates an empty filter for the given number of blocks, without the size limit of the
+    /// requests.
+    pub(crate) fn with_capacity(blocks: usize) -> Self {
+        let bits = (blocks * BITS_PER_BLOCK).max(64);
+
+        BloomFilter {
+            hashes: HASHES,
+            bits: vec![0; (bits + 7) / 8],
+        }
+    }
+
+    /// Adds the block to the filter.
+    pub(crate) fn insert(&mut self, cid: &Cid) {
+        for index in self.indices(cid) {
+            self.bits[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    /// Returns true if the block is possibly in the filter, false if it is certainly not.
+    pub(crate) fn contains(&self, cid: &Cid) -> bool {
+        self.indices(cid)
+            .all(|index| self.bits[index / 8] & (1 << (index % 8)) != 0)
+    }
+
+    fn indices(&self, cid: &Cid) -> impl Iterator<Item = usize> {
+        // double hashing with two fnv-1a hashes, stable across the platforms unlike the std
+        // hashers
+        let key = cid.hash().as_bytes();
+        let h1 = fnv1a(key, 0xcbf2_9ce4_8422_2325);
+        let h2 = fnv1a(key, 0x6c62_272e_07bb_0142) | 1;
+        let len = (self.bits.len() * 8) as u64;
+
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+fn fnv1a(bytes: &[u8], offset: u64) -> u64 {
+    bytes.iter().fold(offset, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Lists the blocks under the `root` missing from the filter of the request, walking the blocks
+/// available locally in the same order for every page.
+pub(crate) async fn list_missing<T: RepoTypes>(
+    repo: &Repo<T>,
+    request: SyncRequest,
+) -> SyncResponse {
+    let SyncRequest { root, skip, filter } = request;
+
+    match repo.get_block_now(&root).await {
+        Ok(Some(_)) => {}
+        _ => return SyncResponse::RootNotFound,
+    }
+
+    let mut work = VecDeque::new();
+    let mut visited = HashSet::new();
+    let mut missing = 0u64;
+    let mut cids = Vec::new();
+
+    visited.insert(root.clone());
+    work.push_back(root);
+
+    while let Some(cid) = work.pop_front() {
+        let block = match repo.get_block_now(&cid).await {
+            Ok(Some(block)) => block,
+            // the blocks this node does not have cannot be served
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("sync: failed to load {}: {}", cid, e);
+                continue;
+            }
+        };
+
+        if !filter.contains(&cid) {
+            missing += 1;
+
+            if missing > skip {
+                if cids.len() == MAX_CIDS {
+                    return SyncResponse::Missing {
+                        cids,
+                        complete: false,
+                    };
+                }
+                cids.push(cid.clone());
+            }
+        }
+
+        let ipld = match decode_ipld(&cid, &block.data) {
+            Ok(ipld) => ipld,
+            Err(e) => {
+                debug!("sync: failed to parse {}: {}", cid, e);
+                continue;
+            }
+        };
+
+        for (_, next) in ipld_links(&cid, ipld) {
+            if visited.insert(next.clone()) {
+                work.push_back(next);
+            }
+        }
+    }
+
+    SyncResponse::Missing {
+        cids,
+        complete: true,
+    }
+}
+
+/// The protocol of the sync requests.
+#[derive(Debug, Clone)]
+pub struct BlockSyncProtocol;
+
+impl ProtocolName for BlockSyncProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        BLOCK_SYNC_PROTOCOL.as_bytes()
+    }
+}
+
+/// Reads and writes the sync requests and responses.
+#[derive(Debug, Clone, Default)]
+pub struct BlockSyncCodec;
+
+#[async_trait]
+impl RequestResponseCodec for BlockSyncCodec {
+    type Protocol = BlockSyncProtocol;
+    type Request = SyncRequest;
+    type Response = SyncResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &BlockSyncProtocol,
+        io: &mut T,
+    ) -> io::Result<SyncRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = read_length_prefixed(io, MAX_REQUEST_SIZE).await?;
+        decode_request(&bytes).ok_or_else(|| invalid_data("invalid sync request"))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &BlockSyncProtocol,
+        io: &mut T,
+    ) -> io::Result<SyncResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = read_length_prefixed(io, MAX_RESPONSE_SIZE).await?;
+        decode_response(&bytes).ok_or_else(|| invalid_data("invalid sync response"))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &BlockSyncProtocol,
+        io: &mut T,
+        request: SyncRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, encode_request(&request)).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &BlockSyncProtocol,
+        io: &mut T,
+        response: SyncResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, encode_response(&response)).await?;
+        io.close().await
+    }
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn encode_request(request: &SyncRequest) -> Vec<u8> {
+    let root = request.root.to_bytes();
+    let mut out = Vec::with_capacity(2 + root.len() + 8 + 1 + request.filter.bits.len());
+    out.extend_from_slice(&(root.len() as u16).to_be_bytes());
+    out.extend_from_slice(&root);
+    out.extend_from_slice(&request.skip.to_be_bytes());
+    out.push(request.filter.hashes);
+    out.extend_from_slice(&request.filter.bits);
+    out
+}
+
+fn decode_request(bytes: &[u8]) -> Option<SyncRequest> {
+    let (root, rest) = split_cid(bytes)?;
+
+    if rest.len() < 8 + 1 + 1 {
+        return None;
+    }
+
+    let skip = u64::from_be_bytes(rest[..8].try_into().unwrap());
+    let hashes = rest[8];
+    let bits = rest[9..].to_vec();
+
+    Some(SyncRequest {
+        root,
+        skip,
+        filter: BloomFilter { hashes, bits },
+    })
+}
+
+fn encode_response(response: &SyncResponse) -> Vec<u8> {
+    match response {
+        SyncResponse::Missing { cids, complete } => {
+            let mut out = vec![if *complete { COMPLETE } else { INCOMPLETE }];
+            for cid in cids {
+                let cid = cid.to_bytes();
+                out.extend_from_slice(&(cid.len() as u16).to_be_bytes());
+                out.extend_from_slice(&cid);
+            }
+            out
+        }
+        SyncResponse::RootNotFound => vec![ROOT_NOT_FOUND],
+    }
+}
+
+fn decode_response(bytes: &[u8]) -> Option<SyncResponse> {
+    let (kind, mut rest) = bytes.split_first()?;
+
+    let complete = match *kind {
+        INCOMPLETE => false,
+        COMPLETE => true,
+        ROOT_NOT_FOUND if rest.is_empty() => return Some(SyncResponse::RootNotFound),
+        _ => return None,
+    };
+
+    let mut cids = Vec::new();
+
+    while !rest.is_empty() {
+        let (cid, next) = split_cid(rest)?;
+        cids.push(cid);
+        rest = next;
+    }
+
+    Some(SyncResponse::Missing { cids, complete })
+}
+
+/// Splits the length prefixed Cid off the bytes.
+fn split_cid(bytes: &[u8]) -> Option<(Cid, &[u8])> {
+    if bytes.len() < 2 {
+        return None;
+    }
+
+    let (len, rest) = bytes.split_at(2);
+    let len = u16::from_be_bytes(len.try_into().unwrap()) as usize;
+
+    if rest.len() < len {
+        return None;
+    }
+
+    let (cid, rest) = rest.split_at(len);
+    Some((Cid::try_from(cid).ok()?, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::Codec;
+    use multihash::Sha2_256;
+
+    fn cid(n: u32) -> Cid {
+        Cid::new_v1(Codec::Raw, Sha2_256::digest(&n.to_be_bytes()))
+    }
+
+    #[test]
+    fn bloom_filter_contains_its_blocks() {
+        let cids = (0..1000).map(cid).collect::<Vec<_>>();
+        let filter = BloomFilter::new(&cids);
+
+        assert!(cids.iter().all(|cid| filter.contains(cid)));
+
+        let false_positives = (1000..11000).filter(|n| filter.contains(&cid(*n))).count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        // the versions of the same block are the same
+        let v0 = Cid::new_v0(Sha2_256::digest(b"foo")).unwrap();
+        let v1 = Cid::new_v1(Codec::DagProtobuf, Sha2_256::digest(b"foo"));
+        assert!(BloomFilter::new(&[v0]).contains(&v1));
+    }
+
+    #[test]
+    fn messages_roundtrip() {
+        let request = SyncRequest {
+            root: cid(0),
+            skip: 8192,
+            filter: BloomFilter::new(&[cid(1), cid(2)]),
+        };
+        assert_eq!(decode_request(&encode_request(&request)), Some(request));
+
+        let responses = [
+            SyncResponse::Missing {
+                cids: vec![cid(1), cid(2)],
+                complete: false,
+            },
+            SyncResponse::Missing {
+                cids: Vec::new(),
+                complete: true,
+            },
+            SyncResponse::RootNotFound,
+        ];
+
+        for response in &responses {
+            assert_eq!(
+                decode_response(&encode_response(response)).as_ref(),
+                Some(response)
+            );
+        }
+
+        assert_eq!(decode_response(&[COMPLETE, 0, 40]), None);
+    }
+}
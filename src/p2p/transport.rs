@@ -1,6 +1,8 @@
+use crate::dns::DnsResolver;
 use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::timeout::TransportTimeout;
 use libp2p::core::transport::upgrade::Version;
-use libp2p::core::transport::Boxed;
+use libp2p::core::transport::{Boxed, MemoryTransport};
 use libp2p::core::upgrade::SelectUpgrade;
 use libp2p::dns::TokioDnsConfig;
 use libp2p::identity;
@@ -17,22 +19,61 @@ pub(crate) type TTransport = Boxed<(PeerId, StreamMuxerBox)>;
 
 /// Builds the transport that serves as a common ground for all connections.
 ///
-/// Set up an encrypted TCP transport over the Mplex protocol.
-pub fn build_transport(keypair: identity::Keypair) -> io::Result<TTransport> {
+/// Set up an encrypted TCP transport over the Mplex protocol. Dialing an address, including the
+/// upgrades, is limited by the `dial_timeout`. The DNS names are resolved with the `dns`.
+pub fn build_transport(
+    keypair: identity::Keypair,
+    dial_timeout: Duration,
+    dns: &DnsResolver,
+) -> io::Result<TTransport> {
     let xx_keypair = noise::Keypair::<noise::X25519Spec>::new()
         .into_authentic(&keypair)
         .unwrap();
     let noise_config = NoiseConfig::xx(xx_keypair).into_authenticated();
 
-    Ok(TokioDnsConfig::system(TokioTcpConfig::new())?
+    let (dns_config, dns_opts) = dns.config()?;
+
+    let transport = TokioDnsConfig::custom(TokioTcpConfig::new(), dns_config, dns_opts)?
+        .upgrade(Version::V1)
+        .authenticate(noise_config)
+        .multiplex(SelectUpgrade::new(
+            YamuxConfig::default(),
+            MplexConfig::new(),
+        ))
+        .timeout(Duration::from_secs(20));
+
+    Ok(
+        TransportTimeout::with_outgoing_timeout(transport, dial_timeout)
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .map_err(|err| Error::new(ErrorKind::Other, err))
+            .boxed(),
+    )
+}
+
+/// Builds the in-process transport of the tests, listening on and dialing only the
+/// `/memory/<port>` addresses, with the same upgrades as [`build_transport`].
+pub fn build_memory_transport(
+    keypair: identity::Keypair,
+    dial_timeout: Duration,
+) -> io::Result<TTransport> {
+    let xx_keypair = noise::Keypair::<noise::X25519Spec>::new()
+        .into_authentic(&keypair)
+        .unwrap();
+    let noise_config = NoiseConfig::xx(xx_keypair).into_authenticated();
+
+    let transport = MemoryTransport::default()
         .upgrade(Version::V1)
         .authenticate(noise_config)
         .multiplex(SelectUpgrade::new(
             YamuxConfig::default(),
             MplexConfig::new(),
         ))
-        .timeout(Duration::from_secs(20))
-        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
-        .map_err(|err| Error::new(ErrorKind::Other, err))
-        .boxed())
+        .timeout(Duration::from_secs(20));
+
+    Ok(
+        TransportTimeout::with_outgoing_timeout(transport, dial_timeout)
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .map_err(|err| Error::new(ErrorKind::Other, err))
+            .boxed(),
+    )
 }
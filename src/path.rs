@@ -281,6 +281,20 @@ impl fmt::Display for PathRoot {
     }
 }
 
+impl FromStr for PathRoot {
+    type Err = Error;
+
+    /// Parses the root of an [`IpfsPath`], without any segments.
+    fn from_str(string: &str) -> Result<Self, Error> {
+        let path = IpfsPath::from_str(string)?;
+        if path.path.is_empty() {
+            Ok(path.root)
+        } else {
+            Err(IpfsPathError::InvalidPath(string.to_owned()).into())
+        }
+    }
+}
+
 impl From<Cid> for PathRoot {
     fn from(cid: Cid) -> Self {
         PathRoot::Ipld(cid)
@@ -445,4 +459,14 @@ mod tests {
 
         assert_eq!(p.to_string(), "c");
     }
+
+    #[test]
+    fn path_root_without_segments() {
+        let root = "/ipns/ipfs.io".parse::<super::PathRoot>().unwrap();
+        assert_eq!(root, super::PathRoot::Dns("ipfs.io".into()));
+
+        "/ipfs/QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n/a"
+            .parse::<super::PathRoot>()
+            .unwrap_err();
+    }
 }
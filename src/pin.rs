@@ -0,0 +1,4 @@
+//! Pinning of the content on remote services, in addition to the local pins of
+//! [`crate::Ipfs::insert_pin`], see [`remote`].
+
+pub mod remote;
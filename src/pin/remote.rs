@@ -0,0 +1,629 @@
+//! Delegating the persistence of the content to the services implementing the [IPFS Pinning
+//! Service API], like Pinata, web3.storage or a self-hosted service.
+//!
+//! The services and their credentials are configured in [`crate::IpfsOptions::pinning_services`]
+//! and can be changed at runtime with [`Ipfs::add_pinning_service`] and
+//! [`Ipfs::remove_pinning_service`]. With the `remote_pinning` feature, the content is pinned,
+//! listed and unpinned on a service with [`Ipfs::pin_remote_add`], [`Ipfs::pin_remote_ls`] and
+//! [`Ipfs::pin_remote_rm`], or directly through the [`RemotePinning`] client.
+//!
+//! [IPFS Pinning Service API]: https://ipfs.github.io/pinning-services-api-spec/
+
+use crate::error::Error;
+use crate::{Ipfs, IpfsTypes};
+use cid::Cid;
+use libp2p::Multiaddr;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+#[cfg(feature = "remote_pinning")]
+pub use self::client::RemotePinning;
+
+/// A remote pinning service and the access token for it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PinningService {
+    /// The local name of the service, used to refer to it.
+    pub name: String,
+    /// The base URL of the API, for example `https://api.pinata.cloud/psa`.
+    pub endpoint: String,
+    /// The access token sent as the bearer token.
+    pub key: String,
+}
+
+impl fmt::Debug for PinningService {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the key is a secret, which should not end up in the logs
+        fmt.debug_struct("PinningService")
+            .field("name", &self.name)
+            .field("endpoint", &self.endpoint)
+            .finish()
+    }
+}
+
+/// The status of a pin on a remote service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RemotePinStatus {
+    /// Waiting to be processed by the service.
+    Queued,
+    /// The service is fetching the content.
+    Pinning,
+    /// The content is pinned on the service.
+    Pinned,
+    /// The service could not pin the content.
+    Failed,
+}
+
+impl RemotePinStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RemotePinStatus::Queued => "queued",
+            RemotePinStatus::Pinning => "pinning",
+            RemotePinStatus::Pinned => "pinned",
+            RemotePinStatus::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for RemotePinStatus {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+/// The string given to [`RemotePinStatus::from_str`] is not one of the statuses.
+#[derive(Debug, thiserror::Error)]
+#[error("unknown remote pin status {0:?}")]
+pub struct UnknownStatus(String);
+
+impl FromStr for RemotePinStatus {
+    type Err = UnknownStatus;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "queued" => RemotePinStatus::Queued,
+            "pinning" => RemotePinStatus::Pinning,
+            "pinned" => RemotePinStatus::Pinned,
+            "failed" => RemotePinStatus::Failed,
+            other => return Err(UnknownStatus(other.to_owned())),
+        })
+    }
+}
+
+/// The content to pin on a remote service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemotePin {
+    pub cid: Cid,
+    /// Optional name for the pin, which can be used to find it.
+    pub name: Option<String>,
+    /// The addresses of the peers known to have the content, like this node.
+    pub origins: Vec<Multiaddr>,
+    /// Optional metadata for the service.
+    pub meta: BTreeMap<String, String>,
+}
+
+impl RemotePin {
+    pub fn new(cid: Cid) -> Self {
+        RemotePin {
+            cid,
+            name: None,
+            origins: Vec::new(),
+            meta: BTreeMap::new(),
+        }
+    }
+}
+
+/// A pin on a remote service, as returned by the service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemotePinRecord {
+    /// The identifier of the pin on the service.
+    pub request_id: String,
+    pub status: RemotePinStatus,
+    /// RFC 3339 timestamp of the creation of the pin.
+    pub created: String,
+    pub pin: RemotePin,
+    /// The addresses of the peers of the service which fetch the content. Connecting to them
+    /// speeds up the pinning of the content added only to this node.
+    pub delegates: Vec<Multiaddr>,
+    /// Optional additional information from the service.
+    pub info: BTreeMap<String, String>,
+}
+
+/// Which of the pins [`Ipfs::pin_remote_ls`] and [`Ipfs::pin_remote_rm`] apply to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RemotePinQuery {
+    /// The pins of any of these Cids, or of all Cids if empty.
+    pub cids: Vec<Cid>,
+    /// The pins with exactly this name.
+    pub name: Option<String>,
+    /// The pins with any of these statuses, or only the pinned ones if empty.
+    pub statuses: Vec<RemotePinStatus>,
+    /// At most this many of the latest pins, or all of them.
+    pub limit: Option<usize>,
+}
+
+/// The failure response of a pinning service.
+#[derive(Debug, thiserror::Error)]
+#[error("pinning service responded with {status}: {reason}")]
+pub struct ServiceError {
+    /// The HTTP status code.
+    pub status: u16,
+    /// The short description of the failure, like `BAD_REQUEST` or `DUPLICATE_OBJECT`.
+    pub reason: String,
+    /// The optional longer description.
+    pub details: Option<String>,
+}
+
+/// The pinning services of the node, see [`crate::pin::remote`].
+#[derive(Debug, Default)]
+pub(crate) struct PinningServices(RwLock<BTreeMap<String, PinningService>>);
+
+impl PinningServices {
+    pub(crate) fn new(services: &[PinningService]) -> Self {
+        let services = services
+            .iter()
+            .map(|service| (service.name.clone(), service.clone()))
+            .collect();
+        PinningServices(RwLock::new(services))
+    }
+}
+
+impl<Types: IpfsTypes> Ipfs<Types> {
+    /// Returns the configured remote pinning services, ordered by their names.
+    pub fn pinning_services(&self) -> Vec<PinningService> {
+        self.pinning_services
+            .0
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Adds a remote pinning service, failing if a service with the same name already exists.
+    pub fn add_pinning_service(&self, service: PinningService) -> Result<(), Error> {
+        let mut services = self.pinning_services.0.write().unwrap();
+        if services.contains_key(&service.name) {
+            return Err(anyhow::anyhow!(
+                "pinning service {:?} already exists",
+                service.name
+            ));
+        }
+        services.insert(service.name.clone(), service);
+        Ok(())
+    }
+
+    /// Removes the remote pinning service; returns `false` if there was no such service. The
+    /// pins on the service are left as they are.
+    pub fn remove_pinning_service(&self, name: &str) -> bool {
+        self.pinning_services
+            .0
+            .write()
+            .unwrap()
+            .remove(name)
+            .is_some()
+    }
+}
+
+#[cfg(feature = "remote_pinning")]
+impl<Types: IpfsTypes> Ipfs<Types> {
+    /// Returns the client of the configured pinning service called `service`.
+    pub fn remote_pinning(&self, service: &str) -> Result<RemotePinning, Error> {
+        let services = self.pinning_services.0.read().unwrap();
+        let service = services
+            .get(service)
+            .ok_or_else(|| anyhow::anyhow!("unknown pinning service {:?}", service))?;
+        RemotePinning::new(service)
+    }
+
+    /// Asks the pinning service called `service` to pin the `cid`, giving the addresses of this
+    /// node as the origins of the content. The service pins the content in the background; the
+    /// returned record is usually still queued.
+    pub async fn pin_remote_add(
+        &self,
+        service: &str,
+        cid: Cid,
+        name: Option<String>,
+    ) -> Result<RemotePinRecord, Error> {
+        let client = self.remote_pinning(service)?;
+        let (_, origins) = self.identity().await?;
+
+        let pin = RemotePin {
+            name,
+            origins,
+            ..RemotePin::new(cid)
+        };
+
+        client.add(&pin).await
+    }
+
+    /// Lists the pins matching the `query` on the pinning service called `service`.
+    pub async fn pin_remote_ls(
+        &self,
+        service: &str,
+        query: &RemotePinQuery,
+    ) -> Result<Vec<RemotePinRecord>, Error> {
+        self.remote_pinning(service)?.ls(query).await
+    }
+
+    /// Removes the pins matching the `query` from the pinning service called `service`. Unless
+    /// `force` is given, fails without removing anything if more than one pin matches.
+    ///
+    /// Returns the removed pins.
+    pub async fn pin_remote_rm(
+        &self,
+        service: &str,
+        query: &RemotePinQuery,
+        force: bool,
+    ) -> Result<Vec<RemotePinRecord>, Error> {
+        let client = self.remote_pinning(service)?;
+        let matching = client.ls(query).await?;
+
+        if matching.len() > 1 && !force {
+            return Err(anyhow::anyhow!(
+                "{} pins match the query; use force to remove all of them",
+                matching.len()
+            ));
+        }
+
+        for record in &matching {
+            client.rm(&record.request_id).await?;
+        }
+
+        Ok(matching)
+    }
+}
+
+#[cfg(feature = "remote_pinning")]
+mod client {
+    use super::{
+        PinningService, RemotePin, RemotePinQuery, RemotePinRecord, RemotePinStatus, ServiceError,
+    };
+    use crate::error::Error;
+    use cid::Cid;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    /// The largest page of pins the services are required to return.
+    const MAX_PAGE: usize = 1000;
+
+    /// Client of a service implementing the [IPFS Pinning Service API].
+    ///
+    /// [IPFS Pinning Service API]: https://ipfs.github.io/pinning-services-api-spec/
+    #[derive(Clone)]
+    pub struct RemotePinning {
+        name: String,
+        endpoint: String,
+        key: String,
+        client: reqwest::Client,
+    }
+
+    impl std::fmt::Debug for RemotePinning {
+        fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            fmt.debug_struct("RemotePinning")
+                .field("name", &self.name)
+                .field("endpoint", &self.endpoint)
+                .finish()
+        }
+    }
+
+    impl RemotePinning {
+        /// Creates a client of the `service`.
+        pub fn new(service: &PinningService) -> Result<Self, Error> {
+            let mut endpoint = service.endpoint.clone();
+            while endpoint.ends_with('/') {
+                endpoint.pop();
+            }
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(60))
+                .build()?;
+            Ok(RemotePinning {
+                name: service.name.clone(),
+                endpoint,
+                key: service.key.clone(),
+                client,
+            })
+        }
+
+        /// Asks the service to pin the content.
+        pub async fn add(&self, pin: &RemotePin) -> Result<RemotePinRecord, Error> {
+            let request = self
+                .client
+                .post(&format!("{}/pins", self.endpoint))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&PinObject::from(pin))?);
+            let body = self.send(request).await?;
+            parse_status(&body)
+        }
+
+        /// Returns the pin with the `request_id`.
+        pub async fn get(&self, request_id: &str) -> Result<RemotePinRecord, Error> {
+            let request = self
+                .client
+                .get(&format!("{}/pins/{}", self.endpoint, request_id));
+            let body = self.send(request).await?;
+            parse_status(&body)
+        }
+
+        /// Lists the pins matching the `query`, from the latest to the oldest, following the
+        /// pages of the results.
+        pub async fn ls(&self, query: &RemotePinQuery) -> Result<Vec<RemotePinRecord>, Error> {
+            let mut params = Vec::new();
+            if !query.cids.is_empty() {
+                let cids = query.cids.iter().map(|cid| cid.to_string());
+                params.push(("cid", cids.collect::<Vec<_>>().join(",")));
+            }
+            if let Some(name) = &query.name {
+                params.push(("name", name.clone()));
+                params.push(("match", "exact".to_owned()));
+            }
+            if !query.statuses.is_empty() {
+                let statuses = query.statuses.iter().map(RemotePinStatus::as_str);
+                params.push(("status", statuses.collect::<Vec<_>>().join(",")));
+            }
+
+            let mut records = Vec::new();
+            let mut before: Option<String> = None;
+
+            if query.limit == Some(0) {
+                return Ok(records);
+            }
+
+            loop {
+                let wanted = query
+                    .limit
+                    .map(|limit| limit - records.len())
+                    .unwrap_or(MAX_PAGE)
+                    .min(MAX_PAGE);
+
+                let mut page_params = params.clone();
+                page_params.push(("limit", wanted.to_string()));
+                if let Some(before) = &before {
+                    page_params.push(("before", before.clone()));
+                }
+
+                let request = self
+                    .client
+                    .get(&format!("{}/pins", self.endpoint))
+                    .query(&page_params);
+                let body = self.send(request).await?;
+                let (count, page) = parse_results(&body)?;
+
+                let last_page = page.len() < wanted;
+                before = page.last().map(|record| record.created.clone());
+                records.extend(page);
+
+                if last_page
+                    || records.len() as u64 >= count
+                    || query.limit.map_or(false, |limit| records.len() >= limit)
+                {
+                    return Ok(records);
+                }
+            }
+        }
+
+        /// Removes the pin with the `request_id`.
+        pub async fn rm(&self, request_id: &str) -> Result<(), Error> {
+            let request = self
+                .client
+                .delete(&format!("{}/pins/{}", self.endpoint, request_id));
+            self.send(request).await?;
+            Ok(())
+        }
+
+        async fn send(&self, request: reqwest::RequestBuilder) -> Result<bytes::Bytes, Error> {
+            let response = request
+                .bearer_auth(&self.key)
+                .header(reqwest::header::ACCEPT, "application/json")
+                .send()
+                .await?;
+
+            let status = response.status();
+            let body = response.bytes().await?;
+
+            if status.is_success() {
+                Ok(body)
+            } else {
+                Err(parse_failure(status.as_u16(), &body).into())
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct PinObject {
+        cid: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        origins: Vec<String>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        meta: BTreeMap<String, String>,
+    }
+
+    impl From<&RemotePin> for PinObject {
+        fn from(pin: &RemotePin) -> Self {
+            PinObject {
+                cid: pin.cid.to_string(),
+                name: pin.name.clone(),
+                origins: pin.origins.iter().map(|addr| addr.to_string()).collect(),
+                meta: pin.meta.clone(),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct PinStatusObject {
+        requestid: String,
+        status: String,
+        created: String,
+        pin: PinObject,
+        #[serde(default)]
+        delegates: Vec<String>,
+        #[serde(default)]
+        info: BTreeMap<String, String>,
+    }
+
+    impl TryFrom<PinStatusObject> for RemotePinRecord {
+        type Error = Error;
+
+        fn try_from(object: PinStatusObject) -> Result<Self, Self::Error> {
+            let pin = object.pin;
+            Ok(RemotePinRecord {
+                request_id: object.requestid,
+                status: object.status.parse()?,
+                created: object.created,
+                pin: RemotePin {
+                    cid: Cid::try_from(pin.cid.as_str())?,
+                    name: pin.name,
+                    // the unparseable addresses are of no use, and not worth failing over
+                    origins: pin.origins.iter().filter_map(|a| a.parse().ok()).collect(),
+                    meta: pin.meta,
+                },
+                delegates: object
+                    .delegates
+                    .iter()
+                    .filter_map(|a| a.parse().ok())
+                    .collect(),
+                info: object.info,
+            })
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct PinResults {
+        count: u64,
+        results: Vec<PinStatusObject>,
+    }
+
+    #[derive(Deserialize)]
+    struct Failure {
+        error: FailureError,
+    }
+
+    #[derive(Deserialize)]
+    struct FailureError {
+        reason: String,
+        details: Option<String>,
+    }
+
+    pub(super) fn parse_status(body: &[u8]) -> Result<RemotePinRecord, Error> {
+        let object: PinStatusObject = serde_json::from_slice(body)?;
+        RemotePinRecord::try_from(object)
+    }
+
+    pub(super) fn parse_results(body: &[u8]) -> Result<(u64, Vec<RemotePinRecord>), Error> {
+        let results: PinResults = serde_json::from_slice(body)?;
+        let records = results
+            .results
+            .into_iter()
+            .map(RemotePinRecord::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok((results.count, records))
+    }
+
+    /// The services should describe the failures as json, but the proxies in front of them
+    /// might not.
+    pub(super) fn parse_failure(status: u16, body: &[u8]) -> ServiceError {
+        match serde_json::from_slice::<Failure>(body) {
+            Ok(Failure { error }) => ServiceError {
+                status,
+                reason: error.reason,
+                details: error.details,
+            },
+            Err(_) => ServiceError {
+                status,
+                reason: String::from_utf8_lossy(body).trim().to_owned(),
+                details: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PinningService, RemotePinStatus};
+    use crate::{IpfsOptions, Node};
+
+    fn service(name: &str) -> PinningService {
+        PinningService {
+            name: name.to_owned(),
+            endpoint: "https://pinning.example.com/psa".to_owned(),
+            key: "secret".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn services_are_managed_at_runtime() {
+        let mut opts = IpfsOptions::inmemory_with_generated_keys();
+        opts.pinning_services = vec![service("first")];
+        let ipfs = Node::with_options(opts).await;
+
+        ipfs.add_pinning_service(service("second")).unwrap();
+        assert!(ipfs.add_pinning_service(service("first")).is_err());
+
+        let names = ipfs
+            .pinning_services()
+            .into_iter()
+            .map(|s| s.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["first", "second"]);
+
+        assert!(ipfs.remove_pinning_service("first"));
+        assert!(!ipfs.remove_pinning_service("first"));
+
+        assert!(!format!("{:?}", service("first")).contains("secret"));
+        assert_eq!(
+            "pinning".parse::<RemotePinStatus>().unwrap(),
+            RemotePinStatus::Pinning
+        );
+    }
+
+    #[cfg(feature = "remote_pinning")]
+    #[test]
+    fn pinning_service_responses() {
+        use super::client::{parse_failure, parse_results};
+
+        let body = br#"{"count": 2, "results": [
+            {
+                "requestid": "UniqueIdOfPinRequest",
+                "status": "pinned",
+                "created": "2020-07-27T17:32:28Z",
+                "pin": {
+                    "cid": "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH",
+                    "name": "hello",
+                    "origins": ["/ip4/1.2.3.4/tcp/4001/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN"]
+                },
+                "delegates": ["/ip4/5.6.7.8/tcp/4001/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa", "bogus"],
+                "info": {"region": "eu"}
+            },
+            {
+                "requestid": "Other",
+                "status": "queued",
+                "created": "2020-07-27T17:30:00Z",
+                "pin": { "cid": "bafkqaaa" }
+            }
+        ]}"#;
+
+        let (count, records) = parse_results(body).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(records[0].request_id, "UniqueIdOfPinRequest");
+        assert_eq!(records[0].status, RemotePinStatus::Pinned);
+        assert_eq!(records[0].pin.name.as_deref(), Some("hello"));
+        assert_eq!(records[0].pin.origins.len(), 1);
+        assert_eq!(records[0].delegates.len(), 1);
+        assert_eq!(records[0].info["region"], "eu");
+        assert_eq!(records[1].status, RemotePinStatus::Queued);
+
+        let failure = parse_failure(
+            409,
+            br#"{"error": {"reason": "DUPLICATE_OBJECT", "details": "already pinned"}}"#,
+        );
+        assert_eq!(failure.reason, "DUPLICATE_OBJECT");
+        assert_eq!(failure.details.as_deref(), Some("already pinned"));
+
+        assert_eq!(parse_failure(502, b"Bad Gateway\n").reason, "Bad Gateway");
+    }
+}
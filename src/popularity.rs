@@ -0,0 +1,249 @@
+//! Tracking of the most requested content.
+//!
+//! The requests served through the gateway and to other peers over bitswap are counted per Cid
+//! into a score which decays over time, so that the score reflects both how often and how
+//! recently the content has been requested. The most popular content can be listed, and the
+//! [`crate::republish::Republisher`] can be configured to republish the provider records of the
+//! popular content more often.
+
+use cid::Cid;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Configuration of the [`PopularityTracker`].
+#[derive(Debug, Clone)]
+pub struct PopularityConfig {
+    /// The maximum number of tracked Cids. The least popular ones are forgotten over this.
+    pub capacity: usize,
+    /// The time in which the score of a Cid halves, when it is not requested.
+    pub half_life: Duration,
+    /// The score over which the content is considered popular.
+    pub popular_score: f64,
+}
+
+impl Default for PopularityConfig {
+    fn default() -> Self {
+        PopularityConfig {
+            capacity: 10_000,
+            half_life: Duration::from_secs(60 * 60),
+            popular_score: 10.0,
+        }
+    }
+}
+
+/// Where the content was requested from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestSource {
+    /// The HTTP gateway.
+    Gateway,
+    /// Another peer over bitswap.
+    Bitswap,
+}
+
+/// A tracked Cid returned by [`PopularityTracker::top`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PopularContent {
+    pub cid: Cid,
+    /// The decayed request count.
+    pub score: f64,
+    /// Number of the requests through the gateway since the Cid started to be tracked.
+    pub gateway_requests: u64,
+    /// Number of the requests over bitswap since the Cid started to be tracked.
+    pub bitswap_requests: u64,
+    /// Time of the latest request.
+    pub last_requested: SystemTime,
+}
+
+#[derive(Debug)]
+struct Entry {
+    score: f64,
+    updated: Instant,
+    gateway_requests: u64,
+    bitswap_requests: u64,
+    last_requested: SystemTime,
+}
+
+impl Entry {
+    fn score_at(&self, now: Instant, half_life: Duration) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.score * 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64().max(1e-3))
+    }
+}
+
+/// Keeps the decaying request counts per Cid.
+#[derive(Debug)]
+pub struct PopularityTracker {
+    config: PopularityConfig,
+    entries: Mutex<HashMap<Cid, Entry>>,
+}
+
+impl Default for PopularityTracker {
+    fn default() -> Self {
+        PopularityTracker::new(PopularityConfig::default())
+    }
+}
+
+impl PopularityTracker {
+    pub fn new(config: PopularityConfig) -> Self {
+        PopularityTracker {
+            config,
+            entries: Default::default(),
+        }
+    }
+
+    /// Counts a request for the `cid`.
+    pub fn record(&self, cid: &Cid, source: RequestSource) {
+        self.record_at(cid, source, Instant::now())
+    }
+
+    fn record_at(&self, cid: &Cid, source: RequestSource, now: Instant) {
+        let half_life = self.config.half_life;
+        let mut entries = self.entries.lock().unwrap();
+
+        let entry = entries.entry(cid.to_owned()).or_insert_with(|| Entry {
+            score: 0.0,
+            updated: now,
+            gateway_requests: 0,
+            bitswap_requests: 0,
+            last_requested: SystemTime::now(),
+        });
+
+        entry.score = entry.score_at(now, half_life) + 1.0;
+        entry.updated = now;
+        entry.last_requested = SystemTime::now();
+        match source {
+            RequestSource::Gateway => entry.gateway_requests += 1,
+            RequestSource::Bitswap => entry.bitswap_requests += 1,
+        }
+
+        if entries.len() > self.config.capacity {
+            // forget a tenth at once instead of scanning all of the entries on every request
+            let keep = self.config.capacity - self.config.capacity / 10;
+            let mut scores = entries
+                .iter()
+                .map(|(cid, entry)| (entry.score_at(now, half_life), cid.to_owned()))
+                .collect::<Vec<_>>();
+            sort_descending(&mut scores);
+
+            for (_, cid) in scores.into_iter().skip(keep) {
+                entries.remove(&cid);
+            }
+        }
+    }
+
+    /// Returns the current score of the `cid`, zero if it is not tracked.
+    pub fn score(&self, cid: &Cid) -> f64 {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(cid)
+            .map(|entry| entry.score_at(Instant::now(), self.config.half_life))
+            .unwrap_or(0.0)
+    }
+
+    /// Returns true if the score of the `cid` is over [`PopularityConfig::popular_score`].
+    pub fn is_popular(&self, cid: &Cid) -> bool {
+        self.score(cid) >= self.config.popular_score
+    }
+
+    /// Returns at most `count` of the most popular Cids, the most popular first.
+    pub fn top(&self, count: usize) -> Vec<PopularContent> {
+        self.top_at(count, Instant::now())
+    }
+
+    fn top_at(&self, count: usize, now: Instant) -> Vec<PopularContent> {
+        let entries = self.entries.lock().unwrap();
+
+        let mut scores = entries
+            .iter()
+            .map(|(cid, entry)| (entry.score_at(now, self.config.half_life), cid))
+            .collect::<Vec<_>>();
+        sort_descending(&mut scores);
+
+        scores
+            .into_iter()
+            .take(count)
+            .map(|(score, cid)| {
+                let entry = &entries[cid];
+                PopularContent {
+                    cid: cid.to_owned(),
+                    score,
+                    gateway_requests: entry.gateway_requests,
+                    bitswap_requests: entry.bitswap_requests,
+                    last_requested: entry.last_requested,
+                }
+            })
+            .collect()
+    }
+}
+
+fn sort_descending<T>(scores: &mut [(f64, T)]) {
+    scores.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PopularityConfig, PopularityTracker, RequestSource};
+    use cid::{Cid, Codec};
+    use multihash::Sha2_256;
+    use std::time::{Duration, Instant};
+
+    fn cid(data: &[u8]) -> Cid {
+        Cid::new_v1(Codec::Raw, Sha2_256::digest(data))
+    }
+
+    #[test]
+    fn scores_decay() {
+        let tracker = PopularityTracker::new(PopularityConfig {
+            half_life: Duration::from_secs(60),
+            ..Default::default()
+        });
+
+        let (old, new) = (cid(b"old"), cid(b"new"));
+        let start = Instant::now();
+
+        for _ in 0..4 {
+            tracker.record_at(&old, RequestSource::Gateway, start);
+        }
+        tracker.record_at(
+            &new,
+            RequestSource::Bitswap,
+            start + Duration::from_secs(120),
+        );
+        tracker.record_at(
+            &new,
+            RequestSource::Bitswap,
+            start + Duration::from_secs(120),
+        );
+
+        let top = tracker.top_at(10, start + Duration::from_secs(120));
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].cid, new);
+        assert!((top[0].score - 2.0).abs() < 1e-6);
+        assert_eq!(top[0].bitswap_requests, 2);
+        assert_eq!(top[1].cid, old);
+        assert!((top[1].score - 1.0).abs() < 1e-6);
+        assert_eq!(top[1].gateway_requests, 4);
+    }
+
+    #[test]
+    fn least_popular_are_forgotten() {
+        let tracker = PopularityTracker::new(PopularityConfig {
+            capacity: 10,
+            ..Default::default()
+        });
+
+        let now = Instant::now();
+        let popular = cid(b"popular");
+        tracker.record_at(&popular, RequestSource::Gateway, now);
+        tracker.record_at(&popular, RequestSource::Gateway, now);
+
+        for i in 0u32..10 {
+            tracker.record_at(&cid(&i.to_be_bytes()), RequestSource::Bitswap, now);
+        }
+
+        let top = tracker.top_at(100, now);
+        assert_eq!(top.len(), 9);
+        assert_eq!(top[0].cid, popular);
+    }
+}
@@ -0,0 +1,258 @@
+//! Prefetching of the blocks an application expects to need soon, like the next segment of a
+//! video or the images next to the one being viewed, see [`Ipfs::prefetch`].
+//!
+//! The prefetched blocks are fetched in the background with a low priority: at most
+//! [`PREFETCH_CONCURRENCY`] of them are fetched from the network at once over all of the
+//! prefetches, and a fetch is started only when no other fetch of a missing block is in
+//! progress, so that the prefetching yields to the blocks requested with [`Ipfs::get_block`] and
+//! the other interactive requests. The blocks already in the blockstore are not fetched again.
+
+use crate::selectors::{Selector, SelectorTraversal};
+use crate::{Error, Ipfs, IpfsTypes};
+use cid::Cid;
+use futures::stream::{self, StreamExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{Notify, Semaphore, SemaphorePermit};
+use tokio::task::JoinHandle;
+use tracing_futures::Instrument;
+
+/// The maximum number of the prefetched blocks fetched from the network at once.
+pub const PREFETCH_CONCURRENCY: usize = 4;
+
+/// What to prefetch.
+#[derive(Debug, Clone)]
+pub enum Prefetch {
+    /// The given blocks, without following their links.
+    Blocks(Vec<Cid>),
+    /// The blocks reached by the selector from the root, see [`SelectorTraversal`].
+    Selected { root: Cid, selector: Selector },
+}
+
+impl From<Cid> for Prefetch {
+    fn from(cid: Cid) -> Self {
+        Prefetch::Blocks(vec![cid])
+    }
+}
+
+impl From<Vec<Cid>> for Prefetch {
+    fn from(cids: Vec<Cid>) -> Self {
+        Prefetch::Blocks(cids)
+    }
+}
+
+/// The outcome of a finished prefetch.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PrefetchStats {
+    /// The number of the blocks now in the blockstore, including the ones which already were.
+    pub blocks: usize,
+    /// The number of the blocks which could not be fetched. A selector traversal ends on the
+    /// first one, as the links of the block are not known.
+    pub failed: usize,
+}
+
+/// A prefetch running in the background. Dropping the handle leaves the prefetch running.
+#[derive(Debug)]
+pub struct PrefetchHandle {
+    task: JoinHandle<PrefetchStats>,
+}
+
+impl PrefetchHandle {
+    /// Stops the prefetch. The blocks already fetched are kept.
+    pub fn cancel(self) {
+        self.task.abort();
+    }
+
+    /// Waits for the prefetch to finish.
+    pub async fn finished(self) -> Result<PrefetchStats, Error> {
+        Ok(self.task.await?)
+    }
+}
+
+/// Keeps count of the fetches of the missing blocks made on behalf of the interactive requests,
+/// and limits the concurrency of the prefetches.
+#[derive(Debug)]
+pub(crate) struct PrefetchState {
+    foreground: AtomicUsize,
+    idle: Notify,
+    slots: Semaphore,
+}
+
+impl Default for PrefetchState {
+    fn default() -> Self {
+        PrefetchState {
+            foreground: AtomicUsize::new(0),
+            idle: Notify::new(),
+            slots: Semaphore::new(PREFETCH_CONCURRENCY),
+        }
+    }
+}
+
+impl PrefetchState {
+    /// Marks a fetch of a missing block as in progress until the returned guard is dropped.
+    pub(crate) fn foreground(&self) -> ForegroundFetch<'_> {
+        self.foreground.fetch_add(1, Ordering::SeqCst);
+        ForegroundFetch(self)
+    }
+
+    /// Waits for a free slot for a prefetched block, and then until no fetch of a missing block
+    /// is in progress.
+    pub(crate) async fn background(&self) -> SemaphorePermit<'_> {
+        let permit = self
+            .slots
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+
+        loop {
+            // created before checking the count so that the notification is not missed in
+            // between
+            let idle = self.idle.notified();
+            if self.foreground.load(Ordering::SeqCst) == 0 {
+                return permit;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// A fetch of a missing block in progress, see [`PrefetchState::foreground`].
+pub(crate) struct ForegroundFetch<'a>(&'a PrefetchState);
+
+impl Drop for ForegroundFetch<'_> {
+    fn drop(&mut self) {
+        if self.0.foreground.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl<Types: IpfsTypes> Ipfs<Types> {
+    /// Starts fetching the blocks of the `target` into the blockstore in the background, as a
+    /// hint of what is likely to be needed next. The prefetch yields to the interactive requests,
+    /// see [`crate::prefetch`].
+    pub fn prefetch(&self, target: impl Into<Prefetch>) -> PrefetchHandle {
+        let ipfs = self.clone();
+        let target = target.into();
+
+        let task = tokio::spawn(
+            async move {
+                let stats = match target {
+                    Prefetch::Blocks(cids) => prefetch_blocks(&ipfs, cids).await,
+                    Prefetch::Selected { root, selector } => {
+                        prefetch_selected(&ipfs, root, selector).await
+                    }
+                };
+                debug!(
+                    blocks = stats.blocks,
+                    failed = stats.failed,
+                    "prefetch finished"
+                );
+                stats
+            }
+            .instrument(self.span.clone()),
+        );
+
+        PrefetchHandle { task }
+    }
+}
+
+async fn prefetch_blocks<Types: IpfsTypes>(ipfs: &Ipfs<Types>, cids: Vec<Cid>) -> PrefetchStats {
+    stream::iter(cids)
+        .map(|cid| async move {
+            let result = ipfs.repo.prefetch_block(&cid).await;
+            if let Err(e) = &result {
+                debug!(cid = %cid, "failed to prefetch: {}", e);
+            }
+            result.is_ok()
+        })
+        .buffer_unordered(PREFETCH_CONCURRENCY)
+        .fold(PrefetchStats::default(), |mut stats, fetched| async move {
+            if fetched {
+                stats.blocks += 1;
+            } else {
+                stats.failed += 1;
+            }
+            stats
+        })
+        .await
+}
+
+async fn prefetch_selected<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    root: Cid,
+    selector: Selector,
+) -> PrefetchStats {
+    let mut stats = PrefetchStats::default();
+
+    let traversal = SelectorTraversal::new(selector)
+        .in_background()
+        .traverse(ipfs, root);
+    futures::pin_mut!(traversal);
+
+    while let Some(selected) = traversal.next().await {
+        match selected {
+            Ok(_) => stats.blocks += 1,
+            Err(e) => {
+                debug!("prefetch traversal failed: {}", e);
+                stats.failed += 1;
+            }
+        }
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PrefetchState, PrefetchStats};
+    use crate::selectors::Selector;
+    use crate::{Block, Node};
+    use cid::Cid;
+    use multihash::Sha2_256;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn background_waits_for_the_foreground() {
+        let state = PrefetchState::default();
+
+        let fetch = state.foreground();
+        assert!(timeout(Duration::from_millis(50), state.background())
+            .await
+            .is_err());
+
+        drop(fetch);
+        timeout(Duration::from_millis(50), state.background())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn prefetches_local_blocks() {
+        let ipfs = Node::new("test_node").await;
+
+        let data = b"hello block\n".to_vec().into_boxed_slice();
+        let cid = Cid::new_v1(cid::Codec::Raw, Sha2_256::digest(&data));
+        ipfs.put_block(Block::new(data, cid.clone())).await.unwrap();
+
+        let stats = ipfs.prefetch(cid.clone()).finished().await.unwrap();
+        assert_eq!(
+            stats,
+            PrefetchStats {
+                blocks: 1,
+                failed: 0
+            }
+        );
+
+        let root = ipfs.put_dag(make_ipld!({ "next": cid })).await.unwrap();
+        let stats = ipfs
+            .prefetch(super::Prefetch::Selected {
+                root,
+                selector: Selector::all(),
+            })
+            .finished()
+            .await
+            .unwrap();
+        assert_eq!(stats.blocks, 2);
+    }
+}
@@ -250,7 +250,7 @@ where
     }
 }
 
-fn ipld_links(
+pub(crate) fn ipld_links(
     cid: &Cid,
     ipld: Ipld,
 ) -> impl Iterator<Item = (Option<String>, Cid)> + Send + 'static {
@@ -0,0 +1,242 @@
+//! Per block access statistics, tracked with the `block_access_stats` feature.
+//!
+//! Every read of a block counts as an access, and both the reads and the writes update the time
+//! of the latest access. Writing the statistics on every read would turn each read into a write,
+//! so the accesses are collected in memory and merged into the [`Column::Access`] of the
+//! datastore in batches, once a minute or once enough blocks have been accessed.
+
+use super::{Batch, Column, DataStore};
+use crate::error::Error;
+use cid::Cid;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long the accesses are collected in memory at most.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many accessed blocks are collected in memory at most.
+const FLUSH_THRESHOLD: usize = 10_000;
+
+/// The access statistics of a stored block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockAccess {
+    pub cid: Cid,
+    /// Number of the reads since the statistics started to be tracked.
+    pub reads: u64,
+    /// Time of the latest read or write, rounded down to seconds. `None` if the block has not
+    /// been accessed since the statistics started to be tracked.
+    pub last_access: Option<SystemTime>,
+}
+
+/// The stored statistics of a block, keyed by the multihash so that the Cid versions are not
+/// tracked separately.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Counts {
+    reads: u64,
+    /// Seconds since the unix epoch.
+    last_access: u64,
+}
+
+impl Counts {
+    fn merge(self, other: Counts) -> Counts {
+        Counts {
+            reads: self.reads.saturating_add(other.reads),
+            last_access: self.last_access.max(other.last_access),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.reads.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.last_access.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Counts> {
+        if bytes.len() != 16 {
+            return None;
+        }
+
+        Some(Counts {
+            reads: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            last_access: u64::from_be_bytes(bytes[8..].try_into().unwrap()),
+        })
+    }
+
+    pub(crate) fn into_access(self, cid: Cid) -> BlockAccess {
+        BlockAccess {
+            cid,
+            reads: self.reads,
+            last_access: Some(self.last_access)
+                .filter(|secs| *secs > 0)
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+}
+
+/// Collects the accesses in memory until they are flushed into the datastore.
+#[derive(Debug)]
+pub(crate) struct AccessTracker {
+    pending: Mutex<Pending>,
+    /// Held over a flush so that concurrent flushes do not overwrite each other's merges.
+    flushing: tokio::sync::Mutex<()>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    counts: HashMap<Vec<u8>, Counts>,
+    since: Instant,
+}
+
+impl Default for AccessTracker {
+    fn default() -> Self {
+        AccessTracker {
+            pending: Mutex::new(Pending {
+                counts: HashMap::new(),
+                since: Instant::now(),
+            }),
+            flushing: Default::default(),
+        }
+    }
+}
+
+impl AccessTracker {
+    /// Records a read or a write of the block. Returns true if the collected accesses should be
+    /// flushed.
+    pub(crate) fn record(&self, cid: &Cid, read: bool) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+
+        let access = Counts {
+            reads: read as u64,
+            last_access: now,
+        };
+
+        let mut pending = self.pending.lock().unwrap();
+        let counts = pending.counts.entry(key(cid)).or_default();
+        *counts = counts.merge(access);
+
+        pending.counts.len() >= FLUSH_THRESHOLD || pending.since.elapsed() >= FLUSH_INTERVAL
+    }
+
+    /// Forgets the accesses of a removed block, returning the key of its stored statistics.
+    pub(crate) fn forget(&self, cid: &Cid) -> Vec<u8> {
+        let key = key(cid);
+        self.pending.lock().unwrap().counts.remove(&key);
+        key
+    }
+
+    /// Returns the statistics of the block, including the accesses not yet flushed.
+    pub(crate) async fn get<D: DataStore>(
+        &self,
+        data_store: &D,
+        cid: &Cid,
+    ) -> Result<Counts, Error> {
+        let key = key(cid);
+        let stored = self.stored(data_store, &key).await?;
+        let pending = self.pending.lock().unwrap().counts.get(&key).copied();
+        Ok(stored.merge(pending.unwrap_or_default()))
+    }
+
+    /// Returns the stored statistics of all of the accessed blocks by their multihashes.
+    pub(crate) async fn all<D: DataStore>(
+        &self,
+        data_store: &D,
+    ) -> Result<HashMap<Vec<u8>, Counts>, Error> {
+        self.flush(data_store).await?;
+
+        Ok(data_store
+            .scan(Column::Access, &super::KeyRange::all())
+            .await?
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, Counts::from_bytes(&value)?)))
+            .collect())
+    }
+
+    /// Merges the collected accesses into the datastore. The collected accesses are dropped if
+    /// the datastore fails.
+    pub(crate) async fn flush<D: DataStore>(&self, data_store: &D) -> Result<(), Error> {
+        let _flushing = self.flushing.lock().await;
+
+        let counts = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.since = Instant::now();
+            std::mem::take(&mut pending.counts)
+        };
+
+        if counts.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = Batch::default();
+
+        for (key, counts) in counts {
+            let stored = self.stored(data_store, &key).await?;
+            batch.put(Column::Access, &key, &stored.merge(counts).to_bytes());
+        }
+
+        data_store.write_batch(batch).await
+    }
+
+    async fn stored<D: DataStore>(&self, data_store: &D, key: &[u8]) -> Result<Counts, Error> {
+        Ok(data_store
+            .get(Column::Access, key)
+            .await?
+            .and_then(|bytes| Counts::from_bytes(&bytes))
+            .unwrap_or_default())
+    }
+}
+
+/// The key of the statistics of the block, the multihash of the Cid.
+pub(crate) fn key(cid: &Cid) -> Vec<u8> {
+    cid.hash().as_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessTracker, Counts};
+    use crate::repo::{mem::MemDataStore, DataStore};
+    use cid::{Cid, Codec};
+    use multihash::Sha2_256;
+    use std::path::PathBuf;
+
+    #[test]
+    fn counts_roundtrip() {
+        let counts = Counts {
+            reads: 42,
+            last_access: 1_600_000_000,
+        };
+        assert_eq!(Counts::from_bytes(&counts.to_bytes()), Some(counts));
+        assert_eq!(Counts::from_bytes(&[0; 8]), None);
+    }
+
+    #[tokio::test]
+    async fn accesses_are_merged_on_flush() {
+        let data_store = MemDataStore::new(PathBuf::new());
+        let tracker = AccessTracker::default();
+
+        let v1 = Cid::new_v1(Codec::DagProtobuf, Sha2_256::digest(b"block"));
+        let v0 = Cid::new_v0(Sha2_256::digest(b"block")).unwrap();
+
+        tracker.record(&v1, false);
+        tracker.record(&v1, true);
+        tracker.flush(&data_store).await.unwrap();
+
+        // both versions of the cid are the same block
+        tracker.record(&v0, true);
+
+        let counts = tracker.get(&data_store, &v1).await.unwrap();
+        assert_eq!(counts.reads, 2);
+        assert!(counts.into_access(v1.clone()).last_access.is_some());
+
+        tracker.flush(&data_store).await.unwrap();
+
+        let all = tracker.all(&data_store).await.unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[&super::key(&v0)].reads, 2);
+    }
+}
@@ -0,0 +1,265 @@
+//! Selecting the blockstore of the repo at runtime, see [`BlockBackend`] and [`DynBlockStore`].
+
+use super::{BlockPut, BlockRm, BlockRmError, BlockStorage, BlockStore, Rebalance};
+use crate::error::Error;
+use crate::Block;
+use async_trait::async_trait;
+use cid::Cid;
+use futures::stream::BoxStream;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// The blockstore of the repo, for the [`BlockStorage::backend`].
+///
+/// Changing the backend of an existing repo does not move the blocks stored by the previous one.
+/// The default is the blockstore enabled by the `sled_block_store`, `rocksdb_block_store` or
+/// `s3_block_store` feature, in that order, or the file per block store without them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockBackend {
+    /// A file per block in the `blockstore` directory of the repo, see
+    /// [`crate::repo::fs::FsBlockStore`].
+    Files,
+    /// A single crash-safe sled database in the `blockstore` directory of the repo, see
+    /// [`crate::repo::kv::KvBlockStore`].
+    Sled,
+    /// A rocksdb database in the `blockstore` directory of the repo, for the repos with tens of
+    /// millions of blocks. Requires the `rocksdb_block_store` feature.
+    RocksDb,
+    /// The bucket of the [`BlockStorage::object_storage`]. Requires the `s3_block_store`
+    /// feature.
+    ObjectStorage,
+}
+
+impl Default for BlockBackend {
+    fn default() -> Self {
+        if cfg!(feature = "sled_block_store") {
+            BlockBackend::Sled
+        } else if cfg!(feature = "rocksdb_block_store") {
+            BlockBackend::RocksDb
+        } else if cfg!(feature = "s3_block_store") {
+            BlockBackend::ObjectStorage
+        } else {
+            BlockBackend::Files
+        }
+    }
+}
+
+impl BlockBackend {
+    fn as_str(self) -> &'static str {
+        match self {
+            BlockBackend::Files => "flatfs",
+            BlockBackend::Sled => "sled",
+            BlockBackend::RocksDb => "rocksdb",
+            BlockBackend::ObjectStorage => "s3",
+        }
+    }
+}
+
+impl fmt::Display for BlockBackend {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+/// The name given to [`BlockBackend::from_str`] is not one of the blockstores.
+#[derive(Debug, thiserror::Error)]
+#[error("unknown blockstore {0:?}, expected one of flatfs, sled, rocksdb or s3")]
+pub struct UnknownBlockBackend(String);
+
+impl FromStr for BlockBackend {
+    type Err = UnknownBlockBackend;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "flatfs" => BlockBackend::Files,
+            "sled" => BlockBackend::Sled,
+            "rocksdb" => BlockBackend::RocksDb,
+            "s3" => BlockBackend::ObjectStorage,
+            other => return Err(UnknownBlockBackend(other.to_owned())),
+        })
+    }
+}
+
+/// Blockstore delegating to the one selected by the [`BlockStorage::backend`] when it is
+/// configured, used by the [`crate::Types`]. Without the configuration the blocks are stored by
+/// the default [`BlockBackend`].
+///
+/// Selecting a backend which was not compiled in fails the [`BlockStore::init`] and
+/// [`BlockStore::open`] of the repo.
+#[derive(Debug)]
+pub struct DynBlockStore {
+    path: PathBuf,
+    backend: BlockBackend,
+    /// `None` when the backend was not compiled in.
+    inner: Option<Box<dyn BlockStore>>,
+}
+
+impl DynBlockStore {
+    fn create(backend: BlockBackend, path: PathBuf) -> Option<Box<dyn BlockStore>> {
+        let store: Box<dyn BlockStore> = match backend {
+            BlockBackend::Files => Box::new(super::fs::FsBlockStore::new(path)),
+            BlockBackend::Sled => Box::new(super::kv::KvBlockStore::new(path)),
+            #[cfg(feature = "rocksdb_block_store")]
+            BlockBackend::RocksDb => Box::new(super::rocks::RocksBlockStore::new(path)),
+            #[cfg(feature = "s3_block_store")]
+            BlockBackend::ObjectStorage => Box::new(super::s3::S3BlockStore::new(path)),
+            #[allow(unreachable_patterns)]
+            _ => return None,
+        };
+        Some(store)
+    }
+
+    /// The backend the blocks are stored by.
+    pub fn backend(&self) -> BlockBackend {
+        self.backend
+    }
+
+    fn inner(&self) -> Result<&dyn BlockStore, Error> {
+        self.inner.as_deref().ok_or_else(|| {
+            anyhow::anyhow!(
+                "the {} blockstore is not supported by this build",
+                self.backend
+            )
+        })
+    }
+}
+
+#[async_trait]
+impl BlockStore for DynBlockStore {
+    fn new(path: PathBuf) -> Self {
+        let backend = BlockBackend::default();
+        DynBlockStore {
+            inner: Self::create(backend, path.clone()),
+            path,
+            backend,
+        }
+    }
+
+    fn configure(&mut self, storage: &BlockStorage) {
+        if storage.backend != self.backend {
+            self.backend = storage.backend;
+            self.inner = Self::create(storage.backend, self.path.clone());
+        }
+
+        if let Some(inner) = self.inner.as_mut() {
+            inner.configure(storage);
+        }
+    }
+
+    async fn init(&self) -> Result<(), Error> {
+        self.inner()?.init().await
+    }
+
+    async fn open(&self) -> Result<(), Error> {
+        self.inner()?.open().await
+    }
+
+    async fn contains(&self, cid: &Cid) -> Result<bool, Error> {
+        self.inner()?.contains(cid).await
+    }
+
+    async fn get(&self, cid: &Cid) -> Result<Option<Block>, Error> {
+        self.inner()?.get(cid).await
+    }
+
+    async fn put(&self, block: Block) -> Result<(Cid, BlockPut), Error> {
+        self.inner()?.put(block).await
+    }
+
+    async fn remove(&self, cid: &Cid) -> Result<Result<BlockRm, BlockRmError>, Error> {
+        self.inner()?.remove(cid).await
+    }
+
+    async fn list(&self) -> Result<Vec<Cid>, Error> {
+        self.inner()?.list().await
+    }
+
+    async fn iter(&self) -> Result<BoxStream<'static, Result<(Cid, u64), Error>>, Error> {
+        self.inner()?.iter().await
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, Error> {
+        self.inner()?.get_many(cids).await
+    }
+
+    async fn rebalance(&self) -> Result<Rebalance, Error> {
+        self.inner()?.rebalance().await
+    }
+
+    async fn wipe(&self) {
+        if let Some(inner) = self.inner.as_deref() {
+            inner.wipe().await
+        }
+    }
+}
+
+#[cfg(test)]
+crate::blockstore_interface_tests!(
+    blockstore_common_tests,
+    |path| {
+        let mut store = crate::repo::backend::DynBlockStore::new(path);
+        store.configure(&crate::repo::BlockStorage {
+            backend: crate::repo::BlockBackend::Sled,
+            ..Default::default()
+        });
+        store
+    },
+    persistent
+);
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockBackend, DynBlockStore};
+    use crate::repo::{BlockStorage, BlockStore};
+
+    #[test]
+    fn backend_names() {
+        for backend in &[
+            BlockBackend::Files,
+            BlockBackend::Sled,
+            BlockBackend::RocksDb,
+            BlockBackend::ObjectStorage,
+        ] {
+            assert_eq!(
+                backend.to_string().parse::<BlockBackend>().unwrap(),
+                *backend
+            );
+        }
+        assert!("badger".parse::<BlockBackend>().is_err());
+    }
+
+    #[tokio::test]
+    async fn backend_is_selected_by_the_configuration() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let mut store = DynBlockStore::new(tmp.path().to_owned());
+        assert_eq!(store.backend(), BlockBackend::default());
+
+        store.configure(&BlockStorage {
+            backend: BlockBackend::Sled,
+            ..Default::default()
+        });
+        assert_eq!(store.backend(), BlockBackend::Sled);
+        store.init().await.unwrap();
+        assert!(format!("{:?}", store).contains("KvBlockStore"));
+    }
+
+    #[cfg(not(feature = "rocksdb_block_store"))]
+    #[tokio::test]
+    async fn missing_backend_fails_init() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let mut store = DynBlockStore::new(tmp.path().to_owned());
+        store.configure(&BlockStorage {
+            backend: BlockBackend::RocksDb,
+            ..Default::default()
+        });
+
+        let e = store.init().await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the rocksdb blockstore is not supported by this build"
+        );
+    }
+}
@@ -0,0 +1,630 @@
+//! Keeping the recently and frequently used blocks in memory in front of the blockstore, see
+//! [`BlockCache`].
+//!
+//! The blocks are cached with the 2Q replacement: a block read for the first time is only kept
+//! for a while in a first-in-first-out queue, and only the blocks read again after having been
+//! evicted from it make it to the least recently used queue holding the most of the cache. A
+//! walk over a large DAG, or a peer requesting many blocks once, then does not evict the blocks
+//! read over and over again, like the roots of the popular content.
+//!
+//! The lookups of the blocks not in the blockstore, common when serving the wants of the peers,
+//! are answered by a bloom filter of the blocks in the blockstore without reading the
+//! blockstore, except for the about 1% false positives. The filter is built by listing the
+//! blockstore when it is opened. The removed blocks are not removed from the filter, so the
+//! false positives only grow until the filter is built again, which happens once the
+//! blockstore has grown to twice its size since the filter was last built.
+
+use super::{BlockPut, BlockRm, BlockRmError, BlockStorage, BlockStore, Rebalance};
+use crate::error::Error;
+use crate::p2p::sync::BloomFilter;
+use crate::Block;
+use async_trait::async_trait;
+use cid::Cid;
+use futures::stream::{BoxStream, TryStreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::RwLock;
+
+/// The smallest number of blocks the bloom filter is built for.
+const MIN_FILTER_CAPACITY: usize = 64 * 1024;
+
+/// The percentage of the cache for the blocks read once, as suggested for the 2Q.
+const FIRST_READ_PERCENT: u64 = 25;
+
+/// The in-memory caching of the blocks in front of the blockstore of the repo, for the
+/// [`BlockStorage::cache`]. See the [module documentation](crate::repo::cache).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockCache {
+    /// The most bytes of the blocks kept in memory, zero for none.
+    pub capacity: u64,
+    /// Answer the lookups of the missing blocks from a bloom filter of the blocks, taking about
+    /// 10 bits of memory for each block in the blockstore.
+    pub negative_filter: bool,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        BlockCache {
+            capacity: 64 * 1024 * 1024,
+            negative_filter: true,
+        }
+    }
+}
+
+/// The effectiveness of the [`BlockCache`], see [`crate::Ipfs::block_cache_stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    /// The lookups answered from the cached blocks.
+    pub hits: u64,
+    /// The lookups which needed to read the blockstore.
+    pub misses: u64,
+    /// The lookups of the missing blocks answered by the bloom filter.
+    pub filtered: u64,
+    /// Number of the blocks in the cache.
+    pub blocks: usize,
+    /// Total size of the blocks in the cache.
+    pub bytes: u64,
+}
+
+/// Wraps the blockstore of the repo with the [`BlockCache`] configured in the
+/// [`BlockStorage::cache`]. Without the configuration all of the calls go straight to the
+/// wrapped blockstore.
+pub(crate) struct CachedBlockStore<B> {
+    inner: B,
+    cache: Option<Mutex<TwoQueues>>,
+    filter: Option<NegativeFilter>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    filtered: AtomicU64,
+}
+
+impl<B: fmt::Debug> fmt::Debug for CachedBlockStore<B> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("CachedBlockStore")
+            .field("inner", &self.inner)
+            .field("cache", &self.cache.is_some())
+            .field("filter", &self.filter.is_some())
+            .finish()
+    }
+}
+
+impl<B: BlockStore> CachedBlockStore<B> {
+    pub(crate) fn stats(&self) -> BlockCacheStats {
+        let (blocks, bytes) = match &self.cache {
+            Some(cache) => {
+                let cache = cache.lock().unwrap();
+                (cache.len(), cache.size())
+            }
+            None => (0, 0),
+        };
+
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            filtered: self.filtered.load(Ordering::Relaxed),
+            blocks,
+            bytes,
+        }
+    }
+
+    fn lookup(&self, cid: &Cid) -> Lookup {
+        if let Some(cache) = &self.cache {
+            if let Some(data) = cache.lock().unwrap().get(cid.hash().as_bytes()) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Lookup::Cached(Block::new(data, cid.to_owned()));
+            }
+        }
+
+        if let Some(filter) = &self.filter {
+            if !filter.may_contain(cid) {
+                self.filtered.fetch_add(1, Ordering::Relaxed);
+                return Lookup::Missing;
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        Lookup::Uncached
+    }
+
+    fn cache(&self, block: &Block) {
+        if let Some(cache) = &self.cache {
+            cache
+                .lock()
+                .unwrap()
+                .insert(block.cid().hash().as_bytes(), block.data());
+        }
+    }
+
+    async fn build_filter(&self) -> Result<(), Error> {
+        if let Some(filter) = &self.filter {
+            let _writes = filter.writes.write().await;
+            filter.build(&self.inner).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<B: BlockStore> BlockStore for CachedBlockStore<B> {
+    fn new(path: PathBuf) -> Self {
+        CachedBlockStore {
+            inner: B::new(path),
+            cache: None,
+            filter: None,
+            hits: Default::default(),
+            misses: Default::default(),
+            filtered: Default::default(),
+        }
+    }
+
+    fn configure(&mut self, storage: &BlockStorage) {
+        self.inner.configure(storage);
+
+        if let Some(config) = &storage.cache {
+            if config.capacity > 0 {
+                self.cache = Some(Mutex::new(TwoQueues::new(config)));
+            }
+            if config.negative_filter {
+                self.filter = Some(NegativeFilter::default());
+            }
+        }
+    }
+
+    async fn init(&self) -> Result<(), Error> {
+        self.inner.init().await?;
+        self.build_filter().await
+    }
+
+    async fn open(&self) -> Result<(), Error> {
+        self.inner.open().await?;
+        self.build_filter().await
+    }
+
+    async fn contains(&self, cid: &Cid) -> Result<bool, Error> {
+        match self.lookup(cid) {
+            Lookup::Cached(_) => Ok(true),
+            Lookup::Missing => Ok(false),
+            Lookup::Uncached => self.inner.contains(cid).await,
+        }
+    }
+
+    async fn get(&self, cid: &Cid) -> Result<Option<Block>, Error> {
+        match self.lookup(cid) {
+            Lookup::Cached(block) => Ok(Some(block)),
+            Lookup::Missing => Ok(None),
+            Lookup::Uncached => {
+                let block = self.inner.get(cid).await?;
+                if let Some(block) = &block {
+                    self.cache(block);
+                }
+                Ok(block)
+            }
+        }
+    }
+
+    async fn put(&self, block: Block) -> Result<(Cid, BlockPut), Error> {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return self.inner.put(block).await,
+        };
+
+        let (put, rebuild) = {
+            // the block is added to the filter before it can be found in the blockstore, and
+            // the filter is not rebuilt while the block is being written
+            let _writes = filter.writes.read().await;
+            let rebuild = filter.insert(block.cid());
+            (self.inner.put(block).await?, rebuild)
+        };
+
+        if rebuild {
+            let _writes = filter.writes.write().await;
+            // another put could have rebuilt the filter while waiting for the writes to complete
+            if filter.is_full() {
+                filter.build(&self.inner).await?;
+            }
+        }
+
+        Ok(put)
+    }
+
+    async fn remove(&self, cid: &Cid) -> Result<Result<BlockRm, BlockRmError>, Error> {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().remove(cid.hash().as_bytes());
+        }
+        self.inner.remove(cid).await
+    }
+
+    async fn list(&self) -> Result<Vec<Cid>, Error> {
+        self.inner.list().await
+    }
+
+    async fn iter(&self) -> Result<BoxStream<'static, Result<(Cid, u64), Error>>, Error> {
+        self.inner.iter().await
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, Error> {
+        let mut blocks = Vec::with_capacity(cids.len());
+        let mut uncached = Vec::new();
+        let mut uncached_at = Vec::new();
+
+        for (i, cid) in cids.iter().enumerate() {
+            match self.lookup(cid) {
+                Lookup::Cached(block) => blocks.push(Some(block)),
+                Lookup::Missing => blocks.push(None),
+                Lookup::Uncached => {
+                    uncached.push(cid.to_owned());
+                    uncached_at.push(i);
+                    blocks.push(None);
+                }
+            }
+        }
+
+        if uncached.is_empty() {
+            return Ok(blocks);
+        }
+
+        let read = self.inner.get_many(&uncached).await?;
+
+        for (i, block) in uncached_at.into_iter().zip(read) {
+            if let Some(block) = &block {
+                self.cache(block);
+            }
+            blocks[i] = block;
+        }
+
+        Ok(blocks)
+    }
+
+    async fn rebalance(&self) -> Result<Rebalance, Error> {
+        self.inner.rebalance().await
+    }
+
+    async fn wipe(&self) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+        self.inner.wipe().await;
+        if let Some(filter) = &self.filter {
+            filter.clear();
+        }
+    }
+}
+
+enum Lookup {
+    Cached(Block),
+    /// The block is certainly not in the blockstore.
+    Missing,
+    /// The block needs to be looked up from the blockstore.
+    Uncached,
+}
+
+/// A bloom filter of the blocks in the blockstore, sized for twice the blocks found when it was
+/// last built.
+#[derive(Default)]
+struct NegativeFilter {
+    state: Mutex<FilterState>,
+    /// Held for reading by the puts, and for writing while the filter is built.
+    writes: RwLock<()>,
+}
+
+#[derive(Default)]
+struct FilterState {
+    /// `None` until the filter has been built, when all of the blocks may be in the blockstore.
+    filter: Option<BloomFilter>,
+    inserted: usize,
+    capacity: usize,
+}
+
+impl NegativeFilter {
+    fn may_contain(&self, cid: &Cid) -> bool {
+        match &self.state.lock().unwrap().filter {
+            Some(filter) => filter.contains(cid),
+            None => true,
+        }
+    }
+
+    /// Adds the block to the filter, returning true if the filter needs to be built again.
+    fn insert(&self, cid: &Cid) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some(filter) = state.filter.as_mut() {
+            filter.insert(cid);
+            state.inserted += 1;
+        }
+        state.filter.is_some() && state.inserted > state.capacity
+    }
+
+    fn is_full(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.inserted > state.capacity
+    }
+
+    /// Builds the filter from the blocks in the `store`. Needs to be called while holding the
+    /// `writes` for writing, so that no blocks are missed.
+    async fn build<B: BlockStore>(&self, store: &B) -> Result<(), Error> {
+        let cids = store
+            .iter()
+            .await?
+            .map_ok(|(cid, _)| cid)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let capacity = (cids.len() * 2).max(MIN_FILTER_CAPACITY);
+        let mut filter = BloomFilter::with_capacity(capacity);
+        for cid in &cids {
+            filter.insert(cid);
+        }
+
+        debug!(
+            blocks = cids.len(),
+            capacity, "built the negative block filter"
+        );
+
+        *self.state.lock().unwrap() = FilterState {
+            filter: Some(filter),
+            inserted: cids.len(),
+            capacity,
+        };
+
+        Ok(())
+    }
+
+    fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        let capacity = state.capacity.max(MIN_FILTER_CAPACITY);
+        *state = FilterState {
+            filter: Some(BloomFilter::with_capacity(capacity)),
+            inserted: 0,
+            capacity,
+        };
+    }
+}
+
+/// The 2Q replacement of the cached blocks, by the multihash so that the Cid versions match.
+struct TwoQueues {
+    capacity: u64,
+    /// The most bytes of `first_read`.
+    first_read_capacity: u64,
+    /// The blocks read once, evicted the oldest first.
+    first_read: Queue<Box<[u8]>>,
+    /// The sizes of the blocks recently evicted from `first_read`, which are moved to the
+    /// `frequent` when read again.
+    evicted: Queue<()>,
+    /// The blocks read again after having been evicted, evicted the least recently used first.
+    frequent: Queue<Box<[u8]>>,
+    next_use: u64,
+}
+
+impl TwoQueues {
+    fn new(config: &BlockCache) -> Self {
+        TwoQueues {
+            capacity: config.capacity,
+            first_read_capacity: config.capacity / 100 * FIRST_READ_PERCENT,
+            first_read: Default::default(),
+            evicted: Default::default(),
+            frequent: Default::default(),
+            next_use: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.first_read.entries.len() + self.frequent.entries.len()
+    }
+
+    fn size(&self) -> u64 {
+        self.first_read.size + self.frequent.size
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<Box<[u8]>> {
+        let next_use = self.next_use();
+        if let Some(data) = self.frequent.touch(key, next_use) {
+            return Some(data.clone());
+        }
+        // the blocks read once are not reordered, so that a burst of reads does not keep them
+        self.first_read.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: &[u8], data: &[u8]) {
+        let len = data.len() as u64;
+        if len > self.capacity || self.first_read.contains(key) || self.frequent.contains(key) {
+            return;
+        }
+
+        let next_use = self.next_use();
+        if self.evicted.remove(key).is_some() {
+            self.frequent
+                .insert(key.to_vec(), data.into(), len, next_use);
+        } else {
+            self.first_read
+                .insert(key.to_vec(), data.into(), len, next_use);
+        }
+
+        while self.size() > self.capacity {
+            if self.first_read.size > self.first_read_capacity || self.frequent.size == 0 {
+                if let Some((key, _, len)) = self.first_read.pop_oldest() {
+                    let next_use = self.next_use();
+                    self.evicted.insert(key, (), len, next_use);
+                }
+            } else {
+                self.frequent.pop_oldest();
+            }
+        }
+
+        // the evicted blocks are remembered for about as many bytes as the cache holds
+        while self.evicted.size > self.capacity {
+            self.evicted.pop_oldest();
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.first_read.remove(key);
+        self.frequent.remove(key);
+        self.evicted.remove(key);
+    }
+
+    fn clear(&mut self) {
+        self.first_read = Default::default();
+        self.evicted = Default::default();
+        self.frequent = Default::default();
+    }
+
+    fn next_use(&mut self) -> u64 {
+        self.next_use += 1;
+        self.next_use
+    }
+}
+
+/// Values ordered by their insertion or last use, the oldest first.
+struct Queue<V> {
+    entries: HashMap<Vec<u8>, (u64, u64, V)>,
+    order: BTreeMap<u64, Vec<u8>>,
+    size: u64,
+}
+
+impl<V> Default for Queue<V> {
+    fn default() -> Self {
+        Queue {
+            entries: Default::default(),
+            order: Default::default(),
+            size: 0,
+        }
+    }
+}
+
+impl<V> Queue<V> {
+    fn contains(&self, key: &[u8]) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&V> {
+        self.entries.get(key).map(|(_, _, value)| value)
+    }
+
+    /// Moves the value to the end of the queue.
+    fn touch(&mut self, key: &[u8], next_use: u64) -> Option<&V> {
+        let (last_use, _, value) = self.entries.get_mut(key)?;
+        let key = self.order.remove(last_use).expect("ordered entry");
+        self.order.insert(next_use, key);
+        *last_use = next_use;
+        Some(value)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: V, len: u64, next_use: u64) {
+        self.remove(&key);
+        self.order.insert(next_use, key.clone());
+        self.entries.insert(key, (next_use, len, value));
+        self.size += len;
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let (last_use, len, value) = self.entries.remove(key)?;
+        self.order.remove(&last_use);
+        self.size -= len;
+        Some(value)
+    }
+
+    fn pop_oldest(&mut self) -> Option<(Vec<u8>, V, u64)> {
+        let oldest = *self.order.keys().next()?;
+        let key = self.order.remove(&oldest).expect("ordered entry");
+        let (_, len, value) = self.entries.remove(&key).expect("ordered entry");
+        self.size -= len;
+        Some((key, value, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::mem::MemBlockStore;
+    use multihash::Sha2_256;
+
+    fn block(n: u8, len: usize) -> Block {
+        let data = vec![n; len].into_boxed_slice();
+        let cid = Cid::new_v1(cid::Codec::Raw, Sha2_256::digest(&data));
+        Block::new(data, cid)
+    }
+
+    async fn store(cache: BlockCache) -> CachedBlockStore<MemBlockStore> {
+        let mut store = CachedBlockStore::<MemBlockStore>::new(PathBuf::new());
+        store.configure(&BlockStorage {
+            cache: Some(cache),
+            ..Default::default()
+        });
+        store.init().await.unwrap();
+        store
+    }
+
+    #[test]
+    fn frequently_read_blocks_survive_a_scan() {
+        let mut queues = TwoQueues::new(&BlockCache {
+            capacity: 100 * 100,
+            negative_filter: false,
+        });
+
+        // read once, evicted, and read again to become frequent
+        queues.insert(b"hot", &[0; 10]);
+        for i in 0..10u8 {
+            queues.insert(&[i], &[0; 10]);
+        }
+        assert!(queues.get(b"hot").is_none());
+        queues.insert(b"hot", &[0; 10]);
+
+        // a scan of blocks read once only goes through the first read queue
+        for i in 10..100u8 {
+            queues.insert(&[i], &[0; 10]);
+        }
+
+        assert!(queues.get(b"hot").is_some());
+        assert!(queues.size() <= 100 * 100);
+    }
+
+    #[tokio::test]
+    async fn reads_are_cached_and_counted() {
+        let store = store(BlockCache {
+            negative_filter: false,
+            ..Default::default()
+        })
+        .await;
+
+        let stored = block(1, 100);
+        store.put(stored.clone()).await.unwrap();
+
+        assert_eq!(store.get(stored.cid()).await.unwrap(), Some(stored.clone()));
+        assert_eq!(store.get(stored.cid()).await.unwrap(), Some(stored.clone()));
+
+        let stats = store.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!((stats.blocks, stats.bytes), (1, 100));
+
+        store.remove(stored.cid()).await.unwrap().unwrap();
+        assert_eq!(store.get(stored.cid()).await.unwrap(), None);
+        assert_eq!(store.stats().blocks, 0);
+    }
+
+    #[tokio::test]
+    async fn missing_blocks_are_filtered() {
+        let store = store(BlockCache {
+            capacity: 0,
+            ..Default::default()
+        })
+        .await;
+
+        let (stored, missing) = (block(1, 10), block(2, 10));
+        store.put(stored.clone()).await.unwrap();
+
+        assert!(store.contains(stored.cid()).await.unwrap());
+        assert!(!store.contains(missing.cid()).await.unwrap());
+
+        let found = store
+            .get_many(&[missing.cid().to_owned(), stored.cid().to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(found, vec![None, Some(stored)]);
+
+        let stats = store.stats();
+        assert_eq!(stats.filtered, 2);
+        assert_eq!(stats.misses, 2);
+    }
+}
@@ -1,6 +1,6 @@
-///! "Interface" tests for pin store, maybe more later
-use crate::repo::DataStore;
-use std::path::PathBuf;
+///! "Interface" tests for pin store, datastore and blockstore, maybe more later
+use crate::repo::{BlockStore, DataStore};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tempfile::TempDir;
 
@@ -39,6 +39,18 @@ impl<T: DataStore> std::ops::Deref for DSTestContext<T> {
     }
 }
 
+/// Creates the BlockStore in the directory with the factory, and initializes and opens it.
+pub async fn opened_blockstore<T, F>(path: &Path, factory: F) -> T
+where
+    T: BlockStore,
+    F: FnOnce(PathBuf) -> T,
+{
+    let store = factory(path.to_owned());
+    store.init().await.unwrap();
+    store.open().await.unwrap();
+    store
+}
+
 /// Generates the "common interface" tests for PinStore implementations as a given module using a
 /// types factory method. When adding tests, it might be easier to write them against the one
 /// implementation and only then move them here; the compiler errors seem to point at the
@@ -311,6 +323,76 @@ macro_rules! pinstore_interface_tests {
                 assert!(one.is_empty(), "{:?}", one);
             }
 
+            #[tokio::test]
+            async fn indirect_pins_are_listed() {
+                let repo = DSTestContext::with($factory).await;
+
+                let cids = (0u8..5)
+                    .map(|i| {
+                        Cid::new_v1(cid::Codec::DagProtobuf, multihash::Sha2_256::digest(&[i]))
+                    })
+                    .collect::<Vec<_>>();
+                let (first, second, refs) = (&cids[0], &cids[1], &cids[2..]);
+
+                repo.insert_recursive_pin(
+                    first,
+                    futures::stream::iter(vec![Ok(refs[0].clone()), Ok(refs[1].clone())]).boxed(),
+                )
+                .await
+                .unwrap();
+
+                // looking up the indirect pins before listing them
+                assert!(repo.is_pinned(&refs[0]).await.unwrap());
+                assert!(!repo.is_pinned(&refs[2]).await.unwrap());
+
+                repo.insert_recursive_pin(
+                    second,
+                    futures::stream::iter(vec![Ok(refs[1].clone()), Ok(refs[2].clone())]).boxed(),
+                )
+                .await
+                .unwrap();
+
+                async fn indirect<T: PinStore>(repo: &T) -> Vec<Cid> {
+                    let mut listed = repo
+                        .list(Some(PinMode::Indirect))
+                        .await
+                        .map_ok(|(cid, mode)| {
+                            assert_eq!(mode, PinMode::Indirect);
+                            cid
+                        })
+                        .try_collect::<Vec<_>>()
+                        .await
+                        .unwrap();
+                    listed.sort_by_key(|cid| cid.to_bytes());
+                    listed
+                }
+
+                let mut expected = refs.to_vec();
+                expected.sort_by_key(|cid| cid.to_bytes());
+                assert_eq!(indirect(&*repo).await, expected);
+
+                let found = repo
+                    .query(vec![refs[2].clone()], Some(PinMode::Indirect))
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    found,
+                    vec![(refs[2].clone(), PinKind::IndirectFrom(second.clone()))]
+                );
+
+                repo.remove_recursive_pin(
+                    second,
+                    futures::stream::iter(vec![Ok(refs[1].clone()), Ok(refs[2].clone())]).boxed(),
+                )
+                .await
+                .unwrap();
+
+                let mut expected = refs[..2].to_vec();
+                expected.sort_by_key(|cid| cid.to_bytes());
+                assert_eq!(indirect(&*repo).await, expected);
+                assert!(!repo.is_pinned(&refs[2]).await.unwrap());
+            }
+
             #[tokio::test]
             async fn cannot_pin_recursively_pinned_directly() {
                 // this is a bit of odd as other ops are additive
@@ -332,3 +414,252 @@ macro_rules! pinstore_interface_tests {
         }
     };
 }
+
+/// Generates the "common interface" tests for the column operations of DataStore
+/// implementations, similar to [`pinstore_interface_tests`].
+#[macro_export]
+macro_rules! datastore_interface_tests {
+    ($module_name:ident, $factory:expr) => {
+        #[cfg(test)]
+        mod $module_name {
+
+            use crate::repo::common_tests::DSTestContext;
+            use crate::repo::{Batch, Column, DataStore, KeyRange};
+
+            async fn populated<T: DataStore>(repo: &T) {
+                for key in &["a/1", "a/2", "a/3", "b/1", "ab"] {
+                    repo.put(Column::Ipns, key.as_bytes(), key.as_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+
+            fn keys(found: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<String> {
+                found
+                    .into_iter()
+                    .map(|(k, v)| {
+                        assert_eq!(k, v);
+                        String::from_utf8(k).unwrap()
+                    })
+                    .collect()
+            }
+
+            #[tokio::test]
+            async fn columns_are_namespaced() {
+                let repo = DSTestContext::with($factory).await;
+
+                repo.put(Column::Ipns, b"key", b"ipns").await.unwrap();
+                assert!(!repo.contains(Column::Providers, b"key").await.unwrap());
+
+                repo.put(Column::Providers, b"key", b"providers")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    repo.get(Column::Ipns, b"key").await.unwrap().as_deref(),
+                    Some(&b"ipns"[..])
+                );
+
+                repo.remove(Column::Ipns, b"key").await.unwrap();
+                assert_eq!(repo.get(Column::Ipns, b"key").await.unwrap(), None);
+                assert!(repo.contains(Column::Providers, b"key").await.unwrap());
+            }
+
+            #[tokio::test]
+            async fn prefix_scan_is_ordered() {
+                let repo = DSTestContext::with($factory).await;
+                populated(&*repo).await;
+
+                let found = repo
+                    .scan(Column::Ipns, &KeyRange::prefix("a/"))
+                    .await
+                    .unwrap();
+                assert_eq!(keys(found), &["a/1", "a/2", "a/3"]);
+
+                let found = repo.scan(Column::Ipns, &KeyRange::all()).await.unwrap();
+                assert_eq!(keys(found), &["a/1", "a/2", "a/3", "ab", "b/1"]);
+
+                let found = repo
+                    .scan(Column::Providers, &KeyRange::all())
+                    .await
+                    .unwrap();
+                assert!(found.is_empty());
+            }
+
+            #[tokio::test]
+            async fn range_scan_with_limit() {
+                let repo = DSTestContext::with($factory).await;
+                populated(&*repo).await;
+
+                let range = KeyRange::prefix("a/").after("a/1").limit(1);
+                let found = repo.scan(Column::Ipns, &range).await.unwrap();
+                assert_eq!(keys(found), &["a/2"]);
+
+                let range = KeyRange::all().from("a/3").until("b/1");
+                let found = repo.scan(Column::Ipns, &range).await.unwrap();
+                assert_eq!(keys(found), &["a/3", "ab"]);
+
+                let range = KeyRange::prefix("a/").after("a/3");
+                let found = repo.scan(Column::Ipns, &range).await.unwrap();
+                assert!(found.is_empty());
+            }
+
+            #[tokio::test]
+            async fn batch_over_columns() {
+                let repo = DSTestContext::with($factory).await;
+                populated(&*repo).await;
+
+                let mut batch = Batch::default();
+                batch
+                    .remove(Column::Ipns, b"a/1")
+                    .put(Column::Ipns, b"a/4", b"a/4")
+                    .put(Column::Providers, b"p", b"first")
+                    .put(Column::Providers, b"p", b"second")
+                    .remove(Column::Providers, b"missing");
+                assert_eq!(batch.len(), 5);
+
+                repo.write_batch(batch).await.unwrap();
+
+                let found = repo
+                    .scan(Column::Ipns, &KeyRange::prefix("a/"))
+                    .await
+                    .unwrap();
+                assert_eq!(keys(found), &["a/2", "a/3", "a/4"]);
+                assert_eq!(
+                    repo.get(Column::Providers, b"p").await.unwrap().as_deref(),
+                    Some(&b"second"[..])
+                );
+
+                repo.write_batch(Batch::default()).await.unwrap();
+            }
+        }
+    };
+}
+
+/// Generates the "common interface" tests for BlockStore implementations, similar to
+/// [`pinstore_interface_tests`]. The blockstores keeping the blocks over reopening them in the
+/// same directory are given the `persistent` flag.
+#[macro_export]
+macro_rules! blockstore_interface_tests {
+    (@tests $module_name:ident, $factory:expr, { $($persistent:tt)* }) => {
+        #[cfg(test)]
+        mod $module_name {
+
+            use crate::repo::common_tests::opened_blockstore;
+            use crate::repo::{BlockPut, BlockStore};
+            use crate::Block;
+            use cid::{Cid, Codec};
+            use futures::stream::TryStreamExt;
+            use multihash::Sha2_256;
+
+            fn dag_pb_block(data: &[u8]) -> Block {
+                let cid = Cid::new_v1(Codec::DagProtobuf, Sha2_256::digest(data));
+                Block::new(data.to_vec().into_boxed_slice(), cid)
+            }
+
+            #[tokio::test]
+            async fn put_get_remove() {
+                let tmp = tempfile::tempdir().unwrap();
+                let store = opened_blockstore(tmp.path(), $factory).await;
+                let block = dag_pb_block(b"1");
+                let cid = block.cid.clone();
+
+                assert!(!store.contains(&cid).await.unwrap());
+                assert_eq!(store.get(&cid).await.unwrap(), None);
+                assert!(store.remove(&cid).await.unwrap().is_err());
+
+                assert_eq!(
+                    store.put(block.clone()).await.unwrap().1,
+                    BlockPut::NewBlock
+                );
+                assert_eq!(store.put(block.clone()).await.unwrap().1, BlockPut::Existed);
+
+                assert!(store.contains(&cid).await.unwrap());
+                assert_eq!(store.get(&cid).await.unwrap(), Some(block));
+
+                store.remove(&cid).await.unwrap().unwrap();
+                assert!(!store.contains(&cid).await.unwrap());
+                assert_eq!(store.get(&cid).await.unwrap(), None);
+                assert!(store.list().await.unwrap().is_empty());
+            }
+
+            #[tokio::test]
+            async fn listed_with_sizes() {
+                let tmp = tempfile::tempdir().unwrap();
+                let store = opened_blockstore(tmp.path(), $factory).await;
+
+                let blocks = [dag_pb_block(b"1"), dag_pb_block(b"22")];
+                for block in &blocks {
+                    store.put(block.clone()).await.unwrap();
+                }
+
+                let mut listed = store.list().await.unwrap();
+                listed.sort_by_key(|cid| cid.to_string());
+                let mut expected = blocks.iter().map(|b| b.cid.clone()).collect::<Vec<_>>();
+                expected.sort_by_key(|cid| cid.to_string());
+                assert_eq!(listed, expected);
+
+                let mut sizes = store
+                    .iter()
+                    .await
+                    .unwrap()
+                    .map_ok(|(_, size)| size)
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap();
+                sizes.sort_unstable();
+                assert_eq!(sizes, vec![1, 2]);
+            }
+
+            #[tokio::test]
+            async fn found_by_the_multihash() {
+                let tmp = tempfile::tempdir().unwrap();
+                let store = opened_blockstore(tmp.path(), $factory).await;
+                let block = dag_pb_block(b"1");
+                let v0 = Cid::new_v0(block.cid.hash().to_owned()).unwrap();
+                let missing = dag_pb_block(b"2").cid;
+
+                store.put(block.clone()).await.unwrap();
+                assert!(store.contains(&v0).await.unwrap());
+
+                let found = store
+                    .get_many(&[v0, missing, block.cid.clone()])
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|block| block.map(|b| b.data().to_vec()))
+                    .collect::<Vec<_>>();
+                assert_eq!(found, vec![Some(b"1".to_vec()), None, Some(b"1".to_vec())]);
+            }
+
+            $($persistent)*
+        }
+    };
+    ($module_name:ident, $factory:expr) => {
+        $crate::blockstore_interface_tests!(@tests $module_name, $factory, {});
+    };
+    ($module_name:ident, $factory:expr, persistent) => {
+        $crate::blockstore_interface_tests!(@tests $module_name, $factory, {
+            #[tokio::test]
+            async fn blocks_survive_reopening() {
+                let tmp = tempfile::tempdir().unwrap();
+                let block = dag_pb_block(b"1");
+                let cid = block.cid.clone();
+
+                {
+                    let store = opened_blockstore(tmp.path(), $factory).await;
+                    store.put(block.clone()).await.unwrap();
+                }
+
+                let store = opened_blockstore(tmp.path(), $factory).await;
+                assert_eq!(store.get(&cid).await.unwrap(), Some(block));
+                assert_eq!(store.list().await.unwrap(), vec![cid.clone()]);
+
+                store.remove(&cid).await.unwrap().unwrap();
+                drop(store);
+
+                let store = opened_blockstore(tmp.path(), $factory).await;
+                assert!(!store.contains(&cid).await.unwrap());
+            }
+        });
+    };
+}
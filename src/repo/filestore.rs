@@ -0,0 +1,469 @@
+//! Referencing the contents of the files added with [`AddOptions::nocopy`] instead of copying
+//! them into the blockstore, like the filestore of go-ipfs.
+//!
+//! The files are added with raw leaves, so that the data of each leaf is the range of the file
+//! it was chunked from. Only the range is recorded in [`Column::Filestore`] of the datastore, by
+//! the multihash of the leaf, and the leaf is read back from the file when it is not found in the
+//! blockstore. The leaves read back are re-hashed, so a changed or removed file fails the reads
+//! of its blocks instead of serving the wrong data; [`crate::Ipfs::filestore_verify`] finds such
+//! files ahead of time.
+//!
+//! With the `urlstore` feature, the files can also be HTTP URLs added with
+//! `ipfs::unixfs::add_url`, like the urlstore of go-ipfs. The file is downloaded once to chunk
+//! it, and the leaves are fetched with range requests when read. Reading the leaves of a URL
+//! without the feature fails.
+//!
+//! [`AddOptions::nocopy`]: crate::unixfs::AddOptions::nocopy
+//! [`Column::Filestore`]: super::Column::Filestore
+
+use crate::error::Error;
+use crate::ipld::validate;
+use crate::Block;
+use cid::{Cid, Codec};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// The file the blocks of a [`FilestoreEntry`] are read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileSource {
+    /// The absolute path of a local file.
+    Path(PathBuf),
+    /// An `http` or `https` URL, see the [module documentation](crate::repo::filestore).
+    Url(String),
+}
+
+impl fmt::Display for FileSource {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileSource::Path(path) => write!(fmt, "{}", path.display()),
+            FileSource::Url(url) => fmt.write_str(url),
+        }
+    }
+}
+
+/// A block kept as the range of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilestoreEntry {
+    /// The Cid of the raw leaf.
+    pub cid: Cid,
+    pub source: FileSource,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// The state of a [`FilestoreEntry`] found by [`crate::Ipfs::filestore_verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilestoreStatus {
+    /// The range of the file still hashes to the Cid.
+    Ok,
+    /// The file has been removed.
+    FileNotFound,
+    /// The range of the file no longer hashes to the Cid, or the file has been truncated.
+    FileChanged,
+    /// Reading the file failed otherwise.
+    FileError(String),
+}
+
+/// A [`FilestoreEntry`] checked by [`crate::Ipfs::filestore_verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilestoreCheck {
+    pub entry: FilestoreEntry,
+    pub status: FilestoreStatus,
+}
+
+impl FilestoreEntry {
+    /// Returns the key of the entry in the datastore, the multihash of the Cid, so that both of
+    /// the Cid versions are found.
+    pub(crate) fn key(cid: &Cid) -> &[u8] {
+        cid.hash().as_bytes()
+    }
+
+    /// Encodes the entry as the big endian offset and length followed by the path or the URL,
+    /// which are told apart by the scheme of the URL, as the paths are absolute.
+    pub(crate) fn to_value(&self) -> Result<Vec<u8>, Error> {
+        let path = match &self.source {
+            FileSource::Path(path) => path
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!("invalid utf-8 in path {}", path.display()))?,
+            FileSource::Url(url) => url.as_str(),
+        };
+
+        let mut value = Vec::with_capacity(16 + path.len());
+        value.extend_from_slice(&self.offset.to_be_bytes());
+        value.extend_from_slice(&self.len.to_be_bytes());
+        value.extend_from_slice(path.as_bytes());
+        Ok(value)
+    }
+
+    /// Decodes the entry stored under the `key`.
+    pub(crate) fn from_value(key: &[u8], value: &[u8]) -> Result<Self, Error> {
+        let multihash = multihash::Multihash::from_bytes(key.to_vec())?;
+
+        if value.len() < 16 {
+            return Err(anyhow::anyhow!("truncated filestore entry"));
+        }
+        let offset = u64::from_be_bytes(<[u8; 8]>::try_from(&value[..8]).unwrap());
+        let len = u64::from_be_bytes(<[u8; 8]>::try_from(&value[8..16]).unwrap());
+        let path = std::str::from_utf8(&value[16..])?;
+
+        let source = if is_url(path) {
+            FileSource::Url(path.to_owned())
+        } else {
+            FileSource::Path(PathBuf::from(path))
+        };
+
+        Ok(FilestoreEntry {
+            cid: Cid::new_v1(Codec::Raw, multihash),
+            source,
+            offset,
+            len,
+        })
+    }
+
+    /// Reads the block back from the file, failing if it no longer hashes to the Cid.
+    pub(crate) async fn read(&self, cid: &Cid) -> Result<Block, Error> {
+        let data = read_range(&self.source, self.offset, self.len)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read {}: {}", self.source, e))?;
+
+        validate(cid, &data).map_err(|_| {
+            anyhow::anyhow!(
+                "{} has changed, the block {} is no longer available",
+                self.source,
+                cid
+            )
+        })?;
+
+        Ok(Block::new(data.into_boxed_slice(), cid.to_owned()))
+    }
+
+    /// Checks whether the block can still be read back from the file.
+    pub(crate) async fn check(self) -> FilestoreCheck {
+        let status = match read_range(&self.source, self.offset, self.len).await {
+            Ok(data) if validate(&self.cid, &data).is_ok() => FilestoreStatus::Ok,
+            Ok(_) => FilestoreStatus::FileChanged,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => FilestoreStatus::FileNotFound,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => FilestoreStatus::FileChanged,
+            Err(e) => FilestoreStatus::FileError(e.to_string()),
+        };
+
+        FilestoreCheck {
+            entry: self,
+            status,
+        }
+    }
+}
+
+pub(crate) fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+async fn read_range(source: &FileSource, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= crate::ipld::MAX_BLOCK_SIZE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "too large block"))?;
+
+    match source {
+        FileSource::Path(path) => read_file_range(path, offset, len).await,
+        FileSource::Url(url) => read_url_range(url, offset, len).await,
+    }
+}
+
+async fn read_file_range(path: &Path, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut data = vec![0u8; len];
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+#[cfg(not(feature = "urlstore"))]
+async fn read_url_range(_url: &str, _offset: u64, _len: usize) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "reading the blocks of URLs requires the urlstore feature",
+    ))
+}
+
+#[cfg(feature = "urlstore")]
+async fn read_url_range(url: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    use reqwest::StatusCode;
+
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+
+    let other = |e| io::Error::new(io::ErrorKind::Other, e);
+
+    let mut response = http_client()
+        .map_err(other)?
+        .get(url)
+        .header(
+            reqwest::header::RANGE,
+            format!("bytes={}-{}", offset, offset + len as u64 - 1),
+        )
+        .send()
+        .await
+        .map_err(other)?;
+
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => {}
+        StatusCode::NOT_FOUND | StatusCode::GONE => {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        // the whole file would need to be downloaded for every block
+        StatusCode::OK => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the server does not support range requests",
+            ))
+        }
+        // the file has been truncated before the range
+        StatusCode::RANGE_NOT_SATISFIABLE => return Err(io::ErrorKind::UnexpectedEof.into()),
+        status => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("the server responded with {}", status),
+            ))
+        }
+    }
+
+    let mut data = Vec::with_capacity(len);
+    while let Some(chunk) = response.chunk().await.map_err(other)? {
+        data.extend_from_slice(&chunk);
+        if data.len() > len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the server sent more than the range",
+            ));
+        }
+    }
+
+    if data.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(data)
+}
+
+/// The client shared by the reads of the blocks of the URLs.
+#[cfg(feature = "urlstore")]
+pub(crate) fn http_client() -> Result<&'static reqwest::Client, reqwest::Error> {
+    static CLIENT: once_cell::sync::OnceCell<reqwest::Client> = once_cell::sync::OnceCell::new();
+
+    CLIENT.get_or_try_init(|| {
+        reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(60))
+            .build()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileSource, FilestoreEntry, FilestoreStatus};
+    use crate::unixfs::{add_path, AddOptions};
+    use crate::Node;
+    use cid::Cid;
+    use futures::stream::TryStreamExt;
+    use std::fs;
+
+    #[tokio::test]
+    async fn nocopy_file_is_read_back_and_verified() {
+        let ipfs = Node::new("test_node").await;
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data.bin");
+
+        // a few chunks of the default 256 KiB chunker
+        let contents = (0..600 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&path, &contents).unwrap();
+
+        let opts = AddOptions {
+            nocopy: true,
+            ..Default::default()
+        };
+        let added = add_path(&ipfs, &path, &opts).await.unwrap();
+        let root = added.last().unwrap().cid.clone();
+
+        // only the root is in the blockstore
+        assert_eq!(ipfs.repo.list_blocks().await.unwrap(), vec![root.clone()]);
+
+        let read: Vec<u8> = ipfs
+            .cat_unixfs(root.clone(), None)
+            .await
+            .unwrap()
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(read, contents);
+
+        let checks = ipfs.filestore_verify().await.unwrap();
+        assert_eq!(checks.len(), 3);
+        assert!(checks
+            .iter()
+            .all(|check| check.status == FilestoreStatus::Ok));
+
+        // changing the second chunk only affects its block
+        let mut changed = contents.clone();
+        changed[300 * 1024] ^= 0xff;
+        fs::write(&path, &changed).unwrap();
+
+        let checks = ipfs.filestore_verify().await.unwrap();
+        let statuses = checks
+            .iter()
+            .filter(|check| check.status != FilestoreStatus::Ok)
+            .map(|check| (check.entry.offset, check.status.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, vec![(256 * 1024, FilestoreStatus::FileChanged)]);
+
+        fs::remove_file(&path).unwrap();
+        let checks = ipfs.filestore_verify().await.unwrap();
+        assert!(checks
+            .iter()
+            .all(|check| check.status == FilestoreStatus::FileNotFound));
+    }
+
+    #[tokio::test]
+    async fn unpinned_nocopy_blocks_are_collected() {
+        let ipfs = Node::new("test_node").await;
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data.bin");
+
+        let contents = (0..600 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&path, &contents).unwrap();
+
+        let opts = AddOptions {
+            nocopy: true,
+            ..Default::default()
+        };
+        let added = add_path(&ipfs, &path, &opts).await.unwrap();
+        let root = added.last().unwrap().cid.clone();
+        let leaves = ipfs.repo.list_filestore_blocks().await.unwrap();
+        assert_eq!(leaves.len(), 3);
+
+        // the pinned blocks are kept
+        ipfs.insert_pin(&root, true).await.unwrap();
+        assert!(ipfs.gc().await.unwrap().is_empty());
+        assert_eq!(ipfs.filestore_verify().await.unwrap().len(), 3);
+
+        ipfs.remove_pin(&root, true).await.unwrap();
+        let removed = ipfs.gc().await.unwrap();
+        for cid in leaves.iter().chain(Some(&root)) {
+            assert!(removed.contains(cid));
+        }
+
+        assert!(ipfs.repo.list_filestore_blocks().await.unwrap().is_empty());
+        assert!(ipfs.filestore_verify().await.unwrap().is_empty());
+        assert_eq!(fs::read(&path).unwrap(), contents);
+    }
+
+    #[tokio::test]
+    async fn nocopy_blocks_on_the_default_types() {
+        use crate::{Block, IpfsOptions, Types, UninitializedIpfs};
+        use multihash::Sha2_256;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let options = IpfsOptions {
+            ipfs_path: tmp.path().join("repo"),
+            ..IpfsOptions::inmemory_with_generated_keys()
+        };
+        let (ipfs, task) = UninitializedIpfs::<Types>::new(options)
+            .start()
+            .await
+            .unwrap();
+        tokio::spawn(task);
+
+        // a raw block found in neither the blockstore nor the filestore
+        let data = b"missing".to_vec().into_boxed_slice();
+        let missing = Cid::new_v1(cid::Codec::Raw, Sha2_256::digest(&data));
+        assert!(ipfs.repo.get_block_now(&missing).await.unwrap().is_none());
+        assert_eq!(
+            ipfs.repo
+                .remove_block(&missing)
+                .await
+                .unwrap_err()
+                .to_string(),
+            "block not found"
+        );
+
+        ipfs.put_block(Block::new(data, missing.clone()))
+            .await
+            .unwrap();
+        assert!(ipfs.repo.get_block_now(&missing).await.unwrap().is_some());
+
+        let path = tmp.path().join("data.bin");
+        let contents = (0..300 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&path, &contents).unwrap();
+
+        let opts = AddOptions {
+            nocopy: true,
+            ..Default::default()
+        };
+        let added = add_path(&ipfs, &path, &opts).await.unwrap();
+        let root = added.last().unwrap().cid.clone();
+
+        let read: Vec<u8> = ipfs
+            .cat_unixfs(root, None)
+            .await
+            .unwrap()
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(read, contents);
+
+        // the leaves are removed from the filestore, leaving the file alone
+        let checks = ipfs.filestore_verify().await.unwrap();
+        assert_eq!(checks.len(), 2);
+        let leaf = checks[0].entry.cid.clone();
+        ipfs.repo.remove_block(&leaf).await.unwrap();
+        assert!(ipfs.repo.get_block_now(&leaf).await.unwrap().is_none());
+        assert!(path.exists());
+
+        ipfs.exit_daemon().await;
+    }
+
+    #[test]
+    fn entry_roundtrip() {
+        let cid = "bafkreigh2akiscaildcqabsyg3dfr6chu3fgpregiymsck7e7aqa4s52zy"
+            .parse()
+            .unwrap();
+
+        let sources = vec![
+            FileSource::Path("/data/file.bin".into()),
+            FileSource::Url("https://example.com/data/file.bin".into()),
+        ];
+
+        for source in sources {
+            let entry = FilestoreEntry {
+                cid: Cid::clone(&cid),
+                source,
+                offset: 262_144,
+                len: 1024,
+            };
+
+            let value = entry.to_value().unwrap();
+            let key = FilestoreEntry::key(&entry.cid);
+            assert_eq!(FilestoreEntry::from_value(key, &value).unwrap(), entry);
+        }
+    }
+
+    #[cfg(not(feature = "urlstore"))]
+    #[tokio::test]
+    async fn url_blocks_need_the_feature() {
+        let entry = FilestoreEntry {
+            cid: "bafkreigh2akiscaildcqabsyg3dfr6chu3fgpregiymsck7e7aqa4s52zy"
+                .parse()
+                .unwrap(),
+            source: FileSource::Url("https://example.com/file.bin".into()),
+            offset: 0,
+            len: 1024,
+        };
+
+        let check = entry.check().await;
+        assert!(matches!(check.status, FilestoreStatus::FileError(_)));
+    }
+}
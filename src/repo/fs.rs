@@ -1,6 +1,6 @@
 //! Persistent fs backed repo.
 //!
-//! Consists of [`FsDataStore`] and [`FsBlockStore`].
+//! Consists of [`FsDataStore`], [`FsBlockStore`] and [`FsJournal`].
 
 use crate::error::Error;
 use async_trait::async_trait;
@@ -9,82 +9,134 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Semaphore;
 
-use super::{BlockRm, BlockRmError, Column, DataStore, Lock, LockError, RepoCid};
+use super::{
+    Batch, BlockRm, BlockRmError, Column, DataStore, KeyRange, Lock, LockError, LockOwner, RepoCid,
+};
 
 /// The PinStore implementation for FsDataStore
 mod pinstore;
+use pinstore::IndirectPins;
 
 /// The FsBlockStore implementation
 mod blocks;
 pub use blocks::FsBlockStore;
 
+/// The column operations of FsDataStore
+mod columns;
+
+/// The FsJournal implementation
+mod journal;
+pub use journal::FsJournal;
+
 /// Path mangling done for pins and blocks
 mod paths;
-use paths::{block_path, filestem_to_block_cid, filestem_to_pin_cid, pin_path};
+use paths::{
+    block_path, column_key_path, filestem_to_block_cid, filestem_to_column_key,
+    filestem_to_flatfs_cid, filestem_to_pin_cid, flatfs_block_path, pin_path,
+};
 
 /// FsDataStore which uses the filesystem as a lockable key-value store. Maintains a similar to
 /// [`FsBlockStore`] sharded two level storage. Direct have empty files, recursive pins record all of
 /// their indirect descendants. Pin files are separated by their file extensions.
 ///
+/// The columns are directories next to the pins, with a file per key.
+///
 /// When modifying, single lock is used.
 ///
-/// For the [`crate::repo::PinStore`] implementation see `fs/pinstore.rs`.
+/// For the [`crate::repo::PinStore`] implementation see `fs/pinstore.rs`, for the column
+/// operations see `fs/columns.rs`.
 #[derive(Debug)]
 pub struct FsDataStore {
     /// The base directory under which we have a sharded directory structure, and the individual
     /// blocks are stored under the shard. See unixfs/examples/cat.rs for read example.
     path: PathBuf,
 
+    /// The directory of the columns, and the parent of `path`.
+    columns: PathBuf,
+
     /// Start with simple, conservative solution, allows concurrent queries but single writer.
     /// It is assumed the reads do not require permit as non-empty writes are done through
     /// tempfiles and the consistency regarding reads is not a concern right now. For garbage
     /// collection implementation, it might be needed to hold this permit for the duration of
     /// garbage collection, or something similar.
     lock: Arc<Semaphore>,
+
+    /// The blocks referenced by the recursive pins, gathered on the first lookup or listing of the
+    /// indirect pins and dropped whenever the recursive pins change.
+    indirect: std::sync::Mutex<IndirectPins>,
 }
 
-/// The column operations are all unimplemented pending at least downscoping of the
-/// DataStore trait itself.
 #[async_trait]
 impl DataStore for FsDataStore {
-    fn new(mut root: PathBuf) -> Self {
-        root.push("pins");
+    fn new(root: PathBuf) -> Self {
         FsDataStore {
-            path: root,
+            path: root.join("pins"),
+            columns: root,
             lock: Arc::new(Semaphore::new(1)),
+            indirect: Default::default(),
         }
     }
 
+    /// Creates the directories, and completes the batch interrupted by a crash, if any.
     async fn init(&self) -> Result<(), Error> {
         tokio::fs::create_dir_all(&self.path).await?;
-        Ok(())
+        self.replay_batch().await
     }
 
     async fn open(&self) -> Result<(), Error> {
-        Ok(())
+        self.replay_batch().await
+    }
+
+    async fn contains(&self, col: Column, key: &[u8]) -> Result<bool, Error> {
+        self.column_contains(col, key).await
     }
 
-    async fn contains(&self, _col: Column, _key: &[u8]) -> Result<bool, Error> {
-        Err(anyhow::anyhow!("not implemented"))
+    async fn get(&self, col: Column, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.column_get(col, key).await
     }
 
-    async fn get(&self, _col: Column, _key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
-        Err(anyhow::anyhow!("not implemented"))
+    async fn put(&self, col: Column, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.column_put(col, key, value).await
     }
 
-    async fn put(&self, _col: Column, _key: &[u8], _value: &[u8]) -> Result<(), Error> {
-        Err(anyhow::anyhow!("not implemented"))
+    async fn remove(&self, col: Column, key: &[u8]) -> Result<(), Error> {
+        self.column_remove(col, key).await
     }
 
-    async fn remove(&self, _col: Column, _key: &[u8]) -> Result<(), Error> {
-        Err(anyhow::anyhow!("not implemented"))
+    async fn scan(&self, col: Column, range: &KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        self.column_scan(col, range).await
     }
 
+    /// Applies the batch through an intent file, which is replayed on the next start if the
+    /// process crashes before all of the modifications have been applied.
+    async fn write_batch(&self, batch: Batch) -> Result<(), Error> {
+        self.column_write_batch(batch).await
+    }
+
+    /// Removes the columns and the pins.
     async fn wipe(&self) {
-        todo!()
+        if let Err(e) = self.wipe_columns().await {
+            warn!("failed to wipe the datastore columns: {}", e);
+        }
+
+        let _permit = self.lock.acquire().await;
+        match tokio::fs::remove_dir_all(&self.path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("failed to wipe the pins: {}", e),
+        }
+        if let Err(e) = tokio::fs::create_dir_all(&self.path).await {
+            warn!("failed to recreate the pin directory: {}", e);
+        }
+        self.recursive_pins_changed();
     }
 }
 
+/// The repo lock held as an advisory lock on the lock file, recording the owner next to it.
+///
+/// The operating system releases the lock when the owning process exits, so the lock of a crashed
+/// daemon never needs to be taken over: the lock is still held only when another process, like
+/// one started by the daemon, has inherited it.
 #[derive(Debug)]
 pub struct FsLock {
     file: Option<File>,
@@ -98,6 +150,28 @@ enum State {
     Exclusive,
 }
 
+impl FsLock {
+    /// The owner is recorded next to the lock file instead of in it, as on Windows the locked file
+    /// cannot be read by the other processes.
+    fn owner_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".owner");
+        PathBuf::from(path)
+    }
+}
+
+impl Drop for FsLock {
+    fn drop(&mut self) {
+        // the owner is removed while still holding the lock, so that it cannot remove the owner
+        // recorded by the next process
+        if let State::Exclusive = self.state {
+            if let Err(e) = std::fs::remove_file(self.owner_path()) {
+                warn!("failed to remove the repo lock owner: {}", e);
+            }
+        }
+    }
+}
+
 impl Lock for FsLock {
     fn new(path: PathBuf) -> Self {
         Self {
@@ -117,7 +191,32 @@ impl Lock for FsLock {
             .create(true)
             .open(&self.path)?;
 
-        file.try_lock_exclusive()?;
+        let owner_path = self.owner_path();
+
+        if let Err(e) = file.try_lock_exclusive() {
+            return match (LockError::from(e), std::fs::read_to_string(&owner_path)) {
+                (LockError::RepoInUse, Ok(contents)) => match contents.parse::<LockOwner>() {
+                    Ok(owner) if owner.is_stale() => Err(LockError::HeldAfterExit(owner)),
+                    Ok(owner) => Err(LockError::RepoInUseBy(owner)),
+                    Err(_) => Err(LockError::RepoInUse),
+                },
+                (e, _) => Err(e),
+            };
+        }
+
+        // a previous owner is only left recorded by a process which did not exit cleanly; its
+        // lock was released by the operating system when it exited
+        if let Some(previous) = std::fs::read_to_string(&owner_path)
+            .ok()
+            .and_then(|contents| contents.parse::<LockOwner>().ok())
+            .filter(|previous| *previous != LockOwner::current())
+        {
+            info!("locking the repo left behind by {}", previous);
+        }
+
+        // record the owner for the error messages of the later attempts
+        let owner = LockOwner::current();
+        std::fs::write(&owner_path, format!("{} {}\n", owner.pid, owner.host))?;
 
         self.state = State::Exclusive;
         self.file = Some(file);
@@ -129,9 +228,12 @@ impl Lock for FsLock {
 #[cfg(test)]
 crate::pinstore_interface_tests!(common_tests, crate::repo::fs::FsDataStore::new);
 
+#[cfg(test)]
+crate::datastore_interface_tests!(datastore_common_tests, crate::repo::fs::FsDataStore::new);
+
 #[cfg(test)]
 mod tests {
-    use super::{FsLock, Lock};
+    use super::{FsLock, Lock, LockError, LockOwner};
 
     #[test]
     fn creates_an_exclusive_repo_lock() {
@@ -147,6 +249,100 @@ mod tests {
         assert!(result.is_err());
 
         // Clean-up.
+        drop(lock);
         std::fs::remove_file(lockfile_path).unwrap();
     }
+
+    #[test]
+    fn lock_errors_name_the_owner() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lockfile_path = temp_dir.path().join("repo_lock");
+
+        let mut lock = FsLock::new(lockfile_path.clone());
+        lock.try_exclusive().unwrap();
+
+        let mut failing_lock = FsLock::new(lockfile_path);
+        match failing_lock.try_exclusive() {
+            Err(LockError::RepoInUseBy(owner)) => assert_eq!(owner, LockOwner::current()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn releasing_the_lock_removes_the_owner() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lockfile_path = temp_dir.path().join("repo_lock");
+
+        let mut lock = FsLock::new(lockfile_path.clone());
+        lock.try_exclusive().unwrap();
+        let owner_path = lock.owner_path();
+
+        // failing to lock leaves the owner of the held lock alone
+        let mut failing_lock = FsLock::new(lockfile_path.clone());
+        assert!(failing_lock.try_exclusive().is_err());
+        drop(failing_lock);
+        assert!(owner_path.exists());
+
+        drop(lock);
+        assert!(!owner_path.exists());
+
+        let mut next = FsLock::new(lockfile_path);
+        next.try_exclusive().unwrap();
+        assert!(owner_path.exists());
+    }
+
+    /// Returns the owner recorded for a process which has already exited.
+    fn exited_owner() -> LockOwner {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        LockOwner {
+            pid: child.id(),
+            ..LockOwner::current()
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn locks_the_repo_left_behind_by_an_exited_owner() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lockfile_path = temp_dir.path().join("repo_lock");
+
+        let lock = FsLock::new(lockfile_path.clone());
+        let owner = exited_owner();
+        std::fs::write(&lockfile_path, "").unwrap();
+        std::fs::write(lock.owner_path(), format!("{} {}\n", owner.pid, owner.host)).unwrap();
+
+        // the lock was released when the owner exited
+        let mut next = FsLock::new(lockfile_path.clone());
+        next.try_exclusive().unwrap();
+
+        let recorded = std::fs::read_to_string(next.owner_path()).unwrap();
+        assert_eq!(recorded.parse::<LockOwner>(), Ok(LockOwner::current()));
+
+        let mut failing_lock = FsLock::new(lockfile_path);
+        assert!(failing_lock.try_exclusive().is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn reports_the_lock_held_after_the_owner_exited() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lockfile_path = temp_dir.path().join("repo_lock");
+
+        // for example a process started by the recorded owner keeps holding the lock
+        let mut lock = FsLock::new(lockfile_path.clone());
+        lock.try_exclusive().unwrap();
+
+        let owner = exited_owner();
+        std::fs::write(lock.owner_path(), format!("{} {}\n", owner.pid, owner.host)).unwrap();
+
+        let mut failing_lock = FsLock::new(lockfile_path.clone());
+        match failing_lock.try_exclusive() {
+            Err(LockError::HeldAfterExit(found)) => assert_eq!(found, owner),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // the lock file is left alone for the holder
+        assert!(lockfile_path.exists());
+    }
 }
@@ -1,15 +1,18 @@
-use super::{block_path, filestem_to_block_cid};
+use super::{block_path, filestem_to_block_cid, filestem_to_flatfs_cid, flatfs_block_path};
 use super::{BlockRm, BlockRmError, RepoCid};
 use crate::error::Error;
+use crate::repo::placement::{BlockStorage, Rebalance, Volumes};
 use crate::repo::{BlockPut, BlockStore};
 use crate::Block;
 use async_trait::async_trait;
 use cid::Cid;
+use futures::stream::BoxStream;
 use hash_hasher::{HashBuildHasher, HashedMap};
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 use std::io::Read;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use tokio::fs;
@@ -18,19 +21,41 @@ use tracing_futures::Instrument;
 
 type ArcMutexHashedMap<A, B> = Arc<Mutex<HashedMap<A, B>>>;
 
+/// The file naming the sharding function of a go-ipfs flatfs blockstore.
+const SHARDING_FILE: &str = "SHARDING";
+
+/// The only sharding function of go-ipfs flatfs supported, the default one of go-ipfs.
+const FLATFS_SHARDING: &str = "/repo/flatfs/shard/v1/next-to-last/2";
+
+/// The disk usage of a go-ipfs flatfs blockstore, which go-ipfs only recalculates if missing.
+const DISK_USAGE_CACHE: &str = "diskUsage.cache";
+
 /// File system backed block store.
 ///
-/// For information on path mangling, please see `block_path` and `filestem_to_block_cid`.
+/// The blocks can be spread over multiple volumes, see [`BlockStorage`].
+///
+/// The `blocks` directory of a go-ipfs repo can be opened as the primary volume, in which case the
+/// blocks are named like go-ipfs flatfs names them on all of the volumes. This is detected from
+/// the `SHARDING` file go-ipfs creates, and only the default `next-to-last/2` sharding is
+/// supported.
+///
+/// For information on path mangling, please see `block_path` and `filestem_to_block_cid`, or
+/// `flatfs_block_path` and `filestem_to_flatfs_cid` for the go-ipfs flatfs layout.
 #[derive(Debug)]
 pub struct FsBlockStore {
-    /// The base directory under which we have a sharded directory structure, and the individual
-    /// blocks are stored under the shard. See unixfs/examples/cat.rs for read example.
-    path: PathBuf,
+    /// The base directories under each of which we have a sharded directory structure, and the
+    /// individual blocks are stored under the shard. See unixfs/examples/cat.rs for read example.
+    volumes: Volumes,
+
+    /// True when the primary volume is a go-ipfs flatfs blockstore, detected on `init` and
+    /// `open`.
+    flatfs: AtomicBool,
 
     /// Synchronize concurrent reads and writes to the same Cid.
     /// If the write ever happens, the message sent will be Ok(()), on failure it'll be an Err(()).
     /// Since this is a broadcast channel, the late arriving receiver might not get any messages.
-    writes: ArcMutexHashedMap<RepoCid, broadcast::Sender<Result<(), ()>>>,
+    /// The concurrent writers all write to the volume selected by the first one.
+    writes: ArcMutexHashedMap<RepoCid, (broadcast::Sender<Result<(), ()>>, usize)>,
 
     /// Initially used to demonstrate a bug, not really needed anymore. Could be used as a basis
     /// for periodic synching to disk to know much space we have used.
@@ -62,6 +87,41 @@ impl<K: Eq + Hash, V> Drop for RemoveOnDrop<K, V> {
     }
 }
 
+/// How the blocks are named under the volumes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    /// Named after the Cid of the block, see `block_path`.
+    Cid,
+    /// Named after the multihash of the block like go-ipfs flatfs does, see `flatfs_block_path`.
+    Flatfs,
+}
+
+impl Layout {
+    fn block_path(self, base: PathBuf, cid: &Cid) -> PathBuf {
+        match self {
+            Layout::Cid => block_path(base, cid),
+            Layout::Flatfs => flatfs_block_path(base, cid),
+        }
+    }
+
+    fn filestem_to_cid(self, file_stem: Option<&std::ffi::OsStr>) -> Option<Cid> {
+        match self {
+            Layout::Cid => filestem_to_block_cid(file_stem),
+            Layout::Flatfs => filestem_to_flatfs_cid(file_stem),
+        }
+    }
+}
+
+/// The outcome of moving a block between the volumes.
+#[derive(Debug)]
+enum Move {
+    Moved,
+    /// The block was already on the target volume, and the source copy was removed.
+    Duplicate,
+    /// The block was being written, and was left in place.
+    Busy,
+}
+
 /// When synchronizing to a possible ongoing write through `FsBlockStore::writes` these are the
 /// possible outcomes.
 #[derive(Debug)]
@@ -73,6 +133,46 @@ enum WriteCompletion {
 }
 
 impl FsBlockStore {
+    fn layout(&self) -> Layout {
+        if self.flatfs.load(Ordering::Relaxed) {
+            Layout::Flatfs
+        } else {
+            Layout::Cid
+        }
+    }
+
+    /// Detects a go-ipfs flatfs blockstore from the `SHARDING` file of the primary volume.
+    async fn detect_layout(&self) -> Result<(), Error> {
+        let primary = self.volumes.path(0);
+
+        let sharding = match fs::read_to_string(primary.join(SHARDING_FILE)).await {
+            Ok(sharding) => sharding,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.flatfs.store(false, Ordering::Relaxed);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if sharding.trim() != FLATFS_SHARDING {
+            return Err(anyhow::anyhow!(
+                "unsupported flatfs sharding {:?}, only {:?} is supported",
+                sharding.trim(),
+                FLATFS_SHARDING
+            ));
+        }
+
+        // the cached disk usage would no longer be accurate once blocks are written or removed
+        match fs::remove_file(primary.join(DISK_USAGE_CACHE)).await {
+            Ok(()) => debug!("removed the disk usage cache of the go-ipfs flatfs blockstore"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        self.flatfs.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
     /// Returns the same Cid in either case. Ok variant is returned in case it is suspected the
     /// write completed successfully or there was never any write ongoing. Err variant is returned
     /// if it's known that the write failed.
@@ -85,7 +185,7 @@ impl FsBlockStore {
             .expect("cannot support poisoned")
             .entry(RepoCid(cid.clone()))
         {
-            Entry::Occupied(oe) => oe.get().subscribe(),
+            Entry::Occupied(oe) => oe.get().0.subscribe(),
             Entry::Vacant(_) => return WriteCompletion::NotOngoing,
         };
 
@@ -100,13 +200,155 @@ impl FsBlockStore {
             }
         }
     }
+
+    /// The paths the block could be stored at, the most likely one first.
+    fn block_paths(&self, cid: &Cid) -> Vec<(usize, PathBuf)> {
+        self.volumes
+            .lookup_order(cid)
+            .into_iter()
+            .map(|index| {
+                (
+                    index,
+                    self.layout().block_path(self.volumes.path(index), cid),
+                )
+            })
+            .collect()
+    }
+
+    /// Measures the space used on each of the volumes, when needed for the placement.
+    async fn measure_volumes(&self) -> Result<(), Error> {
+        use futures::stream::TryStreamExt;
+
+        if !self.volumes.tracks_usage() {
+            return Ok(());
+        }
+
+        for index in 0..self.volumes.len() {
+            let used = iter_volume(self.volumes.path(index), self.layout())
+                .await?
+                .try_fold(0u64, |used, (_, size)| async move {
+                    Ok::<_, Error>(used + size)
+                })
+                .await?;
+
+            self.volumes.set_used(index, used);
+        }
+
+        Ok(())
+    }
+
+    /// Moves a block between the volumes while holding it in `writes`, so that the concurrent
+    /// writes of the same block wait for the move.
+    async fn move_block(&self, cid: &Cid, from: usize, to: usize) -> Result<Move, Error> {
+        use std::collections::hash_map::Entry;
+
+        let tx = match self
+            .writes
+            .lock()
+            .expect("cant support poisoned")
+            .entry(RepoCid(cid.to_owned()))
+        {
+            // leave the block for the next rebalancing
+            Entry::Occupied(_) => return Ok(Move::Busy),
+            Entry::Vacant(ve) => {
+                let (tx, _) = broadcast::channel(1);
+                ve.insert((tx.clone(), to));
+                tx
+            }
+        };
+
+        let cleanup = RemoveOnDrop(self.writes.clone(), Some(RepoCid(cid.to_owned())));
+
+        let source = self.layout().block_path(self.volumes.path(from), cid);
+        let target = self.layout().block_path(self.volumes.path(to), cid);
+
+        let moved = tokio::task::spawn_blocking(move || {
+            std::fs::create_dir_all(target.parent().expect("the shard parent exists"))?;
+
+            let moved = match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&target)
+            {
+                Ok(file) => {
+                    let data = std::fs::read(&source)?;
+                    let temp_path = target.with_extension("tmp");
+
+                    if let Err(e) = write_through_tempfile(file, &target, temp_path, &data) {
+                        std::fs::remove_file(&target).ok();
+                        return Err(e);
+                    }
+                    Move::Moved
+                }
+                // an extra copy
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Move::Duplicate,
+                Err(e) => return Err(e),
+            };
+
+            std::fs::remove_file(&source)?;
+            Ok::<_, std::io::Error>(moved)
+        })
+        .await?;
+
+        // the readers find the block from either of the volumes during the move
+        let _ = tx.send(Ok(()));
+        drop(cleanup);
+
+        Ok(moved?)
+    }
+}
+
+/// Lists the blocks stored on a single volume along with their sizes, reading the shards lazily.
+async fn iter_volume(
+    p: PathBuf,
+    layout: Layout,
+) -> Result<BoxStream<'static, Result<(Cid, u64), Error>>, Error> {
+    use futures::stream::{StreamExt, TryStreamExt};
+    use tokio_stream::wrappers::ReadDirStream;
+
+    let stream = ReadDirStream::new(fs::read_dir(p).await?)
+        .try_filter_map(|d| async move {
+            // map over the shard directories
+            Ok(if d.file_type().await?.is_dir() {
+                Some(ReadDirStream::new(fs::read_dir(d.path()).await?))
+            } else {
+                None
+            })
+        })
+        // shards are read one at a time as the stream is polled
+        .try_flatten()
+        .try_filter_map(move |d| async move {
+            let name = d.file_name();
+            let path: &std::path::Path = name.as_ref();
+
+            if path.extension() != Some("data".as_ref()) {
+                return Ok(None);
+            }
+
+            let cid = match layout.filestem_to_cid(path.file_stem()) {
+                Some(cid) => cid,
+                None => return Ok(None),
+            };
+
+            match d.metadata().await {
+                Ok(m) => Ok(Some((cid, m.len()))),
+                // removed after the directory was read
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+        .map_err(Error::from)
+        .instrument(tracing::trace_span!("iterating blocks"));
+
+    Ok(stream.boxed())
 }
 
 #[async_trait]
 impl BlockStore for FsBlockStore {
     fn new(path: PathBuf) -> Self {
         FsBlockStore {
-            path,
+            volumes: Volumes::new(path, &BlockStorage::default()),
+            flatfs: AtomicBool::new(false),
             writes: Arc::new(Mutex::new(HashedMap::with_capacity_and_hasher(
                 8,
                 HashBuildHasher::default(),
@@ -115,29 +357,39 @@ impl BlockStore for FsBlockStore {
         }
     }
 
+    fn configure(&mut self, storage: &BlockStorage) {
+        let primary = self.volumes.path(0);
+        self.volumes = Volumes::new(primary, storage);
+    }
+
     async fn init(&self) -> Result<(), Error> {
-        fs::create_dir_all(self.path.clone()).await?;
-        Ok(())
+        for index in 0..self.volumes.len() {
+            fs::create_dir_all(self.volumes.path(index)).await?;
+        }
+        self.detect_layout().await?;
+        self.measure_volumes().await
     }
 
     async fn open(&self) -> Result<(), Error> {
-        // TODO: we probably want to cache the space usage?
-        Ok(())
+        self.detect_layout().await?;
+        // the usage is only measured when needed for the placement
+        self.measure_volumes().await
     }
 
     async fn contains(&self, cid: &Cid) -> Result<bool, Error> {
-        let path = block_path(self.path.clone(), cid);
-
         // why doesn't this synchronize with the rest? Not sure if there is any use for this method
         // actually. When does it matter if a block exists, except for testing.
 
-        let metadata = match fs::metadata(path).await {
-            Ok(m) => m,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
-            Err(e) => return Err(e.into()),
-        };
+        for (_, path) in self.block_paths(cid) {
+            match fs::metadata(path).await {
+                Ok(m) if m.is_file() => return Ok(true),
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
 
-        Ok(metadata.is_file())
+        Ok(false)
     }
 
     async fn get(&self, cid: &Cid) -> Result<Option<Block>, Error> {
@@ -148,27 +400,31 @@ impl BlockStore for FsBlockStore {
                 return Ok(None);
             }
 
-            let path = block_path(self.path.clone(), cid);
+            let paths = self.block_paths(cid);
 
             let cid = cid.to_owned();
 
             // probably best to do everything in the blocking thread if we are to issue multiple
             // syscalls
             tokio::task::spawn_blocking(move || {
-                let mut file = match std::fs::File::open(path) {
-                    Ok(file) => file,
-                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
-                    Err(e) => {
-                        return Err(e.into());
-                    }
-                };
+                for (_, path) in paths {
+                    let mut file = match std::fs::File::open(path) {
+                        Ok(file) => file,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                        Err(e) => {
+                            return Err(e.into());
+                        }
+                    };
+
+                    let len = file.metadata()?.len();
 
-                let len = file.metadata()?.len();
+                    let mut data = Vec::with_capacity(len as usize);
+                    file.read_to_end(&mut data)?;
+                    let block = Block::new(data.into_boxed_slice(), cid);
+                    return Ok(Some(block));
+                }
 
-                let mut data = Vec::with_capacity(len as usize);
-                file.read_to_end(&mut data)?;
-                let block = Block::new(data.into_boxed_slice(), cid);
-                Ok(Some(block))
+                Ok(None)
             })
             .await?
         }
@@ -181,7 +437,6 @@ impl BlockStore for FsBlockStore {
 
         let span = tracing::trace_span!("put block", cid = %block.cid());
 
-        let target_path = block_path(self.path.clone(), block.cid());
         let cid = block.cid;
         let data = block.data;
 
@@ -193,20 +448,25 @@ impl BlockStore for FsBlockStore {
 
             // FIXME: allowing only the creator to cleanup means this is not forget safe. the forget
             // doesn't result in memory unsafety but it will deadlock any other access to the cid.
-            let (tx, mut rx) = {
+            let (tx, mut rx, volume) = {
                 let mut g = self.writes.lock().expect("cant support poisoned");
 
                 match g.entry(RepoCid(cid.to_owned())) {
                     Entry::Occupied(oe) => {
                         // someone is already writing this, nice
                         trace!("joining in on another already writing the block");
-                        (oe.get().clone(), oe.get().subscribe())
+                        let (tx, volume) = oe.get();
+                        (tx.clone(), tx.subscribe(), *volume)
                     }
                     Entry::Vacant(ve) => {
                         // we might be the first, or then the block exists already
+                        let volume = match self.volumes.place(&cid, data.len() as u64) {
+                            Some(volume) => volume,
+                            None => return Err(anyhow::anyhow!("all block volumes are full")),
+                        };
                         let (tx, rx) = broadcast::channel(1);
-                        ve.insert(tx.clone());
-                        (tx, rx)
+                        ve.insert((tx.clone(), volume));
+                        (tx, rx, volume)
                     }
                 }
             };
@@ -214,6 +474,16 @@ impl BlockStore for FsBlockStore {
             // create this in case the winner is dropped while awaiting
             let cleanup = RemoveOnDrop(self.writes.clone(), Some(RepoCid(cid.to_owned())));
 
+            let target_path = self.layout().block_path(self.volumes.path(volume), &cid);
+
+            // the block could already exist on the other volumes
+            let elsewhere = self
+                .block_paths(&cid)
+                .into_iter()
+                .filter(|(index, _)| *index != volume)
+                .map(|(_, path)| path)
+                .collect::<Vec<_>>();
+
             // launch a blocking task for the filesystem mutation.
             let je = tokio::task::spawn_blocking(move || {
                 // pick winning writer with filesystem and create_new; this error will be the 1st
@@ -222,6 +492,10 @@ impl BlockStore for FsBlockStore {
                 // this is blocking context, use this instead of instrument
                 let _entered = inner_span.enter();
 
+                if elsewhere.iter().any(|path| path.is_file()) {
+                    return Ok(Ok(None));
+                }
+
                 let sharded = target_path
                     .parent()
                     .expect("we already have at least the shard parent");
@@ -240,7 +514,7 @@ impl BlockStore for FsBlockStore {
                 match write_through_tempfile(target, &target_path, temp_path, &data) {
                     Ok(()) => {
                         trace!("successfully wrote the block");
-                        Ok::<_, std::io::Error>(Ok(data.len()))
+                        Ok::<_, std::io::Error>(Ok(Some(data.len())))
                     }
                     Err(e) => {
                         match std::fs::remove_file(&target_path) {
@@ -261,7 +535,14 @@ impl BlockStore for FsBlockStore {
             drop(cleanup);
 
             match je {
-                Ok(Ok(Ok(written))) => {
+                Ok(Ok(Ok(None))) => {
+                    trace!("the block existed on another volume");
+                    drop(rx);
+                    drop(tx);
+
+                    Ok((cid, BlockPut::Existed))
+                }
+                Ok(Ok(Ok(Some(written)))) => {
                     trace!(bytes = written, "block writing succeeded");
                     let _ = tx
                         .send(Ok(()))
@@ -272,6 +553,7 @@ impl BlockStore for FsBlockStore {
 
                     self.written_bytes
                         .fetch_add(written as u64, Ordering::SeqCst);
+                    self.volumes.add_used(volume, written as u64);
 
                     Ok((cid, BlockPut::NewBlock))
                 }
@@ -336,21 +618,42 @@ impl BlockStore for FsBlockStore {
     }
 
     async fn remove(&self, cid: &Cid) -> Result<Result<BlockRm, BlockRmError>, Error> {
-        let path = block_path(self.path.clone(), cid);
-
         let span = trace_span!("remove block", cid = %cid);
 
         match self.write_completion(cid).instrument(span).await {
             WriteCompletion::KnownBad => Ok(Err(BlockRmError::NotFound(cid.to_owned()))),
             completion => {
                 trace!(cid = %cid, completion = ?completion, "removing block after synchronizing");
-                match fs::remove_file(path).await {
-                    // FIXME: not sure if theres any point in taking cid ownership here?
-                    Ok(()) => Ok(Ok(BlockRm::Removed(cid.to_owned()))),
-                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                        Ok(Err(BlockRmError::NotFound(cid.to_owned())))
+
+                let mut removed = false;
+
+                // remove the copies from all of the volumes
+                for (index, path) in self.block_paths(cid) {
+                    let size = if self.volumes.tracks_usage() {
+                        match fs::metadata(&path).await {
+                            Ok(m) => m.len(),
+                            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                            Err(e) => return Err(e.into()),
+                        }
+                    } else {
+                        0
+                    };
+
+                    match fs::remove_file(path).await {
+                        Ok(()) => {
+                            self.volumes.sub_used(index, size);
+                            removed = true;
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e.into()),
                     }
-                    Err(e) => Err(e.into()),
+                }
+
+                if removed {
+                    // FIXME: not sure if theres any point in taking cid ownership here?
+                    Ok(Ok(BlockRm::Removed(cid.to_owned())))
+                } else {
+                    Ok(Err(BlockRmError::NotFound(cid.to_owned())))
                 }
             }
         }
@@ -370,7 +673,7 @@ impl BlockStore for FsBlockStore {
         ///
         /// [the first gist]: https://gist.github.com/koivunej/d6abccb4133839eeab8b36992f1a95fa
         /// [the second gist]: https://gist.github.com/koivunej/cbcaae52b7242a73419ef62e4c606bd7
-        async fn list0(p: PathBuf) -> Result<Vec<Cid>, Error> {
+        async fn list0(p: PathBuf, layout: Layout) -> Result<Vec<Cid>, Error> {
             use futures::future::ready;
             use futures::stream::TryStreamExt;
             use tokio_stream::wrappers::ReadDirStream;
@@ -392,14 +695,14 @@ impl BlockStore for FsBlockStore {
                     // flatten each; there could be unordered execution pre-flattening
                     .try_flatten()
                     // convert the paths ending in ".data" into cid
-                    .try_filter_map(|d| {
+                    .try_filter_map(move |d| {
                         let name = d.file_name();
                         let path: &std::path::Path = name.as_ref();
 
                         ready(if path.extension() != Some("data".as_ref()) {
                             Ok(None)
                         } else {
-                            let maybe_cid = filestem_to_block_cid(path.file_stem());
+                            let maybe_cid = layout.filestem_to_cid(path.file_stem());
                             Ok(maybe_cid)
                         })
                     })
@@ -411,7 +714,109 @@ impl BlockStore for FsBlockStore {
             .instrument(span)
             .await
         }
-        list0(self.path.to_owned()).await
+        let mut seen = HashSet::new();
+        let mut cids = Vec::new();
+
+        for index in 0..self.volumes.len() {
+            // a block is only stored on multiple volumes while being moved
+            for cid in list0(self.volumes.path(index), self.layout()).await? {
+                if seen.insert(cid.clone()) {
+                    cids.push(cid);
+                }
+            }
+        }
+
+        Ok(cids)
+    }
+
+    async fn iter(&self) -> Result<BoxStream<'static, Result<(Cid, u64), Error>>, Error> {
+        use futures::stream::StreamExt;
+
+        let mut streams = Vec::with_capacity(self.volumes.len());
+        for index in 0..self.volumes.len() {
+            // see `list0` on why the volumes are read by a separate function
+            streams.push(iter_volume(self.volumes.path(index), self.layout()).await?);
+        }
+
+        // the block being moved between the volumes could be listed twice
+        Ok(futures::stream::iter(streams).flatten().boxed())
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, Error> {
+        use futures::stream::{StreamExt, TryStreamExt};
+
+        // the reads happen on the blocking threads so there is little point in going much wider
+        const CONCURRENT_READS: usize = 8;
+
+        futures::stream::iter(cids)
+            .map(|cid| self.get(cid))
+            .buffered(CONCURRENT_READS)
+            .try_collect()
+            .await
+    }
+
+    async fn rebalance(&self) -> Result<Rebalance, Error> {
+        use futures::stream::TryStreamExt;
+
+        let span = tracing::debug_span!("rebalance blocks");
+
+        async move {
+            let mut rebalance = Rebalance::default();
+            // the volume of the kept copy of each of the blocks
+            let mut kept = HashMap::new();
+
+            self.measure_volumes().await?;
+
+            for from in 0..self.volumes.len() {
+                let mut blocks = iter_volume(self.volumes.path(from), self.layout()).await?;
+
+                while let Some((cid, size)) = blocks.try_next().await? {
+                    match kept.get(&cid) {
+                        // moved here earlier
+                        Some(volume) if *volume == from => {}
+                        // left over from an interrupted move
+                        Some(_) => {
+                            let path = self.layout().block_path(self.volumes.path(from), &cid);
+                            match fs::remove_file(path).await {
+                                Ok(()) => {
+                                    self.volumes.sub_used(from, size);
+                                    rebalance.removed_duplicates += 1;
+                                }
+                                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                                Err(e) => return Err(e.into()),
+                            }
+                        }
+                        None => {
+                            let volume = match self.volumes.rebalance_target(from, &cid, size) {
+                                Some(to) => match self.move_block(&cid, from, to).await? {
+                                    Move::Moved => {
+                                        trace!(cid = %cid, from, to, "moved block");
+                                        self.volumes.sub_used(from, size);
+                                        self.volumes.add_used(to, size);
+                                        rebalance.moved += 1;
+                                        rebalance.moved_bytes += size;
+                                        to
+                                    }
+                                    Move::Duplicate => {
+                                        self.volumes.sub_used(from, size);
+                                        rebalance.removed_duplicates += 1;
+                                        to
+                                    }
+                                    Move::Busy => from,
+                                },
+                                None => from,
+                            };
+
+                            kept.insert(cid, volume);
+                        }
+                    }
+                }
+            }
+
+            Ok(rebalance)
+        }
+        .instrument(span)
+        .await
     }
 
     async fn wipe(&self) {
@@ -449,6 +854,13 @@ fn write_through_tempfile(
     Ok(())
 }
 
+#[cfg(test)]
+crate::blockstore_interface_tests!(
+    blockstore_common_tests,
+    crate::repo::fs::FsBlockStore::new,
+    persistent
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -547,6 +959,184 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_fs_blockstore_iter_and_get_many() {
+        use futures::stream::TryStreamExt;
+
+        let mut tmp = temp_dir();
+        tmp.push("blockstore_iter");
+        std::fs::remove_dir_all(&tmp).ok();
+
+        let block_store = FsBlockStore::new(tmp.clone());
+        block_store.init().await.unwrap();
+        block_store.open().await.unwrap();
+
+        let mut cids = Vec::new();
+        for data in &[&b"1"[..], &b"22"[..], &b"333"[..]] {
+            let data_slice = data.to_vec().into_boxed_slice();
+            let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data_slice));
+            block_store
+                .put(Block::new(data_slice, cid.clone()))
+                .await
+                .unwrap();
+            cids.push(cid);
+        }
+
+        let mut listed = block_store
+            .iter()
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        listed.sort_by_key(|(_, size)| *size);
+        assert_eq!(
+            listed,
+            vec![
+                (cids[0].clone(), 1),
+                (cids[1].clone(), 2),
+                (cids[2].clone(), 3)
+            ]
+        );
+
+        let missing = Cid::new_v1(Codec::Raw, Sha2_256::digest(b"4"));
+        let query = [cids[2].clone(), missing, cids[0].clone()];
+        let found = block_store.get_many(&query).await.unwrap();
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0].as_ref().map(|b| b.data()), Some(&b"333"[..]));
+        assert!(found[1].is_none());
+        assert_eq!(found[2].as_ref().map(|b| b.data()), Some(&b"1"[..]));
+
+        std::fs::remove_dir_all(&tmp).ok();
+    }
+
+    #[tokio::test]
+    async fn blocks_are_rebalanced_to_an_added_volume() {
+        use crate::repo::{BlockPlacement, BlockStorage, BlockVolume};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let primary = tmp.path().join("blockstore");
+
+        let blocks = (0..20)
+            .map(|n| {
+                let data = n.to_string().into_bytes().into_boxed_slice();
+                let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data));
+                Block::new(data, cid)
+            })
+            .collect::<Vec<_>>();
+
+        let block_store = FsBlockStore::new(primary.clone());
+        block_store.init().await.unwrap();
+        for block in &blocks {
+            block_store.put(block.clone()).await.unwrap();
+        }
+
+        let mut block_store = FsBlockStore::new(primary);
+        block_store.configure(&BlockStorage {
+            capacity: None,
+            volumes: vec![BlockVolume {
+                path: tmp.path().join("added"),
+                capacity: None,
+            }],
+            placement: BlockPlacement::RoundRobin,
+            object_storage: None,
+            cache: None,
+            backend: Default::default(),
+        });
+        block_store.init().await.unwrap();
+        assert_eq!(block_store.volumes.used(0), 30);
+
+        let rebalance = block_store.rebalance().await.unwrap();
+        assert!(rebalance.moved > 0);
+        assert_eq!(rebalance.removed_duplicates, 0);
+
+        let (first, second) = (block_store.volumes.used(0), block_store.volumes.used(1));
+        assert_eq!(first + second, 30);
+        assert!(first.max(second) - first.min(second) <= 2);
+
+        // balanced already
+        assert_eq!(block_store.rebalance().await.unwrap().moved, 0);
+
+        assert_eq!(block_store.list().await.unwrap().len(), blocks.len());
+
+        for block in &blocks {
+            assert_eq!(
+                block_store.get(block.cid()).await.unwrap().as_ref(),
+                Some(block)
+            );
+            // the existing blocks are not written again to another volume
+            let (_, put) = block_store.put(block.clone()).await.unwrap();
+            assert!(matches!(put, BlockPut::Existed));
+        }
+
+        block_store.remove(blocks[0].cid()).await.unwrap().unwrap();
+        assert!(!block_store.contains(blocks[0].cid()).await.unwrap());
+        assert_eq!(
+            block_store.volumes.used(0) + block_store.volumes.used(1),
+            29
+        );
+    }
+
+    #[tokio::test]
+    async fn go_ipfs_flatfs_is_opened() {
+        let tmp = tempfile::tempdir().unwrap();
+        let blocks = tmp.path().join("blocks");
+
+        // a block as written by go-ipfs
+        let existing = Cid::try_from("QmRgutAxd8t7oGkSm4wmeuByG6M51wcTso6cubDdQtuEfL").unwrap();
+        let existing_data = hex!("0a0d08021207666f6f6261720a1807");
+        let existing_path = flatfs_block_path(blocks.clone(), &existing);
+
+        std::fs::create_dir_all(existing_path.parent().unwrap()).unwrap();
+        std::fs::write(&existing_path, &existing_data).unwrap();
+        std::fs::write(blocks.join(SHARDING_FILE), format!("{}\n", FLATFS_SHARDING)).unwrap();
+        std::fs::write(blocks.join(DISK_USAGE_CACHE), b"{\"diskUsage\":15}").unwrap();
+
+        let block_store = FsBlockStore::new(blocks.clone());
+        block_store.open().await.unwrap();
+        assert!(!blocks.join(DISK_USAGE_CACHE).exists());
+
+        let found = block_store.get(&existing).await.unwrap().unwrap();
+        assert_eq!(found.data(), &existing_data[..]);
+
+        let data = b"1".to_vec().into_boxed_slice();
+        let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data));
+        block_store
+            .put(Block::new(data, cid.clone()))
+            .await
+            .unwrap();
+        assert!(flatfs_block_path(blocks.clone(), &cid).is_file());
+
+        let mut listed = block_store
+            .list()
+            .await
+            .unwrap()
+            .iter()
+            .map(|cid| cid.hash().to_owned())
+            .collect::<Vec<_>>();
+        listed.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+        let mut expected = vec![existing.hash().to_owned(), cid.hash().to_owned()];
+        expected.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        assert_eq!(listed, expected);
+
+        block_store.remove(&existing).await.unwrap().unwrap();
+        assert!(!existing_path.exists());
+    }
+
+    #[tokio::test]
+    async fn unsupported_flatfs_sharding() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join(SHARDING_FILE),
+            "/repo/flatfs/shard/v1/prefix/2\n",
+        )
+        .unwrap();
+
+        let block_store = FsBlockStore::new(tmp.path().to_owned());
+        assert!(block_store.open().await.is_err());
+    }
+
     #[tokio::test]
     async fn race_to_insert_new() {
         // FIXME: why not tempdir?
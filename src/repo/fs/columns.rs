@@ -0,0 +1,374 @@
+//! The column operations of [`FsDataStore`]: every column is a directory next to the pins, with a
+//! file per key. See [`column_key_path`] for the naming of the files.
+//!
+//! The values are written through temporary files renamed into place, so a crash leaves either
+//! the old or the new value. A [`Batch`] is first written to an intent file, which is replayed by
+//! [`FsDataStore`] when the repo is started again if the process crashed before the batch was
+//! completely applied.
+use super::{column_key_path, filestem_to_column_key, FsDataStore};
+use crate::error::Error;
+use crate::repo::{Batch, BatchOp, Column, KeyRange};
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// The name of the intent file of the batch being applied.
+const BATCH_FILE: &str = "batch";
+
+impl FsDataStore {
+    fn column_path(&self, col: Column) -> PathBuf {
+        self.columns.join(col.namespace())
+    }
+
+    fn key_path(&self, col: Column, key: &[u8]) -> PathBuf {
+        column_key_path(self.column_path(col), key)
+    }
+
+    pub(super) async fn column_contains(&self, col: Column, key: &[u8]) -> Result<bool, Error> {
+        let path = self.key_path(col, key);
+        Ok(tokio::task::spawn_blocking(move || path.is_file()).await?)
+    }
+
+    pub(super) async fn column_get(
+        &self,
+        col: Column,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let path = self.key_path(col, key);
+        tokio::task::spawn_blocking(move || read_value(&path)).await?
+    }
+
+    pub(super) async fn column_put(
+        &self,
+        col: Column,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        let permit = Semaphore::acquire_owned(Arc::clone(&self.lock)).await;
+        let path = self.key_path(col, key);
+        let value = value.to_owned();
+
+        let span = tracing::Span::current();
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _entered = span.enter();
+            write_value(&path, &value)
+        })
+        .await?
+    }
+
+    pub(super) async fn column_remove(&self, col: Column, key: &[u8]) -> Result<(), Error> {
+        let permit = Semaphore::acquire_owned(Arc::clone(&self.lock)).await;
+        let path = self.key_path(col, key);
+
+        let span = tracing::Span::current();
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _entered = span.enter();
+            remove_value(&path)
+        })
+        .await?
+    }
+
+    pub(super) async fn column_scan(
+        &self,
+        col: Column,
+        range: &KeyRange,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        let dir = self.column_path(col);
+        let range = range.clone();
+
+        let span = tracing::Span::current();
+
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            scan_column(&dir, &range)
+        })
+        .await?
+    }
+
+    pub(super) async fn column_write_batch(&self, batch: Batch) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let permit = Semaphore::acquire_owned(Arc::clone(&self.lock)).await;
+        let root = self.columns.clone();
+        let ops = batch
+            .ops()
+            .iter()
+            .map(|op| match op {
+                BatchOp::Put(col, key, value) => (self.key_path(*col, key), Some(value.to_owned())),
+                BatchOp::Remove(col, key) => (self.key_path(*col, key), None),
+            })
+            .collect::<Vec<_>>();
+
+        let span = tracing::Span::current();
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _entered = span.enter();
+
+            // the batch is committed once the intent file is in place
+            let intent = root.join(BATCH_FILE);
+            let temp = intent.with_extension("temp");
+            let mut file = File::create(&temp)?;
+            for (path, value) in &ops {
+                writeln!(file, "{}", intent_record(&root, path, value.as_deref())?)?;
+            }
+            file.sync_all()?;
+            drop(file);
+            std::fs::rename(&temp, &intent)?;
+
+            apply_ops(&ops)?;
+            std::fs::remove_file(&intent)?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Completes the batch interrupted by a crash, if any.
+    pub(super) async fn replay_batch(&self) -> Result<(), Error> {
+        let root = self.columns.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let intent = root.join(BATCH_FILE);
+            let file = match File::open(&intent) {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+
+            let ops = BufReader::new(file)
+                .lines()
+                .map(|line| parse_intent_record(&root, &line?))
+                .collect::<Result<Vec<_>, Error>>()?;
+
+            info!(
+                "completing an interrupted batch of {} modifications",
+                ops.len()
+            );
+            apply_ops(&ops)?;
+            std::fs::remove_file(&intent)?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Removes all of the columns, leaving the pins as they are.
+    pub(super) async fn wipe_columns(&self) -> Result<(), Error> {
+        let _permit = self.lock.acquire().await;
+
+        let mut entries = tokio::fs::read_dir(&self.columns).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path() == self.path {
+                continue;
+            }
+            if entry.file_type().await?.is_dir() {
+                tokio::fs::remove_dir_all(entry.path()).await?;
+            } else {
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_value(path: &Path) -> Result<Option<Vec<u8>>, Error> {
+    match std::fs::read(path) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_value(path: &Path, value: &[u8]) -> Result<(), Error> {
+    std::fs::create_dir_all(path.parent().expect("column directory has to exist"))?;
+
+    let temp = path.with_extension("value_temp");
+    let mut file = File::create(&temp)?;
+    file.write_all(value)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+fn remove_value(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn apply_ops(ops: &[(PathBuf, Option<Vec<u8>>)]) -> Result<(), Error> {
+    for (path, value) in ops {
+        match value {
+            Some(value) => write_value(path, value)?,
+            None => remove_value(path)?,
+        }
+    }
+    Ok(())
+}
+
+fn scan_column(dir: &Path, range: &KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+    if range.bounds().is_none() {
+        return Ok(Vec::new());
+    }
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut keys = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("value") {
+            // the temporary files
+            continue;
+        }
+        match filestem_to_column_key(path.file_stem()) {
+            Some(key) if range.contains(&key) => keys.push((key, path)),
+            _ => {}
+        }
+    }
+    keys.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    let limit = range.max_results().unwrap_or(usize::MAX);
+    let mut found = Vec::new();
+    for (key, path) in keys {
+        if found.len() >= limit {
+            break;
+        }
+        // the value could have been removed since listing the directory
+        if let Some(value) = read_value(&path)? {
+            found.push((key, value));
+        }
+    }
+    Ok(found)
+}
+
+/// Formats a modification of the batch as `put <path> <value>` or `remove <path>`, with the path
+/// relative to the datastore root and the value as lower case base16.
+fn intent_record(root: &Path, path: &Path, value: Option<&[u8]>) -> Result<String, Error> {
+    let relative = path
+        .strip_prefix(root)?
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("non utf8 path: {:?}", path))?;
+
+    Ok(match value {
+        Some(value) => format!(
+            "put {} {}",
+            relative,
+            multibase::Base::Base16Lower.encode(value)
+        ),
+        None => format!("remove {}", relative),
+    })
+}
+
+fn parse_intent_record(root: &Path, line: &str) -> Result<(PathBuf, Option<Vec<u8>>), Error> {
+    let mut parts = line.split(' ');
+    let op = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("put"), Some(path), Some(value), None) => (
+            root.join(path),
+            Some(multibase::Base::Base16Lower.decode(value)?),
+        ),
+        (Some("remove"), Some(path), None, None) => (root.join(path), None),
+        _ => return Err(anyhow::anyhow!("invalid batch record: {:?}", line)),
+    };
+    Ok(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{intent_record, BATCH_FILE};
+    use crate::repo::fs::FsDataStore;
+    use crate::repo::{Column, DataStore};
+
+    #[tokio::test]
+    async fn interrupted_batch_is_completed_on_open() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_owned();
+
+        let store = FsDataStore::new(root.clone());
+        store.init().await.unwrap();
+        store.open().await.unwrap();
+        store.put(Column::Ipns, b"gone", b"old").await.unwrap();
+
+        // the intent of a batch was written but the process crashed before applying it
+        let records = [
+            intent_record(
+                &root,
+                &store.key_path(Column::Config, b"head"),
+                Some(&b"new"[..]),
+            ),
+            intent_record(&root, &store.key_path(Column::Ipns, b"gone"), None),
+        ]
+        .iter()
+        .map(|record| format!("{}\n", record.as_ref().unwrap()))
+        .collect::<String>();
+        std::fs::write(root.join(BATCH_FILE), records).unwrap();
+        drop(store);
+
+        let store = FsDataStore::new(root.clone());
+        store.open().await.unwrap();
+
+        assert_eq!(
+            store.get(Column::Config, b"head").await.unwrap().as_deref(),
+            Some(&b"new"[..])
+        );
+        assert!(!store.contains(Column::Ipns, b"gone").await.unwrap());
+        assert!(!root.join(BATCH_FILE).exists());
+    }
+
+    #[cfg(not(feature = "sled_data_store"))]
+    #[tokio::test]
+    async fn interrupted_batch_is_completed_on_start() {
+        use crate::{IpfsOptions, Types, UninitializedIpfs};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("datastore");
+
+        let store = FsDataStore::new(root.clone());
+        store.init().await.unwrap();
+        let record = intent_record(
+            &root,
+            &store.key_path(Column::Ipns, b"interrupted"),
+            Some(&b"value"[..]),
+        )
+        .unwrap();
+        std::fs::write(root.join(BATCH_FILE), format!("{}\n", record)).unwrap();
+        drop(store);
+
+        let options = IpfsOptions {
+            ipfs_path: tmp.path().to_owned(),
+            ..IpfsOptions::inmemory_with_generated_keys()
+        };
+        let (ipfs, task) = UninitializedIpfs::<Types>::new(options)
+            .start()
+            .await
+            .unwrap();
+        tokio::spawn(task);
+
+        assert!(!root.join(BATCH_FILE).exists());
+        let store = FsDataStore::new(root);
+        assert_eq!(
+            store
+                .get(Column::Ipns, b"interrupted")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some(&b"value"[..])
+        );
+
+        ipfs.exit_daemon().await;
+    }
+}
@@ -0,0 +1,401 @@
+use crate::error::Error;
+use crate::repo::{Journal, Mutation};
+use async_trait::async_trait;
+use cid::Cid;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A journal larger than this is rewritten to contain only the unfinished mutations, unless most
+/// of it is still needed.
+const COMPACTION_THRESHOLD: u64 = 64 * 1024;
+
+/// [`Journal`] stored as an append-only text file with a record per line:
+///
+/// * `begin <id> <kind> <cid>` is written and synced to the disk before starting a mutation
+/// * `end <id>` is written along with the next synced records, as losing it only causes the
+///   mutation to be checked needlessly on the next start
+///
+/// The records are written and synced on the blocking threads, and the records of the mutations
+/// started while a sync is in progress are committed together by the next one.
+///
+/// A torn last line left by a crash is skipped. The file is rewritten to contain only the
+/// unfinished mutations when opened, when it has grown large, and after a failed write.
+#[derive(Debug)]
+pub struct FsJournal {
+    path: PathBuf,
+    state: Mutex<State>,
+    /// Held while writing and syncing, the writer committing the buffered records of the others.
+    writer: tokio::sync::Mutex<Writer>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_id: u64,
+    /// The `begin` records of the started but not yet completed mutations.
+    pending: BTreeMap<u64, String>,
+    /// Approximate length of the file, including the buffered records.
+    written: u64,
+    /// The records not yet written to the file.
+    buffer: Vec<u8>,
+    /// Number of the `begin` records appended to the buffer so far.
+    appended: u64,
+}
+
+#[derive(Debug, Default)]
+struct Writer {
+    file: Option<Arc<File>>,
+    /// Number of the `begin` records known to be synced to the disk.
+    synced: u64,
+    /// Set after a failed write, which could have left the file with a partial record and lost
+    /// the records of the others waiting on it, to rewrite the file on the next commit.
+    broken: bool,
+}
+
+#[async_trait]
+impl Journal for FsJournal {
+    fn new(path: PathBuf) -> Self {
+        FsJournal {
+            path,
+            state: Default::default(),
+            writer: Default::default(),
+        }
+    }
+
+    fn open(&self) -> Result<Vec<(u64, Mutation)>, Error> {
+        let mut contents = Vec::new();
+        match File::open(&self.path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut contents)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut unfinished = BTreeMap::new();
+        let mut next_id = 0;
+
+        for line in contents.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+            let line = String::from_utf8_lossy(line);
+            match parse_record(&line) {
+                Some(Record::Begin(id, mutation)) => {
+                    next_id = next_id.max(id + 1);
+                    unfinished.insert(id, mutation);
+                }
+                Some(Record::End(id)) => {
+                    unfinished.remove(&id);
+                }
+                None => warn!("skipping invalid journal record: {:?}", line),
+            }
+        }
+
+        // rewrite the journal not to replay the whole history on every start
+        let pending = unfinished
+            .iter()
+            .map(|(id, mutation)| (*id, begin_record(*id, mutation)))
+            .collect::<BTreeMap<_, _>>();
+        let records = pending.values().map(String::as_str).collect::<String>();
+        let file = rewrite(&self.path, records.as_bytes())?;
+
+        *self.state.lock().unwrap() = State {
+            next_id,
+            pending,
+            written: records.len() as u64,
+            ..Default::default()
+        };
+        *self.writer.try_lock()? = Writer {
+            file: Some(Arc::new(file)),
+            ..Default::default()
+        };
+
+        Ok(unfinished.into_iter().collect())
+    }
+
+    async fn begin(&self, mutation: &Mutation) -> Result<u64, Error> {
+        let (id, appended) = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_id;
+            let record = begin_record(id, mutation);
+
+            state.buffer.extend_from_slice(record.as_bytes());
+            state.next_id += 1;
+            state.written += record.len() as u64;
+            state.appended += 1;
+            state.pending.insert(id, record);
+
+            (id, state.appended)
+        };
+
+        let mut writer = self.writer.lock().await;
+        if writer.synced >= appended {
+            // committed along with the records of an earlier writer
+            return Ok(id);
+        }
+
+        if let Err(e) = self.commit(&mut writer).await {
+            // the mutation is not started, so its record is not needed
+            self.state.lock().unwrap().pending.remove(&id);
+            return Err(e);
+        }
+
+        Ok(id)
+    }
+
+    fn complete(&self, id: u64) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+
+        let record = format!("end {}\n", id);
+        state.buffer.extend_from_slice(record.as_bytes());
+        state.pending.remove(&id);
+        state.written += record.len() as u64;
+
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        let mut writer = self.writer.lock().await;
+        if writer.file.is_none() || (!writer.broken && self.state.lock().unwrap().buffer.is_empty())
+        {
+            return Ok(());
+        }
+        self.commit(&mut writer).await
+    }
+}
+
+impl FsJournal {
+    /// Writes and syncs the buffered records, or rewrites the whole file from the pending records
+    /// when it has grown large or a previous write failed.
+    async fn commit(&self, writer: &mut Writer) -> Result<(), Error> {
+        let file = writer
+            .file
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("journal has not been opened"))?;
+
+        let (records, appended, rewriting) = {
+            let mut state = self.state.lock().unwrap();
+            let live = state.pending.values().map(|r| r.len() as u64).sum::<u64>();
+            let rewriting = writer.broken || state.written > COMPACTION_THRESHOLD.max(2 * live);
+
+            let records = if rewriting {
+                state.buffer.clear();
+                state.written = live;
+                state
+                    .pending
+                    .values()
+                    .map(String::as_str)
+                    .collect::<String>()
+                    .into_bytes()
+            } else {
+                std::mem::take(&mut state.buffer)
+            };
+
+            (records, state.appended, rewriting)
+        };
+
+        let path = self.path.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            if rewriting {
+                rewrite(&path, &records).map(Some)
+            } else {
+                write_records(&file, &records, true).map(|_| None)
+            }
+        })
+        .await
+        .map_err(Error::from)
+        .and_then(|res| res.map_err(Error::from));
+
+        match res {
+            Ok(rewritten) => {
+                if let Some(file) = rewritten {
+                    writer.file = Some(Arc::new(file));
+                }
+                writer.broken = false;
+                writer.synced = appended;
+                Ok(())
+            }
+            Err(e) => {
+                writer.broken = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for FsJournal {
+    fn drop(&mut self) {
+        let buffer = std::mem::take(&mut self.state.get_mut().unwrap().buffer);
+        let file = match self.writer.get_mut().file.clone() {
+            Some(file) if !buffer.is_empty() => file,
+            _ => return,
+        };
+
+        // the completions not written by a flush are written on a blocking thread when possible
+        let write = move || {
+            if let Err(e) = write_records(&file, &buffer, false) {
+                warn!(
+                    "failed to write the completed mutations to the journal: {}",
+                    e
+                );
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(write)),
+            Err(_) => write(),
+        }
+    }
+}
+
+/// Appends the records to the journal file, syncing it if requested.
+fn write_records(mut file: &File, records: &[u8], sync: bool) -> Result<(), std::io::Error> {
+    file.write_all(records)?;
+    if sync {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Replaces the journal file with one containing only the records, returning it opened for
+/// appending.
+fn rewrite(path: &Path, records: &[u8]) -> Result<File, std::io::Error> {
+    let temp_path = path.with_extension("temp");
+    {
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(records)?;
+        temp.sync_all()?;
+    }
+    std::fs::rename(&temp_path, path)?;
+
+    OpenOptions::new().append(true).open(path)
+}
+
+enum Record {
+    Begin(u64, Mutation),
+    End(u64),
+}
+
+fn begin_record(id: u64, mutation: &Mutation) -> String {
+    format!("begin {} {} {}\n", id, mutation.kind(), mutation.cid())
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let mut parts = line.split(' ');
+    let record = match parts.next()? {
+        "begin" => {
+            let id = parts.next()?.parse().ok()?;
+            let kind = parts.next()?;
+            let cid = Cid::try_from(parts.next()?).ok()?;
+            Record::Begin(id, Mutation::from_kind(kind, cid)?)
+        }
+        "end" => Record::End(parts.next()?.parse().ok()?),
+        _ => return None,
+    };
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FsJournal;
+    use crate::repo::{Journal, Mutation};
+    use cid::Cid;
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn unfinished_mutations_survive_reopening() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("journal");
+
+        let first = Cid::try_from("QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap();
+        let second = Cid::try_from("QmX5S2xLu32K6WxWnyLeChQFbDHy79ULV9feJYH2Hy9bgp").unwrap();
+
+        let journal = FsJournal::new(path.clone());
+        assert!(journal.open().unwrap().is_empty());
+
+        let a = journal
+            .begin(&Mutation::PutBlock(first.clone()))
+            .await
+            .unwrap();
+        let b = journal
+            .begin(&Mutation::InsertRecursivePin(second.clone()))
+            .await
+            .unwrap();
+        journal.complete(a).unwrap();
+        journal.flush().await.unwrap();
+        drop(journal);
+
+        // simulate a crash in the middle of writing a record
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"begin 2 remove_bl")
+            .unwrap();
+
+        let journal = FsJournal::new(path.clone());
+        assert_eq!(
+            journal.open().unwrap(),
+            vec![(b, Mutation::InsertRecursivePin(second))]
+        );
+
+        let c = journal.begin(&Mutation::RemoveBlock(first)).await.unwrap();
+        assert!(c > b, "ids must not be reused");
+        journal.complete(c).unwrap();
+        journal.complete(b).unwrap();
+        journal.flush().await.unwrap();
+        drop(journal);
+
+        let journal = FsJournal::new(path);
+        assert!(journal.open().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn recovers_from_a_failed_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("journal");
+
+        let first = Cid::try_from("QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap();
+        let second = Cid::try_from("QmX5S2xLu32K6WxWnyLeChQFbDHy79ULV9feJYH2Hy9bgp").unwrap();
+
+        let journal = FsJournal::new(path.clone());
+        assert!(journal.open().unwrap().is_empty());
+
+        let a = journal
+            .begin(&Mutation::PutBlock(first.clone()))
+            .await
+            .unwrap();
+
+        // writing to a file opened only for reading fails
+        journal.writer.lock().await.file = Some(Arc::new(File::open(&path).unwrap()));
+        assert!(journal
+            .begin(&Mutation::RemoveBlock(first.clone()))
+            .await
+            .is_err());
+
+        // the next mutation rewrites the journal instead of failing as well
+        let c = journal
+            .begin(&Mutation::InsertRecursivePin(second.clone()))
+            .await
+            .unwrap();
+        journal.flush().await.unwrap();
+        drop(journal);
+
+        let journal = FsJournal::new(path);
+        assert_eq!(
+            journal.open().unwrap(),
+            vec![
+                (a, Mutation::PutBlock(first)),
+                (c, Mutation::InsertRecursivePin(second))
+            ]
+        );
+    }
+}
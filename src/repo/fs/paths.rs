@@ -36,6 +36,29 @@ pub fn filestem_to_block_cid(file_stem: Option<&std::ffi::OsStr>) -> Option<Cid>
     })
 }
 
+/// The path of the block in a go-ipfs flatfs blockstore: the blocks are named after their
+/// multihash as unpadded upper case base32, as go-ipfs does since the version 12 of its repo. The
+/// produced filename must be converted back to `Cid` using [`filestem_to_flatfs_cid`].
+pub fn flatfs_block_path(mut base: PathBuf, cid: &Cid) -> PathBuf {
+    let key = multibase::Base::Base32Upper.encode(cid.hash().as_bytes());
+    shard(&mut base, &key);
+
+    base.set_extension("data");
+    base
+}
+
+/// Decodes the file stem produced by [`flatfs_block_path`], ignoring errors. As only the multihash
+/// is stored, the block is returned as a raw CIDv1 like go-ipfs does.
+pub fn filestem_to_flatfs_cid(file_stem: Option<&std::ffi::OsStr>) -> Option<Cid> {
+    file_stem.and_then(|stem| stem.to_str()).and_then(|s| {
+        let bytes = multibase::Base::Base32Upper.decode(s).ok()?;
+        let mh = multihash::Multihash::from_bytes(bytes).ok()?;
+
+        // See filestem_to_block_cid for discusison on why the error is ignored
+        Some(Cid::new_v1(cid::Codec::Raw, mh))
+    })
+}
+
 /// Same as `block_path` except it doesn't canonicalize the cid to later version. The produced
 /// filename must be converted back to `Cid` using [`filestem_to_pin_cid`].
 pub fn pin_path(mut base: PathBuf, cid: &Cid) -> PathBuf {
@@ -57,6 +80,26 @@ pub fn filestem_to_pin_cid(file_stem: Option<&std::ffi::OsStr>) -> Option<Cid> {
     })
 }
 
+/// The path of the value of a datastore column key: the keys are stored unsharded as lower case
+/// base16 prefixed with `k`, which keeps the empty key representable and the file names sorting
+/// like the keys. The produced filename must be converted back using [`filestem_to_column_key`].
+pub fn column_key_path(mut base: PathBuf, key: &[u8]) -> PathBuf {
+    let mut name = String::with_capacity(1 + key.len() * 2);
+    name.push('k');
+    name.push_str(&multibase::Base::Base16Lower.encode(key));
+    base.push(name);
+    base.set_extension("value");
+    base
+}
+
+/// Decodes the file stem produced by [`column_key_path`], ignoring errors.
+pub fn filestem_to_column_key(file_stem: Option<&std::ffi::OsStr>) -> Option<Vec<u8>> {
+    file_stem
+        .and_then(|stem| stem.to_str())
+        .and_then(|s| s.strip_prefix('k'))
+        .and_then(|s| multibase::Base::Base16Lower.decode(s).ok())
+}
+
 /// second-to-last/2 sharding, just by taking the two characters from suffix ignoring the last
 /// character from an ASCII encoded key string to be prepended as the directory or "shard".
 ///
@@ -152,6 +195,43 @@ mod tests {
         assert_eq!(parsed, Some(cid_v1));
     }
 
+    #[test]
+    fn column_key_to_path_and_back() {
+        for key in &[&b""[..], b"head", b"a/\xff"] {
+            let path = super::column_key_path(PathBuf::from("ipns"), key);
+            assert_eq!(path.extension().unwrap(), "value");
+            let parsed = super::filestem_to_column_key(path.file_stem()).unwrap();
+            assert_eq!(&parsed, key);
+        }
+
+        let path = super::column_key_path(PathBuf::from("config"), b"head");
+        assert_eq!(path, Path::new("config/k68656164.value"));
+    }
+
+    #[test]
+    fn cid_to_flatfs_block_path_and_back() {
+        let cid_v0 = "QmTEn8ypAkbJXZUXCRHBorwF2jM8uTUW9yRLzrcQouSoD4";
+        let cid_v0 = Cid::try_from(cid_v0).unwrap();
+
+        let path = super::flatfs_block_path(PathBuf::from("blocks"), &cid_v0);
+
+        // the same path go-ipfs uses for the block
+        let expected = "blocks/2W/CIQERSKZQA5KVNY63DM6BYUERDSBKIADRNZLERPHZ2ZDCPDVOZUH2WY.data";
+        assert_eq!(path, Path::new(expected));
+
+        let parsed = super::filestem_to_flatfs_cid(path.file_stem()).unwrap();
+        assert_eq!(parsed.version(), cid::Version::V1);
+        assert_eq!(parsed.codec(), cid::Codec::Raw);
+        assert_eq!(parsed.hash(), cid_v0.hash());
+
+        // the cidv1 of the same multihash is the same block for go-ipfs
+        let cid_v1 = Cid::new_v1(cid::Codec::DagProtobuf, cid_v0.hash().to_owned());
+        assert_eq!(
+            super::flatfs_block_path(PathBuf::from("blocks"), &cid_v1),
+            path
+        );
+    }
+
     #[test]
     fn invalid_block_path_is_silently_ignored() {
         let block_path = Path::new("another_root/ba/foobar.data");
@@ -6,7 +6,7 @@ use async_trait::async_trait;
 use cid::Cid;
 use core::convert::TryFrom;
 use futures::stream::TryStreamExt;
-use hash_hasher::HashedSet;
+use hash_hasher::{HashedMap, HashedSet};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -27,28 +27,7 @@ impl PinStore for FsDataStore {
             return Ok(true);
         }
 
-        let st = self.list_pinfiles().await.try_filter_map(|(cid, mode)| {
-            futures::future::ready(if mode == PinMode::Recursive {
-                Ok(Some(cid))
-            } else {
-                Ok(None)
-            })
-        });
-
-        futures::pin_mut!(st);
-
-        while let Some(recursive) = StreamExt::try_next(&mut st).await? {
-            // TODO: it might be much better to just deserialize the vec one by one and comparing while
-            // going
-            let (_, references) = read_recursively_pinned(self.path.clone(), recursive).await?;
-
-            // if we always wrote down the cids in some order we might be able to binary search?
-            if references.into_iter().any(move |x| x == *cid) {
-                return Ok(true);
-            }
-        }
-
-        Ok(false)
+        Ok(self.indirect_pins().await?.contains_key(cid))
     }
 
     async fn insert_direct_pin(&self, target: &Cid) -> Result<(), Error> {
@@ -94,7 +73,7 @@ impl PinStore for FsDataStore {
 
         let span = tracing::Span::current();
 
-        tokio::task::spawn_blocking(move || {
+        let result = tokio::task::spawn_blocking(move || {
             let _permit = permit; // again move to the threadpool thread
             let _entered = span.enter();
 
@@ -141,7 +120,10 @@ impl PinStore for FsDataStore {
 
             Ok::<_, Error>(())
         })
-        .await??;
+        .await;
+
+        self.recursive_pins_changed();
+        result??;
 
         Ok(())
     }
@@ -188,7 +170,7 @@ impl PinStore for FsDataStore {
 
         let span = tracing::Span::current();
 
-        tokio::task::spawn_blocking(move || {
+        let result = tokio::task::spawn_blocking(move || {
             let _permit = permit; // move into threadpool thread
             let _entered = span.enter();
 
@@ -229,7 +211,10 @@ impl PinStore for FsDataStore {
                 Ok(())
             }
         })
-        .await??;
+        .await;
+
+        self.recursive_pins_changed();
+        result??;
 
         Ok(())
     }
@@ -241,10 +226,14 @@ impl PinStore for FsDataStore {
         // no locking, dirty reads are probably good enough until gc
         let cids = self.list_pinfiles().await;
 
-        let path = self.path.clone();
-
         let requirement = PinModeRequirement::from(requirement);
 
+        let indirect = if requirement.is_indirect_or_any() {
+            Some(self.indirect_pins().await)
+        } else {
+            None
+        };
+
         // depending on what was queried we must iterate through the results in the order of
         // recursive, direct and indirect.
         //
@@ -257,12 +246,8 @@ impl PinStore for FsDataStore {
             // keep track of all returned not to give out duplicate cids
             let mut returned: HashedSet<Cid> = HashedSet::default();
 
-            // the set of recursive will be interesting after all others
-            let mut recursive: HashedSet<Cid> = HashedSet::default();
             let mut direct: HashedSet<Cid> = HashedSet::default();
 
-            let collect_recursive_for_indirect = requirement.is_indirect_or_any();
-
             futures::pin_mut!(cids);
 
             while let Some((cid, mode)) = StreamExt::try_next(&mut cids).await? {
@@ -270,9 +255,6 @@ impl PinStore for FsDataStore {
                 let matches = requirement.matches(&mode);
 
                 if mode == PinMode::Recursive {
-                    if collect_recursive_for_indirect {
-                        recursive.insert(cid.clone());
-                    }
                     if matches && returned.insert(cid.clone()) {
                         // the recursive pins can always be returned right away since they have
                         // the highest priority in this listing or output
@@ -296,26 +278,19 @@ impl PinStore for FsDataStore {
 
             trace!(unique = returned.len(), "completed listing direct");
 
-            if !collect_recursive_for_indirect {
-                // we didn't collect the recursive to list the indirect so, done.
-                return;
-            }
-
-            // the threadpool passing adds probably some messaging latency, maybe run small
-            // amount in parallel?
-            let mut recursive = futures::stream::iter(recursive.into_iter().map(Ok))
-                .map_ok(move |cid| read_recursively_pinned(path.clone(), cid))
-                .try_buffer_unordered(4);
+            let indirect = match indirect {
+                Some(indirect) => indirect?,
+                // the indirect were not requested so, done.
+                None => return,
+            };
 
-            while let Some((_, next_batch)) = StreamExt::try_next(&mut recursive).await? {
-                for indirect in next_batch {
-                    if returned.insert(indirect.clone()) {
-                        yield (indirect, PinMode::Indirect);
-                    }
+            for cid in indirect.keys() {
+                if returned.insert(cid.clone()) {
+                    yield (cid.clone(), PinMode::Indirect);
                 }
-
-                trace!(unique = returned.len(), "completed batch of indirect");
             }
+
+            trace!(unique = returned.len(), "completed listing indirect");
         };
 
         Box::pin(st)
@@ -398,35 +373,16 @@ impl PinStore for FsDataStore {
                 "query trying to find remaining indirect pins"
             );
 
-            let recursives = self
-                .list_pinfiles()
-                .await
-                .try_filter_map(|(cid, mode)| {
-                    futures::future::ready(if mode == PinMode::Recursive {
-                        Ok(Some(cid))
-                    } else {
-                        Ok(None)
-                    })
-                })
-                .map_ok(|cid| read_recursively_pinned(self.path.clone(), cid))
-                .try_buffer_unordered(4);
-
-            futures::pin_mut!(recursives);
-
-            'out: while let Some((referring, references)) =
-                StreamExt::try_next(&mut recursives).await?
-            {
-                // FIXME: maybe binary search?
-                for cid in references {
-                    if let Some(index) = remaining.remove(&cid) {
-                        response[index] = Some((cid, PinKind::IndirectFrom(referring.clone())));
+            let indirect = self.indirect_pins().await?;
 
-                        if remaining.is_empty() {
-                            break 'out;
-                        }
-                    }
+            remaining.retain(|cid, index| match indirect.get(cid) {
+                Some(referring) => {
+                    response[*index] =
+                        Some((cid.clone(), PinKind::IndirectFrom(referring.clone())));
+                    false
                 }
-            }
+                None => true,
+            });
         }
 
         if let Some((cid, _)) = remaining.into_iter().next() {
@@ -482,6 +438,64 @@ impl FsDataStore {
                 futures::future::ready(Ok(maybe_tuple))
             })
     }
+
+    /// Returns the blocks referenced by the recursive pins, each mapped to one of the recursive pins
+    /// referencing it. The recursive pin files are read again only when the pins have changed since
+    /// the previous call, or when there were too many references to cache.
+    async fn indirect_pins(&self) -> Result<Arc<HashedMap<Cid, Cid>>, Error> {
+        let generation = {
+            let indirect = self.indirect.lock().unwrap();
+            if let Some(refs) = &indirect.refs {
+                return Ok(Arc::clone(refs));
+            }
+            indirect.generation
+        };
+
+        let recursive = self.list_pinfiles().await.try_filter_map(|(cid, mode)| {
+            futures::future::ready(if mode == PinMode::Recursive {
+                Ok(Some(cid))
+            } else {
+                Ok(None)
+            })
+        });
+
+        futures::pin_mut!(recursive);
+
+        let mut refs = HashedMap::default();
+        while let Some(root) = StreamExt::try_next(&mut recursive).await? {
+            let (root, references) = read_recursively_pinned(self.path.clone(), root).await?;
+            for cid in references {
+                refs.entry(cid).or_insert_with(|| root.clone());
+            }
+        }
+
+        let refs = Arc::new(refs);
+        let mut indirect = self.indirect.lock().unwrap();
+        // the pins read may already be outdated if they changed meanwhile
+        if indirect.generation == generation && refs.len() <= MAX_CACHED_INDIRECT_PINS {
+            indirect.refs = Some(Arc::clone(&refs));
+        }
+        Ok(refs)
+    }
+
+    /// Drops the cached indirect pins after a recursive pin has been added or removed.
+    pub(super) fn recursive_pins_changed(&self) {
+        let mut indirect = self.indirect.lock().unwrap();
+        indirect.generation += 1;
+        indirect.refs = None;
+    }
+}
+
+/// The most indirect pins kept in the [`IndirectPins`]; with more of them the recursive pin files
+/// are read again on every lookup.
+const MAX_CACHED_INDIRECT_PINS: usize = 1 << 20;
+
+/// The cached blocks referenced by the recursive pins, see [`FsDataStore::indirect_pins`].
+#[derive(Debug, Default)]
+pub(super) struct IndirectPins {
+    /// Incremented whenever the recursive pins change.
+    generation: u64,
+    refs: Option<Arc<HashedMap<Cid, Cid>>>,
 }
 
 /// Reads our serialized format for recusive pins, which is JSON array of stringified Cids.
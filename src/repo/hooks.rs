@@ -0,0 +1,177 @@
+//! Hooks run on the block reads and writes of the [`Repo`](super::Repo).
+use crate::error::Error;
+use crate::repo::BlockPut;
+use crate::Block;
+use async_trait::async_trait;
+use cid::Cid;
+use libp2p::core::PeerId;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+
+/// On whose behalf a block is read or written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockAccessor {
+    /// The node itself, through the [`crate::Ipfs`] facade or its own maintenance like
+    /// [`crate::Ipfs::repo_fsck`].
+    Local,
+    /// A peer over bitswap, either sending the block or wanting it.
+    Peer(PeerId),
+}
+
+/// Hooks on the block accesses, registered with [`crate::Ipfs::add_block_hook`], allowing for
+/// example access control, accounting or replicating the blocks to another store without a
+/// [`super::BlockStore`] of its own.
+///
+/// The hooks are awaited in the order they were registered, on the task accessing the block, so
+/// any slow work should be spawned to run in the background.
+#[async_trait]
+pub trait BlockHook: Debug + Send + Sync + 'static {
+    /// Called before the block is stored. Returning an error fails the put and the block is not
+    /// stored.
+    async fn before_put(&self, _block: &Block, _accessor: &BlockAccessor) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called after the block has been stored, `put` telling if the block was already stored.
+    async fn after_put(&self, _block: &Block, _put: &BlockPut, _accessor: &BlockAccessor) {}
+
+    /// Called before the block is read. Returning an error fails the read, or for a peer, the
+    /// block is not sent.
+    async fn before_get(&self, _cid: &Cid, _accessor: &BlockAccessor) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called after the block has been read or, for [`crate::Ipfs::get_block`], fetched.
+    async fn after_get(&self, _block: &Block, _accessor: &BlockAccessor) {}
+}
+
+/// The registered [`BlockHook`]s.
+#[derive(Debug, Default)]
+pub(crate) struct BlockHooks {
+    hooks: RwLock<Vec<Arc<dyn BlockHook>>>,
+}
+
+impl BlockHooks {
+    pub(crate) fn add(&self, hook: Arc<dyn BlockHook>) {
+        self.hooks.write().unwrap().push(hook);
+    }
+
+    /// Returns the hooks to run, so that the lock is not held over the awaits.
+    fn snapshot(&self) -> Vec<Arc<dyn BlockHook>> {
+        self.hooks.read().unwrap().clone()
+    }
+
+    pub(crate) async fn before_put(
+        &self,
+        block: &Block,
+        accessor: &BlockAccessor,
+    ) -> Result<(), Error> {
+        for hook in self.snapshot() {
+            hook.before_put(block, accessor).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn after_put(&self, block: &Block, put: &BlockPut, accessor: &BlockAccessor) {
+        for hook in self.snapshot() {
+            hook.after_put(block, put, accessor).await;
+        }
+    }
+
+    pub(crate) async fn before_get(
+        &self,
+        cid: &Cid,
+        accessor: &BlockAccessor,
+    ) -> Result<(), Error> {
+        for hook in self.snapshot() {
+            hook.before_get(cid, accessor).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn after_get(&self, block: &Block, accessor: &BlockAccessor) {
+        for hook in self.snapshot() {
+            hook.after_get(block, accessor).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockAccessor, BlockHook};
+    use crate::repo::BlockPut;
+    use crate::{Block, Error, Node};
+    use async_trait::async_trait;
+    use cid::{Cid, Codec};
+    use multihash::Sha2_256;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        denied: Option<Cid>,
+        events: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl BlockHook for Recorder {
+        async fn after_put(&self, block: &Block, put: &BlockPut, accessor: &BlockAccessor) {
+            assert_eq!(accessor, &BlockAccessor::Local);
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("put {} {:?}", block.cid, put));
+        }
+
+        async fn before_get(&self, cid: &Cid, _accessor: &BlockAccessor) -> Result<(), Error> {
+            if self.denied.as_ref() == Some(cid) {
+                return Err(anyhow::anyhow!("access denied"));
+            }
+            Ok(())
+        }
+
+        async fn after_get(&self, block: &Block, _accessor: &BlockAccessor) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("get {}", block.cid));
+        }
+    }
+
+    fn block(data: &[u8]) -> Block {
+        let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(data));
+        Block::new(data.to_vec().into_boxed_slice(), cid)
+    }
+
+    #[tokio::test]
+    async fn hooks_see_and_deny_accesses() {
+        let ipfs = Node::new("test_node").await;
+
+        let (allowed, denied) = (block(b"allowed"), block(b"denied"));
+        let recorder = Arc::new(Recorder {
+            denied: Some(denied.cid.clone()),
+            ..Default::default()
+        });
+        ipfs.add_block_hook(recorder.clone());
+
+        ipfs.put_block(allowed.clone()).await.unwrap();
+        ipfs.put_block(allowed.clone()).await.unwrap();
+        ipfs.put_block(denied.clone()).await.unwrap();
+
+        assert_eq!(ipfs.get_block(&allowed.cid).await.unwrap(), allowed);
+        assert!(ipfs.get_block(&denied.cid).await.is_err());
+        assert!(ipfs
+            .get_blocks_now(&[allowed.cid.clone(), denied.cid.clone()])
+            .await
+            .is_err());
+
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            vec![
+                format!("put {} NewBlock", allowed.cid),
+                format!("put {} Existed", allowed.cid),
+                format!("put {} NewBlock", denied.cid),
+                format!("get {}", allowed.cid),
+            ]
+        );
+    }
+}
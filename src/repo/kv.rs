@@ -1,4 +1,4 @@
-use super::{Column, DataStore, PinModeRequirement};
+use super::{Batch, BatchOp, Column, DataStore, KeyRange, PinModeRequirement};
 use crate::error::Error;
 use crate::repo::{PinKind, PinMode, PinStore, References};
 use async_trait::async_trait;
@@ -11,20 +11,24 @@ use sled::{
         ConflictableTransactionError, TransactionError, TransactionResult, TransactionalTree,
         UnabortableTransactionError,
     },
-    Config as DbConfig, Db, Mode as DbMode,
+    Config as DbConfig, Db, Mode as DbMode, Tree,
 };
 use std::collections::BTreeSet;
 use std::convert::Infallible;
 use std::path::PathBuf;
 use std::str::{self, FromStr};
 
-/// [`sled`] based pinstore implementation. Implements datastore which errors for each call.
-/// Currently feature-gated behind `sled_data_store` feature in the [`crate::Types`], usable
-/// directly in custom type configurations.
+/// The KvBlockStore implementation
+mod blocks;
+pub use blocks::KvBlockStore;
+
+/// [`sled`] based pinstore and datastore implementation. Currently feature-gated behind
+/// `sled_data_store` feature in the [`crate::Types`], usable directly in custom type
+/// configurations.
 ///
-/// Current schema is to use the the default tree for storing pins, which are serialized as
-/// [`get_pin_key`]. Depending on the kind of pin values are generated by [`direct_value`],
-/// [`recursive_value`], and [`indirect_value`].
+/// Current schema is to use the the default tree for storing pins and a tree per [`Column`] for
+/// the datastore values. The pins are serialized as [`get_pin_key`]. Depending on the kind of pin
+/// values are generated by [`direct_value`], [`recursive_value`], and [`indirect_value`].
 ///
 /// [`sled`]: https://github.com/spacejam/sled
 #[derive(Debug)]
@@ -38,6 +42,11 @@ impl KvDataStore {
     fn get_db(&self) -> &Db {
         self.db.get().unwrap()
     }
+
+    /// Each of the columns is kept in a separate tree, named after the column namespace.
+    fn get_column(&self, col: Column) -> Result<Tree, Error> {
+        Ok(self.get_db().open_tree(col.namespace())?)
+    }
 }
 
 #[async_trait]
@@ -68,23 +77,114 @@ impl DataStore for KvDataStore {
     }
 
     /// Checks if a key is present in the datastore.
-    async fn contains(&self, _col: Column, _key: &[u8]) -> Result<bool, Error> {
-        Err(anyhow::anyhow!("not implemented"))
+    async fn contains(&self, col: Column, key: &[u8]) -> Result<bool, Error> {
+        let tree = self.get_column(col)?;
+        let key = key.to_owned();
+        tokio::task::spawn_blocking(move || Ok(tree.contains_key(key)?)).await?
     }
 
     /// Returns the value associated with a key from the datastore.
-    async fn get(&self, _col: Column, _key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
-        Err(anyhow::anyhow!("not implemented"))
+    async fn get(&self, col: Column, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let tree = self.get_column(col)?;
+        let key = key.to_owned();
+        tokio::task::spawn_blocking(move || Ok(tree.get(key)?.map(|value| value.to_vec()))).await?
     }
 
     /// Puts the value under the key in the datastore.
-    async fn put(&self, _col: Column, _key: &[u8], _value: &[u8]) -> Result<(), Error> {
-        Err(anyhow::anyhow!("not implemented"))
+    async fn put(&self, col: Column, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let tree = self.get_column(col)?;
+        let key = key.to_owned();
+        let value = value.to_owned();
+        tokio::task::spawn_blocking(move || {
+            tree.insert(key, value)?;
+            tree.flush()?;
+            Ok(())
+        })
+        .await?
     }
 
     /// Removes a key-value pair from the datastore.
-    async fn remove(&self, _col: Column, _key: &[u8]) -> Result<(), Error> {
-        Err(anyhow::anyhow!("not implemented"))
+    async fn remove(&self, col: Column, key: &[u8]) -> Result<(), Error> {
+        let tree = self.get_column(col)?;
+        let key = key.to_owned();
+        tokio::task::spawn_blocking(move || {
+            tree.remove(key)?;
+            tree.flush()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Returns the key-value pairs of the column within the range, ordered by the key.
+    async fn scan(&self, col: Column, range: &KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        let tree = self.get_column(col)?;
+        let limit = range.max_results().unwrap_or(usize::MAX);
+        let bounds = match range.bounds() {
+            Some(bounds) => bounds,
+            None => return Ok(Vec::new()),
+        };
+
+        tokio::task::spawn_blocking(move || {
+            tree.range(bounds)
+                .take(limit)
+                .map(|res| {
+                    res.map(|(k, v)| (k.to_vec(), v.to_vec()))
+                        .map_err(Error::from)
+                })
+                .collect()
+        })
+        .await?
+    }
+
+    /// Applies all of the modifications in the batch in a single transaction over the modified
+    /// columns.
+    async fn write_batch(&self, batch: Batch) -> Result<(), Error> {
+        use sled::Transactional;
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let columns = batch.columns();
+        let trees = columns
+            .iter()
+            .map(|col| self.get_column(*col))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let span = tracing::Span::current();
+
+        tokio::task::spawn_blocking(move || {
+            let span = tracing::trace_span!(parent: &span, "blocking");
+            let _g = span.enter();
+
+            let res = trees.as_slice().transaction(|tx_trees| {
+                for op in batch.ops() {
+                    let index = columns
+                        .iter()
+                        .position(|col| *col == op.column())
+                        .expect("all of the columns were gathered");
+
+                    match op {
+                        BatchOp::Put(_, key, value) => {
+                            tx_trees[index].insert(key.as_slice(), value.as_slice())?;
+                        }
+                        BatchOp::Remove(_, key) => {
+                            tx_trees[index].remove(key.as_slice())?;
+                        }
+                    }
+                }
+                Ok(())
+            });
+
+            launder(res)?;
+
+            for tree in &trees {
+                tree.flush()?;
+            }
+
+            Ok(())
+        })
+        .await?
     }
 
     /// Wipes the datastore.
@@ -315,7 +415,13 @@ impl PinStore for KvDataStore {
 
             // this probably doesn't need to be transactional? well, perhaps transactional reads would
             // be the best, not sure what is the guaratee for in-sequence key reads.
-            let iter = db.range::<String, std::ops::RangeFull>(..);
+            //
+            // the keys are grouped by the mode, so only the required mode needs to be read.
+            let prefix = match requirement {
+                Some(mode) => format!("pin.{}.", pin_mode_literal(&mode)),
+                None => String::from("pin."),
+            };
+            let iter = db.scan_prefix(prefix);
 
             let requirement = PinModeRequirement::from(requirement);
 
@@ -524,3 +630,6 @@ fn is_not_pinned_or_pinned_indirectly(
 
 #[cfg(test)]
 crate::pinstore_interface_tests!(common_tests, crate::repo::kv::KvDataStore::new);
+
+#[cfg(test)]
+crate::datastore_interface_tests!(datastore_common_tests, crate::repo::kv::KvDataStore::new);
@@ -0,0 +1,203 @@
+use super::launder;
+use crate::error::Error;
+use crate::repo::{BlockPut, BlockRm, BlockRmError, BlockStore};
+use crate::Block;
+use async_trait::async_trait;
+use cid::Cid;
+use futures::stream::{BoxStream, StreamExt};
+use once_cell::sync::OnceCell;
+use sled::{Config as DbConfig, Db, Mode as DbMode, Transactional, Tree};
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+/// [`sled`] based blockstore, keeping all of the blocks in a single database under the
+/// blockstore directory. Selected for the [`crate::Types`] with
+/// [`crate::repo::BlockBackend::Sled`].
+///
+/// Unlike the [`crate::repo::fs::FsBlockStore`], a block is either fully written or missing
+/// after a crash, and there are no files to manage for each block. Like in the other
+/// blockstores, the blocks are found by the multihash, so a block put with a Cid of one version
+/// is also found with the other. The data and the Cid of the block, as it was first put, are
+/// kept in separate trees which are updated in a single transaction.
+///
+/// [`sled`]: https://github.com/spacejam/sled
+#[derive(Debug)]
+pub struct KvBlockStore {
+    path: PathBuf,
+    db: OnceCell<Db>,
+}
+
+impl KvBlockStore {
+    /// Returns the tree of the block data and the tree of the Cids, both keyed by the multihash.
+    fn trees(&self) -> Result<(Tree, Tree), Error> {
+        let db = self
+            .db
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("blockstore has not been initialized"))?;
+
+        Ok((db.open_tree("blocks")?, db.open_tree("cids")?))
+    }
+}
+
+fn block_key(cid: &Cid) -> Vec<u8> {
+    cid.hash().as_bytes().to_vec()
+}
+
+#[async_trait]
+impl BlockStore for KvBlockStore {
+    fn new(path: PathBuf) -> Self {
+        KvBlockStore {
+            path,
+            db: Default::default(),
+        }
+    }
+
+    async fn init(&self) -> Result<(), Error> {
+        let path = self.path.clone();
+        let db = tokio::task::spawn_blocking(move || {
+            DbConfig::new()
+                .mode(DbMode::HighThroughput)
+                .path(path)
+                .open()
+        })
+        .await??;
+
+        match self.db.set(db) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(anyhow::anyhow!("failed to init sled")),
+        }
+    }
+
+    async fn open(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn contains(&self, cid: &Cid) -> Result<bool, Error> {
+        let (blocks, _) = self.trees()?;
+        let key = block_key(cid);
+        tokio::task::spawn_blocking(move || Ok(blocks.contains_key(key)?)).await?
+    }
+
+    async fn get(&self, cid: &Cid) -> Result<Option<Block>, Error> {
+        let (blocks, _) = self.trees()?;
+        let key = block_key(cid);
+        let cid = cid.to_owned();
+
+        tokio::task::spawn_blocking(move || {
+            Ok(blocks
+                .get(key)?
+                .map(|data| Block::new(data.to_vec().into_boxed_slice(), cid)))
+        })
+        .await?
+    }
+
+    async fn put(&self, block: Block) -> Result<(Cid, BlockPut), Error> {
+        let (blocks, cids) = self.trees()?;
+
+        tokio::task::spawn_blocking(move || {
+            let key = block_key(&block.cid);
+
+            let res = (&blocks, &cids).transaction(|(tx_blocks, tx_cids)| {
+                if tx_blocks.get(key.as_slice())?.is_some() {
+                    return Ok(BlockPut::Existed);
+                }
+                tx_blocks.insert(key.as_slice(), block.data())?;
+                tx_cids.insert(key.as_slice(), block.cid.to_bytes())?;
+                Ok(BlockPut::NewBlock)
+            });
+
+            let put = launder(res)?;
+
+            if put == BlockPut::NewBlock {
+                // the trees share the log, flushing one of them persists both
+                blocks.flush()?;
+            }
+
+            Ok((block.cid, put))
+        })
+        .await?
+    }
+
+    async fn remove(&self, cid: &Cid) -> Result<Result<BlockRm, BlockRmError>, Error> {
+        let (blocks, cids) = self.trees()?;
+        let key = block_key(cid);
+        let cid = cid.to_owned();
+
+        tokio::task::spawn_blocking(move || {
+            let res = (&blocks, &cids).transaction(|(tx_blocks, tx_cids)| {
+                let removed = tx_blocks.remove(key.as_slice())?.is_some();
+                tx_cids.remove(key.as_slice())?;
+                Ok(removed)
+            });
+
+            if !launder(res)? {
+                return Ok(Err(BlockRmError::NotFound(cid)));
+            }
+
+            blocks.flush()?;
+            Ok(Ok(BlockRm::Removed(cid)))
+        })
+        .await?
+    }
+
+    async fn list(&self) -> Result<Vec<Cid>, Error> {
+        let (_, cids) = self.trees()?;
+
+        tokio::task::spawn_blocking(move || {
+            cids.iter()
+                .values()
+                .map(|cid| -> Result<Cid, Error> { Ok(Cid::try_from(cid?.as_ref())?) })
+                .collect()
+        })
+        .await?
+    }
+
+    async fn iter(&self) -> Result<BoxStream<'static, Result<(Cid, u64), Error>>, Error> {
+        let (blocks, cids) = self.trees()?;
+
+        // the trees are read a block at a time as the stream is polled, mostly from the cache
+        let iter = cids.iter().map(move |entry| -> Result<(Cid, u64), Error> {
+            let (key, cid) = entry?;
+            let cid = Cid::try_from(cid.as_ref())?;
+            let len = blocks
+                .get(key)?
+                .map(|data| data.len() as u64)
+                .unwrap_or_default();
+            Ok((cid, len))
+        });
+
+        Ok(futures::stream::iter(iter).boxed())
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, Error> {
+        let (blocks, _) = self.trees()?;
+        let cids = cids.to_vec();
+
+        tokio::task::spawn_blocking(move || {
+            cids.into_iter()
+                .map(|cid| -> Result<Option<Block>, Error> {
+                    let data = blocks.get(block_key(&cid))?;
+                    Ok(data.map(|data| Block::new(data.to_vec().into_boxed_slice(), cid)))
+                })
+                .collect()
+        })
+        .await?
+    }
+
+    async fn wipe(&self) {
+        if let Ok((blocks, cids)) = self.trees() {
+            let _ = tokio::task::spawn_blocking(move || {
+                let _ = blocks.clear();
+                let _ = cids.clear();
+            })
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+crate::blockstore_interface_tests!(
+    blockstore_common_tests,
+    crate::repo::kv::KvBlockStore::new,
+    persistent
+);
@@ -1,12 +1,13 @@
 //! Volatile memory backed repo
 use crate::error::Error;
 use crate::repo::{
-    BlockPut, BlockStore, Column, DataStore, Lock, LockError, PinKind, PinMode, PinModeRequirement,
-    PinStore,
+    Batch, BatchOp, BlockPut, BlockStore, Column, DataStore, Journal, KeyRange, Lock, LockError,
+    Mutation, PinKind, PinMode, PinModeRequirement, PinStore,
 };
 use crate::Block;
 use async_trait::async_trait;
 use cid::Cid;
+use futures::stream::BoxStream;
 use hash_hasher::HashedMap;
 use std::convert::TryFrom;
 use std::path::PathBuf;
@@ -17,7 +18,8 @@ use std::collections::hash_map::Entry;
 
 // FIXME: Transition to Persistent Map to make iterating more consistent
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 /// Describes an in-memory block store.
@@ -90,6 +92,27 @@ impl BlockStore for MemBlockStore {
         Ok(guard.iter().map(|(cid, _block)| cid.0.clone()).collect())
     }
 
+    async fn iter(&self) -> Result<BoxStream<'static, Result<(Cid, u64), Error>>, Error> {
+        use futures::stream::StreamExt;
+
+        // there is nothing to gain from laziness here; take a snapshot not to hold the lock
+        let guard = self.blocks.lock().await;
+        let snapshot = guard
+            .iter()
+            .map(|(cid, block)| Ok((cid.0.clone(), block.data().len() as u64)))
+            .collect::<Vec<_>>();
+
+        Ok(futures::stream::iter(snapshot).boxed())
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, Error> {
+        let guard = self.blocks.lock().await;
+        Ok(cids
+            .iter()
+            .map(|cid| guard.get(&RepoCid(cid.to_owned())).cloned())
+            .collect())
+    }
+
     async fn wipe(&self) {
         self.blocks.lock().await.clear();
     }
@@ -98,7 +121,8 @@ impl BlockStore for MemBlockStore {
 /// Describes an in-memory `DataStore`.
 #[derive(Debug, Default)]
 pub struct MemDataStore {
-    ipns: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+    /// The columns are kept ordered for the range scans.
+    columns: Mutex<HashMap<Column, BTreeMap<Vec<u8>, Vec<u8>>>>,
     // this could also be PinDocument however doing any serialization allows to see the required
     // error types easier
     pin: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
@@ -405,39 +429,78 @@ impl DataStore for MemDataStore {
     }
 
     async fn contains(&self, col: Column, key: &[u8]) -> Result<bool, Error> {
-        let map = match col {
-            Column::Ipns => &self.ipns,
-        };
-        let contains = map.lock().await.contains_key(key);
+        let columns = self.columns.lock().await;
+        let contains = columns
+            .get(&col)
+            .map(|map| map.contains_key(key))
+            .unwrap_or(false);
         Ok(contains)
     }
 
     async fn get(&self, col: Column, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
-        let map = match col {
-            Column::Ipns => &self.ipns,
-        };
-        let value = map.lock().await.get(key).map(|value| value.to_owned());
+        let columns = self.columns.lock().await;
+        let value = columns
+            .get(&col)
+            .and_then(|map| map.get(key))
+            .map(|value| value.to_owned());
         Ok(value)
     }
 
     async fn put(&self, col: Column, key: &[u8], value: &[u8]) -> Result<(), Error> {
-        let map = match col {
-            Column::Ipns => &self.ipns,
-        };
-        map.lock().await.insert(key.to_owned(), value.to_owned());
+        let mut columns = self.columns.lock().await;
+        columns
+            .entry(col)
+            .or_default()
+            .insert(key.to_owned(), value.to_owned());
         Ok(())
     }
 
     async fn remove(&self, col: Column, key: &[u8]) -> Result<(), Error> {
-        let map = match col {
-            Column::Ipns => &self.ipns,
+        let mut columns = self.columns.lock().await;
+        if let Some(map) = columns.get_mut(&col) {
+            map.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn scan(&self, col: Column, range: &KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        let columns = self.columns.lock().await;
+
+        let (map, bounds) = match (columns.get(&col), range.bounds()) {
+            (Some(map), Some(bounds)) => (map, bounds),
+            _ => return Ok(Vec::new()),
         };
-        map.lock().await.remove(key);
+
+        Ok(map
+            .range(bounds)
+            .take(range.max_results().unwrap_or(usize::MAX))
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect())
+    }
+
+    async fn write_batch(&self, batch: Batch) -> Result<(), Error> {
+        // holding the lock makes the batch appear atomic to the readers
+        let mut columns = self.columns.lock().await;
+        for op in batch.ops() {
+            match op {
+                BatchOp::Put(col, key, value) => {
+                    columns
+                        .entry(*col)
+                        .or_default()
+                        .insert(key.to_owned(), value.to_owned());
+                }
+                BatchOp::Remove(col, key) => {
+                    if let Some(map) = columns.get_mut(col) {
+                        map.remove(key);
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
     async fn wipe(&self) {
-        self.ipns.lock().await.clear();
+        self.columns.lock().await.clear();
         self.pin.lock().await.clear();
     }
 }
@@ -675,9 +738,44 @@ impl Lock for MemLock {
     }
 }
 
+/// Used for in memory repos; there is nothing to recover after a crash so the mutations are not
+/// recorded anywhere.
+#[derive(Debug, Default)]
+pub struct MemJournal {
+    next_id: AtomicU64,
+}
+
+#[async_trait]
+impl Journal for MemJournal {
+    fn new(_path: PathBuf) -> Self {
+        Self::default()
+    }
+
+    fn open(&self) -> Result<Vec<(u64, Mutation)>, Error> {
+        Ok(Vec::new())
+    }
+
+    async fn begin(&self, _mutation: &Mutation) -> Result<u64, Error> {
+        Ok(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn complete(&self, _id: u64) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 crate::pinstore_interface_tests!(common_tests, crate::repo::mem::MemDataStore::new);
 
+#[cfg(test)]
+crate::datastore_interface_tests!(datastore_common_tests, crate::repo::mem::MemDataStore::new);
+
+#[cfg(test)]
+crate::blockstore_interface_tests!(
+    blockstore_common_tests,
+    crate::repo::mem::MemBlockStore::new
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,349 @@
+//! Versioning of the on-disk format of the repo, and the forward migrations between the versions.
+//!
+//! The version of the repo is stored in the `version` file directly under the repo path, like
+//! go-ipfs does. When the repo is opened, the migrations from the stored version up to
+//! [`REPO_VERSION`] are run in order, see [`MigrationMode`]. The `version` file is updated after
+//! each of the migrations, so an interrupted migration is continued from the same migration on
+//! the next open; the migrations need to be safe to run again after an interruption. A repo of a
+//! newer version than [`REPO_VERSION`] is never opened.
+//!
+//! A change to the on-disk format bumps [`REPO_VERSION`] and adds the migration from the previous
+//! version to `MIGRATIONS`.
+use crate::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The version of the on-disk format of the repos created by this version of the crate.
+pub const REPO_VERSION: u32 = 1;
+
+/// The version of the repos created before the `version` file was introduced.
+const UNVERSIONED: u32 = 1;
+
+/// The file storing the version of the repo.
+const VERSION_FILE: &str = "version";
+
+/// The migrations from each of the older versions to the next one, in order.
+const MIGRATIONS: &[Migration] = &[];
+
+/// A forward migration of the repo from the version `from` to the next one.
+#[derive(Clone, Copy)]
+struct Migration {
+    from: u32,
+    description: &'static str,
+    run: fn(&Path) -> Result<(), Error>,
+}
+
+/// Whether the migrations needed by an outdated repo are run when the repo is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationMode {
+    /// Run the migrations before opening the repo.
+    Run,
+    /// Only report the migrations which would be run, failing to open an outdated repo with
+    /// [`MigrationError::DryRun`].
+    DryRun,
+}
+
+impl Default for MigrationMode {
+    fn default() -> Self {
+        MigrationMode::Run
+    }
+}
+
+/// The migrations needed to bring a repo up to [`REPO_VERSION`], see [`plan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationPlan {
+    /// The current version of the repo.
+    pub from: u32,
+    /// The version of the repo after the migrations.
+    pub to: u32,
+    /// The descriptions of the migrations in the order they are run.
+    pub steps: Vec<&'static str>,
+}
+
+impl MigrationPlan {
+    /// Returns true if the repo is up to date.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl fmt::Display for MigrationPlan {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "from version {} to {}", self.from, self.to)?;
+        for (version, step) in (self.from..).zip(&self.steps) {
+            write!(fmt, "; {} -> {}: {}", version, version + 1, step)?;
+        }
+        Ok(())
+    }
+}
+
+/// Failures to bring the repo up to [`REPO_VERSION`].
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    /// The repo has been created or migrated by a newer version of the crate.
+    #[error("repo version {found} is newer than the supported version {supported}")]
+    TooNew { found: u32, supported: u32 },
+    /// The `version` file did not contain a version.
+    #[error("invalid repo version {0:?}")]
+    InvalidVersion(String),
+    /// The repo is outdated, and [`MigrationMode::DryRun`] was used.
+    #[error("repo needs to be migrated {0}")]
+    DryRun(MigrationPlan),
+    /// The migration from the version failed, leaving the repo at the version.
+    #[error("repo migration from version {from} failed: {error}")]
+    Failed { from: u32, error: Error },
+    /// Reading or writing the `version` file failed.
+    #[error("failed to access the repo version: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Returns the migrations needed to bring the repo at `path` up to [`REPO_VERSION`] without
+/// running them.
+pub fn plan(path: &Path) -> Result<MigrationPlan, MigrationError> {
+    let from = match read_version(path)? {
+        Some(version) => version,
+        None if is_new(path)? => REPO_VERSION,
+        None => UNVERSIONED,
+    };
+    plan_with(from, REPO_VERSION, MIGRATIONS)
+}
+
+/// Brings the repo at `path` up to [`REPO_VERSION`] as configured by the `mode`, returning the
+/// migrations run. A new repo is created at [`REPO_VERSION`].
+pub(crate) fn migrate(path: &Path, mode: MigrationMode) -> Result<MigrationPlan, MigrationError> {
+    migrate_with(path, mode, REPO_VERSION, MIGRATIONS)
+}
+
+fn migrate_with(
+    path: &Path,
+    mode: MigrationMode,
+    current: u32,
+    migrations: &[Migration],
+) -> Result<MigrationPlan, MigrationError> {
+    let from = match read_version(path)? {
+        Some(version) => version,
+        None => {
+            // nothing to migrate in a new repo, which is then created in the current format
+            let version = if is_new(path)? { current } else { UNVERSIONED };
+            write_version(path, version)?;
+            version
+        }
+    };
+
+    let plan = plan_with(from, current, migrations)?;
+
+    if plan.is_empty() {
+        return Ok(plan);
+    }
+
+    if mode == MigrationMode::DryRun {
+        return Err(MigrationError::DryRun(plan));
+    }
+
+    for migration in &migrations[(from - UNVERSIONED) as usize..] {
+        info!(
+            "migrating the repo from version {}: {}",
+            migration.from, migration.description
+        );
+
+        (migration.run)(path).map_err(|error| MigrationError::Failed {
+            from: migration.from,
+            error,
+        })?;
+
+        write_version(path, migration.from + 1)?;
+    }
+
+    Ok(plan)
+}
+
+fn plan_with(
+    from: u32,
+    current: u32,
+    migrations: &[Migration],
+) -> Result<MigrationPlan, MigrationError> {
+    if from > current {
+        return Err(MigrationError::TooNew {
+            found: from,
+            supported: current,
+        });
+    }
+
+    if from < UNVERSIONED {
+        return Err(MigrationError::InvalidVersion(from.to_string()));
+    }
+
+    debug_assert_eq!(migrations.len() as u32, current - UNVERSIONED);
+
+    let steps = migrations[(from - UNVERSIONED) as usize..]
+        .iter()
+        .map(|migration| migration.description)
+        .collect();
+
+    Ok(MigrationPlan {
+        from,
+        to: current,
+        steps,
+    })
+}
+
+/// Returns the version in the `version` file, or `None` if there is no such file.
+fn read_version(path: &Path) -> Result<Option<u32>, MigrationError> {
+    let version = match fs::read_to_string(path.join(VERSION_FILE)) {
+        Ok(version) => version,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    version
+        .trim()
+        .parse()
+        .map(Some)
+        .map_err(|_| MigrationError::InvalidVersion(version.trim().to_owned()))
+}
+
+/// Writes the `version` file through a temporary file, so that it is never found half written.
+fn write_version(path: &Path, version: u32) -> Result<(), MigrationError> {
+    fs::create_dir_all(path)?;
+
+    let temp_path = path.join(VERSION_FILE).with_extension("tmp");
+    {
+        let mut temp = fs::File::create(&temp_path)?;
+        writeln!(temp, "{}", version)?;
+        temp.sync_all()?;
+    }
+    fs::rename(temp_path, path.join(VERSION_FILE))?;
+
+    Ok(())
+}
+
+/// A repo is new if none of the stores have been created under the path yet. The other files,
+/// like the lock taken before migrating, do not depend on the version.
+fn is_new(path: &Path) -> io::Result<bool> {
+    for store in &["blockstore", "datastore", "journal"] {
+        match fs::symlink_metadata(path.join(store)) {
+            Ok(_) => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_first(path: &Path) -> Result<(), Error> {
+        Ok(fs::write(path.join("first"), b"")?)
+    }
+
+    fn add_second(path: &Path) -> Result<(), Error> {
+        Ok(fs::write(path.join("second"), b"")?)
+    }
+
+    fn migrations() -> Vec<Migration> {
+        vec![
+            Migration {
+                from: 1,
+                description: "add the first file",
+                run: add_first,
+            },
+            Migration {
+                from: 2,
+                description: "add the second file",
+                run: add_second,
+            },
+        ]
+    }
+
+    #[test]
+    fn new_repo_is_created_at_the_current_version() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("repo");
+
+        let plan = migrate_with(&path, MigrationMode::DryRun, 3, &migrations()).unwrap();
+        assert!(plan.is_empty());
+        assert_eq!(read_version(&path).unwrap(), Some(3));
+        assert!(!path.join("first").exists());
+    }
+
+    #[test]
+    fn unversioned_repo_is_migrated_in_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("blockstore")).unwrap();
+
+        let plan = migrate_with(tmp.path(), MigrationMode::Run, 3, &migrations()).unwrap();
+        assert_eq!(
+            plan,
+            MigrationPlan {
+                from: 1,
+                to: 3,
+                steps: vec!["add the first file", "add the second file"],
+            }
+        );
+        assert_eq!(read_version(tmp.path()).unwrap(), Some(3));
+        assert!(tmp.path().join("first").exists());
+        assert!(tmp.path().join("second").exists());
+
+        // up to date
+        let plan = migrate_with(tmp.path(), MigrationMode::Run, 3, &migrations()).unwrap();
+        assert!(plan.is_empty());
+    }
+
+    #[test]
+    fn dry_run_does_not_migrate() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_version(tmp.path(), 2).unwrap();
+
+        match migrate_with(tmp.path(), MigrationMode::DryRun, 3, &migrations()) {
+            Err(MigrationError::DryRun(plan)) => {
+                assert_eq!(plan.steps, vec!["add the second file"]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert_eq!(read_version(tmp.path()).unwrap(), Some(2));
+        assert!(!tmp.path().join("second").exists());
+    }
+
+    #[test]
+    fn failed_migration_leaves_the_completed_version() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_version(tmp.path(), 1).unwrap();
+
+        let mut migrations = migrations();
+        migrations[1].run = |_| Err(anyhow::anyhow!("out of space"));
+
+        match migrate_with(tmp.path(), MigrationMode::Run, 3, &migrations) {
+            Err(MigrationError::Failed { from: 2, .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert_eq!(read_version(tmp.path()).unwrap(), Some(2));
+        assert!(tmp.path().join("first").exists());
+    }
+
+    #[test]
+    fn newer_repo_is_refused() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_version(tmp.path(), REPO_VERSION + 1).unwrap();
+
+        match migrate(tmp.path(), MigrationMode::Run) {
+            Err(MigrationError::TooNew { found, supported }) => {
+                assert_eq!(found, REPO_VERSION + 1);
+                assert_eq!(supported, REPO_VERSION);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        fs::write(tmp.path().join(VERSION_FILE), b"one\n").unwrap();
+        assert!(matches!(
+            plan(tmp.path()),
+            Err(MigrationError::InvalidVersion(_))
+        ));
+    }
+}
@@ -1,7 +1,11 @@
 //! Storage implementation(s) backing the [`crate::Ipfs`].
+use crate::budget::{MemoryBudgetConfig, MemoryBudgets};
 use crate::error::Error;
+use crate::gc::GcBarrier;
 use crate::p2p::KadResult;
 use crate::path::IpfsPath;
+use crate::popularity::{PopularityConfig, PopularityTracker};
+use crate::prefetch::PrefetchState;
 use crate::subscription::{RequestKind, SubscriptionFuture, SubscriptionRegistry};
 use crate::{Block, IpfsOptions};
 use async_trait::async_trait;
@@ -13,9 +17,11 @@ use futures::channel::{
     oneshot,
 };
 use futures::sink::SinkExt;
+use futures::stream::BoxStream;
 use libp2p::core::PeerId;
 use std::borrow::Borrow;
 use std::hash::{Hash, Hasher};
+use std::ops::Bound;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::{error, fmt, io};
@@ -24,9 +30,35 @@ use std::{error, fmt, io};
 #[cfg(test)]
 mod common_tests;
 
+pub mod backend;
+pub mod cache;
+pub mod filestore;
 pub mod fs;
 pub mod kv;
 pub mod mem;
+pub mod migrations;
+#[cfg(feature = "rocksdb_block_store")]
+pub mod rocks;
+pub mod s3;
+
+#[cfg(feature = "block_access_stats")]
+mod access;
+#[cfg(feature = "block_access_stats")]
+pub use access::BlockAccess;
+
+mod hooks;
+pub(crate) use hooks::BlockHooks;
+
+use cache::CachedBlockStore;
+
+mod placement;
+pub use backend::{BlockBackend, UnknownBlockBackend};
+pub use cache::{BlockCache, BlockCacheStats};
+pub use filestore::{FileSource, FilestoreCheck, FilestoreEntry, FilestoreStatus};
+pub use hooks::{BlockAccessor, BlockHook};
+pub use migrations::{MigrationError, MigrationMode};
+pub use placement::{BlockPlacement, BlockStorage, BlockVolume, Rebalance};
+pub use s3::ObjectStorage;
 
 /// Consolidates `BlockStore` and `DataStore` into a representation of storage.
 pub trait RepoTypes: Send + Sync + 'static {
@@ -35,18 +67,33 @@ pub trait RepoTypes: Send + Sync + 'static {
     /// Describes a datastore.
     type TDataStore: DataStore;
     type TLock: Lock;
+    /// Describes a journal of the repo mutations.
+    type TJournal: Journal;
+    /// True when the repo is stored under the path of the [`RepoOptions`], in which case the
+    /// format of the repo is versioned and migrated when opened, see [`migrations`].
+    const VERSIONED: bool = true;
 }
 
 /// Configuration for a repo.
 #[derive(Clone, Debug)]
 pub struct RepoOptions {
     path: PathBuf,
+    popularity: PopularityConfig,
+    memory_budgets: MemoryBudgetConfig,
+    migrations: MigrationMode,
+    journal: bool,
+    block_storage: BlockStorage,
 }
 
 impl From<&IpfsOptions> for RepoOptions {
     fn from(options: &IpfsOptions) -> Self {
         RepoOptions {
             path: options.ipfs_path.clone(),
+            popularity: options.popularity.clone(),
+            memory_budgets: options.memory_budgets.clone(),
+            migrations: options.repo_migrations,
+            journal: options.repo_journal,
+            block_storage: options.block_storage.clone(),
         }
     }
 }
@@ -105,7 +152,9 @@ pub enum BlockRmError {
 // FIXME: why is this unpin? doesn't probably need to be since all of the futures are Box::pin'd.
 #[async_trait]
 pub trait BlockStore: Debug + Send + Sync + Unpin + 'static {
-    fn new(path: PathBuf) -> Self;
+    fn new(path: PathBuf) -> Self
+    where
+        Self: Sized;
     async fn init(&self) -> Result<(), Error>;
     /// FIXME: redundant and never called during initialization, which is expected to happen during [`init`].
     async fn open(&self) -> Result<(), Error>;
@@ -119,6 +168,21 @@ pub trait BlockStore: Debug + Send + Sync + Unpin + 'static {
     async fn remove(&self, cid: &Cid) -> Result<Result<BlockRm, BlockRmError>, Error>;
     /// Returns a list of the blocks (Cids), in the blockstore.
     async fn list(&self) -> Result<Vec<Cid>, Error>;
+    /// Returns a stream of the blocks (Cids) and their sizes in bytes, in the blockstore. Unlike
+    /// [`BlockStore::list`], the blockstore is read lazily as the stream is polled.
+    async fn iter(&self) -> Result<BoxStream<'static, Result<(Cid, u64), Error>>, Error>;
+    /// Returns the blocks in the same order as the given Cids, `None` for the ones not in the
+    /// blockstore. The implementations read the blocks concurrently where possible.
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, Error>;
+    /// Spreads the blocks over the volumes, see [`BlockStorage`]. Called before
+    /// [`BlockStore::init`] and [`BlockStore::open`]. Ignored by the blockstores not storing the
+    /// blocks on the disk.
+    fn configure(&mut self, _storage: &BlockStorage) {}
+    /// Moves the blocks to the volumes the placement would now choose for them, see
+    /// [`BlockStorage`].
+    async fn rebalance(&self) -> Result<Rebalance, Error> {
+        Ok(Rebalance::default())
+    }
     /// Wipes the blockstore.
     async fn wipe(&self);
 }
@@ -137,6 +201,11 @@ pub trait DataStore: PinStore + Debug + Send + Sync + Unpin + 'static {
     async fn put(&self, col: Column, key: &[u8], value: &[u8]) -> Result<(), Error>;
     /// Removes a key-value pair from the datastore.
     async fn remove(&self, col: Column, key: &[u8]) -> Result<(), Error>;
+    /// Returns the key-value pairs of the column within the range, ordered by the key.
+    async fn scan(&self, col: Column, range: &KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error>;
+    /// Applies all of the modifications in the batch atomically; either all of them are
+    /// persisted or none of them are, even if the process crashes midway.
+    async fn write_batch(&self, batch: Batch) -> Result<(), Error>;
     /// Wipes the datastore.
     async fn wipe(&self);
 }
@@ -145,17 +214,33 @@ pub trait DataStore: PinStore + Debug + Send + Sync + Unpin + 'static {
 #[derive(Debug)]
 pub enum LockError {
     RepoInUse,
+    /// The repository is locked by the given process.
+    RepoInUseBy(LockOwner),
+    /// The repository lock is still held by another process although the recorded owner has
+    /// exited, for example by a process the owner started.
+    HeldAfterExit(LockOwner),
     LockFileOpenFailed(io::Error),
 }
 
 impl fmt::Display for LockError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let msg = match self {
-            LockError::RepoInUse => "The repository is already being used by an IPFS instance.",
-            LockError::LockFileOpenFailed(_) => "Failed to open repository lock file.",
-        };
-
-        write!(f, "{}", msg)
+        match self {
+            LockError::RepoInUse => write!(
+                f,
+                "The repository is already being used by an IPFS instance."
+            ),
+            LockError::RepoInUseBy(owner) => write!(
+                f,
+                "The repository is already being used by an IPFS instance ({}).",
+                owner
+            ),
+            LockError::HeldAfterExit(owner) => write!(
+                f,
+                "The repository lock of {}, which is no longer running, is still held by another process.",
+                owner
+            ),
+            LockError::LockFileOpenFailed(_) => write!(f, "Failed to open repository lock file."),
+        }
     }
 }
 
@@ -180,6 +265,71 @@ impl error::Error for LockError {
     }
 }
 
+/// The process holding a repository lock, as recorded in the lock file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub host: String,
+}
+
+impl LockOwner {
+    /// The current process.
+    pub fn current() -> Self {
+        LockOwner {
+            pid: std::process::id(),
+            host: hostname(),
+        }
+    }
+
+    /// Returns true if the owner is known to no longer be running: it ran on this host and there
+    /// is no process with the same PID. The processes on other hosts and on platforms where the
+    /// processes cannot be checked are never considered stale.
+    pub fn is_stale(&self) -> bool {
+        if self.host != hostname() || self.pid == std::process::id() {
+            return false;
+        }
+
+        if cfg!(target_os = "linux") {
+            !std::path::Path::new("/proc")
+                .join(self.pid.to_string())
+                .exists()
+        } else {
+            false
+        }
+    }
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid {} on {}", self.pid, self.host)
+    }
+}
+
+impl std::str::FromStr for LockOwner {
+    type Err = ();
+
+    /// Parses the `<pid> <host>` recorded along with the lock files.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, ' ');
+        let pid = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let host = parts.next().filter(|h| !h.is_empty()).ok_or(())?;
+        Ok(LockOwner {
+            pid,
+            host: host.to_owned(),
+        })
+    }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|host| host.trim().replace(char::is_whitespace, "_"))
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
 /// A trait for describing repository locking.
 ///
 /// This ensures no two IPFS nodes can be started with the same peer ID, as exclusive access to the
@@ -189,6 +339,78 @@ pub trait Lock: Debug + Send + Sync {
     fn try_exclusive(&mut self) -> Result<(), LockError>;
 }
 
+/// A repo mutation spanning multiple steps, recorded in the [`Journal`] before it is started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    /// Writing a block to the blockstore.
+    PutBlock(Cid),
+    /// Removing a block from the blockstore.
+    RemoveBlock(Cid),
+    /// Pinning a block and all of its descendants.
+    InsertRecursivePin(Cid),
+    /// Unpinning a recursively pinned block and all of its descendants.
+    RemoveRecursivePin(Cid),
+}
+
+impl Mutation {
+    /// The Cid of the block or the pin root being mutated.
+    pub fn cid(&self) -> &Cid {
+        use Mutation::*;
+        match self {
+            PutBlock(cid)
+            | RemoveBlock(cid)
+            | InsertRecursivePin(cid)
+            | RemoveRecursivePin(cid) => cid,
+        }
+    }
+
+    /// A stable name for the kind of the mutation, used in the persisted journals.
+    pub fn kind(&self) -> &'static str {
+        use Mutation::*;
+        match self {
+            PutBlock(_) => "put_block",
+            RemoveBlock(_) => "remove_block",
+            InsertRecursivePin(_) => "insert_recursive_pin",
+            RemoveRecursivePin(_) => "remove_recursive_pin",
+        }
+    }
+
+    /// Creates the mutation from the [`Mutation::kind`] and the Cid.
+    pub fn from_kind(kind: &str, cid: Cid) -> Option<Self> {
+        use Mutation::*;
+        Some(match kind {
+            "put_block" => PutBlock(cid),
+            "remove_block" => RemoveBlock(cid),
+            "insert_recursive_pin" => InsertRecursivePin(cid),
+            "remove_recursive_pin" => RemoveRecursivePin(cid),
+            _ => return None,
+        })
+    }
+}
+
+/// A write-ahead journal of the repo mutations.
+///
+/// Each [`Mutation`] is durably recorded before it is started and marked completed after it has
+/// been completed, successfully or not. The mutations interrupted by a crash are returned from
+/// [`Journal::open`] on the next start, to be repaired by [`crate::Ipfs::repo_fsck`].
+#[async_trait]
+pub trait Journal: Debug + Send + Sync + 'static {
+    fn new(path: PathBuf) -> Self;
+    /// Opens the journal, returning the ids and the mutations which were started but not
+    /// completed. These stay in the journal until completed.
+    fn open(&self) -> Result<Vec<(u64, Mutation)>, Error>;
+    /// Records the start of the mutation, returning the id to complete it with once the record
+    /// is durable.
+    async fn begin(&self, mutation: &Mutation) -> Result<u64, Error>;
+    /// Records the completion of the mutation. The record does not need to be durable, so this
+    /// should not block.
+    fn complete(&self, id: u64) -> Result<(), Error>;
+    /// Writes the buffered completions, if any.
+    async fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
 type References<'a> = futures::stream::BoxStream<'a, Result<Cid, crate::refs::IpldRefsError>>;
 
 #[async_trait]
@@ -230,9 +452,253 @@ pub trait PinStore: Debug + Send + Sync + Unpin + 'static {
     ) -> Result<Vec<(Cid, PinKind<Cid>)>, Error>;
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Namespaces of the [`DataStore`] keys; the same key can be used in different columns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Column {
+    /// The latest IPNS records published or imported on this node, keyed by the name.
     Ipns,
+    /// The queued provider records.
+    Providers,
+    /// The head of the configuration history, see [`crate::config::history`].
+    Config,
+    /// The root of the mutable file system, see [`crate::mfs`].
+    Files,
+    /// The per block access statistics, see [`BlockAccess`].
+    #[cfg(feature = "block_access_stats")]
+    Access,
+    /// The blocks kept as ranges of the files added without copying, see [`filestore`].
+    Filestore,
+}
+
+impl Column {
+    /// The name of the namespace, usable as a prefix or a table name by the implementations.
+    pub fn namespace(&self) -> &'static str {
+        match self {
+            Column::Ipns => "ipns",
+            Column::Providers => "providers",
+            Column::Config => "config",
+            Column::Files => "files",
+            #[cfg(feature = "block_access_stats")]
+            Column::Access => "access",
+            Column::Filestore => "filestore",
+        }
+    }
+}
+
+/// The key of the latest configuration snapshot in [`Column::Config`].
+const CONFIG_HEAD_KEY: &[u8] = b"head";
+
+/// The key of the root directory of the mutable file system in [`Column::Files`].
+const FILES_ROOT_KEY: &[u8] = b"root";
+
+/// A set of [`DataStore`] modifications to be applied atomically with
+/// [`DataStore::write_batch`]. The modifications are applied in the order they were added, so
+/// the last modification of a key wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single modification within a [`Batch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Puts the value under the key.
+    Put(Column, Vec<u8>, Vec<u8>),
+    /// Removes the key, if it exists.
+    Remove(Column, Vec<u8>),
+}
+
+impl BatchOp {
+    /// The column the modification targets.
+    pub fn column(&self) -> Column {
+        match self {
+            BatchOp::Put(col, ..) | BatchOp::Remove(col, _) => *col,
+        }
+    }
+}
+
+impl Batch {
+    /// Adds putting the value under the key to the batch.
+    pub fn put(&mut self, col: Column, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops
+            .push(BatchOp::Put(col, key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Adds removing the key to the batch.
+    pub fn remove(&mut self, col: Column, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Remove(col, key.to_owned()));
+        self
+    }
+
+    /// Returns the number of modifications in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns true if there are no modifications in the batch.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the modifications in the order they were added.
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Returns the distinct columns modified by the batch.
+    pub fn columns(&self) -> Vec<Column> {
+        let mut columns = Vec::new();
+        for col in self.ops.iter().map(BatchOp::column) {
+            if !columns.contains(&col) {
+                columns.push(col);
+            }
+        }
+        columns
+    }
+}
+
+/// An ordered range of keys within a [`Column`] for [`DataStore::scan`], optionally limited to
+/// keys starting with a prefix and to a number of results.
+///
+/// The bounds and the prefix are combined, so for example a scan continuing from a previously
+/// returned key of a prefix listing can be expressed as `KeyRange::prefix(p).after(key)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRange {
+    prefix: Vec<u8>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    limit: Option<usize>,
+}
+
+impl Default for KeyRange {
+    fn default() -> Self {
+        KeyRange::all()
+    }
+}
+
+impl KeyRange {
+    /// The range of all keys.
+    pub fn all() -> Self {
+        KeyRange {
+            prefix: Vec::new(),
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+            limit: None,
+        }
+    }
+
+    /// The range of the keys starting with `prefix`.
+    pub fn prefix<P: Into<Vec<u8>>>(prefix: P) -> Self {
+        KeyRange {
+            prefix: prefix.into(),
+            ..KeyRange::all()
+        }
+    }
+
+    /// Restricts the range to the keys greater than or equal to `key`.
+    pub fn from<K: Into<Vec<u8>>>(mut self, key: K) -> Self {
+        self.start = Bound::Included(key.into());
+        self
+    }
+
+    /// Restricts the range to the keys greater than `key`.
+    pub fn after<K: Into<Vec<u8>>>(mut self, key: K) -> Self {
+        self.start = Bound::Excluded(key.into());
+        self
+    }
+
+    /// Restricts the range to the keys less than `key`.
+    pub fn until<K: Into<Vec<u8>>>(mut self, key: K) -> Self {
+        self.end = Bound::Excluded(key.into());
+        self
+    }
+
+    /// Returns at most `limit` first keys of the range.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// The maximum number of results, if any.
+    pub fn max_results(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Returns true if the key is within the range, not considering the limit.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key.starts_with(&self.prefix)
+            && match &self.start {
+                Bound::Included(start) => key >= start.as_slice(),
+                Bound::Excluded(start) => key > start.as_slice(),
+                Bound::Unbounded => true,
+            }
+            && match &self.end {
+                Bound::Included(end) => key <= end.as_slice(),
+                Bound::Excluded(end) => key < end.as_slice(),
+                Bound::Unbounded => true,
+            }
+    }
+
+    /// Returns the bounds covering the prefix and the start and end bounds, or `None` when the
+    /// range is empty. The bounds can be used with ordered maps.
+    pub fn bounds(&self) -> Option<(Bound<Vec<u8>>, Bound<Vec<u8>>)> {
+        if self.limit == Some(0) {
+            return None;
+        }
+
+        let mut start = self.start.clone();
+        let mut end = self.end.clone();
+
+        if !self.prefix.is_empty() {
+            let below_prefix = match &start {
+                Bound::Included(k) | Bound::Excluded(k) => k.as_slice() < self.prefix.as_slice(),
+                Bound::Unbounded => true,
+            };
+
+            if below_prefix {
+                start = Bound::Included(self.prefix.clone());
+            }
+
+            if let Some(successor) = prefix_successor(&self.prefix) {
+                let beyond_prefix = match &end {
+                    Bound::Included(k) | Bound::Excluded(k) => k >= &successor,
+                    Bound::Unbounded => true,
+                };
+
+                if beyond_prefix {
+                    end = Bound::Excluded(successor);
+                }
+            }
+        }
+
+        let empty = match (&start, &end) {
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s), Bound::Excluded(e))
+            | (Bound::Excluded(s), Bound::Included(e))
+            | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            _ => false,
+        };
+
+        if empty {
+            None
+        } else {
+            Some((start, end))
+        }
+    }
+}
+
+/// Returns the smallest key greater than all of the keys starting with `prefix`, or `None` if
+/// there is no such key.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < 0xff {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+    None
 }
 
 /// `PinMode` is the description of pin type for quering purposes.
@@ -327,11 +793,27 @@ impl<C: Borrow<Cid>> PinKind<C> {
 /// Consolidates a blockstore, a datastore and a subscription registry.
 #[derive(Debug)]
 pub struct Repo<TRepoTypes: RepoTypes> {
-    block_store: TRepoTypes::TBlockStore,
+    block_store: CachedBlockStore<TRepoTypes::TBlockStore>,
     data_store: TRepoTypes::TDataStore,
     events: Sender<RepoEvent>,
     pub(crate) subscriptions: SubscriptionRegistry<Block, String>,
     lockfile: Arc<Mutex<TRepoTypes::TLock>>,
+    /// The root of the repo, where the version of the repo is stored.
+    path: PathBuf,
+    migrations: MigrationMode,
+    journal: TRepoTypes::TJournal,
+    /// Whether the mutations are recorded in the journal, see [`IpfsOptions::repo_journal`].
+    journaling: bool,
+    /// The mutations interrupted by a crash, found when opening the journal, waiting to be
+    /// repaired.
+    unfinished: Mutex<Vec<(u64, Mutation)>>,
+    popularity: PopularityTracker,
+    budgets: MemoryBudgets,
+    hooks: BlockHooks,
+    prefetch: PrefetchState,
+    pub(crate) gc: GcBarrier,
+    #[cfg(feature = "block_access_stats")]
+    access: access::AccessTracker,
 }
 
 /// Events used to communicate to the swarm on repo changes.
@@ -339,6 +821,9 @@ pub struct Repo<TRepoTypes: RepoTypes> {
 pub enum RepoEvent {
     /// Signals a desired block.
     WantBlock(Cid),
+    /// Signals a desired block, asked only from the connected peers without looking up its
+    /// providers.
+    WantBlockFromPeers(Cid),
     /// Signals a desired block is no longer wanted.
     UnwantBlock(Cid),
     /// Signals the posession of a new block.
@@ -364,16 +849,23 @@ impl TryFrom<RequestKind> for RepoEvent {
 
 impl<TRepoTypes: RepoTypes> Repo<TRepoTypes> {
     pub fn new(options: RepoOptions) -> (Self, Receiver<RepoEvent>) {
+        let path = options.path.clone();
         let mut blockstore_path = options.path.clone();
         let mut datastore_path = options.path.clone();
+        let mut journal_path = options.path.clone();
         let mut lockfile_path = options.path;
+        let popularity = PopularityTracker::new(options.popularity);
+        let budgets = MemoryBudgets::new(options.memory_budgets);
         blockstore_path.push("blockstore");
         datastore_path.push("datastore");
+        journal_path.push("journal");
         lockfile_path.push("repo_lock");
 
-        let block_store = TRepoTypes::TBlockStore::new(blockstore_path);
+        let mut block_store = CachedBlockStore::<TRepoTypes::TBlockStore>::new(blockstore_path);
+        block_store.configure(&options.block_storage);
         let data_store = TRepoTypes::TDataStore::new(datastore_path);
         let lockfile = TRepoTypes::TLock::new(lockfile_path);
+        let journal = TRepoTypes::TJournal::new(journal_path);
         let (sender, receiver) = channel(1);
 
         (
@@ -383,11 +875,101 @@ impl<TRepoTypes: RepoTypes> Repo<TRepoTypes> {
                 events: sender,
                 subscriptions: Default::default(),
                 lockfile: Arc::new(Mutex::new(lockfile)),
+                path,
+                migrations: options.migrations,
+                journal,
+                journaling: options.journal,
+                unfinished: Default::default(),
+                popularity,
+                budgets,
+                hooks: Default::default(),
+                prefetch: Default::default(),
+                gc: Default::default(),
+                #[cfg(feature = "block_access_stats")]
+                access: Default::default(),
             },
             receiver,
         )
     }
 
+    /// The request counts of the content served from this repo.
+    pub fn popularity(&self) -> &PopularityTracker {
+        &self.popularity
+    }
+
+    /// The budgets of the blocks in flight between the swarm and this repo.
+    pub fn budgets(&self) -> &MemoryBudgets {
+        &self.budgets
+    }
+
+    /// Registers a hook run on the block accesses, see [`BlockHook`].
+    pub fn add_block_hook(&self, hook: Arc<dyn BlockHook>) {
+        self.hooks.add(hook);
+    }
+
+    /// Records an access of the block, flushing the collected accesses into the datastore once
+    /// due.
+    #[cfg(feature = "block_access_stats")]
+    async fn record_access(&self, cid: &Cid, read: bool) {
+        if self.access.record(cid, read) {
+            if let Err(e) = self.access.flush(&self.data_store).await {
+                warn!("failed to store the block access statistics: {}", e);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "block_access_stats"))]
+    async fn record_access(&self, _cid: &Cid, _read: bool) {}
+
+    /// Removes the access statistics of a removed block.
+    #[cfg(feature = "block_access_stats")]
+    async fn forget_access(&self, cid: &Cid) {
+        let key = self.access.forget(cid);
+        if let Err(e) = self.data_store.remove(Column::Access, &key).await {
+            warn!("failed to remove the access statistics of {}: {}", cid, e);
+        }
+    }
+
+    #[cfg(not(feature = "block_access_stats"))]
+    async fn forget_access(&self, _cid: &Cid) {}
+
+    /// Stores the block accesses collected in memory into the datastore.
+    #[cfg(feature = "block_access_stats")]
+    pub async fn flush_access_stats(&self) -> Result<(), Error> {
+        self.access.flush(&self.data_store).await
+    }
+
+    /// Returns the access statistics of the block, whether or not it is stored.
+    #[cfg(feature = "block_access_stats")]
+    pub async fn block_access(&self, cid: &Cid) -> Result<BlockAccess, Error> {
+        let counts = self.access.get(&self.data_store, cid).await?;
+        Ok(counts.into_access(cid.to_owned()))
+    }
+
+    /// Returns the access statistics of the stored blocks which have not been accessed since
+    /// `time`, including the ones never accessed since the statistics started to be tracked.
+    /// Meant for finding the candidates for garbage collection or for moving to a slower storage.
+    #[cfg(feature = "block_access_stats")]
+    pub async fn blocks_not_accessed_since(
+        &self,
+        time: std::time::SystemTime,
+    ) -> Result<Vec<BlockAccess>, Error> {
+        let all = self.access.all(&self.data_store).await?;
+
+        let stale = self
+            .list_blocks()
+            .await?
+            .into_iter()
+            .map(|cid| {
+                let counts = all.get(&access::key(&cid)).copied().unwrap_or_default();
+                counts.into_access(cid)
+            })
+            .filter(|access| access.last_access.map(|at| at < time).unwrap_or(true))
+            .collect();
+
+        Ok(stale)
+    }
+
     /// Shutdowns the repo, cancelling any pending subscriptions; Likely going away after some
     /// refactoring, see notes on [`crate::Ipfs::exit_daemon`].
     pub fn shutdown(&self) {
@@ -402,14 +984,38 @@ impl<TRepoTypes: RepoTypes> Repo<TRepoTypes> {
             guard.try_exclusive()?;
         }
 
+        // the stores are only opened once the repo has been migrated to the current format
+        if TRepoTypes::VERSIONED {
+            let path = self.path.clone();
+            let mode = self.migrations;
+            let plan =
+                tokio::task::spawn_blocking(move || migrations::migrate(&path, mode)).await??;
+            if !plan.is_empty() {
+                info!("migrated the repo {}", plan);
+            }
+        }
+
         let f1 = self.block_store.init();
         let f2 = self.data_store.init();
         let (r1, r2) = futures::future::join(f1, f2).await;
-        if r1.is_err() {
-            r1
-        } else {
-            r2
+        r1?;
+        r2?;
+
+        if !self.journaling {
+            return Ok(());
         }
+
+        // the journal can only be read once the repo is locked
+        let unfinished = self.journal.open()?;
+        if !unfinished.is_empty() {
+            warn!(
+                "found {} repo mutations interrupted by an unclean shutdown",
+                unfinished.len()
+            );
+        }
+        *self.unfinished.lock().unwrap() = unfinished;
+
+        Ok(())
     }
 
     pub async fn open(&self) -> Result<(), Error> {
@@ -423,10 +1029,136 @@ impl<TRepoTypes: RepoTypes> Repo<TRepoTypes> {
         }
     }
 
+    /// Runs the mutation recorded in the journal. The mutation is completed in the journal even
+    /// if it failed, as only the interrupted mutations need to be repaired.
+    async fn journaled<F, T>(&self, mutation: Mutation, f: F) -> Result<T, Error>
+    where
+        F: std::future::Future<Output = Result<T, Error>>,
+    {
+        if !self.journaling {
+            return f.await;
+        }
+
+        let id = self.journal.begin(&mutation).await?;
+        let res = f.await;
+        if let Err(e) = self.journal.complete(id) {
+            // the mutation will be needlessly checked on the next start
+            warn!("failed to complete {:?} in the journal: {}", mutation, e);
+        }
+        res
+    }
+
+    /// Writes the completions of the mutations buffered by the journal, see
+    /// [`Journal::complete`].
+    pub async fn flush_journal(&self) -> Result<(), Error> {
+        if !self.journaling {
+            return Ok(());
+        }
+        self.journal.flush().await
+    }
+
+    /// Takes the mutations interrupted by a crash, waiting to be repaired.
+    pub(crate) fn take_unfinished(&self) -> Vec<(u64, Mutation)> {
+        std::mem::take(&mut *self.unfinished.lock().unwrap())
+    }
+
+    /// Marks the interrupted mutation as repaired, or returns it to wait for the next repair
+    /// attempt.
+    pub(crate) fn finish_unfinished(&self, id: u64, mutation: Mutation, repaired: bool) {
+        if !repaired {
+            self.unfinished.lock().unwrap().push((id, mutation));
+        } else if let Err(e) = self.journal.complete(id) {
+            warn!(
+                "failed to complete repaired {:?} in the journal: {}",
+                mutation, e
+            );
+        }
+    }
+
     /// Puts a block into the block store.
     pub async fn put_block(&self, block: Block) -> Result<(Cid, BlockPut), Error> {
+        self.put_block_as(block, &BlockAccessor::Local).await
+    }
+
+    /// Puts a block into the block store on behalf of the `accessor`.
+    pub(crate) async fn put_block_as(
+        &self,
+        block: Block,
+        accessor: &BlockAccessor,
+    ) -> Result<(Cid, BlockPut), Error> {
+        self.hooks.before_put(&block, accessor).await?;
+
+        let cid = block.cid.clone();
+        let (_cid, res) = {
+            // kept if the garbage collector is marking, see crate::gc
+            let _gc = self.gc.enter().await;
+            let put = self
+                .journaled(
+                    Mutation::PutBlock(cid.clone()),
+                    self.block_store.put(block.clone()),
+                )
+                .await?;
+            self.gc.record(&cid);
+            put
+        };
+
+        self.announce_put(block, &res, accessor).await?;
+
+        Ok((cid, res))
+    }
+
+    /// Records the block as the range of the file at `source` starting from the `offset` instead
+    /// of writing it to the blockstore, see [`filestore`].
+    pub(crate) async fn put_filestore_block(
+        &self,
+        block: Block,
+        source: &FileSource,
+        offset: u64,
+    ) -> Result<(Cid, BlockPut), Error> {
+        let accessor = BlockAccessor::Local;
+        self.hooks.before_put(&block, &accessor).await?;
+
+        let cid = block.cid.clone();
+        let key = FilestoreEntry::key(&cid);
+        let entry = FilestoreEntry {
+            cid: cid.clone(),
+            source: source.to_owned(),
+            offset,
+            len: block.data().len() as u64,
+        };
+
+        let existed = {
+            // kept if the garbage collector is marking, like the blocks of the blockstore
+            let _gc = self.gc.enter().await;
+            let existed = self.data_store.contains(Column::Filestore, key).await?
+                || self.block_store.contains(&cid).await?;
+            self.data_store
+                .put(Column::Filestore, key, &entry.to_value()?)
+                .await?;
+            self.gc.record(&cid);
+            existed
+        };
+
+        let res = if existed {
+            BlockPut::Existed
+        } else {
+            BlockPut::NewBlock
+        };
+        self.announce_put(block, &res, &accessor).await?;
+
+        Ok((cid, res))
+    }
+
+    /// Runs the hooks after the block has been put, and announces the new block.
+    async fn announce_put(
+        &self,
+        block: Block,
+        res: &BlockPut,
+        accessor: &BlockAccessor,
+    ) -> Result<(), Error> {
         let cid = block.cid.clone();
-        let (_cid, res) = self.block_store.put(block.clone()).await?;
+        self.hooks.after_put(&block, res, accessor).await;
+        self.record_access(&cid, false).await;
 
         // FIXME: this doesn't cause actual DHT providing yet, only some
         // bitswap housekeeping; we might want to not ignore the channel
@@ -450,35 +1182,153 @@ impl<TRepoTypes: RepoTypes> Repo<TRepoTypes> {
             }
         }
 
-        Ok((cid, res))
+        Ok(())
     }
 
     /// Retrives a block from the block store, or starts fetching it from the network and awaits
     /// until it has been fetched.
     pub async fn get_block(&self, cid: &Cid) -> Result<Block, Error> {
+        self.fetch_block(cid, true).await
+    }
+
+    /// Like [`Repo::get_block`], but the providers of the block are looked up only when
+    /// `find_providers` is true, otherwise the block is asked only from the connected peers.
+    pub(crate) async fn fetch_block(
+        &self,
+        cid: &Cid,
+        find_providers: bool,
+    ) -> Result<Block, Error> {
+        self.load_block(cid, find_providers, false).await
+    }
+
+    /// Like [`Repo::get_block`], but a missing block is fetched with a low priority, see
+    /// [`crate::prefetch`].
+    pub(crate) async fn prefetch_block(&self, cid: &Cid) -> Result<Block, Error> {
+        self.load_block(cid, true, true).await
+    }
+
+    async fn load_block(
+        &self,
+        cid: &Cid,
+        find_providers: bool,
+        background: bool,
+    ) -> Result<Block, Error> {
+        self.hooks.before_get(cid, &BlockAccessor::Local).await?;
+
         // FIXME: here's a race: block_store might give Ok(None) and we get to create our
         // subscription after the put has completed. So maybe create the subscription first, then
         // cancel it?
-        if let Some(block) = self.get_block_now(cid).await? {
-            Ok(block)
+        let block = if let Some(block) = self.read_block(cid).await? {
+            block
         } else {
+            // held until the block has been fetched
+            let (_slot, _fetch) = if background {
+                (Some(self.prefetch.background().await), None)
+            } else {
+                (None, Some(self.prefetch.foreground()))
+            };
+
             let subscription = self
                 .subscriptions
                 .create_subscription(cid.clone().into(), Some(self.events.clone()));
             // sending only fails if no one is listening anymore
             // and that is okay with us.
-            self.events
-                .clone()
-                .send(RepoEvent::WantBlock(cid.clone()))
-                .await
-                .ok();
-            Ok(subscription.await?)
-        }
+            let event = if find_providers {
+                RepoEvent::WantBlock(cid.clone())
+            } else {
+                RepoEvent::WantBlockFromPeers(cid.clone())
+            };
+            self.events.clone().send(event).await.ok();
+            subscription.await?
+        };
+
+        self.hooks.after_get(&block, &BlockAccessor::Local).await;
+        self.record_access(cid, true).await;
+        Ok(block)
     }
 
     /// Retrieves a block from the block store if it's available locally.
     pub async fn get_block_now(&self, cid: &Cid) -> Result<Option<Block>, Error> {
-        self.block_store.get(cid).await
+        self.get_block_now_as(cid, &BlockAccessor::Local).await
+    }
+
+    /// Retrieves a block from the block store on behalf of the `accessor`, if it's available
+    /// locally.
+    pub(crate) async fn get_block_now_as(
+        &self,
+        cid: &Cid,
+        accessor: &BlockAccessor,
+    ) -> Result<Option<Block>, Error> {
+        self.hooks.before_get(cid, accessor).await?;
+        let block = self.read_block(cid).await?;
+        if let Some(block) = &block {
+            self.hooks.after_get(block, accessor).await;
+            self.record_access(cid, true).await;
+        }
+        Ok(block)
+    }
+
+    /// Retrieves a block available locally for the repo maintenance, without running the hooks
+    /// or recording the access.
+    pub(crate) async fn get_block_untracked(&self, cid: &Cid) -> Result<Option<Block>, Error> {
+        self.read_block(cid).await
+    }
+
+    /// Reads the block from the blockstore, or from the file it was added from without copying.
+    async fn read_block(&self, cid: &Cid) -> Result<Option<Block>, Error> {
+        match self.block_store.get(cid).await? {
+            Some(block) => Ok(Some(block)),
+            None => self.read_filestore_block(cid).await,
+        }
+    }
+
+    async fn read_filestore_block(&self, cid: &Cid) -> Result<Option<Block>, Error> {
+        match self.filestore_value(cid).await {
+            Some(value) => {
+                let entry = FilestoreEntry::from_value(FilestoreEntry::key(cid), &value)?;
+                Ok(Some(entry.read(cid).await?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the filestore entry of the block, if any. A failing lookup, for example on a
+    /// datastore without the columns, is treated as the block not being in the filestore so that
+    /// the missing blocks are still fetched from the network.
+    async fn filestore_value(&self, cid: &Cid) -> Option<Vec<u8>> {
+        // only the raw leaves are added without copying
+        if cid.codec() != cid::Codec::Raw {
+            return None;
+        }
+
+        match self
+            .data_store
+            .get(Column::Filestore, FilestoreEntry::key(cid))
+            .await
+        {
+            Ok(value) => value,
+            Err(e) => {
+                debug!("failed to look up {} from the filestore: {}", cid, e);
+                None
+            }
+        }
+    }
+
+    /// Checks that the blocks added without copying can still be read from their files, see
+    /// [`filestore`].
+    pub async fn filestore_verify(&self) -> Result<Vec<FilestoreCheck>, Error> {
+        let entries = self
+            .data_store
+            .scan(Column::Filestore, &KeyRange::all())
+            .await?;
+
+        let mut checks = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            let entry = FilestoreEntry::from_value(&key, &value)?;
+            checks.push(entry.check().await);
+        }
+
+        Ok(checks)
     }
 
     /// Lists the blocks in the blockstore.
@@ -486,6 +1336,51 @@ impl<TRepoTypes: RepoTypes> Repo<TRepoTypes> {
         self.block_store.list().await
     }
 
+    /// Lists the blocks added without copying, see [`filestore`].
+    pub async fn list_filestore_blocks(&self) -> Result<Vec<Cid>, Error> {
+        self.data_store
+            .scan(Column::Filestore, &KeyRange::all())
+            .await?
+            .into_iter()
+            .map(|(key, value)| Ok(FilestoreEntry::from_value(&key, &value)?.cid))
+            .collect()
+    }
+
+    /// Streams the blocks and their sizes in the blockstore.
+    pub async fn iter_blocks(
+        &self,
+    ) -> Result<BoxStream<'static, Result<(Cid, u64), Error>>, Error> {
+        self.block_store.iter().await
+    }
+
+    /// Retrieves the blocks available locally, in the same order as the `cids`.
+    pub async fn get_blocks_now(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, Error> {
+        for cid in cids {
+            self.hooks.before_get(cid, &BlockAccessor::Local).await?;
+        }
+        let mut blocks = self.block_store.get_many(cids).await?;
+        for (cid, block) in cids.iter().zip(blocks.iter_mut()) {
+            if block.is_none() {
+                *block = self.read_filestore_block(cid).await?;
+            }
+        }
+        for block in blocks.iter().flatten() {
+            self.hooks.after_get(block, &BlockAccessor::Local).await;
+            self.record_access(&block.cid, true).await;
+        }
+        Ok(blocks)
+    }
+
+    /// Returns the hits and the misses of the [`BlockCache`].
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.block_store.stats()
+    }
+
+    /// Moves the blocks between the volumes of the blockstore, see [`BlockStorage`].
+    pub async fn rebalance_blocks(&self) -> Result<Rebalance, Error> {
+        self.block_store.rebalance().await
+    }
+
     /// Remove block from the block store.
     pub async fn remove_block(&self, cid: &Cid) -> Result<Cid, Error> {
         if self.is_pinned(cid).await? {
@@ -496,9 +1391,15 @@ impl<TRepoTypes: RepoTypes> Repo<TRepoTypes> {
         // I like this pattern of the repo abstraction being some sort of
         // "clearing house" for the underlying result enums, but this
         // could potentially be pushed out out of here up to Ipfs, idk
-        match self.block_store.remove(cid).await? {
+        let removal = self.journaled(
+            Mutation::RemoveBlock(cid.clone()),
+            self.block_store.remove(cid),
+        );
+
+        match removal.await? {
             Ok(success) => match success {
                 BlockRm::Removed(_cid) => {
+                    self.forget_access(cid).await;
                     // sending only fails if the background task has exited
                     self.events
                         .clone()
@@ -509,11 +1410,79 @@ impl<TRepoTypes: RepoTypes> Repo<TRepoTypes> {
                 }
             },
             Err(err) => match err {
-                BlockRmError::NotFound(_cid) => Err(anyhow::anyhow!("block not found")),
+                BlockRmError::NotFound(_cid) => {
+                    if self.filestore_value(cid).await.is_some() {
+                        // the file itself is left alone
+                        self.data_store
+                            .remove(Column::Filestore, FilestoreEntry::key(cid))
+                            .await?;
+                        self.forget_access(cid).await;
+                        self.events
+                            .clone()
+                            .send(RepoEvent::RemovedBlock(cid.clone()))
+                            .await
+                            .ok();
+                        return Ok(cid.clone());
+                    }
+                    Err(anyhow::anyhow!("block not found"))
+                }
             },
         }
     }
 
+    /// Removes a block found unreferenced by the garbage collector, see [`crate::gc`]. Returns
+    /// `false` if the block was already gone.
+    pub(crate) async fn remove_unreferenced_block(&self, cid: &Cid) -> Result<bool, Error> {
+        let removal = self.journaled(
+            Mutation::RemoveBlock(cid.clone()),
+            self.block_store.remove(cid),
+        );
+
+        let removed = match removal.await? {
+            Ok(BlockRm::Removed(_)) => true,
+            Err(BlockRmError::NotFound(_)) => false,
+        };
+
+        // the block could also have been added without copying; the file itself is left alone
+        let in_filestore = self.filestore_value(cid).await.is_some();
+        if in_filestore {
+            self.data_store
+                .remove(Column::Filestore, FilestoreEntry::key(cid))
+                .await?;
+        }
+
+        if !removed && !in_filestore {
+            return Ok(false);
+        }
+
+        self.forget_access(cid).await;
+        // sending only fails if the background task has exited
+        self.events
+            .clone()
+            .send(RepoEvent::RemovedBlock(cid.clone()))
+            .await
+            .ok();
+        Ok(true)
+    }
+
+    /// Removes a block which failed the integrity check, even if it is pinned, so that it can be
+    /// fetched again.
+    pub(crate) async fn remove_corrupted_block(&self, cid: &Cid) -> Result<(), Error> {
+        match self.block_store.remove(cid).await? {
+            Ok(BlockRm::Removed(_)) => {
+                // sending only fails if the background task has exited
+                self.events
+                    .clone()
+                    .send(RepoEvent::RemovedBlock(cid.clone()))
+                    .await
+                    .ok();
+                Ok(())
+            }
+            // already gone
+            Err(BlockRmError::NotFound(_)) => Ok(()),
+        }
+    }
+
     /// Get an ipld path from the datastore.
     pub async fn get_ipns(&self, ipns: &PeerId) -> Result<Option<IpfsPath>, Error> {
         use std::str::FromStr;
@@ -551,14 +1520,71 @@ impl<TRepoTypes: RepoTypes> Repo<TRepoTypes> {
             .await
     }
 
+    /// Returns the latest snapshot of the configuration history, if any.
+    pub async fn get_config_head(&self) -> Result<Option<Cid>, Error> {
+        let bytes = self.data_store.get(Column::Config, CONFIG_HEAD_KEY).await?;
+        match bytes {
+            Some(bytes) => Ok(Some(Cid::try_from(&bytes[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces the latest snapshot of the configuration history.
+    pub async fn put_config_head(&self, cid: &Cid) -> Result<(), Error> {
+        self.data_store
+            .put(Column::Config, CONFIG_HEAD_KEY, &cid.to_bytes())
+            .await
+    }
+
+    /// Returns the root directory of the mutable file system, if it has been created.
+    pub async fn get_files_root(&self) -> Result<Option<Cid>, Error> {
+        let bytes = self.data_store.get(Column::Files, FILES_ROOT_KEY).await?;
+        match bytes {
+            Some(bytes) => Ok(Some(Cid::try_from(&bytes[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces the root directory of the mutable file system.
+    pub async fn put_files_root(&self, cid: &Cid) -> Result<(), Error> {
+        self.data_store
+            .put(Column::Files, FILES_ROOT_KEY, &cid.to_bytes())
+            .await
+    }
+
+    /// Returns the latest IPNS record of the `name` published or imported on this node.
+    pub async fn get_ipns_record(&self, name: &PeerId) -> Result<Option<Vec<u8>>, Error> {
+        self.data_store.get(Column::Ipns, &name.to_bytes()).await
+    }
+
+    /// Replaces the latest IPNS record of the `name`.
+    pub async fn put_ipns_record(&self, name: &PeerId, record: &[u8]) -> Result<(), Error> {
+        self.data_store
+            .put(Column::Ipns, &name.to_bytes(), record)
+            .await
+    }
+
     /// Inserts a direct pin for a `Cid`.
     pub async fn insert_direct_pin(&self, cid: &Cid) -> Result<(), Error> {
+        let _gc = self.gc.enter().await;
+        self.gc.record(cid);
         self.data_store.insert_direct_pin(cid).await
     }
 
     /// Inserts a recursive pin for a `Cid`.
     pub async fn insert_recursive_pin(&self, cid: &Cid, refs: References<'_>) -> Result<(), Error> {
-        self.data_store.insert_recursive_pin(cid, refs).await
+        // the guard is not held while the references are walked, as the walk may need to put
+        // the missing blocks
+        {
+            let _gc = self.gc.enter().await;
+            self.gc.record(cid);
+        }
+
+        self.journaled(
+            Mutation::InsertRecursivePin(cid.clone()),
+            self.data_store.insert_recursive_pin(cid, refs),
+        )
+        .await
     }
 
     /// Removes a direct pin for a `Cid`.
@@ -569,7 +1595,11 @@ impl<TRepoTypes: RepoTypes> Repo<TRepoTypes> {
     /// Removes a recursive pin for a `Cid`.
     pub async fn remove_recursive_pin(&self, cid: &Cid, refs: References<'_>) -> Result<(), Error> {
         // FIXME: not really sure why is there not an easier way to to transfer control
-        self.data_store.remove_recursive_pin(cid, refs).await
+        self.journaled(
+            Mutation::RemoveRecursivePin(cid.clone()),
+            self.data_store.remove_recursive_pin(cid, refs),
+        )
+        .await
     }
 
     /// Checks if a `Cid` is pinned.
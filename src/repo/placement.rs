@@ -0,0 +1,323 @@
+//! Placement of the blocks over multiple blockstore volumes, see [`BlockStorage`].
+//!
+//! Only the filesystem backed blockstore stores the blocks on the volumes, the in-memory one
+//! ignores them.
+
+use super::backend::BlockBackend;
+use super::cache::BlockCache;
+use super::s3::ObjectStorage;
+use cid::Cid;
+use std::cmp::Ordering;
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
+
+/// A directory the blocks are stored under, usually on a disk of its own.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockVolume {
+    pub path: PathBuf,
+    /// The most bytes of blocks placed on the volume, `None` for no limit.
+    pub capacity: Option<u64>,
+}
+
+/// How the new blocks are placed on the volumes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockPlacement {
+    /// The volume is selected by the first byte of the multihash digest, each volume owning an
+    /// equal share of the range. A block is usually found with a single lookup, but the blocks
+    /// of a full volume end up on the following ones.
+    HashPrefix,
+    /// The volumes take turns, skipping the full ones. Finding a block may take a lookup per
+    /// volume.
+    RoundRobin,
+}
+
+impl Default for BlockPlacement {
+    fn default() -> Self {
+        BlockPlacement::HashPrefix
+    }
+}
+
+/// Spreads the blocks over multiple disks without RAID.
+///
+/// The `blockstore` directory of the repo is always the first volume, the `volumes` follow it.
+/// Changing the volumes or the placement of an existing repo is fine, as the blocks are looked
+/// up from all of the volumes, but they are only moved to the volumes the placement would now
+/// choose by [`crate::Ipfs::rebalance_blocks`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockStorage {
+    /// The most bytes of blocks placed in the `blockstore` directory of the repo, `None` for no
+    /// limit.
+    pub capacity: Option<u64>,
+    /// The volumes in addition to the `blockstore` directory of the repo.
+    pub volumes: Vec<BlockVolume>,
+    pub placement: BlockPlacement,
+    /// The bucket of the blockstore keeping the blocks in an object storage, see
+    /// [`crate::repo::s3`]. Ignored by the other blockstores, like the volumes are ignored by
+    /// it.
+    pub object_storage: Option<ObjectStorage>,
+    /// Keeping the blocks in memory in front of any of the blockstores, see
+    /// [`crate::repo::cache`]. `None` by default.
+    pub cache: Option<BlockCache>,
+    /// The blockstore of the [`crate::Types`], see [`crate::repo::backend`].
+    pub backend: BlockBackend,
+}
+
+/// The outcome of [`crate::Ipfs::rebalance_blocks`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rebalance {
+    /// Number of the blocks moved to another volume.
+    pub moved: usize,
+    /// Total size of the moved blocks.
+    pub moved_bytes: u64,
+    /// Number of the extra copies of the blocks found on multiple volumes, which were removed.
+    pub removed_duplicates: usize,
+}
+
+/// The volumes of a blockstore along with their usage.
+#[derive(Debug)]
+pub(crate) struct Volumes {
+    placement: BlockPlacement,
+    volumes: Vec<Volume>,
+    /// The volume to try next with [`BlockPlacement::RoundRobin`].
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Volume {
+    path: PathBuf,
+    capacity: Option<u64>,
+    /// Only kept up to date when [`Volumes::tracks_usage`].
+    used: AtomicU64,
+}
+
+impl Volumes {
+    /// Creates the volumes of a blockstore stored at the `primary` path.
+    pub(crate) fn new(primary: PathBuf, storage: &BlockStorage) -> Self {
+        let primary = BlockVolume {
+            path: primary,
+            capacity: storage.capacity,
+        };
+
+        let volumes = std::iter::once(&primary)
+            .chain(storage.volumes.iter())
+            .map(|volume| Volume {
+                path: volume.path.clone(),
+                capacity: volume.capacity,
+                used: AtomicU64::new(0),
+            })
+            .collect();
+
+        Volumes {
+            placement: storage.placement,
+            volumes,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.volumes.len()
+    }
+
+    pub(crate) fn path(&self, index: usize) -> PathBuf {
+        self.volumes[index].path.clone()
+    }
+
+    /// True if the usage of the volumes is needed for the placement, and needs to be measured
+    /// when opening the blockstore.
+    pub(crate) fn tracks_usage(&self) -> bool {
+        self.volumes.len() > 1 || self.volumes.iter().any(|v| v.capacity.is_some())
+    }
+
+    pub(crate) fn used(&self, index: usize) -> u64 {
+        self.volumes[index].used.load(atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_used(&self, index: usize, bytes: u64) {
+        self.volumes[index]
+            .used
+            .store(bytes, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_used(&self, index: usize, bytes: u64) {
+        self.volumes[index]
+            .used
+            .fetch_add(bytes, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn sub_used(&self, index: usize, bytes: u64) {
+        // the result is ignored as the closure never fails
+        let _ = self.volumes[index].used.fetch_update(
+            atomic::Ordering::Relaxed,
+            atomic::Ordering::Relaxed,
+            |used| Some(used.saturating_sub(bytes)),
+        );
+    }
+
+    /// Selects the volume for a new block of `size` bytes, or `None` if all of the volumes are
+    /// full.
+    pub(crate) fn place(&self, cid: &Cid, size: u64) -> Option<usize> {
+        let first = match self.placement {
+            BlockPlacement::HashPrefix => self.prefix_volume(cid),
+            BlockPlacement::RoundRobin => {
+                self.next.fetch_add(1, atomic::Ordering::Relaxed) % self.volumes.len()
+            }
+        };
+
+        (0..self.volumes.len())
+            .map(|offset| (first + offset) % self.volumes.len())
+            .find(|&index| self.has_room(index, size))
+    }
+
+    /// The order of the volumes to look up the block from, the most likely volume first.
+    pub(crate) fn lookup_order(&self, cid: &Cid) -> Vec<usize> {
+        let first = match self.placement {
+            BlockPlacement::HashPrefix => self.prefix_volume(cid),
+            BlockPlacement::RoundRobin => 0,
+        };
+
+        (0..self.volumes.len())
+            .map(|offset| (first + offset) % self.volumes.len())
+            .collect()
+    }
+
+    /// Selects the volume a block of `size` bytes stored on the volume `from` should be moved to
+    /// when rebalancing, if any.
+    pub(crate) fn rebalance_target(&self, from: usize, cid: &Cid, size: u64) -> Option<usize> {
+        let over_capacity = self.volumes[from]
+            .capacity
+            .map(|capacity| self.used(from) > capacity)
+            .unwrap_or(false);
+
+        if over_capacity {
+            return self
+                .lookup_order(cid)
+                .into_iter()
+                .find(|&index| index != from && self.has_room(index, size));
+        }
+
+        match self.placement {
+            BlockPlacement::HashPrefix => Some(self.prefix_volume(cid))
+                .filter(|&index| index != from && self.has_room(index, size)),
+            BlockPlacement::RoundRobin => {
+                // even out the used share of the volumes, moving the block only if the target
+                // stays less used than the source was
+                let target = (0..self.volumes.len())
+                    .filter(|&index| index != from && self.has_room(index, size))
+                    .min_by(|&a, &b| self.compare_load(a, 0, b, 0))?;
+
+                Some(target)
+                    .filter(|&target| self.compare_load(target, size, from, 0) == Ordering::Less)
+            }
+        }
+    }
+
+    fn prefix_volume(&self, cid: &Cid) -> usize {
+        let prefix = cid.hash().digest().first().copied().unwrap_or_default();
+        usize::from(prefix) * self.volumes.len() / 256
+    }
+
+    fn has_room(&self, index: usize, size: u64) -> bool {
+        self.volumes[index]
+            .capacity
+            .map(|capacity| self.used(index).saturating_add(size) <= capacity)
+            .unwrap_or(true)
+    }
+
+    /// The volumes without a capacity weigh as much as the largest limited one, or all of the
+    /// volumes weigh the same if none is limited.
+    fn weight(&self, index: usize) -> u64 {
+        self.volumes[index]
+            .capacity
+            .or_else(|| self.volumes.iter().filter_map(|v| v.capacity).max())
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    /// Compares the used shares of the volumes `a` and `b`, after adding the given bytes to them.
+    fn compare_load(&self, a: usize, add_a: u64, b: usize, add_b: u64) -> Ordering {
+        let used_a = u128::from(self.used(a)) + u128::from(add_a);
+        let used_b = u128::from(self.used(b)) + u128::from(add_b);
+
+        (used_a * u128::from(self.weight(b))).cmp(&(used_b * u128::from(self.weight(a))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockPlacement, BlockStorage, BlockVolume, Volumes};
+    use cid::{Cid, Codec};
+    use multihash::Sha2_256;
+    use std::path::PathBuf;
+
+    fn storage(placement: BlockPlacement, capacities: &[Option<u64>]) -> Volumes {
+        let storage = BlockStorage {
+            capacity: capacities[0],
+            volumes: capacities[1..]
+                .iter()
+                .enumerate()
+                .map(|(i, capacity)| BlockVolume {
+                    path: PathBuf::from(format!("volume{}", i + 1)),
+                    capacity: *capacity,
+                })
+                .collect(),
+            placement,
+            object_storage: None,
+            cache: None,
+            backend: Default::default(),
+        };
+
+        Volumes::new(PathBuf::from("blockstore"), &storage)
+    }
+
+    fn cid(n: usize) -> Cid {
+        Cid::new_v1(Codec::Raw, Sha2_256::digest(n.to_string().as_bytes()))
+    }
+
+    #[test]
+    fn hash_prefix_placement_is_stable() {
+        let volumes = storage(BlockPlacement::HashPrefix, &[None, None, None]);
+        let mut counts = [0; 3];
+
+        for n in 0..300 {
+            let placed = volumes.place(&cid(n), 10).unwrap();
+            assert_eq!(volumes.place(&cid(n), 10), Some(placed));
+            assert_eq!(volumes.lookup_order(&cid(n))[0], placed);
+            counts[placed] += 1;
+        }
+
+        assert!(counts.iter().all(|&count| count > 50), "{:?}", counts);
+    }
+
+    #[test]
+    fn full_volumes_are_skipped() {
+        let volumes = storage(BlockPlacement::RoundRobin, &[Some(100), None]);
+
+        assert_eq!(volumes.place(&cid(0), 10), Some(0));
+        assert_eq!(volumes.place(&cid(1), 10), Some(1));
+
+        volumes.add_used(0, 95);
+        assert_eq!(volumes.place(&cid(2), 10), Some(1));
+        assert_eq!(volumes.place(&cid(3), 10), Some(1));
+
+        let volumes = storage(BlockPlacement::HashPrefix, &[Some(10)]);
+        volumes.add_used(0, 10);
+        assert_eq!(volumes.place(&cid(0), 1), None);
+    }
+
+    #[test]
+    fn round_robin_rebalances_to_the_new_volume() {
+        let volumes = storage(BlockPlacement::RoundRobin, &[None, None]);
+        volumes.set_used(0, 100);
+
+        assert_eq!(volumes.rebalance_target(0, &cid(0), 10), Some(1));
+
+        volumes.set_used(0, 50);
+        volumes.set_used(1, 50);
+        assert_eq!(volumes.rebalance_target(0, &cid(0), 10), None);
+
+        // the blocks are moved off the volumes over their capacity regardless of the placement
+        let volumes = storage(BlockPlacement::HashPrefix, &[Some(10), None]);
+        volumes.set_used(0, 20);
+        assert_eq!(volumes.rebalance_target(0, &cid(0), 5), Some(1));
+    }
+}
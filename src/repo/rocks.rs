@@ -0,0 +1,359 @@
+//! [`RocksDB`] based blockstore for the large repos, see [`RocksBlockStore`].
+//!
+//! [`RocksDB`]: https://rocksdb.org/
+use crate::error::Error;
+use crate::repo::{BlockPut, BlockRm, BlockRmError, BlockStore};
+use crate::Block;
+use async_trait::async_trait;
+use cid::Cid;
+use futures::stream::{BoxStream, StreamExt};
+use once_cell::sync::OnceCell;
+use rocksdb::{
+    BlockBasedOptions, ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options,
+    WriteBatch, DB,
+};
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// The column family of the block data, keyed by the multihash.
+const BLOCKS: &str = "blocks";
+/// The column family of the Cids the blocks were first put with, keyed by the multihash.
+const CIDS: &str = "cids";
+
+/// The number of entries read on a blocking thread at a time by [`RocksBlockStore::iter`].
+const ITER_PAGE: usize = 1024;
+
+/// [`RocksDB`] based blockstore, meant for the repos with tens of millions of blocks where the
+/// file per block of the [`crate::repo::fs::FsBlockStore`] spends most of the time and the
+/// space on the filesystem metadata. Compiled in with the `rocksdb_block_store` feature, and
+/// selected for the [`crate::Types`] with [`crate::repo::BlockBackend::RocksDb`].
+///
+/// Like in the [`crate::repo::kv::KvBlockStore`], the block data and the Cids are kept apart,
+/// here in separate column families, so that listing the blocks does not read the data. Both
+/// are written in a single batch, so a block is either fully written or missing after a crash.
+/// The block data column family has bloom filters, which answer most of the
+/// [`BlockStore::contains`] calls for the missing blocks, the common case when fetching, without
+/// reading from the disk. The larger blocks are kept in separate blob files, which keeps the
+/// compactions from rewriting them.
+///
+/// `cargo bench --bench blockstore --features rocksdb_block_store` compares the puts, the
+/// lookups and the reads against the other blockstores.
+///
+/// [`RocksDB`]: https://rocksdb.org/
+pub struct RocksBlockStore {
+    path: PathBuf,
+    db: OnceCell<Arc<DB>>,
+    /// Held over checking whether the block exists and writing it, so that only one of the
+    /// concurrent puts of the same block reports [`BlockPut::NewBlock`].
+    writes: Arc<Mutex<()>>,
+}
+
+impl fmt::Debug for RocksBlockStore {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("RocksBlockStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl RocksBlockStore {
+    fn db(&self) -> Result<Arc<DB>, Error> {
+        self.db
+            .get()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("blockstore has not been initialized"))
+    }
+}
+
+fn column<'a>(db: &'a DB, name: &str) -> &'a ColumnFamily {
+    db.cf_handle(name)
+        .expect("the column families are created when opening")
+}
+
+fn block_key(cid: &Cid) -> Vec<u8> {
+    cid.hash().as_bytes().to_vec()
+}
+
+fn open(path: PathBuf) -> Result<DB, Error> {
+    let mut db_opts = Options::default();
+    db_opts.create_if_missing(true);
+    db_opts.create_missing_column_families(true);
+
+    let mut table_opts = BlockBasedOptions::default();
+    table_opts.set_bloom_filter(10.0, false);
+    table_opts.set_cache_index_and_filter_blocks(true);
+
+    let mut blocks_opts = Options::default();
+    blocks_opts.set_block_based_table_factory(&table_opts);
+    blocks_opts.set_enable_blob_files(true);
+    blocks_opts.set_min_blob_size(4 * 1024);
+    blocks_opts.set_enable_blob_gc(true);
+
+    // the Cids are small and read in order when listing, so they are left to the defaults
+    let cids_opts = Options::default();
+
+    let columns = vec![
+        ColumnFamilyDescriptor::new(BLOCKS, blocks_opts),
+        ColumnFamilyDescriptor::new(CIDS, cids_opts),
+    ];
+
+    Ok(DB::open_cf_descriptors(&db_opts, path, columns)?)
+}
+
+/// Reads the next page of the Cids and the block sizes, starting after the key `after`.
+fn read_page(db: &DB, after: Option<&[u8]>) -> Result<Vec<(Box<[u8]>, Cid, u64)>, Error> {
+    let mode = match after {
+        Some(key) => IteratorMode::From(key, Direction::Forward),
+        None => IteratorMode::Start,
+    };
+
+    let blocks = column(db, BLOCKS);
+    let mut page = Vec::with_capacity(ITER_PAGE);
+
+    for (key, cid) in db.iterator_cf(column(db, CIDS), mode) {
+        if Some(key.as_ref()) == after {
+            continue;
+        }
+        if page.len() == ITER_PAGE {
+            break;
+        }
+
+        // removed after the Cid was read
+        let len = match db.get_pinned_cf(blocks, &key)? {
+            Some(data) => data.len() as u64,
+            None => continue,
+        };
+
+        page.push((key, Cid::try_from(cid.as_ref())?, len));
+    }
+
+    Ok(page)
+}
+
+#[async_trait]
+impl BlockStore for RocksBlockStore {
+    fn new(path: PathBuf) -> Self {
+        RocksBlockStore {
+            path,
+            db: Default::default(),
+            writes: Default::default(),
+        }
+    }
+
+    async fn init(&self) -> Result<(), Error> {
+        let path = self.path.clone();
+        let db = tokio::task::spawn_blocking(move || open(path)).await??;
+
+        match self.db.set(Arc::new(db)) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(anyhow::anyhow!("failed to init rocksdb")),
+        }
+    }
+
+    async fn open(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn contains(&self, cid: &Cid) -> Result<bool, Error> {
+        let db = self.db()?;
+        let key = block_key(cid);
+
+        tokio::task::spawn_blocking(move || {
+            let blocks = column(&db, BLOCKS);
+            // the bloom filters rule out most of the missing blocks without reading the disk
+            if !db.key_may_exist_cf(blocks, &key) {
+                return Ok(false);
+            }
+            Ok(db.get_pinned_cf(blocks, &key)?.is_some())
+        })
+        .await?
+    }
+
+    async fn get(&self, cid: &Cid) -> Result<Option<Block>, Error> {
+        let db = self.db()?;
+        let key = block_key(cid);
+        let cid = cid.to_owned();
+
+        tokio::task::spawn_blocking(move || {
+            Ok(db
+                .get_cf(column(&db, BLOCKS), key)?
+                .map(|data| Block::new(data.into_boxed_slice(), cid)))
+        })
+        .await?
+    }
+
+    async fn put(&self, block: Block) -> Result<(Cid, BlockPut), Error> {
+        let db = self.db()?;
+        let writes = Arc::clone(&self.writes);
+
+        tokio::task::spawn_blocking(move || {
+            let key = block_key(&block.cid);
+            let blocks = column(&db, BLOCKS);
+
+            let _guard = writes.lock().unwrap();
+
+            if db.key_may_exist_cf(blocks, &key) && db.get_pinned_cf(blocks, &key)?.is_some() {
+                return Ok((block.cid, BlockPut::Existed));
+            }
+
+            let mut batch = WriteBatch::default();
+            batch.put_cf(blocks, &key, block.data());
+            batch.put_cf(column(&db, CIDS), &key, block.cid.to_bytes());
+            db.write(batch)?;
+
+            Ok((block.cid, BlockPut::NewBlock))
+        })
+        .await?
+    }
+
+    async fn remove(&self, cid: &Cid) -> Result<Result<BlockRm, BlockRmError>, Error> {
+        let db = self.db()?;
+        let writes = Arc::clone(&self.writes);
+        let key = block_key(cid);
+        let cid = cid.to_owned();
+
+        tokio::task::spawn_blocking(move || {
+            let blocks = column(&db, BLOCKS);
+
+            let _guard = writes.lock().unwrap();
+
+            if db.get_pinned_cf(blocks, &key)?.is_none() {
+                return Ok(Err(BlockRmError::NotFound(cid)));
+            }
+
+            let mut batch = WriteBatch::default();
+            batch.delete_cf(blocks, &key);
+            batch.delete_cf(column(&db, CIDS), &key);
+            db.write(batch)?;
+
+            Ok(Ok(BlockRm::Removed(cid)))
+        })
+        .await?
+    }
+
+    async fn list(&self) -> Result<Vec<Cid>, Error> {
+        let db = self.db()?;
+
+        tokio::task::spawn_blocking(move || {
+            db.iterator_cf(column(&db, CIDS), IteratorMode::Start)
+                .map(|(_, cid)| -> Result<Cid, Error> { Ok(Cid::try_from(cid.as_ref())?) })
+                .collect()
+        })
+        .await?
+    }
+
+    async fn iter(&self) -> Result<BoxStream<'static, Result<(Cid, u64), Error>>, Error> {
+        let db = self.db()?;
+
+        // the iterators borrow the database, so the entries are read a page at a time on the
+        // blocking threads as the stream is polled
+        let stream = async_stream::try_stream! {
+            let mut after: Option<Box<[u8]>> = None;
+
+            loop {
+                let db = Arc::clone(&db);
+                let start = after.take();
+                let page = tokio::task::spawn_blocking(move || read_page(&db, start.as_deref()))
+                    .await
+                    .map_err(Error::from)??;
+
+                if page.is_empty() {
+                    break;
+                }
+
+                for (key, cid, len) in page {
+                    after = Some(key);
+                    yield (cid, len);
+                }
+            }
+        };
+
+        Ok(stream.boxed())
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, Error> {
+        let db = self.db()?;
+        let cids = cids.to_vec();
+
+        tokio::task::spawn_blocking(move || {
+            let blocks = column(&db, BLOCKS);
+            let keys = cids.iter().map(|cid| (blocks, block_key(cid)));
+
+            db.multi_get_cf(keys)
+                .into_iter()
+                .zip(cids)
+                .map(|(data, cid)| -> Result<Option<Block>, Error> {
+                    Ok(data?.map(|data| Block::new(data.into_boxed_slice(), cid)))
+                })
+                .collect()
+        })
+        .await?
+    }
+
+    async fn wipe(&self) {
+        if let Ok(db) = self.db() {
+            let _ = tokio::task::spawn_blocking(move || {
+                for name in &[BLOCKS, CIDS] {
+                    let column = column(&db, name);
+                    let mut batch = WriteBatch::default();
+                    for (key, _) in db.iterator_cf(column, IteratorMode::Start) {
+                        batch.delete_cf(column, key);
+                    }
+                    let _ = db.write(batch);
+                }
+            })
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+crate::blockstore_interface_tests!(
+    blockstore_common_tests,
+    crate::repo::rocks::RocksBlockStore::new,
+    persistent
+);
+
+#[cfg(test)]
+mod tests {
+    use super::RocksBlockStore;
+    use crate::repo::BlockStore;
+    use crate::Block;
+    use cid::{Cid, Codec};
+    use futures::stream::TryStreamExt;
+    use multihash::Sha2_256;
+    use std::collections::HashSet;
+
+    fn raw_block(data: &[u8]) -> Block {
+        let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(data));
+        Block::new(data.to_vec().into_boxed_slice(), cid)
+    }
+
+    #[tokio::test]
+    async fn iterating_over_pages() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = RocksBlockStore::new(tmp.path().to_owned());
+        store.init().await.unwrap();
+
+        let count = super::ITER_PAGE * 2 + 1;
+        for i in 0..count {
+            store
+                .put(raw_block(i.to_string().as_bytes()))
+                .await
+                .unwrap();
+        }
+
+        let listed = store
+            .iter()
+            .await
+            .unwrap()
+            .map_ok(|(cid, _)| cid)
+            .try_collect::<HashSet<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(listed.len(), count);
+    }
+}
@@ -0,0 +1,709 @@
+//! Keeping the blocks in an S3 compatible object storage, for the nodes without a disk of their
+//! own. The bucket and its credentials are configured in [`ObjectStorage`], and with the
+//! `s3_block_store` feature the blocks are stored in it by the [`S3BlockStore`].
+
+use std::fmt;
+
+#[cfg(feature = "s3_block_store")]
+pub use self::store::S3BlockStore;
+
+/// A bucket of an S3 compatible object storage, like AWS S3, MinIO or Cloudflare R2, for the
+/// [`crate::repo::BlockStorage::object_storage`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct ObjectStorage {
+    /// The URL of the service, like `https://s3.eu-north-1.amazonaws.com` or
+    /// `http://localhost:9000`. The bucket is addressed in the path of the URL.
+    pub endpoint: String,
+    /// The region of the bucket, used in the signatures of the requests. The services without
+    /// regions usually accept `us-east-1`.
+    pub region: String,
+    pub bucket: String,
+    /// The prefix of the object keys, like `blocks/`, or empty for none.
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// The most bytes of the recently used blocks kept in memory, saving the requests for the
+    /// blocks read repeatedly, like the roots of the popular content.
+    pub cache_size: u64,
+}
+
+impl fmt::Debug for ObjectStorage {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the secret should not end up in the logs
+        fmt.debug_struct("ObjectStorage")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("access_key_id", &self.access_key_id)
+            .field("cache_size", &self.cache_size)
+            .finish()
+    }
+}
+
+#[cfg(feature = "s3_block_store")]
+mod store {
+    use super::ObjectStorage;
+    use crate::error::Error;
+    use crate::repo::{BlockPut, BlockRm, BlockRmError, BlockStorage, BlockStore};
+    use crate::Block;
+    use async_trait::async_trait;
+    use cid::Cid;
+    use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+    use hmac::{Hmac, Mac, NewMac};
+    use once_cell::sync::OnceCell;
+    use reqwest::{Method, StatusCode};
+    use sha2::{Digest, Sha256};
+    use std::collections::{BTreeMap, HashMap};
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    /// The requests made at a time by [`BlockStore::get_many`].
+    const CONCURRENT_REQUESTS: usize = 16;
+
+    const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+    /// Blockstore keeping the blocks as the objects of an S3 compatible bucket, configured with
+    /// the [`BlockStorage::object_storage`]. Compiled in with the `s3_block_store` feature, and
+    /// selected for the [`crate::Types`] with [`crate::repo::BlockBackend::ObjectStorage`].
+    ///
+    /// The objects are named like the files of the [`crate::repo::fs::FsBlockStore`], by the
+    /// Cid of the block upgraded to the version 1 in the default multibase, so that the block
+    /// put with a Cid of one version is also found with the other. The recently used blocks are
+    /// kept in memory up to the [`ObjectStorage::cache_size`], while the blockstore directory of
+    /// the repo is not used.
+    pub struct S3BlockStore {
+        storage: Option<ObjectStorage>,
+        bucket: OnceCell<Bucket>,
+    }
+
+    impl std::fmt::Debug for S3BlockStore {
+        fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            fmt.debug_struct("S3BlockStore")
+                .field("storage", &self.storage)
+                .finish()
+        }
+    }
+
+    impl S3BlockStore {
+        fn bucket(&self) -> Result<&Bucket, Error> {
+            self.bucket
+                .get()
+                .ok_or_else(|| anyhow::anyhow!("blockstore has not been initialized"))
+        }
+    }
+
+    /// The client of the bucket, signing the requests with the AWS signature version 4.
+    #[derive(Clone)]
+    struct Bucket {
+        client: reqwest::Client,
+        storage: ObjectStorage,
+        /// The endpoint without the trailing slashes.
+        endpoint: String,
+        /// The host and the port of the endpoint, as sent in the `Host` header.
+        host: String,
+        cache: Arc<Mutex<Cache>>,
+    }
+
+    impl Bucket {
+        fn new(storage: &ObjectStorage) -> Result<Self, Error> {
+            let endpoint = storage.endpoint.trim_end_matches('/').to_owned();
+            let url = reqwest::Url::parse(&endpoint)?;
+            let host = match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (Some(host), None) => host.to_owned(),
+                (None, _) => anyhow::bail!("no host in the endpoint {:?}", storage.endpoint),
+            };
+
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(60))
+                .build()?;
+
+            Ok(Bucket {
+                client,
+                storage: storage.clone(),
+                endpoint,
+                host,
+                cache: Arc::new(Mutex::new(Cache::new(storage.cache_size))),
+            })
+        }
+
+        /// Returns the key of the object of the block.
+        fn key(&self, cid: &Cid) -> String {
+            // like the file names of the FsBlockStore
+            let cid = if cid.version() == cid::Version::V1 {
+                cid.to_string()
+            } else {
+                Cid::new_v1(cid.codec(), cid.hash().to_owned()).to_string()
+            };
+            format!("{}{}", self.storage.prefix, cid)
+        }
+
+        /// Sends the signed request for the object `key`, or the bucket itself if `None`.
+        async fn send(
+            &self,
+            method: Method,
+            key: Option<&str>,
+            query: &[(&str, &str)],
+            body: Vec<u8>,
+        ) -> Result<reqwest::Response, Error> {
+            let mut path = format!("/{}", uri_encode(&self.storage.bucket, true));
+            if let Some(key) = key {
+                path.push('/');
+                path.push_str(&uri_encode(key, false));
+            }
+
+            let mut query = query
+                .iter()
+                .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+                .collect::<Vec<_>>();
+            query.sort();
+            let query = query
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("&");
+
+            // like 20220614T101502Z
+            let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+                .to_string()
+                .replace(&['-', ':'][..], "");
+            let date = &timestamp[..8];
+            let payload_hash = hex(&Sha256::digest(&body));
+
+            let canonical_request = format!(
+                "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+                method,
+                path,
+                query,
+                self.host,
+                payload_hash,
+                timestamp,
+                SIGNED_HEADERS,
+                payload_hash
+            );
+            let scope = format!("{}/{}/s3/aws4_request", date, self.storage.region);
+            let string_to_sign = format!(
+                "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                timestamp,
+                scope,
+                hex(&Sha256::digest(canonical_request.as_bytes()))
+            );
+
+            let secret = format!("AWS4{}", self.storage.secret_access_key);
+            let signing_key = [self.storage.region.as_str(), "s3", "aws4_request"]
+                .iter()
+                .fold(
+                    hmac_sha256(secret.as_bytes(), date.as_bytes()),
+                    |key, part| hmac_sha256(&key, part.as_bytes()),
+                );
+            let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+            let authorization = format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.storage.access_key_id, scope, SIGNED_HEADERS, signature
+            );
+
+            let mut url = format!("{}{}", self.endpoint, path);
+            if !query.is_empty() {
+                url.push('?');
+                url.push_str(&query);
+            }
+
+            let response = self
+                .client
+                .request(method.clone(), &url)
+                .header("x-amz-date", &timestamp)
+                .header("x-amz-content-sha256", &payload_hash)
+                .header(reqwest::header::AUTHORIZATION, authorization)
+                .body(body)
+                .send()
+                .await?;
+
+            match response.status() {
+                status if status.is_success() || status == StatusCode::NOT_FOUND => Ok(response),
+                status => Err(anyhow::anyhow!(
+                    "object storage responded with {} to {} {}",
+                    status,
+                    method,
+                    path
+                )),
+            }
+        }
+
+        /// Fails on the `404 Not Found` responses to the requests which only get it when the
+        /// bucket is missing.
+        fn found(&self, response: &reqwest::Response) -> Result<(), Error> {
+            if response.status() == StatusCode::NOT_FOUND {
+                anyhow::bail!("bucket {:?} not found", self.storage.bucket);
+            }
+            Ok(())
+        }
+
+        async fn contains(&self, key: &str) -> Result<bool, Error> {
+            if self.cache.lock().unwrap().contains(key) {
+                return Ok(true);
+            }
+            let response = self.send(Method::HEAD, Some(key), &[], Vec::new()).await?;
+            Ok(response.status() != StatusCode::NOT_FOUND)
+        }
+
+        async fn get(&self, cid: &Cid) -> Result<Option<Block>, Error> {
+            let key = self.key(cid);
+
+            if let Some(data) = self.cache.lock().unwrap().get(&key) {
+                return Ok(Some(Block::new(data, cid.to_owned())));
+            }
+
+            let response = self.send(Method::GET, Some(&key), &[], Vec::new()).await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let data = response.bytes().await?.to_vec().into_boxed_slice();
+
+            self.cache.lock().unwrap().insert(key, &data);
+            Ok(Some(Block::new(data, cid.to_owned())))
+        }
+
+        /// Returns a page of the Cids of the blocks and their sizes, and the token for the next
+        /// page if there is one.
+        async fn list_page(
+            &self,
+            token: Option<String>,
+        ) -> Result<(Vec<(Cid, u64)>, Option<String>), Error> {
+            let mut query = vec![("list-type", "2"), ("prefix", self.storage.prefix.as_str())];
+            if let Some(token) = token.as_deref() {
+                query.push(("continuation-token", token));
+            }
+
+            let response = self.send(Method::GET, None, &query, Vec::new()).await?;
+            self.found(&response)?;
+            let body = response.text().await?;
+
+            let mut page = Vec::new();
+            for contents in elements(&body, "Contents") {
+                let key = elements(contents, "Key").next().map(unescape);
+                let size = elements(contents, "Size").next().map(str::parse::<u64>);
+
+                let cid = key
+                    .as_deref()
+                    .and_then(|key| key.strip_prefix(self.storage.prefix.as_str()))
+                    .and_then(|cid| Cid::try_from(cid).ok());
+
+                // the other objects under the prefix are not blocks
+                if let (Some(cid), Some(Ok(size))) = (cid, size) {
+                    page.push((cid, size));
+                }
+            }
+
+            let truncated = elements(&body, "IsTruncated").next() == Some("true");
+            let next = elements(&body, "NextContinuationToken")
+                .next()
+                .filter(|_| truncated)
+                .map(unescape);
+
+            Ok((page, next))
+        }
+    }
+
+    #[async_trait]
+    impl BlockStore for S3BlockStore {
+        fn new(_path: PathBuf) -> Self {
+            S3BlockStore {
+                storage: None,
+                bucket: Default::default(),
+            }
+        }
+
+        fn configure(&mut self, storage: &BlockStorage) {
+            self.storage = storage.object_storage.clone();
+        }
+
+        async fn init(&self) -> Result<(), Error> {
+            let storage = self.storage.as_ref().ok_or_else(|| {
+                anyhow::anyhow!("no object storage configured for the blockstore")
+            })?;
+            let bucket = Bucket::new(storage)?;
+
+            // fails early on the wrong endpoint, bucket or credentials
+            let response = bucket.send(Method::HEAD, None, &[], Vec::new()).await?;
+            bucket.found(&response)?;
+
+            match self.bucket.set(bucket) {
+                Ok(()) => Ok(()),
+                Err(_) => Err(anyhow::anyhow!("failed to init object storage")),
+            }
+        }
+
+        async fn open(&self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn contains(&self, cid: &Cid) -> Result<bool, Error> {
+            let bucket = self.bucket()?;
+            bucket.contains(&bucket.key(cid)).await
+        }
+
+        async fn get(&self, cid: &Cid) -> Result<Option<Block>, Error> {
+            self.bucket()?.get(cid).await
+        }
+
+        async fn put(&self, block: Block) -> Result<(Cid, BlockPut), Error> {
+            let bucket = self.bucket()?;
+            let key = bucket.key(&block.cid);
+
+            // the objects are only ever written with the same content, so a block put
+            // concurrently may be written twice but never differently
+            if bucket.contains(&key).await? {
+                return Ok((block.cid, BlockPut::Existed));
+            }
+
+            let response = bucket
+                .send(Method::PUT, Some(&key), &[], block.data().to_vec())
+                .await?;
+            bucket.found(&response)?;
+            bucket.cache.lock().unwrap().insert(key, block.data());
+
+            Ok((block.cid, BlockPut::NewBlock))
+        }
+
+        async fn remove(&self, cid: &Cid) -> Result<Result<BlockRm, BlockRmError>, Error> {
+            let bucket = self.bucket()?;
+            let key = bucket.key(cid);
+
+            // deleting reports success also for the missing objects
+            let response = bucket
+                .send(Method::HEAD, Some(&key), &[], Vec::new())
+                .await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(Err(BlockRmError::NotFound(cid.to_owned())));
+            }
+
+            bucket.cache.lock().unwrap().remove(&key);
+            let response = bucket
+                .send(Method::DELETE, Some(&key), &[], Vec::new())
+                .await?;
+            bucket.found(&response)?;
+
+            Ok(Ok(BlockRm::Removed(cid.to_owned())))
+        }
+
+        async fn list(&self) -> Result<Vec<Cid>, Error> {
+            self.iter()
+                .await?
+                .map_ok(|(cid, _)| cid)
+                .try_collect()
+                .await
+        }
+
+        async fn iter(&self) -> Result<BoxStream<'static, Result<(Cid, u64), Error>>, Error> {
+            let bucket = self.bucket()?.clone();
+
+            // the bucket is listed a page of up to 1000 objects at a time as the stream is polled
+            let stream = async_stream::try_stream! {
+                let mut token = None;
+
+                loop {
+                    let (page, next) = bucket.list_page(token.take()).await?;
+
+                    for entry in page {
+                        yield entry;
+                    }
+
+                    match next {
+                        Some(next) => token = Some(next),
+                        None => break,
+                    }
+                }
+            };
+
+            Ok(stream.boxed())
+        }
+
+        async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block>>, Error> {
+            let bucket = self.bucket()?;
+
+            futures::stream::iter(cids)
+                .map(|cid| bucket.get(cid))
+                .buffered(CONCURRENT_REQUESTS)
+                .try_collect()
+                .await
+        }
+
+        async fn wipe(&self) {
+            let cids = match self.list().await {
+                Ok(cids) => cids,
+                Err(_) => return,
+            };
+            for cid in cids {
+                let _ = self.remove(&cid).await;
+            }
+        }
+    }
+
+    /// The most recently used blocks, up to a total size in bytes.
+    #[derive(Debug, Default)]
+    struct Cache {
+        capacity: u64,
+        size: u64,
+        /// The data and the last use of the blocks by the key.
+        entries: HashMap<String, (u64, Box<[u8]>)>,
+        /// The keys by the last use, the least recently used first.
+        uses: BTreeMap<u64, String>,
+        next_use: u64,
+    }
+
+    impl Cache {
+        fn new(capacity: u64) -> Self {
+            Cache {
+                capacity,
+                ..Default::default()
+            }
+        }
+
+        fn contains(&self, key: &str) -> bool {
+            self.entries.contains_key(key)
+        }
+
+        fn get(&mut self, key: &str) -> Option<Box<[u8]>> {
+            let next_use = self.next_use;
+            let (last_use, data) = self.entries.get_mut(key)?;
+
+            self.uses.remove(&*last_use);
+            self.uses.insert(next_use, key.to_owned());
+            *last_use = next_use;
+            self.next_use += 1;
+
+            Some(data.clone())
+        }
+
+        fn insert(&mut self, key: String, data: &[u8]) {
+            self.remove(&key);
+
+            let len = data.len() as u64;
+            if len > self.capacity {
+                return;
+            }
+
+            while self.size + len > self.capacity {
+                let oldest = match self.uses.keys().next() {
+                    Some(oldest) => *oldest,
+                    None => break,
+                };
+                if let Some(evicted) = self.uses.remove(&oldest) {
+                    self.remove(&evicted);
+                }
+            }
+
+            self.uses.insert(self.next_use, key.clone());
+            self.entries.insert(key, (self.next_use, data.into()));
+            self.next_use += 1;
+            self.size += len;
+        }
+
+        fn remove(&mut self, key: &str) {
+            if let Some((last_use, data)) = self.entries.remove(key) {
+                self.uses.remove(&last_use);
+                self.size -= data.len() as u64;
+            }
+        }
+    }
+
+    fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any length");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Percent-encodes all but the unreserved characters, and the slashes unless
+    /// `encode_slash`, as required by the signatures.
+    fn uri_encode(s: &str, encode_slash: bool) -> String {
+        let mut encoded = String::with_capacity(s.len());
+        for b in s.bytes() {
+            match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    encoded.push(b as char)
+                }
+                b'/' if !encode_slash => encoded.push('/'),
+                b => encoded.push_str(&format!("%{:02X}", b)),
+            }
+        }
+        encoded
+    }
+
+    /// Returns the contents of the elements named `tag`, enough for the flat responses of the
+    /// listings.
+    fn elements<'a>(xml: &'a str, tag: &str) -> impl Iterator<Item = &'a str> {
+        let open = format!("<{}>", tag);
+        let close = format!("</{}>", tag);
+        let mut rest = xml;
+
+        std::iter::from_fn(move || {
+            let start = rest.find(&open)? + open.len();
+            let len = rest[start..].find(&close)?;
+            let element = &rest[start..start + len];
+            rest = &rest[start + len + close.len()..];
+            Some(element)
+        })
+    }
+
+    fn unescape(text: &str) -> String {
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&")
+    }
+
+    #[cfg(test)]
+    crate::blockstore_interface_tests!(
+        blockstore_common_tests,
+        crate::repo::s3::store::tests::fake_bucket_store,
+        persistent
+    );
+
+    #[cfg(test)]
+    mod tests {
+        use super::{elements, hex, hmac_sha256, uri_encode, Cache, S3BlockStore};
+        use crate::repo::{BlockStorage, BlockStore, ObjectStorage};
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Method, Request, Response, Server, StatusCode};
+        use std::convert::Infallible;
+        use std::path::PathBuf;
+
+        /// Creates a blockstore of a bucket served from the `bucket` directory under the `path`
+        /// by a local server, which does not check the signatures of the requests.
+        pub(super) fn fake_bucket_store(path: PathBuf) -> S3BlockStore {
+            let dir = path.join("bucket");
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let make_service = make_service_fn(move |_| {
+                let dir = dir.clone();
+                async move { Ok::<_, Infallible>(service_fn(move |req| serve_object(dir.clone(), req))) }
+            });
+            let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+            let endpoint = format!("http://{}", server.local_addr());
+            tokio::spawn(server);
+
+            let mut store = S3BlockStore::new(path);
+            store.configure(&BlockStorage {
+                object_storage: Some(ObjectStorage {
+                    endpoint,
+                    region: "us-east-1".into(),
+                    bucket: "blocks".into(),
+                    prefix: String::new(),
+                    access_key_id: "key".into(),
+                    secret_access_key: "secret".into(),
+                    cache_size: 0,
+                }),
+                ..Default::default()
+            });
+            store
+        }
+
+        async fn serve_object(
+            dir: PathBuf,
+            req: Request<Body>,
+        ) -> Result<Response<Body>, Infallible> {
+            let respond = |status: StatusCode, body: Body| {
+                let mut response = Response::new(body);
+                *response.status_mut() = status;
+                Ok(response)
+            };
+
+            let (parts, body) = req.into_parts();
+            let key = match parts.uri.path().strip_prefix("/blocks") {
+                Some(key) => key.trim_start_matches('/'),
+                None => return respond(StatusCode::NOT_FOUND, Body::empty()),
+            };
+
+            if key.is_empty() {
+                if parts.method == Method::HEAD {
+                    return respond(StatusCode::OK, Body::empty());
+                }
+
+                let mut listing =
+                    String::from("<ListBucketResult><IsTruncated>false</IsTruncated>");
+                for entry in std::fs::read_dir(&dir).unwrap() {
+                    let entry = entry.unwrap();
+                    listing.push_str(&format!(
+                        "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                        entry.file_name().to_str().unwrap(),
+                        entry.metadata().unwrap().len()
+                    ));
+                }
+                listing.push_str("</ListBucketResult>");
+                return respond(StatusCode::OK, listing.into());
+            }
+
+            let path = dir.join(key);
+            match parts.method {
+                Method::PUT => {
+                    let data = hyper::body::to_bytes(body).await.unwrap();
+                    std::fs::write(&path, &data).unwrap();
+                    respond(StatusCode::OK, Body::empty())
+                }
+                Method::DELETE => {
+                    let _ = std::fs::remove_file(&path);
+                    respond(StatusCode::NO_CONTENT, Body::empty())
+                }
+                method => match std::fs::read(&path) {
+                    Ok(data) if method == Method::GET => respond(StatusCode::OK, data.into()),
+                    Ok(_) => respond(StatusCode::OK, Body::empty()),
+                    Err(_) => respond(StatusCode::NOT_FOUND, Body::empty()),
+                },
+            }
+        }
+
+        #[test]
+        fn least_recently_used_are_evicted() {
+            let mut cache = Cache::new(4);
+            cache.insert("a".into(), b"12");
+            cache.insert("b".into(), b"34");
+            assert!(cache.get("a").is_some());
+
+            cache.insert("c".into(), b"56");
+            assert!(cache.contains("a"));
+            assert!(!cache.contains("b"));
+            assert!(cache.contains("c"));
+
+            // too large to be cached at all
+            cache.insert("d".into(), b"12345");
+            assert!(!cache.contains("d"));
+            assert_eq!(cache.size, 4);
+        }
+
+        #[test]
+        fn signing_key_derivation() {
+            // the example of the AWS signature version 4 documentation
+            let key = ["us-east-1", "iam", "aws4_request"].iter().fold(
+                hmac_sha256(b"AWS4wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", b"20150830"),
+                |key, part| hmac_sha256(&key, part.as_bytes()),
+            );
+            assert_eq!(
+                hex(&key),
+                "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+            );
+        }
+
+        #[test]
+        fn listing_parts() {
+            let xml = "<ListBucketResult><IsTruncated>false</IsTruncated>\
+                <Contents><Key>a&amp;b</Key><Size>1</Size></Contents>\
+                <Contents><Key>c</Key><Size>2</Size></Contents></ListBucketResult>";
+
+            let keys = elements(xml, "Contents")
+                .filter_map(|contents| elements(contents, "Key").next())
+                .collect::<Vec<_>>();
+            assert_eq!(keys, vec!["a&amp;b", "c"]);
+            assert_eq!(super::unescape(keys[0]), "a&b");
+            assert_eq!(uri_encode("a b/c", false), "a%20b/c");
+        }
+    }
+}
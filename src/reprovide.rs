@@ -0,0 +1,393 @@
+//! Announcing the locally stored content as provider records in the DHT.
+//!
+//! The provider records expire from the DHT, so the [`Reprovider`] announces the blocks selected
+//! by the [`ReprovideStrategy`] shortly after being started and again on every
+//! [`ReprovideConfig::interval`]. With [`ReprovideStrategy::All`] the blocks added to the node are
+//! also announced as soon as they are stored, so that other nodes can find them right away. The
+//! announcements are made in batches of [`ReprovideConfig::batch_size`] concurrent DHT queries.
+
+use crate::error::Error;
+use crate::repo::{BlockAccessor, BlockHook, BlockPut, PinMode};
+use crate::task::{TaskHandle, TaskState};
+use crate::{Block, Ipfs, IpfsTypes};
+use async_trait::async_trait;
+use cid::Cid;
+use futures::stream::TryStreamExt;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Which of the local blocks are announced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReprovideStrategy {
+    /// All of the blocks in the blockstore.
+    All,
+    /// The pinned blocks, including the blocks referenced by the recursive pins.
+    Pinned,
+    /// Only the roots of the direct and the recursive pins.
+    Roots,
+}
+
+impl Default for ReprovideStrategy {
+    fn default() -> Self {
+        ReprovideStrategy::All
+    }
+}
+
+impl fmt::Display for ReprovideStrategy {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ReprovideStrategy::All => "all",
+            ReprovideStrategy::Pinned => "pinned",
+            ReprovideStrategy::Roots => "roots",
+        };
+        fmt.write_str(s)
+    }
+}
+
+/// The strategy was not one of `all`, `pinned` or `roots`.
+#[derive(Debug, thiserror::Error)]
+#[error("unknown reprovide strategy {0:?}, expected one of \"all\", \"pinned\" or \"roots\"")]
+pub struct UnknownStrategy(String);
+
+impl FromStr for ReprovideStrategy {
+    type Err = UnknownStrategy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(ReprovideStrategy::All),
+            "pinned" => Ok(ReprovideStrategy::Pinned),
+            "roots" => Ok(ReprovideStrategy::Roots),
+            other => Err(UnknownStrategy(other.to_owned())),
+        }
+    }
+}
+
+/// Configuration of the [`Reprovider`].
+#[derive(Debug, Clone)]
+pub struct ReprovideConfig {
+    /// Which of the local blocks are announced.
+    pub strategy: ReprovideStrategy,
+    /// How often all of the blocks selected by the `strategy` are announced again.
+    pub interval: Duration,
+    /// Delay before the first announcement of all of the blocks, allowing the node to connect
+    /// to the DHT first.
+    pub initial_delay: Duration,
+    /// The number of blocks announced at the same time.
+    pub batch_size: usize,
+}
+
+impl Default for ReprovideConfig {
+    fn default() -> Self {
+        // the timings follow the go-ipfs defaults
+        ReprovideConfig {
+            strategy: ReprovideStrategy::All,
+            interval: Duration::from_secs(12 * 60 * 60),
+            initial_delay: Duration::from_secs(60),
+            batch_size: 16,
+        }
+    }
+}
+
+/// The progress of the [`Reprovider`], returned by [`Reprovider::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReprovideStats {
+    /// Time the latest announcement of all of the blocks was started, if any.
+    pub last_reprovide: Option<SystemTime>,
+    /// Number of the blocks waiting to be announced.
+    pub queued: usize,
+    /// Number of the successful announcements.
+    pub provided: u64,
+    /// Number of the failed announcements.
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct Queue {
+    cids: VecDeque<Cid>,
+    /// The Cids in `cids`, so that a block is not queued twice.
+    queued: HashSet<Cid>,
+    /// True if all of the blocks should be announced without waiting for the interval.
+    reprovide_requested: bool,
+    stats: ReprovideStats,
+}
+
+impl Queue {
+    fn push(&mut self, cid: Cid) {
+        if self.queued.insert(cid.clone()) {
+            self.cids.push_back(cid);
+        }
+    }
+
+    fn pop_batch(&mut self, batch_size: usize) -> Vec<Cid> {
+        let len = batch_size.max(1).min(self.cids.len());
+        let batch = self.cids.drain(..len).collect::<Vec<_>>();
+        for cid in &batch {
+            self.queued.remove(cid);
+        }
+        batch
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+}
+
+fn push(shared: &TaskHandle<Shared>, cid: Cid) {
+    shared.queue.lock().unwrap().push(cid);
+    shared.wake();
+}
+
+/// Queues the blocks added to the node for [`ReprovideStrategy::All`].
+struct NewBlocks(TaskState<Shared>);
+
+impl fmt::Debug for NewBlocks {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("NewBlocks").finish()
+    }
+}
+
+#[async_trait]
+impl BlockHook for NewBlocks {
+    async fn after_put(&self, block: &Block, put: &BlockPut, _accessor: &BlockAccessor) {
+        if let (BlockPut::NewBlock, Some(shared)) = (put, self.0.upgrade()) {
+            push(&shared, block.cid().to_owned());
+        }
+    }
+}
+
+/// Handle to the announcing background task created with [`Reprovider::new`]. The background
+/// task exits after all of the handles have been dropped.
+#[derive(Clone)]
+pub struct Reprovider {
+    shared: TaskHandle<Shared>,
+}
+
+impl fmt::Debug for Reprovider {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Reprovider")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl Reprovider {
+    /// Creates a new reprovider for the given node. The returned future is the background task
+    /// which announces the blocks, and it should be spawned.
+    pub fn new<Types: IpfsTypes>(
+        ipfs: Ipfs<Types>,
+        config: ReprovideConfig,
+    ) -> (Self, impl Future<Output = ()>) {
+        let (shared, state) = TaskHandle::new(Shared::default());
+
+        if config.strategy == ReprovideStrategy::All {
+            ipfs.add_block_hook(Arc::new(NewBlocks(state.clone())));
+        }
+
+        let task = run(ipfs, config, state);
+
+        (Reprovider { shared }, task)
+    }
+
+    /// Queues the block to be announced with the next batch, regardless of the strategy.
+    pub fn provide(&self, cid: Cid) {
+        push(&self.shared, cid);
+    }
+
+    /// Announces all of the blocks selected by the strategy without waiting for the interval.
+    pub fn reprovide_now(&self) {
+        self.shared.queue.lock().unwrap().reprovide_requested = true;
+        self.shared.wake();
+    }
+
+    /// Returns the progress of the announcements.
+    pub fn stats(&self) -> ReprovideStats {
+        let queue = self.shared.queue.lock().unwrap();
+        ReprovideStats {
+            queued: queue.cids.len(),
+            ..queue.stats.clone()
+        }
+    }
+}
+
+/// Lists the Cids of the local blocks selected by the `strategy`.
+async fn keys<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    strategy: ReprovideStrategy,
+) -> Result<Vec<Cid>, Error> {
+    match strategy {
+        ReprovideStrategy::All => ipfs.repo.list_blocks().await,
+        ReprovideStrategy::Pinned | ReprovideStrategy::Roots => {
+            ipfs.list_pins(None)
+                .await
+                .try_filter_map(|(cid, mode)| {
+                    let selected =
+                        strategy == ReprovideStrategy::Pinned || !matches!(mode, PinMode::Indirect);
+                    futures::future::ready(Ok(Some(cid).filter(|_| selected)))
+                })
+                .try_collect()
+                .await
+        }
+    }
+}
+
+async fn run<Types: IpfsTypes>(
+    ipfs: Ipfs<Types>,
+    config: ReprovideConfig,
+    shared: TaskState<Shared>,
+) {
+    let mut next_reprovide = Instant::now() + config.initial_delay;
+
+    loop {
+        let (batch, reprovide) = {
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            let mut queue = shared.queue.lock().unwrap();
+
+            let reprovide = queue.reprovide_requested || Instant::now() >= next_reprovide;
+            if reprovide {
+                queue.reprovide_requested = false;
+                queue.stats.last_reprovide = Some(SystemTime::now());
+            }
+
+            (queue.pop_batch(config.batch_size), reprovide)
+        };
+
+        if reprovide {
+            next_reprovide = Instant::now() + config.interval;
+
+            match keys(&ipfs, config.strategy).await {
+                Ok(cids) => {
+                    debug!(strategy = %config.strategy, "reproviding {} blocks", cids.len());
+                    match shared.upgrade() {
+                        Some(shared) => {
+                            let mut queue = shared.queue.lock().unwrap();
+                            cids.into_iter().for_each(|cid| queue.push(cid));
+                        }
+                        None => return,
+                    }
+                }
+                Err(e) => warn!("failed to list the blocks to reprovide: {}", e),
+            }
+        }
+
+        if batch.is_empty() {
+            if !reprovide {
+                let sleep = tokio::time::sleep_until(next_reprovide.into());
+                tokio::select! {
+                    _ = sleep => {},
+                    _ = shared.notified() => {},
+                }
+            }
+            continue;
+        }
+
+        let results =
+            futures::future::join_all(batch.iter().map(|cid| ipfs.provide(cid.to_owned()))).await;
+
+        let mut failed = 0;
+        for (cid, result) in batch.iter().zip(results) {
+            if let Err(e) = result {
+                debug!("failed to provide {}: {}", cid, e);
+                failed += 1;
+            }
+        }
+
+        match shared.upgrade() {
+            Some(shared) => {
+                let mut queue = shared.queue.lock().unwrap();
+                queue.stats.provided += (batch.len() - failed) as u64;
+                queue.stats.failed += failed as u64;
+            }
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keys, ReprovideConfig, ReprovideStrategy, Reprovider};
+    use crate::{make_ipld, Block, Node};
+    use cid::{Cid, Codec};
+    use multihash::Sha2_256;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn strategies_select_blocks() {
+        let ipfs = Node::new("test_node").await;
+        let leaf = ipfs.put_dag(make_ipld!("leaf")).await.unwrap();
+        let root = ipfs.put_dag(make_ipld!([leaf.clone()])).await.unwrap();
+        let direct = ipfs.put_dag(make_ipld!("direct")).await.unwrap();
+        let unpinned = ipfs.put_dag(make_ipld!("unpinned")).await.unwrap();
+
+        ipfs.insert_pin(&root, true).await.unwrap();
+        ipfs.insert_pin(&direct, false).await.unwrap();
+
+        let select = |strategy| {
+            let ipfs = &ipfs.ipfs;
+            async move {
+                keys(ipfs, strategy)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .collect::<HashSet<_>>()
+            }
+        };
+
+        let all = select(ReprovideStrategy::All).await;
+        let pinned = select(ReprovideStrategy::Pinned).await;
+        let roots = select(ReprovideStrategy::Roots).await;
+
+        assert!(all.contains(&unpinned) && all.contains(&leaf));
+        assert_eq!(
+            pinned,
+            vec![root.clone(), leaf, direct.clone()]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(roots, vec![root, direct].into_iter().collect());
+    }
+
+    #[tokio::test]
+    async fn new_blocks_are_queued_once() {
+        let ipfs = Node::new("test_node").await;
+        let config = ReprovideConfig {
+            initial_delay: Duration::from_secs(60 * 60),
+            ..Default::default()
+        };
+        // the background task is not spawned, leaving the blocks queued
+        let (reprovider, _task) = Reprovider::new(ipfs.ipfs.clone(), config);
+
+        let data = b"new block".to_vec().into_boxed_slice();
+        let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data));
+        ipfs.put_block(Block::new(data.clone(), cid.clone()))
+            .await
+            .unwrap();
+        ipfs.put_block(Block::new(data, cid.clone())).await.unwrap();
+        reprovider.provide(cid);
+
+        assert_eq!(reprovider.stats().queued, 1);
+    }
+
+    #[test]
+    fn strategy_round_trips() {
+        for strategy in &[
+            ReprovideStrategy::All,
+            ReprovideStrategy::Pinned,
+            ReprovideStrategy::Roots,
+        ] {
+            assert_eq!(
+                strategy.to_string().parse::<ReprovideStrategy>().ok(),
+                Some(*strategy)
+            );
+        }
+        assert!("everything".parse::<ReprovideStrategy>().is_err());
+    }
+}
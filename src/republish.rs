@@ -0,0 +1,667 @@
+//! Periodic republishing of IPNS and provider records.
+//!
+//! The records published to the DHT expire, so they need to be published again before that. The
+//! [`Republisher`] keeps track of the records to republish, spreads the work over time by adding
+//! jitter to the deadlines and retries failures with an exponential backoff.
+//!
+//! The IPNS records are also put to the DHT again between the intervals when the peers closest to
+//! the record key have changed enough since the record was last put, as the peers holding the
+//! replicas may have left the network. The closest peers are taken from the local routing table,
+//! so this does not cost any queries until the record needs to be put again.
+
+use crate::error::Error;
+use crate::ipns::{self, PublishOptions, Signer};
+use crate::path::IpfsPath;
+use crate::task::{TaskHandle, TaskState};
+use crate::{Ipfs, IpfsTypes};
+use cid::Cid;
+use libp2p::core::PeerId;
+use libp2p::kad::K_VALUE;
+use std::collections::hash_map::{Entry, HashMap, RandomState};
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Identifies a record maintained by the [`Republisher`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RecordKey {
+    /// The IPNS record of the given name.
+    Ipns(PeerId),
+    /// The provider record of the local node for the given Cid.
+    Provider(Cid),
+}
+
+impl fmt::Display for RecordKey {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordKey::Ipns(name) => write!(fmt, "/ipns/{}", name),
+            RecordKey::Provider(cid) => write!(fmt, "/providers/{}", cid),
+        }
+    }
+}
+
+/// Timing configuration of the [`Republisher`].
+#[derive(Debug, Clone)]
+pub struct RepublishConfig {
+    /// How often the IPNS records are republished.
+    pub ipns_interval: Duration,
+    /// How often the provider records are republished.
+    pub provider_interval: Duration,
+    /// How often the provider records of the popular content are republished, see
+    /// [`crate::popularity`]. When `None`, the `provider_interval` is used for all of the
+    /// provider records.
+    pub popular_provider_interval: Option<Duration>,
+    /// The fraction of the interval used as jitter, from `0.0` to `1.0`. The records are
+    /// republished early by at most this fraction of the interval.
+    pub jitter: f64,
+    /// Delay before the first retry of a failed publication, doubled on every consecutive failure.
+    pub initial_backoff: Duration,
+    /// Upper bound for the retry delay.
+    pub max_backoff: Duration,
+    /// How often the peers closest to the IPNS record keys are compared to the ones the records
+    /// were last put to. When `None`, the IPNS records are put only at the `ipns_interval`.
+    pub ipns_churn_check: Option<Duration>,
+    /// The fraction of the peers the IPNS record was last put to, from `0.0` to `1.0`, which
+    /// need to have dropped out of the closest peers for the record to be put again.
+    pub ipns_churn_threshold: f64,
+}
+
+impl Default for RepublishConfig {
+    fn default() -> Self {
+        // the intervals follow the go-ipfs defaults
+        RepublishConfig {
+            ipns_interval: Duration::from_secs(4 * 60 * 60),
+            provider_interval: Duration::from_secs(12 * 60 * 60),
+            popular_provider_interval: None,
+            jitter: 0.1,
+            initial_backoff: Duration::from_secs(30),
+            max_backoff: Duration::from_secs(30 * 60),
+            ipns_churn_check: Some(Duration::from_secs(10 * 60)),
+            ipns_churn_threshold: 0.5,
+        }
+    }
+}
+
+/// The publishing status of a single record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordStatus {
+    /// Time of the latest successful publication, if any.
+    pub last_published: Option<SystemTime>,
+    /// Number of consecutive failed publications.
+    pub failures: u32,
+    /// The error from the latest failed publication, cleared on success.
+    pub last_error: Option<String>,
+}
+
+enum Job {
+    Ipns(Arc<dyn Signer>, IpfsPath),
+    Provide(Cid),
+}
+
+struct Scheduled {
+    job: Job,
+    deadline: Instant,
+    status: RecordStatus,
+    /// True if the content of a provider record was popular when it was last published.
+    popular: bool,
+    /// The peers closest to the key of an IPNS record when it was last put.
+    replicas: Vec<PeerId>,
+}
+
+/// The deadline bookkeeping, separate from the publishing for testing purposes.
+struct Schedule {
+    config: RepublishConfig,
+    records: HashMap<RecordKey, Scheduled>,
+    /// When the closest peers of the IPNS records were last checked for churn.
+    churn_checked: Option<Instant>,
+}
+
+impl Schedule {
+    fn new(config: RepublishConfig) -> Self {
+        Schedule {
+            config,
+            records: HashMap::new(),
+            churn_checked: None,
+        }
+    }
+
+    fn interval(&self, key: &RecordKey) -> Duration {
+        match key {
+            RecordKey::Ipns(_) => self.config.ipns_interval,
+            RecordKey::Provider(_) => match self.config.popular_provider_interval {
+                Some(interval) if self.records.get(key).map_or(false, |s| s.popular) => interval,
+                _ => self.config.provider_interval,
+            },
+        }
+    }
+
+    /// Random duration of up to `jitter` fraction of the interval of the `key`.
+    fn jitter(&self, key: &RecordKey, attempt: u32) -> Duration {
+        let fraction = self.config.jitter.max(0.0).min(1.0) * random_fraction(key, attempt);
+        self.interval(key).mul_f64(fraction)
+    }
+
+    /// Adds or replaces the record. New records are scheduled within the jitter window so that
+    /// adding many records at once does not publish them all at once.
+    fn insert(&mut self, key: RecordKey, job: Job, now: Instant) {
+        let deadline = now + self.jitter(&key, 0);
+        match self.records.entry(key) {
+            Entry::Occupied(mut oe) => {
+                let scheduled = oe.get_mut();
+                scheduled.job = job;
+                scheduled.deadline = deadline;
+            }
+            Entry::Vacant(ve) => {
+                ve.insert(Scheduled {
+                    job,
+                    deadline,
+                    status: RecordStatus {
+                        last_published: None,
+                        failures: 0,
+                        last_error: None,
+                    },
+                    popular: false,
+                    replicas: Vec::new(),
+                });
+            }
+        }
+    }
+
+    fn set_popular(&mut self, key: &RecordKey, popular: bool) {
+        if let Some(scheduled) = self.records.get_mut(key) {
+            scheduled.popular = popular;
+        }
+    }
+
+    /// Remembers the peers closest to the key of the IPNS record as it was put.
+    fn set_replicas(&mut self, key: &RecordKey, replicas: Vec<PeerId>, now: Instant) {
+        if let Some(scheduled) = self.records.get_mut(key) {
+            scheduled.replicas = replicas;
+            self.churn_checked.get_or_insert(now);
+        }
+    }
+
+    /// The time of the next churn check, if enabled and there are records to check.
+    fn next_churn_check(&self) -> Option<Instant> {
+        let interval = self.config.ipns_churn_check?;
+        let checked = self.churn_checked?;
+        if self.records.values().any(|s| !s.replicas.is_empty()) {
+            Some(checked + interval)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the names of the IPNS records to check with the peers they were last put to, and
+    /// marks the check as started.
+    fn start_churn_check(&mut self, now: Instant) -> Vec<(PeerId, Vec<PeerId>)> {
+        self.churn_checked = Some(now);
+        self.records
+            .iter()
+            .filter(|(_, s)| !s.replicas.is_empty())
+            .filter_map(|(key, s)| match key {
+                RecordKey::Ipns(name) => Some((*name, s.replicas.clone())),
+                RecordKey::Provider(_) => None,
+            })
+            .collect()
+    }
+
+    /// Returns true if enough of the `replicas` are no longer among the `closest` peers.
+    fn churned(&self, replicas: &[PeerId], closest: &[PeerId]) -> bool {
+        if replicas.is_empty() {
+            return false;
+        }
+        let gone = replicas.iter().filter(|p| !closest.contains(p)).count();
+        let threshold = self.config.ipns_churn_threshold.max(0.0).min(1.0);
+        gone > 0 && gone as f64 >= threshold * replicas.len() as f64
+    }
+
+    fn remove(&mut self, key: &RecordKey) -> bool {
+        self.records.remove(key).is_some()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.records.values().map(|s| s.deadline).min()
+    }
+
+    fn due(&self, now: Instant) -> Vec<RecordKey> {
+        self.records
+            .iter()
+            .filter(|(_, s)| s.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn completed(&mut self, key: &RecordKey, result: Result<(), Error>, now: Instant) {
+        let interval = self.interval(key);
+        let success_jitter = self.jitter(key, 0);
+        let initial_backoff = self.config.initial_backoff;
+        let max_backoff = self.config.max_backoff;
+
+        let scheduled = match self.records.get_mut(key) {
+            Some(scheduled) => scheduled,
+            // removed while being published
+            None => return,
+        };
+
+        match result {
+            Ok(()) => {
+                scheduled.deadline = now + interval - success_jitter;
+                scheduled.status.last_published = Some(SystemTime::now());
+                scheduled.status.failures = 0;
+                scheduled.status.last_error = None;
+            }
+            Err(e) => {
+                let failures = scheduled.status.failures.saturating_add(1);
+                let backoff = initial_backoff
+                    .checked_mul(1 << (failures - 1).min(16))
+                    .unwrap_or(max_backoff)
+                    .min(max_backoff);
+                // jitter the retries by at most half of the backoff
+                let jitter = backoff.mul_f64(0.5 * random_fraction(key, failures));
+
+                scheduled.deadline = now + backoff - jitter;
+                scheduled.status.failures = failures;
+                scheduled.status.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+/// Uniformly distributed value in `[0, 1)`, without requiring a random number generator
+/// dependency.
+fn random_fraction(key: &RecordKey, attempt: u32) -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    key.hash(&mut hasher);
+    attempt.hash(&mut hasher);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+struct Shared {
+    schedule: Mutex<Schedule>,
+}
+
+/// Handle to the republishing background task created with [`Republisher::new`]. The background
+/// task exits after all of the handles have been dropped.
+#[derive(Clone)]
+pub struct Republisher {
+    shared: TaskHandle<Shared>,
+    keypair: crate::Keypair,
+}
+
+impl fmt::Debug for Republisher {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let records = self.shared.schedule.lock().unwrap().records.len();
+        fmt.debug_struct("Republisher")
+            .field("records", &records)
+            .finish()
+    }
+}
+
+impl Republisher {
+    /// Creates a new republisher for the given node. The returned future is the background task
+    /// which publishes the records, and it should be spawned.
+    pub fn new<Types: IpfsTypes>(
+        ipfs: Ipfs<Types>,
+        config: RepublishConfig,
+    ) -> (Self, impl Future<Output = ()>) {
+        let (shared, state) = TaskHandle::new(Shared {
+            schedule: Mutex::new(Schedule::new(config)),
+        });
+
+        let keypair = ipfs.keys.get_ref().clone();
+        let task = run(ipfs, state);
+
+        (Republisher { shared, keypair }, task)
+    }
+
+    /// Keeps the IPNS record of the node's own name pointing at `path`.
+    pub fn republish_ipns(&self, path: IpfsPath) -> PeerId {
+        let signer = Arc::new(self.keypair.clone());
+        self.republish_ipns_with(signer, path)
+    }
+
+    /// Keeps the IPNS record of the name of `signer` pointing at `path`.
+    pub fn republish_ipns_with(&self, signer: Arc<dyn Signer>, path: IpfsPath) -> PeerId {
+        let name = signer.public_key().to_peer_id();
+        self.insert(RecordKey::Ipns(name), Job::Ipns(signer, path));
+        name
+    }
+
+    /// Keeps the provider record of `cid` published.
+    pub fn republish_provider(&self, cid: Cid) {
+        self.insert(RecordKey::Provider(cid.clone()), Job::Provide(cid));
+    }
+
+    /// Stops republishing the record; returns `false` if it was not being republished.
+    pub fn remove(&self, key: &RecordKey) -> bool {
+        self.shared.schedule.lock().unwrap().remove(key)
+    }
+
+    /// Returns the status of the record, if it is being republished.
+    pub fn status(&self, key: &RecordKey) -> Option<RecordStatus> {
+        let schedule = self.shared.schedule.lock().unwrap();
+        schedule.records.get(key).map(|s| s.status.clone())
+    }
+
+    /// Returns the status of all of the records being republished.
+    pub fn list(&self) -> Vec<(RecordKey, RecordStatus)> {
+        let schedule = self.shared.schedule.lock().unwrap();
+        schedule
+            .records
+            .iter()
+            .map(|(key, s)| (key.clone(), s.status.clone()))
+            .collect()
+    }
+
+    fn insert(&self, key: RecordKey, job: Job) {
+        self.shared
+            .schedule
+            .lock()
+            .unwrap()
+            .insert(key, job, Instant::now());
+        self.shared.wake();
+    }
+}
+
+async fn run<Types: IpfsTypes>(ipfs: Ipfs<Types>, shared: TaskState<Shared>) {
+    loop {
+        let (due, churn_check, next_deadline) = {
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            let mut schedule = shared.schedule.lock().unwrap();
+            let now = Instant::now();
+
+            let due = schedule
+                .due(now)
+                .into_iter()
+                .map(|key| {
+                    let job = match &schedule.records[&key].job {
+                        Job::Ipns(signer, path) => Job::Ipns(Arc::clone(signer), path.clone()),
+                        Job::Provide(cid) => Job::Provide(cid.clone()),
+                    };
+                    (key, job)
+                })
+                .collect::<Vec<_>>();
+
+            let churn_check = match schedule.next_churn_check() {
+                Some(check) if check <= now => schedule.start_churn_check(now),
+                _ => Vec::new(),
+            };
+
+            let next_deadline = match (schedule.next_deadline(), schedule.next_churn_check()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+
+            (due, churn_check, next_deadline)
+        };
+
+        if due.is_empty() && churn_check.is_empty() {
+            match next_deadline {
+                Some(deadline) => {
+                    let sleep = tokio::time::sleep_until(deadline.into());
+                    tokio::select! {
+                        _ = sleep => {},
+                        _ = shared.notified() => {},
+                    }
+                }
+                None => shared.notified().await,
+            }
+            continue;
+        }
+
+        for (name, replicas) in churn_check {
+            let closest = match ipfs
+                .local_closest_peers(ipns::record_key(&name), K_VALUE.get())
+                .await
+            {
+                Ok(closest) => closest,
+                // the node is shutting down
+                Err(_) => return,
+            };
+
+            let key = RecordKey::Ipns(name);
+
+            match shared.upgrade() {
+                Some(shared) => {
+                    if !shared.schedule.lock().unwrap().churned(&replicas, &closest) {
+                        continue;
+                    }
+                }
+                None => return,
+            }
+
+            // the replicas are kept on failure so that the next check tries again
+            match ipfs.ipns().put_stored_record(&name).await {
+                Ok(true) => debug!(record = %key, "put again after the closest peers changed"),
+                Ok(false) => continue,
+                Err(e) => {
+                    debug!(record = %key, "putting again failed: {}", e);
+                    continue;
+                }
+            }
+
+            match shared.upgrade() {
+                Some(shared) => {
+                    shared
+                        .schedule
+                        .lock()
+                        .unwrap()
+                        .set_replicas(&key, closest, Instant::now())
+                }
+                None => return,
+            }
+        }
+
+        for (key, job) in due {
+            let popular = match &job {
+                Job::Provide(cid) => ipfs.popularity().is_popular(cid),
+                Job::Ipns(..) => false,
+            };
+
+            let result = match job {
+                Job::Ipns(signer, path) => ipfs
+                    .publish_ipns_with(&*signer, &path, &PublishOptions::default())
+                    .await
+                    .map(|_| ()),
+                Job::Provide(cid) => ipfs.provide(cid).await,
+            };
+
+            let replicas = match (&key, &result) {
+                (RecordKey::Ipns(name), Ok(())) => ipfs
+                    .local_closest_peers(ipns::record_key(name), K_VALUE.get())
+                    .await
+                    .ok(),
+                _ => None,
+            };
+
+            match &result {
+                Ok(()) => debug!(record = %key, "republished"),
+                Err(e) => debug!(record = %key, "republishing failed: {}", e),
+            }
+
+            match shared.upgrade() {
+                Some(shared) => {
+                    let mut schedule = shared.schedule.lock().unwrap();
+                    let now = Instant::now();
+                    schedule.set_popular(&key, popular);
+                    if let Some(replicas) = replicas {
+                        schedule.set_replicas(&key, replicas, now);
+                    }
+                    schedule.completed(&key, result, now)
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Job, RecordKey, RepublishConfig, Schedule};
+    use crate::Keypair;
+    use cid::Cid;
+    use libp2p::core::PeerId;
+    use std::convert::TryFrom;
+    use std::time::{Duration, Instant};
+
+    fn provider_key() -> RecordKey {
+        RecordKey::Provider(
+            Cid::try_from("QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap(),
+        )
+    }
+
+    fn insert_provider(schedule: &mut Schedule, now: Instant) -> RecordKey {
+        let key = provider_key();
+        let cid = match &key {
+            RecordKey::Provider(cid) => cid.clone(),
+            _ => unreachable!(),
+        };
+        schedule.insert(key.clone(), Job::Provide(cid), now);
+        key
+    }
+
+    #[test]
+    fn new_records_are_spread_within_jitter() {
+        let config = RepublishConfig::default();
+        let max_jitter = config.provider_interval.mul_f64(config.jitter);
+        let mut schedule = Schedule::new(config);
+        let now = Instant::now();
+
+        insert_provider(&mut schedule, now);
+
+        let deadline = schedule.next_deadline().unwrap();
+        assert!(deadline >= now && deadline <= now + max_jitter);
+        assert!(schedule.due(now + max_jitter).len() == 1);
+    }
+
+    #[test]
+    fn success_schedules_next_interval() {
+        let config = RepublishConfig::default();
+        let interval = config.provider_interval;
+        let max_jitter = interval.mul_f64(config.jitter);
+        let mut schedule = Schedule::new(config);
+        let now = Instant::now();
+
+        let key = insert_provider(&mut schedule, now);
+        schedule.completed(&key, Ok(()), now);
+
+        let deadline = schedule.next_deadline().unwrap();
+        assert!(deadline <= now + interval && deadline >= now + interval - max_jitter);
+
+        let status = &schedule.records[&key].status;
+        assert!(status.last_published.is_some());
+        assert_eq!(status.failures, 0);
+    }
+
+    #[test]
+    fn popular_providers_are_republished_more_often() {
+        let config = RepublishConfig {
+            popular_provider_interval: Some(Duration::from_secs(60 * 60)),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let mut schedule = Schedule::new(config);
+        let now = Instant::now();
+
+        let key = insert_provider(&mut schedule, now);
+        schedule.set_popular(&key, true);
+        schedule.completed(&key, Ok(()), now);
+
+        assert_eq!(
+            schedule.next_deadline().unwrap(),
+            now + Duration::from_secs(60 * 60)
+        );
+    }
+
+    #[test]
+    fn failures_back_off_up_to_max() {
+        let config = RepublishConfig {
+            initial_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let mut schedule = Schedule::new(config);
+        let now = Instant::now();
+
+        let key = insert_provider(&mut schedule, now);
+
+        for (nth, expected) in [10u64, 20, 40, 60, 60].iter().enumerate() {
+            schedule.completed(&key, Err(anyhow::anyhow!("failed #{}", nth)), now);
+
+            let expected = Duration::from_secs(*expected);
+            let deadline = schedule.next_deadline().unwrap();
+            assert!(deadline <= now + expected, "retry #{} too late", nth);
+            assert!(deadline >= now + expected / 2, "retry #{} too early", nth);
+        }
+
+        let status = &schedule.records[&key].status;
+        assert_eq!(status.failures, 5);
+        assert_eq!(status.last_error.as_deref(), Some("failed #4"));
+        assert!(status.last_published.is_none());
+    }
+
+    #[test]
+    fn removed_while_publishing_is_not_rescheduled() {
+        let mut schedule = Schedule::new(RepublishConfig::default());
+        let now = Instant::now();
+
+        let key = insert_provider(&mut schedule, now);
+        assert!(schedule.remove(&key));
+        schedule.completed(&key, Ok(()), now);
+
+        assert!(schedule.next_deadline().is_none());
+    }
+
+    #[test]
+    fn ipns_records_are_put_again_after_churn() {
+        let config = RepublishConfig {
+            ipns_churn_check: Some(Duration::from_secs(60)),
+            ipns_churn_threshold: 0.5,
+            ..Default::default()
+        };
+        let mut schedule = Schedule::new(config);
+        let now = Instant::now();
+
+        let keypair = Keypair::generate_ed25519();
+        let name = keypair.public().to_peer_id();
+        let key = RecordKey::Ipns(name);
+        let path = "/ipfs/QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH"
+            .parse()
+            .unwrap();
+        schedule.insert(
+            key.clone(),
+            Job::Ipns(std::sync::Arc::new(keypair), path),
+            now,
+        );
+
+        // nothing to check before the record has been put
+        assert!(schedule.next_churn_check().is_none());
+
+        let replicas = (0..4).map(|_| PeerId::random()).collect::<Vec<_>>();
+        schedule.set_replicas(&key, replicas.clone(), now);
+        assert_eq!(
+            schedule.next_churn_check(),
+            Some(now + Duration::from_secs(60))
+        );
+
+        let checked = schedule.start_churn_check(now + Duration::from_secs(60));
+        assert_eq!(checked, vec![(name, replicas.clone())]);
+        assert_eq!(
+            schedule.next_churn_check(),
+            Some(now + Duration::from_secs(120))
+        );
+
+        let mut closest = replicas.clone();
+        closest[0] = PeerId::random();
+        assert!(!schedule.churned(&replicas, &closest));
+
+        closest[1] = PeerId::random();
+        assert!(schedule.churned(&replicas, &closest));
+    }
+}
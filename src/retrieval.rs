@@ -0,0 +1,275 @@
+//! Policies for fetching the missing blocks from the network, see [`RetrievalStrategy`].
+//!
+//! The strategies are used with [`crate::Ipfs::get_block_with`], [`crate::Ipfs::get_dag_with`]
+//! and [`crate::Ipfs::insert_pin_with`], while the other operations keep asking the connected
+//! peers and looking up the providers at the same time, and wait until they are cancelled.
+
+use crate::refs::ipld_links;
+use crate::{Block, Error, Ipfs, IpfsTypes};
+use cid::Cid;
+use futures::future::{self, Either};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+
+/// When the providers of a missing block are looked up in the DHT and connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderDiscovery {
+    /// Along with asking the block from the connected peers.
+    Immediately,
+    /// Once the connected peers have not sent the block in the given time.
+    After(Duration),
+    /// Never, the block is only asked from the connected peers.
+    Never,
+}
+
+/// Controls how the missing blocks are fetched, trading latency against network cost.
+pub trait RetrievalStrategy: fmt::Debug + Send + Sync {
+    /// When the providers of a missing block are looked up.
+    fn provider_discovery(&self) -> ProviderDiscovery;
+
+    /// How many of the discovered providers of a block are connected to.
+    fn max_providers(&self) -> usize;
+
+    /// How many blocks of a DAG are fetched at the same time when pinning it.
+    fn parallelism(&self) -> usize;
+
+    /// How long a missing block is waited for before giving up, or `None` to wait until the
+    /// operation is cancelled.
+    fn block_timeout(&self) -> Option<Duration>;
+}
+
+/// Looks up the providers right away and fetches many blocks at once, giving up on the blocks
+/// which do not arrive quickly.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FastFirst;
+
+impl RetrievalStrategy for FastFirst {
+    fn provider_discovery(&self) -> ProviderDiscovery {
+        ProviderDiscovery::Immediately
+    }
+
+    fn max_providers(&self) -> usize {
+        20
+    }
+
+    fn parallelism(&self) -> usize {
+        32
+    }
+
+    fn block_timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(30))
+    }
+}
+
+/// Asks the connected peers first and looks up only a few providers of the blocks they do not
+/// send, fetching a single block at a time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cheapest;
+
+impl RetrievalStrategy for Cheapest {
+    fn provider_discovery(&self) -> ProviderDiscovery {
+        ProviderDiscovery::After(Duration::from_secs(30))
+    }
+
+    fn max_providers(&self) -> usize {
+        3
+    }
+
+    fn parallelism(&self) -> usize {
+        1
+    }
+
+    fn block_timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(5 * 60))
+    }
+}
+
+/// Looks up the providers right away and never gives up on a block.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Thorough;
+
+impl RetrievalStrategy for Thorough {
+    fn provider_discovery(&self) -> ProviderDiscovery {
+        ProviderDiscovery::Immediately
+    }
+
+    fn max_providers(&self) -> usize {
+        usize::MAX
+    }
+
+    fn parallelism(&self) -> usize {
+        8
+    }
+
+    fn block_timeout(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// The block was not fetched within the [`RetrievalStrategy::block_timeout`].
+#[derive(Debug, thiserror::Error)]
+#[error("gave up on fetching {0} after {1:?}")]
+pub struct GaveUp(pub Cid, pub Duration);
+
+/// Fetches the block following the `strategy`.
+pub(crate) async fn fetch_block<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    cid: &Cid,
+    strategy: &dyn RetrievalStrategy,
+) -> Result<Block, Error> {
+    let discovery = strategy.provider_discovery();
+
+    // the providers are looked up here instead of along with the want, to be connected to
+    let fetch = ipfs.repo.fetch_block(cid, false);
+
+    let lookup = async {
+        match discovery {
+            ProviderDiscovery::Immediately => {}
+            ProviderDiscovery::After(delay) => tokio::time::sleep(delay).await,
+            ProviderDiscovery::Never => return future::pending().await,
+        }
+
+        match ipfs.get_providers(cid.to_owned()).await {
+            Ok(providers) => {
+                let local = ipfs.keys.get_ref().public().to_peer_id();
+                let providers = providers
+                    .into_iter()
+                    .filter(|peer_id| *peer_id != local)
+                    .take(strategy.max_providers())
+                    .collect::<Vec<_>>();
+                debug!("connecting to {} providers of {}", providers.len(), cid);
+                ipfs.dial_peers(providers).await;
+            }
+            Err(e) => debug!("failed to look up the providers of {}: {}", cid, e),
+        }
+
+        future::pending::<()>().await
+    };
+
+    futures::pin_mut!(fetch, lookup);
+
+    let fetch = async {
+        match future::select(fetch, lookup).await {
+            Either::Left((res, _)) => res,
+            Either::Right(((), _)) => unreachable!("the lookup never completes"),
+        }
+    };
+
+    match strategy.block_timeout() {
+        Some(limit) => tokio::time::timeout(limit, fetch)
+            .await
+            .map_err(|_| GaveUp(cid.to_owned(), limit))?,
+        None => fetch.await,
+    }
+}
+
+/// Fetches all of the blocks of the DAG rooted at `root` following the `strategy`, up to
+/// [`RetrievalStrategy::parallelism`] blocks at a time.
+pub(crate) async fn fetch_dag<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    root: &Cid,
+    strategy: &dyn RetrievalStrategy,
+) -> Result<(), Error> {
+    let parallelism = strategy.parallelism().max(1);
+    let mut seen = HashSet::new();
+    let mut pending = vec![root.to_owned()];
+    let mut in_flight = FuturesUnordered::new();
+
+    seen.insert(root.to_owned());
+
+    loop {
+        while in_flight.len() < parallelism {
+            let cid = match pending.pop() {
+                Some(cid) => cid,
+                None => break,
+            };
+            in_flight.push(async move {
+                let block = fetch_block(ipfs, &cid, strategy).await;
+                (cid, block)
+            });
+        }
+
+        let (cid, block) = match in_flight.next().await {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+
+        let block = block?;
+        let ipld = ipfs.codecs.decode(&cid, block.data())?;
+
+        for (_, link) in ipld_links(&cid, ipld) {
+            if seen.insert(link.clone()) {
+                pending.push(link);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GaveUp, ProviderDiscovery, RetrievalStrategy, Thorough};
+    use crate::{make_ipld, Node};
+    use cid::{Cid, Codec};
+    use multihash::Sha2_256;
+    use std::time::Duration;
+
+    /// Waits for the connected peers only, and not for long.
+    #[derive(Debug)]
+    struct Impatient;
+
+    impl RetrievalStrategy for Impatient {
+        fn provider_discovery(&self) -> ProviderDiscovery {
+            ProviderDiscovery::Never
+        }
+
+        fn max_providers(&self) -> usize {
+            0
+        }
+
+        fn parallelism(&self) -> usize {
+            2
+        }
+
+        fn block_timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(100))
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_missing_block() {
+        let ipfs = Node::new("test_node").await;
+        let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(b"missing"));
+
+        let e = ipfs.get_block_with(&cid, &Impatient).await.unwrap_err();
+        let GaveUp(gave_up, after) = e.downcast::<GaveUp>().unwrap();
+
+        assert_eq!(gave_up, cid);
+        assert_eq!(after, Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn pins_local_dag_with_strategy() {
+        let ipfs = Node::new("test_node").await;
+        let leaf = ipfs.put_dag(make_ipld!("leaf")).await.unwrap();
+        let middle = ipfs
+            .put_dag(make_ipld!([leaf.clone(), leaf.clone()]))
+            .await
+            .unwrap();
+        let root = ipfs
+            .put_dag(make_ipld!({ "a": middle, "b": leaf.clone() }))
+            .await
+            .unwrap();
+
+        ipfs.insert_pin_with(&root, true, &Impatient).await.unwrap();
+        assert!(ipfs.is_pinned(&leaf).await.unwrap());
+
+        let path = crate::IpfsPath::from(root).sub_path("a/1").unwrap();
+        let resolved = ipfs
+            .get_dag_with(path, std::sync::Arc::new(Thorough))
+            .await
+            .unwrap();
+        assert_eq!(resolved, make_ipld!("leaf"));
+    }
+}
@@ -0,0 +1,229 @@
+//! Finding the providers of content through other means than the DHT, see [`ContentRouting`].
+//!
+//! The content routers given in [`crate::IpfsOptions::content_routing`] are asked for the
+//! providers along with the DHT by [`crate::Ipfs::get_providers`], and so also by the
+//! [`crate::retrieval`] strategies. With the `delegated_routing` feature, [`DelegatedRouting`]
+//! asks the providers from a server implementing the [Routing V1 HTTP API], such as
+//! `https://cid.contact`, which allows light nodes to find the providers without joining the DHT.
+//!
+//! [Routing V1 HTTP API]: https://specs.ipfs.tech/routing/http-routing-v1/
+
+use crate::error::Error;
+use async_trait::async_trait;
+use cid::Cid;
+use libp2p::{Multiaddr, PeerId};
+use std::fmt;
+
+#[cfg(feature = "delegated_routing")]
+pub use self::delegated::DelegatedRouting;
+
+/// A provider of content found by a [`ContentRouting`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provider {
+    pub peer_id: PeerId,
+    /// The addresses of the provider, if known. They are used when connecting to the provider.
+    pub addrs: Vec<Multiaddr>,
+}
+
+/// A source of the providers of content, used alongside the DHT.
+#[async_trait]
+pub trait ContentRouting: fmt::Debug + Send + Sync {
+    /// Returns the providers of the `cid`, or an empty list if none were found.
+    async fn find_providers(&self, cid: &Cid) -> Result<Vec<Provider>, Error>;
+}
+
+#[cfg(feature = "delegated_routing")]
+mod delegated {
+    use super::{ContentRouting, Provider};
+    use crate::error::Error;
+    use async_trait::async_trait;
+    use cid::Cid;
+    use serde::Deserialize;
+    use std::time::Duration;
+
+    /// The protocol name of bitswap in the Routing V1 API.
+    const BITSWAP: &str = "transport-bitswap";
+
+    /// Client of a [Routing V1 HTTP API] server, asking it for the providers of the content.
+    ///
+    /// [Routing V1 HTTP API]: https://specs.ipfs.tech/routing/http-routing-v1/
+    #[derive(Debug, Clone)]
+    pub struct DelegatedRouting {
+        endpoint: String,
+        client: reqwest::Client,
+    }
+
+    impl DelegatedRouting {
+        /// Creates a client of the server at `endpoint`, for example `https://cid.contact`.
+        pub fn new(endpoint: impl Into<String>) -> Result<Self, Error> {
+            Self::with_timeout(endpoint, Duration::from_secs(30))
+        }
+
+        /// Creates a client of the server at `endpoint`, giving up on the requests after the
+        /// `timeout`.
+        pub fn with_timeout(endpoint: impl Into<String>, timeout: Duration) -> Result<Self, Error> {
+            let mut endpoint = endpoint.into();
+            while endpoint.ends_with('/') {
+                endpoint.pop();
+            }
+            let client = reqwest::Client::builder().timeout(timeout).build()?;
+            Ok(DelegatedRouting { endpoint, client })
+        }
+    }
+
+    #[async_trait]
+    impl ContentRouting for DelegatedRouting {
+        async fn find_providers(&self, cid: &Cid) -> Result<Vec<Provider>, Error> {
+            let url = format!("{}/routing/v1/providers/{}", self.endpoint, cid);
+            let response = self
+                .client
+                .get(&url)
+                .header(reqwest::header::ACCEPT, "application/json")
+                .send()
+                .await?;
+
+            // the servers answer with not found when there are no providers
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(Vec::new());
+            }
+
+            let body = response.error_for_status()?.bytes().await?;
+            parse_providers(&body)
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct ProvidersResponse {
+        #[serde(default)]
+        providers: Option<Vec<ProviderRecord>>,
+    }
+
+    /// Both the `peer` schema records and the older `bitswap` schema records are accepted.
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct ProviderRecord {
+        schema: String,
+        #[serde(rename = "ID")]
+        id: Option<String>,
+        #[serde(default)]
+        addrs: Vec<String>,
+        /// Of the `peer` schema.
+        protocols: Option<Vec<String>>,
+        /// Of the `bitswap` schema.
+        protocol: Option<String>,
+    }
+
+    /// Parses the providers speaking bitswap out of the JSON response, skipping the records of
+    /// the unknown schemas and the unparseable addresses.
+    pub(super) fn parse_providers(body: &[u8]) -> Result<Vec<Provider>, Error> {
+        let response: ProvidersResponse = serde_json::from_slice(body)?;
+
+        let providers = response
+            .providers
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|record| match record.schema.as_str() {
+                "peer" => record
+                    .protocols
+                    .as_ref()
+                    .map_or(true, |protocols| protocols.iter().any(|p| p == BITSWAP)),
+                "bitswap" => record.protocol.as_deref().map_or(true, |p| p == BITSWAP),
+                _ => false,
+            })
+            .filter_map(|record| {
+                let peer_id = record.id?.parse().ok()?;
+                let addrs = record
+                    .addrs
+                    .iter()
+                    .filter_map(|addr| addr.parse().ok())
+                    .collect();
+                Some(Provider { peer_id, addrs })
+            })
+            .collect();
+
+        Ok(providers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContentRouting, Provider};
+    use crate::error::Error;
+    use crate::{IpfsOptions, Node};
+    use async_trait::async_trait;
+    use cid::Cid;
+    use libp2p::identity::Keypair;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    /// Knows a single provider of all of the content.
+    #[derive(Debug)]
+    struct Static(Provider);
+
+    #[async_trait]
+    impl ContentRouting for Static {
+        async fn find_providers(&self, _cid: &Cid) -> Result<Vec<Provider>, Error> {
+            Ok(vec![self.0.clone()])
+        }
+    }
+
+    #[tokio::test]
+    async fn providers_are_found_without_dht() {
+        let provider = Provider {
+            peer_id: Keypair::generate_ed25519().public().to_peer_id(),
+            addrs: vec!["/ip4/127.0.0.1/tcp/4001".parse().unwrap()],
+        };
+
+        let mut opts = IpfsOptions::inmemory_with_generated_keys();
+        opts.content_routing = vec![Arc::new(Static(provider.clone()))];
+        let ipfs = Node::with_options(opts).await;
+
+        let cid = Cid::try_from("QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap();
+        let providers = ipfs.get_providers(cid).await.unwrap();
+
+        assert_eq!(providers, vec![provider.peer_id]);
+    }
+
+    #[cfg(feature = "delegated_routing")]
+    #[test]
+    fn routing_v1_response() {
+        let body = br#"{"Providers": [
+            {
+                "Schema": "peer",
+                "ID": "QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
+                "Addrs": ["/ip4/1.2.3.4/tcp/4001", "not an address"],
+                "Protocols": ["transport-bitswap"]
+            },
+            {
+                "Schema": "peer",
+                "ID": "QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
+                "Addrs": [],
+                "Protocols": ["transport-graphsync-filecoinv1"]
+            },
+            {
+                "Schema": "bitswap",
+                "Protocol": "transport-bitswap",
+                "ID": "QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ"
+            },
+            {
+                "Schema": "unknown"
+            }
+        ]}"#;
+
+        let providers = super::delegated::parse_providers(body).unwrap();
+
+        assert_eq!(providers.len(), 2);
+        assert_eq!(
+            providers[0].addrs,
+            vec!["/ip4/1.2.3.4/tcp/4001"
+                .parse::<libp2p::Multiaddr>()
+                .unwrap()]
+        );
+        assert!(providers[1].addrs.is_empty());
+
+        assert!(super::delegated::parse_providers(br#"{"Providers": null}"#)
+            .unwrap()
+            .is_empty());
+    }
+}
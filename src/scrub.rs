@@ -0,0 +1,393 @@
+//! Periodic integrity checking of the pinned blocks.
+//!
+//! Storage can corrupt data silently, and the corruption of a pinned block would otherwise only be
+//! noticed when it is requested. The [`Scrubber`] re-hashes a rotating subset of the pinned blocks
+//! on every round, so that all of them are eventually checked without reading the whole repo at
+//! once. Corrupted and missing blocks are reported as [`ScrubEvent`]s and counted in the
+//! [`ScrubStats`], and can optionally be fetched again from the network.
+
+use crate::error::Error;
+use crate::task::{TaskHandle, TaskState};
+use crate::{Ipfs, IpfsTypes};
+use cid::Cid;
+use futures::stream::TryStreamExt;
+use std::fmt;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+
+/// Configuration of the [`Scrubber`].
+#[derive(Debug, Clone)]
+pub struct ScrubConfig {
+    /// Time between the scrubbing rounds.
+    pub interval: Duration,
+    /// The fraction of the pinned blocks checked on every round, from `0.0` to `1.0`. At least one
+    /// block is checked on every round.
+    pub fraction: f64,
+    /// Fetch the corrupted and missing blocks again from the network.
+    pub refetch: bool,
+    /// How long to wait for a single block to be fetched again.
+    pub refetch_timeout: Duration,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        // daily rounds, covering all of the pinned blocks in a week
+        ScrubConfig {
+            interval: Duration::from_secs(24 * 60 * 60),
+            fraction: 1.0 / 7.0,
+            refetch: false,
+            refetch_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Findings of the [`Scrubber`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScrubEvent {
+    /// The contents of the pinned block no longer hash to its Cid.
+    Corrupted(Cid),
+    /// The pinned block was not found in the blockstore.
+    Missing(Cid),
+    /// A corrupted or missing block was fetched again from the network.
+    Repaired(Cid),
+    /// Fetching a corrupted or missing block again failed.
+    RepairFailed(Cid, String),
+    /// A round was completed.
+    RoundCompleted {
+        /// Number of blocks checked during the round.
+        checked: usize,
+        /// Number of blocks found corrupted or missing during the round.
+        failed: usize,
+    },
+}
+
+/// Cumulative counters of the [`Scrubber`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScrubStats {
+    /// Number of completed rounds.
+    pub rounds: u64,
+    /// Number of blocks checked.
+    pub checked: u64,
+    /// Number of corrupted blocks found.
+    pub corrupted: u64,
+    /// Number of missing blocks found.
+    pub missing: u64,
+    /// Number of blocks fetched again successfully.
+    pub repaired: u64,
+    /// Time when the latest round was completed.
+    pub last_round: Option<SystemTime>,
+}
+
+/// Picks the blocks to check on each round, continuing from where the previous round ended.
+#[derive(Debug, Default)]
+struct Rotation {
+    /// The last checked Cid, in the order of the Cid bytes.
+    cursor: Option<Vec<u8>>,
+}
+
+impl Rotation {
+    fn next_round(&mut self, pinned: Vec<Cid>, fraction: f64) -> Vec<Cid> {
+        let mut pinned = pinned
+            .into_iter()
+            .map(|cid| (cid.to_bytes(), cid))
+            .collect::<Vec<_>>();
+        pinned.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        pinned.dedup_by(|a, b| a.0 == b.0);
+
+        if pinned.is_empty() {
+            return Vec::new();
+        }
+
+        let fraction = fraction.max(0.0).min(1.0);
+        let count = ((pinned.len() as f64 * fraction).ceil() as usize)
+            .max(1)
+            .min(pinned.len());
+
+        let start = match &self.cursor {
+            Some(cursor) => pinned.partition_point(|(bytes, _)| bytes <= cursor),
+            None => 0,
+        };
+
+        let selected = pinned
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(count)
+            .collect::<Vec<_>>();
+
+        self.cursor = selected.last().map(|(bytes, _)| bytes.clone());
+
+        selected.into_iter().map(|(_, cid)| cid.clone()).collect()
+    }
+}
+
+/// Returns true if the data hashes to the multihash of the Cid.
+pub(crate) fn verify(cid: &Cid, data: &[u8]) -> bool {
+    let hash = cid.hash().algorithm().digest(data);
+    hash.as_ref() == cid.hash()
+}
+
+struct Shared {
+    stats: Mutex<ScrubStats>,
+    events: broadcast::Sender<ScrubEvent>,
+}
+
+impl Shared {
+    fn report(&self, event: ScrubEvent) {
+        // sending fails only when there are no subscribers
+        let _ = self.events.send(event);
+    }
+}
+
+/// Handle to the scrubbing background task created with [`Scrubber::new`]. The background task
+/// exits after all of the handles have been dropped.
+#[derive(Clone)]
+pub struct Scrubber {
+    shared: TaskHandle<Shared>,
+}
+
+impl fmt::Debug for Scrubber {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Scrubber")
+            .field("stats", &*self.shared.stats.lock().unwrap())
+            .finish()
+    }
+}
+
+impl Scrubber {
+    /// Creates a new scrubber for the given node. The returned future is the background task which
+    /// checks the blocks, and it should be spawned. The first round starts after the interval, or
+    /// when requested with [`Scrubber::scrub_now`].
+    pub fn new<Types: IpfsTypes>(
+        ipfs: Ipfs<Types>,
+        config: ScrubConfig,
+    ) -> (Self, impl Future<Output = ()>) {
+        let (events, _) = broadcast::channel(64);
+        let (shared, state) = TaskHandle::new(Shared {
+            stats: Mutex::new(ScrubStats::default()),
+            events,
+        });
+
+        let task = run(ipfs, config, state);
+
+        (Scrubber { shared }, task)
+    }
+
+    /// Returns the cumulative counters.
+    pub fn stats(&self) -> ScrubStats {
+        self.shared.stats.lock().unwrap().clone()
+    }
+
+    /// Subscribes to the events of the following rounds. Slow subscribers miss events once more
+    /// than 64 of them are queued.
+    pub fn subscribe(&self) -> broadcast::Receiver<ScrubEvent> {
+        self.shared.events.subscribe()
+    }
+
+    /// Starts the next round without waiting for the interval.
+    pub fn scrub_now(&self) {
+        self.shared.wake();
+    }
+}
+
+async fn run<Types: IpfsTypes>(ipfs: Ipfs<Types>, config: ScrubConfig, shared: TaskState<Shared>) {
+    let mut rotation = Rotation::default();
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(config.interval) => {},
+            _ = shared.notified() => {},
+        }
+
+        if shared.is_closed() {
+            return;
+        }
+
+        if let Err(e) = round(&ipfs, &config, &shared, &mut rotation).await {
+            warn!("scrubbing round failed: {}", e);
+        }
+    }
+}
+
+async fn round<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    config: &ScrubConfig,
+    shared: &TaskState<Shared>,
+    rotation: &mut Rotation,
+) -> Result<(), Error> {
+    let pinned = ipfs
+        .list_pins(None)
+        .await
+        .map_ok(|(cid, _)| cid)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let selected = rotation.next_round(pinned, config.fraction);
+    let mut failed = 0;
+
+    debug!(blocks = selected.len(), "starting scrubbing round");
+
+    for cid in &selected {
+        let event = match ipfs.repo.get_block_now(cid).await? {
+            Some(block) if verify(cid, &block.data) => None,
+            Some(_) => Some(ScrubEvent::Corrupted(cid.clone())),
+            None => Some(ScrubEvent::Missing(cid.clone())),
+        };
+
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return Ok(()),
+        };
+
+        shared.stats.lock().unwrap().checked += 1;
+
+        let event = match event {
+            Some(event) => event,
+            None => continue,
+        };
+
+        failed += 1;
+
+        {
+            let mut stats = shared.stats.lock().unwrap();
+            match event {
+                ScrubEvent::Corrupted(_) => stats.corrupted += 1,
+                _ => stats.missing += 1,
+            }
+        }
+
+        warn!(cid = %cid, "scrubbing found a bad block: {:?}", event);
+        let corrupted = matches!(event, ScrubEvent::Corrupted(_));
+        shared.report(event);
+
+        if !config.refetch {
+            continue;
+        }
+
+        let repair = async {
+            if corrupted {
+                ipfs.repo.remove_corrupted_block(cid).await?;
+            }
+            match tokio::time::timeout(config.refetch_timeout, ipfs.repo.get_block(cid)).await {
+                Ok(res) => res.map(|_| ()),
+                Err(_) => Err(anyhow::anyhow!("timed out")),
+            }
+        };
+
+        match repair.await {
+            Ok(()) => {
+                shared.stats.lock().unwrap().repaired += 1;
+                shared.report(ScrubEvent::Repaired(cid.clone()));
+            }
+            Err(e) => shared.report(ScrubEvent::RepairFailed(cid.clone(), e.to_string())),
+        }
+    }
+
+    if let Some(shared) = shared.upgrade() {
+        {
+            let mut stats = shared.stats.lock().unwrap();
+            stats.rounds += 1;
+            stats.last_round = Some(SystemTime::now());
+        }
+        shared.report(ScrubEvent::RoundCompleted {
+            checked: selected.len(),
+            failed,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, Rotation, ScrubEvent, ScrubStats, Scrubber};
+    use crate::{Block, Node};
+    use cid::{Cid, Codec};
+    use multihash::Sha2_256;
+
+    fn cids(n: u8) -> Vec<Cid> {
+        (0..n)
+            .map(|i| Cid::new_v1(Codec::Raw, Sha2_256::digest(&[i])))
+            .collect()
+    }
+
+    #[test]
+    fn rotation_covers_all_blocks() {
+        let pinned = cids(10);
+        let mut rotation = Rotation::default();
+
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            let round = rotation.next_round(pinned.clone(), 0.25);
+            assert_eq!(round.len(), 3);
+            seen.extend(round);
+        }
+
+        // 12 checks over 10 blocks wrap around once
+        let mut unique = seen.iter().map(|cid| cid.to_bytes()).collect::<Vec<_>>();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), 10);
+        assert_eq!(seen[10], seen[0]);
+    }
+
+    #[test]
+    fn rotation_survives_removed_cursor() {
+        let mut pinned = cids(4);
+        let mut rotation = Rotation::default();
+
+        let first = rotation.next_round(pinned.clone(), 0.25);
+        pinned.retain(|cid| cid != &first[0]);
+
+        let second = rotation.next_round(pinned, 0.25);
+        assert_eq!(second.len(), 1);
+        assert_ne!(second[0], first[0]);
+    }
+
+    #[test]
+    fn verify_detects_corruption() {
+        let data = b"scrub me";
+        let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(data));
+        assert!(verify(&cid, data));
+        assert!(!verify(&cid, b"scrub mE"));
+    }
+
+    #[tokio::test]
+    async fn reports_missing_pinned_block() {
+        let ipfs = Node::new("test_node").await;
+
+        let data = b"pinned".to_vec().into_boxed_slice();
+        let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data));
+        ipfs.put_block(Block::new(data, cid.clone())).await.unwrap();
+        ipfs.insert_pin(&cid, false).await.unwrap();
+        ipfs.repo.remove_corrupted_block(&cid).await.unwrap();
+
+        let (scrubber, task) = Scrubber::new(ipfs.ipfs.clone(), Default::default());
+        let mut events = scrubber.subscribe();
+        tokio::spawn(task);
+        scrubber.scrub_now();
+
+        assert_eq!(events.recv().await.unwrap(), ScrubEvent::Missing(cid));
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ScrubEvent::RoundCompleted {
+                checked: 1,
+                failed: 1
+            }
+        );
+
+        let stats = scrubber.stats();
+        assert_eq!(
+            stats,
+            ScrubStats {
+                rounds: 1,
+                checked: 1,
+                missing: 1,
+                last_round: stats.last_round,
+                ..Default::default()
+            }
+        );
+    }
+}
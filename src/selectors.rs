@@ -0,0 +1,601 @@
+//! [IPLD selectors] describing the parts of a DAG, and the traversal of the DAG over the
+//! blockstore with them, see [`Selector`] and [`SelectorTraversal`].
+//!
+//! [IPLD selectors]: https://ipld.io/specs/selectors/
+
+use crate::ipld::{decode_ipld, BlockError, Ipld};
+use crate::{Block, Ipfs, IpfsTypes};
+use async_stream::stream;
+use cid::Cid;
+use futures::stream::Stream;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+
+/// A selector over the IPLD data model, walking through the maps, the lists and the links of a
+/// DAG. The selectors can be created directly or parsed from their [`Ipld`] representation with
+/// [`Selector::from_ipld`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Selector {
+    /// Matches the current node and ends the traversal of this branch.
+    Matcher,
+    /// Continues with the `next` selector on all of the elements of a list or the values of a map.
+    ExploreAll { next: Box<Selector> },
+    /// Continues with the given selectors on the named fields of a map, or the list elements
+    /// with the field names parsed as indices.
+    ExploreFields { fields: BTreeMap<String, Selector> },
+    /// Continues with the `next` selector on the list element at `index`.
+    ExploreIndex { index: usize, next: Box<Selector> },
+    /// Continues with the `next` selector on the list elements in `start..end`.
+    ExploreRange {
+        start: usize,
+        end: usize,
+        next: Box<Selector>,
+    },
+    /// Continues with the `sequence`, which is applied again in place of each
+    /// [`Selector::ExploreRecursiveEdge`] found in it, as long as the `limit` allows.
+    ExploreRecursive {
+        limit: RecursionLimit,
+        sequence: Box<Selector>,
+    },
+    /// Applies the `sequence` of the innermost enclosing [`Selector::ExploreRecursive`] again.
+    /// Does nothing outside of one.
+    ExploreRecursiveEdge,
+    /// Continues with all of the selectors on the same node.
+    ExploreUnion(Vec<Selector>),
+}
+
+/// The limit of the [`Selector::ExploreRecursive`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecursionLimit {
+    /// The sequence can be applied any number of times.
+    None,
+    /// The sequence can be applied at most the given number of times, including the first time.
+    Depth(u64),
+}
+
+/// The [`Ipld`] given to [`Selector::from_ipld`] is not a valid or a supported selector.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[error("invalid selector: {0}")]
+pub struct InvalidSelector(&'static str);
+
+impl Selector {
+    /// Returns the selector matching all of the nodes of the DAG, like the one used by
+    /// `ipfs dag export`.
+    pub fn all() -> Selector {
+        Selector::ExploreRecursive {
+            limit: RecursionLimit::None,
+            sequence: Box::new(Selector::ExploreUnion(vec![
+                Selector::Matcher,
+                Selector::ExploreAll {
+                    next: Box::new(Selector::ExploreRecursiveEdge),
+                },
+            ])),
+        }
+    }
+
+    /// Parses the selector from its representation in the [selector schema], for example
+    /// `{"R": {"l": {"none": {}}, ":>": {"a": {">": {"@": {}}}}}}`. The conditions and the
+    /// `stopAt` of the recursions are not supported.
+    ///
+    /// [selector schema]: https://ipld.io/specs/selectors/#the-selector-schema
+    pub fn from_ipld(ipld: &Ipld) -> Result<Selector, InvalidSelector> {
+        Self::parse(ipld, false)
+    }
+
+    fn parse(ipld: &Ipld, in_recursion: bool) -> Result<Selector, InvalidSelector> {
+        let (kind, body) = match ipld {
+            Ipld::Map(map) if map.len() == 1 => map.iter().next().expect("length was checked"),
+            _ => return Err(InvalidSelector("expected a map with a single key")),
+        };
+
+        let body = match body {
+            Ipld::Map(body) => body,
+            Ipld::List(selectors) if kind == "|" => {
+                return selectors
+                    .iter()
+                    .map(|s| Self::parse(s, in_recursion))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(Selector::ExploreUnion);
+            }
+            _ => return Err(InvalidSelector("expected a map as the selector body")),
+        };
+
+        let next = |key: &str| -> Result<Box<Selector>, InvalidSelector> {
+            match body.get(key) {
+                Some(next) => Self::parse(next, in_recursion).map(Box::new),
+                None => Err(InvalidSelector("missing the next selector")),
+            }
+        };
+
+        let int = |key: &str| -> Result<usize, InvalidSelector> {
+            match body.get(key) {
+                Some(Ipld::Integer(i)) => {
+                    usize::try_from(*i).map_err(|_| InvalidSelector("index out of range"))
+                }
+                _ => Err(InvalidSelector("missing an index")),
+            }
+        };
+
+        let selector = match kind.as_str() {
+            "." => Selector::Matcher,
+            "a" => Selector::ExploreAll { next: next(">")? },
+            "f" => match body.get("f>") {
+                Some(Ipld::Map(fields)) => Selector::ExploreFields {
+                    fields: fields
+                        .iter()
+                        .map(|(name, s)| Ok((name.clone(), Self::parse(s, in_recursion)?)))
+                        .collect::<Result<_, _>>()?,
+                },
+                _ => return Err(InvalidSelector("missing the fields")),
+            },
+            "i" => Selector::ExploreIndex {
+                index: int("i")?,
+                next: next(">")?,
+            },
+            "r" => {
+                let (start, end) = (int("^")?, int("$")?);
+                if start > end {
+                    return Err(InvalidSelector("range start after the end"));
+                }
+                Selector::ExploreRange {
+                    start,
+                    end,
+                    next: next(">")?,
+                }
+            }
+            "R" => {
+                if body.contains_key("!") {
+                    return Err(InvalidSelector("stopAt is not supported"));
+                }
+                let limit = match body.get("l") {
+                    Some(Ipld::Map(limit)) if limit.len() == 1 => {
+                        match limit.iter().next().expect("length was checked") {
+                            (kind, _) if kind == "none" => RecursionLimit::None,
+                            (kind, Ipld::Integer(depth)) if kind == "depth" => {
+                                RecursionLimit::Depth(
+                                    u64::try_from(*depth)
+                                        .map_err(|_| InvalidSelector("depth out of range"))?,
+                                )
+                            }
+                            _ => return Err(InvalidSelector("unknown recursion limit")),
+                        }
+                    }
+                    _ => return Err(InvalidSelector("missing the recursion limit")),
+                };
+                let sequence = match body.get(":>") {
+                    Some(sequence) => Box::new(Self::parse(sequence, true)?),
+                    None => return Err(InvalidSelector("missing the sequence")),
+                };
+                Selector::ExploreRecursive { limit, sequence }
+            }
+            "@" if in_recursion => Selector::ExploreRecursiveEdge,
+            "@" => return Err(InvalidSelector("recursion edge outside of a recursion")),
+            "&" | "~" => return Err(InvalidSelector("unsupported selector")),
+            _ => return Err(InvalidSelector("unknown selector")),
+        };
+
+        Ok(selector)
+    }
+
+    /// Returns the representation of the selector in the selector schema, the inverse of
+    /// [`Selector::from_ipld`].
+    pub fn to_ipld(&self) -> Ipld {
+        fn map(entries: Vec<(&str, Ipld)>) -> Ipld {
+            Ipld::Map(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key.to_owned(), value))
+                    .collect(),
+            )
+        }
+
+        fn int<T: Into<i128>>(value: T) -> Ipld {
+            Ipld::Integer(value.into())
+        }
+
+        let (kind, body) = match self {
+            Selector::Matcher => (".", map(vec![])),
+            Selector::ExploreAll { next } => ("a", map(vec![(">", next.to_ipld())])),
+            Selector::ExploreFields { fields } => {
+                let fields = fields
+                    .iter()
+                    .map(|(name, s)| (name.clone(), s.to_ipld()))
+                    .collect();
+                ("f", map(vec![("f>", Ipld::Map(fields))]))
+            }
+            Selector::ExploreIndex { index, next } => (
+                "i",
+                map(vec![("i", int(*index as u64)), (">", next.to_ipld())]),
+            ),
+            Selector::ExploreRange { start, end, next } => (
+                "r",
+                map(vec![
+                    ("^", int(*start as u64)),
+                    ("$", int(*end as u64)),
+                    (">", next.to_ipld()),
+                ]),
+            ),
+            Selector::ExploreRecursive { limit, sequence } => {
+                let limit = match limit {
+                    RecursionLimit::None => map(vec![("none", map(vec![]))]),
+                    RecursionLimit::Depth(depth) => map(vec![("depth", int(*depth))]),
+                };
+                ("R", map(vec![("l", limit), (":>", sequence.to_ipld())]))
+            }
+            Selector::ExploreRecursiveEdge => ("@", map(vec![])),
+            Selector::ExploreUnion(selectors) => (
+                "|",
+                Ipld::List(selectors.iter().map(Selector::to_ipld).collect()),
+            ),
+        };
+
+        map(vec![(kind, body)])
+    }
+}
+
+/// A block loaded by the [`SelectorTraversal`].
+#[derive(Debug)]
+pub struct SelectedBlock {
+    pub block: Block,
+    /// The paths of the nodes matched by a [`Selector::Matcher`] in this block, as the
+    /// `/`-separated fields and indices from the root of the traversal. The path of a link
+    /// target is the path of the link.
+    pub matched: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TraversalError {
+    #[error("failed to decode {0}")]
+    Block(Cid, #[source] BlockError),
+    #[error("loading failed")]
+    Loading(#[from] crate::Error),
+    #[error("block not found locally: {0}")]
+    BlockNotFound(Cid),
+}
+
+/// The traversal of a DAG with a [`Selector`], loading the blocks of the links reached by it.
+///
+/// The traversal applies the selector to the root block, and continues into the target of each
+/// link the selector explores, so that only the blocks needed to describe the selected part of the
+/// DAG are loaded, for example for a partial CAR export with [`crate::car::Selector::Ipld`].
+#[derive(Debug, Clone)]
+pub struct SelectorTraversal {
+    selector: Selector,
+    existing_blocks: bool,
+    background: bool,
+}
+
+impl SelectorTraversal {
+    pub fn new(selector: Selector) -> Self {
+        SelectorTraversal {
+            selector,
+            existing_blocks: false,
+            background: false,
+        }
+    }
+
+    /// Overrides the default of fetching the missing blocks from the network. The traversal ends
+    /// with [`TraversalError::BlockNotFound`] on the first block which is not found locally.
+    pub fn with_existing_blocks(mut self) -> Self {
+        self.existing_blocks = true;
+        self
+    }
+
+    /// Fetches the missing blocks with a low priority, for [`crate::prefetch`].
+    pub(crate) fn in_background(mut self) -> Self {
+        self.background = true;
+        self
+    }
+
+    /// Returns the stream of the blocks loaded during the traversal of the DAG rooted at `root`.
+    /// The blocks are visited depth-first in the order of the links. A block reached again with
+    /// the same state of the selector is skipped, but a block reached with different states, for
+    /// example through the different selectors of a [`Selector::ExploreUnion`], is returned for
+    /// each of them.
+    ///
+    /// Depending on how this function is called, the lifetime will be tied to the lifetime of
+    /// given `&Ipfs` or `'static` when given ownership of `Ipfs`.
+    pub fn traverse<'a, Types, MaybeOwned>(
+        self,
+        ipfs: MaybeOwned,
+        root: Cid,
+    ) -> impl Stream<Item = Result<SelectedBlock, TraversalError>> + Send + 'a
+    where
+        Types: IpfsTypes,
+        MaybeOwned: Borrow<Ipfs<Types>> + Send + 'a,
+    {
+        let SelectorTraversal {
+            selector,
+            existing_blocks,
+            background,
+        } = self;
+
+        stream! {
+            let state = State {
+                selector,
+                recursion: None,
+            };
+
+            let mut work = vec![(root, state, Vec::new())];
+            let mut visited = HashSet::new();
+
+            while let Some((cid, state, path)) = work.pop() {
+                if !visited.insert((cid.clone(), state.clone())) {
+                    continue;
+                }
+
+                // if this is not bound to a local variable it'll introduce a Sync requirement on
+                // `MaybeOwned` which we don't necessarily need.
+                let borrowed = ipfs.borrow();
+
+                let block = if existing_blocks {
+                    match borrowed.repo.get_block_now(&cid).await {
+                        Ok(Some(block)) => block,
+                        Ok(None) => {
+                            yield Err(TraversalError::BlockNotFound(cid));
+                            return;
+                        }
+                        Err(e) => {
+                            yield Err(e.into());
+                            return;
+                        }
+                    }
+                } else if background {
+                    match borrowed.repo.prefetch_block(&cid).await {
+                        Ok(block) => block,
+                        Err(e) => {
+                            yield Err(e.into());
+                            return;
+                        }
+                    }
+                } else {
+                    match borrowed.get_block(&cid).await {
+                        Ok(block) => block,
+                        Err(e) => {
+                            yield Err(e.into());
+                            return;
+                        }
+                    }
+                };
+
+                let ipld = match decode_ipld(&cid, &block.data) {
+                    Ok(ipld) => ipld,
+                    Err(e) => {
+                        yield Err(TraversalError::Block(cid, e));
+                        return;
+                    }
+                };
+
+                let mut visit = Visit::default();
+                let mut path = path;
+                explore(&ipld, &state, &mut path, &mut visit);
+
+                // reversed to pop the first link first
+                work.extend(visit.links.into_iter().rev());
+
+                trace!(cid = %cid, matched = visit.matched.len(), "selected");
+
+                yield Ok(SelectedBlock {
+                    block,
+                    matched: visit.matched,
+                });
+            }
+        }
+    }
+}
+
+/// The selector applied to a node, and the sequence and the limit of the innermost recursion.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct State {
+    selector: Selector,
+    recursion: Option<(Selector, RecursionLimit)>,
+}
+
+impl State {
+    fn next(&self, selector: &Selector) -> State {
+        State {
+            selector: selector.clone(),
+            recursion: self.recursion.clone(),
+        }
+    }
+}
+
+/// The results of exploring a single block.
+#[derive(Default)]
+struct Visit {
+    matched: Vec<String>,
+    links: Vec<(Cid, State, Vec<String>)>,
+}
+
+/// Explores the child `node` at `segment` below the `path`.
+fn child(segment: String, node: &Ipld, state: State, path: &mut Vec<String>, visit: &mut Visit) {
+    path.push(segment);
+    explore(node, &state, path, visit);
+    path.pop();
+}
+
+/// Applies the selector of the `state` to the `node` at `path` within a block.
+fn explore(node: &Ipld, state: &State, path: &mut Vec<String>, visit: &mut Visit) {
+    if let Ipld::Link(cid) = node {
+        visit.links.push((cid.clone(), state.clone(), path.clone()));
+        return;
+    }
+
+    match &state.selector {
+        Selector::Matcher => visit.matched.push(path.join("/")),
+        Selector::ExploreAll { next } => match node {
+            Ipld::List(list) => {
+                for (i, node) in list.iter().enumerate() {
+                    child(i.to_string(), node, state.next(next), path, visit);
+                }
+            }
+            Ipld::Map(map) => {
+                for (key, node) in map {
+                    child(key.clone(), node, state.next(next), path, visit);
+                }
+            }
+            _ => {}
+        },
+        Selector::ExploreFields { fields } => {
+            for (name, next) in fields {
+                let found = match node {
+                    Ipld::Map(map) => map.get(name),
+                    Ipld::List(list) => name.parse::<usize>().ok().and_then(|i| list.get(i)),
+                    _ => None,
+                };
+                if let Some(node) = found {
+                    child(name.clone(), node, state.next(next), path, visit);
+                }
+            }
+        }
+        Selector::ExploreIndex { index, next } => {
+            if let Ipld::List(list) = node {
+                if let Some(node) = list.get(*index) {
+                    child(index.to_string(), node, state.next(next), path, visit);
+                }
+            }
+        }
+        Selector::ExploreRange { start, end, next } => {
+            if let Ipld::List(list) = node {
+                for (i, node) in list.iter().enumerate().take(*end).skip(*start) {
+                    child(i.to_string(), node, state.next(next), path, visit);
+                }
+            }
+        }
+        Selector::ExploreRecursive { limit, sequence } => {
+            let state = State {
+                selector: (**sequence).clone(),
+                recursion: Some(((**sequence).clone(), *limit)),
+            };
+            explore(node, &state, path, visit);
+        }
+        Selector::ExploreRecursiveEdge => {
+            let (sequence, limit) = match &state.recursion {
+                Some((sequence, limit)) => (sequence, limit),
+                None => return,
+            };
+            let limit = match limit {
+                RecursionLimit::None => RecursionLimit::None,
+                RecursionLimit::Depth(depth) if *depth > 1 => RecursionLimit::Depth(depth - 1),
+                RecursionLimit::Depth(_) => return,
+            };
+            let state = State {
+                selector: sequence.clone(),
+                recursion: Some((sequence.clone(), limit)),
+            };
+            explore(node, &state, path, visit);
+        }
+        Selector::ExploreUnion(selectors) => {
+            for selector in selectors {
+                explore(node, &state.next(selector), path, visit);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecursionLimit, Selector, SelectorTraversal};
+    use crate::ipld::dag_json::DagJsonCodec;
+    use crate::{make_ipld, Node};
+    use cid::{Cid, Codec};
+    use futures::stream::TryStreamExt;
+
+    fn parse(json: &str) -> Selector {
+        Selector::from_ipld(&DagJsonCodec::decode(json.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parses_and_encodes_the_schema() {
+        let selector =
+            parse(r#"{"R":{"l":{"depth":2},":>":{"|":[{".":{}},{"a":{">":{"@":{}}}}]}}}"#);
+
+        let expected = Selector::ExploreRecursive {
+            limit: RecursionLimit::Depth(2),
+            sequence: Box::new(Selector::ExploreUnion(vec![
+                Selector::Matcher,
+                Selector::ExploreAll {
+                    next: Box::new(Selector::ExploreRecursiveEdge),
+                },
+            ])),
+        };
+
+        assert_eq!(selector, expected);
+        assert_eq!(Selector::from_ipld(&selector.to_ipld()).unwrap(), selector);
+    }
+
+    #[test]
+    fn rejects_invalid_selectors() {
+        for json in &[
+            r#"{"@":{}}"#,
+            r#"{"a":{}}"#,
+            r#"{"r":{"^":2,"$":1,">":{".":{}}}}"#,
+            r#"{".":{},"a":{">":{".":{}}}}"#,
+            r#"{"R":{"l":{"none":{}},":>":{"@":{}},"!":{}}}"#,
+        ] {
+            let ipld = DagJsonCodec::decode(json.as_bytes()).unwrap();
+            assert!(Selector::from_ipld(&ipld).is_err(), "{}", json);
+        }
+    }
+
+    /// Returns a node with `root -> {"left": [a], "right": b}`, and the Cids of the root, a and b.
+    async fn node_with_dag() -> (Node, Cid, Cid, Cid) {
+        let ipfs = Node::new("test_node").await;
+        let dag = ipfs.dag();
+
+        let a = dag.put(make_ipld!("a"), Codec::DagCBOR).await.unwrap();
+        let b = dag
+            .put(make_ipld!({ "b": 1 }), Codec::DagCBOR)
+            .await
+            .unwrap();
+        let root = dag
+            .put(
+                make_ipld!({
+                    "left": [a.clone()],
+                    "right": b.clone(),
+                }),
+                Codec::DagCBOR,
+            )
+            .await
+            .unwrap();
+
+        (ipfs, root, a, b)
+    }
+
+    async fn traverse(ipfs: &Node, root: &Cid, selector: Selector) -> Vec<(Cid, Vec<String>)> {
+        SelectorTraversal::new(selector)
+            .with_existing_blocks()
+            .traverse(&**ipfs, root.clone())
+            .map_ok(|selected| (selected.block.cid, selected.matched))
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn loads_only_the_selected_blocks() {
+        let (ipfs, root, _, b) = node_with_dag().await;
+
+        let selector = parse(r#"{"f":{"f>":{"right":{"f":{"f>":{"b":{".":{}}}}}}}}"#);
+        let selected = traverse(&ipfs, &root, selector).await;
+
+        assert_eq!(
+            selected,
+            vec![(root, vec![]), (b, vec!["right/b".to_owned()])]
+        );
+    }
+
+    #[tokio::test]
+    async fn all_matches_every_node_depth_first() {
+        let (ipfs, root, a, b) = node_with_dag().await;
+
+        let selected = traverse(&ipfs, &root, Selector::all()).await;
+
+        let cids = selected.iter().map(|(cid, _)| cid).collect::<Vec<_>>();
+        assert_eq!(cids, vec![&root, &a, &b]);
+        assert_eq!(selected[0].1, vec!["", "left"]);
+        assert_eq!(selected[1].1, vec!["left/0"]);
+        assert_eq!(selected[2].1, vec!["right", "right/b"]);
+    }
+}
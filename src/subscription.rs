@@ -21,10 +21,6 @@ use std::sync::{
 };
 use std::task::{Context, Poll, Waker};
 
-// a counter used to assign unique identifiers to `Subscription`s and `SubscriptionFuture`s
-// (which obtain the same number as their counterpart `Subscription`)
-static GLOBAL_REQ_COUNT: AtomicU64 = AtomicU64::new(0);
-
 /// The type of a request for subscription.
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum RequestKind {
@@ -78,6 +74,10 @@ pub type Subscriptions<T, E> = HashMap<RequestKind, HashMap<SubscriptionId, Subs
 pub struct SubscriptionRegistry<T: Debug + Clone + PartialEq, E: Debug + Clone> {
     pub(crate) subscriptions: Arc<Mutex<Subscriptions<T, E>>>,
     shutting_down: AtomicBool,
+    // a counter used to assign unique identifiers to `Subscription`s and `SubscriptionFuture`s
+    // (which obtain the same number as their counterpart `Subscription`); kept per registry so
+    // that the nodes running in the same process do not share any state
+    next_id: AtomicU64,
 }
 
 impl<T: Debug + Clone + PartialEq, E: Debug + Clone> fmt::Debug for SubscriptionRegistry<T, E> {
@@ -98,7 +98,7 @@ impl<T: Debug + Clone + PartialEq, E: Debug + Clone> SubscriptionRegistry<T, E>
         kind: RequestKind,
         cancel_notifier: Option<Sender<RepoEvent>>,
     ) -> SubscriptionFuture<T, E> {
-        let id = GLOBAL_REQ_COUNT.fetch_add(1, Ordering::SeqCst);
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
         debug!("Creating subscription {} to {}", id, kind);
 
         let mut subscription = Subscription::new(cancel_notifier);
@@ -192,6 +192,7 @@ impl<T: Debug + Clone + PartialEq, E: Debug + Clone> Default for SubscriptionReg
         Self {
             subscriptions: Default::default(),
             shutting_down: Default::default(),
+            next_id: Default::default(),
         }
     }
 }
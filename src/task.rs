@@ -0,0 +1,120 @@
+//! Handles to the background tasks of the [`crate::scrub::Scrubber`],
+//! [`crate::republish::Republisher`] and [`crate::reprovide::Reprovider`].
+//!
+//! The handles share the state with the background task, which only holds a [`TaskState`] so
+//! that it can exit once all of the [`TaskHandle`]s have been dropped.
+
+use std::ops::Deref;
+use std::sync::{Arc, Weak};
+use tokio::sync::Notify;
+
+struct Inner<S> {
+    state: S,
+    wakeup: Arc<Notify>,
+}
+
+impl<S> Drop for Inner<S> {
+    fn drop(&mut self) {
+        // allow the background task to notice that all of the handles are gone
+        self.wakeup.notify_one();
+    }
+}
+
+/// Handle owning the state shared with a background task.
+pub(crate) struct TaskHandle<S> {
+    inner: Arc<Inner<S>>,
+}
+
+impl<S> Clone for TaskHandle<S> {
+    fn clone(&self) -> Self {
+        TaskHandle {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<S> Deref for TaskHandle<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.inner.state
+    }
+}
+
+impl<S> TaskHandle<S> {
+    /// Creates the first handle to the `state`, and the [`TaskState`] to be moved to the
+    /// background task.
+    pub(crate) fn new(state: S) -> (Self, TaskState<S>) {
+        let wakeup = Arc::new(Notify::new());
+        let inner = Arc::new(Inner {
+            state,
+            wakeup: Arc::clone(&wakeup),
+        });
+        let task = TaskState {
+            inner: Arc::downgrade(&inner),
+            wakeup,
+        };
+        (TaskHandle { inner }, task)
+    }
+
+    /// Wakes up the background task waiting in [`TaskState::notified`].
+    pub(crate) fn wake(&self) {
+        self.inner.wakeup.notify_one();
+    }
+}
+
+/// The background task's view of the state, which does not keep the state alive.
+pub(crate) struct TaskState<S> {
+    inner: Weak<Inner<S>>,
+    wakeup: Arc<Notify>,
+}
+
+impl<S> Clone for TaskState<S> {
+    fn clone(&self) -> Self {
+        TaskState {
+            inner: Weak::clone(&self.inner),
+            wakeup: Arc::clone(&self.wakeup),
+        }
+    }
+}
+
+impl<S> TaskState<S> {
+    /// Returns a handle to the state, or `None` once all of the handles have been dropped and the
+    /// background task should exit.
+    pub(crate) fn upgrade(&self) -> Option<TaskHandle<S>> {
+        self.inner.upgrade().map(|inner| TaskHandle { inner })
+    }
+
+    /// Returns `true` once all of the handles have been dropped.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.inner.strong_count() == 0
+    }
+
+    /// Waits until [`TaskHandle::wake`] is called or the last handle is dropped.
+    pub(crate) async fn notified(&self) {
+        self.wakeup.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TaskHandle;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn dropping_the_last_handle_wakes_the_task() {
+        let (handle, task) = TaskHandle::new(1u8);
+        let clone = handle.clone();
+
+        drop(handle);
+        assert!(!task.is_closed());
+        assert_eq!(task.upgrade().as_deref(), Some(&1));
+
+        drop(clone);
+        tokio::time::timeout(Duration::from_secs(1), task.notified())
+            .await
+            .unwrap();
+        assert!(task.is_closed());
+        assert!(task.upgrade().is_none());
+    }
+}
@@ -0,0 +1,939 @@
+//! Importing files and directory trees from the local filesystem.
+
+use super::ignore::IgnoreRules;
+use super::manifest::{FileChecksum, Manifest};
+use crate::repo::FileSource;
+use crate::{Block, Error, Ipfs, IpfsTypes};
+use cid::{Cid, Codec};
+use ipfs_unixfs::dir::builder::{
+    BufferingTreeBuilder, TreeBuildingFailed, TreeConstructionFailed, TreeNode, TreeOptions,
+};
+use ipfs_unixfs::file::adder::FileAdder;
+use ipfs_unixfs::Metadata;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Options for [`add_path`].
+#[derive(Debug, Clone)]
+pub struct AddOptions {
+    /// Rules for excluding files and directories from the import. The default rules exclude the
+    /// `.git` directories.
+    pub ignore: IgnoreRules,
+    /// Include the hidden files and directories, the ones with names starting with a dot. The
+    /// ignore rules still apply to them.
+    pub hidden: bool,
+    /// Wrap the added file or directory in an additional directory.
+    pub wrap_with_directory: bool,
+    /// How the symbolic links are handled by [`add_path`]. Defaults to skipping them.
+    pub symlinks: SymlinkPolicy,
+    /// Read the files hardlinked within the import only once, adding them as a single leaf linked
+    /// to from each of their paths. Only supported on unix, defaults to true.
+    pub dedup_hardlinks: bool,
+    /// Store the permission bits of the files and directories, like `ipfs add --preserve-mode`.
+    /// Only supported on unix, defaults to false.
+    pub preserve_mode: bool,
+    /// Store the modification times of the files and directories, like
+    /// `ipfs add --preserve-mtime`. Defaults to false.
+    pub preserve_mtime: bool,
+    /// Whether the checksums of the added files are computed for a [`Manifest`]. Defaults to
+    /// skipping them.
+    pub manifest: ManifestPolicy,
+    /// Keep only references to the contents of the files instead of copying them into the
+    /// blockstore, like `ipfs add --nocopy`. The files are chunked into raw leaves, which gives
+    /// them other Cids than the default. See [`crate::repo::filestore`]; defaults to false.
+    pub nocopy: bool,
+}
+
+impl Default for AddOptions {
+    fn default() -> Self {
+        AddOptions {
+            ignore: IgnoreRules::default(),
+            hidden: false,
+            wrap_with_directory: false,
+            symlinks: SymlinkPolicy::Skip,
+            dedup_hardlinks: true,
+            preserve_mode: false,
+            preserve_mtime: false,
+            manifest: ManifestPolicy::Skip,
+            nocopy: false,
+        }
+    }
+}
+
+/// How the symbolic links are handled when adding from the filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// The symbolic links are left out of the import.
+    Skip,
+    /// The symbolic links are added as UnixFS symlinks to their unresolved targets.
+    Store,
+    /// The symbolic links are followed, adding their targets in their place. A link to one of its
+    /// own ancestor directories fails the import with [`AddError::SymlinkCycle`].
+    Follow,
+}
+
+/// Whether [`add_path`] computes the checksums of the added files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestPolicy {
+    /// No checksums are computed.
+    Skip,
+    /// The checksums are returned as the [`AddedEntry::checksum`] of the files, from which
+    /// [`Manifest::from_added`] creates the manifest.
+    Return,
+    /// Like `Return`, with the JSON encoded manifest also added as a file of the given name in
+    /// the added directory. Adding a single file with this policy fails with
+    /// [`AddError::ManifestWithoutDirectory`].
+    Store(String),
+}
+
+/// A file or a directory created by [`add_path`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddedEntry {
+    /// Path of the entry, starting with the name of the added file or directory.
+    pub path: String,
+    /// The root Cid of the entry.
+    pub cid: Cid,
+    /// Cumulative size of the blocks making up the entry.
+    pub total_size: u64,
+    /// The checksum of the contents of a file, computed unless [`AddOptions::manifest`] is
+    /// [`ManifestPolicy::Skip`]. Always `None` for the directories, the symlinks and the stored
+    /// manifest itself.
+    pub checksum: Option<FileChecksum>,
+}
+
+/// Failure modes of [`add_path`].
+#[derive(Debug, thiserror::Error)]
+pub enum AddError {
+    /// Reading from the filesystem failed.
+    #[error("failed to read {}", .0.display())]
+    Io(PathBuf, #[source] std::io::Error),
+
+    /// The file or directory name was not valid UTF-8.
+    #[error("invalid utf-8 in file name: {}", .0.display())]
+    InvalidFilename(PathBuf),
+
+    /// The target of the symbolic link to be stored was not valid UTF-8.
+    #[error("invalid utf-8 in the target of symlink {}", .0.display())]
+    InvalidSymlinkTarget(PathBuf),
+
+    /// The followed symbolic link leads to a directory containing the link.
+    #[error("symlink cycle at {}", .0.display())]
+    SymlinkCycle(PathBuf),
+
+    /// Reading the archive stream failed.
+    #[error("failed to read the archive")]
+    ArchiveIo(#[source] std::io::Error),
+
+    /// The archive was malformed or used unsupported features.
+    #[error("invalid archive: {}", .0)]
+    InvalidArchive(String),
+
+    /// Downloading the file at the URL failed.
+    #[error("failed to download {}", .0)]
+    Download(String, #[source] Error),
+
+    /// Storing the created blocks failed.
+    #[error("put_block failed")]
+    Persisting(#[source] Error),
+
+    /// The gathered tree was invalid.
+    #[error("invalid directory tree")]
+    TreeGathering(#[source] TreeBuildingFailed),
+
+    /// Building the gathered tree failed.
+    #[error("constructed invalid directory tree")]
+    TreeBuilding(#[source] TreeConstructionFailed),
+
+    /// The manifest was to be stored, but a single file was added instead of a directory.
+    #[error("the manifest can only be stored in an added directory")]
+    ManifestWithoutDirectory,
+}
+
+/// Adds the file or the directory tree at `path`, returning the created files and directories so
+/// that the root of the import is last.
+///
+/// Directories are walked recursively while skipping the paths matched by the
+/// [`AddOptions::ignore`] rules, and unless [`AddOptions::hidden`] is set, the hidden files and
+/// directories. Symbolic links are handled according to [`AddOptions::symlinks`].
+///
+/// The paths within the import are always separated by `/`. On Windows the directories are walked
+/// through the verbatim `\\?\` paths, so that the paths longer than `MAX_PATH` can be added.
+pub async fn add_path<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    path: &Path,
+    opts: &AddOptions,
+) -> Result<Vec<AddedEntry>, AddError> {
+    let root_name = match path.file_name() {
+        Some(name) => name
+            .to_str()
+            .ok_or_else(|| AddError::InvalidFilename(path.to_owned()))?
+            .to_owned(),
+        // for example "/" or ".."; there is nothing better to use
+        None => String::new(),
+    };
+
+    let mut tree_opts = TreeOptions::default();
+    if opts.wrap_with_directory {
+        tree_opts.wrap_with_directory();
+    }
+
+    let mut tree = BufferingTreeBuilder::new(tree_opts);
+    let mut added = Vec::new();
+
+    // the already added files by their device and inode numbers
+    let mut hardlinks = HashMap::new();
+    let mut root_is_dir = false;
+
+    // the filesystem path, the relative path within the import and the canonical paths of the
+    // ancestor directories, which are only tracked when following the symlinks
+    let mut pending = vec![(long_path(path), String::new(), Vec::new())];
+
+    while let Some((fs_path, relative, ancestors)) = pending.pop() {
+        let mut metadata = tokio::fs::symlink_metadata(&fs_path)
+            .await
+            .map_err(|e| AddError::Io(fs_path.clone(), e))?;
+
+        let tree_path = if relative.is_empty() {
+            root_name.clone()
+        } else {
+            format!("{}/{}", root_name, relative)
+        };
+
+        if metadata.file_type().is_symlink() {
+            match opts.symlinks {
+                SymlinkPolicy::Skip => {
+                    trace!("skipping symlink {:?}", fs_path);
+                    continue;
+                }
+                SymlinkPolicy::Store => {
+                    let target = tokio::fs::read_link(&fs_path)
+                        .await
+                        .map_err(|e| AddError::Io(fs_path.clone(), e))?;
+                    let target = target
+                        .to_str()
+                        .ok_or_else(|| AddError::InvalidSymlinkTarget(fs_path.clone()))?;
+
+                    let (cid, total_size) =
+                        add_symlink(ipfs, &mut tree, &tree_path, target).await?;
+
+                    added.push(AddedEntry {
+                        path: tree_path,
+                        cid,
+                        total_size,
+                        checksum: None,
+                    });
+                    continue;
+                }
+                SymlinkPolicy::Follow => {
+                    metadata = tokio::fs::metadata(&fs_path)
+                        .await
+                        .map_err(|e| AddError::Io(fs_path.clone(), e))?;
+                }
+            }
+        }
+
+        if metadata.is_dir() {
+            root_is_dir |= relative.is_empty();
+
+            let ancestors = if opts.symlinks == SymlinkPolicy::Follow {
+                let canonical = tokio::fs::canonicalize(&fs_path)
+                    .await
+                    .map_err(|e| AddError::Io(fs_path.clone(), e))?;
+
+                if ancestors.contains(&canonical) {
+                    return Err(AddError::SymlinkCycle(fs_path));
+                }
+
+                let mut ancestors = ancestors;
+                ancestors.push(canonical);
+                ancestors
+            } else {
+                ancestors
+            };
+
+            tree.set_metadata(&tree_path, preserved_metadata(&metadata, opts))
+                .map_err(AddError::TreeGathering)?;
+
+            let mut entries = tokio::fs::read_dir(&fs_path)
+                .await
+                .map_err(|e| AddError::Io(fs_path.clone(), e))?;
+
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .map_err(|e| AddError::Io(fs_path.clone(), e))?
+            {
+                let name = entry.file_name();
+                let name = name
+                    .to_str()
+                    .ok_or_else(|| AddError::InvalidFilename(entry.path()))?;
+
+                if !opts.hidden && name.starts_with('.') {
+                    continue;
+                }
+
+                let child = if relative.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{}/{}", relative, name)
+                };
+
+                let file_type = entry
+                    .file_type()
+                    .await
+                    .map_err(|e| AddError::Io(entry.path(), e))?;
+
+                let is_dir = if file_type.is_symlink() && opts.symlinks == SymlinkPolicy::Follow {
+                    // a dangling link will fail the import once it is visited
+                    tokio::fs::metadata(entry.path())
+                        .await
+                        .map(|metadata| metadata.is_dir())
+                        .unwrap_or(false)
+                } else {
+                    file_type.is_dir()
+                };
+
+                if opts.ignore.is_ignored(&child, is_dir) {
+                    trace!("ignoring {:?}", entry.path());
+                    continue;
+                }
+
+                pending.push((entry.path(), child, ancestors.clone()));
+            }
+        } else if metadata.is_file() {
+            let hardlink = hardlink_key(&metadata).filter(|_| opts.dedup_hardlinks);
+
+            let (cid, total_size, checksum) = match hardlink.and_then(|key| hardlinks.get(&key)) {
+                Some((cid, total_size, checksum)) => {
+                    trace!("reusing the hardlinked {:?}", fs_path);
+                    (cid.clone(), *total_size, *checksum)
+                }
+                None => {
+                    let preserved = preserved_metadata(&metadata, opts);
+                    let with_checksum = opts.manifest != ManifestPolicy::Skip;
+                    let (cid, total_size, checksum) =
+                        add_file(ipfs, &fs_path, preserved, with_checksum, opts.nocopy).await?;
+                    if let Some(key) = hardlink {
+                        hardlinks.insert(key, (cid.clone(), total_size, checksum));
+                    }
+                    (cid, total_size, checksum)
+                }
+            };
+
+            tree.put_link(&tree_path, cid.clone(), total_size)
+                .map_err(AddError::TreeGathering)?;
+
+            added.push(AddedEntry {
+                path: tree_path,
+                cid,
+                total_size,
+                checksum,
+            });
+        } else {
+            trace!("skipping non-regular file {:?}", fs_path);
+        }
+    }
+
+    if let ManifestPolicy::Store(name) = &opts.manifest {
+        if !root_is_dir {
+            return Err(AddError::ManifestWithoutDirectory);
+        }
+
+        let json = Manifest::from_added(&added).to_json();
+
+        let mut import = FileImport::default();
+        import.push(ipfs, &json).await?;
+        let (cid, total_size) = import.finish(ipfs).await?;
+
+        let path = format!("{}/{}", root_name, name);
+        tree.put_link(&path, cid.clone(), total_size)
+            .map_err(AddError::TreeGathering)?;
+
+        added.push(AddedEntry {
+            path,
+            cid,
+            total_size,
+            checksum: None,
+        });
+    }
+
+    build_tree(ipfs, tree, &mut added).await?;
+
+    Ok(added)
+}
+
+/// Returns the `path` in the verbatim form lifting the `MAX_PATH` limit of Windows. The last
+/// component is kept as is, so that a symbolic link is not resolved to its target.
+#[cfg(windows)]
+fn long_path(path: &Path) -> PathBuf {
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return path.to_owned(),
+    };
+
+    match (std::fs::canonicalize(parent), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => path.to_owned(),
+    }
+}
+
+#[cfg(not(windows))]
+fn long_path(path: &Path) -> PathBuf {
+    path.to_owned()
+}
+
+/// Returns the mode and the modification time to store for a file or a directory, as selected by
+/// [`AddOptions::preserve_mode`] and [`AddOptions::preserve_mtime`].
+fn preserved_metadata(metadata: &std::fs::Metadata, opts: &AddOptions) -> Metadata {
+    let mode = if opts.preserve_mode {
+        file_mode(metadata)
+    } else {
+        None
+    };
+
+    let mtime = if opts.preserve_mtime {
+        metadata.modified().ok().map(|modified| {
+            match modified.duration_since(std::time::UNIX_EPOCH) {
+                Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+                Err(e) => {
+                    // the fractional part always counts forward from the seconds
+                    let before = e.duration();
+                    match before.subsec_nanos() {
+                        0 => (-(before.as_secs() as i64), 0),
+                        nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+                    }
+                }
+            }
+        })
+    } else {
+        None
+    };
+
+    Metadata::new(mode, mtime)
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // only the permission bits, the file type is implied by the UnixFS type
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Returns the device and inode numbers identifying a file with multiple hardlinks.
+#[cfg(unix)]
+fn hardlink_key(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    if metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn hardlink_key(_: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Builds and stores the directories of the tree, appending them to `added`.
+pub(super) async fn build_tree<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    tree: BufferingTreeBuilder,
+    added: &mut Vec<AddedEntry>,
+) -> Result<(), AddError> {
+    let mut iter = tree.build();
+
+    while let Some(res) = iter.next_borrowed() {
+        let TreeNode {
+            path,
+            cid,
+            total_size,
+            block,
+            bucket,
+            ..
+        } = res.map_err(AddError::TreeBuilding)?;
+
+        ipfs.put_block(Block {
+            cid: cid.to_owned(),
+            data: block.into(),
+        })
+        .await
+        .map_err(AddError::Persisting)?;
+
+        if bucket {
+            // an inner bucket of a sharded directory, only the root bucket is reported
+            continue;
+        }
+
+        added.push(AddedEntry {
+            path: path.to_owned(),
+            cid: cid.to_owned(),
+            total_size,
+            checksum: None,
+        });
+    }
+
+    Ok(())
+}
+
+/// Adds a single file, returning the root Cid, the cumulative size of the blocks and the
+/// checksum of the contents if asked for.
+async fn add_file<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    path: &Path,
+    metadata: Metadata,
+    with_checksum: bool,
+    nocopy: bool,
+) -> Result<(Cid, u64, Option<FileChecksum>), AddError> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| AddError::Io(path.to_owned(), e))?;
+
+    let mut import = if nocopy {
+        // the references need to stay valid regardless of the working directory
+        let absolute = tokio::fs::canonicalize(path)
+            .await
+            .map_err(|e| AddError::Io(path.to_owned(), e))?;
+        FileImport::without_copying(metadata, FileSource::Path(absolute))
+    } else {
+        FileImport::with_metadata(metadata)
+    };
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut hasher = if with_checksum {
+        Some(Sha256::new())
+    } else {
+        None
+    };
+    let mut size = 0;
+
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .await
+            .map_err(|e| AddError::Io(path.to_owned(), e))?;
+
+        if read == 0 {
+            break;
+        }
+
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buffer[..read]);
+        }
+        size += read as u64;
+
+        import.push(ipfs, &buffer[..read]).await?;
+    }
+
+    let (cid, total_size) = import.finish(ipfs).await?;
+
+    let checksum = hasher.map(|hasher| FileChecksum {
+        size,
+        sha256: hasher.finalize().into(),
+    });
+
+    Ok((cid, total_size, checksum))
+}
+
+/// Adds a UnixFS symlink to the `target` at `path` of the `tree`, returning its Cid and the size
+/// of the block.
+pub(super) async fn add_symlink<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    tree: &mut BufferingTreeBuilder,
+    path: &str,
+    target: &str,
+) -> Result<(Cid, u64), AddError> {
+    let (cid, block) = tree
+        .put_symlink(path, target)
+        .map_err(AddError::TreeGathering)?;
+    let total_size = block.len() as u64;
+
+    let cid = put(ipfs, cid, block).await?;
+    Ok((cid, total_size))
+}
+
+/// Chunks the file contents pushed in and stores the completed blocks as they are created.
+#[derive(Default)]
+pub(crate) struct FileImport {
+    adder: FileAdder,
+    last: Option<Cid>,
+    total_size: u64,
+    /// The file the raw leaves are referenced from, and the offset of the next leaf.
+    nocopy: Option<(FileSource, u64)>,
+}
+
+impl FileImport {
+    /// Creates an import storing the `metadata` in the root block of the file.
+    pub(super) fn with_metadata(metadata: Metadata) -> Self {
+        FileImport {
+            adder: FileAdder::builder().with_metadata(metadata).build(),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an import of the file at the `source` recording the raw leaves as the ranges of
+    /// the file instead of storing them, see [`AddOptions::nocopy`].
+    pub(super) fn without_copying(metadata: Metadata, source: FileSource) -> Self {
+        FileImport {
+            adder: FileAdder::builder()
+                .with_metadata(metadata)
+                .with_raw_leaves(true)
+                .build(),
+            nocopy: Some((source, 0)),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) async fn push<Types: IpfsTypes>(
+        &mut self,
+        ipfs: &Ipfs<Types>,
+        mut bytes: &[u8],
+    ) -> Result<(), AddError> {
+        while !bytes.is_empty() {
+            let (blocks, used) = self.adder.push(bytes);
+            bytes = &bytes[used..];
+            for (cid, data) in blocks {
+                self.total_size += data.len() as u64;
+                let cid = self.store(ipfs, cid, data).await?;
+                self.last = Some(cid);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the root Cid and the cumulative size of the blocks.
+    pub(crate) async fn finish<Types: IpfsTypes>(
+        mut self,
+        ipfs: &Ipfs<Types>,
+    ) -> Result<(Cid, u64), AddError> {
+        let blocks = std::mem::take(&mut self.adder).finish().collect::<Vec<_>>();
+        for (cid, data) in blocks {
+            self.total_size += data.len() as u64;
+            let cid = self.store(ipfs, cid, data).await?;
+            self.last = Some(cid);
+        }
+
+        let cid = self
+            .last
+            .expect("finish always produces at least the root block");
+        Ok((cid, self.total_size))
+    }
+
+    async fn store<Types: IpfsTypes>(
+        &mut self,
+        ipfs: &Ipfs<Types>,
+        cid: Cid,
+        data: Vec<u8>,
+    ) -> Result<Cid, AddError> {
+        match self.nocopy.as_mut() {
+            Some((source, offset)) if cid.codec() == Codec::Raw => {
+                let len = data.len() as u64;
+                let block = Block {
+                    cid,
+                    data: data.into_boxed_slice(),
+                };
+                let (cid, _) = ipfs
+                    .repo
+                    .put_filestore_block(block, source, *offset)
+                    .await
+                    .map_err(AddError::Persisting)?;
+                *offset += len;
+                Ok(cid)
+            }
+            _ => put(ipfs, cid, data).await,
+        }
+    }
+}
+
+pub(super) async fn put<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    cid: Cid,
+    data: Vec<u8>,
+) -> Result<Cid, AddError> {
+    ipfs.put_block(Block {
+        cid,
+        data: data.into_boxed_slice(),
+    })
+    .await
+    .map_err(AddError::Persisting)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_path, AddError, AddOptions, ManifestPolicy, SymlinkPolicy};
+    use crate::unixfs::{IgnoreRules, Manifest};
+    use crate::Node;
+    use std::fs;
+
+    fn paths(entries: &[super::AddedEntry]) -> Vec<&str> {
+        let mut paths = entries.iter().map(|e| e.path.as_str()).collect::<Vec<_>>();
+        paths.sort_unstable();
+        paths
+    }
+
+    #[tokio::test]
+    async fn add_directory_with_ignores() {
+        let ipfs = Node::new("test_node").await;
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("project");
+
+        fs::create_dir_all(root.join(".git/objects")).unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("target/debug")).unwrap();
+        fs::write(root.join(".git/HEAD"), b"ref: refs/heads/master\n").unwrap();
+        fs::write(root.join(".env"), b"SECRET=1\n").unwrap();
+        fs::write(root.join("src/lib.rs"), b"fn main() {}\n").unwrap();
+        fs::write(root.join("src/lib.o"), b"\x7fELF").unwrap();
+        fs::write(root.join("target/debug/out"), b"binary").unwrap();
+
+        let mut opts = AddOptions::default();
+        opts.ignore.extend_from_str("target/\n*.o");
+
+        let added = add_path(&ipfs, &root, &opts).await.unwrap();
+        assert_eq!(added.last().unwrap().path, "project");
+        assert_eq!(
+            paths(&added),
+            &["project", "project/src", "project/src/lib.rs"]
+        );
+
+        opts.hidden = true;
+        let added = add_path(&ipfs, &root, &opts).await.unwrap();
+        assert_eq!(
+            paths(&added),
+            &[
+                "project",
+                "project/.env",
+                "project/src",
+                "project/src/lib.rs"
+            ]
+        );
+
+        opts.ignore = IgnoreRules::empty();
+        let added = add_path(&ipfs, &root, &opts).await.unwrap();
+        assert_eq!(
+            paths(&added),
+            &[
+                "project",
+                "project/.env",
+                "project/.git",
+                "project/.git/HEAD",
+                "project/.git/objects",
+                "project/src",
+                "project/src/lib.o",
+                "project/src/lib.rs",
+                "project/target",
+                "project/target/debug",
+                "project/target/debug/out",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn add_single_file() {
+        let ipfs = Node::new("test_node").await;
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.txt");
+        fs::write(&file, b"Here is some data\n").unwrap();
+
+        let added = add_path(&ipfs, &file, &AddOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].path, "a.txt");
+        assert!(ipfs.get_block(&added[0].cid).await.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlink_policies() {
+        use std::os::unix::fs::symlink;
+
+        let ipfs = Node::new("test_node").await;
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("site");
+
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("docs/index.html"), b"<html></html>\n").unwrap();
+        symlink("docs/index.html", root.join("index.html")).unwrap();
+        symlink("docs", root.join("latest")).unwrap();
+
+        let mut opts = AddOptions::default();
+        let added = add_path(&ipfs, &root, &opts).await.unwrap();
+        assert_eq!(
+            paths(&added),
+            &["site", "site/docs", "site/docs/index.html"]
+        );
+
+        opts.symlinks = SymlinkPolicy::Store;
+        let added = add_path(&ipfs, &root, &opts).await.unwrap();
+        assert_eq!(
+            paths(&added),
+            &[
+                "site",
+                "site/docs",
+                "site/docs/index.html",
+                "site/index.html",
+                "site/latest"
+            ]
+        );
+        let link = added.iter().find(|e| e.path == "site/latest").unwrap();
+        let file = added
+            .iter()
+            .find(|e| e.path == "site/docs/index.html")
+            .unwrap();
+        assert_ne!(link.cid, file.cid);
+
+        opts.symlinks = SymlinkPolicy::Follow;
+        let added = add_path(&ipfs, &root, &opts).await.unwrap();
+        assert_eq!(
+            paths(&added),
+            &[
+                "site",
+                "site/docs",
+                "site/docs/index.html",
+                "site/index.html",
+                "site/latest",
+                "site/latest/index.html"
+            ]
+        );
+        let cid_of = |path: &str| &added.iter().find(|e| e.path == path).unwrap().cid;
+        assert_eq!(cid_of("site/latest"), cid_of("site/docs"));
+        assert_eq!(cid_of("site/index.html"), cid_of("site/docs/index.html"));
+
+        symlink("..", root.join("docs/up")).unwrap();
+        let err = add_path(&ipfs, &root, &opts).await.unwrap_err();
+        assert!(matches!(err, AddError::SymlinkCycle(_)), "{:?}", err);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hardlinked_files() {
+        let ipfs = Node::new("test_node").await;
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("data");
+
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.bin"), vec![7u8; 300 * 1024]).unwrap();
+        fs::hard_link(root.join("a.bin"), root.join("b.bin")).unwrap();
+
+        let added = add_path(&ipfs, &root, &AddOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(paths(&added), &["data", "data/a.bin", "data/b.bin"]);
+        assert_eq!(added[0].cid, added[1].cid);
+        assert_eq!(added[0].total_size, added[1].total_size);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn preserved_mode_and_mtime() {
+        use ipfs_unixfs::file::visit::IdleFileVisit;
+        use std::os::unix::fs::PermissionsExt;
+
+        let ipfs = Node::new("test_node").await;
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.txt");
+        fs::write(&file, b"Here is some data\n").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let plain = add_path(&ipfs, &file, &AddOptions::default())
+            .await
+            .unwrap();
+
+        let opts = AddOptions {
+            preserve_mode: true,
+            preserve_mtime: true,
+            ..Default::default()
+        };
+        let preserved = add_path(&ipfs, &file, &opts).await.unwrap();
+        assert_ne!(plain[0].cid, preserved[0].cid);
+
+        let block = ipfs.get_block(&preserved[0].cid).await.unwrap();
+        let (content, _, metadata, _) = IdleFileVisit::default().start(block.data()).unwrap();
+        assert_eq!(content, b"Here is some data\n");
+        assert_eq!(metadata.mode(), Some(0o640));
+
+        let modified = fs::metadata(&file).unwrap().modified().unwrap();
+        let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(
+            metadata.mtime(),
+            Some((since_epoch.as_secs() as i64, since_epoch.subsec_nanos()))
+        );
+    }
+
+    #[tokio::test]
+    async fn checksum_manifest() {
+        let ipfs = Node::new("test_node").await;
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("dataset");
+
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("b.txt"), b"abc").unwrap();
+        fs::write(root.join("sub/a.txt"), b"").unwrap();
+
+        let plain = add_path(&ipfs, &root, &AddOptions::default())
+            .await
+            .unwrap();
+        assert!(plain.iter().all(|e| e.checksum.is_none()));
+
+        let mut opts = AddOptions {
+            manifest: ManifestPolicy::Return,
+            ..Default::default()
+        };
+        let returned = add_path(&ipfs, &root, &opts).await.unwrap();
+        assert_eq!(returned.last().unwrap().cid, plain.last().unwrap().cid);
+
+        let manifest = Manifest::from_added(&returned);
+        let listed = manifest
+            .entries
+            .iter()
+            .map(|e| (e.path.as_str(), e.checksum.size))
+            .collect::<Vec<_>>();
+        assert_eq!(listed, &[("dataset/b.txt", 3), ("dataset/sub/a.txt", 0)]);
+        assert_eq!(
+            manifest.entries[0].checksum.sha256,
+            hex_literal::hex!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+
+        opts.manifest = ManifestPolicy::Store("SHA256SUMS.json".into());
+        let stored = add_path(&ipfs, &root, &opts).await.unwrap();
+        assert_eq!(
+            paths(&stored),
+            &[
+                "dataset",
+                "dataset/SHA256SUMS.json",
+                "dataset/b.txt",
+                "dataset/sub",
+                "dataset/sub/a.txt"
+            ]
+        );
+        assert_ne!(stored.last().unwrap().cid, plain.last().unwrap().cid);
+
+        let file = stored
+            .iter()
+            .find(|e| e.path == "dataset/SHA256SUMS.json")
+            .unwrap();
+        let block = ipfs.get_block(&file.cid).await.unwrap();
+        let (content, _, _, _) = ipfs_unixfs::file::visit::IdleFileVisit::default()
+            .start(block.data())
+            .unwrap();
+        assert_eq!(content, &manifest.to_json()[..]);
+
+        let file = root.join("b.txt");
+        let err = add_path(&ipfs, &file, &opts).await.unwrap_err();
+        assert!(
+            matches!(err, AddError::ManifestWithoutDirectory),
+            "{:?}",
+            err
+        );
+    }
+}
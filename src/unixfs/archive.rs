@@ -0,0 +1,781 @@
+//! Importing directory trees from tar and zip archive streams without unpacking them to disk.
+
+use super::add::{add_symlink, build_tree, AddError, AddOptions, AddedEntry, FileImport};
+use super::normalize::{segments, Separators};
+use crate::{Ipfs, IpfsTypes};
+use cid::Cid;
+use ipfs_unixfs::dir::builder::{BufferingTreeBuilder, TreeOptions};
+use ipfs_unixfs::Metadata;
+use std::borrow::Cow;
+use std::convert::TryInto;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+/// The supported archive formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// Uncompressed ustar, GNU or pax tar archive.
+    Tar,
+    /// Zip archive with stored or deflated entries.
+    Zip,
+}
+
+/// Adds the files, directories and symlinks of the archive read from `reader`, returning the
+/// created entries so that the roots of the import are last.
+///
+/// The archive is consumed as a stream, so the zip central directory is never consulted and
+/// entries are imported in the order of their local headers. The [`AddOptions::ignore`] rules and
+/// [`AddOptions::hidden`] apply to the paths within the archive. The modes and modification times
+/// of the file and directory entries are carried over to the created blocks; hard links and
+/// special files are skipped.
+///
+/// The zip entry names are normalized as Windows paths, since the archivers on Windows write them
+/// with backslashes and at times with the drive letters.
+pub async fn add_archive<Types, R>(
+    ipfs: &Ipfs<Types>,
+    reader: R,
+    format: ArchiveFormat,
+    opts: &AddOptions,
+) -> Result<Vec<AddedEntry>, AddError>
+where
+    Types: IpfsTypes,
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let separators = match format {
+        ArchiveFormat::Tar => Separators::Slash,
+        ArchiveFormat::Zip => Separators::Windows,
+    };
+    let mut import = ArchiveImport::new(opts, separators);
+
+    match format {
+        ArchiveFormat::Tar => import_tar(ipfs, &mut reader, &mut import).await?,
+        ArchiveFormat::Zip => import_zip(ipfs, &mut reader, &mut import).await?,
+    }
+
+    let ArchiveImport {
+        tree, mut added, ..
+    } = import;
+
+    build_tree(ipfs, tree, &mut added).await?;
+
+    Ok(added)
+}
+
+/// Upper limit for the GNU long names and pax extended headers which are buffered in memory.
+const MAX_HEADER_DATA: u64 = 1024 * 1024;
+
+async fn import_tar<Types, R>(
+    ipfs: &Ipfs<Types>,
+    reader: &mut R,
+    import: &mut ArchiveImport<'_>,
+) -> Result<(), AddError>
+where
+    Types: IpfsTypes,
+    R: AsyncBufRead + Unpin,
+{
+    let mut block = [0u8; 512];
+    // overrides for the next entry from GNU long name or pax extended headers
+    let mut long_name: Option<Vec<u8>> = None;
+    let mut long_link: Option<Vec<u8>> = None;
+
+    loop {
+        reader
+            .read_exact(&mut block)
+            .await
+            .map_err(AddError::ArchiveIo)?;
+
+        if block.iter().all(|&b| b == 0) {
+            // end of archive marker; the second zero block and any padding are not read
+            return Ok(());
+        }
+
+        let header = tar::Header::from_byte_slice(&block);
+        let kind = header.entry_type();
+        let size = header.entry_size().map_err(AddError::ArchiveIo)?;
+        let padding = (512 - size % 512) % 512;
+
+        if kind.is_gnu_longname() || kind.is_gnu_longlink() || kind.is_pax_local_extensions() {
+            let data = read_header_data(reader, size).await?;
+
+            if kind.is_gnu_longname() {
+                long_name = Some(trim_nul(data));
+            } else if kind.is_gnu_longlink() {
+                long_link = Some(trim_nul(data));
+            } else {
+                for (key, value) in pax_records(&data)? {
+                    match key {
+                        "path" => long_name = Some(value.to_vec()),
+                        "linkpath" => long_link = Some(value.to_vec()),
+                        _ => {}
+                    }
+                }
+            }
+        } else {
+            let name = long_name
+                .take()
+                .unwrap_or_else(|| header.path_bytes().into_owned());
+            let link = long_link
+                .take()
+                .or_else(|| header.link_name_bytes().map(Cow::into_owned));
+            let name =
+                String::from_utf8(name).map_err(|_| invalid("invalid utf-8 in tar entry name"))?;
+
+            let mode = header.mode().ok();
+            let mtime = header.mtime().ok().map(|secs| (secs as i64, 0));
+            let metadata = Metadata::new(mode, mtime);
+
+            if kind.is_file() || kind.is_contiguous() {
+                let mut data = (&mut *reader).take(size);
+                import
+                    .file_from_reader(ipfs, &name, metadata, &mut data)
+                    .await?;
+            } else if kind.is_dir() {
+                import.directory(&name, metadata)?;
+                skip(reader, size).await?;
+            } else if kind.is_symlink() {
+                let target = link.ok_or_else(|| invalid("tar symlink without a target"))?;
+                let target = String::from_utf8(target)
+                    .map_err(|_| invalid("invalid utf-8 in tar symlink target"))?;
+                import.symlink(ipfs, &name, &target).await?;
+                skip(reader, size).await?;
+            } else {
+                trace!("skipping tar entry {:?} of type {:?}", name, kind);
+                skip(reader, size).await?;
+            }
+        }
+
+        skip(reader, padding).await?;
+    }
+}
+
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+async fn import_zip<Types, R>(
+    ipfs: &Ipfs<Types>,
+    reader: &mut R,
+    import: &mut ArchiveImport<'_>,
+) -> Result<(), AddError>
+where
+    Types: IpfsTypes,
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        match reader.read_u32_le().await.map_err(AddError::ArchiveIo)? {
+            LOCAL_FILE_HEADER => {}
+            // all of the entries have been read once the central directory starts
+            CENTRAL_DIRECTORY_HEADER | END_OF_CENTRAL_DIRECTORY => return Ok(()),
+            other => return Err(invalid(format!("unexpected zip signature {:#010x}", other))),
+        }
+
+        let mut fixed = [0u8; 26];
+        reader
+            .read_exact(&mut fixed)
+            .await
+            .map_err(AddError::ArchiveIo)?;
+
+        let le16 = |at: usize| u16::from_le_bytes([fixed[at], fixed[at + 1]]);
+        let le32 = |at: usize| u32::from_le_bytes(fixed[at..at + 4].try_into().unwrap());
+
+        let flags = le16(2);
+        let method = le16(4);
+        let mtime = dos_datetime_to_unix(le16(8), le16(6));
+        let mut compressed_size = u64::from(le32(14));
+        let mut uncompressed_size = u64::from(le32(18));
+
+        let name = read_header_data(reader, u64::from(le16(22))).await?;
+        let extra = read_header_data(reader, u64::from(le16(24))).await?;
+
+        if flags & 0x0001 != 0 {
+            return Err(invalid("encrypted zip entries are unsupported"));
+        }
+
+        let zip64 = apply_zip64_extra(&extra, &mut uncompressed_size, &mut compressed_size)?;
+        let has_descriptor = flags & 0x0008 != 0;
+
+        let name =
+            String::from_utf8(name).map_err(|_| invalid("invalid utf-8 in zip entry name"))?;
+
+        // the permissions are only recorded in the central directory
+        let metadata = Metadata::new(None, Some((mtime, 0)));
+
+        if name.ends_with('/') || name.ends_with('\\') {
+            import.directory(&name, metadata)?;
+            if !has_descriptor {
+                skip(reader, compressed_size).await?;
+            }
+        } else {
+            match method {
+                0 if has_descriptor => {
+                    return Err(invalid(
+                        "stored zip entries with data descriptors are unsupported",
+                    ))
+                }
+                0 => {
+                    let mut data = (&mut *reader).take(compressed_size);
+                    import
+                        .file_from_reader(ipfs, &name, metadata, &mut data)
+                        .await?;
+                }
+                8 => {
+                    let path = import.accept(&name, false)?;
+                    let mut file = path.as_ref().map(|_| FileImport::with_metadata(metadata));
+                    inflate(ipfs, reader, file.as_mut()).await?;
+                    if let (Some(path), Some(file)) = (path, file) {
+                        let (cid, total_size) = file.finish(ipfs).await?;
+                        import.put_file(path, cid, total_size)?;
+                    }
+                }
+                other => {
+                    return Err(invalid(format!(
+                        "unsupported zip compression method {}",
+                        other
+                    )))
+                }
+            }
+        }
+
+        if has_descriptor {
+            // the signature is optional; without it the first field is the crc32
+            let first = reader.read_u32_le().await.map_err(AddError::ArchiveIo)?;
+            let sizes = if zip64 { 16 } else { 8 };
+            let remaining = if first == DATA_DESCRIPTOR {
+                4 + sizes
+            } else {
+                sizes
+            };
+            skip(reader, remaining).await?;
+        }
+    }
+}
+
+/// Replaces the saturated sizes with the ones from the zip64 extended information, returning true
+/// if it was present.
+fn apply_zip64_extra(
+    mut extra: &[u8],
+    uncompressed_size: &mut u64,
+    compressed_size: &mut u64,
+) -> Result<bool, AddError> {
+    while extra.len() >= 4 {
+        let id = u16::from_le_bytes([extra[0], extra[1]]);
+        let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        let data = extra
+            .get(4..4 + len)
+            .ok_or_else(|| invalid("truncated zip extra field"))?;
+        extra = &extra[4 + len..];
+
+        if id != ZIP64_EXTRA_FIELD {
+            continue;
+        }
+
+        let mut values = data
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+
+        for size in [uncompressed_size, compressed_size] {
+            if *size == u64::from(u32::MAX) {
+                *size = values
+                    .next()
+                    .ok_or_else(|| invalid("truncated zip64 extra field"))?;
+            }
+        }
+
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+/// Inflates a deflate stream from the reader, pushing the contents to `file` if given. Only the
+/// bytes of the deflate stream are consumed from the reader.
+async fn inflate<Types, R>(
+    ipfs: &Ipfs<Types>,
+    reader: &mut R,
+    mut file: Option<&mut FileImport>,
+) -> Result<(), AddError>
+where
+    Types: IpfsTypes,
+    R: AsyncBufRead + Unpin,
+{
+    let mut inflater = flate2::Decompress::new(false);
+    let mut out = vec![0u8; 64 * 1024];
+
+    loop {
+        let input = reader.fill_buf().await.map_err(AddError::ArchiveIo)?;
+
+        if input.is_empty() {
+            return Err(invalid("truncated deflate stream"));
+        }
+
+        let (in_before, out_before) = (inflater.total_in(), inflater.total_out());
+        let status = inflater
+            .decompress(input, &mut out, flate2::FlushDecompress::None)
+            .map_err(|e| invalid(format!("invalid deflate stream: {}", e)))?;
+        let consumed = (inflater.total_in() - in_before) as usize;
+        let produced = (inflater.total_out() - out_before) as usize;
+
+        reader.consume(consumed);
+
+        if let Some(file) = file.as_mut() {
+            file.push(ipfs, &out[..produced]).await?;
+        }
+
+        if status == flate2::Status::StreamEnd {
+            return Ok(());
+        }
+    }
+}
+
+/// Collects the entries of an archive into the tree, applying the ignore rules and the hidden
+/// files setting.
+struct ArchiveImport<'a> {
+    opts: &'a AddOptions,
+    separators: Separators,
+    tree: BufferingTreeBuilder,
+    added: Vec<AddedEntry>,
+}
+
+impl<'a> ArchiveImport<'a> {
+    fn new(opts: &'a AddOptions, separators: Separators) -> Self {
+        let mut tree_opts = TreeOptions::default();
+        if opts.wrap_with_directory {
+            tree_opts.wrap_with_directory();
+        }
+
+        ArchiveImport {
+            opts,
+            separators,
+            tree: BufferingTreeBuilder::new(tree_opts),
+            added: Vec::new(),
+        }
+    }
+
+    /// Returns the normalized path of the entry, or `None` if the entry should be skipped.
+    fn accept(&self, name: &str, is_dir: bool) -> Result<Option<String>, AddError> {
+        let segments = segments(name, self.separators)
+            .ok_or_else(|| invalid(format!("parent directory in entry {:?}", name)))?;
+
+        if segments.is_empty() {
+            return Ok(None);
+        }
+
+        if !self.opts.hidden && segments.iter().any(|s| s.starts_with('.')) {
+            return Ok(None);
+        }
+
+        // ancestors need to be checked as well since the entries are not walked from the root
+        for end in 1..=segments.len() {
+            let path = segments[..end].join("/");
+            let is_dir = end < segments.len() || is_dir;
+            if self.opts.ignore.is_ignored(&path, is_dir) {
+                trace!("ignoring archive entry {:?}", name);
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(segments.join("/")))
+    }
+
+    fn directory(&mut self, name: &str, metadata: Metadata) -> Result<(), AddError> {
+        if let Some(path) = self.accept(name, true)? {
+            self.tree
+                .set_metadata(&path, metadata)
+                .map_err(AddError::TreeGathering)?;
+        }
+        Ok(())
+    }
+
+    /// Imports the file from a reader which ends with the file contents, storing the `metadata`
+    /// in the root block. The reader is drained even when the file is skipped.
+    async fn file_from_reader<Types, R>(
+        &mut self,
+        ipfs: &Ipfs<Types>,
+        name: &str,
+        metadata: Metadata,
+        reader: &mut R,
+    ) -> Result<(), AddError>
+    where
+        Types: IpfsTypes,
+        R: AsyncRead + Unpin,
+    {
+        let path = match self.accept(name, false)? {
+            Some(path) => path,
+            None => {
+                tokio::io::copy(reader, &mut tokio::io::sink())
+                    .await
+                    .map_err(AddError::ArchiveIo)?;
+                return Ok(());
+            }
+        };
+
+        let mut file = FileImport::with_metadata(metadata);
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        loop {
+            let read = reader
+                .read(&mut buffer)
+                .await
+                .map_err(AddError::ArchiveIo)?;
+
+            if read == 0 {
+                break;
+            }
+
+            file.push(ipfs, &buffer[..read]).await?;
+        }
+
+        let (cid, total_size) = file.finish(ipfs).await?;
+        self.put_file(path, cid, total_size)
+    }
+
+    async fn symlink<Types: IpfsTypes>(
+        &mut self,
+        ipfs: &Ipfs<Types>,
+        name: &str,
+        target: &str,
+    ) -> Result<(), AddError> {
+        let path = match self.accept(name, false)? {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let (cid, total_size) = add_symlink(ipfs, &mut self.tree, &path, target).await?;
+
+        self.added.push(AddedEntry {
+            path,
+            cid,
+            total_size,
+            checksum: None,
+        });
+
+        Ok(())
+    }
+
+    fn put_file(&mut self, path: String, cid: Cid, total_size: u64) -> Result<(), AddError> {
+        self.tree
+            .put_link(&path, cid.clone(), total_size)
+            .map_err(AddError::TreeGathering)?;
+
+        self.added.push(AddedEntry {
+            path,
+            cid,
+            total_size,
+            checksum: None,
+        });
+
+        Ok(())
+    }
+}
+
+async fn read_header_data<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    len: u64,
+) -> Result<Vec<u8>, AddError> {
+    if len > MAX_HEADER_DATA {
+        return Err(invalid(format!("too large header of {} bytes", len)));
+    }
+
+    let mut data = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut data)
+        .await
+        .map_err(AddError::ArchiveIo)?;
+    Ok(data)
+}
+
+async fn skip<R: AsyncRead + Unpin>(reader: &mut R, len: u64) -> Result<(), AddError> {
+    let skipped = tokio::io::copy(&mut reader.take(len), &mut tokio::io::sink())
+        .await
+        .map_err(AddError::ArchiveIo)?;
+
+    if skipped != len {
+        return Err(AddError::ArchiveIo(
+            std::io::ErrorKind::UnexpectedEof.into(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn trim_nul(mut data: Vec<u8>) -> Vec<u8> {
+    while data.last() == Some(&0) {
+        data.pop();
+    }
+    data
+}
+
+/// Parses the `"<len> <key>=<value>\n"` records of a pax extended header.
+fn pax_records(mut data: &[u8]) -> Result<Vec<(&str, &[u8])>, AddError> {
+    let mut records = Vec::new();
+
+    while !data.is_empty() {
+        let space = data
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or_else(|| invalid("invalid pax record"))?;
+        let len = std::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| len > space + 1 && len <= data.len())
+            .ok_or_else(|| invalid("invalid pax record length"))?;
+
+        // without the trailing newline
+        let record = &data[space + 1..len - 1];
+        data = &data[len..];
+
+        let eq = record
+            .iter()
+            .position(|&b| b == b'=')
+            .ok_or_else(|| invalid("invalid pax record"))?;
+        let key =
+            std::str::from_utf8(&record[..eq]).map_err(|_| invalid("invalid pax record key"))?;
+
+        records.push((key, &record[eq + 1..]));
+    }
+
+    Ok(records)
+}
+
+/// Converts the MS-DOS date and time fields to seconds since the unix epoch, interpreting the local
+/// time as UTC.
+fn dos_datetime_to_unix(date: u16, time: u16) -> i64 {
+    let year = 1980 + i64::from(date >> 9);
+    let month = i64::from((date >> 5) & 0x0f).max(1);
+    let day = i64::from(date & 0x1f).max(1);
+
+    let hours = i64::from(time >> 11);
+    let minutes = i64::from((time >> 5) & 0x3f);
+    let seconds = i64::from(time & 0x1f) * 2;
+
+    days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds
+}
+
+/// Days since the unix epoch for the proleptic gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn invalid<S: Into<String>>(msg: S) -> AddError {
+    AddError::InvalidArchive(msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_archive, dos_datetime_to_unix, pax_records, ArchiveFormat};
+    use crate::unixfs::{AddOptions, AddedEntry};
+    use crate::Node;
+    use std::io::Write;
+
+    fn paths(entries: &[AddedEntry]) -> Vec<&str> {
+        let mut paths = entries.iter().map(|e| e.path.as_str()).collect::<Vec<_>>();
+        paths.sort_unstable();
+        paths
+    }
+
+    fn tar_archive() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "dist/", std::io::empty())
+            .unwrap();
+
+        let long_name = format!("dist/{}.js", "a".repeat(150));
+        let files: [(&str, &[u8]); 2] = [("dist/index.html", b"<html>"), (&long_name, b"x")];
+        for (path, content) in files.iter() {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(content.len() as u64);
+            builder.append_data(&mut header, path, *content).unwrap();
+        }
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "dist/latest", "index.html")
+            .unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        builder
+            .append_data(&mut header, "dist/.cache", &b"abc"[..])
+            .unwrap();
+
+        builder.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn import_tar() {
+        let ipfs = Node::new("test_node").await;
+        let archive = tar_archive();
+
+        let added = add_archive(
+            &ipfs,
+            archive.as_slice(),
+            ArchiveFormat::Tar,
+            &AddOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let long_name = format!("dist/{}.js", "a".repeat(150));
+        let mut expected = vec!["dist", "dist/index.html", "dist/latest", long_name.as_str()];
+        expected.sort_unstable();
+
+        assert_eq!(added.last().unwrap().path, "dist");
+        assert_eq!(paths(&added), expected);
+    }
+
+    #[tokio::test]
+    async fn file_mode_and_mtime_survive_the_import() {
+        use ipfs_unixfs::file::visit::IdleFileVisit;
+
+        let ipfs = Node::new("test_node").await;
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o640);
+        header.set_mtime(1_595_865_585);
+        header.set_size(4);
+        builder
+            .append_data(&mut header, "a.txt", &b"data"[..])
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let added = add_archive(
+            &ipfs,
+            archive.as_slice(),
+            ArchiveFormat::Tar,
+            &AddOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let block = ipfs.get_block(&added[0].cid).await.unwrap();
+        let (content, _, metadata, _) = IdleFileVisit::default().start(block.data()).unwrap();
+        assert_eq!(content, b"data");
+        assert_eq!(metadata.mode(), Some(0o640));
+        assert_eq!(metadata.mtime(), Some((1_595_865_585, 0)));
+
+        // the zip entries only carry the modification time in the local headers
+        let archive = zip_archive();
+        let added = add_archive(
+            &ipfs,
+            archive.as_slice(),
+            ArchiveFormat::Zip,
+            &AddOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        for path in &["site/stored.txt", "site/deflated.txt"] {
+            let entry = added.iter().find(|e| e.path == *path).unwrap();
+            let block = ipfs.get_block(&entry.cid).await.unwrap();
+            let (_, _, metadata, _) = IdleFileVisit::default().start(block.data()).unwrap();
+            assert_eq!(metadata.mode(), None);
+            // 1980-01-01 00:00:00
+            assert_eq!(metadata.mtime(), Some((315_532_800, 0)));
+        }
+    }
+
+    /// Writes a zip archive with a stored, a deflated and a directory entry, where the deflated
+    /// entry uses a data descriptor, and an entry named like on Windows.
+    fn zip_archive() -> Vec<u8> {
+        let mut deflated = flate2::write::DeflateEncoder::new(Vec::new(), Default::default());
+        deflated.write_all(&[b'z'; 10_000]).unwrap();
+        let deflated = deflated.finish().unwrap();
+
+        let mut out = Vec::new();
+        let mut local = |name: &str, method: u16, flags: u16, data: &[u8], size: u32| {
+            out.extend_from_slice(&super::LOCAL_FILE_HEADER.to_le_bytes());
+            out.extend_from_slice(&20u16.to_le_bytes());
+            out.extend_from_slice(&flags.to_le_bytes());
+            out.extend_from_slice(&method.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&33u16.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(data);
+        };
+
+        local("site/", 0, 0, &[], 0);
+        local("site/stored.txt", 0, 0, b"stored", 6);
+        local("C:\\site\\docs\\windows.txt", 0, 0, b"windows", 7);
+        local("site/deflated.txt", 8, 0x0008, &deflated, 0);
+
+        // data descriptor with the signature
+        out.extend_from_slice(&super::DATA_DESCRIPTOR.to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&(deflated.len() as u32).to_le_bytes());
+        out.extend_from_slice(&10_000u32.to_le_bytes());
+
+        // the central directory is not read
+        out.extend_from_slice(&super::CENTRAL_DIRECTORY_HEADER.to_le_bytes());
+        out
+    }
+
+    #[tokio::test]
+    async fn import_zip() {
+        let ipfs = Node::new("test_node").await;
+        let archive = zip_archive();
+
+        let mut opts = AddOptions::default();
+        opts.wrap_with_directory = true;
+
+        let added = add_archive(&ipfs, archive.as_slice(), ArchiveFormat::Zip, &opts)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            paths(&added),
+            &[
+                "",
+                "site",
+                "site/deflated.txt",
+                "site/docs",
+                "site/docs/windows.txt",
+                "site/stored.txt"
+            ]
+        );
+
+        let deflated = added
+            .iter()
+            .find(|e| e.path == "site/deflated.txt")
+            .unwrap();
+        assert!(deflated.total_size >= 10_000);
+    }
+
+    #[test]
+    fn dos_datetime() {
+        // 1980-01-01 00:00:00
+        assert_eq!(dos_datetime_to_unix(33, 0), 315_532_800);
+        // 2020-02-29 12:34:56
+        let date = (40 << 9) | (2 << 5) | 29;
+        let time = (12 << 11) | (34 << 5) | (56 / 2);
+        assert_eq!(dos_datetime_to_unix(date, time), 1_582_979_696);
+    }
+
+    #[test]
+    fn pax_path_record() {
+        let data = b"29 mtime=1595865585.66937645\n14 path=a/b/c\n";
+        let records = pax_records(data).unwrap();
+        assert_eq!(records[1], ("path", &b"a/b/c"[..]));
+    }
+}
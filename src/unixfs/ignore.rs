@@ -0,0 +1,259 @@
+//! `.gitignore` style rules for excluding paths from directory imports.
+//!
+//! Supported syntax:
+//!
+//! * blank lines and lines starting with `#` are skipped
+//! * `!` negates the pattern, re-including a previously excluded path
+//! * trailing `/` makes the pattern match only directories
+//! * patterns without a `/` in the middle or start match the file name at any depth, other
+//!   patterns are matched against the whole path relative to the import root
+//! * `*` matches anything except `/`, `?` matches a single character except `/` and `**` as a
+//!   whole segment matches any number of segments
+//! * `\` escapes the next character
+//!
+//! As with git, the last matching rule decides whether the path is ignored. Excluded directories
+//! are not descended into, so re-including files under them is not possible.
+
+use std::fmt;
+use std::path::Path;
+
+/// Ordered set of ignore rules.
+#[derive(Clone, PartialEq, Eq)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl Default for IgnoreRules {
+    /// Rules which only exclude the `.git` directories.
+    fn default() -> Self {
+        let mut rules = IgnoreRules::empty();
+        rules.add_rule(".git");
+        rules
+    }
+}
+
+impl fmt::Debug for IgnoreRules {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_list()
+            .entries(self.rules.iter().map(|r| &r.original))
+            .finish()
+    }
+}
+
+impl IgnoreRules {
+    /// Rules which will not ignore anything.
+    pub fn empty() -> Self {
+        IgnoreRules { rules: Vec::new() }
+    }
+
+    /// Parses the rules from the contents of an ignore file, like `.gitignore`.
+    pub fn parse(contents: &str) -> Self {
+        let mut rules = IgnoreRules::empty();
+        rules.extend_from_str(contents);
+        rules
+    }
+
+    /// Reads and appends the rules from an ignore file.
+    pub fn extend_from_file(&mut self, path: &Path) -> std::io::Result<()> {
+        let contents = std::fs::read_to_string(path)?;
+        self.extend_from_str(&contents);
+        Ok(())
+    }
+
+    /// Appends the rules from the contents of an ignore file.
+    pub fn extend_from_str(&mut self, contents: &str) {
+        for line in contents.lines() {
+            self.add_rule(line);
+        }
+    }
+
+    /// Appends a single rule. Blank lines and comments are skipped.
+    pub fn add_rule(&mut self, line: &str) {
+        if let Some(rule) = Rule::parse(line) {
+            self.rules.push(rule);
+        }
+    }
+
+    /// Returns true if there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns true if the `/` separated path relative to the import root should be ignored.
+    pub fn is_ignored(&self, relative_path: &str, is_dir: bool) -> bool {
+        let segments = relative_path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+
+        if segments.is_empty() {
+            return false;
+        }
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(&segments, is_dir))
+            .map(|rule| !rule.negated)
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    original: String,
+    segments: Vec<String>,
+    anchored: bool,
+    dir_only: bool,
+    negated: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Rule> {
+        let original = line;
+        // trailing spaces are ignored unless escaped
+        let mut line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+        while line.ends_with(' ') && !line.ends_with("\\ ") {
+            line = &line[..line.len() - 1];
+        }
+
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+
+        // a slash anywhere else than in the end anchors the pattern to the root
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+
+        if line.is_empty() {
+            return None;
+        }
+
+        let segments = line
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect();
+
+        Some(Rule {
+            original: original.to_owned(),
+            segments,
+            anchored,
+            dir_only,
+            negated,
+        })
+    }
+
+    fn matches(&self, path: &[&str], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        if self.anchored {
+            match_segments(&self.segments, path)
+        } else {
+            // unanchored patterns are always single segment
+            let basename = path.last().expect("path is never empty");
+            glob(self.segments[0].as_bytes(), basename.as_bytes())
+        }
+    }
+}
+
+fn match_segments(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skipped| match_segments(rest, &path[skipped..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, remaining)) => {
+                glob(first.as_bytes(), segment.as_bytes()) && match_segments(rest, remaining)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Matches a single path segment against a pattern segment.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skipped| glob(rest, &text[skipped..])),
+        Some((b'?', rest)) => !text.is_empty() && glob(rest, &text[1..]),
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == rest.first() && glob(&rest[1..], &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IgnoreRules;
+
+    #[test]
+    fn default_ignores_git() {
+        let rules = IgnoreRules::default();
+        assert!(rules.is_ignored(".git", true));
+        assert!(rules.is_ignored("nested/.git", true));
+        assert!(!rules.is_ignored(".gitignore", false));
+        assert!(!rules.is_ignored("src/lib.rs", false));
+    }
+
+    #[test]
+    fn basename_patterns_match_at_any_depth() {
+        let rules = IgnoreRules::parse("*.o\ntarget/\n# comment\n\n");
+        assert!(rules.is_ignored("main.o", false));
+        assert!(rules.is_ignored("a/b/c.o", false));
+        assert!(!rules.is_ignored("a/b/c.rs", false));
+        assert!(rules.is_ignored("target", true));
+        assert!(rules.is_ignored("sub/target", true));
+        assert!(!rules.is_ignored("target", false), "dir only pattern");
+    }
+
+    #[test]
+    fn anchored_patterns() {
+        let rules = IgnoreRules::parse("/build\ndocs/*.html");
+        assert!(rules.is_ignored("build", true));
+        assert!(!rules.is_ignored("sub/build", true));
+        assert!(rules.is_ignored("docs/index.html", false));
+        assert!(!rules.is_ignored("docs/api/index.html", false));
+    }
+
+    #[test]
+    fn double_star() {
+        let rules = IgnoreRules::parse("**/node_modules\nlogs/**/*.log");
+        assert!(rules.is_ignored("node_modules", true));
+        assert!(rules.is_ignored("a/b/node_modules", true));
+        assert!(rules.is_ignored("logs/a.log", false));
+        assert!(rules.is_ignored("logs/x/y/a.log", false));
+        assert!(!rules.is_ignored("other/a.log", false));
+    }
+
+    #[test]
+    fn negation_last_rule_wins() {
+        let rules = IgnoreRules::parse("*.txt\n!keep.txt");
+        assert!(rules.is_ignored("drop.txt", false));
+        assert!(!rules.is_ignored("keep.txt", false));
+        assert!(!rules.is_ignored("dir/keep.txt", false));
+    }
+
+    #[test]
+    fn question_mark_and_escapes() {
+        let rules = IgnoreRules::parse("file?.bin\n\\#literal\n\\!bang");
+        assert!(rules.is_ignored("file1.bin", false));
+        assert!(!rules.is_ignored("file10.bin", false));
+        assert!(rules.is_ignored("#literal", false));
+        assert!(rules.is_ignored("!bang", false));
+    }
+}
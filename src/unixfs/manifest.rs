@@ -0,0 +1,78 @@
+//! Checksum manifests of the added files, see [`Manifest`].
+
+use super::add::AddedEntry;
+use cid::Cid;
+use std::fmt::Write;
+
+/// The size and the SHA-256 digest of the contents of an added file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileChecksum {
+    /// The size of the contents in bytes.
+    pub size: u64,
+    /// The SHA-256 digest of the contents.
+    pub sha256: [u8; 32],
+}
+
+/// A file listed in a [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path of the file, starting with the name of the added file or directory.
+    pub path: String,
+    /// The root Cid of the file.
+    pub cid: Cid,
+    pub checksum: FileChecksum,
+}
+
+/// A verification manifest listing the path, the Cid, the size and the SHA-256 digest of the
+/// added files, to be distributed alongside a dataset. The digests allow verifying the files
+/// after they have been downloaded by other means than IPFS.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// The files sorted by their path.
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Creates the manifest of the added files with [`AddedEntry::checksum`], which are computed
+    /// unless [`super::AddOptions::manifest`] is [`super::ManifestPolicy::Skip`].
+    pub fn from_added(added: &[AddedEntry]) -> Self {
+        let mut entries = added
+            .iter()
+            .filter_map(|entry| {
+                entry.checksum.map(|checksum| ManifestEntry {
+                    path: entry.path.clone(),
+                    cid: entry.cid.clone(),
+                    checksum,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        entries.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+        Manifest { entries }
+    }
+
+    /// Encodes the manifest as a JSON array of `{"path", "cid", "size", "sha256"}` objects, with
+    /// the digests in lowercase hex.
+    pub fn to_json(&self) -> Vec<u8> {
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let mut sha256 = String::with_capacity(64);
+                for byte in &entry.checksum.sha256 {
+                    write!(sha256, "{:02x}", byte).expect("writing to a string cannot fail");
+                }
+
+                serde_json::json!({
+                    "path": entry.path,
+                    "cid": entry.cid.to_string(),
+                    "size": entry.checksum.size,
+                    "sha256": sha256,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::to_vec_pretty(&entries).expect("serializing the manifest cannot fail")
+    }
+}
@@ -1,13 +1,35 @@
 //! Adaptation for `ipfs-unixfs` crate functionality on top of [`crate::Ipfs`].
 //!
-//! Adding files and directory structures is supported but not exposed via an API. See examples and
-//! `ipfs-http`.
+//! Files and directory trees can be added from the local filesystem with [`add_path`] and from tar
+//! or zip streams with [`add_archive`]; adding from other sources is supported by the lower level
+//! API, see examples and `ipfs-http`. With the `urlstore` feature, the files served over HTTP can
+//! be added without storing their contents with `add_url`.
 
 pub use ipfs_unixfs as ll;
 
+mod add;
+pub(crate) use add::FileImport;
+pub use add::{add_path, AddError, AddOptions, AddedEntry, ManifestPolicy, SymlinkPolicy};
+
+mod archive;
+pub use archive::{add_archive, ArchiveFormat};
+
 mod cat;
 pub use cat::{cat, StartingPoint, TraversalFailed};
 
+mod ignore;
+pub use ignore::IgnoreRules;
+
+mod manifest;
+pub use manifest::{FileChecksum, Manifest, ManifestEntry};
+
+mod normalize;
+
+#[cfg(feature = "urlstore")]
+mod url;
+#[cfg(feature = "urlstore")]
+pub use url::add_url;
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,119 @@
+//! Normalization of the paths of the imported entries into the relative `/` separated paths used
+//! within the UnixFS directories.
+
+/// How the separators of an entry path are interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Separators {
+    /// Only `/` separates the segments, backslashes are a part of the names.
+    Slash,
+    /// Both `/` and `\` separate the segments, and the path may start with a Windows prefix.
+    Windows,
+}
+
+/// Splits the `name` of an imported entry into the segments of its relative UnixFS path,
+/// dropping the empty and `.` segments. Returns `None` if the path contains a `..` segment.
+///
+/// With [`Separators::Windows`] the prefixes making the path absolute are dropped along with the
+/// leading separators: the drive letters as in `C:\dir`, the UNC shares as in
+/// `\\server\share\dir`, and the verbatim and device prefixes as in `\\?\C:\dir` or
+/// `\\?\UNC\server\share\dir`. Reserved names like `CON` or `NUL` are valid in UnixFS and kept as
+/// is.
+pub(super) fn segments(name: &str, separators: Separators) -> Option<Vec<&str>> {
+    let mut segments = match separators {
+        Separators::Slash => name.split('/').collect::<Vec<_>>(),
+        Separators::Windows => {
+            let mut segments = name
+                .split(|c: char| c == '/' || c == '\\')
+                .collect::<Vec<_>>();
+            let prefix = windows_prefix_len(&segments);
+            if let Some(first) = segments.get_mut(prefix) {
+                // the drive relative paths like `C:dir`
+                *first = strip_drive(*first).unwrap_or(*first);
+            }
+            segments.drain(..prefix);
+            segments
+        }
+    };
+
+    if segments.contains(&"..") {
+        return None;
+    }
+
+    segments.retain(|segment| !segment.is_empty() && *segment != ".");
+    Some(segments)
+}
+
+/// Returns the number of segments making up the Windows prefix of the split path.
+fn windows_prefix_len(segments: &[&str]) -> usize {
+    match segments {
+        // `\\?\UNC\server\share` and `\\.\UNC\server\share`
+        ["", "", "?", unc, ..] | ["", "", ".", unc, ..] if unc.eq_ignore_ascii_case("UNC") => {
+            segments.len().min(6)
+        }
+        // `\\?\C:` and `\\.\C:`
+        ["", "", "?", drive, ..] | ["", "", ".", drive, ..] if is_drive(drive) => 4,
+        // `\\?\Volume{..}` and the other devices
+        ["", "", "?", _, ..] | ["", "", ".", _, ..] => 4,
+        // `\\server\share`
+        ["", "", _, ..] => segments.len().min(4),
+        // `C:\dir`
+        [drive, ..] if is_drive(drive) => 1,
+        _ => 0,
+    }
+}
+
+fn is_drive(segment: &str) -> bool {
+    strip_drive(segment) == Some("")
+}
+
+/// Returns the rest of the `segment` after a leading drive letter and colon.
+fn strip_drive(segment: &str) -> Option<&str> {
+    let bytes = segment.as_bytes();
+    if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        Some(&segment[2..])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{segments, Separators};
+
+    fn windows(name: &str) -> Option<String> {
+        segments(name, Separators::Windows).map(|segments| segments.join("/"))
+    }
+
+    #[test]
+    fn slash_separated() {
+        assert_eq!(
+            segments("./a//b/c\\d.txt", Separators::Slash),
+            Some(vec!["a", "b", "c\\d.txt"])
+        );
+        assert_eq!(segments("c:/a", Separators::Slash), Some(vec!["c:", "a"]));
+        assert_eq!(segments("a/../b", Separators::Slash), None);
+    }
+
+    #[test]
+    fn windows_prefixes_are_dropped() {
+        let cases = [
+            ("dir\\sub\\file.txt", "dir/sub/file.txt"),
+            ("dir/sub\\file.txt", "dir/sub/file.txt"),
+            ("C:\\Users\\me\\file.txt", "Users/me/file.txt"),
+            ("c:/Users/me", "Users/me"),
+            ("C:file.txt", "file.txt"),
+            ("\\dir\\file.txt", "dir/file.txt"),
+            ("\\\\server\\share\\dir\\file.txt", "dir/file.txt"),
+            ("\\\\?\\C:\\dir\\file.txt", "dir/file.txt"),
+            ("\\\\?\\UNC\\server\\share\\dir", "dir"),
+            ("\\\\.\\COM1", ""),
+            ("dir\\CON\\nul.txt", "dir/CON/nul.txt"),
+        ];
+
+        for (name, expected) in cases.iter() {
+            assert_eq!(windows(name).as_deref(), Some(*expected), "{:?}", name);
+        }
+
+        assert_eq!(windows("dir\\..\\..\\file.txt"), None);
+    }
+}
@@ -0,0 +1,51 @@
+//! Adding the files served over HTTP without storing their contents, like `ipfs urlstore add`.
+
+use super::add::{AddError, AddedEntry, FileImport};
+use crate::repo::filestore::{http_client, is_url};
+use crate::repo::FileSource;
+use crate::{Ipfs, IpfsTypes};
+use ipfs_unixfs::Metadata;
+
+/// Adds the file at the `url` by referencing the ranges of the URL in place of the raw leaves,
+/// see [`crate::repo::filestore`]. The file is downloaded once to chunk it, and the leaves are
+/// fetched again with range requests when they are read, so the server needs to support them
+/// and to keep serving the same contents.
+///
+/// The returned entry has the `url` as its path.
+pub async fn add_url<Types: IpfsTypes>(
+    ipfs: &Ipfs<Types>,
+    url: &str,
+) -> Result<AddedEntry, AddError> {
+    let failed = |e: reqwest::Error| AddError::Download(url.to_owned(), e.into());
+
+    if !is_url(url) {
+        return Err(AddError::Download(
+            url.to_owned(),
+            anyhow::anyhow!("only http and https URLs are supported"),
+        ));
+    }
+
+    let mut response = http_client()
+        .map_err(failed)?
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(failed)?;
+
+    let mut import =
+        FileImport::without_copying(Metadata::default(), FileSource::Url(url.to_owned()));
+
+    while let Some(chunk) = response.chunk().await.map_err(failed)? {
+        import.push(ipfs, &chunk).await?;
+    }
+
+    let (cid, total_size) = import.finish(ipfs).await?;
+
+    Ok(AddedEntry {
+        path: url.to_owned(),
+        cid,
+        total_size,
+        checksum: None,
+    })
+}
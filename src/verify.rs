@@ -0,0 +1,312 @@
+//! Checking the integrity of a whole DAG before announcing it.
+//!
+//! [`Ipfs::verify`] walks the DAG under a root from a [`VerifySource`], re-hashes every block and
+//! follows the links of the blocks which hash correctly. The blocks found missing, corrupt or
+//! undecodable are reported with the path through which they were first reached, and their links
+//! are not followed. Every block is read once, even if it is linked from many places.
+
+use crate::car;
+use crate::error::Error;
+use crate::ipld::{decode_ipld, validate};
+use crate::refs::ipld_links;
+use crate::{Ipfs, IpfsTypes};
+use cid::Cid;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Where [`Ipfs::verify`] reads the blocks from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifySource {
+    /// The local repo. The blocks missing from the repo are not fetched from the network.
+    Local,
+    /// A CARv1 or CARv2 file, which is read without importing it.
+    Car(PathBuf),
+    /// A trustless HTTP gateway, like `https://ipfs.io`, from which the blocks are requested one
+    /// at a time as `application/vnd.ipld.raw`.
+    #[cfg(feature = "gateway_client")]
+    Gateway(String),
+}
+
+/// What is wrong with a block reported by [`Ipfs::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyProblem {
+    /// The source does not have the block.
+    Missing,
+    /// The data of the block does not hash to its Cid, or is too large to be a block.
+    Corrupt,
+    /// The block could not be decoded to find its links.
+    Undecodable(String),
+}
+
+/// A block found missing or damaged by [`Ipfs::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedBlock {
+    pub cid: Cid,
+    /// The path through which the block was first reached, starting with the root Cid and
+    /// continuing with the dag-pb link names, or the index of the link for the unnamed links.
+    pub path: String,
+    pub problem: VerifyProblem,
+}
+
+/// Outcome of [`Ipfs::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub root: Cid,
+    /// Number of the blocks found and re-hashed.
+    pub checked: usize,
+    /// Total size of the blocks found.
+    pub bytes: u64,
+    /// The blocks missing or damaged, in the order they were reached.
+    pub damaged: Vec<DamagedBlock>,
+}
+
+impl VerifyReport {
+    /// Returns true if the whole DAG was found intact.
+    pub fn is_ok(&self) -> bool {
+        self.damaged.is_empty()
+    }
+}
+
+impl<Types: IpfsTypes> Ipfs<Types> {
+    /// Checks that the whole DAG under the `root` is available from the `source` and hashes
+    /// correctly, see the [module documentation](crate::verify). Failing to read the source fails
+    /// the whole check, while the problems with the blocks are listed in the report.
+    pub async fn verify(&self, root: &Cid, source: VerifySource) -> Result<VerifyReport, Error> {
+        let mut blocks = match source {
+            VerifySource::Local => Blocks::Local(self),
+            VerifySource::Car(path) => Blocks::car(path).await?,
+            #[cfg(feature = "gateway_client")]
+            VerifySource::Gateway(base) => Blocks::Gateway(gateway::Gateway::new(base)?),
+        };
+
+        let mut report = VerifyReport {
+            root: root.to_owned(),
+            checked: 0,
+            bytes: 0,
+            damaged: Vec::new(),
+        };
+
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        seen.insert(root.to_owned());
+        queue.push_back((root.to_owned(), root.to_string()));
+
+        while let Some((cid, path)) = queue.pop_front() {
+            let problem = match blocks.get(&cid).await? {
+                Some(data) => {
+                    report.checked += 1;
+                    report.bytes += data.len() as u64;
+
+                    match check(&cid, &data) {
+                        Ok(links) => {
+                            for (i, (name, link)) in links.into_iter().enumerate() {
+                                if !seen.insert(link.clone()) {
+                                    continue;
+                                }
+                                let segment = match name {
+                                    Some(name) if !name.is_empty() => name,
+                                    _ => i.to_string(),
+                                };
+                                queue.push_back((link, format!("{}/{}", path, segment)));
+                            }
+                            continue;
+                        }
+                        Err(problem) => problem,
+                    }
+                }
+                None => VerifyProblem::Missing,
+            };
+
+            debug!(cid = %cid, path = %path, ?problem, "damaged block");
+            report.damaged.push(DamagedBlock { cid, path, problem });
+        }
+
+        Ok(report)
+    }
+}
+
+/// Re-hashes the block, returning its links.
+fn check(cid: &Cid, data: &[u8]) -> Result<Vec<(Option<String>, Cid)>, VerifyProblem> {
+    validate(cid, data).map_err(|_| VerifyProblem::Corrupt)?;
+    let ipld = decode_ipld(cid, data).map_err(|e| VerifyProblem::Undecodable(e.to_string()))?;
+    Ok(ipld_links(cid, ipld).collect())
+}
+
+enum Blocks<'a, Types: IpfsTypes> {
+    Local(&'a Ipfs<Types>),
+    Car {
+        file: tokio::fs::File,
+        /// The offsets and the lengths of the data of the blocks, by the multihashes of their Cids.
+        locations: HashMap<Vec<u8>, (u64, usize)>,
+    },
+    #[cfg(feature = "gateway_client")]
+    Gateway(gateway::Gateway),
+}
+
+impl<'a, Types: IpfsTypes> Blocks<'a, Types> {
+    async fn car(path: PathBuf) -> Result<Blocks<'a, Types>, Error> {
+        let file = tokio::fs::File::open(&path).await?;
+        let (_, found) = car::locate_blocks(file).await?;
+
+        let locations = found
+            .into_iter()
+            .map(|block| {
+                (
+                    block.cid.hash().as_bytes().to_vec(),
+                    (block.offset, block.len),
+                )
+            })
+            .collect();
+
+        // opened again, as the first one has been read through a buffer
+        let file = tokio::fs::File::open(&path).await?;
+
+        Ok(Blocks::Car { file, locations })
+    }
+
+    /// Returns the data of the block without verifying it, or `None` if the source does not have
+    /// the block.
+    async fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>, Error> {
+        match self {
+            Blocks::Local(ipfs) => Ok(ipfs.repo.get_block_now(cid).await?.map(|b| b.into_vec())),
+            Blocks::Car { file, locations } => {
+                let (offset, len) = match locations.get(cid.hash().as_bytes()) {
+                    Some(location) => *location,
+                    None => return Ok(None),
+                };
+
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut data = vec![0u8; len];
+                file.read_exact(&mut data).await?;
+                Ok(Some(data))
+            }
+            #[cfg(feature = "gateway_client")]
+            Blocks::Gateway(gateway) => gateway.get(cid).await,
+        }
+    }
+}
+
+#[cfg(feature = "gateway_client")]
+mod gateway {
+    use crate::error::Error;
+    use crate::ipld::MAX_BLOCK_SIZE;
+    use cid::Cid;
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    /// Requests the blocks from a trustless gateway.
+    pub(super) struct Gateway {
+        base: String,
+        client: reqwest::Client,
+    }
+
+    impl Gateway {
+        pub(super) fn new(mut base: String) -> Result<Self, Error> {
+            while base.ends_with('/') {
+                base.pop();
+            }
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(60))
+                .build()?;
+            Ok(Gateway { base, client })
+        }
+
+        pub(super) async fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>, Error> {
+            let url = format!("{}/ipfs/{}?format=raw", self.base, cid);
+            let mut response = self
+                .client
+                .get(&url)
+                .header(reqwest::header::ACCEPT, "application/vnd.ipld.raw")
+                .send()
+                .await?;
+
+            // the gateways time out on the blocks they cannot find
+            match response.status() {
+                StatusCode::NOT_FOUND | StatusCode::GONE | StatusCode::GATEWAY_TIMEOUT => {
+                    return Ok(None)
+                }
+                _ => {}
+            }
+            response = response.error_for_status()?;
+
+            // anything over the block size fails the verification, so the rest is not read
+            let mut data = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                data.extend_from_slice(&chunk);
+                if data.len() > MAX_BLOCK_SIZE {
+                    break;
+                }
+            }
+            Ok(Some(data))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::car::CarExport;
+    use crate::{make_ipld, Node};
+    use futures::stream::TryStreamExt;
+
+    async fn node_with_dag() -> (Node, Cid, Cid) {
+        let ipfs = Node::new("test_node").await;
+
+        let leaf = ipfs.put_dag(make_ipld!("leaf")).await.unwrap();
+        let middle = ipfs
+            .put_dag(make_ipld!({ "leaf": leaf.clone(), "again": leaf.clone() }))
+            .await
+            .unwrap();
+        let root = ipfs.put_dag(make_ipld!([middle.clone(), 1])).await.unwrap();
+
+        (ipfs, root, leaf)
+    }
+
+    #[tokio::test]
+    async fn local_dag_with_missing_block() {
+        let (ipfs, root, leaf) = node_with_dag().await;
+
+        let report = ipfs.verify(&root, VerifySource::Local).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.checked, 3);
+
+        ipfs.remove_block(leaf.clone()).await.unwrap();
+
+        let report = ipfs.verify(&root, VerifySource::Local).await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(
+            report.damaged,
+            vec![DamagedBlock {
+                cid: leaf,
+                path: format!("{}/0/0", root),
+                problem: VerifyProblem::Missing,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupt_block_in_car() {
+        let (ipfs, root, leaf) = node_with_dag().await;
+
+        let mut car: Vec<u8> = CarExport::default()
+            .export(&*ipfs, root.clone())
+            .try_concat()
+            .await
+            .unwrap();
+
+        // the last byte of the leaf, which is the last block of the depth first export
+        *car.last_mut().unwrap() ^= 0xff;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("dag.car");
+        std::fs::write(&path, &car).unwrap();
+
+        let report = ipfs.verify(&root, VerifySource::Car(path)).await.unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].cid, leaf);
+        assert_eq!(report.damaged[0].problem, VerifyProblem::Corrupt);
+    }
+}
@@ -46,3 +46,37 @@ async fn long_get_block() {
     nodes[0].put_block(block.clone()).await.unwrap();
     nodes[N - 1].get_block(&block.cid).await.unwrap();
 }
+
+// the ledgers of both of the nodes account for the exchanged block
+#[tokio::test]
+async fn ledgers_after_exchange() {
+    let nodes = spawn_nodes(2, Topology::Line).await;
+    let block = create_block();
+
+    nodes[0].put_block(block.clone()).await.unwrap();
+    timeout(Duration::from_secs(10), nodes[1].get_block(&block.cid))
+        .await
+        .expect("get_block did not complete in time")
+        .unwrap();
+
+    // the received block is accounted for after it has been stored
+    let received = timeout(Duration::from_secs(10), async {
+        loop {
+            let ledger = nodes[1].bitswap_ledger(nodes[0].id).await.unwrap();
+            if ledger.blocks_received == 1 {
+                break ledger;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the received block was not accounted for in time");
+
+    assert_eq!(received.data_received, block.data.len() as u64);
+    assert_eq!(received.exchanged(), 1);
+
+    let sent = nodes[0].bitswap_ledger(nodes[1].id).await.unwrap();
+    assert_eq!(sent.blocks_sent, 1);
+    assert_eq!(sent.data_sent, block.data.len() as u64);
+    assert!(sent.debt_ratio() > 1.0);
+}
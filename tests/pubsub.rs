@@ -268,6 +268,71 @@ async fn publish_between_two_nodes_different_topics() {
     assert!(disappeared, "timed out before a saw b's unsubscription");
 }
 
+#[tokio::test]
+async fn batched_messages_between_two_nodes() {
+    use ipfs::{IpfsOptions, PubsubBatchConfig};
+
+    let batching = || {
+        let mut opts = IpfsOptions::inmemory_with_generated_keys();
+        opts.pubsub_batching = Some(PubsubBatchConfig::default());
+        Node::with_options(opts)
+    };
+
+    let a = batching().await;
+    let b = batching().await;
+    a.connect(b.addrs[0].clone()).await.unwrap();
+
+    let topic = "telemetry".to_owned();
+    let batches = format!("{}{}", ipfs::PUBSUB_BATCH_PROTOCOL, topic);
+    let mut b_msgs = b.pubsub_subscribe(topic.clone()).await.unwrap();
+
+    // the messages are batched only once b is seen to support the batches
+    let mut appeared = false;
+    for _ in 0..100usize {
+        if a.pubsub_peers(Some(batches.clone()))
+            .await
+            .unwrap()
+            .contains(&b.id)
+        {
+            appeared = true;
+            break;
+        }
+        timeout(Duration::from_millis(100), pending::<()>())
+            .await
+            .unwrap_err();
+    }
+
+    assert!(
+        appeared,
+        "timed out before b appeared as a batch subscriber"
+    );
+
+    let sent = (0..200u32)
+        .map(|i| format!("{{\"reading\":{}}}", i).into_bytes())
+        .collect::<Vec<_>>();
+
+    for data in &sent {
+        a.pubsub_publish(topic.clone(), data.clone()).await.unwrap();
+    }
+
+    let received = timeout(
+        Duration::from_secs(2),
+        b_msgs
+            .by_ref()
+            .take(sent.len())
+            .map(|msg| {
+                assert_eq!(msg.source, a.id);
+                assert_eq!(msg.topics, &[topic.clone()]);
+                msg.data.clone()
+            })
+            .collect::<Vec<_>>(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(received, sent);
+}
+
 #[cfg(any(feature = "test_go_interop", feature = "test_js_interop"))]
 #[tokio::test]
 #[ignore = "doesn't work yet"]
@@ -0,0 +1,77 @@
+use cid::{Cid, Codec};
+use ipfs::{Block, Ipld};
+use multihash::Sha2_256;
+use std::time::Duration;
+use tokio::time::timeout;
+
+mod common;
+use common::{spawn_nodes, Topology};
+
+fn create_block(n: usize) -> Block {
+    let data = format!("sync block {}\n", n)
+        .into_bytes()
+        .into_boxed_slice();
+    let cid = Cid::new_v1(Codec::Raw, Sha2_256::digest(&data));
+
+    Block { cid, data }
+}
+
+// only the blocks missing from the syncing node are listed, and all of them end up local
+#[tokio::test]
+async fn sync_missing_blocks() {
+    let nodes = spawn_nodes(2, Topology::Line).await;
+    let blocks = (0..10).map(create_block).collect::<Vec<_>>();
+
+    for block in &blocks {
+        nodes[0].put_block(block.clone()).await.unwrap();
+    }
+
+    let links = blocks
+        .iter()
+        .map(|block| Ipld::Link(block.cid.clone()))
+        .collect();
+    let root = nodes[0].put_dag(Ipld::List(links)).await.unwrap();
+
+    // the syncing node already has some of the blocks
+    for block in &blocks[..4] {
+        nodes[1].put_block(block.clone()).await.unwrap();
+    }
+
+    let listed = timeout(
+        Duration::from_secs(10),
+        nodes[1].sync_from(nodes[0].id, root.clone()),
+    )
+    .await
+    .expect("sync_from did not complete in time")
+    .unwrap();
+
+    // the root and the 6 missing blocks, unless the filter had a false positive
+    assert!(listed <= 7, "listed {}", listed);
+
+    let found = nodes[1].get_blocks_now(&[root]).await.unwrap();
+    assert!(found[0].is_some());
+
+    let cids = blocks
+        .iter()
+        .map(|block| block.cid.clone())
+        .collect::<Vec<_>>();
+    let found = nodes[1].get_blocks_now(&cids).await.unwrap();
+
+    for (block, found) in blocks.iter().zip(found) {
+        assert_eq!(found.map(|found| found.data), Some(block.data.clone()));
+    }
+}
+
+#[tokio::test]
+async fn sync_unknown_root() {
+    let nodes = spawn_nodes(2, Topology::Line).await;
+    let block = create_block(0);
+
+    timeout(
+        Duration::from_secs(10),
+        nodes[1].sync_from(nodes[0].id, block.cid),
+    )
+    .await
+    .expect("sync_from did not complete in time")
+    .unwrap_err();
+}
@@ -179,6 +179,9 @@ fn walk(blocks: ShardedBlockStore, mut path: IpfsPath) -> Result<Option<Cid>, Er
 
             NotFound => return Ok(None),
 
+            // symlinks are not followed
+            Symlink(_) => return Ok(None),
+
             // when we stumble upon a HAMT shard, we'll need to look up other blocks in order to
             // find the final link. The current implementation cannot search for the directory by
             // hashing the name and looking it up, but the implementation can be changed underneath
@@ -213,6 +216,7 @@ fn walk(blocks: ShardedBlockStore, mut path: IpfsPath) -> Result<Option<Cid>, Er
                     break;
                 }
                 NeedToLoadMore(next) => walker = next,
+                Symlink(_) => unreachable!("continue_walk only returns links from the buckets"),
             }
             other_blocks += 1;
         }
@@ -45,6 +45,10 @@ pub fn resolve<'needle>(
         Ok(flat) if flat.data.Type == UnixFsType::Directory => {
             check_directory_supported(flat)?.links
         }
+        Ok(symlink) if symlink.data.Type == UnixFsType::Symlink => {
+            let target = symlink.data.Data.unwrap_or_default().into_owned();
+            return Ok(MaybeResolved::Symlink(target));
+        }
         Err(ParsingFailed::InvalidUnixFs(_, PBNode { Links: links, .. }))
         | Err(ParsingFailed::NoData(PBNode { Links: links, .. })) => links,
         Ok(other) => {
@@ -76,6 +80,75 @@ pub fn resolve<'needle>(
     }
 }
 
+/// Lists the links of a `dag-pb` or UnixFS directory (normal, sharded) block without loading any
+/// of the linked blocks.
+///
+/// For a HAMT sharded directory the entries are spread over the buckets, which are returned in
+/// [`Listing::buckets`] and need to be listed as well to find all of the entries.
+pub fn list(block: &[u8]) -> Result<Listing, ResolveError> {
+    let (links, sharded) = match FlatUnixFs::try_parse(block) {
+        Ok(hamt) if hamt.data.Type == UnixFsType::HAMTShard => {
+            (check_hamtshard_supported(hamt)?.links, true)
+        }
+        Ok(flat) if flat.data.Type == UnixFsType::Directory => {
+            (check_directory_supported(flat)?.links, false)
+        }
+        Err(ParsingFailed::InvalidUnixFs(_, PBNode { Links: links, .. }))
+        | Err(ParsingFailed::NoData(PBNode { Links: links, .. })) => (links, false),
+        Ok(other) => return Err(ResolveError::UnexpectedType(other.data.Type.into())),
+        Err(ParsingFailed::InvalidDagPb(e)) => return Err(ResolveError::Read(e)),
+    };
+
+    let mut listing = Listing::default();
+
+    for (i, link) in links.into_iter().enumerate() {
+        let name = link.Name.as_deref().unwrap_or_default().to_owned();
+        let total_size = link.Tsize.unwrap_or_default();
+        let cid = try_convert_cid(i, link)?;
+
+        let name = if !sharded {
+            name
+        } else if name.len() == 2 {
+            // the magic number of two comes from the fanout (256), see `ShardedLookup`
+            listing.buckets.push(cid);
+            continue;
+        } else {
+            match name.get(2..) {
+                Some(name) if !name.is_empty() => name.to_owned(),
+                _ => continue,
+            }
+        };
+
+        listing.entries.push(ListedEntry {
+            name,
+            cid,
+            total_size,
+        });
+    }
+
+    Ok(listing)
+}
+
+/// The links of a single directory block returned by [`list`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Listing {
+    /// The entries linked from the block, in the order of the links.
+    pub entries: Vec<ListedEntry>,
+    /// The HAMT buckets linked from the block, in the order of the links.
+    pub buckets: Vec<Cid>,
+}
+
+/// A directory entry returned by [`list`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedEntry {
+    /// The name of the entry, without the bucket prefix of the sharded directories.
+    pub name: String,
+    /// The Cid of the entry.
+    pub cid: Cid,
+    /// The cumulative size of the entry as recorded in the link.
+    pub total_size: u64,
+}
+
 fn try_convert_cid(nth: usize, link: PBLink<'_>) -> Result<Cid, InvalidCidInLink> {
     let hash = link.Hash.as_deref().unwrap_or_default();
     Cid::try_from(hash).map_err(|e| InvalidCidInLink::from((nth, link, e)))
@@ -92,6 +165,10 @@ pub enum MaybeResolved<'needle> {
     NeedToLoadMore(ShardedLookup<'needle>),
     /// The segment could not be found.
     NotFound,
+    /// The block presented to `resolve` was a UnixFS symlink with the contained target path. The
+    /// target is usually relative to the directory of the symlink, and resolving the segment
+    /// needs to continue from the target, if the caller chooses to follow the symlinks.
+    Symlink(Vec<u8>),
 }
 
 /// Resolving can fail similarly as with `ShardedLookup::continue_walk` but in addition to sharded
@@ -212,7 +289,7 @@ impl MultipleMatchingLinks {
 #[cfg(test)]
 mod tests {
 
-    use super::{resolve, MaybeResolved};
+    use super::{list, resolve, MaybeResolved};
     use crate::test_support::FakeBlockstore;
     use cid::Cid;
     use core::convert::TryFrom;
@@ -296,4 +373,72 @@ mod tests {
             "QmRgutAxd8t7oGkSm4wmeuByG6M51wcTso6cubDdQtuEfL"
         );
     }
+
+    #[test]
+    fn list_plain_dagpb() {
+        let payload = hex!("12330a2212206aad27d7e2fc815cd15bf679535062565dc927a831547281fc0af9e5d7e67c74120b6166726963616e2e747874180812340a221220fd36ac5279964db0cba8f7fa45f8c4c44ef5e2ff55da85936a378c96c9c63204120c616d6572696361732e747874180812360a2212207564c20415869d77a8a40ca68a9158e397dd48bdff1325cdb23c5bcd181acd17120e6175737472616c69616e2e7478741808");
+
+        let listing = list(&payload[..]).unwrap();
+        assert!(listing.buckets.is_empty());
+
+        let entries = listing
+            .entries
+            .iter()
+            .map(|e| (e.name.as_str(), e.cid.to_string(), e.total_size))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            entries,
+            &[
+                (
+                    "african.txt",
+                    "QmVX54jfjB8eRxLVxyQSod6b1FyDh7mR4mQie9j97i2Qk3".to_string(),
+                    8
+                ),
+                (
+                    "americas.txt",
+                    "QmfP6D9bRV4FEYDL4EHZtZG58kDwDfnzmyjuyK5d1pvzbM".to_string(),
+                    8
+                ),
+                (
+                    "australian.txt",
+                    "QmWEuXAjUGyndgr4MKqMBgzMW36XgPgvitt2jsXgtuc7JE".to_string(),
+                    8
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn list_sharded_directory() {
+        let blocks = FakeBlockstore::with_fixtures();
+
+        let block = blocks.get_by_str("QmQXUANxYGpkwMTWQUdZBPx9jqfFP7acNgL4FHRWkndKCe");
+
+        let listing = list(block).unwrap();
+        assert!(listing.buckets.is_empty());
+        assert_eq!(listing.entries.len(), 1);
+        assert_eq!(listing.entries[0].name, "non_sharded_dir");
+        assert_eq!(
+            listing.entries[0].cid.to_string(),
+            "QmYmmkD3dGZjuozuqSzDYjU4ZyhAgc4T4P4SUgY6qjzBi8"
+        );
+    }
+
+    #[test]
+    fn list_errors_with_file() {
+        let payload = hex!("0a130802120d666f6f6261720a666f6f626172180d");
+        list(&payload[..]).unwrap_err();
+    }
+
+    #[test]
+    fn resolve_surfaces_symlink_target() {
+        let mut block = Vec::new();
+        crate::symlink::serialize_symlink_block("../b", &mut block);
+
+        match resolve(&block, "anything", &mut None).unwrap() {
+            MaybeResolved::Symlink(target) => assert_eq!(target, b"../b"),
+            x => unreachable!("{:?}", x),
+        }
+    }
 }
@@ -1,11 +1,13 @@
-use cid::Cid;
+use cid::{Cid, Codec, Version};
 use core::fmt;
+use multihash::Code;
+use std::path::PathBuf;
 
 mod dir_builder;
 use dir_builder::DirBuilder;
 
 mod iter;
-pub use iter::{OwnedTreeNode, PostOrderIterator, TreeNode};
+pub use iter::{InvalidCheckpoint, OwnedTreeNode, PostOrderIterator, TreeCheckpoint, TreeNode};
 
 mod buffered;
 pub use buffered::BufferingTreeBuilder;
@@ -13,6 +15,13 @@ pub use buffered::BufferingTreeBuilder;
 mod custom_pb;
 use custom_pb::CustomFlatUnixFs;
 
+mod hamt;
+
+mod spill;
+
+#[cfg(feature = "rayon")]
+mod parallel;
+
 enum Entry {
     Leaf(Leaf),
     Directory(DirBuilder),
@@ -55,6 +64,13 @@ impl fmt::Debug for Leaf {
 pub struct TreeOptions {
     block_size_limit: Option<u64>,
     wrap_with_directory: bool,
+    hamt_sharding_threshold: Option<u64>,
+    cid_version: Version,
+    hash: Code,
+    #[cfg(feature = "rayon")]
+    parallel_rendering: bool,
+    memory_budget: Option<u64>,
+    spill_directory: Option<PathBuf>,
 }
 
 impl Default for TreeOptions {
@@ -63,6 +79,14 @@ impl Default for TreeOptions {
             // this is just a guess; our bitswap message limit is a bit more
             block_size_limit: Some(512 * 1024),
             wrap_with_directory: false,
+            // same as go-ipfs
+            hamt_sharding_threshold: Some(256 * 1024),
+            cid_version: Version::V0,
+            hash: Code::Sha2_256,
+            #[cfg(feature = "rayon")]
+            parallel_rendering: false,
+            memory_budget: None,
+            spill_directory: None,
         }
     }
 }
@@ -79,6 +103,63 @@ impl TreeOptions {
     pub fn wrap_with_directory(&mut self) {
         self.wrap_with_directory = true;
     }
+
+    /// Overrides the default threshold for HAMT sharding the directories. Once the estimated size
+    /// of a directory, the sum of the lengths of its link names and Cids, reaches the threshold,
+    /// the directory is created as HAMT buckets like go-ipfs does. If the threshold is set to
+    /// `None`, directories are never sharded. Defaults to 256 KiB.
+    pub fn hamt_sharding_threshold(&mut self, threshold: Option<u64>) {
+        self.hamt_sharding_threshold = threshold;
+    }
+
+    /// Overrides the version of the Cids of the created directories, like the `--cid-version`
+    /// option of `ipfs add`. Defaults to `Version::V0`.
+    pub fn cid_version(&mut self, version: Version) {
+        self.cid_version = version;
+    }
+
+    /// Overrides the hash function used for the Cids of the created directories, like the
+    /// `--hash` option of `ipfs add`. As `Version::V0` only supports sha2-256, any other hash
+    /// function implies `Version::V1`. Defaults to `Code::Sha2_256`.
+    pub fn hash(&mut self, hash: Code) {
+        self.hash = hash;
+    }
+
+    /// When called, the sibling directories are serialized and hashed concurrently on the rayon
+    /// thread pool. The whole tree is then rendered on the first call to
+    /// [`PostOrderIterator::next`] and kept in memory, but the nodes are returned in the same
+    /// order as without this. Defaults to false.
+    #[cfg(feature = "rayon")]
+    pub fn parallel_rendering(&mut self) {
+        self.parallel_rendering = true;
+    }
+
+    /// Limits the estimated memory used by the links buffered by the [`BufferingTreeBuilder`].
+    /// Once the links take more, they are moved into a temporary file, and read back a
+    /// directory at a time by the [`PostOrderIterator`], so that the largest directory needs to
+    /// fit in memory instead of the whole tree. The directories themselves are kept in memory.
+    /// With [`TreeOptions::parallel_rendering`] the whole tree is read back at once. Defaults to
+    /// `None`, keeping all of the links in memory.
+    pub fn memory_budget(&mut self, budget: Option<u64>) {
+        self.memory_budget = budget;
+    }
+
+    /// Overrides the directory of the temporary file of the links moved out of the memory, see
+    /// [`TreeOptions::memory_budget`]. Defaults to the temporary directory of the system.
+    pub fn spill_directory(&mut self, dir: PathBuf) {
+        self.spill_directory = Some(dir);
+    }
+
+    /// Returns the Cid of the dag-pb `block` with the configured version and hash function.
+    fn cid_of(&self, block: &[u8]) -> Cid {
+        let mh = self.hash.digest(block);
+
+        if self.cid_version == Version::V0 && self.hash == Code::Sha2_256 {
+            Cid::new_v0(mh).expect("sha2_256 is the correct multihash for cidv0")
+        } else {
+            Cid::new_v1(Codec::DagProtobuf, mh)
+        }
+    }
 }
 
 /// Tree building failure cases.
@@ -97,6 +178,8 @@ pub enum TreeBuildingFailed {
     DuplicatePath(String),
     /// The given full path had already been added as a link to an opaque entry.
     LeafAsDirectory(String),
+    /// Moving the links out of the memory failed, see [`TreeOptions::memory_budget`].
+    Spill(std::io::Error),
 }
 
 impl fmt::Display for TreeBuildingFailed {
@@ -118,6 +201,7 @@ impl fmt::Display for TreeBuildingFailed {
                 "attempted to use already added leaf as a subdirectory: {:?}",
                 s
             ),
+            Spill(e) => write!(fmt, "failed to spill the links to the disk: {}", e),
         }
     }
 }
@@ -129,9 +213,15 @@ impl std::error::Error for TreeBuildingFailed {}
 pub enum TreeConstructionFailed {
     /// Failed to serialize the protobuf node for the directory
     Protobuf(quick_protobuf::Error),
-    /// The resulting directory would be too large and HAMT sharding is yet to be implemented or
-    /// denied.
+    /// The resulting directory or HAMT bucket would be too large, and HAMT sharding was disabled
+    /// or configured with a too large threshold.
     TooLargeBlock(u64),
+    /// Reading the links moved out of the memory back failed, see
+    /// [`TreeOptions::memory_budget`].
+    Spill(std::io::Error),
+    /// The given full path had been added already, which is only found out for the links moved
+    /// out of the memory once they are read back.
+    DuplicatePath(String),
 }
 
 impl fmt::Display for TreeConstructionFailed {
@@ -141,6 +231,8 @@ impl fmt::Display for TreeConstructionFailed {
         match self {
             Protobuf(e) => write!(fmt, "serialization failed: {}", e),
             TooLargeBlock(size) => write!(fmt, "attempted to create block of {} bytes", size),
+            Spill(e) => write!(fmt, "failed to read the spilled links: {}", e),
+            DuplicatePath(s) => write!(fmt, "path exists already: {:?}", s),
         }
     }
 }
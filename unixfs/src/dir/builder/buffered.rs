@@ -1,4 +1,6 @@
+use super::spill::{self, Spill};
 use super::{DirBuilder, Entry, Leaf, PostOrderIterator, TreeBuildingFailed, TreeOptions};
+use crate::symlink::serialize_symlink_block;
 use crate::Metadata;
 use alloc::collections::btree_map::Entry::*;
 use cid::Cid;
@@ -14,6 +16,10 @@ pub struct BufferingTreeBuilder {
     // recover all children's rendered Cids
     counter: u64,
     opts: TreeOptions,
+    /// The estimated memory used by the links not yet spilled, see
+    /// [`TreeOptions::memory_budget`].
+    buffered: u64,
+    spill: Option<Spill>,
 }
 
 impl Default for BufferingTreeBuilder {
@@ -30,6 +36,8 @@ impl BufferingTreeBuilder {
             longest_path: 0,
             counter: 1,
             opts,
+            buffered: 0,
+            spill: None,
         }
     }
 
@@ -47,11 +55,61 @@ impl BufferingTreeBuilder {
             total_size,
         };
 
+        let basename = full_path.rsplit('/').next().unwrap_or(full_path);
+        let size = spill::estimated_size(basename, &leaf);
+
         self.modify_with(full_path, |parent, basename, _| {
             parent
                 .put_leaf(basename, leaf)
                 .map_err(|_| TreeBuildingFailed::DuplicatePath(full_path.to_string()))
-        })
+        })?;
+
+        self.buffered += size;
+        self.spill_over_budget()
+    }
+
+    /// Moves all of the buffered links into the spill file, if they take more memory than the
+    /// [`TreeOptions::memory_budget`].
+    fn spill_over_budget(&mut self) -> Result<(), TreeBuildingFailed> {
+        match self.opts.memory_budget {
+            Some(budget) if self.buffered > budget => {}
+            _ => return Ok(()),
+        }
+
+        if self.spill.is_none() {
+            let dir = self
+                .opts
+                .spill_directory
+                .clone()
+                .unwrap_or_else(std::env::temp_dir);
+            self.spill = Some(Spill::create(&dir).map_err(TreeBuildingFailed::Spill)?);
+        }
+
+        self.spill
+            .as_mut()
+            .expect("spill file was created above")
+            .spill(&mut self.root_builder)
+            .map_err(TreeBuildingFailed::Spill)?;
+        self.buffered = 0;
+
+        Ok(())
+    }
+
+    /// Registers the given path to be a UnixFS symlink to the `target`, which is stored as is and
+    /// interpreted relative to the directory of the symlink by the readers. Returns the Cid and the
+    /// symlink block, which needs to be stored along with the blocks of the directories.
+    pub fn put_symlink(
+        &mut self,
+        full_path: &str,
+        target: &str,
+    ) -> Result<(Cid, Vec<u8>), TreeBuildingFailed> {
+        let mut block = Vec::new();
+        serialize_symlink_block(target, &mut block);
+
+        let cid = self.opts.cid_of(&block);
+        self.put_link(full_path, cid.clone(), block.len() as u64)?;
+
+        Ok((cid, block))
     }
 
     /// Directories get "put" implicitly through the put files, and directories need to be adjusted
@@ -179,14 +237,15 @@ impl BufferingTreeBuilder {
     /// its data during the walk. `PostOrderIterator` implements `Iterator` while also allowing
     /// borrowed access via `next_borrowed`.
     pub fn build(self) -> PostOrderIterator {
-        PostOrderIterator::new(self.root_builder, self.opts, self.longest_path)
+        PostOrderIterator::new(self.root_builder, self.opts, self.longest_path, self.spill)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
-        super::OwnedTreeNode, BufferingTreeBuilder, Metadata, TreeBuildingFailed, TreeOptions,
+        super::{OwnedTreeNode, TreeConstructionFailed},
+        BufferingTreeBuilder, Metadata, TreeBuildingFailed, TreeOptions,
     };
     use cid::Cid;
     use core::convert::TryFrom;
@@ -376,6 +435,96 @@ mod tests {
         );
     }
 
+    #[test]
+    fn metadata_is_stored_in_the_directories() {
+        use crate::pb::FlatUnixFs;
+
+        let metadata = Metadata::new(Some(0o755), Some((1_600_000_000, 0)));
+
+        for threshold in &[None, Some(0)] {
+            let mut opts = TreeOptions::default();
+            opts.hamt_sharding_threshold(*threshold);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.set_metadata("a/b", metadata.clone()).unwrap();
+            builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+
+            let nodes = builder.build().collect::<Result<Vec<_>, _>>().unwrap();
+            let paths = nodes.iter().map(|n| n.path.as_str()).collect::<Vec<_>>();
+            assert_eq!(paths, &["a/b", "a"]);
+
+            assert_eq!(nodes[0].metadata, metadata);
+            assert!(nodes[1].metadata.is_empty());
+
+            let flat = FlatUnixFs::try_from(nodes[0].block.as_ref()).unwrap();
+            assert_eq!(flat.data.mode, Some(0o755));
+            let mtime = flat.data.mtime.as_ref().unwrap();
+            assert_eq!(
+                (mtime.Seconds, mtime.FractionalNanoseconds),
+                (1_600_000_000, None)
+            );
+
+            let flat = FlatUnixFs::try_from(nodes[1].block.as_ref()).unwrap();
+            assert_eq!((flat.data.mode, flat.data.mtime), (None, None));
+        }
+    }
+
+    #[test]
+    fn put_symlink() {
+        let mut builder = BufferingTreeBuilder::default();
+        // same as in `symlink::tests::simple_symlink`
+        let (cid, block) = builder.put_symlink("a/a", "b").unwrap();
+        assert_eq!(
+            cid.to_string(),
+            "QmfLJN6HLyREnWr7QQNmgmuNziUhcbwUopkHQ8gD3pMfp6"
+        );
+        assert_eq!(block.len(), 7);
+
+        let err = builder.put_symlink("a/a", "c").unwrap_err();
+        assert!(
+            matches!(err, TreeBuildingFailed::DuplicatePath(_)),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn parallel_rendering_matches_serial() {
+        let metadata = Metadata::new(Some(0o755), None);
+
+        for threshold in &[None, Some(100)] {
+            let build = |parallel: bool| {
+                let mut opts = TreeOptions::default();
+                opts.wrap_with_directory();
+                opts.hamt_sharding_threshold(*threshold);
+                if parallel {
+                    opts.parallel_rendering();
+                }
+
+                let mut builder = BufferingTreeBuilder::new(opts);
+                builder.set_metadata("a/1", metadata.clone()).unwrap();
+
+                for i in 0..50 {
+                    let path = format!("{}/{}/{}.txt", ["a", "b", "c"][i % 3], i % 7, i);
+                    builder.put_link(&path, some_cid(i), 1).unwrap();
+                }
+
+                let mut iter = builder.build();
+                let mut nodes = Vec::new();
+                while let Some(node) = iter.next_borrowed() {
+                    let node = node.unwrap().into_owned();
+                    nodes.push((node.path, node.cid, node.block, node.metadata, node.bucket));
+                }
+                nodes
+            };
+
+            let serial = build(false);
+            assert_eq!(serial.iter().any(|node| node.4), threshold.is_some());
+            assert_eq!(build(true), serial);
+        }
+    }
+
     #[test]
     fn dir_with_cidv1_link() {
         // this is `echo '{ "name": "hello" }` | ./ipfs dag put`
@@ -396,6 +545,95 @@ mod tests {
         verify_results(expected, actual);
     }
 
+    #[test]
+    fn cid_version_and_hash() {
+        use cid::{Codec, Version};
+        use multihash::Code;
+
+        let build = |version, hash| {
+            let mut opts = TreeOptions::default();
+            opts.cid_version(version);
+            opts.hash(hash);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+
+            let mut nodes = builder.build().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(nodes.len(), 1);
+            nodes.pop().unwrap()
+        };
+
+        let v0 = build(Version::V0, Code::Sha2_256);
+        assert_eq!(v0.cid.version(), Version::V0);
+
+        let v1 = build(Version::V1, Code::Sha2_256);
+        assert_eq!(v1.cid.version(), Version::V1);
+        assert_eq!(v1.cid.codec(), Codec::DagProtobuf);
+        assert_eq!(v1.cid.hash().as_bytes(), v0.cid.hash().as_bytes());
+        assert_eq!(v1.block, v0.block);
+
+        for hash in &[Code::Sha2_512, Code::Blake2b256, Code::Blake3] {
+            // only sha2-256 can be used with cidv0
+            let node = build(Version::V0, *hash);
+            assert_eq!(node.cid.version(), Version::V1);
+            assert_eq!(
+                node.cid.hash().as_bytes(),
+                hash.digest(&node.block).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn spilled_links_match_buffered() {
+        for threshold in &[None, Some(100)] {
+            let build = |budget: Option<u64>| {
+                let mut opts = TreeOptions::default();
+                opts.wrap_with_directory();
+                opts.hamt_sharding_threshold(*threshold);
+                opts.memory_budget(budget);
+
+                let mut builder = BufferingTreeBuilder::new(opts);
+
+                for i in 0..50 {
+                    let path = format!("{}/{}/{}.txt", ["a", "b", "c"][i % 3], i % 7, i);
+                    builder.put_link(&path, some_cid(i), 1).unwrap();
+                }
+
+                let mut iter = builder.build();
+                let mut nodes = Vec::new();
+                while let Some(node) = iter.next_borrowed() {
+                    let node = node.unwrap().into_owned();
+                    nodes.push((node.path, node.cid, node.block, node.bucket));
+                }
+                nodes
+            };
+
+            let buffered = build(None);
+            assert_eq!(buffered.iter().any(|node| node.3), threshold.is_some());
+            // spilled after every link
+            assert_eq!(build(Some(1)), buffered);
+            assert_eq!(build(Some(1000)), buffered);
+        }
+    }
+
+    #[test]
+    fn spilled_duplicate_path() {
+        let mut opts = TreeOptions::default();
+        opts.memory_budget(Some(1));
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+        // the first link has already been spilled so this is only noticed on build
+        builder.put_link("a/b/c.txt", some_cid(1), 1).unwrap();
+
+        let err = builder.build().collect::<Result<Vec<_>, _>>().unwrap_err();
+
+        match err {
+            TreeConstructionFailed::DuplicatePath(path) => assert_eq!(path, "a/b/c.txt"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
     fn verify_results(
         mut expected: Vec<(
             impl AsRef<str> + core::fmt::Debug,
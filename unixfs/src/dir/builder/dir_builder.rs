@@ -1,3 +1,4 @@
+use super::spill::Run;
 use super::{Entry, Leaf};
 use crate::Metadata;
 use alloc::collections::btree_map::Entry::*;
@@ -12,11 +13,13 @@ pub(super) struct DirBuilder {
     /// Immediate files, symlinks or directories in this directory
     pub nodes: BTreeMap<String, Entry>,
     /// Metadata for this directory
-    metadata: Metadata,
+    pub metadata: Metadata,
     /// Id of the parent; None for the root node
     pub parent_id: Option<u64>,
     /// Internal id, used for propagating Cids back from children during post order visit.
     pub id: u64,
+    /// The links of the files moved out of the memory, see [`super::TreeOptions::memory_budget`].
+    pub spilled: Vec<Run>,
 }
 
 impl DirBuilder {
@@ -27,6 +30,7 @@ impl DirBuilder {
             metadata: Default::default(),
             parent_id: Some(parent_id),
             id,
+            spilled: Vec::new(),
         }
     }
 
@@ -36,6 +40,7 @@ impl DirBuilder {
             metadata: Default::default(),
             parent_id: None,
             id,
+            spilled: Vec::new(),
         }
     }
 
@@ -65,7 +70,7 @@ impl DirBuilder {
     }
 
     pub fn len(&self) -> usize {
-        self.nodes.len()
+        self.nodes.len() + self.spilled.iter().map(Run::count).sum::<usize>()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -0,0 +1,313 @@
+//! HAMT sharding of the directories too large for a single block, producing the same `HAMTShard`
+//! buckets as go-ipfs does.
+//!
+//! The entries are bucketed by the murmur3-x64-64 hash of their names, eight bits of the hash at a
+//! time. A slot holding a single entry links to it with the slot index as a two character
+//! uppercase hex prefix of the entry name, while a slot shared by multiple entries links to a
+//! nested bucket with only the slot index as the name.
+
+use super::iter::render_node;
+use super::{Leaf, NamedLeaf, TreeConstructionFailed, TreeOptions};
+use crate::pb::{UnixFs, UnixFsType};
+use crate::Metadata;
+use alloc::borrow::Cow;
+use core::convert::TryInto;
+
+/// The fanout of the buckets; the only fanout supported by `ShardedLookup`.
+const FANOUT: u64 = 256;
+
+/// The multicodec of the murmur3-x64-64 hash used to bucket the entries.
+const MURMUR3_X64_64: u64 = 0x22;
+
+/// The number of nested buckets the 64 bits of the hash allow.
+const MAX_DEPTH: usize = 8;
+
+/// Returns true if the directory of the `links` should be sharded with the given threshold.
+///
+/// Like in go-ipfs, the size of the directory is estimated as the sum of the lengths of the link
+/// names and the Cids.
+pub(super) fn needs_sharding(links: &[Option<NamedLeaf>], threshold: Option<u64>) -> bool {
+    let threshold = match threshold {
+        Some(threshold) => threshold,
+        None => return false,
+    };
+
+    let estimated = links
+        .iter()
+        .filter_map(Option::as_ref)
+        .map(|NamedLeaf(name, cid, _)| (name.len() + cid.to_bytes().len()) as u64)
+        .sum::<u64>();
+
+    estimated >= threshold
+}
+
+/// Renders the HAMT buckets of the directory of the `links` in post order, the root bucket being
+/// the last.
+pub(super) fn render(
+    links: &[Option<NamedLeaf>],
+    metadata: &Metadata,
+    opts: &TreeOptions,
+) -> Result<Vec<(Leaf, Vec<u8>)>, TreeConstructionFailed> {
+    let mut entries = links
+        .iter()
+        .map(|link| {
+            let link = link.as_ref().expect("all links have been rendered");
+            (murmur3_x64_64(link.0.as_bytes()).to_be_bytes(), link)
+        })
+        .collect::<Vec<_>>();
+
+    entries.sort_unstable_by(|a, b| a.0.cmp(&b.0).then_with(|| (a.1).0.cmp(&(b.1).0)));
+
+    let mut buckets = Vec::new();
+    render_bucket(&entries, 0, metadata, opts, &mut buckets)?;
+    Ok(buckets)
+}
+
+/// Renders the bucket of the `entries` sorted by their hash, after the nested buckets. Only the
+/// root bucket carries the `metadata` of the directory.
+fn render_bucket(
+    entries: &[([u8; 8], &NamedLeaf)],
+    depth: usize,
+    metadata: &Metadata,
+    opts: &TreeOptions,
+    buckets: &mut Vec<(Leaf, Vec<u8>)>,
+) -> Result<Leaf, TreeConstructionFailed> {
+    let mut links = Vec::new();
+    let mut bitfield = [0u8; FANOUT as usize / 8];
+    let mut rest = entries;
+
+    while let Some((hash, _)) = rest.first() {
+        let index = hash[depth];
+        let len = rest.iter().take_while(|(h, _)| h[depth] == index).count();
+        let (slot, tail) = rest.split_at(len);
+        rest = tail;
+
+        // same layout as go-bitfield: the highest indices are in the first byte
+        bitfield[bitfield.len() - 1 - index as usize / 8] |= 1 << (index % 8);
+
+        if slot.len() == 1 || depth + 1 == MAX_DEPTH {
+            // the full hashes can only collide in the last bucket, where the entries share a slot
+            links.extend(slot.iter().map(|(_, NamedLeaf(name, cid, total_size))| {
+                Some(NamedLeaf(
+                    format!("{:02X}{}", index, name),
+                    cid.clone(),
+                    *total_size,
+                ))
+            }));
+        } else {
+            let nested = render_bucket(slot, depth + 1, &Metadata::default(), opts, buckets)?;
+            links.push(Some(NamedLeaf(
+                format!("{:02X}", index),
+                nested.link,
+                nested.total_size,
+            )));
+        }
+    }
+
+    // go-bitfield leaves out the leading zero bytes
+    let first_set = bitfield
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(bitfield.len());
+
+    let mut data = UnixFs {
+        Type: UnixFsType::HAMTShard,
+        Data: Some(&bitfield[first_set..])
+            .filter(|bits| !bits.is_empty())
+            .map(Cow::Borrowed),
+        hashType: Some(MURMUR3_X64_64),
+        fanout: Some(FANOUT),
+        ..Default::default()
+    };
+    metadata.write_to(&mut data);
+
+    let mut block = Vec::new();
+    let leaf = render_node(&links, data, &mut block, opts)?;
+
+    buckets.push((
+        Leaf {
+            link: leaf.link.clone(),
+            total_size: leaf.total_size,
+        },
+        block,
+    ));
+
+    Ok(leaf)
+}
+
+/// The first 64 bits of the x64 variant of the 128-bit murmur3 with zero seed, as used by
+/// go-ipfs.
+fn murmur3_x64_64(data: &[u8]) -> u64 {
+    const C1: u64 = 0x87c3_7b91_1142_53d5;
+    const C2: u64 = 0x4cf5_ad43_2745_937f;
+
+    fn fmix64(mut k: u64) -> u64 {
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        k ^= k >> 33;
+        k
+    }
+
+    let (mut h1, mut h2) = (0u64, 0u64);
+
+    let mut chunks = data.chunks_exact(16);
+
+    for chunk in &mut chunks {
+        let k1 = u64::from_le_bytes(chunk[..8].try_into().unwrap());
+        let k2 = u64::from_le_bytes(chunk[8..].try_into().unwrap());
+
+        h1 ^= k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+        h1 = h1
+            .rotate_left(27)
+            .wrapping_add(h2)
+            .wrapping_mul(5)
+            .wrapping_add(0x52dc_e729);
+
+        h2 ^= k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+        h2 = h2
+            .rotate_left(31)
+            .wrapping_add(h1)
+            .wrapping_mul(5)
+            .wrapping_add(0x3849_5ab5);
+    }
+
+    let tail = chunks.remainder();
+
+    if tail.len() > 8 {
+        let k2 = tail[8..]
+            .iter()
+            .rev()
+            .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+        h2 ^= k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+    }
+
+    if !tail.is_empty() {
+        let k1 = tail[..tail.len().min(8)]
+            .iter()
+            .rev()
+            .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+        h1 ^= k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+    }
+
+    h1 ^= data.len() as u64;
+    h2 ^= data.len() as u64;
+
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+
+    h1 = fmix64(h1);
+    h2 = fmix64(h2);
+
+    h1.wrapping_add(h2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::murmur3_x64_64;
+    use crate::dir::builder::{BufferingTreeBuilder, OwnedTreeNode, TreeOptions};
+    use crate::pb::{FlatUnixFs, UnixFsType};
+    use crate::{resolve, MaybeResolved};
+    use cid::Cid;
+    use core::convert::TryFrom;
+    use std::collections::HashMap;
+
+    #[test]
+    fn murmur3() {
+        let cases: &[(&str, u64)] = &[
+            ("", 0),
+            ("a", 0x8555_5565_f659_7889),
+            ("hello", 0xcbd8_a7b3_41bd_9b02),
+            ("0123456789abcdef0", 0xeb24_ae87_85a5_c075),
+            ("hello world, this is a longer input", 0xd442_ffb1_cafa_af1a),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(murmur3_x64_64(input.as_bytes()), *expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn large_directory_is_sharded() {
+        let nodes = build(1000, Some(1024));
+
+        let (root, buckets) = nodes.split_last().unwrap();
+        assert!(!root.bucket);
+        assert!(!buckets.is_empty());
+        assert!(buckets
+            .iter()
+            .all(|node| node.bucket && node.path.is_empty()));
+
+        let parsed = FlatUnixFs::try_from(&root.block[..]).unwrap();
+        assert_eq!(parsed.data.Type, UnixFsType::HAMTShard);
+        assert_eq!(parsed.data.fanout, Some(256));
+        assert_eq!(parsed.data.hashType, Some(0x22));
+
+        let blocks = nodes
+            .iter()
+            .map(|node| (node.cid.clone(), &node.block[..]))
+            .collect::<HashMap<_, _>>();
+
+        for i in 0..1000 {
+            let name = format!("file-{}.txt", i);
+            let mut cache = None;
+            let mut resolved = resolve(&root.block, &name, &mut cache).unwrap();
+
+            let found = loop {
+                match resolved {
+                    MaybeResolved::Found(cid) => break cid,
+                    MaybeResolved::NeedToLoadMore(lookup) => {
+                        let next = blocks[lookup.pending_links().0];
+                        resolved = lookup.continue_walk(next, &mut cache).unwrap();
+                    }
+                    MaybeResolved::NotFound => panic!("{} not found", name),
+                    MaybeResolved::Symlink(_) => panic!("{} is not a symlink", name),
+                }
+            };
+
+            assert_eq!(found, some_cid(i));
+        }
+
+        let total_size = nodes
+            .iter()
+            .map(|node| node.block.len() as u64)
+            .sum::<u64>()
+            + 1000 * 10;
+        assert_eq!(root.total_size, total_size);
+    }
+
+    #[test]
+    fn small_directory_is_not_sharded() {
+        for threshold in &[None, TreeOptions::default().hamt_sharding_threshold] {
+            let nodes = build(1000, *threshold);
+            assert_eq!(nodes.len(), 1);
+
+            let parsed = FlatUnixFs::try_from(&nodes[0].block[..]).unwrap();
+            assert_eq!(parsed.data.Type, UnixFsType::Directory);
+        }
+    }
+
+    fn build(entries: usize, threshold: Option<u64>) -> Vec<OwnedTreeNode> {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        opts.hamt_sharding_threshold(threshold);
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        for i in 0..entries {
+            builder
+                .put_link(&format!("file-{}.txt", i), some_cid(i), 10)
+                .unwrap();
+        }
+
+        builder.build().collect::<Result<Vec<_>, _>>().unwrap()
+    }
+
+    /// Returns a quick and dirty sha2-256 of the given number as a Cidv0
+    fn some_cid(number: usize) -> Cid {
+        use multihash::Sha2_256;
+        let mh = Sha2_256::digest(&number.to_le_bytes());
+        Cid::new_v0(mh).unwrap()
+    }
+}
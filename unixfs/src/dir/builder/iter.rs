@@ -1,10 +1,16 @@
+use super::spill::Spill;
 use super::{
-    CustomFlatUnixFs, DirBuilder, Entry, Leaf, NamedLeaf, TreeConstructionFailed, TreeOptions,
+    hamt, CustomFlatUnixFs, DirBuilder, Entry, Leaf, NamedLeaf, TreeConstructionFailed, TreeOptions,
 };
+use crate::pb::{UnixFs, UnixFsType};
+use crate::Metadata;
 use cid::Cid;
 use core::fmt;
 use std::collections::HashMap;
 
+mod checkpoint;
+pub use checkpoint::{InvalidCheckpoint, TreeCheckpoint};
+
 /// Constructs the directory nodes required for a tree.
 ///
 /// Implements the Iterator interface for owned values and the borrowed version, `next_borrowed`.
@@ -21,8 +27,19 @@ pub struct PostOrderIterator {
     reused_children: Vec<Visited>,
     cid: Option<Cid>,
     total_size: u64,
+    // metadata of the latest rendered directory
+    metadata: Metadata,
+    // the rendered HAMT buckets of a sharded directory in reverse order, the root bucket first
+    buckets: Vec<(Leaf, Vec<u8>)>,
+    // the nodes rendered with TreeOptions::parallel_rendering and the latest one of them
+    #[cfg(feature = "rayon")]
+    rendered: Option<std::vec::IntoIter<OwnedTreeNode>>,
+    #[cfg(feature = "rayon")]
+    current: Option<OwnedTreeNode>,
     // from TreeOptions
     opts: TreeOptions,
+    // the links spilled over TreeOptions::memory_budget, read back when descending
+    spill: Option<Spill>,
 }
 
 /// The link list used to create the directory node. This list is created from a the BTreeMap
@@ -55,14 +72,21 @@ enum Visited {
         /// Leaves will be stored directly in this field when there are no DirBuilder descendants,
         /// in the `PostOrderIterator::persisted_cids` otherwise.
         leaves: LeafStorage,
+        metadata: Metadata,
     },
     PostRoot {
         leaves: LeafStorage,
+        metadata: Metadata,
     },
 }
 
 impl PostOrderIterator {
-    pub(super) fn new(root: DirBuilder, opts: TreeOptions, longest_path: usize) -> Self {
+    pub(super) fn new(
+        root: DirBuilder,
+        opts: TreeOptions,
+        longest_path: usize,
+        spill: Option<Spill>,
+    ) -> Self {
         let root = Visited::DescentRoot(root);
         PostOrderIterator {
             full_path: String::with_capacity(longest_path),
@@ -73,96 +97,110 @@ impl PostOrderIterator {
             reused_children: Vec::new(),
             cid: None,
             total_size: 0,
+            metadata: Metadata::default(),
+            buckets: Vec::new(),
+            #[cfg(feature = "rayon")]
+            rendered: None,
+            #[cfg(feature = "rayon")]
+            current: None,
             opts,
+            spill,
         }
     }
 
-    fn render_directory(
+    /// Reads the spilled links of the `node` back, if any were spilled.
+    fn restore(&self, node: &mut DirBuilder) -> Result<(), TreeConstructionFailed> {
+        match &self.spill {
+            Some(spill) => spill.restore(node, &self.full_path),
+            None => Ok(()),
+        }
+    }
+
+    /// Renders the directory of the `links` into `block_buffer`, or if it needs to be sharded,
+    /// the HAMT buckets into `buckets`. Returns the link to the directory.
+    fn render(
+        &mut self,
         links: &[Option<NamedLeaf>],
-        buffer: &mut Vec<u8>,
-        block_size_limit: &Option<u64>,
+        metadata: Metadata,
     ) -> Result<Leaf, TreeConstructionFailed> {
-        use crate::pb::{UnixFs, UnixFsType};
-        use quick_protobuf::{BytesWriter, MessageWrite, Writer};
-        use sha2::{Digest, Sha256};
-
-        // FIXME: ideas on how to turn this into a HAMT sharding on some heuristic. we probably
-        // need to introduce states in to the "iterator":
-        //
-        // 1. bucketization
-        // 2. another post order visit of the buckets?
-        //
-        // the nested post order visit should probably re-use the existing infra ("message
-        // passing") and new ids can be generated by giving this iterator the counter from
-        // BufferedTreeBuilder.
-        //
-        // could also be that the HAMT shard building should start earlier, since the same
-        // heuristic can be detected *at* bufferedtreewriter. there the split would be easier, and
-        // this would "just" be a single node rendering, and not need any additional states..
-
-        let node = CustomFlatUnixFs {
-            links,
-            data: UnixFs {
-                Type: UnixFsType::Directory,
-                ..Default::default()
-            },
-        };
+        self.metadata = metadata;
 
-        let size = node.get_size();
+        let (leaf, mut buckets) =
+            render_directory(links, &self.metadata, &self.opts, &mut self.block_buffer)?;
 
-        if let Some(limit) = block_size_limit {
-            let size = size as u64;
-            if *limit < size {
-                // FIXME: this could probably be detected at builder
-                return Err(TreeConstructionFailed::TooLargeBlock(size));
-            }
-        }
-
-        let cap = buffer.capacity();
+        buckets.reverse();
+        self.buckets = buckets;
 
-        if let Some(additional) = size.checked_sub(cap) {
-            buffer.reserve(additional);
-        }
+        Ok(leaf)
+    }
 
-        if let Some(mut needed_zeroes) = size.checked_sub(buffer.len()) {
-            let zeroes = [0; 8];
+    /// Returns the next of the nodes rendered with [`TreeOptions::parallel_rendering`], rendering
+    /// the whole tree on the first call.
+    #[cfg(feature = "rayon")]
+    fn next_rendered(&mut self) -> Option<Result<OwnedTreeNode, TreeConstructionFailed>> {
+        if self.rendered.is_none() {
+            let mut root = match self.pending.pop()? {
+                Visited::DescentRoot(root) => root,
+                other => unreachable!("parallel rendering starts from the root, not {:?}", other),
+            };
 
-            while needed_zeroes > 8 {
-                buffer.extend_from_slice(&zeroes[..]);
-                needed_zeroes -= zeroes.len();
+            if let Some(spill) = &self.spill {
+                if let Err(e) = spill.restore_tree(&mut root, "") {
+                    return Some(Err(e));
+                }
             }
 
-            buffer.extend(core::iter::repeat(0).take(needed_zeroes));
+            match super::parallel::render_tree(root, &self.opts) {
+                Ok(nodes) => self.rendered = Some(nodes.into_iter()),
+                Err(e) => return Some(Err(e)),
+            }
         }
 
-        let mut writer = Writer::new(BytesWriter::new(&mut buffer[..]));
-        node.write_message(&mut writer)
-            .map_err(TreeConstructionFailed::Protobuf)?;
+        self.rendered.as_mut()?.next().map(Ok)
+    }
 
-        buffer.truncate(size);
+    /// Returns the next of the rendered HAMT buckets, the root bucket being the last.
+    fn next_bucket(&mut self) -> Option<Result<TreeNode<'_>, TreeConstructionFailed>> {
+        let (leaf, block) = self.buckets.pop()?;
 
-        let mh = multihash::wrap(multihash::Code::Sha2_256, &Sha256::digest(buffer));
-        let cid = Cid::new_v0(mh).expect("sha2_256 is the correct multihash for cidv0");
+        self.block_buffer = block;
+        self.cid = Some(leaf.link);
+        self.total_size = leaf.total_size;
 
-        let combined_from_links = links
-            .iter()
-            .map(|opt| {
-                opt.as_ref()
-                    .map(|NamedLeaf(_, _, total_size)| total_size)
-                    .unwrap()
-            })
-            .sum::<u64>();
+        let bucket = !self.buckets.is_empty();
 
-        Ok(Leaf {
-            link: cid,
-            total_size: buffer.len() as u64 + combined_from_links,
-        })
+        Some(Ok(TreeNode {
+            path: self.full_path.as_str(),
+            cid: self.cid.as_ref().unwrap(),
+            total_size: self.total_size,
+            block: &self.block_buffer,
+            // only the root bucket carries the metadata of the directory
+            metadata: if bucket { &NO_METADATA } else { &self.metadata },
+            bucket,
+        }))
     }
 
     /// Construct the next dag-pb node, if any.
     ///
     /// Returns a `TreeNode` of the latest constructed tree node.
     pub fn next_borrowed(&mut self) -> Option<Result<TreeNode<'_>, TreeConstructionFailed>> {
+        #[cfg(feature = "rayon")]
+        {
+            if self.opts.parallel_rendering {
+                return match self.next_rendered()? {
+                    Ok(node) => {
+                        self.current = Some(node);
+                        self.current.as_ref().map(|node| Ok(node.as_borrowed()))
+                    }
+                    Err(e) => Some(Err(e)),
+                };
+            }
+        }
+
+        if !self.buckets.is_empty() {
+            return self.next_bucket();
+        }
+
         while let Some(visited) = self.pending.pop() {
             let (name, depth) = match &visited {
                 Visited::DescentRoot(_) => (None, 0),
@@ -174,7 +212,11 @@ impl PostOrderIterator {
             update_full_path((&mut self.full_path, &mut self.old_depth), name, depth);
 
             match visited {
-                Visited::DescentRoot(node) => {
+                Visited::DescentRoot(mut node) => {
+                    if let Err(e) = self.restore(&mut node) {
+                        return Some(Err(e));
+                    }
+
                     let children = &mut self.reused_children;
                     let leaves = partition_children_leaves(depth, node.nodes.into_iter(), children);
                     let any_children = !children.is_empty();
@@ -186,15 +228,22 @@ impl PostOrderIterator {
                         leaves.into()
                     };
 
-                    self.pending.push(Visited::PostRoot { leaves });
+                    self.pending.push(Visited::PostRoot {
+                        leaves,
+                        metadata: node.metadata,
+                    });
                     self.pending.append(children);
                 }
                 Visited::Descent {
-                    node,
+                    mut node,
                     name,
                     depth,
                     index,
                 } => {
+                    if let Err(e) = self.restore(&mut node) {
+                        return Some(Err(e));
+                    }
+
                     let children = &mut self.reused_children;
                     let leaves = partition_children_leaves(depth, node.nodes.into_iter(), children);
                     let any_children = !children.is_empty();
@@ -213,6 +262,7 @@ impl PostOrderIterator {
                         depth,
                         leaves,
                         index,
+                        metadata: node.metadata,
                     });
 
                     self.pending.append(children);
@@ -222,16 +272,12 @@ impl PostOrderIterator {
                     name,
                     leaves,
                     index,
+                    metadata,
                     ..
                 } => {
                     let leaves = leaves.into_inner(&mut self.persisted_cids);
-                    let buffer = &mut self.block_buffer;
 
-                    let leaf = match Self::render_directory(
-                        &leaves,
-                        buffer,
-                        &self.opts.block_size_limit,
-                    ) {
+                    let leaf = match self.render(&leaves, metadata) {
                         Ok(leaf) => leaf,
                         Err(e) => return Some(Err(e)),
                     };
@@ -258,32 +304,36 @@ impl PostOrderIterator {
                         }
                     }
 
+                    if !self.buckets.is_empty() {
+                        return self.next_bucket();
+                    }
+
                     return Some(Ok(TreeNode {
                         path: self.full_path.as_str(),
                         cid: self.cid.as_ref().unwrap(),
                         total_size: self.total_size,
                         block: &self.block_buffer,
+                        metadata: &self.metadata,
+                        bucket: false,
                     }));
                 }
-                Visited::PostRoot { leaves } => {
+                Visited::PostRoot { leaves, metadata } => {
                     let leaves = leaves.into_inner(&mut self.persisted_cids);
 
                     if !self.opts.wrap_with_directory {
                         break;
                     }
 
-                    let buffer = &mut self.block_buffer;
-
-                    let leaf = match Self::render_directory(
-                        &leaves,
-                        buffer,
-                        &self.opts.block_size_limit,
-                    ) {
+                    let leaf = match self.render(&leaves, metadata) {
                         Ok(leaf) => leaf,
                         Err(e) => return Some(Err(e)),
                     };
 
-                    self.cid = Some(leaf.link.clone());
+                    if !self.buckets.is_empty() {
+                        return self.next_bucket();
+                    }
+
+                    self.cid = Some(leaf.link);
                     self.total_size = leaf.total_size;
 
                     return Some(Ok(TreeNode {
@@ -291,6 +341,8 @@ impl PostOrderIterator {
                         cid: self.cid.as_ref().unwrap(),
                         total_size: self.total_size,
                         block: &self.block_buffer,
+                        metadata: &self.metadata,
+                        bucket: false,
                     }));
                 }
             }
@@ -303,11 +355,24 @@ impl Iterator for PostOrderIterator {
     type Item = Result<OwnedTreeNode, TreeConstructionFailed>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        #[cfg(feature = "rayon")]
+        {
+            if self.opts.parallel_rendering {
+                return self.next_rendered();
+            }
+        }
+
         self.next_borrowed()
             .map(|res| res.map(TreeNode::into_owned))
     }
 }
 
+/// The metadata of the inner HAMT buckets.
+static NO_METADATA: Metadata = Metadata {
+    mode: None,
+    mtime: None,
+};
+
 /// Borrowed representation of a node in the tree.
 pub struct TreeNode<'a> {
     /// Full path to the node.
@@ -318,6 +383,13 @@ pub struct TreeNode<'a> {
     pub total_size: u64,
     /// Raw dag-pb document.
     pub block: &'a [u8],
+    /// The mode and the modification time stored in the directory, see
+    /// [`BufferingTreeBuilder::set_metadata`](super::BufferingTreeBuilder::set_metadata).
+    pub metadata: &'a Metadata,
+    /// True for the inner HAMT buckets of a sharded directory. The buckets share the path of the
+    /// directory and need to be stored, but are not directories of their own; the root bucket of
+    /// the directory is returned last, with this set to false.
+    pub bucket: bool,
 }
 
 impl<'a> fmt::Debug for TreeNode<'a> {
@@ -327,6 +399,8 @@ impl<'a> fmt::Debug for TreeNode<'a> {
             .field("cid", &format_args!("{}", self.cid))
             .field("total_size", &self.total_size)
             .field("size", &self.block.len())
+            .field("metadata", &self.metadata)
+            .field("bucket", &self.bucket)
             .finish()
     }
 }
@@ -339,6 +413,22 @@ impl TreeNode<'_> {
             cid: self.cid.to_owned(),
             total_size: self.total_size,
             block: self.block.into(),
+            metadata: self.metadata.clone(),
+            bucket: self.bucket,
+        }
+    }
+}
+
+impl OwnedTreeNode {
+    #[cfg(feature = "rayon")]
+    fn as_borrowed(&self) -> TreeNode<'_> {
+        TreeNode {
+            path: &self.path,
+            cid: &self.cid,
+            total_size: self.total_size,
+            block: &self.block,
+            metadata: &self.metadata,
+            bucket: self.bucket,
         }
     }
 }
@@ -353,6 +443,102 @@ pub struct OwnedTreeNode {
     pub total_size: u64,
     /// Raw dag-pb document.
     pub block: Box<[u8]>,
+    /// The mode and the modification time stored in the directory, see [`TreeNode::metadata`].
+    pub metadata: Metadata,
+    /// True for the inner HAMT buckets of a sharded directory, see [`TreeNode::bucket`].
+    pub bucket: bool,
+}
+
+/// Renders the directory of the `links` into the `buffer`, or if it needs to be sharded, returns
+/// the rendered HAMT buckets in post order, the root bucket being the last. Returns the link to
+/// the directory.
+pub(super) fn render_directory(
+    links: &[Option<NamedLeaf>],
+    metadata: &Metadata,
+    opts: &TreeOptions,
+    buffer: &mut Vec<u8>,
+) -> Result<(Leaf, Vec<(Leaf, Vec<u8>)>), TreeConstructionFailed> {
+    if hamt::needs_sharding(links, opts.hamt_sharding_threshold) {
+        let buckets = hamt::render(links, metadata, opts)?;
+
+        let (root, _) = buckets.last().expect("the root bucket is always rendered");
+        let leaf = Leaf {
+            link: root.link.clone(),
+            total_size: root.total_size,
+        };
+
+        return Ok((leaf, buckets));
+    }
+
+    let mut data = UnixFs {
+        Type: UnixFsType::Directory,
+        ..Default::default()
+    };
+    metadata.write_to(&mut data);
+
+    let leaf = render_node(links, data, buffer, opts)?;
+    Ok((leaf, Vec::new()))
+}
+
+/// Serializes the dag-pb node of the `links` and `data` into the `buffer`, returning the link to
+/// it with the Cid version and hash function of the `opts`.
+pub(super) fn render_node(
+    links: &[Option<NamedLeaf>],
+    data: UnixFs<'_>,
+    buffer: &mut Vec<u8>,
+    opts: &TreeOptions,
+) -> Result<Leaf, TreeConstructionFailed> {
+    use quick_protobuf::{BytesWriter, MessageWrite, Writer};
+
+    let node = CustomFlatUnixFs { links, data };
+
+    let size = node.get_size();
+
+    if let Some(limit) = &opts.block_size_limit {
+        let size = size as u64;
+        if *limit < size {
+            return Err(TreeConstructionFailed::TooLargeBlock(size));
+        }
+    }
+
+    let cap = buffer.capacity();
+
+    if let Some(additional) = size.checked_sub(cap) {
+        buffer.reserve(additional);
+    }
+
+    if let Some(mut needed_zeroes) = size.checked_sub(buffer.len()) {
+        let zeroes = [0; 8];
+
+        while needed_zeroes > 8 {
+            buffer.extend_from_slice(&zeroes[..]);
+            needed_zeroes -= zeroes.len();
+        }
+
+        buffer.extend(core::iter::repeat(0).take(needed_zeroes));
+    }
+
+    let mut writer = Writer::new(BytesWriter::new(&mut buffer[..]));
+    node.write_message(&mut writer)
+        .map_err(TreeConstructionFailed::Protobuf)?;
+
+    buffer.truncate(size);
+
+    let cid = opts.cid_of(buffer);
+
+    let combined_from_links = links
+        .iter()
+        .map(|opt| {
+            opt.as_ref()
+                .map(|NamedLeaf(_, _, total_size)| total_size)
+                .unwrap()
+        })
+        .sum::<u64>();
+
+    Ok(Leaf {
+        link: cid,
+        total_size: buffer.len() as u64 + combined_from_links,
+    })
 }
 
 fn update_full_path(
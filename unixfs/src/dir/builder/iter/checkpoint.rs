@@ -0,0 +1,639 @@
+//! Resumable state of the [`PostOrderIterator`], see [`TreeCheckpoint`].
+
+use super::{LeafStorage, Leaves, PostOrderIterator, Spill, Visited};
+use crate::dir::builder::{DirBuilder, Entry, Leaf, NamedLeaf, OwnedTreeNode, TreeOptions};
+use crate::walk::{CursorReader, CursorWriter, InvalidCursor};
+use alloc::collections::BTreeMap;
+use cid::Version;
+use core::convert::TryFrom;
+use core::fmt;
+use multihash::Code;
+use std::collections::HashMap;
+
+/// The version of the encoding, bumped on incompatible changes.
+const VERSION: u64 = 1;
+
+/// The state of a [`PostOrderIterator`] between two nodes, returned by
+/// [`PostOrderIterator::checkpoint`].
+///
+/// The checkpoint can be stored as bytes and resumed with [`PostOrderIterator::resume`] later, so
+/// that an interrupted add of a very large tree can continue without adding all of the files
+/// again. The checkpoint contains the links of all of the directories yet to be rendered, so it is
+/// about the size of the remaining directory blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeCheckpoint(Vec<u8>);
+
+impl TreeCheckpoint {
+    /// Returns the checkpoint as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the checkpoint as bytes.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl From<Vec<u8>> for TreeCheckpoint {
+    /// Wraps the bytes of a previously stored checkpoint, which are only validated on
+    /// [`PostOrderIterator::resume`].
+    fn from(bytes: Vec<u8>) -> Self {
+        TreeCheckpoint(bytes)
+    }
+}
+
+/// The checkpoint given to [`PostOrderIterator::resume`] was truncated, created by an
+/// incompatible version or otherwise invalid.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidCheckpoint(&'static str);
+
+impl From<InvalidCursor> for InvalidCheckpoint {
+    fn from(e: InvalidCursor) -> Self {
+        InvalidCheckpoint(e.reason())
+    }
+}
+
+impl fmt::Display for InvalidCheckpoint {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "invalid tree checkpoint: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCheckpoint {}
+
+impl PostOrderIterator {
+    /// Returns the state of the iterator before the next node. An iterator resumed from the
+    /// checkpoint continues with the same node as this one would, so the checkpoint should be
+    /// stored only once the previously returned nodes have been stored.
+    ///
+    /// The links spilled over [`TreeOptions::memory_budget`] are read back into the checkpoint.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the spilled links cannot be read back.
+    pub fn checkpoint(&self) -> TreeCheckpoint {
+        debug_assert!(self.reused_children.is_empty());
+
+        let mut w = CursorWriter::default();
+        w.u64(VERSION);
+
+        write_options(&mut w, &self.opts);
+        w.str(&self.full_path);
+        w.u64(self.old_depth as u64);
+        w.metadata(&self.metadata);
+
+        w.u64(self.buckets.len() as u64);
+        for (leaf, block) in &self.buckets {
+            write_leaf(&mut w, leaf);
+            w.bytes(block);
+        }
+
+        w.u64(self.pending.len() as u64);
+        for visited in &self.pending {
+            write_visited(&mut w, visited, self.spill.as_ref());
+        }
+
+        // sorted to have the same checkpoint for the same state
+        let mut stashed = self.persisted_cids.iter().collect::<Vec<_>>();
+        stashed.sort_unstable_by_key(|(id, _)| **id);
+
+        w.u64(stashed.len() as u64);
+        for (id, leaves) in stashed {
+            w.u64(*id);
+            write_leaves(&mut w, leaves);
+        }
+
+        #[cfg(feature = "rayon")]
+        w.option(self.rendered.as_ref(), |w, rendered| {
+            let nodes = rendered.as_slice();
+            w.u64(nodes.len() as u64);
+            for node in nodes {
+                write_node(w, node);
+            }
+        });
+
+        #[cfg(not(feature = "rayon"))]
+        w.u64(0);
+
+        TreeCheckpoint(w.0)
+    }
+
+    /// Returns an iterator continuing from the `checkpoint` previously returned by
+    /// [`PostOrderIterator::checkpoint`].
+    pub fn resume(checkpoint: &TreeCheckpoint) -> Result<PostOrderIterator, InvalidCheckpoint> {
+        let mut r = CursorReader(checkpoint.as_bytes());
+
+        if r.u64()? != VERSION {
+            return Err(InvalidCheckpoint("unsupported version"));
+        }
+
+        let opts = read_options(&mut r)?;
+
+        let full_path = read_path(&mut r)?;
+        let old_depth = r.usize()?;
+
+        if old_depth != full_path.split('/').filter(|s| !s.is_empty()).count() {
+            return Err(InvalidCheckpoint("depth does not match the path"));
+        }
+
+        let metadata = r.metadata()?;
+
+        let mut buckets = Vec::new();
+        for _ in 0..r.u64()? {
+            let leaf = read_leaf(&mut r)?;
+            buckets.push((leaf, r.bytes()?.to_vec()));
+        }
+
+        let mut pending = Vec::new();
+        for _ in 0..r.u64()? {
+            pending.push(read_visited(&mut r)?);
+        }
+
+        let mut persisted_cids = HashMap::new();
+        for _ in 0..r.u64()? {
+            let id = r.u64()?;
+            if persisted_cids.insert(id, read_leaves(&mut r)?).is_some() {
+                return Err(InvalidCheckpoint("duplicate directory id"));
+            }
+        }
+
+        let rendered = read_option(&mut r, |r| {
+            let mut nodes = Vec::new();
+            for _ in 0..r.u64()? {
+                nodes.push(read_node(r)?);
+            }
+            Ok(nodes)
+        })?;
+
+        #[cfg(not(feature = "rayon"))]
+        {
+            if rendered.is_some() {
+                return Err(InvalidCheckpoint("parallel rendering is not enabled"));
+            }
+        }
+
+        if !r.0.is_empty() {
+            return Err(InvalidCheckpoint("trailing bytes"));
+        }
+
+        validate_pending(&pending, &persisted_cids, old_depth)?;
+
+        Ok(PostOrderIterator {
+            full_path,
+            old_depth,
+            block_buffer: Default::default(),
+            pending,
+            persisted_cids,
+            reused_children: Vec::new(),
+            cid: None,
+            total_size: 0,
+            metadata,
+            buckets,
+            #[cfg(feature = "rayon")]
+            rendered: rendered.map(Vec::into_iter),
+            #[cfg(feature = "rayon")]
+            current: None,
+            opts,
+            spill: None,
+        })
+    }
+}
+
+/// Checks that the links of the directories can be found once they are rendered, and that the
+/// paths can be rebuilt with the depths of the pending nodes.
+fn validate_pending(
+    pending: &[Visited],
+    stash: &HashMap<u64, Leaves>,
+    old_depth: usize,
+) -> Result<(), InvalidCheckpoint> {
+    let unrendered = |parent_id: u64, index: usize| {
+        stash
+            .get(&parent_id)
+            .and_then(|leaves| leaves.get(index))
+            .map(Option::is_none)
+            .unwrap_or(false)
+    };
+
+    let available = |leaves: &LeafStorage| match leaves {
+        LeafStorage::Direct(leaves) => leaves.iter().all(Option::is_some),
+        LeafStorage::Stashed(id) => stash.contains_key(id),
+    };
+
+    let mut current_depth = old_depth;
+
+    // the pending nodes are popped from the end
+    for visited in pending.iter().rev() {
+        let (found, depth) = match visited {
+            Visited::DescentRoot(_) => (true, 0),
+            Visited::Descent {
+                node, depth, index, ..
+            } => (
+                node.parent_id
+                    .map(|parent_id| unrendered(parent_id, *index))
+                    .unwrap_or(false),
+                *depth,
+            ),
+            Visited::Post {
+                parent_id,
+                depth,
+                index,
+                leaves,
+                ..
+            } => (unrendered(*parent_id, *index) && available(leaves), *depth),
+            Visited::PostRoot { leaves, .. } => (available(leaves), 0),
+        };
+
+        if !found {
+            return Err(InvalidCheckpoint("links of a directory not found"));
+        }
+
+        let is_root = matches!(visited, Visited::DescentRoot(_) | Visited::PostRoot { .. });
+
+        if !is_root && (depth == 0 || depth > current_depth + 1) {
+            return Err(InvalidCheckpoint("invalid depth"));
+        }
+
+        current_depth = depth;
+    }
+
+    Ok(())
+}
+
+fn write_options(w: &mut CursorWriter, opts: &TreeOptions) {
+    w.option(opts.block_size_limit, |w, limit| w.u64(limit));
+    w.u64(opts.wrap_with_directory as u64);
+    w.option(opts.hamt_sharding_threshold, |w, threshold| {
+        w.u64(threshold)
+    });
+    w.u64(u64::from(opts.cid_version));
+    w.u64(u64::from(opts.hash));
+
+    #[cfg(feature = "rayon")]
+    w.u64(opts.parallel_rendering as u64);
+
+    #[cfg(not(feature = "rayon"))]
+    w.u64(0);
+}
+
+fn read_options(r: &mut CursorReader<'_>) -> Result<TreeOptions, InvalidCheckpoint> {
+    let mut opts = TreeOptions::default();
+
+    opts.block_size_limit = r.option(|r| r.u64())?;
+    opts.wrap_with_directory = read_bool(r)?;
+    opts.hamt_sharding_threshold = r.option(|r| r.u64())?;
+    opts.cid_version =
+        Version::try_from(r.u64()?).map_err(|_| InvalidCheckpoint("unsupported cid version"))?;
+    opts.hash = Code::try_from(r.u64()?).map_err(|_| InvalidCheckpoint("unsupported hash"))?;
+
+    // the nodes are the same regardless of rendering them in parallel
+    let parallel = read_bool(r)?;
+
+    #[cfg(feature = "rayon")]
+    {
+        opts.parallel_rendering = parallel;
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    let _ = parallel;
+
+    Ok(opts)
+}
+
+fn write_visited(w: &mut CursorWriter, visited: &Visited, spill: Option<&Spill>) {
+    match visited {
+        Visited::DescentRoot(node) => {
+            w.u64(0);
+            write_dir(w, node, spill);
+        }
+        Visited::Descent {
+            node,
+            name,
+            depth,
+            index,
+        } => {
+            w.u64(1);
+            write_dir(w, node, spill);
+            w.str(name);
+            w.u64(*depth as u64);
+            w.u64(*index as u64);
+        }
+        Visited::Post {
+            parent_id,
+            depth,
+            name,
+            index,
+            leaves,
+            metadata,
+        } => {
+            w.u64(2);
+            w.u64(*parent_id);
+            w.u64(*depth as u64);
+            w.str(name);
+            w.u64(*index as u64);
+            write_storage(w, leaves);
+            w.metadata(metadata);
+        }
+        Visited::PostRoot { leaves, metadata } => {
+            w.u64(3);
+            write_storage(w, leaves);
+            w.metadata(metadata);
+        }
+    }
+}
+
+fn read_visited(r: &mut CursorReader<'_>) -> Result<Visited, InvalidCheckpoint> {
+    Ok(match r.u64()? {
+        0 => Visited::DescentRoot(read_dir(r)?),
+        1 => Visited::Descent {
+            node: read_dir(r)?,
+            name: read_name(r)?,
+            depth: r.usize()?,
+            index: r.usize()?,
+        },
+        2 => Visited::Post {
+            parent_id: r.u64()?,
+            depth: r.usize()?,
+            name: read_name(r)?,
+            index: r.usize()?,
+            leaves: read_storage(r)?,
+            metadata: r.metadata()?,
+        },
+        3 => Visited::PostRoot {
+            leaves: read_storage(r)?,
+            metadata: r.metadata()?,
+        },
+        _ => return Err(InvalidCheckpoint("unknown kind of pending node")),
+    })
+}
+
+fn write_dir(w: &mut CursorWriter, node: &DirBuilder, spill: Option<&Spill>) {
+    w.option(node.parent_id, |w, parent_id| w.u64(parent_id));
+    w.u64(node.id);
+    w.metadata(&node.metadata);
+
+    w.u64(node.len() as u64);
+    for (name, entry) in &node.nodes {
+        w.str(name);
+        match entry {
+            Entry::Leaf(leaf) => {
+                w.u64(0);
+                write_leaf(w, leaf);
+            }
+            Entry::Directory(dir) => {
+                w.u64(1);
+                write_dir(w, dir, spill);
+            }
+        }
+    }
+
+    // the spilled links are written as if they had never been spilled
+    for run in &node.spilled {
+        let spill = spill.expect("links were spilled without a spill file");
+        let leaves = spill
+            .read(run)
+            .expect("failed to read the spilled links for a checkpoint");
+
+        for (name, leaf) in &leaves {
+            w.str(name);
+            w.u64(0);
+            write_leaf(w, leaf);
+        }
+    }
+}
+
+fn read_dir(r: &mut CursorReader<'_>) -> Result<DirBuilder, InvalidCheckpoint> {
+    let parent_id = r.option(|r| r.u64())?;
+    let id = r.u64()?;
+    let metadata = r.metadata()?;
+
+    let mut nodes = BTreeMap::new();
+    for _ in 0..r.u64()? {
+        let name = read_name(r)?;
+        let entry = match r.u64()? {
+            0 => Entry::Leaf(read_leaf(r)?),
+            1 => {
+                let dir = read_dir(r)?;
+                if dir.parent_id != Some(id) {
+                    return Err(InvalidCheckpoint("invalid parent of a directory"));
+                }
+                Entry::Directory(dir)
+            }
+            _ => return Err(InvalidCheckpoint("unknown kind of entry")),
+        };
+
+        if nodes.insert(name, entry).is_some() {
+            return Err(InvalidCheckpoint("duplicate name in a directory"));
+        }
+    }
+
+    Ok(DirBuilder {
+        nodes,
+        metadata,
+        parent_id,
+        id,
+        spilled: Vec::new(),
+    })
+}
+
+fn write_storage(w: &mut CursorWriter, leaves: &LeafStorage) {
+    match leaves {
+        LeafStorage::Direct(leaves) => {
+            w.u64(0);
+            write_leaves(w, leaves);
+        }
+        LeafStorage::Stashed(id) => {
+            w.u64(1);
+            w.u64(*id);
+        }
+    }
+}
+
+fn read_storage(r: &mut CursorReader<'_>) -> Result<LeafStorage, InvalidCheckpoint> {
+    match r.u64()? {
+        0 => Ok(LeafStorage::Direct(read_leaves(r)?)),
+        1 => Ok(LeafStorage::Stashed(r.u64()?)),
+        _ => Err(InvalidCheckpoint("unknown kind of links")),
+    }
+}
+
+fn write_leaves(w: &mut CursorWriter, leaves: &[Option<NamedLeaf>]) {
+    w.u64(leaves.len() as u64);
+    for leaf in leaves {
+        w.option(leaf.as_ref(), |w, NamedLeaf(name, cid, total_size)| {
+            w.str(name);
+            w.cid(cid);
+            w.u64(*total_size);
+        });
+    }
+}
+
+fn read_leaves(r: &mut CursorReader<'_>) -> Result<Leaves, InvalidCheckpoint> {
+    let mut leaves = Vec::new();
+    for _ in 0..r.u64()? {
+        leaves.push(read_option(r, |r| {
+            Ok(NamedLeaf(read_name(r)?, r.cid()?, r.u64()?))
+        })?);
+    }
+    Ok(leaves)
+}
+
+fn write_leaf(w: &mut CursorWriter, leaf: &Leaf) {
+    w.cid(&leaf.link);
+    w.u64(leaf.total_size);
+}
+
+fn read_leaf(r: &mut CursorReader<'_>) -> Result<Leaf, InvalidCheckpoint> {
+    Ok(Leaf {
+        link: r.cid()?,
+        total_size: r.u64()?,
+    })
+}
+
+#[cfg(feature = "rayon")]
+fn write_node(w: &mut CursorWriter, node: &OwnedTreeNode) {
+    w.str(&node.path);
+    w.cid(&node.cid);
+    w.u64(node.total_size);
+    w.bytes(&node.block);
+    w.metadata(&node.metadata);
+    w.u64(node.bucket as u64);
+}
+
+fn read_node(r: &mut CursorReader<'_>) -> Result<OwnedTreeNode, InvalidCheckpoint> {
+    Ok(OwnedTreeNode {
+        path: read_path(r)?,
+        cid: r.cid()?,
+        total_size: r.u64()?,
+        block: r.bytes()?.into(),
+        metadata: r.metadata()?,
+        bucket: read_bool(r)?,
+    })
+}
+
+/// Reads a name of a directory entry, which the paths are split into.
+fn read_name(r: &mut CursorReader<'_>) -> Result<String, InvalidCheckpoint> {
+    let name = r.str()?;
+    if name.is_empty() || name.contains('/') {
+        return Err(InvalidCheckpoint("invalid name"));
+    }
+    Ok(name.to_owned())
+}
+
+fn read_path(r: &mut CursorReader<'_>) -> Result<String, InvalidCheckpoint> {
+    let path = r.str()?;
+    if !path.is_empty() && path.split('/').any(str::is_empty) {
+        return Err(InvalidCheckpoint("invalid path"));
+    }
+    Ok(path.to_owned())
+}
+
+fn read_bool(r: &mut CursorReader<'_>) -> Result<bool, InvalidCheckpoint> {
+    match r.u64()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(InvalidCheckpoint("invalid boolean")),
+    }
+}
+
+fn read_option<T>(
+    r: &mut CursorReader<'_>,
+    f: impl FnOnce(&mut CursorReader<'_>) -> Result<T, InvalidCheckpoint>,
+) -> Result<Option<T>, InvalidCheckpoint> {
+    match r.u64()? {
+        0 => Ok(None),
+        1 => f(r).map(Some),
+        _ => Err(InvalidCheckpoint("invalid option")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InvalidCheckpoint, TreeCheckpoint};
+    use crate::dir::builder::{BufferingTreeBuilder, PostOrderIterator, TreeOptions};
+    use crate::Metadata;
+    use cid::Cid;
+
+    fn build(threshold: Option<u64>) -> PostOrderIterator {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        opts.hamt_sharding_threshold(threshold);
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder
+            .set_metadata("a/1", Metadata::new(Some(0o755), None))
+            .unwrap();
+
+        for i in 0..30 {
+            let path = format!("{}/{}/{}.txt", ["a", "b", "c"][i % 3], i % 5, i);
+            builder.put_link(&path, some_cid(i), 1).unwrap();
+        }
+
+        builder.build()
+    }
+
+    fn collect(iter: PostOrderIterator) -> Vec<(String, Cid, Box<[u8]>, bool)> {
+        iter.map(|node| node.unwrap())
+            .map(|node| (node.path, node.cid, node.block, node.bucket))
+            .collect()
+    }
+
+    #[test]
+    fn resumed_from_checkpoints() {
+        for threshold in &[None, Some(100)] {
+            let expected = collect(build(*threshold));
+            assert_eq!(
+                expected.iter().any(|node| node.3),
+                threshold.is_some(),
+                "{:?}",
+                threshold
+            );
+
+            let mut iter = build(*threshold);
+
+            for skipped in 0..=expected.len() {
+                let checkpoint = iter.checkpoint();
+
+                // the checkpoint survives being stored as bytes
+                let checkpoint = TreeCheckpoint::from(checkpoint.into_bytes());
+                let resumed = PostOrderIterator::resume(&checkpoint).unwrap();
+
+                assert_eq!(collect(resumed), &expected[skipped..]);
+
+                if iter.next().is_none() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_checkpoints() {
+        let checkpoint = build(None).checkpoint().into_bytes();
+
+        for len in 0..checkpoint.len() {
+            let truncated = TreeCheckpoint::from(checkpoint[..len].to_vec());
+            assert!(PostOrderIterator::resume(&truncated).is_err(), "{}", len);
+        }
+
+        let mut trailing = checkpoint.clone();
+        trailing.push(0);
+        assert_eq!(
+            PostOrderIterator::resume(&trailing.into()).unwrap_err(),
+            InvalidCheckpoint("trailing bytes")
+        );
+
+        let mut version = checkpoint;
+        version[0] = 2;
+        assert_eq!(
+            PostOrderIterator::resume(&version.into()).unwrap_err(),
+            InvalidCheckpoint("unsupported version")
+        );
+    }
+
+    fn some_cid(number: usize) -> Cid {
+        use multihash::Sha2_256;
+        let mh = Sha2_256::digest(&number.to_le_bytes());
+        Cid::new_v0(mh).unwrap()
+    }
+}
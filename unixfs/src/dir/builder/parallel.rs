@@ -0,0 +1,127 @@
+//! Rendering of the whole tree with the sibling directories rendered concurrently, see
+//! [`TreeOptions::parallel_rendering`].
+
+use super::iter::render_directory;
+use super::{
+    DirBuilder, Entry, Leaf, NamedLeaf, OwnedTreeNode, TreeConstructionFailed, TreeOptions,
+};
+use crate::Metadata;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+
+/// Renders the directories of the tree in the same order the serial visit of
+/// [`super::PostOrderIterator`] would return them.
+pub(super) fn render_tree(
+    root: DirBuilder,
+    opts: &TreeOptions,
+) -> Result<Vec<OwnedTreeNode>, TreeConstructionFailed> {
+    let (links, mut nodes) = render_children(root.nodes, "", opts)?;
+
+    if opts.wrap_with_directory {
+        push_directory(&links, root.metadata, String::new(), opts, &mut nodes)?;
+    }
+
+    Ok(nodes)
+}
+
+/// Renders the directory at `path` after its subdirectories, returning the link to it.
+fn render_subtree(
+    node: DirBuilder,
+    path: String,
+    opts: &TreeOptions,
+) -> Result<(Leaf, Vec<OwnedTreeNode>), TreeConstructionFailed> {
+    let (links, mut nodes) = render_children(node.nodes, &path, opts)?;
+    let leaf = push_directory(&links, node.metadata, path, opts, &mut nodes)?;
+    Ok((leaf, nodes))
+}
+
+/// Renders the subdirectories among the `entries` concurrently, returning the links to all of
+/// the entries and the rendered nodes of the subdirectories.
+fn render_children(
+    entries: BTreeMap<String, Entry>,
+    path: &str,
+    opts: &TreeOptions,
+) -> Result<(Vec<Option<NamedLeaf>>, Vec<OwnedTreeNode>), TreeConstructionFailed> {
+    let mut links = Vec::with_capacity(entries.len());
+    let mut dirs = Vec::new();
+
+    for (index, (name, entry)) in entries.into_iter().enumerate() {
+        match entry {
+            Entry::Leaf(leaf) => links.push(Some(NamedLeaf(name, leaf.link, leaf.total_size))),
+            Entry::Directory(node) => {
+                dirs.push((index, name, node));
+                links.push(None);
+            }
+        }
+    }
+
+    let rendered = dirs
+        .into_par_iter()
+        .map(|(index, name, node)| {
+            let path = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", path, name)
+            };
+
+            render_subtree(node, path, opts).map(|(leaf, nodes)| (index, name, leaf, nodes))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut nodes = Vec::new();
+
+    // the serial visit pops the subdirectories from a stack, the last one first
+    for (index, name, leaf, subtree) in rendered.into_iter().rev() {
+        links[index] = Some(NamedLeaf(name, leaf.link, leaf.total_size));
+        nodes.extend(subtree);
+    }
+
+    Ok((links, nodes))
+}
+
+/// Renders the directory of the `links` and pushes it, or its HAMT buckets, to the `nodes`.
+fn push_directory(
+    links: &[Option<NamedLeaf>],
+    metadata: Metadata,
+    path: String,
+    opts: &TreeOptions,
+    nodes: &mut Vec<OwnedTreeNode>,
+) -> Result<Leaf, TreeConstructionFailed> {
+    let mut block = Vec::new();
+    let (leaf, buckets) = render_directory(links, &metadata, opts, &mut block)?;
+
+    if buckets.is_empty() {
+        nodes.push(OwnedTreeNode {
+            path,
+            cid: leaf.link.clone(),
+            total_size: leaf.total_size,
+            block: block.into_boxed_slice(),
+            metadata,
+            bucket: false,
+        });
+
+        return Ok(leaf);
+    }
+
+    let root = buckets.len() - 1;
+
+    for (i, (bucket, block)) in buckets.into_iter().enumerate() {
+        // only the root bucket carries the metadata of the directory
+        let metadata = if i == root {
+            metadata.clone()
+        } else {
+            Metadata::default()
+        };
+
+        nodes.push(OwnedTreeNode {
+            path: path.clone(),
+            cid: bucket.link,
+            total_size: bucket.total_size,
+            block: block.into_boxed_slice(),
+            metadata,
+            bucket: i != root,
+        });
+    }
+
+    Ok(leaf)
+}
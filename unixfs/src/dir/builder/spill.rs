@@ -0,0 +1,190 @@
+//! Moving the buffered links of the files into a temporary file once they take more memory than
+//! allowed, see [`TreeOptions::memory_budget`](super::TreeOptions::memory_budget).
+//!
+//! The links are written as runs, one for each directory on each spill, and the runs of a
+//! directory are read back when the [`PostOrderIterator`](super::PostOrderIterator) descends
+//! into it. The directories themselves are kept in memory.
+
+use super::{DirBuilder, Entry, Leaf, TreeConstructionFailed};
+use crate::walk::{CursorReader, CursorWriter, InvalidCursor};
+use alloc::collections::btree_map::Entry::*;
+use core::mem;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The estimated memory used by an entry in addition to the name and the multihash.
+const ENTRY_OVERHEAD: u64 = 96;
+
+/// Distinguishes the spill files of the same process.
+static NEXT_SPILL: AtomicU64 = AtomicU64::new(0);
+
+/// The links of one directory written on one spill.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Run {
+    offset: u64,
+    len: u64,
+    count: usize,
+}
+
+impl Run {
+    pub(super) fn count(&self) -> usize {
+        self.count
+    }
+}
+
+/// The temporary file of the spilled links, removed when dropped.
+#[derive(Debug)]
+pub(super) struct Spill {
+    file: File,
+    path: PathBuf,
+    len: u64,
+}
+
+impl Spill {
+    pub(super) fn create(dir: &Path) -> io::Result<Self> {
+        let name = format!(
+            "unixfs-spill-{}-{}",
+            std::process::id(),
+            NEXT_SPILL.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        Ok(Spill { file, path, len: 0 })
+    }
+
+    /// Moves the links of the files directly under the `node` and its subdirectories into the
+    /// file.
+    pub(super) fn spill(&mut self, node: &mut DirBuilder) -> io::Result<()> {
+        for entry in node.nodes.values_mut() {
+            if let Entry::Directory(dir) = entry {
+                self.spill(dir)?;
+            }
+        }
+
+        let mut w = CursorWriter::default();
+        let mut count = 0;
+        for (name, entry) in &node.nodes {
+            if let Entry::Leaf(leaf) = entry {
+                w.str(name);
+                w.cid(&leaf.link);
+                w.u64(leaf.total_size);
+                count += 1;
+            }
+        }
+
+        if count == 0 {
+            return Ok(());
+        }
+
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.len))?;
+        file.write_all(&w.0)?;
+
+        node.spilled.push(Run {
+            offset: self.len,
+            len: w.0.len() as u64,
+            count,
+        });
+        self.len += w.0.len() as u64;
+
+        node.nodes
+            .retain(|_, entry| matches!(entry, Entry::Directory(_)));
+
+        Ok(())
+    }
+
+    /// Reads the links of the `run` back.
+    pub(super) fn read(&self, run: &Run) -> io::Result<Vec<(String, Leaf)>> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(run.offset))?;
+
+        let mut bytes = vec![0; run.len as usize];
+        file.read_exact(&mut bytes)?;
+
+        let invalid = |e: InvalidCursor| io::Error::new(io::ErrorKind::InvalidData, e.reason());
+        let mut r = CursorReader(&bytes[..]);
+        let mut leaves = Vec::with_capacity(run.count);
+
+        for _ in 0..run.count {
+            let name = r.str().map_err(invalid)?.to_owned();
+            let leaf = Leaf {
+                link: r.cid().map_err(invalid)?,
+                total_size: r.u64().map_err(invalid)?,
+            };
+            leaves.push((name, leaf));
+        }
+
+        Ok(leaves)
+    }
+
+    /// Reads the spilled links of the `node` back into it. The `path` of the node is only used
+    /// for the error on a name found twice, which is only noticed here for the spilled links.
+    pub(super) fn restore(
+        &self,
+        node: &mut DirBuilder,
+        path: &str,
+    ) -> Result<(), TreeConstructionFailed> {
+        for run in mem::take(&mut node.spilled) {
+            for (name, leaf) in self.read(&run).map_err(TreeConstructionFailed::Spill)? {
+                match node.nodes.entry(name) {
+                    Vacant(ve) => {
+                        ve.insert(Entry::Leaf(leaf));
+                    }
+                    Occupied(oe) if path.is_empty() => {
+                        return Err(TreeConstructionFailed::DuplicatePath(oe.key().to_owned()));
+                    }
+                    Occupied(oe) => {
+                        return Err(TreeConstructionFailed::DuplicatePath(format!(
+                            "{}/{}",
+                            path,
+                            oe.key()
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the spilled links of the whole tree under the `node` back.
+    pub(super) fn restore_tree(
+        &self,
+        node: &mut DirBuilder,
+        path: &str,
+    ) -> Result<(), TreeConstructionFailed> {
+        self.restore(node, path)?;
+
+        for (name, entry) in node.nodes.iter_mut() {
+            if let Entry::Directory(dir) = entry {
+                let path = if path.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{}/{}", path, name)
+                };
+                self.restore_tree(dir, &path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the estimated memory used by the buffered link.
+pub(super) fn estimated_size(name: &str, leaf: &Leaf) -> u64 {
+    (name.len() + leaf.link.hash().as_bytes().len()) as u64 + ENTRY_OVERHEAD
+}
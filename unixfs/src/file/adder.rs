@@ -1,24 +1,33 @@
-use cid::Cid;
+use cid::{Cid, Codec};
 
 use crate::pb::{FlatUnixFs, PBLink, UnixFs, UnixFsType};
+use crate::Metadata;
 use alloc::borrow::Cow;
 use core::fmt;
 use quick_protobuf::{MessageWrite, Writer};
 
 use sha2::{Digest, Sha256};
 
+mod chunker;
+pub use chunker::{Buzhash, Chunker, ChunkerOptions, InvalidChunkerOptions, Rabin};
+
 /// File tree builder. Implements [`core::default::Default`] which tracks the recent defaults.
 ///
 /// Custom file tree builder can be created with [`FileAdder::builder()`] and configuring the
 /// chunker and collector.
 ///
 /// Current implementation maintains an internal buffer for the block creation and uses a
-/// non-customizable hash function to produce Cid version 0 links. Currently does not support
-/// inline links.
+/// non-customizable hash function to produce Cid version 0 links, or Cid version 1 links to the
+/// raw leaves. Currently does not support inline links.
 #[derive(Default)]
 pub struct FileAdder {
     chunker: Chunker,
     collector: Collector,
+    raw_leaves: bool,
+    metadata: Metadata,
+    // with metadata, the first leaf is held back until it is known not to be the root, which needs
+    // to carry the metadata
+    held_leaf: Option<(Cid, Vec<u8>)>,
     block_buffer: Vec<u8>,
     // all unflushed links as a flat vec; this is compacted as we grow and need to create a link
     // block for the last N blocks, as decided by the collector.
@@ -32,8 +41,10 @@ impl fmt::Debug for FileAdder {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             fmt,
-            "FileAdder {{ chunker: {:?}, block_buffer: {}/{}, unflushed_links: {} }}",
+            "FileAdder {{ chunker: {:?}, raw_leaves: {}, metadata: {:?}, block_buffer: {}/{}, unflushed_links: {} }}",
             self.chunker,
+            self.raw_leaves,
+            self.metadata,
             self.block_buffer.len(),
             self.block_buffer.capacity(),
             LinkFormatter(&self.unflushed_links),
@@ -85,6 +96,7 @@ impl fmt::Display for LinkFormatter<'_> {
 
 /// Represents an intermediate structure which will be serialized into link blocks as both PBLink
 /// and UnixFs::blocksize. Also holds `depth`, which helps with compaction of the link blocks.
+#[derive(Clone)]
 struct Link {
     /// Depth of this link. Zero is leaf, and anything above it is, at least for
     /// [`BalancedCollector`], the compacted link blocks.
@@ -109,17 +121,47 @@ impl fmt::Debug for Link {
     }
 }
 
+/// A link block of the file tree which will not change anymore while more content is pushed to
+/// the [`FileAdder`], see [`FileAdder::completed_subtrees`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletedSubtree {
+    /// The Cid of the link block, which has already been returned by [`FileAdder::push`].
+    pub cid: Cid,
+    /// The depth of the subtree; the leaves are at depth zero.
+    pub depth: usize,
+    /// The offset of the first byte of the subtree within the file.
+    pub offset: u64,
+    /// The length of the file content in the subtree.
+    pub file_size: u64,
+    /// The size of all of the blocks in the subtree, including the link block.
+    pub total_size: u64,
+}
+
+/// How the leaf blocks are encoded.
+#[derive(Debug, Clone, Copy)]
+enum LeafFormat {
+    /// dag-pb blocks wrapping the content in UnixFs of the given type.
+    UnixFs(UnixFsType),
+    /// raw blocks of only the content, with Cid version 1.
+    Raw,
+}
+
 /// Convenience type to facilitate configuring [`FileAdder`]s.
 #[derive(Default)]
 pub struct FileAdderBuilder {
     chunker: Chunker,
     collector: Collector,
+    raw_leaves: bool,
+    metadata: Metadata,
 }
 
 impl FileAdderBuilder {
-    /// Configures the builder to use the given chunker.
-    pub fn with_chunker(self, chunker: Chunker) -> Self {
-        FileAdderBuilder { chunker, ..self }
+    /// Configures the builder to use the given chunker or [`ChunkerOptions`].
+    pub fn with_chunker(self, chunker: impl Into<Chunker>) -> Self {
+        FileAdderBuilder {
+            chunker: chunker.into(),
+            ..self
+        }
     }
 
     /// Configures the builder to use the given collector or layout.
@@ -130,13 +172,34 @@ impl FileAdderBuilder {
         }
     }
 
+    /// Configures the builder to create the leaves as raw blocks instead of dag-pb blocks, like
+    /// `ipfs add --raw-leaves`. The raw leaves are linked with Cid version 1 and the `raw` codec,
+    /// and a file of a single chunk is the raw block itself.
+    pub fn with_raw_leaves(self, raw_leaves: bool) -> Self {
+        FileAdderBuilder { raw_leaves, ..self }
+    }
+
+    /// Configures the builder to store the mode and the modification time in the root block of
+    /// the file, like `ipfs add --preserve-mode --preserve-mtime`. With raw leaves, a file of a
+    /// single chunk is wrapped in a dag-pb root block to carry the metadata.
+    pub fn with_metadata(self, metadata: Metadata) -> Self {
+        FileAdderBuilder { metadata, ..self }
+    }
+
     /// Returns a new FileAdder
     pub fn build(self) -> FileAdder {
-        let FileAdderBuilder { chunker, collector } = self;
+        let FileAdderBuilder {
+            chunker,
+            collector,
+            raw_leaves,
+            metadata,
+        } = self;
 
         FileAdder {
             chunker,
             collector,
+            raw_leaves,
+            metadata,
             ..Default::default()
         }
     }
@@ -162,6 +225,7 @@ impl FileAdder {
     /// `input` consumed.
     pub fn push(&mut self, input: &[u8]) -> (impl Iterator<Item = (Cid, Vec<u8>)>, usize) {
         let (accepted, ready) = self.chunker.accept(input, &self.block_buffer);
+        let leaf_format = self.leaf_format();
 
         if self.block_buffer.is_empty() && ready {
             // save single copy as the caller is giving us whole chunks.
@@ -171,11 +235,16 @@ impl FileAdder {
             // blocks and user takes care of chunking (and buffering)?
             //
             // cat file | my_awesome_chunker | my_brilliant_collector
-            let leaf = Self::flush_buffered_leaf(accepted, &mut self.unflushed_links, false);
+            let leaf =
+                Self::flush_buffered_leaf(accepted, &mut self.unflushed_links, leaf_format, false);
             assert!(leaf.is_some(), "chunk completed, must produce a new block");
             self.block_buffer.clear();
+            let (held, leaf) = self.hold_first_leaf(leaf);
             let links = self.flush_buffered_links(false);
-            (leaf.into_iter().chain(links.into_iter()), accepted.len())
+            (
+                held.into_iter().chain(leaf).chain(links.into_iter()),
+                accepted.len(),
+            )
         } else {
             // slower path as we manage the buffer.
 
@@ -188,26 +257,61 @@ impl FileAdder {
             self.block_buffer.extend_from_slice(accepted);
             let written = accepted.len();
 
-            let (leaf, links) = if !ready {
+            let (held, leaf, links) = if !ready {
                 // a new block did not become ready, which means we couldn't have gotten a new cid.
-                (None, Vec::new())
+                (None, None, Vec::new())
             } else {
                 // a new leaf must be output, as well as possibly a new link block
                 let leaf = Self::flush_buffered_leaf(
                     self.block_buffer.as_slice(),
                     &mut self.unflushed_links,
+                    leaf_format,
                     false,
                 );
                 assert!(leaf.is_some(), "chunk completed, must produce a new block");
                 self.block_buffer.clear();
+                let (held, leaf) = self.hold_first_leaf(leaf);
                 let links = self.flush_buffered_links(false);
 
-                (leaf, links)
+                (held, leaf, links)
             };
-            (leaf.into_iter().chain(links.into_iter()), written)
+            (
+                held.into_iter().chain(leaf).chain(links.into_iter()),
+                written,
+            )
         }
     }
 
+    /// Returns the completed link blocks of the tree built so far, in the order of the content
+    /// they cover. Only the topmost completed subtrees are returned, not the ones linked from
+    /// them, so the subtrees do not overlap.
+    ///
+    /// Can be called between the pushes, for example after every thousand leaves, to checkpoint
+    /// the progress of a long file or to look for the already stored subtrees before the file is
+    /// finished. A completed subtree stays in the finished tree as is, though the later ones may
+    /// link it from a new link block.
+    pub fn completed_subtrees(&self) -> impl Iterator<Item = CompletedSubtree> + '_ {
+        let mut offset = 0;
+
+        self.collector
+            .links()
+            .chain(self.unflushed_links.iter())
+            .map(move |link| {
+                let start = offset;
+                offset += link.file_size;
+                (start, link)
+            })
+            // the leaves are not link blocks
+            .filter(|(_, link)| link.depth > 0)
+            .map(|(offset, link)| CompletedSubtree {
+                cid: link.target.clone(),
+                depth: link.depth,
+                offset,
+                file_size: link.file_size,
+                total_size: link.total_size,
+            })
+    }
+
     /// Called after the last [`FileAdder::push`] to finish the tree construction.
     ///
     /// Returns a list of Cids and their respective blocks.
@@ -215,24 +319,154 @@ impl FileAdder {
     /// Note: the API will hopefully evolve in a direction which will not allocate a new Vec for
     /// every block in the near-ish future.
     pub fn finish(mut self) -> impl Iterator<Item = (Cid, Vec<u8>)> {
-        let last_leaf =
-            Self::flush_buffered_leaf(&self.block_buffer, &mut self.unflushed_links, true);
+        let empty_file = self.unflushed_links.is_empty() && !self.collector.has_links();
+        let leaf_format = match self.leaf_format() {
+            // like with go-ipfs, the empty trickle file is not a raw block
+            LeafFormat::Raw if empty_file && matches!(self.collector, Collector::Trickle(_)) => {
+                LeafFormat::UnixFs(UnixFsType::File)
+            }
+            other => other,
+        };
+        let last_leaf = Self::flush_buffered_leaf(
+            &self.block_buffer,
+            &mut self.unflushed_links,
+            leaf_format,
+            empty_file,
+        );
         let root_links = self.flush_buffered_links(true);
+
         // should probably error if there is neither?
-        last_leaf.into_iter().chain(root_links.into_iter())
+        let mut blocks = self
+            .held_leaf
+            .take()
+            .into_iter()
+            .chain(last_leaf)
+            .chain(root_links)
+            .collect::<Vec<_>>();
+
+        if !self.metadata.is_empty() {
+            self.attach_metadata(&mut blocks);
+        }
+
+        blocks.into_iter()
     }
 
-    /// Returns `None` when the input is empty but there are links, otherwise a new Cid and a
-    /// block.
+    /// Holds back the first leaf when there is metadata, as the leaf of a single chunk file is the
+    /// root. Returns the previously held leaf once it is known not to be the root, and the new
+    /// leaf unless it was held back.
+    #[allow(clippy::type_complexity)]
+    fn hold_first_leaf(
+        &mut self,
+        leaf: Option<(Cid, Vec<u8>)>,
+    ) -> (Option<(Cid, Vec<u8>)>, Option<(Cid, Vec<u8>)>) {
+        let first = self.unflushed_links.len() == 1 && !self.collector.has_links();
+
+        if first && !self.metadata.is_empty() {
+            self.held_leaf = leaf;
+            (None, None)
+        } else {
+            (self.held_leaf.take(), leaf)
+        }
+    }
+
+    /// Replaces the root block, the last of the `blocks`, with one carrying the metadata. A raw
+    /// root is wrapped in a new root block instead.
+    fn attach_metadata(&self, blocks: &mut Vec<(Cid, Vec<u8>)>) {
+        let root = match self.unflushed_links.as_slice() {
+            [root] => root,
+            other => unreachable!("finishing leaves a single root link, not {}", other.len()),
+        };
+
+        let mut flat = if root.target.codec() == Codec::Raw {
+            let mut links = Vec::with_capacity(1);
+            let mut blocksizes = Vec::with_capacity(1);
+            let (mut file_size, mut total_size) = (0, 0);
+
+            BalancedCollector::partition_link(
+                root,
+                &mut links,
+                &mut blocksizes,
+                &mut file_size,
+                &mut total_size,
+            );
+
+            FlatUnixFs {
+                links,
+                data: UnixFs {
+                    Type: UnixFsType::File,
+                    filesize: Some(file_size),
+                    blocksizes,
+                    ..Default::default()
+                },
+            }
+        } else {
+            let (cid, block) = blocks.pop().expect("the root block is the last one");
+            assert_eq!(cid, root.target, "the root block is the last one");
+
+            let flat = FlatUnixFs::try_parse(&block).expect("the root block was just rendered");
+            // the links and the data borrow the block
+            let FlatUnixFs { links, data } = flat;
+
+            FlatUnixFs {
+                links: links
+                    .into_iter()
+                    .map(|link| PBLink {
+                        Hash: link.Hash.map(|hash| Cow::Owned(hash.into_owned())),
+                        Name: link.Name.map(|name| Cow::Owned(name.into_owned())),
+                        Tsize: link.Tsize,
+                    })
+                    .collect(),
+                data: UnixFs {
+                    Type: data.Type,
+                    Data: data.Data.map(|data| Cow::Owned(data.into_owned())),
+                    filesize: data.filesize,
+                    blocksizes: data.blocksizes,
+                    ..Default::default()
+                },
+            }
+        };
+
+        self.metadata.write_to(&mut flat.data);
+        blocks.push(render_and_hash(&flat));
+    }
+
+    fn leaf_format(&self) -> LeafFormat {
+        if self.raw_leaves {
+            LeafFormat::Raw
+        } else {
+            LeafFormat::UnixFs(self.collector.leaf_type())
+        }
+    }
+
+    /// Returns `None` when the input is empty, unless the block of an `empty_file` is needed,
+    /// otherwise a new Cid and a block in the `leaf_format`.
     fn flush_buffered_leaf(
         input: &[u8],
         unflushed_links: &mut Vec<Link>,
-        finishing: bool,
+        leaf_format: LeafFormat,
+        empty_file: bool,
     ) -> Option<(Cid, Vec<u8>)> {
-        if input.is_empty() && (!finishing || !unflushed_links.is_empty()) {
+        if input.is_empty() && !empty_file {
             return None;
         }
 
+        let leaf_type = match leaf_format {
+            LeafFormat::UnixFs(leaf_type) => leaf_type,
+            LeafFormat::Raw => {
+                let mh = multihash::wrap(multihash::Code::Sha2_256, &Sha256::digest(input));
+                let cid = Cid::new_v1(Codec::Raw, mh);
+
+                unflushed_links.push(Link {
+                    depth: 0,
+                    target: cid.clone(),
+                    total_size: input.len() as u64,
+                    file_size: input.len() as u64,
+                });
+
+                return Some((cid, input.to_vec()));
+            }
+        };
+
         // for empty unixfs file the bytes is missing but filesize is present.
 
         let data = if !input.is_empty() {
@@ -246,7 +480,12 @@ impl FileAdder {
         let inner = FlatUnixFs {
             links: Vec::new(),
             data: UnixFs {
-                Type: UnixFsType::File,
+                // the empty file is the root block
+                Type: if input.is_empty() {
+                    UnixFsType::File
+                } else {
+                    leaf_type
+                },
                 Data: data,
                 filesize,
                 // no blocksizes as there are no links
@@ -317,51 +556,16 @@ fn render_and_hash(flat: &FlatUnixFs<'_>) -> (Cid, Vec<u8>) {
     (cid, out)
 }
 
-/// Chunker strategy
-#[derive(Debug, Clone)]
-pub enum Chunker {
-    /// Size based chunking
-    Size(usize),
-}
-
-impl Default for Chunker {
-    /// Returns a default chunker which matches go-ipfs 0.6
-    fn default() -> Self {
-        Chunker::Size(256 * 1024)
-    }
-}
-
-impl Chunker {
-    fn accept<'a>(&mut self, input: &'a [u8], buffered: &[u8]) -> (&'a [u8], bool) {
-        use Chunker::*;
-
-        match self {
-            Size(max) => {
-                let l = input.len().min(*max - buffered.len());
-                let accepted = &input[..l];
-                let ready = buffered.len() + l >= *max;
-                (accepted, ready)
-            }
-        }
-    }
-
-    fn size_hint(&self) -> usize {
-        use Chunker::*;
-
-        match self {
-            Size(max) => *max,
-        }
-    }
-}
-
 /// Collector or layout strategy. For more information, see the [Layout section of the spec].
-/// Currently only the default balanced collector/layout has been implemented.
+/// The balanced layout is the default, and the trickle layout is available as an alternative.
 ///
 /// [Layout section of the spec]: https://github.com/ipfs/specs/blob/master/UNIXFS.md#layout
 #[derive(Debug, Clone)]
 pub enum Collector {
     /// Balanced trees.
     Balanced(BalancedCollector),
+    /// Trickle trees.
+    Trickle(TrickleCollector),
 }
 
 impl Default for Collector {
@@ -376,6 +580,35 @@ impl Collector {
 
         match self {
             Balanced(bc) => bc.flush_links(pending, finishing),
+            Trickle(tc) => tc.flush_links(pending, finishing),
+        }
+    }
+
+    /// The UnixFs type of the leaf blocks.
+    fn leaf_type(&self) -> UnixFsType {
+        match self {
+            // go-ipfs uses the raw type for the trickle leaves, while the reason is unknown
+            Collector::Trickle(_) => UnixFsType::Raw,
+            Collector::Balanced(_) => UnixFsType::File,
+        }
+    }
+
+    /// Returns the links the collector has taken from the pending ones, in the order of the
+    /// content, which precede the pending links.
+    fn links(&self) -> impl Iterator<Item = &Link> {
+        let open = match self {
+            Collector::Balanced(_) => &[][..],
+            Collector::Trickle(tc) => tc.open.as_slice(),
+        };
+
+        open.iter().flat_map(|node| node.links.iter())
+    }
+
+    /// Returns true if the collector has taken some of the links from the pending ones.
+    fn has_links(&self) -> bool {
+        match self {
+            Collector::Balanced(_) => false,
+            Collector::Trickle(tc) => !tc.open.is_empty(),
         }
     }
 }
@@ -637,10 +870,208 @@ impl BalancedCollector {
     }
 }
 
+/// TrickleCollector creates trickle UnixFs trees like `go-ipfs add --trickle`, most optimized for
+/// reading the file sequentially from the start, as the first blocks of the file are linked
+/// directly from the root. The trees grow in depth only as needed, which suits streamed content
+/// whose length is not known up front.
+///
+/// Each link block first links up to `max_links` leaves, followed by `layer_repeat` subtrees of
+/// each increasing depth. A subtree of depth `n` is built the same way, but stops before the
+/// subtrees of depth `n`.
+#[derive(Clone)]
+pub struct TrickleCollector {
+    max_links: usize,
+    layer_repeat: usize,
+    /// The link blocks being filled, from the root to the one receiving the next leaf.
+    open: Vec<TrickleNode>,
+}
+
+#[derive(Clone)]
+struct TrickleNode {
+    /// Depth of the subtree, `None` for the root which is not limited.
+    max_depth: Option<usize>,
+    links: Vec<Link>,
+    leaves: usize,
+    /// Depth of the next subtree to be linked after the leaves.
+    depth: usize,
+    /// How many subtrees of the `depth` have been linked.
+    repeat: usize,
+}
+
+impl TrickleNode {
+    fn new(max_depth: Option<usize>) -> Self {
+        TrickleNode {
+            max_depth,
+            links: Vec::new(),
+            leaves: 0,
+            depth: 1,
+            repeat: 0,
+        }
+    }
+}
+
+impl fmt::Debug for TrickleCollector {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fmt,
+            "TrickleCollector {{ max_links: {}, layer_repeat: {} }}",
+            self.max_links, self.layer_repeat
+        )
+    }
+}
+
+impl Default for TrickleCollector {
+    /// Returns a default collector which matches go-ipfs 0.6 `add --trickle`.
+    fn default() -> Self {
+        Self::with_parameters(174, 4)
+    }
+}
+
+impl From<TrickleCollector> for Collector {
+    fn from(t: TrickleCollector) -> Self {
+        Collector::Trickle(t)
+    }
+}
+
+impl TrickleCollector {
+    /// Configure Trickle collector with the given maximum number of leaves per link block and
+    /// the number of subtrees of each depth.
+    pub fn with_parameters(max_links: usize, layer_repeat: usize) -> Self {
+        assert!(max_links > 0);
+        assert!(layer_repeat > 0);
+
+        Self {
+            max_links,
+            layer_repeat,
+            open: Vec::new(),
+        }
+    }
+
+    /// Moves the `pending` leaves into the trickle tree, returning the link blocks completed by
+    /// them. When `finishing`, the remaining link blocks are completed up to the root, which is
+    /// left as the only pending link.
+    fn flush_links(&mut self, pending: &mut Vec<Link>, finishing: bool) -> Vec<(Cid, Vec<u8>)> {
+        let mut ret = Vec::new();
+
+        if finishing && self.open.is_empty() && pending.len() == 1 && pending[0].file_size == 0 {
+            // the empty file is its own root
+            return ret;
+        }
+
+        for leaf in pending.drain(..) {
+            self.push_leaf(leaf, &mut ret);
+        }
+
+        if finishing {
+            while let Some(node) = self.open.pop() {
+                let link = Self::render(node, &mut ret);
+                match self.open.len().checked_sub(1) {
+                    Some(parent) => self.add_subtree(parent, link),
+                    None => pending.push(link),
+                }
+            }
+        }
+
+        ret
+    }
+
+    fn push_leaf(&mut self, leaf: Link, ret: &mut Vec<(Cid, Vec<u8>)>) {
+        if self.open.is_empty() {
+            self.open.push(TrickleNode::new(None));
+        }
+
+        loop {
+            let node = self.open.last_mut().expect("the root was just created");
+
+            if node.leaves < self.max_links {
+                node.leaves += 1;
+                node.links.push(leaf);
+                break;
+            }
+
+            // the leaves are full, and as the node is not complete, the leaf goes to a new
+            // subtree
+            let max_depth = Some(node.depth);
+            self.open.push(TrickleNode::new(max_depth));
+        }
+
+        // render the subtrees completed by the leaf, as the parents will not change them anymore
+        while self.open.last().map(|node| self.is_complete(node)) == Some(true) {
+            let node = self.open.pop().expect("checked above");
+            let link = Self::render(node, ret);
+            let parent = self.open.len() - 1;
+            self.add_subtree(parent, link);
+        }
+    }
+
+    fn is_complete(&self, node: &TrickleNode) -> bool {
+        match node.max_depth {
+            Some(max_depth) => node.leaves == self.max_links && node.depth >= max_depth,
+            // the root is only completed when finishing
+            None => false,
+        }
+    }
+
+    fn add_subtree(&mut self, index: usize, link: Link) {
+        let layer_repeat = self.layer_repeat;
+        let node = &mut self.open[index];
+
+        node.links.push(link);
+        node.repeat += 1;
+
+        if node.repeat == layer_repeat {
+            node.depth += 1;
+            node.repeat = 0;
+        }
+    }
+
+    /// Renders the link block of the node, returning the link to it.
+    fn render(node: TrickleNode, ret: &mut Vec<(Cid, Vec<u8>)>) -> Link {
+        let mut links = Vec::with_capacity(node.links.len());
+        let mut blocksizes = Vec::with_capacity(node.links.len());
+        let mut nested_size = 0;
+        let mut nested_total_size = 0;
+
+        for link in &node.links {
+            BalancedCollector::partition_link(
+                link,
+                &mut links,
+                &mut blocksizes,
+                &mut nested_size,
+                &mut nested_total_size,
+            );
+        }
+
+        let inner = FlatUnixFs {
+            links,
+            data: UnixFs {
+                Type: UnixFsType::File,
+                filesize: Some(nested_size),
+                blocksizes,
+                ..Default::default()
+            },
+        };
+
+        let (cid, vec) = render_and_hash(&inner);
+
+        let link = Link {
+            depth: node.max_depth.unwrap_or(node.depth),
+            target: cid.clone(),
+            total_size: nested_total_size + vec.len() as u64,
+            file_size: nested_size,
+        };
+
+        ret.push((cid, vec));
+
+        link
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
-    use super::{BalancedCollector, Chunker, FileAdder};
+    use super::{BalancedCollector, Chunker, Collector, FileAdder, TrickleCollector};
+    use crate::pb::{FlatUnixFs, UnixFsType};
     use crate::test_support::FakeBlockstore;
     use cid::Cid;
     use core::convert::TryFrom;
@@ -866,4 +1297,235 @@ mod tests {
 
         assert_eq!(blocks_count, 175);
     }
+
+    #[test]
+    fn trickle_multi_block_file() {
+        // go-ipfs 0.5 add --trickle -s size-2
+        let blocks = FakeBlockstore::with_fixtures();
+        let content = b"foobar\n";
+        let adder = FileAdder::builder()
+            .with_chunker(Chunker::Size(2))
+            .with_collector(TrickleCollector::default())
+            .build();
+
+        let blocks_received = adder.collect_blocks(content, 0);
+
+        assert_eq!(blocks_received.len(), 5);
+
+        for (cid, block) in &blocks_received {
+            assert_eq!(blocks.get_by_cid(cid), block.as_slice());
+        }
+
+        assert_eq!(
+            blocks_received.last().unwrap().0.to_string(),
+            "QmWfQ48ChJUj4vWKFsUDe4646xCBmXgdmNfhjz9T7crywd"
+        );
+    }
+
+    #[test]
+    fn completed_subtrees_while_pushing() {
+        let content = b"abcdefghijklmnopqrstuvwxyz";
+
+        for collector in vec![
+            Collector::from(BalancedCollector::with_branching_factor(3)),
+            Collector::from(TrickleCollector::with_parameters(2, 2)),
+        ] {
+            let mut adder = FileAdder::builder()
+                .with_chunker(Chunker::Size(1))
+                .with_collector(collector)
+                .build();
+
+            let mut received = Vec::new();
+            let mut completed = Vec::new();
+
+            for (pushed, byte) in content.chunks(1).enumerate() {
+                let (blocks, _) = adder.push(byte);
+                received.extend(blocks.map(|(cid, _)| cid));
+
+                let subtrees = adder.completed_subtrees().collect::<Vec<_>>();
+                for subtree in &subtrees {
+                    assert!(subtree.depth > 0);
+                    assert!(received.contains(&subtree.cid));
+                    assert!(subtree.offset + subtree.file_size <= pushed as u64 + 1);
+                }
+                assert!(subtrees
+                    .windows(2)
+                    .all(|w| w[0].offset + w[0].file_size <= w[1].offset));
+                completed.extend(subtrees.into_iter().map(|subtree| subtree.cid));
+            }
+
+            assert!(!completed.is_empty());
+
+            // the completed subtrees are part of the finished tree
+            let blocks = received
+                .into_iter()
+                .chain(adder.finish().map(|(cid, _)| cid))
+                .collect::<Vec<_>>();
+            assert!(completed.iter().all(|cid| blocks.contains(cid)));
+        }
+    }
+
+    #[test]
+    fn trickle_layers() {
+        let content = b"abcdefgh";
+
+        // root
+        //  |- a, b
+        //  |- depth 1: c, d
+        //  \- depth 2: e, f
+        //      \- depth 1: g, h
+        let root = |amt| {
+            let adder = FileAdder::builder()
+                .with_chunker(Chunker::Size(1))
+                .with_collector(TrickleCollector::with_parameters(2, 1))
+                .build();
+
+            let mut blocks_received = adder.collect_blocks(content, amt);
+
+            // 8 leaves, 3 link blocks below the root and the root
+            assert_eq!(blocks_received.len(), 12);
+
+            blocks_received.pop().unwrap()
+        };
+
+        let (cid, block) = root(0);
+
+        let root = FlatUnixFs::try_from(block.as_slice()).unwrap();
+        assert_eq!(root.data.Type, UnixFsType::File);
+        assert_eq!(root.data.filesize, Some(content.len() as u64));
+        assert_eq!(root.data.blocksizes, &[1, 1, 2, 4]);
+
+        for amt in 1..content.len() {
+            assert_eq!(root(amt).0, cid, "amt: {}", amt);
+        }
+    }
+
+    #[test]
+    fn trickle_empty_file() {
+        let blocks = FileAdder::builder()
+            .with_collector(TrickleCollector::default())
+            .build()
+            .collect_blocks(b"", 0);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(
+            blocks[0].0.to_string(),
+            "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH"
+        );
+    }
+
+    #[test]
+    fn raw_leaves_single_block_file() {
+        let adder = FileAdder::builder().with_raw_leaves(true).build();
+
+        let blocks_received = adder.collect_blocks(b"foobar\n", 0);
+
+        // the raw block is the whole file
+        assert_eq!(blocks_received.len(), 1);
+        assert_eq!(blocks_received[0].1, b"foobar\n");
+        assert_eq!(
+            blocks_received[0].0.to_string(),
+            "bafkreifoybygix7fh3r3g5rqle3wcnhqldgdg4shzf4k3ulyw3gn7mabt4"
+        );
+
+        let blocks = FileAdder::builder()
+            .with_raw_leaves(true)
+            .build()
+            .collect_blocks(b"", 0);
+        assert_eq!(
+            blocks[0].0.to_string(),
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        );
+    }
+
+    #[test]
+    fn raw_leaves_multi_block_file() {
+        use crate::file::visit::IdleFileVisit;
+        use cid::Codec;
+
+        let content = b"foobar\n";
+
+        for collector in &[
+            Collector::from(BalancedCollector::default()),
+            Collector::from(TrickleCollector::default()),
+        ] {
+            let adder = FileAdder::builder()
+                .with_chunker(Chunker::Size(2))
+                .with_collector(collector.clone())
+                .with_raw_leaves(true)
+                .build();
+
+            let mut blocks_received = adder.collect_blocks(content, 0);
+            let (root_cid, root) = blocks_received.pop().unwrap();
+
+            assert_eq!(root_cid.codec(), Codec::DagProtobuf);
+            assert!(blocks_received
+                .iter()
+                .all(|(cid, _)| cid.codec() == Codec::Raw));
+
+            let flat = FlatUnixFs::try_from(root.as_slice()).unwrap();
+            assert_eq!(flat.data.blocksizes, &[2, 2, 2, 1]);
+            let tsizes = flat.links.iter().map(|link| link.Tsize).collect::<Vec<_>>();
+            assert_eq!(tsizes, &[Some(2), Some(2), Some(2), Some(1)]);
+
+            // the raw leaves are read back as they are
+            let (_, file_size, _, mut visit) = IdleFileVisit::default().start(&root).unwrap();
+            assert_eq!(file_size, content.len() as u64);
+
+            let mut read = Vec::new();
+            while let Some(ongoing) = visit {
+                let next = ongoing.pending_links().0.to_owned();
+                let (_, block) = blocks_received
+                    .iter()
+                    .find(|(cid, _)| cid == &next)
+                    .unwrap();
+                let (bytes, next_visit) = ongoing.continue_walk(block, &mut None).unwrap();
+                read.extend_from_slice(bytes);
+                visit = next_visit;
+            }
+
+            assert_eq!(read, content);
+        }
+    }
+
+    #[test]
+    fn metadata_is_stored_in_the_root() {
+        use crate::Metadata;
+        use cid::Codec;
+
+        let metadata = Metadata::new(Some(0o644), Some((1_600_000_000, 5)));
+
+        for (raw_leaves, chunk_size) in &[(false, 256), (false, 2), (true, 256), (true, 2)] {
+            let adder = FileAdder::builder()
+                .with_chunker(Chunker::Size(*chunk_size))
+                .with_raw_leaves(*raw_leaves)
+                .with_metadata(metadata.clone())
+                .build();
+
+            let mut blocks_received = adder.collect_blocks(b"foobar\n", 1);
+            let (root_cid, root) = blocks_received.pop().unwrap();
+
+            // a single raw leaf is wrapped to carry the metadata
+            assert_eq!(root_cid.codec(), Codec::DagProtobuf);
+            assert_eq!(
+                blocks_received.is_empty(),
+                !raw_leaves && *chunk_size == 256
+            );
+
+            let flat = FlatUnixFs::try_from(root.as_slice()).unwrap();
+            assert_eq!(flat.data.Type, UnixFsType::File);
+            assert_eq!(flat.data.filesize, Some(7));
+            assert_eq!(flat.data.mode, Some(0o644));
+            let mtime = flat.data.mtime.as_ref().unwrap();
+            assert_eq!(
+                (mtime.Seconds, mtime.FractionalNanoseconds),
+                (1_600_000_000, Some(5))
+            );
+
+            // all of the linked blocks are emitted before the root
+            for link in &flat.links {
+                let target = Cid::try_from(link.Hash.as_deref().unwrap()).unwrap();
+                assert!(blocks_received.iter().any(|(cid, _)| cid == &target));
+            }
+        }
+    }
 }
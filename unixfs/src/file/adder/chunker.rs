@@ -0,0 +1,593 @@
+//! Chunkers splitting the file content into the leaf blocks of the [`super::FileAdder`].
+
+use core::fmt;
+use core::str::FromStr;
+
+/// The largest chunk size accepted by go-ipfs, 1 MiB.
+const CHUNK_SIZE_LIMIT: usize = 1024 * 1024;
+
+/// The default chunk size of go-ipfs 0.6, 256 KiB.
+const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Chunker strategy
+#[derive(Debug, Clone)]
+pub enum Chunker {
+    /// Size based chunking
+    Size(usize),
+    /// Content defined chunking with a rolling rabin fingerprint.
+    Rabin(Rabin),
+    /// Content defined chunking with a rolling buzhash.
+    Buzhash(Buzhash),
+}
+
+impl Default for Chunker {
+    /// Returns a default chunker which matches go-ipfs 0.6
+    fn default() -> Self {
+        Chunker::Size(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl From<ChunkerOptions> for Chunker {
+    fn from(options: ChunkerOptions) -> Self {
+        match options {
+            ChunkerOptions::Size(size) => Chunker::Size(size),
+            ChunkerOptions::Rabin { min, avg, max } => Chunker::Rabin(Rabin::new(min, avg, max)),
+            ChunkerOptions::Buzhash => Chunker::Buzhash(Buzhash::default()),
+        }
+    }
+}
+
+impl Chunker {
+    /// Returns the part of the `input` belonging to the chunk of the `buffered` bytes, and true if
+    /// the chunk was completed by it.
+    pub(super) fn accept<'a>(&mut self, input: &'a [u8], buffered: &[u8]) -> (&'a [u8], bool) {
+        use Chunker::*;
+
+        match self {
+            Size(max) => {
+                let l = input.len().min(*max - buffered.len());
+                let accepted = &input[..l];
+                let ready = buffered.len() + l >= *max;
+                (accepted, ready)
+            }
+            Rabin(rabin) => rabin.accept(input, buffered.len()),
+            Buzhash(buzhash) => buzhash.accept(input, buffered.len()),
+        }
+    }
+
+    pub(super) fn size_hint(&self) -> usize {
+        use Chunker::*;
+
+        match self {
+            Size(max) => *max,
+            Rabin(rabin) => rabin.max,
+            Buzhash(_) => self::Buzhash::MAX,
+        }
+    }
+}
+
+/// The configuration of a [`Chunker`], which can be parsed from the `--chunker` values of
+/// go-ipfs: `size-<size>`, `rabin`, `rabin-<avg>`, `rabin-<min>-<avg>-<max>` and `buzhash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkerOptions {
+    /// Chunks of the given size, except for the last one.
+    Size(usize),
+    /// Chunks of `min` to `max` bytes, averaging at about `avg` bytes, cut where the rabin
+    /// fingerprint of the last 16 bytes has as many zero bits as `avg` is a power of two.
+    Rabin {
+        /// The minimum size of a chunk, at least 16 bytes.
+        min: usize,
+        /// The targeted average size of a chunk.
+        avg: usize,
+        /// The maximum size of a chunk.
+        max: usize,
+    },
+    /// Chunks of 128 KiB to 512 KiB, see [`Buzhash`].
+    Buzhash,
+}
+
+impl Default for ChunkerOptions {
+    fn default() -> Self {
+        ChunkerOptions::Size(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl ChunkerOptions {
+    /// The rabin chunker of the given average size, with the minimum and maximum derived from it
+    /// like go-ipfs does.
+    pub fn rabin(avg: usize) -> Self {
+        ChunkerOptions::Rabin {
+            min: avg / 3,
+            avg,
+            max: avg + avg / 2,
+        }
+    }
+}
+
+impl FromStr for ChunkerOptions {
+    type Err = InvalidChunkerOptions;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use InvalidChunkerOptions::*;
+
+        if s.is_empty() || s == "default" {
+            return Ok(ChunkerOptions::default());
+        }
+
+        if s == "buzhash" {
+            return Ok(ChunkerOptions::Buzhash);
+        }
+
+        if let Some(size) = s.strip_prefix("size-") {
+            let size = size.parse::<usize>().map_err(|_| InvalidSize)?;
+
+            return if size == 0 {
+                Err(InvalidSize)
+            } else if size > CHUNK_SIZE_LIMIT {
+                Err(SizeTooLarge)
+            } else {
+                Ok(ChunkerOptions::Size(size))
+            };
+        }
+
+        if !s.starts_with("rabin") {
+            return Err(UnknownChunker);
+        }
+
+        let parts = s.split('-').collect::<Vec<_>>();
+
+        let options = match parts.as_slice() {
+            ["rabin"] => ChunkerOptions::rabin(DEFAULT_CHUNK_SIZE),
+            ["rabin", avg] => ChunkerOptions::rabin(avg.parse().map_err(|_| InvalidSize)?),
+            ["rabin", min, avg, max] => {
+                // the sizes can also be labeled, as in `rabin-min:16-avg:32-max:64`
+                let parse = |part: &str, label: &str| {
+                    let part = match part.split_once(':') {
+                        Some((found, size)) if found == label => size,
+                        Some(_) => return Err(InvalidFormat),
+                        None => part,
+                    };
+                    part.parse::<usize>().map_err(|_| InvalidSize)
+                };
+
+                ChunkerOptions::Rabin {
+                    min: parse(min, "min")?,
+                    avg: parse(avg, "avg")?,
+                    max: parse(max, "max")?,
+                }
+            }
+            _ => return Err(InvalidFormat),
+        };
+
+        match options {
+            ChunkerOptions::Rabin { min, .. } if min < Rabin::WINDOW_SIZE => Err(RabinMinTooSmall),
+            ChunkerOptions::Rabin { min, avg, max } if min >= avg || avg >= max => {
+                Err(InvalidFormat)
+            }
+            ChunkerOptions::Rabin { max, .. } if max > CHUNK_SIZE_LIMIT => Err(SizeTooLarge),
+            options => Ok(options),
+        }
+    }
+}
+
+/// The ways parsing the [`ChunkerOptions`] can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidChunkerOptions {
+    /// The chunker is not one of `size`, `rabin` or `buzhash`.
+    UnknownChunker,
+    /// The rabin chunker was not given as `rabin`, `rabin-<avg>` or `rabin-<min>-<avg>-<max>`,
+    /// or the sizes were not in increasing order.
+    InvalidFormat,
+    /// A size was not a positive number.
+    InvalidSize,
+    /// A size was larger than the 1 MiB supported by go-ipfs.
+    SizeTooLarge,
+    /// The minimum size of the rabin chunks was less than 16 bytes.
+    RabinMinTooSmall,
+}
+
+impl fmt::Display for InvalidChunkerOptions {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use InvalidChunkerOptions::*;
+
+        match self {
+            UnknownChunker => write!(fmt, "unrecognized chunker option"),
+            InvalidFormat => write!(
+                fmt,
+                "incorrect format (expected 'rabin' 'rabin-[avg]' or 'rabin-[min]-[avg]-[max]' \
+                 with increasing sizes)"
+            ),
+            InvalidSize => write!(fmt, "chunker size must be a number greater than 0"),
+            SizeTooLarge => write!(
+                fmt,
+                "chunker parameters may not exceed the maximum chunk size of {}",
+                CHUNK_SIZE_LIMIT
+            ),
+            RabinMinTooSmall => write!(fmt, "rabin min must be at least 16"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidChunkerOptions {}
+
+/// Rabin fingerprinting chunker producing the same chunks as the rabin chunker of go-ipfs.
+///
+/// The fingerprint is calculated over a sliding window of 16 bytes, modulo the same irreducible
+/// polynomial as go-ipfs uses. The first `min - 16` bytes of every chunk are skipped, and a chunk
+/// is cut after the first byte where the fingerprint has its low bits cleared, or at `max` bytes.
+#[derive(Clone)]
+pub struct Rabin {
+    min: usize,
+    max: usize,
+    mask: u64,
+    tables: Box<RabinTables>,
+    window: [u8; Rabin::WINDOW_SIZE],
+    wpos: usize,
+    digest: u64,
+}
+
+#[derive(Clone)]
+struct RabinTables {
+    /// The fingerprint of a byte followed by 15 zero bytes, for sliding the byte out.
+    out: [u64; 256],
+    /// The reduction of the 8 bits above the degree of the polynomial.
+    reduce: [u64; 256],
+}
+
+impl fmt::Debug for Rabin {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fmt,
+            "Rabin {{ min: {}, mask: {:#x}, max: {} }}",
+            self.min, self.mask, self.max
+        )
+    }
+}
+
+impl Rabin {
+    /// The polynomial used by go-ipfs.
+    const POLYNOMIAL: u64 = 17_437_180_132_763_653;
+
+    /// The size of the sliding window in bytes.
+    const WINDOW_SIZE: usize = 16;
+
+    /// Creates a rabin chunker with chunks of `min` to `max` bytes, averaging at about `avg`
+    /// bytes.
+    ///
+    /// # Panics
+    ///
+    /// If `min` is less than 16 bytes or the sizes are not in increasing order.
+    pub fn new(min: usize, avg: usize, max: usize) -> Self {
+        assert!(min >= Self::WINDOW_SIZE);
+        assert!(min <= avg && avg <= max);
+
+        // the average size is rounded down to a power of two
+        let bits = 63 - (avg as u64).leading_zeros();
+
+        let mut rabin = Rabin {
+            min,
+            max,
+            mask: (1 << bits) - 1,
+            tables: Box::new(RabinTables::new(Self::POLYNOMIAL)),
+            window: [0; Self::WINDOW_SIZE],
+            wpos: 0,
+            digest: 0,
+        };
+
+        rabin.reset();
+        rabin
+    }
+
+    fn accept<'a>(&mut self, input: &'a [u8], buffered: usize) -> (&'a [u8], bool) {
+        let skipped = self.min - Self::WINDOW_SIZE;
+        let mut count = buffered;
+
+        for (i, &byte) in input.iter().enumerate() {
+            count += 1;
+
+            if count <= skipped {
+                continue;
+            }
+
+            self.slide(byte);
+
+            if count >= self.min && (self.digest & self.mask == 0 || count >= self.max) {
+                self.reset();
+                return (&input[..=i], true);
+            }
+        }
+
+        (input, false)
+    }
+
+    fn reset(&mut self) {
+        self.window = [0; Self::WINDOW_SIZE];
+        self.wpos = 0;
+        self.digest = 0;
+        // go-ipfs starts every chunk with a single one byte in the window
+        self.slide(1);
+    }
+
+    fn slide(&mut self, byte: u8) {
+        let out = self.window[self.wpos];
+        self.window[self.wpos] = byte;
+        self.digest ^= self.tables.out[out as usize];
+        self.wpos = (self.wpos + 1) % Self::WINDOW_SIZE;
+
+        let shift = degree(Self::POLYNOMIAL) - 8;
+        let index = (self.digest >> shift) as usize;
+        self.digest <<= 8;
+        self.digest |= u64::from(byte);
+        self.digest ^= self.tables.reduce[index];
+    }
+}
+
+impl RabinTables {
+    fn new(polynomial: u64) -> Self {
+        let mut out = [0u64; 256];
+        let mut reduce = [0u64; 256];
+
+        let k = degree(polynomial);
+
+        for b in 0..256u64 {
+            let mut hash = append_byte(0, b as u8, polynomial);
+            for _ in 0..Rabin::WINDOW_SIZE - 1 {
+                hash = append_byte(hash, 0, polynomial);
+            }
+            out[b as usize] = hash;
+
+            // the reduction of the 8 bits above the degree along with the bits themselves, so
+            // that a single xor clears them
+            reduce[b as usize] = modulo(b << k, polynomial) | (b << k);
+        }
+
+        RabinTables { out, reduce }
+    }
+}
+
+/// The degree of the polynomial over GF(2), or `-1` for zero.
+fn degree(polynomial: u64) -> i32 {
+    63 - polynomial.leading_zeros() as i32
+}
+
+/// The remainder of the polynomial division over GF(2).
+fn modulo(mut x: u64, polynomial: u64) -> u64 {
+    while degree(x) >= degree(polynomial) {
+        x ^= polynomial << (degree(x) - degree(polynomial));
+    }
+    x
+}
+
+fn append_byte(hash: u64, byte: u8, polynomial: u64) -> u64 {
+    modulo((hash << 8) | u64::from(byte), polynomial)
+}
+
+/// Buzhash chunker with the parameters of the buzhash chunker of go-ipfs: chunks of 128 KiB to
+/// 512 KiB, cut where the hash of the last 32 bytes has its 17 low bits cleared.
+///
+/// The hash is not calculated with the byte table of go-ipfs, so the chunk boundaries differ from
+/// the ones of `ipfs add --chunker=buzhash`.
+#[derive(Clone)]
+pub struct Buzhash {
+    table: [u32; 256],
+    window: [u8; Buzhash::WINDOW_SIZE],
+    state: u32,
+}
+
+impl fmt::Debug for Buzhash {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fmt,
+            "Buzhash {{ min: {}, max: {} }}",
+            Buzhash::MIN,
+            Buzhash::MAX
+        )
+    }
+}
+
+impl Default for Buzhash {
+    fn default() -> Self {
+        // a fixed table of pseudorandom values, xorshift32 from a fixed seed
+        let mut table = [0u32; 256];
+        let mut x = 0x9e37_79b9u32;
+        for value in table.iter_mut() {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *value = x;
+        }
+
+        Buzhash {
+            table,
+            window: [0; Buzhash::WINDOW_SIZE],
+            state: 0,
+        }
+    }
+}
+
+impl Buzhash {
+    const MIN: usize = 128 * 1024;
+    const MAX: usize = 512 * 1024;
+    const MASK: u32 = (1 << 17) - 1;
+    const WINDOW_SIZE: usize = 32;
+
+    fn accept<'a>(&mut self, input: &'a [u8], buffered: usize) -> (&'a [u8], bool) {
+        let first_hashed = Self::MIN - Self::WINDOW_SIZE;
+
+        for (i, &byte) in input.iter().enumerate() {
+            let offset = buffered + i;
+
+            if offset < first_hashed {
+                continue;
+            }
+
+            // the oldest byte has been rotated a full round by now
+            let slot = offset % Self::WINDOW_SIZE;
+            let out = if offset >= Self::MIN {
+                self.table[self.window[slot] as usize]
+            } else {
+                0
+            };
+            self.window[slot] = byte;
+            self.state = self.state.rotate_left(1) ^ out ^ self.table[byte as usize];
+
+            let count = offset + 1;
+
+            if count >= Self::MIN && (self.state & Self::MASK == 0 || count >= Self::MAX) {
+                self.state = 0;
+                return (&input[..=i], true);
+            }
+        }
+
+        (input, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chunker, ChunkerOptions, InvalidChunkerOptions, Rabin};
+
+    #[test]
+    fn parse_options() {
+        let cases: &[(&str, Result<ChunkerOptions, InvalidChunkerOptions>)] = &[
+            ("", Ok(ChunkerOptions::Size(262_144))),
+            ("size-1024", Ok(ChunkerOptions::Size(1024))),
+            ("size-0", Err(InvalidChunkerOptions::InvalidSize)),
+            ("size-1048577", Err(InvalidChunkerOptions::SizeTooLarge)),
+            ("rabin", Ok(ChunkerOptions::rabin(262_144))),
+            (
+                "rabin-3000",
+                Ok(ChunkerOptions::Rabin {
+                    min: 1000,
+                    avg: 3000,
+                    max: 4500,
+                }),
+            ),
+            (
+                "rabin-16-32-64",
+                Ok(ChunkerOptions::Rabin {
+                    min: 16,
+                    avg: 32,
+                    max: 64,
+                }),
+            ),
+            (
+                "rabin-min:16-avg:32-max:64",
+                Ok(ChunkerOptions::Rabin {
+                    min: 16,
+                    avg: 32,
+                    max: 64,
+                }),
+            ),
+            (
+                "rabin-avg:16-min:32-max:64",
+                Err(InvalidChunkerOptions::InvalidFormat),
+            ),
+            (
+                "rabin-8-32-64",
+                Err(InvalidChunkerOptions::RabinMinTooSmall),
+            ),
+            ("rabin-64-32-16", Err(InvalidChunkerOptions::InvalidFormat)),
+            ("rabin-16-32", Err(InvalidChunkerOptions::InvalidFormat)),
+            ("buzhash", Ok(ChunkerOptions::Buzhash)),
+            ("fastcdc", Err(InvalidChunkerOptions::UnknownChunker)),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(&input.parse::<ChunkerOptions>(), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn rabin_tables() {
+        let rabin = Rabin::new(16, 32, 64);
+
+        // sliding out a zero byte does nothing
+        assert_eq!(rabin.tables.out[0], 0);
+        assert_eq!(rabin.tables.reduce[0], 0);
+
+        for (b, (out, reduce)) in rabin
+            .tables
+            .out
+            .iter()
+            .zip(rabin.tables.reduce.iter())
+            .enumerate()
+        {
+            // below the degree of the polynomial
+            assert_eq!(out >> 53, 0);
+            // the 8 bits above the degree are the index itself
+            assert_eq!(reduce >> 53, b as u64);
+        }
+    }
+
+    #[test]
+    fn content_defined_chunk_sizes() {
+        let content = pseudorandom(4 * 1024 * 1024);
+
+        let chunkers: &[(Chunker, usize, usize)] = &[
+            (ChunkerOptions::rabin(8 * 1024).into(), 2730, 12 * 1024),
+            (ChunkerOptions::Buzhash.into(), 128 * 1024, 512 * 1024),
+        ];
+
+        for (chunker, min, max) in chunkers {
+            let sizes = chunk_sizes(chunker.clone(), &content, content.len());
+
+            assert!(sizes.len() > 1, "{:?}", chunker);
+            assert_eq!(sizes.iter().sum::<usize>(), content.len());
+            let (last, rest) = sizes.split_last().unwrap();
+            assert!(*last <= *max);
+            assert!(
+                rest.iter().all(|size| (*min..=*max).contains(size)),
+                "{:?}: {:?}",
+                chunker,
+                sizes
+            );
+
+            // the cut points only depend on the content
+            for amt in &[1000, 4096, 65_537] {
+                assert_eq!(
+                    chunk_sizes(chunker.clone(), &content, *amt),
+                    sizes,
+                    "{:?}",
+                    chunker
+                );
+            }
+        }
+    }
+
+    fn chunk_sizes(mut chunker: Chunker, content: &[u8], amt: usize) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        let mut buffered = Vec::new();
+        let mut written = 0;
+
+        while written < content.len() {
+            let end = written + (content.len() - written).min(amt);
+            let (accepted, ready) = chunker.accept(&content[written..end], &buffered);
+            buffered.extend_from_slice(accepted);
+            written += accepted.len();
+
+            if ready {
+                sizes.push(buffered.len());
+                buffered.clear();
+            }
+        }
+
+        if !buffered.is_empty() {
+            sizes.push(buffered.len());
+        }
+
+        sizes
+    }
+
+    fn pseudorandom(len: usize) -> Vec<u8> {
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                (x >> 32) as u8
+            })
+            .collect()
+    }
+}
@@ -4,6 +4,7 @@ use core::fmt;
 use core::ops::Range;
 
 use crate::file::{FileError, FileReadFailed, Metadata, UnwrapBorrowedExt};
+use crate::walk::{CursorReader, CursorWriter, InvalidCursor};
 
 /// Navigates the UnixFs files, which are either:
 ///  - single block files which have everything needed to all of the contents
@@ -212,10 +213,61 @@ impl Traversal {
         FileReader::from_continued(self, tree_range.start, next_block)
     }
 
+    /// Continues the walk on the merkle tree with the contents of a raw leaf block, which is
+    /// the file content at the range as is. The range is expected to be the next one, like with
+    /// [`Traversal::continue_walk`].
+    pub fn continue_raw<'a>(
+        self,
+        next_block: &'a [u8],
+        tree_range: &Range<u64>,
+    ) -> Result<(&'a [u8], Traversal), FileReadFailed> {
+        self.last_ending
+            .check_is_suitable_next(self.last_offset, tree_range)?;
+
+        let traversal = Traversal {
+            last_ending: Ending::Chunk(tree_range.start + next_block.len() as u64),
+            last_offset: tree_range.start,
+            ..self
+        };
+
+        Ok((next_block, traversal))
+    }
+
     /// Returns the total size of the file.
     pub fn file_size(&self) -> u64 {
         self.file_size
     }
+
+    pub(crate) fn write_cursor(&self, w: &mut CursorWriter) {
+        match self.last_ending {
+            Ending::TreeCoverage(end) => {
+                w.u64(0);
+                w.u64(end);
+            }
+            Ending::Chunk(end) => {
+                w.u64(1);
+                w.u64(end);
+            }
+        }
+        w.u64(self.last_offset);
+        w.u64(self.file_size);
+        w.metadata(&self.metadata);
+    }
+
+    pub(crate) fn read_cursor(r: &mut CursorReader<'_>) -> Result<Self, InvalidCursor> {
+        let last_ending = match r.u64()? {
+            0 => Ending::TreeCoverage(r.u64()?),
+            1 => Ending::Chunk(r.u64()?),
+            _ => return Err(InvalidCursor::new("unknown file ending")),
+        };
+
+        Ok(Traversal {
+            last_ending,
+            last_offset: r.u64()?,
+            file_size: r.u64()?,
+            metadata: r.metadata()?,
+        })
+    }
 }
 
 impl AsRef<Metadata> for Traversal {
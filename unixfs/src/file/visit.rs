@@ -1,10 +1,11 @@
-use cid::Cid;
+use cid::{Cid, Codec};
 use core::convert::TryFrom;
 use core::ops::Range;
 
 use crate::file::reader::{FileContent, FileReader, Traversal};
 use crate::file::{FileReadFailed, Metadata};
 use crate::pb::{merkledag::PBLink, FlatUnixFs};
+use crate::walk::{CursorReader, CursorWriter, InvalidCursor};
 use crate::InvalidCidInLink;
 
 /// IdleFileVisit represents a prepared file visit over a tree. The user has to know the CID and be
@@ -155,25 +156,25 @@ impl FileVisit {
         cache: &mut Option<Cache>,
     ) -> Result<(&'a [u8], Option<Self>), FileReadFailed> {
         let traversal = self.state;
-        let (_, range) = self
+        let (cid, range) = self
             .pending
             .pop()
             .expect("User called continue_walk there must have been a next link");
 
+        if cid.codec() == Codec::Raw {
+            // raw leaves, as created by `ipfs add --raw-leaves`, are the file content as is
+            let (content, traversal) = traversal.continue_raw(next, &range)?;
+            self.state = traversal;
+            return Ok(self.leaf_content(content, &range, cache));
+        }
+
         // interesting, validation doesn't trigger if the range is the same?
         let fr = traversal.continue_walk(next, &range)?;
         let (content, traversal) = fr.content();
         match content {
             FileContent::Bytes(content) => {
-                let content = maybe_target_slice(content, &range, self.range.as_ref());
-
-                if !self.pending.is_empty() {
-                    self.state = traversal;
-                    Ok((content, Some(self)))
-                } else {
-                    *cache = Some(self.pending.into());
-                    Ok((content, None))
-                }
+                self.state = traversal;
+                Ok(self.leaf_content(content, &range, cache))
             }
             FileContent::Links(iter) => {
                 let before = self.pending.len();
@@ -195,10 +196,56 @@ impl FileVisit {
         }
     }
 
+    /// Returns the content of the leaf block at `range` within the target range, and the visit
+    /// if there is something more to visit.
+    fn leaf_content<'a>(
+        self,
+        content: &'a [u8],
+        range: &Range<u64>,
+        cache: &mut Option<Cache>,
+    ) -> (&'a [u8], Option<Self>) {
+        let content = maybe_target_slice(content, range, self.range.as_ref());
+
+        if !self.pending.is_empty() {
+            (content, Some(self))
+        } else {
+            *cache = Some(self.pending.into());
+            (content, None)
+        }
+    }
+
     /// Returns the total size of the file in bytes.
     pub fn file_size(&self) -> u64 {
         self.state.file_size()
     }
+
+    pub(crate) fn write_cursor(&self, w: &mut CursorWriter) {
+        w.u64(self.pending.len() as u64);
+        for (cid, range) in &self.pending {
+            w.cid(cid);
+            w.range(range);
+        }
+        w.option(self.range.as_ref(), |w, range| w.range(range));
+        self.state.write_cursor(w);
+    }
+
+    pub(crate) fn read_cursor(r: &mut CursorReader<'_>) -> Result<Self, InvalidCursor> {
+        let count = r.u64()?;
+        let mut pending = Vec::new();
+        for _ in 0..count {
+            pending.push((r.cid()?, r.range()?));
+        }
+
+        if pending.is_empty() {
+            return Err(InvalidCursor::new("file visit without pending links"));
+        }
+
+        Ok(FileVisit {
+            pending,
+            range: r.option(|r| r.range())?,
+            state: Traversal::read_cursor(r)?,
+        })
+    }
 }
 
 impl AsRef<Metadata> for FileVisit {
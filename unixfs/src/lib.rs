@@ -21,7 +21,7 @@ pub mod symlink;
 
 /// Directory and directory tree support
 pub mod dir;
-pub use dir::{resolve, LookupError, MaybeResolved, ResolveError};
+pub use dir::{list, resolve, ListedEntry, Listing, LookupError, MaybeResolved, ResolveError};
 
 mod pb;
 use pb::{UnixFs, UnixFsType};
@@ -32,6 +32,10 @@ pub mod dagpb;
 /// Support for walking over all UnixFs trees
 pub mod walk;
 
+/// Typed views over the UnixFs blocks
+pub mod node;
+pub use node::{Node, NodeError, NodeLink};
+
 #[cfg(test)]
 pub(crate) mod test_support;
 
@@ -134,6 +138,12 @@ pub struct Metadata {
 }
 
 impl Metadata {
+    /// Creates metadata out of the optional mode and the optional `(seconds, nanos)` modification
+    /// time, see [`Metadata::mode`] and [`Metadata::mtime`].
+    pub fn new(mode: Option<u32>, mtime: Option<(i64, u32)>) -> Self {
+        Metadata { mode, mtime }
+    }
+
     /// Returns the full file mode, if one has been specified.
     ///
     /// The full file mode is originally read through `st_mode` field of `stat` struct defined in
@@ -155,6 +165,21 @@ impl Metadata {
         self.mtime
     }
 
+    /// Returns true if neither the mode or the mtime has been specified.
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.mtime.is_none()
+    }
+
+    /// Sets the fields of the UnixFs message from this metadata.
+    pub(crate) fn write_to(&self, data: &mut UnixFs<'_>) {
+        data.mode = self.mode;
+        data.mtime = self.mtime.map(|(seconds, nanos)| pb::UnixTime {
+            Seconds: seconds,
+            // like go-ipfs, the zero nanoseconds are left out
+            FractionalNanoseconds: Some(nanos).filter(|nanos| *nanos != 0),
+        });
+    }
+
     /// Returns the mtime metadata as a `FileTime`. Enabled only in the `filetime` feature.
     #[cfg(feature = "filetime")]
     pub fn mtime_as_filetime(&self) -> Option<filetime::FileTime> {
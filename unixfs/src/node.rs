@@ -0,0 +1,544 @@
+//! Typed views over the UnixFS blocks.
+//!
+//! [`Node::parse`] validates the fields of a UnixFS message for the type of the node, and exposes
+//! them through [`FileNode`], [`DirectoryNode`], [`ShardedDirectoryNode`] and [`SymlinkNode`],
+//! instead of the optional protobuf fields which only make sense for some of the types.
+use crate::dir::{
+    check_directory_supported, ShardError, ShardedLookup, UnexpectedDirectoryProperties,
+};
+use crate::file::{FileError, UnwrapBorrowedExt};
+use crate::pb::{FlatUnixFs, PBLink, ParsingFailed, UnixFsType};
+use crate::{InvalidCidInLink, Metadata, UnexpectedNodeType};
+use alloc::borrow::Cow;
+use cid::Cid;
+use core::convert::TryFrom;
+use core::fmt;
+use core::ops::Range;
+
+/// A validated UnixFS block of any of the supported types.
+#[derive(Debug)]
+pub enum Node<'a> {
+    /// A block of a file, either the root or any other block of the file tree.
+    File(FileNode<'a>),
+    /// A plain directory.
+    Directory(DirectoryNode<'a>),
+    /// The root or a bucket of a HAMT sharded directory.
+    ShardedDirectory(ShardedDirectoryNode<'a>),
+    /// A symbolic link.
+    Symlink(SymlinkNode<'a>),
+}
+
+impl<'a> Node<'a> {
+    /// Parses and validates the dag-pb `block` as a UnixFS node. The node borrows the block.
+    pub fn parse(block: &'a [u8]) -> Result<Self, NodeError> {
+        let flat = FlatUnixFs::try_from(block)?;
+
+        match flat.data.Type {
+            UnixFsType::File | UnixFsType::Raw => FileNode::new(flat).map(Node::File),
+            UnixFsType::Directory => DirectoryNode::new(flat).map(Node::Directory),
+            UnixFsType::HAMTShard => ShardedDirectoryNode::new(flat).map(Node::ShardedDirectory),
+            UnixFsType::Symlink => SymlinkNode::new(flat).map(Node::Symlink),
+            other => Err(NodeError::UnexpectedType(other.into())),
+        }
+    }
+
+    /// Returns the mode and the modification time of the node, which are only expected on the
+    /// root blocks.
+    pub fn metadata(&self) -> &Metadata {
+        match self {
+            Node::File(file) => &file.metadata,
+            Node::Directory(dir) => &dir.metadata,
+            Node::ShardedDirectory(dir) => &dir.metadata,
+            Node::Symlink(symlink) => &symlink.metadata,
+        }
+    }
+
+    /// Returns the links of the node, empty for the symlinks.
+    pub fn links(&self) -> &[NodeLink<'a>] {
+        match self {
+            Node::File(file) => &file.links,
+            Node::Directory(dir) => &dir.links,
+            Node::ShardedDirectory(dir) => &dir.links,
+            Node::Symlink(_) => &[],
+        }
+    }
+}
+
+/// A link of a [`Node`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeLink<'a> {
+    /// The name of the link; usually empty for the files, and prefixed by the bucket for the HAMT
+    /// sharded directories.
+    pub name: Cow<'a, str>,
+    /// The linked block.
+    pub cid: Cid,
+    /// The cumulative size of the linked tree, if recorded.
+    pub total_size: Option<u64>,
+}
+
+fn convert_links(links: Vec<PBLink<'_>>) -> Result<Vec<NodeLink<'_>>, InvalidCidInLink> {
+    links
+        .into_iter()
+        .enumerate()
+        .map(
+            |(nth, link)| match Cid::try_from(link.Hash.as_deref().unwrap_or_default()) {
+                Ok(cid) => Ok(NodeLink {
+                    name: link.Name.unwrap_or_default(),
+                    cid,
+                    total_size: link.Tsize,
+                }),
+                Err(e) => Err(InvalidCidInLink::from((nth, link, e))),
+            },
+        )
+        .collect()
+}
+
+/// A validated block of a file, see [`Node::File`].
+#[derive(Debug)]
+pub struct FileNode<'a> {
+    raw: bool,
+    data: &'a [u8],
+    file_size: u64,
+    blocksizes: Vec<u64>,
+    links: Vec<NodeLink<'a>>,
+    metadata: Metadata,
+}
+
+impl<'a> FileNode<'a> {
+    fn new(flat: FlatUnixFs<'a>) -> Result<Self, NodeError> {
+        let FlatUnixFs { links, data } = flat;
+
+        if data.hashType.is_some() || data.fanout.is_some() {
+            return Err(FileError::UnexpectedRawOrFileProperties {
+                hash_type: data.hashType,
+                fanout: data.fanout,
+            }
+            .into());
+        }
+
+        if links.len() != data.blocksizes.len() {
+            return Err(FileError::LinksAndBlocksizesMismatch.into());
+        }
+
+        let metadata = Metadata::from(&data);
+        let content = data.Data.unwrap_borrowed_or_empty();
+
+        let covered = data
+            .blocksizes
+            .iter()
+            .fold(content.len() as u64, |acc, size| acc.saturating_add(*size));
+
+        let file_size = match data.filesize {
+            Some(file_size) if file_size != covered => {
+                return Err(NodeError::FileSizeMismatch { file_size, covered })
+            }
+            _ => covered,
+        };
+
+        Ok(FileNode {
+            raw: data.Type == UnixFsType::Raw,
+            data: content,
+            file_size,
+            blocksizes: data.blocksizes,
+            links: convert_links(links)?,
+            metadata,
+        })
+    }
+
+    /// Returns true for the blocks of the legacy `Raw` type instead of `File`.
+    pub fn is_raw(&self) -> bool {
+        self.raw
+    }
+
+    /// Returns the content stored in this block, which precedes the content of the linked blocks.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns the size of the file content covered by this block and its links.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Returns the sizes of the file content under each of the links.
+    pub fn blocksizes(&self) -> &[u64] {
+        &self.blocksizes
+    }
+
+    /// Returns the links to the rest of the file tree.
+    pub fn links(&self) -> &[NodeLink<'a>] {
+        &self.links
+    }
+
+    /// Returns the links along with the ranges of the file content under them, relative to the
+    /// start of this block.
+    pub fn link_ranges(&self) -> impl Iterator<Item = (&NodeLink<'a>, Range<u64>)> + '_ {
+        let mut start = self.data.len() as u64;
+
+        self.links
+            .iter()
+            .zip(self.blocksizes.iter())
+            .map(move |(link, size)| {
+                let range = start..(start + size);
+                start = range.end;
+                (link, range)
+            })
+    }
+
+    /// Returns the mode and the modification time, which are only expected on the root block.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
+
+/// A validated plain directory, see [`Node::Directory`].
+#[derive(Debug)]
+pub struct DirectoryNode<'a> {
+    links: Vec<NodeLink<'a>>,
+    metadata: Metadata,
+}
+
+impl<'a> DirectoryNode<'a> {
+    fn new(flat: FlatUnixFs<'a>) -> Result<Self, NodeError> {
+        let FlatUnixFs { links, data } = check_directory_supported(flat)?;
+
+        Ok(DirectoryNode {
+            links: convert_links(links)?,
+            metadata: Metadata::from(&data),
+        })
+    }
+
+    /// Returns the entries of the directory.
+    pub fn links(&self) -> &[NodeLink<'a>] {
+        &self.links
+    }
+
+    /// Returns the mode and the modification time of the directory.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
+
+/// A validated root or bucket of a HAMT sharded directory, see [`Node::ShardedDirectory`].
+#[derive(Debug)]
+pub struct ShardedDirectoryNode<'a> {
+    links: Vec<NodeLink<'a>>,
+    metadata: Metadata,
+}
+
+impl<'a> ShardedDirectoryNode<'a> {
+    fn new(mut flat: FlatUnixFs<'a>) -> Result<Self, NodeError> {
+        ShardedLookup::check_supported(&mut flat)?;
+        let FlatUnixFs { links, data } = flat;
+
+        Ok(ShardedDirectoryNode {
+            links: convert_links(links)?,
+            metadata: Metadata::from(&data),
+        })
+    }
+
+    /// Returns all of the links, both the entries and the buckets.
+    pub fn links(&self) -> &[NodeLink<'a>] {
+        &self.links
+    }
+
+    /// Returns the entries linked from this bucket, along with their names without the bucket
+    /// prefix.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &NodeLink<'a>)> + '_ {
+        // the magic number of two comes from the fanout (256), see `ShardedLookup`
+        self.links
+            .iter()
+            .filter_map(|link| match link.name.get(2..) {
+                Some(name) if !name.is_empty() => Some((name, link)),
+                _ => None,
+            })
+    }
+
+    /// Returns the links to the nested buckets.
+    pub fn buckets(&self) -> impl Iterator<Item = &NodeLink<'a>> + '_ {
+        self.links.iter().filter(|link| link.name.len() == 2)
+    }
+
+    /// Returns the mode and the modification time of the directory, which are only expected on
+    /// the root bucket.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
+
+/// A validated symbolic link, see [`Node::Symlink`].
+#[derive(Debug)]
+pub struct SymlinkNode<'a> {
+    target: &'a [u8],
+    metadata: Metadata,
+}
+
+impl<'a> SymlinkNode<'a> {
+    fn new(flat: FlatUnixFs<'a>) -> Result<Self, NodeError> {
+        if !flat.links.is_empty() {
+            return Err(NodeError::SymlinkWithLinks(flat.links.len()));
+        }
+
+        let metadata = Metadata::from(&flat.data);
+
+        Ok(SymlinkNode {
+            target: flat.data.Data.unwrap_borrowed_or_empty(),
+            metadata,
+        })
+    }
+
+    /// Returns the target path as it was stored.
+    pub fn target(&self) -> &'a [u8] {
+        self.target
+    }
+
+    /// Returns the target path if it is valid UTF-8, as it should be.
+    pub fn target_str(&self) -> Option<&'a str> {
+        core::str::from_utf8(self.target).ok()
+    }
+
+    /// Returns the mode and the modification time of the symlink.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
+
+/// Failures of [`Node::parse`].
+#[derive(Debug)]
+pub enum NodeError {
+    /// The block was not a dag-pb block, or had no valid UnixFS message.
+    Read(Option<quick_protobuf::Error>),
+    /// The UnixFS type is not supported, like the `Metadata` type.
+    UnexpectedType(UnexpectedNodeType),
+    /// The file had unsupported properties.
+    File(FileError),
+    /// The file size does not match the size of its content and the blocksizes of its links.
+    FileSizeMismatch {
+        /// The filesize field of the block.
+        file_size: u64,
+        /// The size of the content and the sum of the blocksizes.
+        covered: u64,
+    },
+    /// The directory had unsupported properties.
+    Directory(UnexpectedDirectoryProperties),
+    /// The HAMT sharded directory had unsupported properties.
+    Shard(ShardError),
+    /// The symlink had the given number of links, while it should have none.
+    SymlinkWithLinks(usize),
+    /// A link could not be converted to a Cid.
+    InvalidCid(InvalidCidInLink),
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use NodeError::*;
+
+        match self {
+            Read(Some(e)) => write!(fmt, "reading failed: {}", e),
+            Read(None) => write!(fmt, "reading failed: missing UnixFS message"),
+            UnexpectedType(ut) => write!(fmt, "unexpected type for UnixFs: {:?}", ut),
+            File(e) => write!(fmt, "{}", e),
+            FileSizeMismatch { file_size, covered } => write!(
+                fmt,
+                "filesize {} does not match the content and blocksizes of {} bytes",
+                file_size, covered
+            ),
+            Directory(e) => write!(fmt, "unexpected directory properties: {}", e),
+            Shard(e) => write!(fmt, "{}", e),
+            SymlinkWithLinks(n) => write!(fmt, "symlink with {} links", n),
+            InvalidCid(e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use NodeError::*;
+
+        match self {
+            Read(Some(e)) => Some(e),
+            File(e) => Some(e),
+            InvalidCid(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> From<ParsingFailed<'a>> for NodeError {
+    fn from(e: ParsingFailed<'a>) -> Self {
+        use ParsingFailed::*;
+
+        match e {
+            InvalidDagPb(e) => NodeError::Read(Some(e)),
+            InvalidUnixFs(e, _) => NodeError::Read(Some(e)),
+            NoData(_) => NodeError::Read(None),
+        }
+    }
+}
+
+impl From<FileError> for NodeError {
+    fn from(e: FileError) -> Self {
+        NodeError::File(e)
+    }
+}
+
+impl From<UnexpectedDirectoryProperties> for NodeError {
+    fn from(e: UnexpectedDirectoryProperties) -> Self {
+        NodeError::Directory(e)
+    }
+}
+
+impl From<ShardError> for NodeError {
+    fn from(e: ShardError) -> Self {
+        NodeError::Shard(e)
+    }
+}
+
+impl From<InvalidCidInLink> for NodeError {
+    fn from(e: InvalidCidInLink) -> Self {
+        NodeError::InvalidCid(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Node, NodeError};
+    use crate::dir::builder::{BufferingTreeBuilder, TreeOptions};
+    use crate::file::adder::{Chunker, FileAdder};
+    use crate::file::FileError;
+    use crate::Metadata;
+    use cid::Cid;
+    use core::convert::TryFrom;
+    use hex_literal::hex;
+
+    fn some_cid(number: usize) -> Cid {
+        let mh = multihash::Sha2_256::digest(&number.to_le_bytes());
+        Cid::new_v0(mh).unwrap()
+    }
+
+    #[test]
+    fn file_tree() {
+        let mut adder = FileAdder::builder()
+            .with_chunker(Chunker::Size(2))
+            .with_metadata(Metadata::new(Some(0o600), None))
+            .build();
+
+        let (blocks, written) = adder.push(b"foobar\n");
+        assert_eq!(written, 2);
+        let mut blocks = blocks.collect::<Vec<_>>();
+        let mut content = &b"foobar\n"[written..];
+
+        while !content.is_empty() {
+            let (more, written) = adder.push(content);
+            blocks.extend(more);
+            content = &content[written..];
+        }
+
+        blocks.extend(adder.finish());
+
+        let (root_cid, root) = blocks.last().unwrap();
+        let file = match Node::parse(root).unwrap() {
+            Node::File(file) => file,
+            x => unreachable!("{:?}", x),
+        };
+
+        assert_eq!(file.file_size(), 7);
+        assert_eq!(file.blocksizes(), &[2, 2, 2, 1]);
+        assert_eq!(file.metadata().mode(), Some(0o600));
+
+        let ranges = file
+            .link_ranges()
+            .map(|(link, range)| {
+                assert!(blocks.iter().any(|(cid, _)| cid == &link.cid));
+                range
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, &[0..2, 2..4, 4..6, 6..7]);
+
+        for (cid, block) in &blocks[..blocks.len() - 1] {
+            assert_ne!(cid, root_cid);
+            match Node::parse(block).unwrap() {
+                Node::File(leaf) => {
+                    assert!(leaf.links().is_empty());
+                    assert_eq!(leaf.file_size(), leaf.data().len() as u64);
+                    assert!(leaf.metadata().is_empty());
+                }
+                x => unreachable!("{:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn file_size_mismatch() {
+        // a File with "content" but a filesize of 8
+        let block = hex!("0a0d08021207636f6e74656e741808");
+        match Node::parse(&block).unwrap_err() {
+            NodeError::FileSizeMismatch {
+                file_size: 8,
+                covered: 7,
+            } => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn links_and_blocksizes_mismatch() {
+        // a File with a single link but no blocksizes
+        let block = hex!(
+            "12240a2212207521fe19c374a97759226dc5c0c8e674e73950e81b211f7dd3b6b30883a08a510a020802"
+        );
+        match Node::parse(&block).unwrap_err() {
+            NodeError::File(FileError::LinksAndBlocksizesMismatch) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn directories() {
+        for threshold in &[None, Some(0)] {
+            let mut opts = TreeOptions::default();
+            opts.hamt_sharding_threshold(*threshold);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+            builder.put_link("a/c.txt", some_cid(1), 1).unwrap();
+
+            let root = builder.build().last().unwrap().unwrap();
+
+            let mut names = match Node::parse(&root.block).unwrap() {
+                Node::Directory(dir) => {
+                    assert!(threshold.is_none());
+                    dir.links()
+                        .iter()
+                        .map(|link| link.name.to_string())
+                        .collect::<Vec<_>>()
+                }
+                Node::ShardedDirectory(dir) => {
+                    assert!(threshold.is_some());
+                    assert_eq!(dir.buckets().count(), 0);
+                    dir.entries()
+                        .map(|(name, _)| name.to_owned())
+                        .collect::<Vec<_>>()
+                }
+                x => unreachable!("{:?}", x),
+            };
+
+            names.sort();
+            assert_eq!(names, &["b.txt", "c.txt"]);
+        }
+    }
+
+    #[test]
+    fn symlink() {
+        let mut block = Vec::new();
+        crate::symlink::serialize_symlink_block("../b", &mut block);
+
+        match Node::parse(&block).unwrap() {
+            Node::Symlink(symlink) => {
+                assert_eq!(symlink.target(), b"../b");
+                assert_eq!(symlink.target_str(), Some("../b"));
+            }
+            x => unreachable!("{:?}", x),
+        }
+    }
+}
@@ -11,6 +11,7 @@ pub(crate) use merkledag::PBNode;
 pub(crate) mod unixfs;
 pub(crate) use unixfs::mod_Data::DataType as UnixFsType;
 pub(crate) use unixfs::Data as UnixFs;
+pub(crate) use unixfs::UnixTime;
 
 /// Failure cases for nested serialization, which allows recovery of the outer `PBNode` when desired.
 #[derive(Debug)]
@@ -10,6 +10,13 @@ use core::fmt;
 use either::Either;
 use std::path::{Path, PathBuf};
 
+mod cursor;
+pub(crate) use cursor::{CursorReader, CursorWriter};
+pub use cursor::{InvalidCursor, WalkCursor};
+
+mod listing;
+pub use listing::{ListedNode, ListedType, ListingError, RecursiveListing};
+
 /// `Walker` helps with walking a UnixFS tree, including all of the content and files. It is
 /// created with `Walker::new` and walked over each block with `Walker::continue_block`. Use
 /// `Walker::pending_links` to obtain the next [`Cid`] to be loaded and the prefetchable links.
@@ -309,6 +316,19 @@ impl Walker {
         self.should_continue
     }
 
+    /// Skips the rest of the blocks of the file the walk is in the middle of, continuing with
+    /// the next entry. Does nothing if the latest block was not a part of a file.
+    pub fn skip_file(&mut self) {
+        if let Some(InnerEntry {
+            kind: InnerKind::File(visit @ Some(_), _),
+            ..
+        }) = &mut self.current
+        {
+            *visit = None;
+            self.should_continue = self.next.is_some();
+        }
+    }
+
     // TODO: we could easily split a 'static value for a directory or bucket, which would pop all
     // entries at a single level out to do some parallel walking, though the skipping could already
     // be used to do that... Maybe we could return the filevisit on Skipped to save user from
@@ -924,6 +944,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn resumed_from_cursors() {
+        let blocks = FakeBlockstore::with_fixtures();
+
+        for (root_name, cid) in &[
+            ("foo", "QmPTotyhVnnfCu9R4qwR4cdhpi5ENaiP8ZJfdqsm8Dw2jB"),
+            ("", "QmZbFPTnDBMWbQ6iBxQAhuhLz8Nu9XptYS96e7cuf5wvbk"),
+        ] {
+            let expected = walk_everything(root_name, cid);
+            let mut ret = HashMap::new();
+
+            let walker = Walker::new(cid::Cid::try_from(*cid).unwrap(), root_name.to_string());
+            let mut cursor = walker.cursor();
+
+            // every block is walked with a new walker resumed from the stored cursor
+            while let Some(stored) = cursor.map(WalkCursor::into_bytes) {
+                let mut walker = Walker::resume(&WalkCursor::from(stored)).unwrap();
+
+                let (next, _) = walker.pending_links();
+                let block = blocks.get_by_cid(next);
+                let cw = walker.next(block, &mut None).unwrap();
+                *ret.entry(PathBuf::from(cw.path())).or_insert(0) += 1;
+
+                cursor = walker.cursor();
+            }
+
+            assert_eq!(ret, expected);
+        }
+    }
+
+    #[test]
+    fn invalid_cursors() {
+        let walker = Walker::new(
+            cid::Cid::try_from("QmPTotyhVnnfCu9R4qwR4cdhpi5ENaiP8ZJfdqsm8Dw2jB").unwrap(),
+            String::from("foo"),
+        );
+
+        let mut bytes = walker.cursor().unwrap().into_bytes();
+        bytes.pop();
+        Walker::resume(&WalkCursor::from(bytes)).unwrap_err();
+
+        Walker::resume(&WalkCursor::from(vec![2])).unwrap_err();
+    }
+
     fn walk_everything(root_name: &str, cid: &str) -> HashMap<PathBuf, usize> {
         let mut ret = HashMap::new();
 
@@ -0,0 +1,315 @@
+//! Resumable positions of the [`Walker`], see [`WalkCursor`].
+
+use super::{InnerEntry, InnerKind, Walker};
+use crate::file::visit::FileVisit;
+use crate::Metadata;
+use cid::Cid;
+use core::convert::TryFrom;
+use core::fmt;
+use core::ops::Range;
+use std::path::PathBuf;
+
+/// The version of the encoding, bumped on incompatible changes.
+const VERSION: u64 = 1;
+
+/// The position of a [`Walker`] between two blocks, returned by [`Walker::cursor`].
+///
+/// The cursor can be stored as bytes and resumed with [`Walker::resume`] later, for example to
+/// continue a paginated listing or an interrupted download, without walking the tree again from
+/// the root. The cursor contains the Cids of the pending links, so it grows with the width of the
+/// walked directories and the size of the walked file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkCursor(Vec<u8>);
+
+impl WalkCursor {
+    /// Returns the cursor as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the cursor as bytes.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl From<Vec<u8>> for WalkCursor {
+    /// Wraps the bytes of a previously stored cursor, which are only validated on
+    /// [`Walker::resume`].
+    fn from(bytes: Vec<u8>) -> Self {
+        WalkCursor(bytes)
+    }
+}
+
+/// The cursor given to [`Walker::resume`] was truncated, created by an incompatible version or
+/// otherwise invalid.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidCursor(&'static str);
+
+impl InvalidCursor {
+    pub(crate) fn new(reason: &'static str) -> Self {
+        InvalidCursor(reason)
+    }
+
+    pub(crate) fn reason(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl fmt::Display for InvalidCursor {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "invalid walk cursor: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCursor {}
+
+impl Walker {
+    /// Returns the position of the walk before the next block, or `None` if the walk has been
+    /// completed. A walker resumed from the cursor continues with the same block as this one
+    /// would, so the cursor should be stored only once the previous block has been handled.
+    pub fn cursor(&self) -> Option<WalkCursor> {
+        if !self.should_continue {
+            return None;
+        }
+
+        let mut w = CursorWriter::default();
+        w.u64(VERSION);
+
+        w.option(self.next.as_ref(), |w, link| w.link(link));
+
+        w.u64(self.pending.len() as u64);
+        for link in &self.pending {
+            w.link(link);
+        }
+
+        w.option(self.current.as_ref(), |w, current| {
+            w.cid(&current.cid);
+            match &current.kind {
+                InnerKind::RootDirectory => w.u64(0),
+                InnerKind::BucketAtRoot => w.u64(1),
+                InnerKind::RootBucket => w.u64(2),
+                InnerKind::Bucket => w.u64(3),
+                InnerKind::Directory => w.u64(4),
+                InnerKind::File(visit, file_size) => {
+                    w.u64(5);
+                    w.option(visit.as_ref(), |w, visit| visit.write_cursor(w));
+                    w.u64(*file_size);
+                }
+                InnerKind::Symlink => w.u64(6),
+            }
+            w.str(
+                current
+                    .path
+                    .to_str()
+                    .expect("the path is built from utf-8 names"),
+            );
+            w.metadata(&current.metadata);
+            w.u64(current.depth as u64);
+        });
+
+        Some(WalkCursor(w.0))
+    }
+
+    /// Returns a walker continuing from the `cursor` previously returned by [`Walker::cursor`].
+    pub fn resume(cursor: &WalkCursor) -> Result<Walker, InvalidCursor> {
+        let mut r = CursorReader(cursor.as_bytes());
+
+        if r.u64()? != VERSION {
+            return Err(InvalidCursor("unsupported version"));
+        }
+
+        let next = r.option(|r| r.link())?;
+
+        let count = r.u64()?;
+        let mut pending = Vec::new();
+        for _ in 0..count {
+            pending.push(r.link()?);
+        }
+
+        let current = r.option(|r| {
+            let cid = r.cid()?;
+            let kind = match r.u64()? {
+                0 => InnerKind::RootDirectory,
+                1 => InnerKind::BucketAtRoot,
+                2 => InnerKind::RootBucket,
+                3 => InnerKind::Bucket,
+                4 => InnerKind::Directory,
+                5 => {
+                    let visit = r.option(FileVisit::read_cursor)?;
+                    InnerKind::File(visit, r.u64()?)
+                }
+                6 => InnerKind::Symlink,
+                _ => return Err(InvalidCursor("unknown kind of entry")),
+            };
+            let path = PathBuf::from(r.str()?);
+            let metadata = r.metadata()?;
+            let depth = r.usize()?;
+
+            if depth != path.ancestors().count() {
+                return Err(InvalidCursor("depth does not match the path"));
+            }
+
+            Ok(InnerEntry {
+                cid,
+                kind,
+                path,
+                metadata,
+                depth,
+            })
+        })?;
+
+        if !r.0.is_empty() {
+            return Err(InvalidCursor("trailing bytes"));
+        }
+
+        let ongoing_file = matches!(
+            current.as_ref().map(|c| &c.kind),
+            Some(InnerKind::File(Some(_), _))
+        );
+
+        if next.is_none() && !ongoing_file {
+            return Err(InvalidCursor("nothing to continue from"));
+        }
+
+        Ok(Walker {
+            current,
+            next,
+            pending,
+            should_continue: true,
+        })
+    }
+}
+
+/// Appends the values of a cursor as varints and length prefixed bytes.
+#[derive(Default)]
+pub(crate) struct CursorWriter(pub(crate) Vec<u8>);
+
+impl CursorWriter {
+    pub(crate) fn u64(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    pub(crate) fn cid(&mut self, cid: &Cid) {
+        self.bytes(&cid.to_bytes());
+    }
+
+    pub(crate) fn range(&mut self, range: &Range<u64>) {
+        self.u64(range.start);
+        self.u64(range.end);
+    }
+
+    pub(crate) fn option<T>(&mut self, value: Option<T>, f: impl FnOnce(&mut Self, T)) {
+        match value {
+            Some(value) => {
+                self.u64(1);
+                f(self, value);
+            }
+            None => self.u64(0),
+        }
+    }
+
+    pub(crate) fn metadata(&mut self, metadata: &Metadata) {
+        self.option(metadata.mode, |w, mode| w.u64(u64::from(mode)));
+        self.option(metadata.mtime, |w, (secs, nanos)| {
+            w.u64(secs as u64);
+            w.u64(u64::from(nanos));
+        });
+    }
+
+    fn link(&mut self, (cid, name, depth): &(Cid, String, usize)) {
+        self.cid(cid);
+        self.str(name);
+        self.u64(*depth as u64);
+    }
+}
+
+/// Reads the values written by [`CursorWriter`].
+pub(crate) struct CursorReader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> CursorReader<'a> {
+    pub(crate) fn u64(&mut self) -> Result<u64, InvalidCursor> {
+        let bytes = self.0;
+        let mut value = 0u64;
+
+        for (i, byte) in bytes.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.0 = &bytes[i + 1..];
+                return Ok(value);
+            }
+        }
+
+        Err(InvalidCursor("truncated or too long varint"))
+    }
+
+    pub(crate) fn usize(&mut self) -> Result<usize, InvalidCursor> {
+        usize::try_from(self.u64()?).map_err(|_| InvalidCursor("too large value"))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], InvalidCursor> {
+        let len = self.usize()?;
+        if len > self.0.len() {
+            return Err(InvalidCursor("truncated"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    pub(crate) fn str(&mut self) -> Result<&'a str, InvalidCursor> {
+        core::str::from_utf8(self.bytes()?).map_err(|_| InvalidCursor("invalid utf-8"))
+    }
+
+    pub(crate) fn cid(&mut self) -> Result<Cid, InvalidCursor> {
+        Cid::try_from(self.bytes()?).map_err(|_| InvalidCursor("invalid cid"))
+    }
+
+    pub(crate) fn range(&mut self) -> Result<Range<u64>, InvalidCursor> {
+        let start = self.u64()?;
+        let end = self.u64()?;
+        if start > end {
+            return Err(InvalidCursor("invalid range"));
+        }
+        Ok(start..end)
+    }
+
+    pub(crate) fn option<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, InvalidCursor>,
+    ) -> Result<Option<T>, InvalidCursor> {
+        match self.u64()? {
+            0 => Ok(None),
+            1 => f(self).map(Some),
+            _ => Err(InvalidCursor("invalid option")),
+        }
+    }
+
+    pub(crate) fn metadata(&mut self) -> Result<Metadata, InvalidCursor> {
+        let mode =
+            self.option(|r| u32::try_from(r.u64()?).map_err(|_| InvalidCursor("too large mode")))?;
+        let mtime = self.option(|r| {
+            let secs = r.u64()? as i64;
+            let nanos = u32::try_from(r.u64()?).map_err(|_| InvalidCursor("too large nanos"))?;
+            Ok((secs, nanos))
+        })?;
+        Ok(Metadata { mode, mtime })
+    }
+
+    fn link(&mut self) -> Result<(Cid, String, usize), InvalidCursor> {
+        Ok((self.cid()?, self.str()?.to_owned(), self.usize()?))
+    }
+}
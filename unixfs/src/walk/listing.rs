@@ -0,0 +1,257 @@
+//! Recursive listing of a UnixFS tree over the [`Walker`], see [`RecursiveListing`].
+
+use super::{ContinuedWalk, Error, Walker};
+use crate::file::visit::Cache;
+use crate::Metadata;
+use cid::Cid;
+use core::fmt;
+use core::future::Future;
+use std::path::PathBuf;
+
+/// The type of a [`ListedNode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListedType {
+    Directory,
+    File,
+    Symlink,
+}
+
+/// An entry of the tree returned by [`RecursiveListing::next`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedNode {
+    /// The path of the entry, starting with the root name given to [`RecursiveListing::new`].
+    pub path: PathBuf,
+    /// The Cid of the entry; for the sharded directories the Cid of the root bucket.
+    pub cid: Cid,
+    /// The size of the file contents, the length of the symlink target or zero for directories.
+    pub size: u64,
+    pub kind: ListedType,
+    pub metadata: Metadata,
+}
+
+/// A failure of [`RecursiveListing::next`], after which the listing ends.
+#[derive(Debug)]
+pub enum ListingError<E> {
+    /// The block could not be fetched.
+    Fetch(Cid, E),
+    /// The block could not be walked.
+    Walk(Cid, Error),
+}
+
+impl<E: fmt::Display> fmt::Display for ListingError<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListingError::Fetch(cid, e) => write!(fmt, "failed to fetch {}: {}", cid, e),
+            ListingError::Walk(cid, e) => write!(fmt, "failed to walk {}: {}", cid, e),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for ListingError<E> {}
+
+/// Lists all of the directories, files and symlinks of a tree like `ipfs ls -r`, walking into
+/// the nested directories and over the HAMT buckets of the sharded directories.
+///
+/// The blocks are fetched with the given async callback. Only the root block of each file is
+/// fetched, the contents of the files are skipped. The entries are returned one at a time by the
+/// async [`RecursiveListing::next`], which can be turned into a `Stream`, for example with
+/// `futures::stream::unfold`.
+pub struct RecursiveListing<F> {
+    walker: Walker,
+    fetch: F,
+    cache: Option<Cache>,
+    failed: bool,
+}
+
+impl<F> fmt::Debug for RecursiveListing<F> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("RecursiveListing")
+            .field("walker", &self.walker)
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
+
+impl<F, Fut, E> RecursiveListing<F>
+where
+    F: FnMut(Cid) -> Fut,
+    Fut: Future<Output = Result<Vec<u8>, E>>,
+{
+    /// Starts listing the tree at `root`, with the paths of the entries starting with the
+    /// `root_name`.
+    pub fn new(root: Cid, root_name: String, fetch: F) -> Self {
+        RecursiveListing {
+            walker: Walker::new(root, root_name),
+            fetch,
+            cache: None,
+            failed: false,
+        }
+    }
+
+    /// Returns the next entry of the tree, or `None` once all of them have been listed or the
+    /// listing has failed. The directories are returned before their entries.
+    pub async fn next(&mut self) -> Option<Result<ListedNode, ListingError<E>>> {
+        loop {
+            if self.failed || !self.walker.should_continue() {
+                return None;
+            }
+
+            let cid = self.walker.pending_links().0.to_owned();
+
+            let block = match (self.fetch)(cid.clone()).await {
+                Ok(block) => block,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(ListingError::Fetch(cid, e)));
+                }
+            };
+
+            let listed = match self.walker.next(&block, &mut self.cache) {
+                Ok(ContinuedWalk::Bucket(..)) => None,
+                Ok(ContinuedWalk::RootDirectory(cid, path, metadata))
+                | Ok(ContinuedWalk::Directory(cid, path, metadata)) => Some(ListedNode {
+                    path: path.to_owned(),
+                    cid: cid.to_owned(),
+                    size: 0,
+                    kind: ListedType::Directory,
+                    metadata: metadata.to_owned(),
+                }),
+                Ok(ContinuedWalk::File(_, cid, path, metadata, size)) => Some(ListedNode {
+                    path: path.to_owned(),
+                    cid: cid.to_owned(),
+                    size,
+                    kind: ListedType::File,
+                    metadata: metadata.to_owned(),
+                }),
+                Ok(ContinuedWalk::Symlink(target, cid, path, metadata)) => Some(ListedNode {
+                    path: path.to_owned(),
+                    cid: cid.to_owned(),
+                    size: target.len() as u64,
+                    kind: ListedType::Symlink,
+                    metadata: metadata.to_owned(),
+                }),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(ListingError::Walk(cid, e)));
+                }
+            };
+
+            self.walker.skip_file();
+
+            if let Some(listed) = listed {
+                return Some(Ok(listed));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ListedType, ListingError, RecursiveListing};
+    use crate::test_support::FakeBlockstore;
+    use cid::Cid;
+    use core::convert::TryFrom;
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    use std::cell::Cell;
+    use std::path::PathBuf;
+
+    /// Polls the future once, enough for the fetches completing immediately.
+    fn now<T>(fut: impl Future<Output = T>) -> T {
+        fn noop_raw() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                noop_raw()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(core::ptr::null(), &VTABLE)
+        }
+
+        let waker = unsafe { Waker::from_raw(noop_raw()) };
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = Box::pin(fut);
+
+        match Pin::new(&mut fut).poll(&mut cx) {
+            Poll::Ready(t) => t,
+            Poll::Pending => panic!("the fetches complete immediately"),
+        }
+    }
+
+    fn list_all(root: &str, root_name: &str) -> (Vec<(PathBuf, u64, ListedType)>, usize) {
+        let blocks = FakeBlockstore::with_fixtures();
+        let fetched = Cell::new(0);
+
+        let fetch = |cid: Cid| {
+            fetched.set(fetched.get() + 1);
+            let block = blocks.get_by_cid(&cid).to_vec();
+            async move { Ok::<_, ()>(block) }
+        };
+
+        let root = Cid::try_from(root).unwrap();
+        let mut listing = RecursiveListing::new(root, root_name.to_owned(), fetch);
+        let mut listed = Vec::new();
+
+        while let Some(node) = now(listing.next()) {
+            let node = node.unwrap();
+            listed.push((node.path, node.size, node.kind));
+        }
+
+        drop(listing);
+        (listed, fetched.get())
+    }
+
+    #[test]
+    fn lists_nested_directories_without_file_contents() {
+        let (listed, fetched) = list_all("QmPTotyhVnnfCu9R4qwR4cdhpi5ENaiP8ZJfdqsm8Dw2jB", "root");
+
+        let dir = "root/QmVkvLsSEm2uJx1h5Fqukje8mMPYg393o5C2kMCkF2bBTA";
+        let expected = vec![
+            (PathBuf::from("root"), 0, ListedType::Directory),
+            (PathBuf::from(dir), 0, ListedType::Directory),
+            (
+                PathBuf::from(dir).join("foobar.balanced"),
+                7,
+                ListedType::File,
+            ),
+            (
+                PathBuf::from(dir).join("foobar.trickle"),
+                7,
+                ListedType::File,
+            ),
+        ];
+
+        assert_eq!(listed, expected);
+        // only the root blocks of the files are needed
+        assert_eq!(fetched, 4);
+    }
+
+    #[test]
+    fn lists_over_hamt_buckets() {
+        let (listed, _) = list_all("QmZbFPTnDBMWbQ6iBxQAhuhLz8Nu9XptYS96e7cuf5wvbk", "");
+
+        assert_eq!(listed[0], (PathBuf::new(), 0, ListedType::Directory));
+        assert_eq!(listed.len(), 17);
+        assert!(listed[1..].iter().all(|(path, size, kind)| path
+            .to_str()
+            .unwrap()
+            .starts_with("long-named-file-")
+            && *size == 0
+            && *kind == ListedType::File));
+    }
+
+    #[test]
+    fn failed_fetch_ends_the_listing() {
+        let root = Cid::try_from("QmPTotyhVnnfCu9R4qwR4cdhpi5ENaiP8ZJfdqsm8Dw2jB").unwrap();
+        let mut listing = RecursiveListing::new(root.clone(), String::new(), |_| async {
+            Err::<Vec<u8>, _>("not found")
+        });
+
+        match now(listing.next()) {
+            Some(Err(ListingError::Fetch(cid, "not found"))) => assert_eq!(cid, root),
+            x => panic!("{:?}", x),
+        }
+
+        assert!(now(listing.next()).is_none());
+    }
+}